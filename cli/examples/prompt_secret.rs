@@ -0,0 +1,22 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Manual check for secret prompts, which need a real terminal. Run with
+//! `cargo run --example prompt_secret` and check that what's typed isn't
+//! shown, and that pressing Ctrl+C at the prompt leaves echo turned on.
+
+use cli::util::input::{prompt_secret, prompt_secret_confirm};
+
+fn main() {
+	match prompt_secret("Passphrase", "EXAMPLE_PASSPHRASE") {
+		Ok(s) => println!("Read {} characters", s.chars().count()),
+		Err(e) => eprintln!("{}", e),
+	}
+
+	match prompt_secret_confirm("New passphrase", "EXAMPLE_NEW_PASSPHRASE") {
+		Ok(s) => println!("Read {} characters", s.chars().count()),
+		Err(e) => eprintln!("{}", e),
+	}
+}
@@ -5,8 +5,10 @@
 
 use crate::{constants::APPLICATION_NAME, util::errors::CodeError};
 use async_trait::async_trait;
+use sha2::{Digest, Sha256};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
@@ -40,6 +42,12 @@ cfg_if::cfg_if! {
 			}
 		}
 
+		impl From<tokio::net::UnixListener> for AsyncPipeListener {
+			fn from(listener: tokio::net::UnixListener) -> Self {
+				AsyncPipeListener(listener)
+			}
+		}
+
 		pub fn socket_stream_split(pipe: AsyncPipe) -> (AsyncPipeReadHalf, AsyncPipeWriteHalf) {
 			pipe.into_split()
 		}
@@ -238,9 +246,90 @@ pub fn get_socket_name() -> PathBuf {
 	}
 }
 
+/// Gets a socket name derived from the given lock file, so that instances of
+/// the CLI running with different data directories each get their own. The
+/// path is hashed to keep the name within the platform's length limits. On
+/// unix the socket is put in a directory only the user can access, see
+/// `socket_dirs_for`, rather than a shared one like the temp dir where
+/// another user could create it first.
+pub fn get_socket_name_for(lock_file: &Path) -> PathBuf {
+	let name = socket_file_name_for(lock_file);
+	cfg_if::cfg_if! {
+		if #[cfg(unix)] {
+			let dir = match runtime_dir() {
+				Some(d) => d,
+				None => {
+					let d = data_socket_dir(lock_file);
+					make_private_dir(&d);
+					d
+				}
+			};
+			dir.join(name)
+		} else {
+			PathBuf::from(format!(r"\\.\pipe\{}", name))
+		}
+	}
+}
+
+/// Gets the file name of the socket from `get_socket_name_for`.
+pub fn socket_file_name_for(lock_file: &Path) -> String {
+	let mut hash = Sha256::new();
+	hash.update(lock_file.to_string_lossy().as_bytes());
+	let id = hash.finalize()[..8]
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect::<String>();
+	format!("{}-{}", APPLICATION_NAME, id)
+}
+
+/// Gets the directories sockets for the lock file may be created in, the
+/// preferred one first: `$XDG_RUNTIME_DIR`, which belongs to the user, and
+/// otherwise a `sockets` directory next to the lock file in the CLI's data
+/// directory.
+#[cfg(unix)]
+pub fn socket_dirs_for(lock_file: &Path) -> Vec<PathBuf> {
+	runtime_dir()
+		.into_iter()
+		.chain(std::iter::once(data_socket_dir(lock_file)))
+		.collect()
+}
+
+#[cfg(unix)]
+fn runtime_dir() -> Option<PathBuf> {
+	std::env::var_os("XDG_RUNTIME_DIR")
+		.map(PathBuf::from)
+		.filter(|d| d.is_absolute() && d.is_dir())
+}
+
+#[cfg(unix)]
+fn data_socket_dir(lock_file: &Path) -> PathBuf {
+	lock_file
+		.parent()
+		.unwrap_or_else(|| Path::new("."))
+		.join("sockets")
+}
+
+/// Creates the directory if needed, and makes sure only the user can access
+/// it, in case it was made with other permissions. Failures are left for
+/// listening on the socket to report.
+#[cfg(unix)]
+fn make_private_dir(dir: &Path) {
+	use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+	let _ = std::fs::DirBuilder::new()
+		.recursive(true)
+		.mode(0o700)
+		.create(dir);
+	let _ = std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700));
+}
+
+/// Halves of an accepted connection, and a name for its peer that per-peer
+/// limits use. Connections to a pipe are named by the process that made them
+/// where that's known, and are otherwise each their own peer.
 pub type AcceptedRW = (
 	Box<dyn AsyncRead + Send + Unpin>,
 	Box<dyn AsyncWrite + Send + Unpin>,
+	String,
 );
 
 #[async_trait]
@@ -252,19 +341,50 @@ pub trait AsyncRWAccepter {
 impl AsyncRWAccepter for AsyncPipeListener {
 	async fn accept_rw(&mut self) -> Result<AcceptedRW, CodeError> {
 		let pipe = self.accept().await?;
+		let peer = pipe_peer(&pipe);
 		let (read, write) = socket_stream_split(pipe);
-		Ok((Box::new(read), Box::new(write)))
+		Ok((Box::new(read), Box::new(write), peer))
+	}
+}
+
+static NEXT_PIPE_PEER: AtomicUsize = AtomicUsize::new(0);
+
+fn pipe_peer(pipe: &AsyncPipe) -> String {
+	#[cfg(unix)]
+	if let Some(pid) = pipe.peer_cred().ok().and_then(|c| c.pid()) {
+		return format!("pipe:pid-{}", pid);
 	}
+	#[cfg(not(unix))]
+	let _ = pipe;
+
+	format!("pipe:{}", NEXT_PIPE_PEER.fetch_add(1, Ordering::Relaxed))
 }
 
 #[async_trait]
 impl AsyncRWAccepter for TcpListener {
 	async fn accept_rw(&mut self) -> Result<AcceptedRW, CodeError> {
-		let (stream, _) = self
+		let (stream, addr) = self
 			.accept()
 			.await
 			.map_err(CodeError::AsyncPipeListenerFailed)?;
 		let (read, write) = tokio::io::split(stream);
-		Ok((Box::new(read), Box::new(write)))
+		Ok((Box::new(read), Box::new(write), addr.ip().to_string()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_pipe_peer_is_connecting_process() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("sock");
+		let mut listener = listen_socket_rw_stream(&path).await.unwrap();
+
+		let _client = get_socket_rw_stream(&path).await.unwrap();
+		let (_, _, peer) = listener.accept_rw().await.unwrap();
+		assert_eq!(peer, format!("pipe:pid-{}", std::process::id()));
 	}
 }
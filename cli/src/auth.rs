@@ -4,25 +4,41 @@
  *--------------------------------------------------------------------------------------------*/
 
 use crate::{
-	constants::{get_default_user_agent, APPLICATION_NAME, IS_INTERACTIVE_CLI, PRODUCT_NAME_LONG},
-	debug, error, info, log,
+	constants::{get_default_user_agent, APPLICATION_NAME, PRODUCT_NAME_LONG},
+	debug, error, info, log, policy,
 	state::{LauncherPaths, PersistedState},
 	trace,
 	util::{
+		crypto::{self, OpenError, SealedEnvelope},
 		errors::{
 			wrap, AnyError, CodeError, OAuthError, RefreshTokenNotAvailableError, StatusError,
 			WrappedError,
 		},
-		input::prompt_options,
+		http::shared_client,
+		input::{is_interactive, prompt_options_with_default},
+		io::write_private_file,
+		machine::get_machine_secret,
 	},
 	warning,
 };
 use async_trait::async_trait;
+use base64::{engine::general_purpose as b64, Engine as _};
 use chrono::{DateTime, Utc};
 use gethostname::gethostname;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{cell::Cell, fmt::Display, path::PathBuf, sync::Arc, thread};
-use tokio::time::sleep;
+use std::{
+	cell::Cell,
+	collections::HashMap,
+	fmt::Display,
+	io::IsTerminal,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicU8, Ordering},
+		Arc,
+	},
+	thread,
+	time::Duration,
+};
 use tunnels::{
 	contracts::PROD_FIRST_PARTY_APP_ID,
 	management::{Authorization, AuthorizationProvider, HttpError},
@@ -35,6 +51,7 @@ struct DeviceCodeResponse {
 	message: Option<String>,
 	verification_uri: String,
 	expires_in: i64,
+	interval: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -42,6 +59,10 @@ struct AuthenticationResponse {
 	access_token: String,
 	refresh_token: Option<String>,
 	expires_in: Option<i64>,
+	#[serde(default)]
+	scope: Option<String>,
+	#[serde(default)]
+	id_token: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -50,7 +71,7 @@ struct AuthenticationError {
 	error_description: Option<String>,
 }
 
-#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AuthProvider {
 	Microsoft,
 	Github,
@@ -73,32 +94,240 @@ impl AuthProvider {
 		}
 	}
 
-	pub fn code_uri(&self) -> &'static str {
-		match self {
-			AuthProvider::Microsoft => {
+	pub fn code_uri(&self, cloud: AuthCloud) -> &'static str {
+		match (self, cloud) {
+			(AuthProvider::Microsoft, AuthCloud::Public) => {
 				"https://login.microsoftonline.com/organizations/oauth2/v2.0/devicecode"
 			}
-			AuthProvider::Github => "https://github.com/login/device/code",
+			(AuthProvider::Microsoft, AuthCloud::Usgov) => {
+				"https://login.microsoftonline.us/organizations/oauth2/v2.0/devicecode"
+			}
+			(AuthProvider::Microsoft, AuthCloud::China) => {
+				"https://login.chinacloudapi.cn/organizations/oauth2/v2.0/devicecode"
+			}
+			(AuthProvider::Github, _) => "https://github.com/login/device/code",
 		}
 	}
 
-	pub fn grant_uri(&self) -> &'static str {
-		match self {
-			AuthProvider::Microsoft => {
+	pub fn grant_uri(&self, cloud: AuthCloud) -> &'static str {
+		match (self, cloud) {
+			(AuthProvider::Microsoft, AuthCloud::Public) => {
 				"https://login.microsoftonline.com/organizations/oauth2/v2.0/token"
 			}
-			AuthProvider::Github => "https://github.com/login/oauth/access_token",
+			(AuthProvider::Microsoft, AuthCloud::Usgov) => {
+				"https://login.microsoftonline.us/organizations/oauth2/v2.0/token"
+			}
+			(AuthProvider::Microsoft, AuthCloud::China) => {
+				"https://login.chinacloudapi.cn/organizations/oauth2/v2.0/token"
+			}
+			(AuthProvider::Github, _) => "https://github.com/login/oauth/access_token",
 		}
 	}
 
-	pub fn get_default_scopes(&self) -> String {
+	/// Gets the cloud that credentials from this provider are issued in.
+	/// GitHub only has a public cloud.
+	fn cloud_for(&self, requested: AuthCloud) -> AuthCloud {
 		match self {
-			AuthProvider::Microsoft => format!(
-				"{}/.default+offline_access+profile+openid",
-				PROD_FIRST_PARTY_APP_ID
-			),
-			AuthProvider::Github => "read:user+read:org".to_string(),
+			AuthProvider::Microsoft => requested,
+			AuthProvider::Github => AuthCloud::Public,
+		}
+	}
+
+	/// Gets the scopes requested when logging in. For GitHub these are the
+	/// scopes needed to host a tunnel. Microsoft needs `offline_access` for
+	/// refresh tokens, and `profile` and `openid` for the account name.
+	pub fn default_scopes(&self) -> Vec<String> {
+		match self {
+			AuthProvider::Microsoft => vec![
+				format!("{}/.default", PROD_FIRST_PARTY_APP_ID),
+				"offline_access".to_string(),
+				"profile".to_string(),
+				"openid".to_string(),
+			],
+			AuthProvider::Github => GH_REQUIRED_SCOPES.iter().map(|s| s.to_string()).collect(),
+		}
+	}
+
+	/// Gets the scopes that credentials need to host a tunnel.
+	pub fn required_scopes(&self) -> &'static [&'static str] {
+		match self {
+			AuthProvider::Microsoft => &[],
+			AuthProvider::Github => GH_REQUIRED_SCOPES,
+		}
+	}
+}
+
+/// Microsoft cloud that accounts are authenticated against.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthCloud {
+	#[default]
+	Public,
+	/// Azure Government
+	Usgov,
+	/// Azure operated by 21Vianet
+	China,
+}
+
+impl AuthCloud {
+	fn is_public(&self) -> bool {
+		*self == AuthCloud::Public
+	}
+}
+
+impl Display for AuthCloud {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			AuthCloud::Public => write!(f, "public"),
+			AuthCloud::Usgov => write!(f, "usgov"),
+			AuthCloud::China => write!(f, "china"),
+		}
+	}
+}
+
+/// Errors Microsoft returns when an account's tenant doesn't exist in, or
+/// can't sign in to, the cloud that was asked.
+const AAD_WRONG_CLOUD_ERRORS: &[&str] = &["AADSTS90002", "AADSTS50020", "AADSTS900382"];
+
+/// Explains errors from Microsoft that mean the account is in another cloud.
+fn explain_cloud_error(provider: AuthProvider, cloud: AuthCloud, e: AnyError) -> AnyError {
+	if let (AuthProvider::Microsoft, AnyError::OAuthError(o)) = (provider, &e) {
+		if let Some(d) = &o.error_description {
+			if AAD_WRONG_CLOUD_ERRORS.iter().any(|c| d.contains(c)) {
+				return CodeError::AuthCloudRejected(cloud.to_string(), d.clone()).into();
+			}
+		}
+	}
+
+	e
+}
+
+/// Name of the profile used when no other one is selected. Its credentials
+/// are stored where they were before profiles were introduced, so existing
+/// single-account logins carry over to it.
+pub const DEFAULT_AUTH_PROFILE: &str = "default";
+
+/// Gets whether the name can be used for an auth profile. Names are used in
+/// keyring entry and file names, so they're limited to a safe character set.
+pub fn is_valid_profile_name(name: &str) -> bool {
+	!name.is_empty()
+		&& name.len() <= 64
+		&& name
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Gets the name of the keyring entry holding the index'th chunk of the
+/// profile's credentials.
+fn keyring_entry_name(profile: &str, index: usize) -> String {
+	if profile == DEFAULT_AUTH_PROFILE {
+		format!("vscode-cli-{}", index)
+	} else {
+		format!("vscode-cli-{}-{}", profile, index)
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct AuthProfilesState {
+	/// Profile used when none is given explicitly. Defaults to the default profile.
+	active: Option<String>,
+	/// Profiles other than the default that have been logged into.
+	#[serde(default)]
+	profiles: Vec<String>,
+	/// Microsoft cloud of profiles that don't use the public cloud.
+	#[serde(default)]
+	clouds: HashMap<String, AuthCloud>,
+}
+
+/// Index of the auth profiles that have been logged into, and the one that's
+/// currently active. When no index exists yet, as is the case for data
+/// directories created before profiles, only the default profile is known
+/// and active.
+#[derive(Clone)]
+pub struct AuthProfiles(PersistedState<AuthProfilesState>);
+
+impl AuthProfiles {
+	pub fn new(paths: &LauncherPaths) -> Self {
+		Self(PersistedState::new(paths.root().join("auth_profiles.json")))
+	}
+
+	/// Gets the profile that should be used when none is given.
+	pub fn active(&self) -> String {
+		self.0
+			.load()
+			.active
+			.unwrap_or_else(|| DEFAULT_AUTH_PROFILE.to_string())
+	}
+
+	/// Lists known profiles, always starting with the default profile.
+	pub fn list(&self) -> Vec<String> {
+		let mut names = vec![DEFAULT_AUTH_PROFILE.to_string()];
+		names.extend(self.0.load().profiles);
+		names
+	}
+
+	/// Records that the profile has credentials.
+	pub fn add(&self, name: &str) -> Result<(), AnyError> {
+		if name == DEFAULT_AUTH_PROFILE {
+			return Ok(());
+		}
+
+		self.0.update(|s| {
+			if !s.profiles.iter().any(|p| p == name) {
+				s.profiles.push(name.to_string());
+			}
+		})?;
+		Ok(())
+	}
+
+	/// Removes the profile from the index, making the default profile active
+	/// if the removed profile was.
+	pub fn remove(&self, name: &str) -> Result<(), AnyError> {
+		self.0.update(|s| {
+			s.profiles.retain(|p| p != name);
+			if s.active.as_deref() == Some(name) {
+				s.active = None;
+			}
+		})?;
+		Ok(())
+	}
+
+	/// Gets the Microsoft cloud the profile logs into.
+	pub fn cloud(&self, name: &str) -> AuthCloud {
+		self.0.load().clouds.get(name).copied().unwrap_or_default()
+	}
+
+	/// Sets the Microsoft cloud the profile logs into.
+	pub fn set_cloud(&self, name: &str, cloud: AuthCloud) -> Result<(), AnyError> {
+		if self.cloud(name) == cloud {
+			return Ok(());
+		}
+
+		self.0.update(|s| match cloud {
+			AuthCloud::Public => {
+				s.clouds.remove(name);
+			}
+			c => {
+				s.clouds.insert(name.to_string(), c);
+			}
+		})?;
+		Ok(())
+	}
+
+	/// Makes the profile active. Fails if the profile hasn't been logged into.
+	pub fn switch(&self, name: &str) -> Result<(), AnyError> {
+		if !self.list().iter().any(|p| p == name) {
+			return Err(CodeError::UnknownAuthProfile(name.to_string()).into());
 		}
+
+		self.0.update(|s| {
+			s.active = if name == DEFAULT_AUTH_PROFILE {
+				None
+			} else {
+				Some(name.to_string())
+			};
+		})?;
+		Ok(())
 	}
 }
 
@@ -112,22 +341,191 @@ pub struct StoredCredential {
 	refresh_token: Option<String>,
 	#[serde(rename = "e")]
 	expires_at: Option<DateTime<Utc>>,
+	/// Whether this is a GitHub personal access token, which can't be refreshed
+	/// or replaced by prompting.
+	#[serde(rename = "pat", default, skip_serializing_if = "std::ops::Not::not")]
+	personal_access_token: bool,
+	/// Login of the account the token belongs to, if known.
+	#[serde(rename = "u", default, skip_serializing_if = "Option::is_none")]
+	account: Option<String>,
+	/// Scopes the token was granted, if the provider reported them.
+	#[serde(rename = "s", default, skip_serializing_if = "Option::is_none")]
+	scopes: Option<Vec<String>>,
+	#[serde(rename = "t", default, skip_serializing_if = "Option::is_none")]
+	refreshed_at: Option<DateTime<Utc>>,
+	/// Cloud the credential was issued in, and is refreshed against.
+	#[serde(rename = "c", default, skip_serializing_if = "AuthCloud::is_public")]
+	cloud: AuthCloud,
 }
 
 const GH_USER_ENDPOINT: &str = "https://api.github.com/user";
 
+/// Revokes any kind of GitHub token without needing the app's client secret,
+/// see https://docs.github.com/en/rest/credentials/revoke
+const GH_REVOKE_ENDPOINT: &str = "https://api.github.com/credentials/revoke";
+
+/// How long to wait on GitHub when checking a token. The shared client has
+/// no timeout of its own, since it's also used for long downloads.
+const GH_USER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Scopes that GitHub tokens need to host a tunnel.
+const GH_REQUIRED_SCOPES: &[&str] = &["read:user", "read:org"];
+
 async fn get_github_user(
 	client: &reqwest::Client,
 	access_token: &str,
+) -> Result<reqwest::Response, reqwest::Error> {
+	get_github_user_at(client, GH_USER_ENDPOINT, access_token).await
+}
+
+async fn get_github_user_at(
+	client: &reqwest::Client,
+	endpoint: &str,
+	access_token: &str,
 ) -> Result<reqwest::Response, reqwest::Error> {
 	client
-		.get(GH_USER_ENDPOINT)
+		.get(endpoint)
 		.header("Authorization", format!("token {}", access_token))
 		.header("User-Agent", get_default_user_agent())
+		.timeout(GH_USER_TIMEOUT)
 		.send()
 		.await
 }
 
+/// Gets whether a granted scope includes the required one. GitHub scopes
+/// imply narrower ones, see https://docs.github.com/en/apps/oauth-apps/building-oauth-apps/scopes-for-oauth-apps
+fn scope_covers(provider: AuthProvider, granted: &str, required: &str) -> bool {
+	if granted == required {
+		return true;
+	}
+
+	match provider {
+		AuthProvider::Github => matches!(
+			(granted, required),
+			("user", "read:user" | "user:email" | "user:follow")
+				| ("admin:org" | "write:org", "read:org")
+				| ("admin:org", "write:org")
+				| (
+					"repo",
+					"repo:status" | "repo_deployment" | "public_repo" | "repo:invite"
+				) | ("admin:public_key" | "write:public_key", "read:public_key")
+				| ("admin:gpg_key" | "write:gpg_key", "read:gpg_key")
+				| ("write:packages", "read:packages")
+		),
+		AuthProvider::Microsoft => false,
+	}
+}
+
+/// Gets the required scopes that none of the granted scopes cover.
+fn missing_scopes<S: AsRef<str>>(
+	provider: AuthProvider,
+	granted: &[String],
+	required: &[S],
+) -> Vec<String> {
+	required
+		.iter()
+		.map(|r| r.as_ref())
+		.filter(|r| !granted.iter().any(|g| scope_covers(provider, g, r)))
+		.map(|r| r.to_string())
+		.collect()
+}
+
+/// Splits a list of scopes separated by spaces or commas.
+fn parse_scopes(scopes: &str) -> Vec<String> {
+	scopes
+		.split(|c: char| c == ',' || c.is_whitespace())
+		.filter(|s| !s.is_empty())
+		.map(|s| s.to_string())
+		.collect()
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+	login: String,
+}
+
+/// Result of looking up the user that a GitHub token belongs to.
+#[derive(Debug)]
+struct GithubTokenCheck {
+	status: reqwest::StatusCode,
+	login: Option<String>,
+	/// Raw `X-OAuth-Scopes` header, which only classic tokens report.
+	scopes: Option<String>,
+}
+
+/// Requests the user a GitHub token belongs to. Fails if GitHub could not
+/// give an answer either way, a 4xx status means the token was rejected.
+async fn check_github_token(
+	client: &reqwest::Client,
+	endpoint: &str,
+	access_token: &str,
+) -> Result<GithubTokenCheck, AnyError> {
+	let res = get_github_user_at(client, endpoint, access_token).await?;
+	let status = res.status();
+	if !status.is_success() && !status.is_client_error() {
+		return Err(StatusError::from_res(res).await?.into());
+	}
+
+	let scopes = res
+		.headers()
+		.get("x-oauth-scopes")
+		.map(|s| s.to_str().unwrap_or_default().to_string());
+	let login = match status.is_success() {
+		true => res.json::<GithubUser>().await.ok().map(|u| u.login),
+		false => None,
+	};
+
+	Ok(GithubTokenCheck {
+		status,
+		login,
+		scopes,
+	})
+}
+
+/// Checks that a GitHub token works and, for classic personal access tokens,
+/// that it has the scopes needed by the tunnel. Fine-grained tokens don't
+/// report scopes, so only their validity is checked.
+async fn validate_github_token(
+	client: &reqwest::Client,
+	endpoint: &str,
+	access_token: &str,
+) -> Result<GithubTokenCheck, AnyError> {
+	let check = check_github_token(client, endpoint, access_token).await?;
+	if check.status.is_client_error() {
+		return Err(CodeError::GithubTokenRejected(check.status.as_u16()).into());
+	}
+
+	if let Some(scopes) = &check.scopes {
+		let missing = missing_scopes(
+			AuthProvider::Github,
+			&parse_scopes(scopes),
+			GH_REQUIRED_SCOPES,
+		);
+		if !missing.is_empty() {
+			return Err(CodeError::GithubTokenMissingScopes(missing.join(", ")).into());
+		}
+	}
+
+	Ok(check)
+}
+
+/// Gets the account name from the claims of an OpenID Connect ID token. The
+/// token comes straight from the provider, so its signature isn't checked.
+fn account_from_id_token(id_token: &str) -> Option<String> {
+	#[derive(Deserialize)]
+	struct Claims {
+		preferred_username: Option<String>,
+		email: Option<String>,
+	}
+
+	let payload = id_token.split('.').nth(1)?;
+	let payload = b64::URL_SAFE_NO_PAD
+		.decode(payload.trim_end_matches('='))
+		.ok()?;
+	let claims: Claims = serde_json::from_slice(&payload).ok()?;
+	claims.preferred_username.or(claims.email)
+}
+
 impl StoredCredential {
 	pub async fn is_expired(&self, log: &log::Logger, client: &reqwest::Client) -> bool {
 		match self.provider {
@@ -160,161 +558,859 @@ impl StoredCredential {
 		}
 	}
 
-	fn from_response(auth: AuthenticationResponse, provider: AuthProvider) -> Self {
+	/// Makes a credential from the provider's response, which was received
+	/// `now`.
+	fn from_response(
+		auth: AuthenticationResponse,
+		provider: AuthProvider,
+		cloud: AuthCloud,
+		now: DateTime<Utc>,
+	) -> Self {
 		StoredCredential {
 			provider,
+			cloud: provider.cloud_for(cloud),
 			access_token: auth.access_token,
 			refresh_token: auth.refresh_token,
-			expires_at: auth
-				.expires_in
-				.map(|e| Utc::now() + chrono::Duration::seconds(e)),
+			expires_at: auth.expires_in.map(|e| now + chrono::Duration::seconds(e)),
+			personal_access_token: false,
+			account: auth.id_token.as_deref().and_then(account_from_id_token),
+			scopes: auth.scope.as_deref().map(parse_scopes),
+			refreshed_at: Some(now),
+		}
+	}
+
+	/// Keeps details of the credential this one replaces that the provider
+	/// didn't give again when refreshing.
+	fn inherit(mut self, previous: &StoredCredential) -> Self {
+		if self.account.is_none() {
+			self.account = previous.account.clone();
+		}
+		if self.scopes.is_none() {
+			self.scopes = previous.scopes.clone();
 		}
+		self
+	}
+
+	pub fn provider(&self) -> AuthProvider {
+		self.provider
+	}
+
+	pub fn scopes(&self) -> Option<&[String]> {
+		self.scopes.as_deref()
 	}
 }
 
-struct StorageWithLastRead {
-	storage: Box<dyn StorageImplementation>,
-	fallback_storage: Option<FileStorage>,
-	last_read: Cell<Result<Option<StoredCredential>, WrappedError>>,
+/// Details of the stored credentials, without the tokens themselves.
+#[derive(Serialize, Debug)]
+pub struct CredentialInfo {
+	pub profile: String,
+	pub provider: AuthProvider,
+	pub cloud: AuthCloud,
+	pub account: Option<String>,
+	pub scopes: Option<Vec<String>>,
+	pub expires_at: Option<DateTime<Utc>>,
+	pub has_refresh_token: bool,
+	pub personal_access_token: bool,
+	pub storage: TokenStorageKind,
+	pub refreshed_at: Option<DateTime<Utc>>,
+	/// Provider used for logins where none is chosen.
+	pub default_provider: Option<AuthProvider>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub validation: Option<CredentialValidation>,
 }
 
-#[derive(Clone)]
-pub struct Auth {
-	client: reqwest::Client,
-	log: log::Logger,
-	file_storage_path: PathBuf,
-	storage: Arc<std::sync::Mutex<Option<StorageWithLastRead>>>,
+/// Result of checking credentials with their provider.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CredentialValidation {
+	pub valid: bool,
+	/// Account the provider says the token belongs to.
+	pub account: Option<String>,
+	/// Scopes the provider says the token has.
+	pub scopes: Option<Vec<String>>,
+	/// Required scopes the token doesn't have.
+	pub missing_scopes: Vec<String>,
+	pub reason: Option<String>,
 }
 
-trait StorageImplementation: Send + Sync {
-	fn read(&mut self) -> Result<Option<StoredCredential>, AnyError>;
-	fn store(&mut self, value: StoredCredential) -> Result<(), AnyError>;
-	fn clear(&mut self) -> Result<(), AnyError>;
+/// Fails if the credentials are known to lack any of the scopes. Scopes that
+/// weren't recorded, as for older credentials, are assumed to be present.
+fn require_scopes(creds: &StoredCredential, required: &[&str]) -> Result<(), AnyError> {
+	let granted = match &creds.scopes {
+		Some(s) => s,
+		None => return Ok(()),
+	};
+
+	let missing = missing_scopes(creds.provider, granted, required);
+	match (missing.is_empty(), creds.personal_access_token) {
+		(true, _) => Ok(()),
+		(false, true) => Err(CodeError::GithubTokenMissingScopes(missing.join(", ")).into()),
+		(false, false) => Err(CodeError::MissingAuthScopes(missing.join(", ")).into()),
+	}
 }
 
-// unseal decrypts and deserializes the value
-fn seal<T>(value: &T) -> String
-where
-	T: Serialize + ?Sized,
-{
-	let dec = serde_json::to_string(value).expect("expected to serialize");
-	if std::env::var("VSCODE_CLI_DISABLE_KEYCHAIN_ENCRYPT").is_ok() {
-		return dec;
+/// Checks the credential with its provider. Microsoft tokens are issued for
+/// the tunnel service rather than a user endpoint, so only their expiry can
+/// be checked.
+async fn validate_credential_at(
+	client: &reqwest::Client,
+	github_endpoint: &str,
+	creds: &StoredCredential,
+) -> Result<CredentialValidation, AnyError> {
+	match creds.provider {
+		AuthProvider::Github => {
+			let check = check_github_token(client, github_endpoint, &creds.access_token).await?;
+			let missing_scopes = check
+				.scopes
+				.as_deref()
+				.map(|s| missing_scopes(AuthProvider::Github, &parse_scopes(s), GH_REQUIRED_SCOPES))
+				.unwrap_or_default();
+			let reason = if check.status.is_client_error() {
+				Some(format!("GitHub rejected the token with {}", check.status))
+			} else if !missing_scopes.is_empty() {
+				Some(format!(
+					"the token is missing required scopes: {}",
+					missing_scopes.join(", ")
+				))
+			} else {
+				None
+			};
+
+			Ok(CredentialValidation {
+				valid: reason.is_none(),
+				account: check.login,
+				scopes: check.scopes.as_deref().map(parse_scopes),
+				missing_scopes,
+				reason,
+			})
+		}
+		AuthProvider::Microsoft => {
+			let expired = creds.expires_at.map(|e| e <= Utc::now()).unwrap_or(false);
+			Ok(CredentialValidation {
+				valid: !expired,
+				account: creds.account.clone(),
+				scopes: creds.scopes.clone(),
+				missing_scopes: vec![],
+				reason: match expired {
+					true => Some("the access token has expired".to_string()),
+					false => None,
+				},
+			})
+		}
 	}
-	encrypt(&dec)
 }
 
-// unseal decrypts and deserializes the value
-fn unseal<T>(value: &str) -> Option<T>
-where
-	T: DeserializeOwned,
-{
-	// small back-compat for old unencrypted values, or if VSCODE_CLI_DISABLE_KEYCHAIN_ENCRYPT set
-	if let Ok(v) = serde_json::from_str::<T>(value) {
-		return Some(v);
+/// Outcome of revoking credentials with their provider.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Revocation {
+	Revoked,
+	/// The provider no longer accepted the token.
+	AlreadyRevoked,
+	/// The provider doesn't let the CLI revoke its tokens.
+	Unsupported,
+}
+
+/// Revokes the credential with its provider. Microsoft has no endpoint
+/// that public clients can use to revoke tokens, so they're left to expire.
+async fn revoke_credential_at(
+	client: &reqwest::Client,
+	github_endpoint: &str,
+	creds: &StoredCredential,
+) -> Result<Revocation, AnyError> {
+	match creds.provider {
+		AuthProvider::Github => {
+			let res = client
+				.post(github_endpoint)
+				.header("Accept", "application/vnd.github+json")
+				.header("User-Agent", get_default_user_agent())
+				.json(&serde_json::json!({ "credentials": [&creds.access_token] }))
+				.send()
+				.await?;
+
+			match res.status().as_u16() {
+				// GitHub queues the revocation, and answers 202 Accepted once
+				// it has, whether or not the token was still valid
+				202 | 200 | 204 => Ok(Revocation::Revoked),
+				401 => Ok(Revocation::AlreadyRevoked),
+				_ => Err(StatusError::from_res(res).await?.into()),
+			}
+		}
+		AuthProvider::Microsoft => Ok(Revocation::Unsupported),
 	}
+}
 
-	let dec = decrypt(value)?;
-	serde_json::from_str::<T>(&dec).ok()
+/// Backend that credentials are stored in.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenStorageKind {
+	/// The OS keyring.
+	Keyring,
+	/// A file in the data directory that only the current user can read.
+	File,
+	/// A file in the data directory, encrypted with a key derived from a
+	/// machine secret and an optional passphrase.
+	FileEncrypted,
 }
 
-#[cfg(target_os = "windows")]
-const KEYCHAIN_ENTRY_LIMIT: usize = 1024;
-#[cfg(not(target_os = "windows"))]
-const KEYCHAIN_ENTRY_LIMIT: usize = 128 * 1024;
+impl Display for TokenStorageKind {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			TokenStorageKind::Keyring => write!(f, "keyring"),
+			TokenStorageKind::File => write!(f, "file"),
+			TokenStorageKind::FileEncrypted => write!(f, "file-encrypted"),
+		}
+	}
+}
 
-const CONTINUE_MARKER: &str = "<MORE>";
+/// How long a failed keyring probe is trusted, and how often the hint about
+/// it is shown.
+const KEYRING_PROBE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
-/// Implementation that wraps the KeyringStorage on Linux to avoid
-/// https://github.com/hwchen/keyring-rs/issues/132
-struct ThreadKeyringStorage {
-	s: Option<KeyringStorage>,
+fn within_probe_ttl(at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+	// a negative age means the clock moved; trust the cached result anyway
+	(now - at)
+		.to_std()
+		.map(|age| age < KEYRING_PROBE_TTL)
+		.unwrap_or(true)
 }
 
-impl ThreadKeyringStorage {
-	fn thread_op<R, Fn>(&mut self, f: Fn) -> Result<R, AnyError>
-	where
-		Fn: 'static + Send + FnOnce(&mut KeyringStorage) -> Result<R, AnyError>,
-		R: 'static + Send,
-	{
-		let mut s = match self.s.take() {
-			Some(s) => s,
-			None => return Err(CodeError::KeyringTimeout.into()),
-		};
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct KeyringProbeState {
+	available: Option<bool>,
+	checked_at: Option<DateTime<Utc>>,
+	hinted_at: Option<DateTime<Utc>>,
+}
 
-		// It seems like on Linux communication to the keyring can block indefinitely.
-		// Fall back after a 5 second timeout.
-		let (sender, receiver) = std::sync::mpsc::channel();
-		let tsender = sender.clone();
+/// Whether the OS keyring worked when last used. Failures are remembered for
+/// a while, so that commands don't wait on a keyring that isn't there.
+#[derive(Clone)]
+pub struct KeyringProbe(PersistedState<KeyringProbeState>);
 
-		thread::spawn(move || sender.send(Some((f(&mut s), s))));
-		thread::spawn(move || {
-			thread::sleep(std::time::Duration::from_secs(5));
-			let _ = tsender.send(None);
-		});
+impl KeyringProbe {
+	pub fn new(paths: &LauncherPaths) -> Self {
+		Self(PersistedState::new(paths.root().join("keyring_probe.json")))
+	}
 
-		match receiver.recv().unwrap() {
-			Some((r, s)) => {
-				self.s = Some(s);
-				r
-			}
-			None => Err(CodeError::KeyringTimeout.into()),
+	/// Gets whether the keyring was available, and when that was checked.
+	pub fn last_result(&self) -> Option<(bool, DateTime<Utc>)> {
+		let state = self.0.load();
+		Some((state.available?, state.checked_at?))
+	}
+
+	fn is_known_unavailable(&self, now: DateTime<Utc>) -> bool {
+		matches!(self.last_result(), Some((false, at)) if within_probe_ttl(at, now))
+	}
+
+	fn record(&self, available: bool, now: DateTime<Utc>) {
+		// avoid writing on every command while the keyring works
+		if available && matches!(self.last_result(), Some((true, _))) {
+			return;
 		}
+
+		self.0
+			.update(|s| {
+				s.available = Some(available);
+				s.checked_at = Some(now);
+			})
+			.ok();
 	}
-}
 
-impl Default for ThreadKeyringStorage {
-	fn default() -> Self {
-		Self {
-			s: Some(KeyringStorage::default()),
+	/// Gets whether the hint about a missing keyring should be shown, which
+	/// is done at most once per TTL.
+	fn take_hint(&self, now: DateTime<Utc>) -> bool {
+		let state = self.0.load();
+		if matches!(state.hinted_at, Some(at) if within_probe_ttl(at, now)) {
+			return false;
 		}
+
+		self.0.update(|s| s.hinted_at = Some(now)).ok();
+		true
 	}
 }
 
-impl StorageImplementation for ThreadKeyringStorage {
-	fn read(&mut self) -> Result<Option<StoredCredential>, AnyError> {
-		self.thread_op(|s| s.read())
-	}
+/// Result of actively checking the OS keyring.
+pub struct KeyringProbeResult {
+	pub error: Option<AnyError>,
+	pub took: Duration,
+}
 
-	fn store(&mut self, value: StoredCredential) -> Result<(), AnyError> {
-		self.thread_op(move |s| s.store(value))
-	}
+/// Checks whether the OS keyring can be read within the timeout, updating
+/// the cached probe result.
+pub fn probe_keyring(paths: &LauncherPaths, timeout: Duration) -> KeyringProbeResult {
+	let start = std::time::Instant::now();
+	let result = ThreadKeyringStorage::<keyring::Entry>::new(DEFAULT_AUTH_PROFILE, timeout).read();
+	KeyringProbe::new(paths).record(result.is_ok(), Utc::now());
 
-	fn clear(&mut self) -> Result<(), AnyError> {
-		self.thread_op(|s| s.clear())
+	KeyringProbeResult {
+		error: result.err(),
+		took: start.elapsed(),
 	}
 }
 
-#[derive(Default)]
-struct KeyringStorage {
-	// keywring storage can be split into multiple entries due to entry length limits
-	// on Windows https://github.com/microsoft/vscode-cli/issues/358
-	entries: Vec<keyring::Entry>,
+fn token_storage_state(paths: &LauncherPaths) -> PersistedState<Option<TokenStorageKind>> {
+	PersistedState::new(paths.root().join("token_storage.json"))
 }
 
-macro_rules! get_next_entry {
-	($self: expr, $i: expr) => {
-		match $self.entries.get($i) {
-			Some(e) => e,
-			None => {
-				let e = keyring::Entry::new("vscode-cli", &format!("vscode-cli-{}", $i)).unwrap();
-				$self.entries.push(e);
-				$self.entries.last().unwrap()
-			}
-		}
-	};
+/// Gets the storage backend configured for the data directory, if any. When
+/// none is configured, the keyring is used with a fallback to file storage.
+pub fn get_configured_token_storage(paths: &LauncherPaths) -> Option<TokenStorageKind> {
+	token_storage_state(paths).load()
 }
 
-impl StorageImplementation for KeyringStorage {
-	fn read(&mut self) -> Result<Option<StoredCredential>, AnyError> {
-		let mut str = String::new();
+/// Configures the storage backend used for the data directory.
+pub fn set_configured_token_storage(
+	paths: &LauncherPaths,
+	kind: TokenStorageKind,
+) -> Result<(), AnyError> {
+	token_storage_state(paths).save(Some(kind))?;
+	Ok(())
+}
 
-		for i in 0.. {
-			let entry = get_next_entry!(self, i);
-			let next_chunk = match entry.get_password() {
-				Ok(value) => value,
-				Err(keyring::Error::NoEntry) => return Ok(None), // missing entries?
-				Err(e) => return Err(wrap(e, "error reading keyring").into()),
+fn default_provider_state(paths: &LauncherPaths) -> PersistedState<Option<AuthProvider>> {
+	PersistedState::new(paths.root().join("auth_provider.json"))
+}
+
+/// Gets the provider that logins use when none is chosen, if any.
+pub fn get_default_auth_provider(paths: &LauncherPaths) -> Option<AuthProvider> {
+	default_provider_state(paths).load()
+}
+
+/// Configures the provider used for logins where none is chosen, such as
+/// when the service starts without credentials.
+pub fn set_default_auth_provider(
+	paths: &LauncherPaths,
+	provider: AuthProvider,
+) -> Result<(), AnyError> {
+	default_provider_state(paths).save(Some(provider))?;
+	Ok(())
+}
+
+/// Options for how an Auth finds its credentials.
+#[derive(Clone, Debug, Default)]
+pub struct AuthOptions {
+	/// Profile to use instead of the active one.
+	pub profile: Option<String>,
+	/// Storage backend to use instead of the configured one.
+	pub token_storage: Option<TokenStorageKind>,
+	/// Passphrase that's combined with the machine secret for encrypted file
+	/// storage.
+	pub token_passphrase: Option<String>,
+	/// How long before expiry the token keepalive refreshes tokens, instead
+	/// of `DEFAULT_TOKEN_REFRESH_MARGIN`.
+	pub refresh_margin: Option<Duration>,
+	/// Microsoft cloud to log into instead of the one the profile uses.
+	pub auth_cloud: Option<AuthCloud>,
+	/// How long to wait for the OS keyring, instead of `DEFAULT_KEYRING_TIMEOUT`.
+	pub keyring_timeout: Option<Duration>,
+	/// Scopes to request when logging in, in addition to the provider's
+	/// default scopes.
+	pub extra_scopes: Vec<String>,
+	/// Provider to log in with when none is chosen, instead of the configured one.
+	pub default_provider: Option<AuthProvider>,
+}
+
+/// Amount the device code polling interval is increased by when the provider
+/// asks us to slow down, per https://www.rfc-editor.org/rfc/rfc8628#section-3.5
+const DEVICE_CODE_SLOW_DOWN: Duration = Duration::from_secs(5);
+
+/// Details of a device code login started with `Auth::start_login`, which
+/// the user needs to complete it.
+#[derive(Serialize, Debug)]
+pub struct StartedLogin {
+	/// Handle to pass to `Auth::continue_login`.
+	pub handle: String,
+	pub provider: AuthProvider,
+	pub verification_uri: String,
+	pub user_code: String,
+	pub message: Option<String>,
+	pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct PendingDeviceCode {
+	provider: AuthProvider,
+	#[serde(default)]
+	cloud: AuthCloud,
+	device_code: String,
+	expires_at: DateTime<Utc>,
+	interval_secs: u64,
+}
+
+/// Device codes of logins that were started but not yet continued, keyed by
+/// their handle. Codes are sealed like credentials since they can be
+/// exchanged for a token until they expire.
+#[derive(Clone)]
+struct PendingLogins(PersistedState<HashMap<String, String>>);
+
+impl PendingLogins {
+	fn new(paths: &LauncherPaths) -> Self {
+		Self(PersistedState::new_with_mode(
+			paths.root().join("pending_logins.json"),
+			0o600,
+		))
+	}
+
+	/// Stores the device code, returning its new handle. Expired codes are
+	/// removed at the same time.
+	fn insert(&self, pending: &PendingDeviceCode) -> Result<String, AnyError> {
+		let handle = uuid::Uuid::new_v4().to_string();
+		self.0.update(|codes| {
+			codes.retain(|_, v| {
+				unseal::<PendingDeviceCode>(v)
+					.map(|p| p.expires_at > Utc::now())
+					.unwrap_or(false)
+			});
+			codes.insert(handle.clone(), seal(pending));
+		})?;
+		Ok(handle)
+	}
+
+	fn get(&self, handle: &str) -> Result<PendingDeviceCode, AnyError> {
+		self.0
+			.load()
+			.get(handle)
+			.and_then(|v| unseal(v))
+			.ok_or_else(|| CodeError::UnknownLoginHandle(handle.to_string()).into())
+	}
+
+	fn remove(&self, handle: &str) -> Result<(), AnyError> {
+		self.0.update(|codes| codes.remove(handle))?;
+		Ok(())
+	}
+}
+
+async fn request_device_code(
+	client: &reqwest::Client,
+	code_uri: &str,
+	provider: AuthProvider,
+	cloud: AuthCloud,
+	scopes: &[String],
+) -> Result<DeviceCodeResponse, AnyError> {
+	let init_code = client
+		.post(code_uri)
+		.header("Accept", "application/json")
+		.body(format!(
+			"client_id={}&scope={}",
+			provider.client_id(),
+			scopes.join("+"),
+		))
+		.send()
+		.await?;
+
+	if !init_code.status().is_success() {
+		let status_code = init_code.status().as_u16();
+		let body = init_code.bytes().await?;
+		let e = Auth::handle_grant_error(code_uri, status_code, body);
+		return Err(explain_cloud_error(provider, cloud, e));
+	}
+
+	Ok(init_code.json::<DeviceCodeResponse>().await?)
+}
+
+/// Does a "grant token" request. Expiry times in the response are relative
+/// to `clock`.
+async fn do_grant(
+	client: &reqwest::Client,
+	grant_uri: &str,
+	provider: AuthProvider,
+	cloud: AuthCloud,
+	body: String,
+	clock: &dyn Clock,
+) -> Result<StoredCredential, AnyError> {
+	let response = client
+		.post(grant_uri)
+		.body(body)
+		.header("Accept", "application/json")
+		.send()
+		.await?;
+
+	let status_code = response.status().as_u16();
+	let body = response.bytes().await?;
+	if let Ok(body) = serde_json::from_slice::<AuthenticationResponse>(&body) {
+		return Ok(StoredCredential::from_response(
+			body,
+			provider,
+			cloud,
+			clock.now(),
+		));
+	}
+
+	let e = Auth::handle_grant_error(grant_uri, status_code, body);
+	Err(explain_cloud_error(provider, cloud, e))
+}
+
+/// Polls for the completion of a device code login.
+struct DeviceCodePoll<'a> {
+	provider: AuthProvider,
+	cloud: AuthCloud,
+	grant_uri: &'a str,
+	device_code: &'a str,
+	expires_at: DateTime<Utc>,
+	interval: Duration,
+	slow_down: Duration,
+	clock: &'a dyn Clock,
+	/// Whether to show how long the code stays valid while waiting.
+	countdown: bool,
+}
+
+impl<'a> DeviceCodePoll<'a> {
+	/// Polls until the user completes the login, or fails with
+	/// `CodeError::DeviceCodeExpired` once the code can no longer be used.
+	/// Responses are handled per https://www.rfc-editor.org/rfc/rfc8628#section-3.5
+	async fn run(
+		mut self,
+		client: &reqwest::Client,
+		log: &log::Logger,
+	) -> Result<StoredCredential, AnyError> {
+		let body = format!(
+			"client_id={}&grant_type=urn:ietf:params:oauth:grant-type:device_code&device_code={}",
+			self.provider.client_id(),
+			self.device_code
+		);
+
+		while self.clock.now() < self.expires_at {
+			self.wait(self.interval).await;
+
+			let result = do_grant(
+				client,
+				self.grant_uri,
+				self.provider,
+				self.cloud,
+				body.clone(),
+				self.clock,
+			)
+			.await;
+
+			match result {
+				Ok(creds) => return Ok(creds),
+				Err(AnyError::OAuthError(e)) if e.error == "authorization_pending" => {
+					trace!(log, "device code login is still pending");
+				}
+				Err(AnyError::OAuthError(e)) if e.error == "slow_down" => {
+					self.interval += self.slow_down;
+					trace!(log, "refresh poll failed, slowing down");
+				}
+				// Github returns a non-standard 429 to slow down
+				Err(AnyError::StatusError(e)) if e.status_code == 429 => {
+					self.interval += self.slow_down;
+					trace!(log, "refresh poll failed, slowing down");
+				}
+				Err(AnyError::OAuthError(e)) if e.error == "expired_token" => break,
+				Err(AnyError::OAuthError(e)) if e.error == "access_denied" => {
+					return Err(CodeError::LoginDenied.into());
+				}
+				// other OAuth errors mean the request can never succeed
+				Err(AnyError::OAuthError(e)) => return Err(e.into()),
+				Err(e) => {
+					trace!(log, "refresh poll failed, retrying: {}", e);
+				}
+			}
+		}
+
+		Err(CodeError::DeviceCodeExpired.into())
+	}
+
+	/// Waits for the duration, updating the countdown each second if shown.
+	async fn wait(&self, duration: Duration) {
+		if !self.countdown {
+			return self.clock.sleep(duration).await;
+		}
+
+		let mut left = duration;
+		while !left.is_zero() {
+			let remaining = (self.expires_at - self.clock.now())
+				.to_std()
+				.unwrap_or_default();
+			render_countdown(remaining);
+
+			let step = left.min(Duration::from_secs(1));
+			self.clock.sleep(step).await;
+			left -= step;
+		}
+	}
+}
+
+fn render_countdown(remaining: Duration) {
+	let secs = remaining.as_secs();
+	eprint!("\rThe code expires in {}:{:02} ", secs / 60, secs % 60);
+}
+
+fn clear_countdown() {
+	eprint!("\r{:30}\r", "");
+}
+
+/// Whether device code polling should show a countdown.
+fn should_show_countdown() -> bool {
+	is_interactive() && std::io::stderr().is_terminal()
+}
+
+struct StorageWithLastRead {
+	kind: TokenStorageKind,
+	storage: Box<dyn StorageImplementation>,
+	fallback_storage: Option<FileStorage>,
+	last_read: Cell<Result<Option<StoredCredential>, WrappedError>>,
+}
+
+#[derive(Clone)]
+pub struct Auth {
+	client: reqwest::Client,
+	log: log::Logger,
+	profile: String,
+	profiles: AuthProfiles,
+	pending_logins: PendingLogins,
+	file_storage_path: PathBuf,
+	encrypted_file_storage_path: PathBuf,
+	token_storage: Option<TokenStorageKind>,
+	token_passphrase: Option<String>,
+	refresh_margin: Duration,
+	cloud: AuthCloud,
+	keyring_timeout: Duration,
+	keyring_probe: KeyringProbe,
+	extra_scopes: Vec<String>,
+	default_provider: Option<AuthProvider>,
+	/// Opens the OS keyring for a profile, replaced in tests.
+	open_keyring: fn(&str, Duration) -> Box<dyn StorageImplementation>,
+	/// Opened on first use, since the keyring can take until the timeout to
+	/// answer and most commands never need credentials.
+	storage: Arc<std::sync::Mutex<Option<StorageWithLastRead>>>,
+	/// The `CredentialStoreState` of the storage, kept apart from it since
+	/// its lock is also held while credentials are read and stored.
+	store_state: Arc<AtomicU8>,
+}
+
+/// Whether the credential store has been opened.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum CredentialStoreState {
+	NotInitialized,
+	/// Being opened, such as while waiting on the keyring.
+	Initializing,
+	Open {
+		storage: TokenStorageKind,
+	},
+}
+
+impl CredentialStoreState {
+	fn to_u8(self) -> u8 {
+		match self {
+			CredentialStoreState::NotInitialized => 0,
+			CredentialStoreState::Initializing => 1,
+			CredentialStoreState::Open { storage } => match storage {
+				TokenStorageKind::Keyring => 2,
+				TokenStorageKind::File => 3,
+				TokenStorageKind::FileEncrypted => 4,
+			},
+		}
+	}
+
+	fn from_u8(value: u8) -> Self {
+		match value {
+			1 => CredentialStoreState::Initializing,
+			2 => CredentialStoreState::Open {
+				storage: TokenStorageKind::Keyring,
+			},
+			3 => CredentialStoreState::Open {
+				storage: TokenStorageKind::File,
+			},
+			4 => CredentialStoreState::Open {
+				storage: TokenStorageKind::FileEncrypted,
+			},
+			_ => CredentialStoreState::NotInitialized,
+		}
+	}
+}
+
+impl std::fmt::Display for CredentialStoreState {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			CredentialStoreState::NotInitialized => write!(f, "not yet initialized"),
+			CredentialStoreState::Initializing => write!(f, "initializing"),
+			CredentialStoreState::Open { storage } => write!(f, "using {} storage", storage),
+		}
+	}
+}
+
+trait StorageImplementation: Send + Sync {
+	fn read(&mut self) -> Result<Option<StoredCredential>, AnyError>;
+	fn store(&mut self, value: StoredCredential) -> Result<(), AnyError>;
+	fn clear(&mut self) -> Result<(), AnyError>;
+}
+
+// unseal decrypts and deserializes the value
+fn seal<T>(value: &T) -> String
+where
+	T: Serialize + ?Sized,
+{
+	let dec = serde_json::to_string(value).expect("expected to serialize");
+	if std::env::var("VSCODE_CLI_DISABLE_KEYCHAIN_ENCRYPT").is_ok() {
+		return dec;
+	}
+	encrypt(&dec)
+}
+
+// unseal decrypts and deserializes the value
+fn unseal<T>(value: &str) -> Option<T>
+where
+	T: DeserializeOwned,
+{
+	// small back-compat for old unencrypted values, or if VSCODE_CLI_DISABLE_KEYCHAIN_ENCRYPT set
+	if let Ok(v) = serde_json::from_str::<T>(value) {
+		return Some(v);
+	}
+
+	let dec = decrypt(value)?;
+	serde_json::from_str::<T>(&dec).ok()
+}
+
+#[cfg(target_os = "windows")]
+const KEYCHAIN_ENTRY_LIMIT: usize = 1024;
+#[cfg(not(target_os = "windows"))]
+const KEYCHAIN_ENTRY_LIMIT: usize = 128 * 1024;
+
+const CONTINUE_MARKER: &str = "<MORE>";
+
+/// Default time to wait for the OS keyring before falling back to other storage.
+pub const DEFAULT_KEYRING_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn open_os_keyring(profile: &str, timeout: Duration) -> Box<dyn StorageImplementation> {
+	#[cfg(not(target_os = "linux"))]
+	{
+		let _ = timeout;
+		Box::new(KeyringStorage::<keyring::Entry>::new(profile))
+	}
+	#[cfg(target_os = "linux")]
+	Box::new(ThreadKeyringStorage::<keyring::Entry>::new(
+		profile, timeout,
+	))
+}
+
+/// Implementation that wraps the KeyringStorage on Linux to avoid
+/// https://github.com/hwchen/keyring-rs/issues/132
+struct ThreadKeyringStorage<E: KeyringEntry + 'static = keyring::Entry> {
+	s: Option<KeyringStorage<E>>,
+	timeout: Duration,
+}
+
+impl<E: KeyringEntry + 'static> ThreadKeyringStorage<E> {
+	fn new(profile: &str, timeout: Duration) -> Self {
+		Self {
+			s: Some(KeyringStorage::new(profile)),
+			timeout,
+		}
+	}
+}
+
+impl<E: KeyringEntry + 'static> ThreadKeyringStorage<E> {
+	fn thread_op<R, Fn>(&mut self, f: Fn) -> Result<R, AnyError>
+	where
+		Fn: 'static + Send + FnOnce(&mut KeyringStorage<E>) -> Result<R, AnyError>,
+		R: 'static + Send,
+	{
+		let mut s = match self.s.take() {
+			Some(s) => s,
+			None => return Err(CodeError::KeyringTimeout(self.timeout.as_secs()).into()),
+		};
+
+		// It seems like on Linux communication to the keyring can block indefinitely.
+		// Fall back after the timeout.
+		let (sender, receiver) = std::sync::mpsc::channel();
+		let tsender = sender.clone();
+		let timeout = self.timeout;
+
+		thread::spawn(move || sender.send(Some((f(&mut s), s))));
+		thread::spawn(move || {
+			thread::sleep(timeout);
+			let _ = tsender.send(None);
+		});
+
+		match receiver.recv().unwrap() {
+			Some((r, s)) => {
+				self.s = Some(s);
+				r
+			}
+			None => Err(CodeError::KeyringTimeout(self.timeout.as_secs()).into()),
+		}
+	}
+}
+
+impl<E: KeyringEntry + 'static> StorageImplementation for ThreadKeyringStorage<E> {
+	fn read(&mut self) -> Result<Option<StoredCredential>, AnyError> {
+		self.thread_op(|s| s.read())
+	}
+
+	fn store(&mut self, value: StoredCredential) -> Result<(), AnyError> {
+		self.thread_op(move |s| s.store(value))
+	}
+
+	fn clear(&mut self) -> Result<(), AnyError> {
+		self.thread_op(|s| s.clear())
+	}
+}
+
+/// An entry in the OS keyring, abstracted so that it can be faked in tests.
+trait KeyringEntry: Send + Sync + Sized {
+	fn open(name: &str) -> Self;
+	fn get_password(&self) -> keyring::Result<String>;
+	fn set_password(&self, password: &str) -> keyring::Result<()>;
+	fn delete_password(&self) -> keyring::Result<()>;
+}
+
+impl KeyringEntry for keyring::Entry {
+	fn open(name: &str) -> Self {
+		keyring::Entry::new("vscode-cli", name).unwrap()
+	}
+
+	fn get_password(&self) -> keyring::Result<String> {
+		keyring::Entry::get_password(self)
+	}
+
+	fn set_password(&self, password: &str) -> keyring::Result<()> {
+		keyring::Entry::set_password(self, password)
+	}
+
+	fn delete_password(&self) -> keyring::Result<()> {
+		keyring::Entry::delete_password(self)
+	}
+}
+
+struct KeyringStorage<E: KeyringEntry = keyring::Entry> {
+	profile: String,
+	// keywring storage can be split into multiple entries due to entry length limits
+	// on Windows https://github.com/microsoft/vscode-cli/issues/358
+	entries: Vec<E>,
+}
+
+impl<E: KeyringEntry> KeyringStorage<E> {
+	fn new(profile: &str) -> Self {
+		Self {
+			profile: profile.to_string(),
+			entries: vec![],
+		}
+	}
+}
+
+macro_rules! get_next_entry {
+	($self: expr, $i: expr) => {
+		match $self.entries.get($i) {
+			Some(e) => e,
+			None => {
+				let e = E::open(&keyring_entry_name(&$self.profile, $i));
+				$self.entries.push(e);
+				$self.entries.last().unwrap()
+			}
+		}
+	};
+}
+
+impl<E: KeyringEntry> StorageImplementation for KeyringStorage<E> {
+	fn read(&mut self) -> Result<Option<StoredCredential>, AnyError> {
+		let mut str = String::new();
+
+		for i in 0.. {
+			let entry = get_next_entry!(self, i);
+			let next_chunk = match entry.get_password() {
+				Ok(value) => value,
+				Err(keyring::Error::NoEntry) => return Ok(None), // missing entries?
+				Err(e) => return Err(wrap(e, "error reading keyring").into()),
 			};
 
 			if next_chunk.ends_with(CONTINUE_MARKER) {
@@ -381,15 +1477,210 @@ impl StorageImplementation for FileStorage {
 	}
 }
 
-impl Auth {
-	pub fn new(paths: &LauncherPaths, log: log::Logger) -> Auth {
-		Auth {
-			log,
-			client: reqwest::Client::new(),
-			file_storage_path: paths.root().join("token.json"),
-			storage: Arc::new(std::sync::Mutex::new(None)),
+/// File storage that's encrypted with a key derived from the machine secret
+/// and an optional passphrase, so that copying the file to another machine,
+/// or reading it without the passphrase, doesn't reveal the credentials.
+struct EncryptedFileStorage {
+	path: PathBuf,
+	secret: Vec<u8>,
+	iterations: u32,
+}
+
+impl EncryptedFileStorage {
+	fn new(path: PathBuf, passphrase: Option<&str>) -> Self {
+		let mut secret = get_machine_secret();
+		if let Some(p) = passphrase {
+			secret.push(0);
+			secret.extend_from_slice(p.as_bytes());
+		}
+
+		Self {
+			path,
+			secret,
+			iterations: crypto::DEFAULT_KDF_ITERATIONS,
 		}
 	}
+}
+
+impl StorageImplementation for EncryptedFileStorage {
+	fn read(&mut self) -> Result<Option<StoredCredential>, AnyError> {
+		let contents = match std::fs::read_to_string(&self.path) {
+			Ok(c) => c,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(wrap(e, "error reading encrypted token storage").into()),
+		};
+
+		let envelope: Option<SealedEnvelope> = serde_json::from_str(&contents)
+			.map_err(|_| CodeError::TokenStoreCorrupted("not a token store".to_string()))?;
+		let envelope = match envelope {
+			Some(e) => e,
+			None => return Ok(None),
+		};
+
+		let plaintext = crypto::open(&self.secret, &envelope).map_err(|e| match e {
+			OpenError::WrongKey => CodeError::TokenStoreWrongPassphrase,
+			OpenError::Corrupted(reason) => CodeError::TokenStoreCorrupted(reason.to_string()),
+		})?;
+
+		let credential = serde_json::from_slice(&plaintext)
+			.map_err(|_| CodeError::TokenStoreCorrupted("invalid credential".to_string()))?;
+		Ok(Some(credential))
+	}
+
+	fn store(&mut self, value: StoredCredential) -> Result<(), AnyError> {
+		let plaintext = serde_json::to_vec(&value).expect("expected to serialize");
+		let envelope = crypto::seal(&self.secret, &plaintext, self.iterations);
+		let contents = serde_json::to_vec(&Some(envelope)).unwrap();
+		write_private_file(&self.path, &contents)
+			.map_err(|e| wrap(e, "error writing encrypted token storage").into())
+	}
+
+	fn clear(&mut self) -> Result<(), AnyError> {
+		match std::fs::remove_file(&self.path) {
+			Ok(_) => Ok(()),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+			Err(e) => Err(wrap(e, "error removing encrypted token storage").into()),
+		}
+	}
+}
+
+/// Moves the credential between storage backends, returning whether there
+/// was one to move.
+fn migrate_credential(
+	from: &mut dyn StorageImplementation,
+	to: &mut dyn StorageImplementation,
+) -> Result<bool, AnyError> {
+	let credential = match from.read()? {
+		Some(c) => c,
+		None => return Ok(false),
+	};
+
+	to.store(credential)?;
+	from.clear()?;
+	Ok(true)
+}
+
+impl Auth {
+	/// Creates an Auth for the active profile.
+	pub fn new(paths: &LauncherPaths, log: log::Logger) -> Auth {
+		Auth::with_options(paths, log, &AuthOptions::default())
+	}
+
+	/// Creates an Auth with the given profile and storage options.
+	pub fn with_options(paths: &LauncherPaths, log: log::Logger, options: &AuthOptions) -> Auth {
+		let profiles = AuthProfiles::new(paths);
+		let profile = options.profile.clone().unwrap_or_else(|| profiles.active());
+
+		Auth {
+			log,
+			client: shared_client(),
+			file_storage_path: Auth::file_storage_path(paths, &profile),
+			encrypted_file_storage_path: Auth::encrypted_file_storage_path(paths, &profile),
+			token_storage: options
+				.token_storage
+				.or_else(|| get_configured_token_storage(paths)),
+			token_passphrase: options.token_passphrase.clone(),
+			refresh_margin: options
+				.refresh_margin
+				.unwrap_or(DEFAULT_TOKEN_REFRESH_MARGIN),
+			cloud: options
+				.auth_cloud
+				.unwrap_or_else(|| profiles.cloud(&profile)),
+			keyring_timeout: options.keyring_timeout.unwrap_or(DEFAULT_KEYRING_TIMEOUT),
+			keyring_probe: KeyringProbe::new(paths),
+			extra_scopes: options.extra_scopes.clone(),
+			default_provider: options
+				.default_provider
+				.or_else(|| get_default_auth_provider(paths)),
+			profile,
+			profiles,
+			pending_logins: PendingLogins::new(paths),
+			open_keyring: open_os_keyring,
+			storage: Arc::new(std::sync::Mutex::new(None)),
+			store_state: Arc::new(AtomicU8::new(CredentialStoreState::NotInitialized.to_u8())),
+		}
+	}
+
+	/// Gets the name of the profile whose credentials are used.
+	pub fn profile(&self) -> &str {
+		&self.profile
+	}
+
+	/// Gets whether the credential store has been opened, without opening
+	/// it or waiting for it to open.
+	pub fn credential_store_state(&self) -> CredentialStoreState {
+		CredentialStoreState::from_u8(self.store_state.load(Ordering::SeqCst))
+	}
+
+	fn set_store_state(&self, state: CredentialStoreState) {
+		self.store_state.store(state.to_u8(), Ordering::SeqCst);
+	}
+
+	/// Gets the provider that logins use when none is chosen, if any.
+	pub fn default_provider(&self) -> Option<AuthProvider> {
+		self.default_provider
+	}
+
+	/// Gets the cloud of the stored credentials, or the cloud that will be
+	/// logged into if there are none.
+	pub fn cloud(&self) -> AuthCloud {
+		match self.get_current_credential() {
+			Ok(Some(c)) => c.cloud,
+			_ => self.cloud,
+		}
+	}
+
+	/// Gets the path the profile's credentials are stored at when using file storage.
+	pub fn file_storage_path(paths: &LauncherPaths, profile: &str) -> PathBuf {
+		if profile == DEFAULT_AUTH_PROFILE {
+			paths.root().join("token.json")
+		} else {
+			paths.root().join(format!("token-{}.json", profile))
+		}
+	}
+
+	/// Gets the path the profile's credentials are stored at when using
+	/// encrypted file storage.
+	pub fn encrypted_file_storage_path(paths: &LauncherPaths, profile: &str) -> PathBuf {
+		if profile == DEFAULT_AUTH_PROFILE {
+			paths.root().join("token.enc.json")
+		} else {
+			paths.root().join(format!("token-{}.enc.json", profile))
+		}
+	}
+
+	fn open_storage(&self, kind: TokenStorageKind) -> Box<dyn StorageImplementation> {
+		match kind {
+			TokenStorageKind::Keyring => (self.open_keyring)(&self.profile, self.keyring_timeout),
+			TokenStorageKind::File => Box::new(FileStorage(PersistedState::new_with_mode(
+				self.file_storage_path.clone(),
+				0o600,
+			))),
+			TokenStorageKind::FileEncrypted => Box::new(EncryptedFileStorage::new(
+				self.encrypted_file_storage_path.clone(),
+				self.token_passphrase.as_deref(),
+			)),
+		}
+	}
+
+	/// Moves the credentials to the given storage backend. Returns whether
+	/// there were credentials to move.
+	pub fn migrate_storage(&self, to: TokenStorageKind) -> Result<bool, AnyError> {
+		let moved = self.with_storage(|storage| {
+			if storage.kind == to {
+				return Ok(false);
+			}
+
+			let mut target = self.open_storage(to);
+			migrate_credential(storage.storage.as_mut(), target.as_mut())
+		})?;
+
+		// storage is picked again on next use
+		let mut storage = self.storage.lock().unwrap();
+		*storage = None;
+		self.set_store_state(CredentialStoreState::NotInitialized);
+		Ok(moved)
+	}
 
 	fn with_storage<T, F>(&self, op: F) -> T
 	where
@@ -400,10 +1691,34 @@ impl Auth {
 			return op(s);
 		}
 
-		#[cfg(not(target_os = "linux"))]
-		let mut keyring_storage = KeyringStorage::default();
-		#[cfg(target_os = "linux")]
-		let mut keyring_storage = ThreadKeyringStorage::default();
+		self.set_store_state(CredentialStoreState::Initializing);
+		let mut storage = self.open_first_storage();
+		let out = op(&mut storage);
+		self.set_store_state(CredentialStoreState::Open {
+			storage: storage.kind,
+		});
+		*opt = Some(storage);
+		out
+	}
+
+	/// Opens the configured storage, or the keyring if it answers and file
+	/// storage otherwise, reading the stored credentials.
+	fn open_first_storage(&self) -> StorageWithLastRead {
+		if let Some(kind) = self.token_storage {
+			let mut storage = self.open_storage(kind);
+			return StorageWithLastRead {
+				kind,
+				last_read: Cell::new(
+					storage
+						.read()
+						.map_err(|e| wrap(e, format!("could not read from {} storage", kind))),
+				),
+				fallback_storage: None,
+				storage,
+			};
+		}
+
+		let mut keyring_storage = (self.open_keyring)(&self.profile, self.keyring_timeout);
 		let mut file_storage = FileStorage(PersistedState::new_with_mode(
 			self.file_storage_path.clone(),
 			0o600,
@@ -413,19 +1728,33 @@ impl Auth {
 			|| self.file_storage_path.exists()
 		{
 			Err(wrap("", "user prefers file storage").into())
+		} else if self.keyring_probe.is_known_unavailable(Utc::now()) {
+			if self.keyring_probe.take_hint(Utc::now()) {
+				info!(
+					self.log,
+					"The OS keyring was unavailable recently, so credentials are stored in {}. Run `{} tunnel doctor` to check it again, or use --keyring-timeout if it's slow to unlock.",
+					self.file_storage_path.display(),
+					APPLICATION_NAME
+				);
+			}
+			Err(wrap("", "keyring was recently unavailable").into())
 		} else {
-			keyring_storage.read()
+			let result = keyring_storage.read();
+			self.keyring_probe.record(result.is_ok(), Utc::now());
+			result
 		};
 
-		let mut storage = match native_storage_result {
+		match native_storage_result {
 			Ok(v) => StorageWithLastRead {
+				kind: TokenStorageKind::Keyring,
 				last_read: Cell::new(Ok(v)),
 				fallback_storage: Some(file_storage),
-				storage: Box::new(keyring_storage),
+				storage: keyring_storage,
 			},
 			Err(e) => {
 				debug!(self.log, "Using file keychain storage due to: {}", e);
 				StorageWithLastRead {
+					kind: TokenStorageKind::File,
 					last_read: Cell::new(
 						file_storage
 							.read()
@@ -435,16 +1764,13 @@ impl Auth {
 					storage: Box::new(file_storage),
 				}
 			}
-		};
-
-		let out = op(&mut storage);
-		*opt = Some(storage);
-		out
+		}
 	}
 
 	/// Gets a tunnel Authentication for use in the tunnel management API.
 	pub async fn get_tunnel_authentication(&self) -> Result<Authorization, AnyError> {
 		let cred = self.get_credential().await?;
+		require_scopes(&cred, cred.provider.required_scopes())?;
 		let auth = match cred.provider {
 			AuthProvider::Microsoft => Authorization::Bearer(cred.access_token),
 			AuthProvider::Github => Authorization::Github(format!(
@@ -466,13 +1792,62 @@ impl Auth {
 		})
 	}
 
+	/// Describes the stored credentials without refreshing them or making any
+	/// network requests. Returns None if not logged in.
+	pub fn get_credential_info(&self) -> Result<Option<CredentialInfo>, WrappedError> {
+		let storage = self.with_storage(|storage| storage.kind);
+		let creds = match self.get_current_credential()? {
+			Some(c) => c,
+			None => return Ok(None),
+		};
+
+		Ok(Some(CredentialInfo {
+			profile: self.profile.clone(),
+			provider: creds.provider,
+			cloud: creds.cloud,
+			account: creds.account,
+			scopes: creds.scopes,
+			expires_at: creds.expires_at,
+			has_refresh_token: creds.refresh_token.is_some(),
+			personal_access_token: creds.personal_access_token,
+			storage,
+			refreshed_at: creds.refreshed_at,
+			default_provider: self.default_provider,
+			validation: None,
+		}))
+	}
+
+	/// Checks the stored credentials with the provider. Returns None if not
+	/// logged in.
+	pub async fn validate_credential(&self) -> Result<Option<CredentialValidation>, AnyError> {
+		match self.get_current_credential()? {
+			Some(c) => validate_credential_at(&self.client, GH_USER_ENDPOINT, &c)
+				.await
+				.map(Some),
+			None => Ok(None),
+		}
+	}
+
+	/// Revokes the stored credentials with the provider, without clearing
+	/// them. Returns None if not logged in.
+	pub async fn revoke_credentials(&self) -> Result<Option<Revocation>, AnyError> {
+		match self.get_current_credential()? {
+			Some(c) => revoke_credential_at(&self.client, GH_REVOKE_ENDPOINT, &c)
+				.await
+				.map(Some),
+			None => Ok(None),
+		}
+	}
+
 	/// Clears login info from the keyring.
 	pub fn clear_credentials(&self) -> Result<(), AnyError> {
 		self.with_storage(|storage| {
 			storage.storage.clear()?;
 			storage.last_read.set(Ok(None));
-			Ok(())
-		})
+			Ok::<(), AnyError>(())
+		})?;
+
+		self.profiles.remove(&self.profile)
 	}
 
 	/// Runs the login flow, optionally pre-filling a provider and/or access token.
@@ -486,6 +1861,7 @@ impl Auth {
 			Some(p) => p,
 			None => self.prompt_for_provider().await?,
 		};
+		policy::current().ensure_auth_provider(provider)?;
 
 		let credentials = match access_token {
 			Some(t) => StoredCredential {
@@ -497,6 +1873,11 @@ impl Auth {
 					.as_ref()
 					.map(|_| Utc::now() + chrono::Duration::minutes(5)),
 				refresh_token,
+				personal_access_token: false,
+				account: None,
+				scopes: None,
+				refreshed_at: Some(Utc::now()),
+				cloud: provider.cloud_for(self.cloud),
 			},
 			None => self.do_device_code_flow_with_provider(provider).await?,
 		};
@@ -505,17 +1886,97 @@ impl Auth {
 		Ok(credentials)
 	}
 
+	/// Logs in again with the provider of the stored credentials, asking for
+	/// the scopes they have as well as the extra scopes from the options. The
+	/// new credentials replace the old ones in the same profile.
+	pub async fn upgrade_scopes(&self) -> Result<StoredCredential, AnyError> {
+		let provider = match self.get_current_credential()? {
+			Some(c) => c.provider,
+			None => return Err(CodeError::UpgradeScopesNotLoggedIn.into()),
+		};
+
+		self.upgrade_scopes_at(
+			provider.code_uri(self.cloud),
+			provider.grant_uri(self.cloud),
+			&SystemClock,
+			should_show_countdown(),
+		)
+		.await
+	}
+
+	async fn upgrade_scopes_at(
+		&self,
+		code_uri: &str,
+		grant_uri: &str,
+		clock: &dyn Clock,
+		countdown: bool,
+	) -> Result<StoredCredential, AnyError> {
+		let previous = match self.get_current_credential()? {
+			Some(c) => c,
+			None => return Err(CodeError::UpgradeScopesNotLoggedIn.into()),
+		};
+		if previous.personal_access_token {
+			return Err(CodeError::UpgradeScopesPersonalAccessToken.into());
+		}
+
+		let scopes = self.requested_scopes(previous.provider, Some(&previous));
+		let credentials = self
+			.do_device_code_flow_at(
+				previous.provider,
+				code_uri,
+				grant_uri,
+				&scopes,
+				clock,
+				countdown,
+			)
+			.await?
+			.inherit(&previous);
+
+		self.store_credentials(credentials.clone());
+		Ok(credentials)
+	}
+
+	/// Validates and stores a GitHub personal access token, for use where the
+	/// device code flow can't be completed.
+	pub async fn login_with_personal_access_token(
+		&self,
+		access_token: String,
+	) -> Result<StoredCredential, AnyError> {
+		policy::current().ensure_auth_provider(AuthProvider::Github)?;
+		let check = validate_github_token(&self.client, GH_USER_ENDPOINT, &access_token).await?;
+
+		let credentials = StoredCredential {
+			provider: AuthProvider::Github,
+			access_token,
+			refresh_token: None,
+			expires_at: None,
+			personal_access_token: true,
+			account: check.login,
+			scopes: check.scopes.as_deref().map(parse_scopes),
+			refreshed_at: Some(Utc::now()),
+			cloud: AuthCloud::Public,
+		};
+
+		self.store_credentials(credentials.clone());
+		Ok(credentials)
+	}
+
 	/// Gets the currently stored credentials, or asks the user to log in.
 	pub async fn get_credential(&self) -> Result<StoredCredential, AnyError> {
 		let entry = match self.get_current_credential() {
 			Ok(Some(old_creds)) => {
 				trace!(self.log, "Found token in keyring");
+				// credentials stored before the policy was set stop working
+				policy::current().ensure_auth_provider(old_creds.provider)?;
 				match self.maybe_refresh_token(&old_creds).await {
 					Ok(Some(new_creds)) => {
 						self.store_credentials(new_creds.clone());
 						new_creds
 					}
 					Ok(None) => old_creds,
+					// personal access tokens were given deliberately, don't
+					// replace them with an interactive login
+					Err(e) if old_creds.personal_access_token => return Err(e),
 					Err(e) => {
 						info!(self.log, "error refreshing token: {}", e);
 						let new_creds = self
@@ -534,6 +1995,10 @@ impl Auth {
 				creds
 			}
 
+			// don't replace credentials in a storage that was chosen explicitly,
+			// the error is likely a wrong passphrase or a damaged store
+			Err(e) if self.token_storage.is_some() => return Err(e.into()),
+
 			Err(e) => {
 				warning!(
 					self.log,
@@ -551,6 +2016,7 @@ impl Auth {
 
 	/// Stores credentials, logging a warning if it fails.
 	fn store_credentials(&self, creds: StoredCredential) {
+		let (creds_provider, creds_cloud) = (creds.provider, creds.cloud);
 		self.with_storage(|storage| {
 			if let Err(e) = storage.storage.store(creds.clone()) {
 				warning!(
@@ -560,6 +2026,7 @@ impl Auth {
 				);
 
 				if let Some(fb) = storage.fallback_storage.take() {
+					storage.kind = TokenStorageKind::File;
 					storage.storage = Box::new(fb);
 					match storage.storage.store(creds.clone()) {
 						Err(e) => {
@@ -571,7 +2038,16 @@ impl Auth {
 			}
 
 			storage.last_read.set(Ok(Some(creds)));
-		})
+		});
+
+		if let Err(e) = self.profiles.add(&self.profile) {
+			warning!(self.log, "Failed to record auth profile: {}", e);
+		}
+		if let AuthProvider::Microsoft = creds_provider {
+			if let Err(e) = self.profiles.set_cloud(&self.profile, creds_cloud) {
+				warning!(self.log, "Failed to record auth cloud: {}", e);
+			}
+		}
 	}
 
 	/// Refreshes the token in the credentials if necessary. Returns None if
@@ -584,7 +2060,7 @@ impl Auth {
 			return Ok(None);
 		}
 
-		self.do_refresh_token(creds).await
+		self.do_refresh_token(creds, &SystemClock).await
 	}
 
 	/// Refreshes the token in the credentials. Returns an error if the process failed.
@@ -592,20 +2068,37 @@ impl Auth {
 	async fn do_refresh_token(
 		&self,
 		creds: &StoredCredential,
+		clock: &dyn Clock,
 	) -> Result<Option<StoredCredential>, AnyError> {
 		match &creds.refresh_token {
 			Some(t) => self
 				.do_grant(
 					creds.provider,
+					creds.cloud,
 					format!(
 						"client_id={}&grant_type=refresh_token&refresh_token={}",
 						creds.provider.client_id(),
 						t
 					),
+					clock,
 				)
 				.await
-				.map(Some),
+				.map(|c| Some(c.inherit(creds))),
 			None => match creds.provider {
+				// personal access tokens can't be refreshed, they're only
+				// unavailable once GitHub no longer accepts them
+				AuthProvider::Github if creds.personal_access_token => {
+					let res = get_github_user(&self.client, &creds.access_token).await?;
+					if res.status().is_client_error() {
+						debug!(
+							self.log,
+							"personal access token was rejected with {}",
+							res.status()
+						);
+						return Err(RefreshTokenNotAvailableError().into());
+					}
+					Ok(None)
+				}
 				AuthProvider::Github => self.touch_github_token(creds).await.map(|_| None),
 				_ => Err(RefreshTokenNotAvailableError().into()),
 			},
@@ -616,27 +2109,19 @@ impl Auth {
 	async fn do_grant(
 		&self,
 		provider: AuthProvider,
+		cloud: AuthCloud,
 		body: String,
+		clock: &dyn Clock,
 	) -> Result<StoredCredential, AnyError> {
-		let response = self
-			.client
-			.post(provider.grant_uri())
-			.body(body)
-			.header("Accept", "application/json")
-			.send()
-			.await?;
-
-		let status_code = response.status().as_u16();
-		let body = response.bytes().await?;
-		if let Ok(body) = serde_json::from_slice::<AuthenticationResponse>(&body) {
-			return Ok(StoredCredential::from_response(body, provider));
-		}
-
-		Err(Auth::handle_grant_error(
-			provider.grant_uri(),
-			status_code,
+		do_grant(
+			&self.client,
+			provider.grant_uri(cloud),
+			provider,
+			cloud,
 			body,
-		))
+			clock,
+		)
+		.await
 	}
 
 	/// GH doesn't have a refresh token, but does limit to the 10 most recently
@@ -680,18 +2165,45 @@ impl Auth {
 	}
 
 	async fn prompt_for_provider(&self) -> Result<AuthProvider, AnyError> {
-		if !*IS_INTERACTIVE_CLI {
+		self.prompt_for_provider_with(is_interactive())
+	}
+
+	/// Asks which provider to log in with, starting at the default provider.
+	/// Without a terminal, the default provider is used if there is one.
+	fn prompt_for_provider_with(&self, interactive: bool) -> Result<AuthProvider, AnyError> {
+		let policy = policy::current();
+		if !interactive {
+			let provider = self
+				.default_provider
+				.ok_or(CodeError::NoDefaultAuthProvider)?;
+			policy.ensure_auth_provider(provider)?;
 			info!(
 				self.log,
-				"Using GitHub for authentication, run `{} tunnel user login --provider <provider>` option to change this.",
+				"Using the default provider, {}, for authentication. Run `{} tunnel user set-default-provider <provider>` to change this.",
+				provider,
 				APPLICATION_NAME
 			);
-			return Ok(AuthProvider::Github);
+			return Ok(provider);
+		}
+
+		let options =
+			policy.allowed_auth_providers(&[AuthProvider::Microsoft, AuthProvider::Github]);
+		if options.len() <= 1 {
+			// nothing to choose from, and an empty list fails with the policy's error
+			let provider = options.first().copied().unwrap_or(AuthProvider::Microsoft);
+			policy.ensure_auth_provider(provider)?;
+			return Ok(provider);
 		}
 
-		let provider = prompt_options(
+		let default = options
+			.iter()
+			.position(|p| Some(*p) == self.default_provider)
+			.unwrap_or(0);
+		let provider = prompt_options_with_default(
 			format!("How would you like to log in to {}?", PRODUCT_NAME_LONG),
-			&[AuthProvider::Microsoft, AuthProvider::Github],
+			&options,
+			default,
+			"--provider",
 		)?;
 
 		Ok(provider)
@@ -701,25 +2213,54 @@ impl Auth {
 		&self,
 		provider: AuthProvider,
 	) -> Result<StoredCredential, AnyError> {
-		loop {
-			let init_code = self
-				.client
-				.post(provider.code_uri())
-				.header("Accept", "application/json")
-				.body(format!(
-					"client_id={}&scope={}",
-					provider.client_id(),
-					provider.get_default_scopes(),
-				))
-				.send()
-				.await?;
+		self.do_device_code_flow_at(
+			provider,
+			provider.code_uri(self.cloud),
+			provider.grant_uri(self.cloud),
+			&self.requested_scopes(provider, None),
+			&SystemClock,
+			should_show_countdown(),
+		)
+		.await
+	}
 
-			if !init_code.status().is_success() {
-				return Err(StatusError::from_res(init_code).await?.into());
+	/// Gets the scopes to request when logging in: the provider's defaults,
+	/// any extra scopes from the options, and those the previous credentials
+	/// had, so that logging in again doesn't lose any.
+	fn requested_scopes(
+		&self,
+		provider: AuthProvider,
+		previous: Option<&StoredCredential>,
+	) -> Vec<String> {
+		let previous = previous.and_then(|p| p.scopes.clone()).unwrap_or_default();
+		let mut scopes: Vec<String> = vec![];
+		for s in provider
+			.default_scopes()
+			.into_iter()
+			.chain(self.extra_scopes.iter().cloned())
+			.chain(previous)
+		{
+			if !scopes.iter().any(|g| scope_covers(provider, g, &s)) {
+				scopes.retain(|g| !scope_covers(provider, &s, g));
+				scopes.push(s);
 			}
+		}
+		scopes
+	}
 
-			let init_code_json = init_code.json::<DeviceCodeResponse>().await?;
-			let expires_at = Utc::now() + chrono::Duration::seconds(init_code_json.expires_in);
+	async fn do_device_code_flow_at(
+		&self,
+		provider: AuthProvider,
+		code_uri: &str,
+		grant_uri: &str,
+		scopes: &[String],
+		clock: &dyn Clock,
+		countdown: bool,
+	) -> Result<StoredCredential, AnyError> {
+		loop {
+			let init_code_json =
+				request_device_code(&self.client, code_uri, provider, self.cloud, scopes).await?;
+			let expires_at = clock.now() + chrono::Duration::seconds(init_code_json.expires_in);
 
 			match &init_code_json.message {
 				Some(m) => self.log.result(m),
@@ -729,115 +2270,1611 @@ impl Auth {
 				)),
 			};
 
-			let body = format!(
-					"client_id={}&grant_type=urn:ietf:params:oauth:grant-type:device_code&device_code={}",
-					provider.client_id(),
-					init_code_json.device_code
-			);
+			let poll = DeviceCodePoll {
+				provider,
+				cloud: self.cloud,
+				grant_uri,
+				device_code: &init_code_json.device_code,
+				expires_at,
+				interval: Duration::from_secs(init_code_json.interval.unwrap_or(5)),
+				slow_down: DEVICE_CODE_SLOW_DOWN,
+				clock,
+				countdown,
+			};
 
-			let mut interval_s = 5;
-			while Utc::now() < expires_at {
-				sleep(std::time::Duration::from_secs(interval_s)).await;
+			let result = tokio::select! {
+				r = poll.run(&self.client, &self.log) => r,
+				_ = tokio::signal::ctrl_c() => Err(CodeError::LoginCancelled.into()),
+			};
+			if countdown {
+				clear_countdown();
+			}
 
-				match self.do_grant(provider, body.clone()).await {
-					Ok(creds) => return Ok(creds),
-					Err(AnyError::OAuthError(e)) if e.error == "slow_down" => {
-						interval_s += 5; // https://www.rfc-editor.org/rfc/rfc8628#section-3.5
-						trace!(self.log, "refresh poll failed, slowing down");
-					}
-					// Github returns a non-standard 429 to slow down
-					Err(AnyError::StatusError(e)) if e.status_code == 429 => {
-						interval_s += 5; // https://www.rfc-editor.org/rfc/rfc8628#section-3.5
-						trace!(self.log, "refresh poll failed, slowing down");
-					}
-					Err(e) => {
-						trace!(self.log, "refresh poll failed, retrying: {}", e);
+			// get a new code if the user didn't complete the login in time
+			match result {
+				Err(AnyError::CodeError(CodeError::DeviceCodeExpired)) => {
+					self.log.notice("The code expired, requesting a new one...");
+					continue;
+				}
+				// providers that don't say what they granted give what was asked
+				Ok(mut creds) => {
+					if creds.scopes.is_none() {
+						creds.scopes = Some(scopes.to_vec());
 					}
+					return Ok(creds);
 				}
+				r => return r,
 			}
 		}
 	}
 
-	/// Maintains the stored credential by refreshing it against the service
-	/// to ensure its stays current. Returns a future that should be polled and
-	/// only errors if a refresh fails in a consistent way.
-	pub async fn keep_token_alive(self) -> Result<(), AnyError> {
-		let this = self.clone();
-		let default_refresh = std::time::Duration::from_secs(60 * 60);
-		let min_refresh = std::time::Duration::from_secs(10);
-
-		let mut credential = this.get_credential().await?;
-		let mut last_did_error = false;
-		loop {
-			let sleep_time = if last_did_error {
-				min_refresh
-			} else {
-				match credential.expires_at {
-					Some(d) => ((d - Utc::now()) * 2 / 3).to_std().unwrap_or(min_refresh),
-					None => default_refresh,
-				}
-			};
+	/// Starts a device code login without waiting for it to be completed, for
+	/// callers that show the code to the user themselves. The device code is
+	/// kept in the data directory until the login is continued.
+	pub async fn start_login(
+		&self,
+		provider: Option<AuthProvider>,
+	) -> Result<StartedLogin, AnyError> {
+		let provider = match provider {
+			Some(p) => p,
+			None => self.prompt_for_provider().await?,
+		};
+		policy::current().ensure_auth_provider(provider)?;
 
-			// to_std errors on negative duration, fall back to a 60s refresh
-			tokio::time::sleep(sleep_time.max(min_refresh)).await;
+		self.start_login_at(provider, provider.code_uri(self.cloud))
+			.await
+	}
 
-			match this.do_refresh_token(&credential).await {
-				// 4xx error means this token is probably not good any mode
-				Err(AnyError::StatusError(e)) if e.status_code >= 400 && e.status_code < 500 => {
-					error!(this.log, "failed to keep token alive: {:?}", e);
-					return Err(e.into());
-				}
-				Err(AnyError::RefreshTokenNotAvailableError(_)) => {
-					return Ok(());
-				}
-				Err(e) => {
-					warning!(this.log, "error refreshing token: {:?}", e);
-					last_did_error = true;
-					continue;
-				}
-				Ok(c) => {
-					trace!(this.log, "token was successfully refreshed in keepalive");
-					last_did_error = false;
-					if let Some(c) = c {
-						this.store_credentials(c.clone());
-						credential = c;
-					}
-				}
-			}
+	async fn start_login_at(
+		&self,
+		provider: AuthProvider,
+		code_uri: &str,
+	) -> Result<StartedLogin, AnyError> {
+		let scopes = self.requested_scopes(provider, None);
+		let code =
+			request_device_code(&self.client, code_uri, provider, self.cloud, &scopes).await?;
+		let pending = PendingDeviceCode {
+			provider,
+			cloud: self.cloud,
+			device_code: code.device_code,
+			expires_at: Utc::now() + chrono::Duration::seconds(code.expires_in),
+			interval_secs: code.interval.unwrap_or(5),
+		};
+
+		Ok(StartedLogin {
+			handle: self.pending_logins.insert(&pending)?,
+			provider,
+			verification_uri: code.verification_uri,
+			user_code: code.user_code,
+			message: code.message,
+			expires_at: pending.expires_at,
+		})
+	}
+
+	/// Waits for a login begun with `start_login` to be completed, and stores
+	/// the resulting credentials.
+	pub async fn continue_login(&self, handle: &str) -> Result<StoredCredential, AnyError> {
+		let creds = self
+			.continue_login_at(handle, None, DEVICE_CODE_SLOW_DOWN)
+			.await?;
+		self.store_credentials(creds.clone());
+		Ok(creds)
+	}
+
+	async fn continue_login_at(
+		&self,
+		handle: &str,
+		grant_uri: Option<&str>,
+		slow_down: Duration,
+	) -> Result<StoredCredential, AnyError> {
+		let pending = self.pending_logins.get(handle)?;
+		let countdown = should_show_countdown();
+		let poll = DeviceCodePoll {
+			provider: pending.provider,
+			cloud: pending.cloud,
+			grant_uri: grant_uri.unwrap_or_else(|| pending.provider.grant_uri(pending.cloud)),
+			device_code: &pending.device_code,
+			expires_at: pending.expires_at,
+			interval: Duration::from_secs(pending.interval_secs),
+			slow_down,
+			clock: &SystemClock,
+			countdown,
+		};
+
+		let result = tokio::select! {
+			r = poll.run(&self.client, &self.log) => Some(r),
+			_ = tokio::signal::ctrl_c() => None,
+		};
+		if countdown {
+			clear_countdown();
 		}
+
+		// the login can be continued again later if it was cancelled
+		let result = match result {
+			Some(r) => r,
+			None => return Err(CodeError::LoginCancelled.into()),
+		};
+
+		// the code is used up once granted, denied, or expired
+		self.pending_logins.remove(handle)?;
+		result
 	}
-}
 
-#[async_trait]
-impl AuthorizationProvider for Auth {
-	async fn get_authorization(&self) -> Result<Authorization, HttpError> {
-		self.get_tunnel_authentication()
-			.await
-			.map_err(|e| HttpError::AuthorizationError(e.to_string()))
+	/// Maintains the stored credential by refreshing it against the service
+	/// to ensure its stays current. Returns a future that should be polled and
+	/// only errors if a refresh fails in a consistent way. The reporter is
+	/// called with the outcome of each refresh.
+	pub async fn keep_token_alive<F>(self, report: F) -> Result<(), AnyError>
+	where
+		F: FnMut(TokenRefreshEvent<'_>) + Send,
+	{
+		let credential = self.get_credential().await?;
+		run_token_keepalive(
+			&self.log,
+			&SystemClock,
+			&self,
+			credential,
+			self.refresh_margin,
+			report,
+		)
+		.await
 	}
 }
 
-lazy_static::lazy_static! {
-	static ref HOSTNAME: Vec<u8> = gethostname().to_string_lossy().bytes().collect();
+/// Default amount of time before a token expires that the keepalive
+/// refreshes it.
+pub const DEFAULT_TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(10 * 60);
+
+/// Refresh interval for tokens without a known expiry.
+const KEEPALIVE_DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const KEEPALIVE_MIN_INTERVAL: Duration = Duration::from_secs(10);
+const KEEPALIVE_MAX_RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Source of time for the token keepalive.
+#[async_trait]
+pub trait Clock: Send + Sync {
+	fn now(&self) -> DateTime<Utc>;
+	async fn sleep(&self, duration: Duration);
 }
 
-#[cfg(feature = "vscode-encrypt")]
-fn encrypt(value: &str) -> String {
-	vscode_encrypt::encrypt(&HOSTNAME, value.as_bytes()).expect("expected to encrypt")
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+	fn now(&self) -> DateTime<Utc> {
+		Utc::now()
+	}
+
+	async fn sleep(&self, duration: Duration) {
+		tokio::time::sleep(duration).await
+	}
 }
 
-#[cfg(feature = "vscode-encrypt")]
-fn decrypt(value: &str) -> Option<String> {
-	let b = vscode_encrypt::decrypt(&HOSTNAME, value).ok()?;
-	String::from_utf8(b).ok()
+/// Outcome of a step of the token keepalive.
+#[derive(Debug)]
+pub enum TokenRefreshEvent<'a> {
+	/// The keepalive started with the stored credential.
+	Started {
+		expires_at: Option<DateTime<Utc>>,
+		next_refresh_at: DateTime<Utc>,
+	},
+	/// The token was refreshed, or confirmed to still be valid.
+	Refreshed {
+		expires_at: Option<DateTime<Utc>>,
+		next_refresh_at: DateTime<Utc>,
+	},
+	/// The refresh failed. `retry_at` is None if the keepalive gave up.
+	Failed {
+		error: &'a AnyError,
+		failures: u32,
+		retry_at: Option<DateTime<Utc>>,
+	},
 }
 
-#[cfg(not(feature = "vscode-encrypt"))]
-fn encrypt(value: &str) -> String {
-	value.to_owned()
+#[async_trait]
+trait CredentialRefresher: Send + Sync {
+	/// Refreshes the credential, with the new expiry relative to the clock.
+	async fn refresh(
+		&self,
+		creds: &StoredCredential,
+		clock: &dyn Clock,
+	) -> Result<Option<StoredCredential>, AnyError>;
+	fn store(&self, creds: StoredCredential);
 }
 
-#[cfg(not(feature = "vscode-encrypt"))]
-fn decrypt(value: &str) -> Option<String> {
-	Some(value.to_owned())
+#[async_trait]
+impl CredentialRefresher for Auth {
+	async fn refresh(
+		&self,
+		creds: &StoredCredential,
+		clock: &dyn Clock,
+	) -> Result<Option<StoredCredential>, AnyError> {
+		self.do_refresh_token(creds, clock).await
+	}
+
+	fn store(&self, creds: StoredCredential) {
+		// also swaps the credential that's returned to API clients
+		self.store_credentials(creds)
+	}
+}
+
+/// Gets how long to wait before refreshing a token. Tokens are refreshed the
+/// margin before they expire, or halfway through their remaining lifetime if
+/// that's shorter than the margin.
+fn next_refresh_delay(
+	now: DateTime<Utc>,
+	expires_at: Option<DateTime<Utc>>,
+	margin: Duration,
+) -> Duration {
+	let delay = match expires_at {
+		Some(e) => {
+			// to_std errors on negative durations, when the token already expired
+			let remaining = (e - now).to_std().unwrap_or_default();
+			remaining.saturating_sub(margin).max(remaining / 2)
+		}
+		None => KEEPALIVE_DEFAULT_INTERVAL,
+	};
+
+	delay.max(KEEPALIVE_MIN_INTERVAL)
+}
+
+/// Gets how long to wait before retrying after the given number of
+/// consecutive failed refreshes.
+fn refresh_retry_delay(failures: u32) -> Duration {
+	KEEPALIVE_MIN_INTERVAL
+		.saturating_mul(1 << failures.saturating_sub(1).min(16))
+		.min(KEEPALIVE_MAX_RETRY_INTERVAL)
+}
+
+fn time_after(now: DateTime<Utc>, duration: Duration) -> DateTime<Utc> {
+	now + chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::zero())
+}
+
+/// Whether the refresh error means the credential can no longer be used.
+fn is_fatal_refresh_error(e: &AnyError) -> bool {
+	match e {
+		// 4xx error means this token is probably not good any more
+		AnyError::StatusError(e) => e.status_code >= 400 && e.status_code < 500,
+		AnyError::OAuthError(e) => e.error == "invalid_grant",
+		_ => false,
+	}
+}
+
+async fn run_token_keepalive<F>(
+	log: &log::Logger,
+	clock: &dyn Clock,
+	refresher: &dyn CredentialRefresher,
+	mut credential: StoredCredential,
+	margin: Duration,
+	mut report: F,
+) -> Result<(), AnyError>
+where
+	F: FnMut(TokenRefreshEvent<'_>) + Send,
+{
+	let mut delay = next_refresh_delay(clock.now(), credential.expires_at, margin);
+	report(TokenRefreshEvent::Started {
+		expires_at: credential.expires_at,
+		next_refresh_at: time_after(clock.now(), delay),
+	});
+
+	let mut failures = 0;
+	loop {
+		clock.sleep(delay).await;
+
+		match refresher.refresh(&credential, clock).await {
+			Ok(c) => {
+				trace!(log, "token was successfully refreshed in keepalive");
+				failures = 0;
+				if let Some(c) = c {
+					refresher.store(c.clone());
+					credential = c;
+				}
+
+				delay = next_refresh_delay(clock.now(), credential.expires_at, margin);
+				report(TokenRefreshEvent::Refreshed {
+					expires_at: credential.expires_at,
+					next_refresh_at: time_after(clock.now(), delay),
+				});
+			}
+			Err(AnyError::RefreshTokenNotAvailableError(_)) => {
+				return Ok(());
+			}
+			Err(e) => {
+				failures += 1;
+				if is_fatal_refresh_error(&e) {
+					error!(log, "failed to keep token alive: {:?}", e);
+					report(TokenRefreshEvent::Failed {
+						error: &e,
+						failures,
+						retry_at: None,
+					});
+					return Err(e);
+				}
+
+				delay = refresh_retry_delay(failures);
+				warning!(
+					log,
+					"error refreshing token, retrying in {}s: {:?}",
+					delay.as_secs(),
+					e
+				);
+				report(TokenRefreshEvent::Failed {
+					error: &e,
+					failures,
+					retry_at: Some(time_after(clock.now(), delay)),
+				});
+			}
+		}
+	}
+}
+
+#[async_trait]
+impl AuthorizationProvider for Auth {
+	async fn get_authorization(&self) -> Result<Authorization, HttpError> {
+		self.get_tunnel_authentication()
+			.await
+			.map_err(|e| HttpError::AuthorizationError(e.to_string()))
+	}
+}
+
+lazy_static::lazy_static! {
+	static ref HOSTNAME: Vec<u8> = gethostname().to_string_lossy().bytes().collect();
+}
+
+#[cfg(feature = "vscode-encrypt")]
+fn encrypt(value: &str) -> String {
+	vscode_encrypt::encrypt(&HOSTNAME, value.as_bytes()).expect("expected to encrypt")
+}
+
+#[cfg(feature = "vscode-encrypt")]
+fn decrypt(value: &str) -> Option<String> {
+	let b = vscode_encrypt::decrypt(&HOSTNAME, value).ok()?;
+	String::from_utf8(b).ok()
+}
+
+#[cfg(not(feature = "vscode-encrypt"))]
+fn encrypt(value: &str) -> String {
+	value.to_owned()
+}
+
+#[cfg(not(feature = "vscode-encrypt"))]
+fn decrypt(value: &str) -> Option<String> {
+	Some(value.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{cell::RefCell, collections::HashMap, convert::Infallible};
+
+	use hyper::service::{make_service_fn, service_fn};
+
+	use super::*;
+
+	thread_local! {
+		static FAKE_KEYRING: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+	}
+
+	struct FakeEntry(String);
+
+	impl KeyringEntry for FakeEntry {
+		fn open(name: &str) -> Self {
+			FakeEntry(name.to_string())
+		}
+
+		fn get_password(&self) -> keyring::Result<String> {
+			FAKE_KEYRING.with(|k| {
+				k.borrow()
+					.get(&self.0)
+					.cloned()
+					.ok_or(keyring::Error::NoEntry)
+			})
+		}
+
+		fn set_password(&self, password: &str) -> keyring::Result<()> {
+			FAKE_KEYRING.with(|k| k.borrow_mut().insert(self.0.clone(), password.to_string()));
+			Ok(())
+		}
+
+		fn delete_password(&self) -> keyring::Result<()> {
+			FAKE_KEYRING.with(|k| {
+				k.borrow_mut()
+					.remove(&self.0)
+					.map(|_| ())
+					.ok_or(keyring::Error::NoEntry)
+			})
+		}
+	}
+
+	fn fake_keys() -> Vec<String> {
+		let mut keys: Vec<_> = FAKE_KEYRING.with(|k| k.borrow().keys().cloned().collect());
+		keys.sort();
+		keys
+	}
+
+	fn credential(access_token: &str) -> StoredCredential {
+		StoredCredential {
+			provider: AuthProvider::Github,
+			access_token: access_token.to_string(),
+			refresh_token: None,
+			expires_at: None,
+			personal_access_token: false,
+			account: None,
+			scopes: None,
+			refreshed_at: None,
+			cloud: AuthCloud::Public,
+		}
+	}
+
+	fn temp_paths() -> (tempfile::TempDir, LauncherPaths) {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		(dir, paths)
+	}
+
+	#[test]
+	fn test_storage_key_derivation() {
+		assert_eq!(keyring_entry_name(DEFAULT_AUTH_PROFILE, 0), "vscode-cli-0");
+		assert_eq!(keyring_entry_name("work", 1), "vscode-cli-work-1");
+
+		let (_dir, paths) = temp_paths();
+		assert_eq!(
+			Auth::file_storage_path(&paths, DEFAULT_AUTH_PROFILE),
+			paths.root().join("token.json")
+		);
+		assert_eq!(
+			Auth::file_storage_path(&paths, "work"),
+			paths.root().join("token-work.json")
+		);
+
+		assert!(is_valid_profile_name("work_2-a"));
+		assert!(!is_valid_profile_name(""));
+		assert!(!is_valid_profile_name("../work"));
+		assert!(!is_valid_profile_name("a b"));
+	}
+
+	#[test]
+	fn test_profiles_are_isolated() {
+		let mut work = KeyringStorage::<FakeEntry>::new("work");
+		work.store(credential("work-token")).unwrap();
+		assert_eq!(fake_keys(), vec!["vscode-cli-work-0"]);
+
+		let mut default = KeyringStorage::<FakeEntry>::new(DEFAULT_AUTH_PROFILE);
+		assert!(default.read().unwrap().is_none());
+
+		default.store(credential("default-token")).unwrap();
+		work.clear().unwrap();
+		assert_eq!(fake_keys(), vec!["vscode-cli-0"]);
+		assert_eq!(
+			default.read().unwrap().unwrap().access_token,
+			"default-token"
+		);
+	}
+
+	#[test]
+	fn test_migrates_single_account_layout() {
+		// credentials written before profiles existed
+		FAKE_KEYRING.with(|k| {
+			k.borrow_mut()
+				.insert("vscode-cli-0".to_string(), seal(&credential("legacy")))
+		});
+
+		let (_dir, paths) = temp_paths();
+		let profiles = AuthProfiles::new(&paths);
+		assert_eq!(profiles.active(), DEFAULT_AUTH_PROFILE);
+		assert_eq!(profiles.list(), vec![DEFAULT_AUTH_PROFILE]);
+
+		let mut storage = KeyringStorage::<FakeEntry>::new(&profiles.active());
+		assert_eq!(storage.read().unwrap().unwrap().access_token, "legacy");
+	}
+
+	#[test]
+	fn test_switch_profile() {
+		let (_dir, paths) = temp_paths();
+		let profiles = AuthProfiles::new(&paths);
+
+		assert!(profiles.switch("work").is_err());
+
+		profiles.add("work").unwrap();
+		profiles.add("work").unwrap();
+		profiles.switch("work").unwrap();
+		assert_eq!(AuthProfiles::new(&paths).active(), "work");
+		assert_eq!(profiles.list(), vec![DEFAULT_AUTH_PROFILE, "work"]);
+
+		profiles.switch(DEFAULT_AUTH_PROFILE).unwrap();
+		assert_eq!(profiles.active(), DEFAULT_AUTH_PROFILE);
+
+		profiles.switch("work").unwrap();
+		profiles.remove("work").unwrap();
+		assert_eq!(profiles.active(), DEFAULT_AUTH_PROFILE);
+		assert_eq!(profiles.list(), vec![DEFAULT_AUTH_PROFILE]);
+	}
+
+	#[test]
+	fn test_missing_scopes() {
+		let gh = AuthProvider::Github;
+		let missing =
+			|granted: &str, required: &[&str]| missing_scopes(gh, &parse_scopes(granted), required);
+		assert!(missing("read:user, read:org, repo", &["read:user", "read:org"]).is_empty());
+		assert!(missing("user, admin:org", &["read:user", "read:org", "write:org"]).is_empty());
+		assert_eq!(
+			missing("user", &["read:user", "read:org"]),
+			vec!["read:org"]
+		);
+		assert_eq!(
+			missing("", GH_REQUIRED_SCOPES),
+			vec!["read:user", "read:org"]
+		);
+		assert_eq!(missing("read:user", &["user"]), vec!["user"]);
+		assert!(missing("repo", &["public_repo"]).is_empty());
+
+		// Microsoft scopes have to match exactly
+		assert_eq!(
+			missing_scopes(
+				AuthProvider::Microsoft,
+				&parse_scopes("openid"),
+				&["profile"]
+			),
+			vec!["profile"]
+		);
+	}
+
+	#[test]
+	fn test_requested_scopes() {
+		let (_dir, paths) = temp_paths();
+		let options = AuthOptions {
+			extra_scopes: vec!["read:org".to_string(), "user".to_string()],
+			..Default::default()
+		};
+		let auth = Auth::with_options(&paths, log::Logger::test(), &options);
+		assert_eq!(
+			AuthProvider::Github.default_scopes(),
+			vec!["read:user", "read:org"]
+		);
+
+		// broader scopes replace the narrower ones they include
+		let previous = StoredCredential {
+			scopes: Some(parse_scopes("read:user gist")),
+			..credential("a")
+		};
+		assert_eq!(
+			auth.requested_scopes(AuthProvider::Github, Some(&previous)),
+			vec!["read:org", "user", "gist"]
+		);
+	}
+
+	#[test]
+	fn test_require_scopes() {
+		let creds = StoredCredential {
+			scopes: Some(parse_scopes("read:user")),
+			..credential("a")
+		};
+		assert!(require_scopes(&creds, &["read:user"]).is_ok());
+		assert!(matches!(
+			require_scopes(&creds, GH_REQUIRED_SCOPES),
+			Err(AnyError::CodeError(CodeError::MissingAuthScopes(s))) if s == "read:org"
+		));
+
+		// unknown scopes are assumed to be there
+		assert!(require_scopes(&credential("a"), &["read:org"]).is_ok());
+
+		let pat = StoredCredential {
+			personal_access_token: true,
+			..creds
+		};
+		assert!(matches!(
+			require_scopes(&pat, &["read:org"]),
+			Err(AnyError::CodeError(CodeError::GithubTokenMissingScopes(_)))
+		));
+	}
+
+	/// Serves responses from the handler, which gets the request path and the
+	/// number of earlier requests to that path. Returns the server's base URL.
+	fn mock_server<F>(handler: F) -> String
+	where
+		F: Fn(&str, usize) -> hyper::Response<hyper::Body> + Send + Sync + 'static,
+	{
+		let handler = Arc::new(handler);
+		let counts = Arc::new(std::sync::Mutex::new(HashMap::<String, usize>::new()));
+		let make_svc = make_service_fn(move |_| {
+			let handler = handler.clone();
+			let counts = counts.clone();
+			async move {
+				Ok::<_, Infallible>(service_fn(move |req| {
+					let path = req.uri().path().to_string();
+					let n = {
+						let mut counts = counts.lock().unwrap();
+						let n = counts.entry(path.clone()).or_default();
+						*n += 1;
+						*n - 1
+					};
+					let res = handler(&path, n);
+					async move { Ok::<_, Infallible>(res) }
+				}))
+			}
+		});
+
+		let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+		let url = format!("http://{}", server.local_addr());
+		tokio::spawn(server);
+		url
+	}
+
+	fn json_response(status: u16, body: &str) -> hyper::Response<hyper::Body> {
+		hyper::Response::builder()
+			.status(status)
+			.header("Content-Type", "application/json")
+			.body(hyper::Body::from(body.to_string()))
+			.unwrap()
+	}
+
+	/// Serves a fake GitHub user endpoint that responds with the given status
+	/// and scopes header, returning its URL.
+	fn mock_user_endpoint(status: u16, scopes: Option<&'static str>) -> String {
+		let base = mock_server(move |_, _| {
+			let mut res = json_response(status, "{}");
+			if let Some(s) = scopes {
+				res.headers_mut()
+					.insert("X-OAuth-Scopes", hyper::header::HeaderValue::from_static(s));
+			}
+			res
+		});
+		format!("{}/user", base)
+	}
+
+	fn test_auth(paths: &LauncherPaths) -> Auth {
+		let mut auth = Auth::new(paths, log::Logger::test());
+		auth.client = reqwest::Client::builder().no_proxy().build().unwrap();
+		auth
+	}
+
+	const DEVICE_CODE_RESPONSE: &str = r#"{"device_code":"dc","user_code":"ABCD-1234","verification_uri":"https://example.com/device","expires_in":900,"interval":0}"#;
+
+	#[tokio::test]
+	async fn test_start_and_continue_login() {
+		let base = mock_server(|path, n| match (path, n) {
+			("/code", _) => json_response(200, DEVICE_CODE_RESPONSE),
+			("/token", 0) => json_response(400, r#"{"error":"slow_down"}"#),
+			("/token", 1) => json_response(400, r#"{"error":"authorization_pending"}"#),
+			("/token", _) => json_response(200, r#"{"access_token":"granted","expires_in":3600}"#),
+			_ => json_response(404, "{}"),
+		});
+
+		let (_dir, paths) = temp_paths();
+		let auth = test_auth(&paths);
+		let started = auth
+			.start_login_at(AuthProvider::Github, &format!("{}/code", base))
+			.await
+			.unwrap();
+		assert_eq!(started.user_code, "ABCD-1234");
+		assert_eq!(started.verification_uri, "https://example.com/device");
+
+		// the handle survives across processes
+		let auth = test_auth(&paths);
+		let creds = auth
+			.continue_login_at(
+				&started.handle,
+				Some(&format!("{}/token", base)),
+				Duration::from_millis(10),
+			)
+			.await
+			.unwrap();
+		assert_eq!(creds.access_token, "granted");
+		assert!(creds.expires_at.is_some());
+
+		let err = auth
+			.continue_login_at(
+				&started.handle,
+				Some(&format!("{}/token", base)),
+				Duration::ZERO,
+			)
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::UnknownLoginHandle(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_continue_login_expired_code() {
+		let base = mock_server(|path, _| match path {
+			"/code" => json_response(200, DEVICE_CODE_RESPONSE),
+			_ => json_response(400, r#"{"error":"expired_token"}"#),
+		});
+
+		let (_dir, paths) = temp_paths();
+		let auth = test_auth(&paths);
+		let started = auth
+			.start_login_at(AuthProvider::Github, &format!("{}/code", base))
+			.await
+			.unwrap();
+
+		let err = auth
+			.continue_login_at(
+				&started.handle,
+				Some(&format!("{}/token", base)),
+				Duration::ZERO,
+			)
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::DeviceCodeExpired)
+		));
+		assert!(auth.pending_logins.get(&started.handle).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_shares_connections_with_other_requests() {
+		use crate::util::http::{ReqwestSimpleHttp, SimpleHttp};
+		use std::sync::atomic::{AtomicUsize, Ordering};
+		use tokio::io::AsyncReadExt;
+
+		let connections = Arc::new(AtomicUsize::new(0));
+		let counted = connections.clone();
+		let make_svc = make_service_fn(move |_| {
+			counted.fetch_add(1, Ordering::SeqCst);
+			async {
+				Ok::<_, Infallible>(service_fn(|_| async {
+					Ok::<_, Infallible>(json_response(200, "{}"))
+				}))
+			}
+		});
+		let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+		let base = format!("http://{}", server.local_addr());
+		tokio::spawn(server);
+
+		let (_dir, paths) = temp_paths();
+		let auth = Auth::new(&paths, log::Logger::test());
+		get_github_user_at(&auth.client, &format!("{}/user", base), "t")
+			.await
+			.unwrap()
+			.bytes()
+			.await
+			.unwrap();
+
+		let mut res = ReqwestSimpleHttp::new()
+			.make_request("GET", format!("{}/update", base))
+			.await
+			.unwrap();
+		res.read.read_to_end(&mut vec![]).await.unwrap();
+
+		shared_client()
+			.get(format!("{}/tunnels", base))
+			.send()
+			.await
+			.unwrap()
+			.bytes()
+			.await
+			.unwrap();
+
+		assert_eq!(connections.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn test_validate_github_token() {
+		let client = reqwest::Client::builder().no_proxy().build().unwrap();
+
+		let url = mock_user_endpoint(200, Some("repo, read:user, read:org"));
+		validate_github_token(&client, &url, "pat").await.unwrap();
+
+		// fine-grained tokens don't report scopes
+		let url = mock_user_endpoint(200, None);
+		validate_github_token(&client, &url, "pat").await.unwrap();
+
+		let url = mock_user_endpoint(200, Some("user, admin:org"));
+		validate_github_token(&client, &url, "pat").await.unwrap();
+
+		let url = mock_user_endpoint(200, Some("repo, user"));
+		let err = validate_github_token(&client, &url, "pat")
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::GithubTokenMissingScopes(ref s)) if s == "read:org"
+		));
+
+		let url = mock_user_endpoint(401, None);
+		let err = validate_github_token(&client, &url, "pat")
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::GithubTokenRejected(401))
+		));
+	}
+
+	fn encrypted_storage(
+		dir: &tempfile::TempDir,
+		passphrase: Option<&str>,
+	) -> EncryptedFileStorage {
+		let mut storage = EncryptedFileStorage::new(dir.path().join("token.enc.json"), passphrase);
+		storage.iterations = 10;
+		storage
+	}
+
+	#[test]
+	fn test_encrypted_storage_round_trip() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut storage = encrypted_storage(&dir, Some("hunter2"));
+		assert!(storage.read().unwrap().is_none());
+
+		storage.store(credential("secret-token")).unwrap();
+		let contents = std::fs::read_to_string(dir.path().join("token.enc.json")).unwrap();
+		assert!(!contents.contains("secret-token"));
+
+		let mut storage = encrypted_storage(&dir, Some("hunter2"));
+		assert_eq!(
+			storage.read().unwrap().unwrap().access_token,
+			"secret-token"
+		);
+
+		storage.clear().unwrap();
+		assert!(storage.read().unwrap().is_none());
+	}
+
+	#[test]
+	fn test_encrypted_storage_wrong_passphrase() {
+		let dir = tempfile::tempdir().unwrap();
+		encrypted_storage(&dir, Some("hunter2"))
+			.store(credential("secret-token"))
+			.unwrap();
+
+		for passphrase in [Some("hunter3"), None] {
+			let err = encrypted_storage(&dir, passphrase).read().unwrap_err();
+			assert!(matches!(
+				err,
+				AnyError::CodeError(CodeError::TokenStoreWrongPassphrase)
+			));
+		}
+	}
+
+	#[test]
+	fn test_encrypted_storage_tampered() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("token.enc.json");
+		encrypted_storage(&dir, None)
+			.store(credential("secret-token"))
+			.unwrap();
+
+		let mut envelope: serde_json::Value =
+			serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+		envelope["data"] = serde_json::Value::String("AAAA".to_string());
+		std::fs::write(&path, envelope.to_string()).unwrap();
+		let err = encrypted_storage(&dir, None).read().unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::TokenStoreCorrupted(_))
+		));
+
+		std::fs::write(&path, "garbage").unwrap();
+		let err = encrypted_storage(&dir, None).read().unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::TokenStoreCorrupted(_))
+		));
+	}
+
+	#[test]
+	fn test_migrate_credential() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut keyring = KeyringStorage::<FakeEntry>::new(DEFAULT_AUTH_PROFILE);
+		let mut encrypted = encrypted_storage(&dir, None);
+
+		assert!(!migrate_credential(&mut keyring, &mut encrypted).unwrap());
+
+		keyring.store(credential("moved")).unwrap();
+		assert!(migrate_credential(&mut keyring, &mut encrypted).unwrap());
+		assert!(fake_keys().is_empty());
+		assert_eq!(encrypted.read().unwrap().unwrap().access_token, "moved");
+
+		assert!(migrate_credential(&mut encrypted, &mut keyring).unwrap());
+		assert!(encrypted.read().unwrap().is_none());
+		assert_eq!(keyring.read().unwrap().unwrap().access_token, "moved");
+	}
+
+	struct FakeClock {
+		now: std::sync::Mutex<DateTime<Utc>>,
+		sleeps: std::sync::Mutex<Vec<u64>>,
+	}
+
+	impl FakeClock {
+		fn new(now: DateTime<Utc>) -> Self {
+			FakeClock {
+				now: std::sync::Mutex::new(now),
+				sleeps: std::sync::Mutex::new(vec![]),
+			}
+		}
+	}
+
+	#[async_trait]
+	impl Clock for FakeClock {
+		fn now(&self) -> DateTime<Utc> {
+			*self.now.lock().unwrap()
+		}
+
+		async fn sleep(&self, duration: Duration) {
+			self.sleeps.lock().unwrap().push(duration.as_secs());
+			*self.now.lock().unwrap() += chrono::Duration::from_std(duration).unwrap();
+		}
+	}
+
+	/// Refreshes tokens against a mocked token endpoint.
+	struct MockRefresher {
+		client: reqwest::Client,
+		grant_uri: String,
+		stored: std::sync::Mutex<Vec<String>>,
+	}
+
+	#[async_trait]
+	impl CredentialRefresher for MockRefresher {
+		async fn refresh(
+			&self,
+			creds: &StoredCredential,
+			clock: &dyn Clock,
+		) -> Result<Option<StoredCredential>, AnyError> {
+			let body = format!(
+				"grant_type=refresh_token&refresh_token={}",
+				creds.refresh_token.as_ref().unwrap()
+			);
+			do_grant(
+				&self.client,
+				&self.grant_uri,
+				creds.provider,
+				creds.cloud,
+				body,
+				clock,
+			)
+			.await
+			.map(Some)
+		}
+
+		fn store(&self, creds: StoredCredential) {
+			self.stored.lock().unwrap().push(creds.access_token);
+		}
+	}
+
+	#[test]
+	fn test_refresh_schedule() {
+		let now = Utc::now();
+		let margin = DEFAULT_TOKEN_REFRESH_MARGIN;
+		let in_secs = |s| Some(now + chrono::Duration::seconds(s));
+
+		assert_eq!(
+			next_refresh_delay(now, in_secs(3600), margin).as_secs(),
+			3000
+		);
+		assert_eq!(next_refresh_delay(now, in_secs(600), margin).as_secs(), 300);
+		assert_eq!(next_refresh_delay(now, in_secs(-60), margin).as_secs(), 10);
+		assert_eq!(next_refresh_delay(now, None, margin).as_secs(), 3600);
+
+		let retries: Vec<_> = (1..=8).map(|n| refresh_retry_delay(n).as_secs()).collect();
+		assert_eq!(retries, vec![10, 20, 40, 80, 160, 320, 600, 600]);
+	}
+
+	#[tokio::test]
+	async fn test_keepalive_retries_and_reports_failures() {
+		let base = mock_server(|_, n| match n {
+			0 | 1 => json_response(500, "oops"),
+			2 => json_response(
+				200,
+				r#"{"access_token":"new","refresh_token":"r2","expires_in":3600}"#,
+			),
+			_ => json_response(400, "{}"),
+		});
+
+		// a fixed time, so that only the fake clock moves it
+		let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+			.unwrap()
+			.with_timezone(&Utc);
+		let clock = FakeClock::new(start);
+		let refresher = MockRefresher {
+			client: reqwest::Client::builder().no_proxy().build().unwrap(),
+			grant_uri: format!("{}/token", base),
+			stored: std::sync::Mutex::new(vec![]),
+		};
+		let credential = StoredCredential {
+			provider: AuthProvider::Microsoft,
+			refresh_token: Some("r1".to_string()),
+			expires_at: Some(start + chrono::Duration::minutes(20)),
+			..credential("old")
+		};
+
+		let mut events = vec![];
+		let result = run_token_keepalive(
+			&log::Logger::test(),
+			&clock,
+			&refresher,
+			credential,
+			DEFAULT_TOKEN_REFRESH_MARGIN,
+			|e| {
+				events.push(match e {
+					TokenRefreshEvent::Started { .. } => "started".to_string(),
+					TokenRefreshEvent::Refreshed { expires_at, .. } => {
+						assert!(expires_at.is_some());
+						"refreshed".to_string()
+					}
+					TokenRefreshEvent::Failed {
+						failures, retry_at, ..
+					} => format!("failed {} {}", failures, retry_at.is_some()),
+				})
+			},
+		)
+		.await;
+
+		assert!(matches!(result, Err(AnyError::StatusError(e)) if e.status_code == 400));
+		assert_eq!(
+			events,
+			vec![
+				"started",
+				"failed 1 true",
+				"failed 2 true",
+				"refreshed",
+				"failed 1 false"
+			]
+		);
+		// refreshes 10 minutes before expiry, then backs off on errors, then
+		// waits until 10 minutes before the new token expires
+		assert_eq!(*clock.sleeps.lock().unwrap(), vec![600, 10, 20, 3000]);
+		assert_eq!(*refresher.stored.lock().unwrap(), vec!["new"]);
+	}
+
+	#[test]
+	fn test_credential_info_from_store() {
+		let (_dir, paths) = temp_paths();
+		let options = AuthOptions {
+			token_storage: Some(TokenStorageKind::File),
+			..Default::default()
+		};
+		let auth = Auth::with_options(&paths, log::Logger::test(), &options);
+		assert!(auth.get_credential_info().unwrap().is_none());
+
+		let refreshed_at = Utc::now();
+		auth.store_credentials(StoredCredential {
+			refresh_token: Some("secret-refresh".to_string()),
+			account: Some("octocat".to_string()),
+			scopes: Some(parse_scopes("read:user read:org")),
+			refreshed_at: Some(refreshed_at),
+			..credential("secret-access")
+		});
+
+		// read through a new instance, as a separate command would
+		let info = Auth::with_options(&paths, log::Logger::test(), &options)
+			.get_credential_info()
+			.unwrap()
+			.unwrap();
+		assert_eq!(info.profile, DEFAULT_AUTH_PROFILE);
+		assert_eq!(info.account.as_deref(), Some("octocat"));
+		assert_eq!(
+			info.scopes,
+			Some(vec!["read:user".into(), "read:org".into()])
+		);
+		assert_eq!(info.storage, TokenStorageKind::File);
+		assert_eq!(info.refreshed_at, Some(refreshed_at));
+		assert!(info.has_refresh_token);
+		assert!(info.validation.is_none());
+
+		let json = serde_json::to_string(&info).unwrap();
+		assert!(!json.contains("secret"));
+	}
+
+	#[tokio::test]
+	async fn test_validate_credential() {
+		let base = mock_server(|path, _| {
+			let (status, scopes) = match path {
+				"/ok" => (200, "read:user, read:org"),
+				"/scopes" => (200, "gist"),
+				_ => (401, ""),
+			};
+			let mut res = json_response(status, r#"{"login":"octocat"}"#);
+			res.headers_mut().insert(
+				"X-OAuth-Scopes",
+				hyper::header::HeaderValue::from_static(scopes),
+			);
+			res
+		});
+		let client = reqwest::Client::builder().no_proxy().build().unwrap();
+		let creds = credential("token");
+
+		let v = validate_credential_at(&client, &format!("{}/ok", base), &creds)
+			.await
+			.unwrap();
+		assert!(v.valid);
+		assert_eq!(v.account.as_deref(), Some("octocat"));
+		assert!(v.missing_scopes.is_empty());
+
+		let v = validate_credential_at(&client, &format!("{}/scopes", base), &creds)
+			.await
+			.unwrap();
+		assert!(!v.valid);
+		assert_eq!(v.missing_scopes, vec!["read:user", "read:org"]);
+
+		let v = validate_credential_at(&client, &format!("{}/revoked", base), &creds)
+			.await
+			.unwrap();
+		assert!(!v.valid);
+		assert_eq!(v.account, None);
+		assert!(v.reason.unwrap().contains("401"));
+	}
+
+	#[test]
+	fn test_account_from_id_token() {
+		let claims = b64::URL_SAFE_NO_PAD.encode(r#"{"preferred_username":"me@contoso.com"}"#);
+		let token = format!("header.{}.signature", claims);
+		assert_eq!(
+			account_from_id_token(&token).as_deref(),
+			Some("me@contoso.com")
+		);
+		assert_eq!(account_from_id_token("not-a-jwt"), None);
+	}
+
+	#[tokio::test]
+	async fn test_device_code_flow_sequence() {
+		let base = mock_server(|path, n| match (path, n) {
+			("/code", n) => json_response(
+				200,
+				&format!(
+					r#"{{"device_code":"dc{}","user_code":"CODE-{}","verification_uri":"https://example.com/device","expires_in":900,"interval":5}}"#,
+					n, n
+				),
+			),
+			("/token", 1) => json_response(400, r#"{"error":"slow_down"}"#),
+			("/token", 3) => json_response(400, r#"{"error":"expired_token"}"#),
+			("/token", 5) => json_response(200, r#"{"access_token":"granted","expires_in":3600}"#),
+			("/token", _) => json_response(400, r#"{"error":"authorization_pending"}"#),
+			_ => json_response(404, "{}"),
+		});
+
+		let (_dir, paths) = temp_paths();
+		let auth = test_auth(&paths);
+		let clock = FakeClock::new(Utc::now());
+		let creds = auth
+			.do_device_code_flow_at(
+				AuthProvider::Github,
+				&format!("{}/code", base),
+				&format!("{}/token", base),
+				&AuthProvider::Github.default_scopes(),
+				&clock,
+				false,
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(creds.access_token, "granted");
+		// the interval grows on slow_down, and resets with the new code
+		// that's requested once the first one expires
+		assert_eq!(*clock.sleeps.lock().unwrap(), vec![5, 5, 10, 10, 5, 5]);
+	}
+
+	#[test]
+	fn test_headless_provider_choice() {
+		let (_dir, paths) = temp_paths();
+		assert_eq!(get_default_auth_provider(&paths), None);
+		let err = test_auth(&paths)
+			.prompt_for_provider_with(false)
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::NoDefaultAuthProvider)
+		));
+		assert!(err.to_string().contains("set-default-provider"));
+
+		set_default_auth_provider(&paths, AuthProvider::Microsoft).unwrap();
+		let auth = test_auth(&paths);
+		assert_eq!(auth.default_provider(), Some(AuthProvider::Microsoft));
+		assert_eq!(
+			auth.prompt_for_provider_with(false).unwrap(),
+			AuthProvider::Microsoft
+		);
+
+		// options override the configured default
+		let options = AuthOptions {
+			default_provider: Some(AuthProvider::Github),
+			..Default::default()
+		};
+		let auth = Auth::with_options(&paths, log::Logger::test(), &options);
+		assert_eq!(
+			auth.prompt_for_provider_with(false).unwrap(),
+			AuthProvider::Github
+		);
+	}
+
+	#[tokio::test]
+	async fn test_upgrade_scopes() {
+		let requested = Arc::new(std::sync::Mutex::new(vec![]));
+		let requested_ = requested.clone();
+		let make_svc = make_service_fn(move |_| {
+			let requested = requested_.clone();
+			async move {
+				Ok::<_, Infallible>(service_fn(move |req: hyper::Request<hyper::Body>| {
+					let requested = requested.clone();
+					async move {
+						let path = req.uri().path().to_string();
+						let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+						let res = match path.as_str() {
+							"/code" => {
+								requested
+									.lock()
+									.unwrap()
+									.push(String::from_utf8_lossy(&body).to_string());
+								json_response(200, DEVICE_CODE_RESPONSE)
+							}
+							_ => json_response(200, r#"{"access_token":"upgraded"}"#),
+						};
+						Ok::<_, Infallible>(res)
+					}
+				}))
+			}
+		});
+		let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+		let base = format!("http://{}", server.local_addr());
+		tokio::spawn(server);
+
+		let (_dir, paths) = temp_paths();
+		let options = AuthOptions {
+			profile: Some("work".to_string()),
+			token_storage: Some(TokenStorageKind::File),
+			extra_scopes: vec!["read:org".to_string()],
+			..Default::default()
+		};
+		let mut auth = Auth::with_options(&paths, log::Logger::test(), &options);
+		auth.client = reqwest::Client::builder().no_proxy().build().unwrap();
+		let clock = FakeClock::new(Utc::now());
+		let (code_uri, grant_uri) = (format!("{}/code", base), format!("{}/token", base));
+
+		assert!(matches!(
+			auth.upgrade_scopes_at(&code_uri, &grant_uri, &clock, false)
+				.await,
+			Err(AnyError::CodeError(CodeError::UpgradeScopesNotLoggedIn))
+		));
+
+		auth.store_credentials(StoredCredential {
+			account: Some("octocat".to_string()),
+			scopes: Some(parse_scopes("read:user gist")),
+			..credential("original")
+		});
+		assert!(matches!(
+			require_scopes(
+				&auth.get_current_credential().unwrap().unwrap(),
+				&["read:org"]
+			),
+			Err(AnyError::CodeError(CodeError::MissingAuthScopes(_)))
+		));
+
+		let creds = auth
+			.upgrade_scopes_at(&code_uri, &grant_uri, &clock, false)
+			.await
+			.unwrap();
+		assert_eq!(
+			*requested.lock().unwrap(),
+			vec![format!(
+				"client_id={}&scope=read:user+read:org+gist",
+				AuthProvider::Github.client_id()
+			)]
+		);
+
+		// the upgraded token replaces the old one in the same profile
+		assert_eq!(creds.access_token, "upgraded");
+		assert_eq!(creds.account.as_deref(), Some("octocat"));
+		let stored = Auth::with_options(&paths, log::Logger::test(), &options)
+			.get_current_credential()
+			.unwrap()
+			.unwrap();
+		assert_eq!(stored.access_token, "upgraded");
+		assert!(require_scopes(&stored, &["read:user", "read:org", "gist"]).is_ok());
+
+		auth.store_credentials(StoredCredential {
+			personal_access_token: true,
+			..credential("pat")
+		});
+		assert!(matches!(
+			auth.upgrade_scopes_at(&code_uri, &grant_uri, &clock, false)
+				.await,
+			Err(AnyError::CodeError(
+				CodeError::UpgradeScopesPersonalAccessToken
+			))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_device_code_poll_ends() {
+		let base = mock_server(|path, _| match path {
+			"/denied" => json_response(400, r#"{"error":"access_denied"}"#),
+			_ => json_response(400, r#"{"error":"authorization_pending"}"#),
+		});
+		let client = reqwest::Client::builder().no_proxy().build().unwrap();
+		let start = Utc::now();
+		let clock = FakeClock::new(start);
+		let poll = |grant_uri| DeviceCodePoll {
+			provider: AuthProvider::Github,
+			cloud: AuthCloud::Public,
+			grant_uri,
+			device_code: "dc",
+			expires_at: start + chrono::Duration::seconds(12),
+			interval: Duration::from_secs(5),
+			slow_down: DEVICE_CODE_SLOW_DOWN,
+			clock: &clock,
+			countdown: false,
+		};
+
+		let pending_uri = format!("{}/pending", base);
+		let err = poll(&pending_uri)
+			.run(&client, &log::Logger::test())
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::DeviceCodeExpired)
+		));
+		assert_eq!(*clock.sleeps.lock().unwrap(), vec![5, 5, 5]);
+
+		*clock.now.lock().unwrap() = start;
+		let denied_uri = format!("{}/denied", base);
+		let err = poll(&denied_uri)
+			.run(&client, &log::Logger::test())
+			.await
+			.unwrap_err();
+		assert!(matches!(err, AnyError::CodeError(CodeError::LoginDenied)));
+	}
+
+	#[test]
+	fn test_cloud_endpoints() {
+		assert_eq!(
+			AuthProvider::Microsoft.grant_uri(AuthCloud::Usgov),
+			"https://login.microsoftonline.us/organizations/oauth2/v2.0/token"
+		);
+		assert_eq!(
+			AuthProvider::Microsoft.code_uri(AuthCloud::China),
+			"https://login.chinacloudapi.cn/organizations/oauth2/v2.0/devicecode"
+		);
+		assert_eq!(
+			AuthProvider::Github.grant_uri(AuthCloud::China),
+			AuthProvider::Github.grant_uri(AuthCloud::Public)
+		);
+		assert_eq!(
+			AuthProvider::Github.cloud_for(AuthCloud::Usgov),
+			AuthCloud::Public
+		);
+
+		let e = explain_cloud_error(
+			AuthProvider::Microsoft,
+			AuthCloud::Usgov,
+			OAuthError {
+				error: "invalid_request".to_string(),
+				error_description: Some("AADSTS90002: Tenant 'x' not found.".to_string()),
+			}
+			.into(),
+		);
+		assert!(matches!(
+			e,
+			AnyError::CodeError(CodeError::AuthCloudRejected(c, _)) if c == "usgov"
+		));
+	}
+
+	#[test]
+	fn test_cloud_persisted_per_profile() {
+		let (_dir, paths) = temp_paths();
+		let options = AuthOptions {
+			token_storage: Some(TokenStorageKind::File),
+			auth_cloud: Some(AuthCloud::Usgov),
+			..Default::default()
+		};
+		let auth = Auth::with_options(&paths, log::Logger::test(), &options);
+		assert_eq!(auth.cloud(), AuthCloud::Usgov);
+		auth.store_credentials(StoredCredential {
+			provider: AuthProvider::Microsoft,
+			cloud: AuthCloud::Usgov,
+			..credential("gov-token")
+		});
+
+		let profiles = AuthProfiles::new(&paths);
+		assert_eq!(profiles.cloud(DEFAULT_AUTH_PROFILE), AuthCloud::Usgov);
+		assert_eq!(profiles.cloud("work"), AuthCloud::Public);
+
+		// later commands use the profile's cloud without the option
+		let options = AuthOptions {
+			token_storage: Some(TokenStorageKind::File),
+			..Default::default()
+		};
+		let auth = Auth::with_options(&paths, log::Logger::test(), &options);
+		assert_eq!(auth.cloud(), AuthCloud::Usgov);
+		assert_eq!(
+			auth.get_credential_info().unwrap().unwrap().cloud,
+			AuthCloud::Usgov
+		);
+
+		profiles
+			.set_cloud(DEFAULT_AUTH_PROFILE, AuthCloud::Public)
+			.unwrap();
+		assert_eq!(profiles.cloud(DEFAULT_AUTH_PROFILE), AuthCloud::Public);
+	}
+
+	#[tokio::test]
+	async fn test_revoke_credential() {
+		let base = mock_server(|path, _| match path {
+			"/revoke" => json_response(202, "{}"),
+			"/revoke-ok" => json_response(200, "{}"),
+			"/revoked" => json_response(401, r#"{"message":"Bad credentials"}"#),
+			_ => json_response(500, "oops"),
+		});
+		let client = reqwest::Client::builder().no_proxy().build().unwrap();
+		let creds = credential("token");
+
+		let revoke = |path: &str| {
+			let url = format!("{}{}", base, path);
+			let client = client.clone();
+			let creds = creds.clone();
+			async move { revoke_credential_at(&client, &url, &creds).await }
+		};
+
+		assert_eq!(revoke("/revoke").await.unwrap(), Revocation::Revoked);
+		assert_eq!(revoke("/revoke-ok").await.unwrap(), Revocation::Revoked);
+		// already revoked tokens are rejected, which is just as good
+		assert_eq!(
+			revoke("/revoked").await.unwrap(),
+			Revocation::AlreadyRevoked
+		);
+		assert!(matches!(
+			revoke("/error").await,
+			Err(AnyError::StatusError(e)) if e.status_code == 500
+		));
+
+		let microsoft = StoredCredential {
+			provider: AuthProvider::Microsoft,
+			..credential("token")
+		};
+		assert_eq!(
+			revoke_credential_at(&client, &base, &microsoft)
+				.await
+				.unwrap(),
+			Revocation::Unsupported
+		);
+	}
+
+	struct HangingEntry;
+
+	impl KeyringEntry for HangingEntry {
+		fn open(_name: &str) -> Self {
+			HangingEntry
+		}
+
+		fn get_password(&self) -> keyring::Result<String> {
+			thread::sleep(Duration::from_secs(60));
+			Err(keyring::Error::NoEntry)
+		}
+
+		fn set_password(&self, _password: &str) -> keyring::Result<()> {
+			thread::sleep(Duration::from_secs(60));
+			Ok(())
+		}
+
+		fn delete_password(&self) -> keyring::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_keyring_timeout() {
+		let mut storage = ThreadKeyringStorage::<HangingEntry>::new(
+			DEFAULT_AUTH_PROFILE,
+			Duration::from_millis(50),
+		);
+		let start = std::time::Instant::now();
+		let err = storage.read().unwrap_err();
+		assert!(start.elapsed() < Duration::from_secs(5));
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::KeyringTimeout(0))
+		));
+
+		// the storage is abandoned to the hung thread
+		assert!(storage.store(credential("a")).is_err());
+	}
+
+	fn open_hanging_keyring(profile: &str, timeout: Duration) -> Box<dyn StorageImplementation> {
+		Box::new(ThreadKeyringStorage::<HangingEntry>::new(profile, timeout))
+	}
+
+	#[test]
+	fn test_keyring_opened_on_first_use() {
+		let (_dir, paths) = temp_paths();
+		let options = AuthOptions {
+			keyring_timeout: Some(Duration::from_millis(500)),
+			..Default::default()
+		};
+
+		// nothing that a command does before needing credentials waits on it
+		let start = std::time::Instant::now();
+		let mut auth = Auth::with_options(&paths, log::Logger::test(), &options);
+		auth.open_keyring = open_hanging_keyring;
+		assert_eq!(auth.profile(), DEFAULT_AUTH_PROFILE);
+		assert_eq!(auth.default_provider(), None);
+		assert_eq!(
+			auth.credential_store_state(),
+			CredentialStoreState::NotInitialized
+		);
+		assert!(start.elapsed() < Duration::from_millis(100));
+
+		let reader = auth.clone();
+		let read = thread::spawn(move || reader.get_current_credential().unwrap().is_none());
+		thread::sleep(Duration::from_millis(100));
+		assert_eq!(
+			auth.credential_store_state(),
+			CredentialStoreState::Initializing
+		);
+
+		// falls back to file storage once the keyring times out
+		assert!(read.join().unwrap());
+		let open = CredentialStoreState::Open {
+			storage: TokenStorageKind::File,
+		};
+		assert_eq!(auth.credential_store_state(), open);
+
+		// and stays open while it's busy reading or storing
+		let busy = auth.clone();
+		let store =
+			thread::spawn(move || busy.with_storage(|_| thread::sleep(Duration::from_millis(300))));
+		thread::sleep(Duration::from_millis(100));
+		assert_eq!(auth.credential_store_state(), open);
+		store.join().unwrap();
+	}
+
+	#[test]
+	fn test_keyring_probe_cache() {
+		let (_dir, paths) = temp_paths();
+		let probe = KeyringProbe::new(&paths);
+		let t0 = Utc::now();
+		assert!(!probe.is_known_unavailable(t0));
+		assert!(probe.last_result().is_none());
+
+		probe.record(false, t0);
+		assert!(probe.is_known_unavailable(t0 + chrono::Duration::hours(1)));
+		assert!(!probe.is_known_unavailable(t0 + chrono::Duration::hours(25)));
+
+		// the hint is shown once per day
+		assert!(probe.take_hint(t0));
+		assert!(!probe.take_hint(t0 + chrono::Duration::hours(1)));
+		assert!(probe.take_hint(t0 + chrono::Duration::hours(25)));
+
+		probe.record(true, t0 + chrono::Duration::hours(2));
+		assert!(!probe.is_known_unavailable(t0 + chrono::Duration::hours(2)));
+		assert_eq!(
+			KeyringProbe::new(&paths).last_result(),
+			Some((true, t0 + chrono::Duration::hours(2)))
+		);
+	}
+
+	#[tokio::test]
+	async fn test_policy_limits_login_providers() {
+		// configures the policy, so it's run in a child
+		if !crate::util::test_env::in_child_with_env(
+			concat!(module_path!(), "::test_policy_limits_login_providers"),
+			&[],
+		) {
+			return;
+		}
+		policy::configure(policy::Policy {
+			source: Some("test".to_string()),
+			allowed_auth_providers: Some(vec![AuthProvider::Github]),
+			..Default::default()
+		});
+
+		let (_dir, paths) = temp_paths();
+		let auth = test_auth(&paths);
+		let err = auth
+			.login(Some(AuthProvider::Microsoft), Some("t".to_string()), None)
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::AuthProviderDisabledByPolicy(
+				AuthProvider::Microsoft,
+				_
+			))
+		));
+		assert!(auth
+			.start_login(Some(AuthProvider::Microsoft))
+			.await
+			.is_err());
+	}
 }
@@ -59,6 +59,7 @@ pub fn try_parse_legacy(
 		extensions_dir: get_first_arg_value("extensions-dir"),
 		user_data_dir: get_first_arg_value("user-data-dir"),
 		use_version: None,
+		auto_provision: false,
 	};
 
 	// Now translate them to subcommands.
@@ -86,6 +87,8 @@ pub fn try_parse_legacy(
 					id_or_path: exts,
 					pre_release: args.contains_key("pre-release"),
 					force: args.contains_key("force"),
+					json: false,
+					concurrency: 3,
 				}),
 				desktop_code_options,
 			})),
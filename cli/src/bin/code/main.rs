@@ -4,47 +4,105 @@
  *--------------------------------------------------------------------------------------------*/
 mod legacy_args;
 
-use std::process::Command;
+use std::{
+	path::PathBuf,
+	process::{Command, Stdio},
+	sync::Arc,
+	time::Duration,
+};
 
-use clap::Parser;
 use cli::{
-	commands::{args, serve_web, tunnels, update, version, CommandContext},
-	constants::get_default_user_agent,
-	desktop, log,
+	commands::{
+		args::{self, OutputFormat},
+		cache, config, extensions, machine_status, manpages,
+		output::{self, ExtensionListOutput, PrerequisitesFailedOutput},
+		serve_web, tunnels, update, version, CommandContext,
+	},
+	desktop, log, policy, rpc_trace,
 	state::LauncherPaths,
+	update_service::UpdateService,
 	util::{
-		errors::{wrap, AnyError},
-		is_integrated_cli,
-		prereqs::PreReqChecker,
+		command::{new_tokio_command, run_command, RunOptions},
+		errors::{wrap, AnyError, CodeError},
+		http::{shared_client, ReqwestSimpleHttp},
+		input, is_integrated_cli, progress,
 	},
 };
 use legacy_args::try_parse_legacy;
 use opentelemetry::sdk::trace::TracerProvider as SdkTracerProvider;
 use opentelemetry::trace::TracerProvider;
 
-#[tokio::main]
-async fn main() -> Result<(), std::convert::Infallible> {
+fn main() -> Result<(), std::convert::Infallible> {
+	// sockets passed by systemd are received, and their environment variables
+	// cleared, while the process still has a single thread
+	#[cfg(unix)]
+	cli::util::socket_activation::receive_listen_fds();
+
+	tokio::runtime::Builder::new_multi_thread()
+		.enable_all()
+		.build()
+		.expect("failed to start the async runtime")
+		.block_on(async_main())
+}
+
+async fn async_main() -> Result<(), std::convert::Infallible> {
 	let raw_args = std::env::args_os().collect::<Vec<_>>();
 	let parsed = try_parse_legacy(&raw_args)
 		.map(|core| args::AnyCli::Integrated(args::IntegratedCli { core }))
 		.unwrap_or_else(|| {
 			if let Ok(true) = is_integrated_cli() {
-				args::AnyCli::Integrated(args::IntegratedCli::parse_from(&raw_args))
+				args::AnyCli::Integrated(config::parse_with_config(&raw_args))
 			} else {
-				args::AnyCli::Standalone(args::StandaloneCli::parse_from(&raw_args))
+				args::AnyCli::Standalone(config::parse_with_config(&raw_args))
 			}
 		});
 
 	let core = parsed.core();
+	let json_output = core.global_options.output_format(false) == OutputFormat::Json;
+	input::configure(input::InputSettings::new(
+		core.global_options.no_input,
+		core.global_options.prompt_timeout.map(Duration::from_secs),
+	));
+	// the service's output goes to the system log, even when it's run
+	// without a log sink or file, as by the systemd unit
+	let is_service_run = matches!(
+		&core.subcommand,
+		Some(args::Commands::Tunnel(args::TunnelArgs {
+			subcommand: Some(args::TunnelSubcommand::Service(
+				args::TunnelServiceSubCommands::InternalRun(_)
+			)),
+			..
+		}))
+	);
+	progress::configure(progress::ProgressMode::select(
+		is_service_run
+			|| core.global_options.log_to_file.is_some()
+			|| !core.global_options.log_sink.is_empty()
+			|| core.global_options.log_format == Some(log::LogFormat::Json),
+		log::stderr_is_terminal(),
+		core.global_options
+			.progress_interval
+			.map(Duration::from_secs)
+			.unwrap_or(cli::constants::DEFAULT_PROGRESS_INTERVAL),
+	));
+	if let Some(path) = &core.global_options.rpc_trace {
+		if let Err(e) = rpc_trace::install(path) {
+			print_and_exit(wrap(e, "could not open the rpc trace file"));
+		}
+	}
+
+	// a policy that can't be read fails closed, rather than lifting its restrictions
+	match policy::load_system_policy() {
+		Ok(p) => policy::configure(p),
+		Err(e) => print_and_exit(e),
+	}
+
 	let context_paths = LauncherPaths::migrate(core.global_options.cli_data_dir.clone()).unwrap();
 	let context_args = core.clone();
 
 	// gets a command context without installing the global logger
 	let context_no_logger = || CommandContext {
-		http: reqwest::ClientBuilder::new()
-			.user_agent(get_default_user_agent())
-			.build()
-			.unwrap(),
+		http: shared_client(),
 		paths: context_paths,
 		log: make_logger(&context_args),
 		args: context_args,
@@ -77,8 +135,26 @@ async fn main() -> Result<(), std::convert::Infallible> {
 			Some(args::Commands::Extension(extension_args)) => {
 				let context = context!();
 				let mut ca = context.args.get_base_code_args();
-				extension_args.add_code_args(&mut ca);
-				start_code(context, ca).await
+				let json = context.args.global_options.output_format(false) == OutputFormat::Json;
+				match &extension_args.subcommand {
+					args::ExtensionSubcommand::List(list_args) if json => {
+						extension_args.desktop_code_options.add_code_args(&mut ca);
+						ca.push("--list-extensions".to_string());
+						ca.push("--show-versions".to_string());
+						if let Some(category) = &list_args.category {
+							ca.push(format!("--category={}", category));
+						}
+						list_extensions(context, ca).await
+					}
+					args::ExtensionSubcommand::Install(install_args) => {
+						extension_args.desktop_code_options.add_code_args(&mut ca);
+						install_extensions(context, ca, install_args).await
+					}
+					_ => {
+						extension_args.add_code_args(&mut ca);
+						start_code(context, ca).await
+					}
+				}
 			}
 
 			Some(args::Commands::Status) => {
@@ -88,13 +164,35 @@ async fn main() -> Result<(), std::convert::Infallible> {
 				start_code(context, ca).await
 			}
 
+			Some(args::Commands::MachineStatus(status_args)) => {
+				machine_status::status(context!(), status_args).await
+			}
+
 			Some(args::Commands::Version(version_args)) => match version_args.subcommand {
 				args::VersionSubcommand::Use(use_version_args) => {
 					version::switch_to(context!(), use_version_args).await
 				}
 				args::VersionSubcommand::Show => version::show(context!()).await,
+				args::VersionSubcommand::List(list_args) => {
+					version::list(context!(), list_args).await
+				}
+				args::VersionSubcommand::Clean(clean_args) => {
+					version::clean(context!(), clean_args).await
+				}
 			},
 
+			Some(args::Commands::Config(config_args)) => {
+				config::config(context!(), config_args.subcommand).await
+			}
+
+			Some(args::Commands::Cache(cache_args)) => {
+				cache::cache(context!(), cache_args.subcommand).await
+			}
+
+			Some(args::Commands::GenerateManpages(manpage_args)) => {
+				manpages::generate_manpages(context!(), manpage_args).await
+			}
+
 			Some(args::Commands::CommandShell(cs_args)) => {
 				tunnels::command_shell(context!(), cs_args).await
 			}
@@ -106,15 +204,40 @@ async fn main() -> Result<(), std::convert::Infallible> {
 			Some(args::Commands::Tunnel(tunnel_args)) => match tunnel_args.subcommand {
 				Some(args::TunnelSubcommand::Prune) => tunnels::prune(context!()).await,
 				Some(args::TunnelSubcommand::Unregister) => tunnels::unregister(context!()).await,
-				Some(args::TunnelSubcommand::Kill) => tunnels::kill(context!()).await,
+				Some(args::TunnelSubcommand::Kill(kill_args)) => {
+					tunnels::kill(context!(), kill_args).await
+				}
 				Some(args::TunnelSubcommand::Restart) => tunnels::restart(context!()).await,
-				Some(args::TunnelSubcommand::Status) => tunnels::status(context!()).await,
+				Some(args::TunnelSubcommand::Status(status_args)) => {
+					tunnels::status(context!(), status_args).await
+				}
+				Some(args::TunnelSubcommand::RpcMethods(methods_args)) => {
+					tunnels::rpc_methods(context!(), methods_args).await
+				}
+				Some(args::TunnelSubcommand::Metrics(metrics_args)) => {
+					tunnels::metrics(context!(), metrics_args).await
+				}
+				Some(args::TunnelSubcommand::Ports(ports_args)) => {
+					tunnels::ports(context!(), ports_args).await
+				}
+				Some(args::TunnelSubcommand::Ping(ping_args)) => {
+					tunnels::ping(context!(), ping_args).await
+				}
+				Some(args::TunnelSubcommand::Log(log_args)) => {
+					tunnels::logs(context!(), log_args).await
+				}
+				Some(args::TunnelSubcommand::Doctor(doctor_args)) => {
+					tunnels::doctor(context!(), doctor_args).await
+				}
 				Some(args::TunnelSubcommand::Rename(rename_args)) => {
 					tunnels::rename(context!(), rename_args).await
 				}
 				Some(args::TunnelSubcommand::User(user_command)) => {
 					tunnels::user(context!(), user_command).await
 				}
+				Some(args::TunnelSubcommand::Server(server_command)) => {
+					tunnels::server(context!(), server_command).await
+				}
 				Some(args::TunnelSubcommand::Service(service_args)) => {
 					tunnels::service(context_no_logger(), service_args).await
 				}
@@ -127,28 +250,106 @@ async fn main() -> Result<(), std::convert::Infallible> {
 	};
 
 	match result {
+		Err(AnyError::CodeError(e @ CodeError::PrerequisitesFailed { .. })) if json_output => {
+			print_prerequisites_and_exit(e)
+		}
 		Err(e) => print_and_exit(e),
 		Ok(code) => std::process::exit(code),
 	}
 }
 
 fn make_logger(core: &args::CliCore) -> log::Logger {
-	let log_level = if core.global_options.verbose {
-		log::Level::Trace
-	} else {
-		core.global_options.log.unwrap_or(log::Level::Info)
-	};
+	log::set_colors_enabled(
+		core.global_options
+			.color
+			.unwrap_or_default()
+			.resolve_from_env(),
+	);
+
+	let log_filter = core
+		.global_options
+		.verbosity()
+		.log_filter(core.global_options.log.clone());
+	// sinks get everything the filter lets through
+	let log_level = log_filter.max_level();
+
+	let log_format = core.global_options.log_format.unwrap_or_default();
 
 	let tracer = SdkTracerProvider::builder().build().tracer("codecli");
-	let mut log = log::Logger::new(tracer, log_level);
-	if let Some(f) = &core.global_options.log_to_file {
-		log = log
-			.with_sink(log::FileLogSink::new(log_level, f).expect("expected to make file logger"))
+	let mut log = log::Logger::new(tracer, log_level, log_format).filtered(log_filter.clone());
+	if !core.global_options.log_sink.is_empty() {
+		match make_log_sinks(&core.global_options, log_level, log_format) {
+			Ok(sinks) => log = log.with_sinks(sinks),
+			Err(e) => print_and_exit(e),
+		}
+	} else if let Some(f) = &core.global_options.log_to_file {
+		log = log.with_sink(
+			log::FileLogSink::new(log_level, log_format, f, core.global_options.log_rotation())
+				.expect("expected to make file logger"),
+		)
 	}
 
+	cli::debug!(log, "Using log filter: {}", log_filter);
 	log
 }
 
+fn make_log_sinks(
+	options: &args::GlobalOptions,
+	level: log::Level,
+	format: log::LogFormat,
+) -> Result<Vec<Box<dyn log::LogSink>>, CodeError> {
+	let mut sinks: Vec<Box<dyn log::LogSink>> = vec![];
+	for kind in &options.log_sink {
+		let open_err = |e| CodeError::CouldNotOpenLogSink(kind.to_string(), e);
+		match kind {
+			log::LogSinkKind::File => {
+				let path = options
+					.log_to_file
+					.as_ref()
+					.ok_or(CodeError::LogFileSinkWithoutPath)?;
+				let sink = log::FileLogSink::new(level, format, path, options.log_rotation())
+					.map_err(open_err)?;
+				sinks.push(Box::new(sink));
+			}
+			log::LogSinkKind::Stderr => {
+				sinks.push(Box::new(log::StderrLogSink::new(level, format)))
+			}
+			// logs to the system go through a queue, since the daemons on the
+			// other end can block us
+			log::LogSinkKind::Syslog => {
+				#[cfg(unix)]
+				{
+					let sink = log::system::SyslogSink::connect(cli::constants::APPLICATION_NAME)
+						.map_err(open_err)?;
+					sinks.push(Box::new(log::QueuedLogSink::new(
+						level,
+						log::DEFAULT_LOG_QUEUE_SIZE,
+						sink,
+					)));
+				}
+				#[cfg(not(unix))]
+				return Err(CodeError::LogSinkUnsupported(kind.to_string()));
+			}
+			log::LogSinkKind::Eventlog => {
+				#[cfg(windows)]
+				{
+					let sink = log::system::EventLogSink::open(cli::constants::PRODUCT_NAME_LONG)
+						.map_err(open_err)?;
+					sinks.push(Box::new(log::QueuedLogSink::new(
+						level,
+						log::DEFAULT_LOG_QUEUE_SIZE,
+						sink,
+					)));
+				}
+				#[cfg(not(windows))]
+				return Err(CodeError::LogSinkUnsupported(kind.to_string()));
+			}
+		}
+	}
+
+	Ok(sinks)
+}
+
 fn print_and_exit<E>(err: E) -> !
 where
 	E: std::fmt::Display,
@@ -157,11 +358,128 @@ where
 	std::process::exit(1);
 }
 
+/// Prints the unmet requirements as JSON, for scripts, before the error.
+fn print_prerequisites_and_exit(err: CodeError) -> ! {
+	if let CodeError::PrerequisitesFailed { requirements, .. } = &err {
+		println!(
+			"{}",
+			output::to_json(&PrerequisitesFailedOutput {
+				error: err.to_string(),
+				requirements: requirements.clone(),
+			})
+		);
+	}
+	print_and_exit(err)
+}
+
 async fn start_code(context: CommandContext, args: Vec<String>) -> Result<i32, AnyError> {
+	let binary = match get_code_binary(&context).await? {
+		Some(b) => b,
+		None => return Ok(1),
+	};
+
+	let code = Command::new(&binary)
+		.args(args)
+		.status()
+		.map(|s| s.code().unwrap_or(1))
+		.map_err(|e| wrap(e, format!("error running editor from {}", binary.display())))?;
+
+	Ok(code)
+}
+
+/// Lists extensions through the editor, printing them with the output format.
+async fn list_extensions(context: CommandContext, args: Vec<String>) -> Result<i32, AnyError> {
+	let binary = match get_code_binary(&context).await? {
+		Some(b) => b,
+		None => return Ok(1),
+	};
+
+	let output = Command::new(&binary)
+		.args(args)
+		.stderr(Stdio::inherit())
+		.output()
+		.map_err(|e| wrap(e, format!("error running editor from {}", binary.display())))?;
+	if !output.status.success() {
+		return Ok(output.status.code().unwrap_or(1));
+	}
+
+	let extensions = ExtensionListOutput::parse(&String::from_utf8_lossy(&output.stdout));
+	context.log.result(
+		context
+			.args
+			.global_options
+			.output_format(false)
+			.render(&extensions),
+	);
+	Ok(0)
+}
+
+/// Installs extensions through the editor, printing the result of each.
+async fn install_extensions(
+	context: CommandContext,
+	args: Vec<String>,
+	install_args: &args::InstallExtensionArgs,
+) -> Result<i32, AnyError> {
+	let binary = match get_code_binary(&context).await? {
+		Some(b) => b,
+		None => return Ok(1),
+	};
+
+	let http = Arc::new(ReqwestSimpleHttp::with_client(context.http.clone()));
+	let output = extensions::install(http, args, install_args, |args| {
+		let mut cmd = new_tokio_command(&binary);
+		cmd.args(args);
+		async move {
+			let output = run_command(
+				cmd,
+				&RunOptions::with_timeout(cli::constants::EXTENSION_INSTALL_TIMEOUT),
+			)
+			.await?;
+			Ok(std::process::Output {
+				status: output.status,
+				stdout: output.stdout,
+				stderr: output.stderr,
+			})
+		}
+	})
+	.await?;
+
+	context.log.result(
+		context
+			.args
+			.global_options
+			.output_format(install_args.json)
+			.render(&output),
+	);
+	Ok(if output.any_failed() { 1 } else { 0 })
+}
+
+/// Gets whether to download the version, which isn't installed. Commits are
+/// always downloaded, since they're asked for by build. Without a terminal to
+/// ask in, the install instructions are shown instead.
+fn should_provision(
+	context: &CommandContext,
+	version: &desktop::RequestedVersion,
+) -> Result<bool, AnyError> {
+	let options = &context.args.editor_options.code_options;
+	match version {
+		desktop::RequestedVersion::Path(_) => Ok(false),
+		desktop::RequestedVersion::Default if options.auto_provision => Ok(true),
+		desktop::RequestedVersion::Default if !input::is_interactive() => Ok(false),
+		desktop::RequestedVersion::Default => input::prompt_yn(
+			&format!("No install of {} was found. Download it?", version),
+			"--auto-provision",
+		),
+		_ => Ok(true),
+	}
+}
+
+/// Gets the editor to run, prompting to install it if it's not found.
+async fn get_code_binary(context: &CommandContext) -> Result<Option<PathBuf>, AnyError> {
 	// todo: once the integrated CLI takes the place of the Node.js CLI, this should
 	// redirect to the current installation without using the CodeVersionManager.
 
-	let platform = PreReqChecker::new().verify().await?;
+	let platform = context.prereqs().verify().await?;
 	let version_manager =
 		desktop::CodeVersionManager::new(context.log.clone(), &context.paths, platform);
 	let version = match &context.args.editor_options.code_options.use_version {
@@ -169,19 +487,17 @@ async fn start_code(context: CommandContext, args: Vec<String>) -> Result<i32, A
 		None => version_manager.get_preferred_version(),
 	};
 
-	let binary = match version_manager.try_get_entrypoint(&version).await {
-		Some(ep) => ep,
-		None => {
-			desktop::prompt_to_install(&version);
-			return Ok(1);
-		}
-	};
-
-	let code = Command::new(&binary)
-		.args(args)
-		.status()
-		.map(|s| s.code().unwrap_or(1))
-		.map_err(|e| wrap(e, format!("error running editor from {}", binary.display())))?;
+	let mut binary = version_manager.try_get_entrypoint(&version).await;
+	if binary.is_none() && should_provision(context, &version)? {
+		let update_service = UpdateService::new(
+			context.log.clone(),
+			Arc::new(ReqwestSimpleHttp::with_client(context.http.clone())),
+		);
+		binary = Some(version_manager.provision(&update_service, &version).await?);
+	}
+	if binary.is_none() {
+		desktop::prompt_to_install(&version);
+	}
 
-	Ok(code)
+	Ok(binary)
 }
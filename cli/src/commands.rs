@@ -6,6 +6,12 @@
 mod context;
 
 pub mod args;
+pub mod cache;
+pub mod config;
+pub mod extensions;
+pub mod machine_status;
+pub mod manpages;
+pub mod output;
 pub mod serve_web;
 pub mod tunnels;
 pub mod update;
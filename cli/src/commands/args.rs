@@ -3,11 +3,22 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{fmt, path::PathBuf};
+use std::{ffi::OsString, fmt, path::PathBuf, time::Duration};
+
+use crate::{
+	auth, constants, log, options,
+	tunnels::{
+		challenge_limiter::ChallengeLimits, code_server::CodeServerArgs,
+		port_watcher::AutoForwardPolicy, protocol::PortPrivacy, remote_exec::RemoteExecPolicy,
+	},
+	update_service::Platform,
+	util::prereqs::Arch,
+};
 
-use crate::{constants, log, options, tunnels::code_server::CodeServerArgs};
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use super::output::Verbosity;
+use clap::{builder::BoolishValueParser, ArgAction, Args, Parser, Subcommand, ValueEnum};
 use const_format::concatcp;
+use serde::Serialize;
 
 const CLI_NAME: &str = concatcp!(constants::PRODUCT_NAME_LONG, " CLI");
 const HELP_COMMANDS: &str = concatcp!(
@@ -42,6 +53,31 @@ const INTEGRATED_TEMPLATE: &str = concatcp!(
 {all-args}"
 );
 
+const TUNNEL_EXAMPLES: &str = concatcp!(
+	"Examples:
+  Start a tunnel named after this machine:
+    ",
+	constants::APPLICATION_NAME,
+	" tunnel --accept-server-license-terms
+
+  Install the tunnel as a service that starts on login:
+    ",
+	constants::APPLICATION_NAME,
+	" tunnel service install"
+);
+const SERVE_WEB_EXAMPLES: &str = concatcp!(
+	"Examples:
+  Serve on all interfaces and open the browser:
+    ",
+	constants::APPLICATION_NAME,
+	" serve-web --host 0.0.0.0 --open
+
+  Serve over HTTPS with a self-signed certificate:
+    ",
+	constants::APPLICATION_NAME,
+	" serve-web --self-signed"
+);
+
 const COMMIT_IN_VERSION: &str = match constants::VSCODE_CLI_COMMIT {
 	Some(c) => c,
 	None => "unknown",
@@ -113,6 +149,308 @@ impl AnyCli {
 	}
 }
 
+/// Prefix of the environment variables bound to flags.
+const ENV_PREFIX: &str = "VSCODE_CLI_";
+
+/// Gets the environment variable for a flag: `VSCODE_CLI_` followed by the
+/// subcommands the flag belongs to and its name, such as
+/// `VSCODE_CLI_SERVE_WEB_PORT` for `serve-web --port`. Global flags only use
+/// their name.
+pub fn env_var_name(subcommands: &[&str], long: &str) -> String {
+	let mut name = ENV_PREFIX.to_string();
+	for part in subcommands.iter().chain(std::iter::once(&long)) {
+		name.push_str(&part.replace('-', "_").to_ascii_uppercase());
+		name.push('_');
+	}
+	name.pop();
+	name
+}
+
+/// Gets whether the flag is one a user would set, and so should be settable
+/// from the environment.
+pub fn is_user_facing_flag(arg: &clap::Arg) -> bool {
+	arg.get_long().is_some()
+		&& !arg.is_hide_set()
+		&& !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version)
+}
+
+/// Binds every user-facing flag in the command to an environment variable
+/// named by [`env_var_name`], unless it already has one. Flags take 1/0 as
+/// well as true/false, from the environment and the command line alike.
+pub fn add_env_bindings(cmd: clap::Command) -> clap::Command {
+	add_env_bindings_at(cmd, &mut vec![])
+}
+
+fn add_env_bindings_at(mut cmd: clap::Command, path: &mut Vec<String>) -> clap::Command {
+	let args: Vec<(clap::Id, String)> = cmd
+		.get_arguments()
+		.filter(|a| is_user_facing_flag(a) && a.get_env().is_none())
+		.map(|a| {
+			let path: Vec<&str> = path.iter().map(|s| s.as_str()).collect();
+			(
+				a.get_id().clone(),
+				env_var_name(&path, a.get_long().unwrap()),
+			)
+		})
+		.collect();
+	for (id, env) in args {
+		cmd = cmd.mut_arg(id, |a| {
+			let secret = ["token", "passphrase", "password", "secret"]
+				.iter()
+				.any(|s| a.get_long().unwrap_or_default().contains(s));
+			a.env(env).hide_env_values(secret)
+		});
+	}
+
+	let flags: Vec<clap::Id> = cmd
+		.get_arguments()
+		.filter(|a| matches!(a.get_action(), ArgAction::SetTrue | ArgAction::SetFalse))
+		.map(|a| a.get_id().clone())
+		.collect();
+	for id in flags {
+		cmd = cmd.mut_arg(id, |a| a.value_parser(BoolishValueParser::new()));
+	}
+
+	let subcommands: Vec<String> = cmd
+		.get_subcommands()
+		.map(|s| s.get_name().to_string())
+		.collect();
+	for name in subcommands {
+		path.push(name.clone());
+		cmd = cmd.mut_subcommand(&name, |s| add_env_bindings_at(s, path));
+		path.pop();
+	}
+
+	cmd
+}
+
+/// A command or flag that was renamed. The old name keeps working, with a
+/// warning, until the release it's removed in, after which using it is an
+/// error that points to the new name.
+pub struct Alias {
+	/// Path to the renamed command, like `["tunnel", "old-name"]`, or the
+	/// command a renamed flag belongs to followed by the flag, like
+	/// `["serve-web", "--old-flag"]`. Flags of the root command are global.
+	pub old: &'static [&'static str],
+	/// New name of the command or flag.
+	pub new: &'static str,
+	/// Release in which the old name stops working.
+	pub removed_in: &'static str,
+}
+
+/// Commands and flags that were renamed. Keep entries until their release has
+/// shipped, so that scripts get an error naming the replacement rather than
+/// a generic one.
+pub const ALIASES: &[Alias] = &[];
+
+/// Disables the warnings printed when a deprecated name is used.
+pub const NO_DEPRECATION_WARNINGS_ENV: &str = "VSCODE_CLI_NO_DEPRECATION_WARNINGS";
+
+/// Use of a deprecated name, which JSON results report as `deprecations`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Deprecation {
+	pub old: String,
+	pub new: String,
+	pub removed_in: String,
+}
+
+impl fmt::Display for Deprecation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"`{}` is deprecated and will be removed in {}, use `{}` instead",
+			self.old, self.removed_in, self.new
+		)
+	}
+}
+
+impl Deprecation {
+	/// Gets the error for using the old name once it's been removed.
+	pub fn removed_message(&self) -> String {
+		format!(
+			"`{}` was removed in {}, use `{}` instead",
+			self.old, self.removed_in, self.new
+		)
+	}
+
+	fn is_flag(&self) -> bool {
+		self.new
+			.rsplit(' ')
+			.next()
+			.unwrap_or_default()
+			.starts_with("--")
+	}
+
+	/// Gets the kind of parse error for using the old name once it's removed.
+	pub fn removed_error_kind(&self) -> clap::error::ErrorKind {
+		if self.is_flag() {
+			clap::error::ErrorKind::UnknownArgument
+		} else {
+			clap::error::ErrorKind::InvalidSubcommand
+		}
+	}
+}
+
+/// Rewrites deprecated commands and flags in the arguments to their new
+/// names, returning what was rewritten. Fails with the first alias that was
+/// removed as of the version.
+pub fn resolve_aliases(
+	cmd: &clap::Command,
+	aliases: &[Alias],
+	version: Option<&str>,
+	args: &[OsString],
+) -> Result<(Vec<OsString>, Vec<Deprecation>), Deprecation> {
+	// propagates global flags, to know which flags take values
+	let mut cmd = cmd.clone();
+	cmd.build();
+
+	let mut resolved = vec![];
+	let mut deprecations = vec![];
+	let mut removed = None;
+	let mut use_alias = |alias: &Alias, path: &[String], old: &str, new: String| {
+		let prefix = path.iter().map(|p| format!("{} ", p)).collect::<String>();
+		let deprecation = Deprecation {
+			old: format!("{}{}", prefix, old),
+			new: format!("{}{}", prefix, new),
+			removed_in: alias.removed_in.to_string(),
+		};
+		if version.is_some_and(|v| is_at_least(v, alias.removed_in)) {
+			removed.get_or_insert(deprecation);
+		} else {
+			deprecations.push(deprecation);
+		}
+		new
+	};
+
+	let mut path: Vec<String> = vec![];
+	let mut current = &cmd;
+	let mut in_positionals = false;
+	let mut iter = args.iter();
+	resolved.extend(iter.next().cloned());
+	while let Some(arg) = iter.next() {
+		let s = match arg.to_str() {
+			Some("--") => {
+				resolved.push(arg.clone());
+				resolved.extend(iter.by_ref().cloned());
+				break;
+			}
+			Some(s) => s,
+			None => {
+				resolved.push(arg.clone());
+				continue;
+			}
+		};
+
+		if let Some(flag) = s.strip_prefix("--") {
+			let (name, value) = match flag.split_once('=') {
+				Some((n, v)) => (n, Some(v)),
+				None => (flag, None),
+			};
+			let old = format!("--{}", name);
+			let name = match find_alias(aliases, &path, &old, true) {
+				Some(a) => use_alias(a, &path, &old, a.new.to_string()),
+				None => old,
+			};
+			let takes_value = current
+				.get_arguments()
+				.find(|a| a.get_long() == name.strip_prefix("--"))
+				.is_some_and(arg_takes_value);
+			resolved.push(match value {
+				Some(v) => format!("{}={}", name, v).into(),
+				None => name.into(),
+			});
+			if takes_value && value.is_none() {
+				resolved.extend(iter.next().cloned());
+			}
+			continue;
+		}
+
+		if let Some(short) = s.strip_prefix('-').filter(|s| !s.is_empty()) {
+			let takes_value = short.chars().count() == 1
+				&& current
+					.get_arguments()
+					.find(|a| a.get_short() == short.chars().next())
+					.is_some_and(arg_takes_value);
+			resolved.push(arg.clone());
+			if takes_value {
+				resolved.extend(iter.next().cloned());
+			}
+			continue;
+		}
+
+		if in_positionals {
+			resolved.push(arg.clone());
+			continue;
+		}
+
+		let name = match find_alias(aliases, &path, s, false) {
+			Some(a) => use_alias(a, &path, s, a.new.to_string()),
+			None => s.to_string(),
+		};
+		match current.find_subcommand(&name) {
+			Some(sub) => {
+				path.push(sub.get_name().to_string());
+				current = sub;
+			}
+			None => in_positionals = true,
+		}
+		resolved.push(name.into());
+	}
+
+	match removed {
+		Some(d) => Err(d),
+		None => Ok((resolved, deprecations)),
+	}
+}
+
+/// Finds the alias for the command or flag `name` used in the command `path`.
+fn find_alias<'a>(
+	aliases: &'a [Alias],
+	path: &[String],
+	name: &str,
+	is_flag: bool,
+) -> Option<&'a Alias> {
+	aliases.iter().find(|a| {
+		let (old, alias_path) = a.old.split_last().expect("expected an alias name");
+		// flags are inherited by subcommands, commands are only found where
+		// they're defined
+		let path_matches = path.len() >= alias_path.len()
+			&& (is_flag || path.len() == alias_path.len())
+			&& path.iter().zip(alias_path.iter()).all(|(a, b)| a == b);
+		*old == name && path_matches
+	})
+}
+
+fn arg_takes_value(arg: &clap::Arg) -> bool {
+	arg.get_action().takes_values() && arg.get_num_args().is_none_or(|n| n.min_values() > 0)
+}
+
+/// Compares dotted version numbers, like `1.90.0`, ignoring anything after
+/// the numbers in each part.
+fn is_at_least(version: &str, target: &str) -> bool {
+	let parse = |v: &str| -> Vec<u32> {
+		v.split('.')
+			.map(|p| {
+				let digits: String = p.chars().take_while(|c| c.is_ascii_digit()).collect();
+				digits.parse().unwrap_or(0)
+			})
+			.collect()
+	};
+	parse(version) >= parse(target)
+}
+
+/// Prints a warning for each deprecated name that was used, unless the
+/// warnings are turned off by [`NO_DEPRECATION_WARNINGS_ENV`].
+pub fn warn_deprecations(deprecations: &[Deprecation], out: &mut impl std::io::Write) {
+	if std::env::var_os(NO_DEPRECATION_WARNINGS_ENV).is_some() {
+		return;
+	}
+
+	for d in deprecations {
+		let _ = writeln!(out, "warning: {}", d);
+	}
+}
+
 impl CliCore {
 	pub fn get_base_code_args(&self) -> Vec<String> {
 		let mut args = self.open_paths.clone();
@@ -126,12 +464,17 @@ impl CliCore {
 impl<'a> From<&'a CliCore> for CodeServerArgs {
 	fn from(cli: &'a CliCore) -> Self {
 		let mut args = CodeServerArgs {
-			log: cli.global_options.log,
+			log: cli.global_options.log_level(),
 			accept_server_license_terms: true,
+			restart_limit: constants::DEFAULT_SERVER_RESTART_LIMIT,
+			skip_requirements_check: cli.global_options.skip_requirements_check,
+			arch: cli.global_options.arch,
+			assume_platform: cli.global_options.assume_platform,
+			ignore_preflight: cli.global_options.ignore_preflight,
 			..Default::default()
 		};
 
-		args.log = cli.global_options.log;
+		args.log = cli.global_options.log_level();
 		args.accept_server_license_terms = true;
 
 		if cli.global_options.verbose {
@@ -166,6 +509,7 @@ pub struct StandaloneUpdateArgs {
 pub enum Commands {
 	/// Create a tunnel that's accessible on vscode.dev from anywhere.
 	/// Run `code tunnel --help` for more usage info.
+	#[clap(after_long_help = TUNNEL_EXAMPLES)]
 	Tunnel(TunnelArgs),
 
 	/// Manage editor extensions.
@@ -175,42 +519,105 @@ pub enum Commands {
 	/// Print process usage and diagnostics information.
 	Status,
 
+	/// Print what the CLI is running on this machine, such as tunnels and
+	/// services.
+	MachineStatus(MachineStatusArgs),
+
 	/// Changes the version of the editor you're using.
 	Version(VersionArgs),
 
 	/// Runs a local web version of VS Code.
 	#[clap(about = concatcp!("Runs a local web version of ", constants::PRODUCT_NAME_LONG))]
+	#[clap(after_long_help = SERVE_WEB_EXAMPLES)]
 	ServeWeb(ServeWebArgs),
 
 	/// Runs the control server on process stdin/stdout
 	#[clap(hide = true)]
 	CommandShell(CommandShellArgs),
+
+	/// Sets the default flags kept in the config file.
+	Config(ConfigArgs),
+
+	/// Inspects the servers and other downloads the CLI keeps.
+	Cache(CacheArgs),
+
+	/// Writes man pages for the CLI and each of its subcommands.
+	#[clap(hide = true)]
+	GenerateManpages(GenerateManpagesArgs),
 }
 
 #[derive(Args, Debug, Clone)]
-pub struct ServeWebArgs {
-	/// Host to listen on, defaults to 'localhost'
+pub struct GenerateManpagesArgs {
+	/// Directory to write the pages to, such as `share/man/man1`.
+	#[clap(value_name = "dir")]
+	pub dir: PathBuf,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct MachineStatusArgs {
+	/// Print the status as JSON.
 	#[clap(long)]
-	pub host: Option<String>,
+	pub json: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServeWebArgs {
+	/// Host to listen on, such as an IPv4 or IPv6 address. May be given multiple times to listen on several addresses; 'localhost' listens on both 127.0.0.1 and ::1. Defaults to 127.0.0.1.
+	#[clap(long, conflicts_with = "socket_path")]
+	pub host: Vec<String>,
 	// The path to a socket file for the server to listen to.
 	#[clap(long)]
 	pub socket_path: Option<String>,
 	/// Port to listen on. If 0 is passed a random free port is picked.
 	#[clap(long, default_value_t = 8000)]
 	pub port: u16,
-	/// A secret that must be included with all requests.
+	/// Open the web UI in the default browser once it's listening.
+	#[clap(long, overrides_with = "no_open", conflicts_with = "socket_path")]
+	pub open: bool,
+	/// Don't open the web UI in the browser, overriding --open.
+	#[clap(long, overrides_with = "open")]
+	pub no_open: bool,
+	/// Print the URL the web UI is available at as a single line of JSON.
 	#[clap(long)]
+	pub json: bool,
+	/// Path to a PEM certificate chain to serve the web UI over HTTPS with. Send SIGHUP to reload it.
+	#[clap(long, requires = "key", conflicts_with_all = ["socket_path", "self_signed"])]
+	pub cert: Option<PathBuf>,
+	/// Path to the PEM private key of the certificate given in --cert.
+	#[clap(long, requires = "cert")]
+	pub key: Option<PathBuf>,
+	/// Serve the web UI over HTTPS with a self-signed certificate, which is kept in the CLI data directory.
+	#[clap(long, conflicts_with = "socket_path")]
+	pub self_signed: bool,
+	/// A secret that must be included with all requests. If not given, one is generated and kept in the CLI data directory.
+	#[clap(long, conflicts_with = "connection_token_file")]
 	pub connection_token: Option<String>,
-	/// A file containing a secret that must be included with all requests.
+	/// A file containing a secret that must be included with all requests, such as one written by a secret manager.
 	#[clap(long)]
 	pub connection_token_file: Option<String>,
 	/// Run without a connection token. Only use this if the connection is secured by other means.
-	#[clap(long)]
+	#[clap(long, conflicts_with_all = ["connection_token", "connection_token_file"])]
 	pub without_connection_token: bool,
 	/// If set, the user accepts the server license terms and the server will be started without a user prompt.
 	#[clap(long)]
 	pub accept_server_license_terms: bool,
-	/// Specifies the path under which the web UI and the code server is provided.
+	/// Only serve the exact server commit that's requested, rather than a substitute when it can't be downloaded.
+	#[clap(long)]
+	pub strict_commit: bool,
+	/// Seconds between pings sent to keep idle websockets from being closed by proxies. Connections that don't answer before the next ping are closed. 0 disables pings.
+	#[clap(long, default_value_t = 30)]
+	pub ws_keepalive: u64,
+	/// Number of seconds to wait for websockets to close after a Ctrl+C or
+	/// SIGTERM before shutting down. Defaults to 10 seconds.
+	#[clap(long, value_name = "SECONDS")]
+	pub drain_timeout: Option<u64>,
+	/// Path to write a line about each request to, or '-' for stdout. Connection tokens are left out.
+	#[clap(long, value_name = "path")]
+	pub access_log: Option<String>,
+	/// Log the client address from the X-Forwarded-For header, when serve-web is behind a proxy that sets it.
+	#[clap(long)]
+	pub trusted_proxy: bool,
+	/// Specifies the path under which the web UI and the code server is provided, such as when serving behind a reverse proxy.
 	#[clap(long)]
 	pub server_base_path: Option<String>,
 	/// Specifies the directory that server data is kept in.
@@ -241,6 +648,26 @@ pub struct CommandShellArgs {
 	/// Optional parent process id. If provided, the server will be stopped when the process of the given pid no longer exists
 	#[clap(long, hide = true)]
 	pub parent_process_id: Option<String>,
+	/// Milliseconds a client must wait after a failed handshake. It doubles with each consecutive failure.
+	#[clap(long, value_name = "MS", default_value_t = constants::DEFAULT_AUTH_RETRY_DELAY.as_millis() as u64)]
+	pub auth_retry_delay: u64,
+	/// Number of consecutive failed handshakes after which a client is locked out.
+	#[clap(long, value_name = "COUNT", default_value_t = constants::DEFAULT_AUTH_LOCKOUT_AFTER)]
+	pub auth_lockout_after: u32,
+	/// Seconds a client is locked out for after too many failed handshakes.
+	#[clap(long, value_name = "SECS", default_value_t = constants::DEFAULT_AUTH_LOCKOUT_DURATION.as_secs())]
+	pub auth_lockout_duration: u64,
+}
+
+impl CommandShellArgs {
+	pub fn challenge_limits(&self) -> ChallengeLimits {
+		ChallengeLimits {
+			retry_delay: Duration::from_millis(self.auth_retry_delay),
+			lockout_after: self.auth_lockout_after.max(1),
+			lockout_duration: Duration::from_secs(self.auth_lockout_duration),
+			..ChallengeLimits::default()
+		}
+	}
 }
 
 #[derive(Args, Debug, Clone)]
@@ -319,10 +746,10 @@ pub struct ListExtensionArgs {
 
 #[derive(Args, Debug, Clone)]
 pub struct InstallExtensionArgs {
-	/// Either an extension id or a path to a VSIX. The identifier of an
-	/// extension is '${publisher}.${name}'. Use '--force' argument to update
-	/// to latest version. To install a specific version provide '@${version}'.
-	/// For example: 'vscode.csharp@1.2.3'.
+	/// One or more extension ids, or paths or http(s) URLs of VSIXs. The
+	/// identifier of an extension is '${publisher}.${name}'. Use '--force'
+	/// argument to update to latest version. To install a specific version
+	/// provide '@${version}'. For example: 'vscode.csharp@1.2.3'.
 	#[clap(name = "ext-id | id")]
 	pub id_or_path: Vec<String>,
 
@@ -333,6 +760,16 @@ pub struct InstallExtensionArgs {
 	/// Update to the latest version of the extension if it's already installed.
 	#[clap(long)]
 	pub force: bool,
+
+	/// Print the result for each extension as JSON.
+	#[clap(long)]
+	pub json: bool,
+
+	/// How many VSIX URLs are downloaded at the same time. The editor then
+	/// installs the extensions one run at a time, since the runs write the
+	/// same extensions directory.
+	#[clap(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..))]
+	pub concurrency: u8,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -357,13 +794,111 @@ pub enum VersionSubcommand {
 
 	/// Shows the currently configured editor version.
 	Show,
+
+	/// Lists the editor installs found on this machine.
+	List(VersionListArgs),
+
+	/// Removes stored versions whose install is gone, and downloaded builds
+	/// that no version uses.
+	Clean(VersionCleanArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct VersionListArgs {
+	/// Print the installs as JSON.
+	#[clap(long)]
+	pub json: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct VersionCleanArgs {
+	/// List what would be removed without removing it.
+	#[clap(long)]
+	pub dry_run: bool,
+
+	/// Remove without asking for confirmation. The version in use is only
+	/// removed with --remove-selected.
+	#[clap(long, short)]
+	pub yes: bool,
+
+	/// Also remove the version in use if its install is gone, after which the
+	/// default version is used.
+	#[clap(long)]
+	pub remove_selected: bool,
+
+	/// Print what was removed as JSON.
+	#[clap(long)]
+	pub json: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CacheArgs {
+	#[clap(subcommand)]
+	pub subcommand: CacheSubcommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CacheSubcommand {
+	/// Lists the installed servers, with their size, last use, and whether
+	/// they're running.
+	Stats(CacheStatsArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CacheStatsArgs {
+	/// Print the stats as JSON.
+	#[clap(long)]
+	pub json: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ConfigArgs {
+	#[clap(subcommand)]
+	pub subcommand: ConfigSubcommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigSubcommand {
+	/// Sets the default value of a flag.
+	Set(ConfigSetArgs),
+
+	/// Prints the value of a flag set in the config file.
+	Get(ConfigKeyArgs),
+
+	/// Lists the flags set in the config file.
+	List,
+
+	/// Removes a flag from the config file.
+	Unset(ConfigKeyArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ConfigKeyArgs {
+	/// Long name of the flag, after the subcommands it's given to, such as
+	/// `log` or `tunnel.name`.
+	#[clap(value_name = "key")]
+	pub key: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ConfigSetArgs {
+	/// Long name of the flag, after the subcommands it's given to, such as
+	/// `log` or `tunnel.name`.
+	#[clap(value_name = "key")]
+	pub key: String,
+
+	/// The value of the flag. Flags that can be given several times take a
+	/// comma-separated list.
+	#[clap(value_name = "value")]
+	pub value: String,
 }
 
 #[derive(Args, Debug, Clone)]
 pub struct UseVersionArgs {
 	/// The version of the editor you want to use. Can be "stable", "insiders",
-	/// or an absolute path to an existing install.
-	#[clap(value_name = "stable | insiders | x.y.z | path")]
+	/// a commit such as "insiders/<commit>", or an absolute path to an existing
+	/// install. Commits are downloaded if they aren't installed.
+	#[clap(value_name = "stable | insiders | x.y.z | commit | path")]
 	pub name: String,
 
 	/// The directory where the version can be found.
@@ -461,9 +996,15 @@ pub struct DesktopCodeOptions {
 
 	/// Sets the editor version to use for this command. The preferred version
 	/// can be persisted with `code version use <version>`. Can be "stable",
-	/// "insiders", a version number, or an absolute path to an existing install.
-	#[clap(long, value_name = "stable | insiders | x.y.z | path")]
+	/// "insiders", a version number, a commit such as "insiders/<commit>", or
+	/// an absolute path to an existing install.
+	#[clap(long, value_name = "stable | insiders | x.y.z | commit | path")]
 	pub use_version: Option<String>,
+
+	/// Downloads the editor without asking if no install of the version is
+	/// found.
+	#[clap(long)]
+	pub auto_provision: bool,
 }
 
 /// Argument specifying the output format.
@@ -491,17 +1032,136 @@ pub struct GlobalOptions {
 	#[clap(long, env = "VSCODE_CLI_DATA_DIR", global = true)]
 	pub cli_data_dir: Option<String>,
 
+	/// Config file of default flags. Defaults to config.toml in the CLI data directory.
+	#[clap(long, value_name = "path", env = "VSCODE_CLI_CONFIG", global = true)]
+	pub config: Option<PathBuf>,
+
 	/// Print verbose output (implies --wait).
 	#[clap(long, global = true)]
 	pub verbose: bool,
 
-	/// Log to a file in addition to stdout. Used when running as a service.
+	/// Only print results and errors.
+	#[clap(long, global = true, conflicts_with = "verbose")]
+	pub quiet: bool,
+
+	/// Never prompt. Prompts fail instead, naming the flag that answers them.
+	/// Also the case when stdin isn't a terminal.
+	#[clap(long, global = true)]
+	pub no_input: bool,
+
+	/// Seconds after which prompts take their default answer. Confirmations
+	/// fail instead.
+	#[clap(long, value_name = "seconds", global = true)]
+	pub prompt_timeout: Option<u64>,
+
+	/// Run the server even if the machine doesn't meet its requirements, such
+	/// as glibc and libstdc++ versions. This is unsupported.
+	#[clap(long, env = "VSCODE_CLI_SKIP_REQUIREMENTS_CHECK", global = true)]
+	pub skip_requirements_check: bool,
+
+	/// Architecture of the server to run on Linux, instead of detecting it
+	/// from the userland and CPU.
+	#[clap(
+		long,
+		value_enum,
+		value_name = "arch",
+		env = "VSCODE_CLI_ARCH",
+		global = true
+	)]
+	pub arch: Option<Arch>,
+
+	/// Platform of the server to run, such as `linux-x64`, skipping all checks
+	/// of whether this machine can run it. This is unsupported.
+	#[clap(
+		long,
+		value_name = "platform",
+		env = "VSCODE_CLI_ASSUME_PLATFORM",
+		global = true
+	)]
+	pub assume_platform: Option<Platform>,
+
+	/// Skip checking for disk space and memory before downloading a server.
+	#[clap(long, env = "VSCODE_CLI_IGNORE_PREFLIGHT", global = true)]
+	pub ignore_preflight: bool,
+
+	/// Log to a file in addition to stderr. Used when running as a service.
 	#[clap(long, global = true, hide = true)]
 	pub log_to_file: Option<PathBuf>,
 
-	/// Log level to use.
-	#[clap(long, value_enum, value_name = "level", global = true)]
-	pub log: Option<log::Level>,
+	/// Log level to use, optionally per module, such as
+	/// `info,update_service=trace` [levels: trace, debug, info, warn, error,
+	/// critical, off]
+	///
+	/// Modules are those of the CLI, such as `tunnels::control_server`, or
+	/// libraries it logs from, such as `russh` or `hyper`. A module on its
+	/// own, with no level, logs everything from it.
+	#[clap(long, value_name = "level", env = "VSCODE_CLI_LOG", global = true)]
+	pub log: Option<log::LogFilter>,
+
+	/// Whether to color output.
+	#[clap(long, value_enum, value_name = "when", global = true)]
+	pub color: Option<log::ColorChoice>,
+
+	/// Format to write logs in, to stderr and the log file.
+	#[clap(
+		long,
+		value_enum,
+		value_name = "format",
+		env = "VSCODE_CLI_LOG_FORMAT",
+		global = true
+	)]
+	pub log_format: Option<log::LogFormat>,
+
+	/// Format to print the results of informational commands in.
+	#[clap(long, value_enum, value_name = "format", global = true)]
+	pub output: Option<OutputFormat>,
+
+	/// Size in megabytes the log file may grow to before it's rotated.
+	#[clap(
+		long,
+		value_name = "MB",
+		env = "VSCODE_CLI_LOG_FILE_MAX_SIZE",
+		global = true,
+		hide = true,
+		value_parser = clap::value_parser!(u64).range(1..)
+	)]
+	pub log_file_max_size: Option<u64>,
+
+	/// Number of rotated log files to keep.
+	#[clap(
+		long,
+		value_name = "count",
+		env = "VSCODE_CLI_LOG_FILE_KEEP",
+		global = true,
+		hide = true
+	)]
+	pub log_file_keep: Option<usize>,
+
+	/// Gzip rotated log files other than the most recent one.
+	#[clap(long, env = "VSCODE_CLI_LOG_FILE_COMPRESS", global = true, hide = true)]
+	pub log_file_compress: bool,
+
+	/// Where to write logs, instead of stderr or the file from
+	/// `--log-to-file`. Can be given more than once.
+	#[clap(
+		long,
+		value_enum,
+		value_name = "sink",
+		value_delimiter = ',',
+		global = true
+	)]
+	pub log_sink: Vec<log::LogSinkKind>,
+
+	/// Write every RPC message sent or received to the file as JSON lines,
+	/// with secrets redacted. Used to debug protocol issues.
+	#[clap(
+		long,
+		env = "VSCODE_CLI_RPC_TRACE",
+		value_name = "path",
+		global = true,
+		hide = true
+	)]
+	pub rpc_trace: Option<PathBuf>,
 
 	/// Disable telemetry for the current command, even if it was previously
 	/// accepted as part of the license prompt or specified in '--telemetry-level'
@@ -511,14 +1171,90 @@ pub struct GlobalOptions {
 	/// Sets the initial telemetry level
 	#[clap(value_enum, long, global = true, hide = true)]
 	pub telemetry_level: Option<options::TelemetryLevel>,
+
+	/// Auth profile whose credentials should be used instead of the active one.
+	#[clap(long, global = true, value_parser = parse_auth_profile)]
+	pub profile: Option<String>,
+
+	/// Where to store credentials, instead of the backend configured when
+	/// logging in.
+	#[clap(long, value_enum, value_name = "storage", global = true)]
+	pub token_storage: Option<auth::TokenStorageKind>,
+
+	/// Passphrase used in addition to a machine secret to encrypt credentials
+	/// with `--token-storage file-encrypted`.
+	#[clap(
+		long,
+		env = "VSCODE_CLI_TOKEN_PASSPHRASE",
+		hide_env_values = true,
+		global = true
+	)]
+	pub token_passphrase: Option<String>,
+
+	/// Microsoft cloud to log into. It's remembered for the profile once
+	/// logged in.
+	#[clap(long, value_enum, value_name = "cloud", global = true)]
+	pub auth_cloud: Option<auth::AuthCloud>,
+
+	/// Seconds to wait for the OS keyring before storing credentials in a
+	/// file instead.
+	#[clap(
+		long,
+		value_name = "seconds",
+		env = "VSCODE_CLI_KEYRING_TIMEOUT",
+		global = true
+	)]
+	pub keyring_timeout: Option<u64>,
+
+	/// Seconds between lines showing the progress of downloads when output
+	/// isn't a terminal, such as in CI.
+	#[clap(
+		long,
+		value_name = "seconds",
+		env = "VSCODE_CLI_PROGRESS_INTERVAL",
+		global = true
+	)]
+	pub progress_interval: Option<u64>,
+}
+
+fn parse_auth_profile(s: &str) -> Result<String, String> {
+	if auth::is_valid_profile_name(s) {
+		Ok(s.to_string())
+	} else {
+		Err(format!(
+			"profile names may only contain letters, numbers, '-' and '_', got `{}`",
+			s
+		))
+	}
 }
 
 impl GlobalOptions {
+	/// Gets the format to print a command's results in. Commands that have
+	/// their own `--json` flag pass it, which also selects JSON.
+	pub fn output_format(&self, json: bool) -> OutputFormat {
+		if json {
+			OutputFormat::Json
+		} else {
+			self.output.unwrap_or_default()
+		}
+	}
+
+	/// Gets how much commands print besides their results.
+	pub fn verbosity(&self) -> Verbosity {
+		if self.quiet {
+			Verbosity::Quiet
+		} else if self.verbose {
+			Verbosity::Verbose
+		} else {
+			Verbosity::Normal
+		}
+	}
+
 	pub fn add_code_args(&self, target: &mut Vec<String>) {
 		if self.verbose {
 			target.push("--verbose".to_string());
 		}
-		if let Some(log) = self.log {
+		if let Some(log) = self.log_level() {
 			target.push(format!("--log={}", log));
 		}
 		if self.disable_telemetry {
@@ -528,6 +1264,96 @@ impl GlobalOptions {
 			target.push(format!("--telemetry-level={}", telemetry_level));
 		}
 	}
+
+	/// Gets the level set for logs that aren't from a particular module, which
+	/// is what's passed on to the editor and server.
+	pub fn log_level(&self) -> Option<log::Level> {
+		self.log.as_ref().map(|f| f.default_level())
+	}
+
+	/// Adds the options for how logs are written, for a service to log the
+	/// same way as the command that installed it.
+	pub fn add_service_log_args(&self, target: &mut Vec<String>) {
+		if let Some(format) = self.log_format {
+			target.push(format!("--log-format={}", format));
+		}
+		if let Some(size) = self.log_file_max_size {
+			target.push(format!("--log-file-max-size={}", size));
+		}
+		if let Some(keep) = self.log_file_keep {
+			target.push(format!("--log-file-keep={}", keep));
+		}
+		if self.log_file_compress {
+			target.push("--log-file-compress".to_string());
+		}
+		for sink in &self.log_sink {
+			target.push(format!("--log-sink={}", sink));
+		}
+		// the file sink needs its path, which is made absolute since services
+		// don't run where they were installed from
+		if let Some(file) = &self.log_to_file {
+			if self.log_sink.contains(&log::LogSinkKind::File) {
+				let file = std::env::current_dir()
+					.map(|d| d.join(file))
+					.unwrap_or_else(|_| file.clone());
+				target.push(format!("--log-to-file={}", file.display()));
+			}
+		}
+	}
+
+	/// Adds the config file given with --config, for a service to use the same
+	/// defaults as the command that installed it.
+	pub fn add_service_config_args(&self, target: &mut Vec<String>) {
+		if let Some(config) = &self.config {
+			let config = std::env::current_dir()
+				.map(|d| d.join(config))
+				.unwrap_or_else(|_| config.clone());
+			target.push(format!("--config={}", config.display()));
+		}
+	}
+
+	/// Adds the options for the servers it runs, such as
+	/// --skip-requirements-check, for a service to run them as the command
+	/// that installed it would. Those given in the environment are passed as
+	/// arguments, since services don't inherit it.
+	pub fn add_service_server_args(&self, target: &mut Vec<String>) {
+		if self.skip_requirements_check {
+			target.push("--skip-requirements-check".to_string());
+		}
+		if let Some(arch) = self.arch {
+			target.push(format!("--arch={}", arch));
+		}
+		if let Some(platform) = self.assume_platform {
+			target.push(format!("--assume-platform={}", platform.name()));
+		}
+		if self.ignore_preflight {
+			target.push("--ignore-preflight".to_string());
+		}
+	}
+
+	/// Adds the token passphrase, if any, for a service to be able to open
+	/// its encrypted credentials. It's passed in the environment rather than
+	/// as an argument so it's not visible in the service's command line.
+	pub fn add_service_env(&self, target: &mut Vec<(String, String)>) {
+		if let Some(passphrase) = &self.token_passphrase {
+			target.push((
+				"VSCODE_CLI_TOKEN_PASSPHRASE".to_string(),
+				passphrase.clone(),
+			));
+		}
+	}
+
+	pub fn log_rotation(&self) -> log::LogRotation {
+		let default = log::LogRotation::default();
+		log::LogRotation {
+			max_size: self
+				.log_file_max_size
+				.map(|mb| mb.saturating_mul(1024 * 1024))
+				.unwrap_or(default.max_size),
+			keep: self.log_file_keep.unwrap_or(default.keep),
+			compress: self.log_file_compress,
+		}
+	}
 }
 
 #[derive(Args, Debug, Default, Clone)]
@@ -610,9 +1436,12 @@ impl fmt::Display for SyncState {
 	}
 }
 
-#[derive(ValueEnum, Clone, Copy, Debug)]
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum OutputFormat {
+	/// A single JSON value, for scripts.
 	Json,
+	/// Human-readable text.
+	#[default]
 	Text,
 }
 
@@ -650,6 +1479,11 @@ pub struct TunnelServeArgs {
 	#[clap(long)]
 	pub no_sleep: bool,
 
+	/// Follows the output of a tunnel that's already running on this machine
+	/// instead of starting one. Detaching leaves the tunnel running.
+	#[clap(long)]
+	pub attach: bool,
+
 	/// Sets the machine name for port forwarding service
 	#[clap(long)]
 	pub name: Option<String>,
@@ -673,12 +1507,99 @@ pub struct TunnelServeArgs {
 	/// Set the root path for extensions.
 	#[clap(long)]
 	pub extensions_dir: Option<String>,
+
+	/// Maximum number of consecutive times to restart the server if it exits unexpectedly.
+	#[clap(long, default_value_t = constants::DEFAULT_SERVER_RESTART_LIMIT)]
+	pub server_restart_limit: u32,
+
+	/// Exits after no clients have been connected for the given number of seconds.
+	#[clap(long, hide = true)]
+	pub idle_timeout: Option<u64>,
+
+	/// Number of seconds before the access token expires that it's refreshed
+	/// in the background. Defaults to 10 minutes.
+	#[clap(long, value_name = "SECONDS")]
+	pub token_refresh_margin: Option<u64>,
+
+	/// Number of seconds to wait for clients to disconnect after a SIGTERM
+	/// before shutting down. Defaults to 10 seconds.
+	#[clap(long, value_name = "SECONDS")]
+	pub drain_timeout: Option<u64>,
+
+	/// Skips checking whether a tunnel is already running on the other side
+	/// of WSL before registering this one.
+	#[clap(long)]
+	pub ignore_interop: bool,
+
+	/// Lets clients that answered the auth challenge with the
+	/// --remote-exec-token run the commands given by --remote-exec-allow on
+	/// this machine. Each run is recorded in remote-exec-audit.log in the CLI
+	/// data directory.
+	#[clap(long, requires = "remote_exec_token")]
+	pub enable_remote_exec: bool,
+
+	/// A command line clients may run with remote exec, such as "systemctl
+	/// --user status". Runs must have exactly the arguments given, since
+	/// extra ones can change what a command runs. Repeat it, or set a list in
+	/// the config file, to allow several.
+	#[clap(long, value_name = "COMMAND", requires = "enable_remote_exec")]
+	pub remote_exec_allow: Vec<String>,
+
+	/// Secret that clients must send with the auth challenge to use remote
+	/// exec. Anyone who can connect to the tunnel can answer the challenge.
+	#[clap(long, value_name = "TOKEN", env = "VSCODE_CLI_REMOTE_EXEC_TOKEN")]
+	pub remote_exec_token: Option<String>,
+
+	/// Watches for ports that the server, or processes started from it, listen
+	/// on and announces them to connected clients.
+	#[clap(long)]
+	pub auto_forward_ports: bool,
+
+	/// Forwards the ports found by --auto-forward-ports with this visibility,
+	/// then unforwards them once they've stopped listening for a few seconds.
+	/// Otherwise ports are only announced.
+	#[clap(long, value_name = "VISIBILITY", requires = "auto_forward_ports")]
+	pub auto_forward_visibility: Option<PortVisibility>,
+
+	/// A port that --auto-forward-ports ignores, such as a debugger's. Repeat it to ignore several.
+	#[clap(long, value_name = "PORT", requires = "auto_forward_ports")]
+	pub auto_forward_exclude: Vec<u16>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortVisibility {
+	/// Only the tunnel's owner can connect.
+	Private,
+	/// Anyone with the URL can connect.
+	Public,
+}
+
+impl From<PortVisibility> for PortPrivacy {
+	fn from(v: PortVisibility) -> Self {
+		match v {
+			PortVisibility::Private => PortPrivacy::Private,
+			PortVisibility::Public => PortPrivacy::Public,
+		}
+	}
 }
 
 impl TunnelServeArgs {
 	pub fn apply_to_server_args(&self, csa: &mut CodeServerArgs) {
 		csa.install_extensions
 			.extend_from_slice(&self.install_extension);
+		csa.restart_limit = self.server_restart_limit;
+		if let (true, Some(token)) = (self.enable_remote_exec, &self.remote_exec_token) {
+			csa.remote_exec = Some(RemoteExecPolicy::new(
+				&self.remote_exec_allow,
+				token.clone(),
+			));
+		}
+		if self.auto_forward_ports {
+			csa.auto_forward = Some(AutoForwardPolicy::new(
+				self.auto_forward_visibility.map(PortPrivacy::from),
+				&self.auto_forward_exclude,
+			));
+		}
 
 		if let Some(d) = &self.server_data_dir {
 			csa.server_data_dir = Some(d.clone());
@@ -705,13 +1626,13 @@ pub enum TunnelSubcommand {
 	Prune,
 
 	/// Stops any running tunnel on the system.
-	Kill,
+	Kill(TunnelKillArgs),
 
 	/// Restarts any running tunnel on the system.
 	Restart,
 
 	/// Gets whether there is a tunnel running on the current machine.
-	Status,
+	Status(TunnelStatusArgs),
 
 	/// Rename the name of this machine associated with port forwarding service.
 	Rename(TunnelRenameArgs),
@@ -719,9 +1640,31 @@ pub enum TunnelSubcommand {
 	/// Remove this machine's association with the port forwarding service.
 	Unregister,
 
+	/// Checks for common problems that stop the tunnel from working.
+	Doctor(TunnelDoctorArgs),
+
+	/// Lists the RPC methods supported by the running tunnel.
+	RpcMethods(TunnelRpcMethodsArgs),
+
+	/// Prints call counts and latencies of the running tunnel's RPC methods.
+	Metrics(TunnelMetricsArgs),
+
+	/// Measures the round-trip time to the running tunnel.
+	Ping(TunnelPingArgs),
+
+	/// Lists the ports forwarded by the running tunnel's clients.
+	Ports(TunnelPortsArgs),
+
+	/// Shows the logs of the running tunnel.
+	Log(TunnelLogArgs),
+
 	#[clap(subcommand)]
 	User(TunnelUserSubCommands),
 
+	/// Manages the servers the tunnel has installed.
+	#[clap(subcommand)]
+	Server(TunnelServerSubCommands),
+
 	/// (Preview) Manages the tunnel when installed as a system service,
 	#[clap(subcommand)]
 	Service(TunnelServiceSubCommands),
@@ -737,14 +1680,20 @@ pub enum TunnelServiceSubCommands {
 	Install(TunnelServiceInstallArgs),
 
 	/// Uninstalls and stops the tunnel service.
-	Uninstall,
+	Uninstall(TunnelServiceUninstallArgs),
+
+	/// Restarts the tunnel service and waits for it to be ready.
+	Restart(TunnelServiceRestartArgs),
 
 	/// Shows logs for the running service.
-	Log,
+	Log(TunnelServiceLogArgs),
+
+	/// Shows whether the service is installed and running.
+	Status(TunnelServiceStatusArgs),
 
 	/// Internal command for running the service
 	#[clap(hide = true)]
-	InternalRun,
+	InternalRun(TunnelServiceInternalRunArgs),
 }
 
 #[derive(Args, Debug, Clone)]
@@ -756,6 +1705,243 @@ pub struct TunnelServiceInstallArgs {
 	/// Sets the machine name for port forwarding service
 	#[clap(long)]
 	pub name: Option<String>,
+
+	/// Installs a system-level service that starts at boot, rather than when
+	/// the current user logs in. Requires root.
+	#[clap(long, requires = "service_user", conflicts_with = "name")]
+	pub system: bool,
+
+	/// The user to run the system-level service as. State and credentials are
+	/// read from this user's data directory.
+	#[clap(long, requires = "system")]
+	pub service_user: Option<String>,
+
+	/// Starts the tunnel on demand when a client connects to its socket, rather
+	/// than at login. Only supported with systemd.
+	#[clap(long, conflicts_with = "system")]
+	pub socket_activated: bool,
+
+	/// Number of seconds without connected clients after which a socket
+	/// activated tunnel exits.
+	#[clap(long, requires = "socket_activated", default_value_t = constants::DEFAULT_SOCKET_IDLE_TIMEOUT_SECS)]
+	pub idle_timeout: u64,
+
+	/// Sets an environment variable for the service, such as a proxy setting.
+	/// May be given multiple times.
+	#[clap(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+	pub env: Vec<(String, String)>,
+
+	/// Number of seconds the service waits for clients to disconnect when it's
+	/// stopped, before shutting down. Defaults to 10 seconds.
+	#[clap(long, value_name = "SECONDS")]
+	pub drain_timeout: Option<u64>,
+
+	/// Replaces an existing service registration, even if it runs a different
+	/// or missing executable.
+	#[clap(long)]
+	pub force: bool,
+
+	/// Provider the service logs in with if it starts without credentials.
+	/// Saved like `tunnel user set-default-provider`.
+	#[clap(value_enum, long, env = "VSCODE_CLI_DEFAULT_AUTH_PROVIDER")]
+	pub default_provider: Option<AuthProvider>,
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+	match s.split_once('=') {
+		Some((key, value)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+			Ok((key.to_string(), value.to_string()))
+		}
+		_ => Err(format!("expected KEY=VALUE, got `{}`", s)),
+	}
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelServiceInternalRunArgs {
+	/// Exits after no clients have been connected for the given number of seconds.
+	#[clap(long)]
+	pub idle_timeout: Option<u64>,
+
+	/// Maximum number of consecutive times to restart the tunnel in-process
+	/// if it fails, before exiting and letting the service manager take over.
+	#[clap(long, default_value_t = constants::DEFAULT_TUNNEL_RESTART_LIMIT)]
+	pub restart_limit: u32,
+
+	/// Logs the RPC metrics at trace level every given number of minutes.
+	#[clap(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+	pub metrics_log_interval: Option<u64>,
+
+	/// Number of seconds to wait for clients to disconnect after a SIGTERM
+	/// before shutting down.
+	#[clap(long, value_name = "SECONDS")]
+	pub drain_timeout: Option<u64>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelServiceUninstallArgs {
+	/// Uninstalls the system-level service. Requires root.
+	#[clap(long)]
+	pub system: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelServiceLogArgs {
+	/// Keeps printing new log lines as they're written.
+	#[clap(long, short)]
+	pub follow: bool,
+
+	/// Number of recent log lines to show.
+	#[clap(long, short = 'n', default_value_t = 20)]
+	pub lines: usize,
+
+	/// Writes the service's logs to the file instead, such as for a bug
+	/// report, compressed if its name ends in `.gz`. Secrets are redacted as
+	/// in other logs.
+	#[clap(long, value_name = "path", conflicts_with = "follow")]
+	pub output_file: Option<PathBuf>,
+
+	/// Only writes logs from this long ago, such as `30m`, `2h`, or `7d`.
+	#[clap(long, value_name = "duration", requires = "output_file", value_parser = parse_since)]
+	pub since: Option<Duration>,
+}
+
+/// Parses a duration in seconds, minutes, hours, or days, such as `2h`.
+fn parse_since(s: &str) -> Result<Duration, String> {
+	let err = || format!("expected a duration such as 30m, 2h, or 7d, got `{}`", s);
+	let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+	let n: u64 = n.parse().map_err(|_| err())?;
+	let unit_secs = match unit {
+		"s" => 1,
+		"m" => 60,
+		"h" => 60 * 60,
+		"d" => 24 * 60 * 60,
+		_ => return Err(err()),
+	};
+
+	n.checked_mul(unit_secs)
+		.map(Duration::from_secs)
+		.ok_or_else(err)
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelServiceRestartArgs {
+	/// Number of seconds to wait for the tunnel to be ready after restarting.
+	#[clap(long, default_value_t = 60)]
+	pub timeout: u64,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelServiceStatusArgs {
+	/// Prints the status as JSON.
+	#[clap(long)]
+	pub json: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelStatusArgs {
+	/// Skips looking for a tunnel on the other side of WSL when no tunnel is
+	/// running here.
+	#[clap(long)]
+	pub ignore_interop: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelRpcMethodsArgs {
+	/// Prints the methods as JSON.
+	#[clap(long)]
+	pub json: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelMetricsArgs {
+	/// Prints the metrics as JSON.
+	#[clap(long)]
+	pub json: bool,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TunnelServerSubCommands {
+	/// Stops and removes the server for a commit, so that the next client
+	/// to ask for it gets a fresh install.
+	Uninstall(TunnelServerUninstallArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelServerUninstallArgs {
+	/// Commit of the server to uninstall.
+	pub commit: String,
+
+	/// Uninstalls the server even if clients are connected to it.
+	#[clap(long)]
+	pub force: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelPortsArgs {
+	/// Prints the ports as JSON.
+	#[clap(long)]
+	pub json: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelPingArgs {
+	/// Number of pings to send.
+	#[clap(long, default_value_t = 4)]
+	pub count: usize,
+
+	/// Pings the control server listening on the address, such as that of a
+	/// `command-shell --on-port` on another machine, rather than the local
+	/// tunnel. That measures the same connection an editor uses.
+	#[clap(long, value_name = "HOST:PORT", conflicts_with = "socket")]
+	pub address: Option<String>,
+
+	/// Pings the control server listening on the socket or named pipe, such as
+	/// that of a `command-shell --on-socket`.
+	#[clap(long, value_name = "PATH")]
+	pub socket: Option<PathBuf>,
+
+	/// Prints the results as JSON.
+	#[clap(long)]
+	pub json: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelLogArgs {
+	/// Keeps printing new log lines as they're written.
+	#[clap(long, short)]
+	pub follow: bool,
+
+	/// Number of recent log lines to show.
+	#[clap(long, short = 'n', default_value_t = 20)]
+	pub lines: usize,
+
+	/// Only shows logs at this level or more severe.
+	#[clap(long, value_enum, value_name = "level")]
+	pub level: Option<log::Level>,
+
+	/// Also shows the output of the servers the tunnel runs. This is logged
+	/// at the trace level, so the tunnel needs to run with `--verbose`.
+	#[clap(long)]
+	pub server: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelKillArgs {
+	/// Terminates the tunnel process without first asking it to shut down.
+	#[clap(long)]
+	pub force: bool,
+
+	/// Number of seconds to wait for the tunnel to shut down before it's
+	/// terminated.
+	#[clap(long, default_value_t = 10)]
+	pub timeout: u64,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelDoctorArgs {
+	/// Prints the results as JSON.
+	#[clap(long)]
+	pub json: bool,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -777,13 +1963,105 @@ pub struct TunnelForwardArgs {
 #[derive(Subcommand, Debug, Clone)]
 pub enum TunnelUserSubCommands {
 	/// Log in to port forwarding service
-	Login(LoginArgs),
+	Login(TunnelUserLoginArgs),
 
-	/// Log out of port forwarding service
-	Logout,
+	/// Log out of port forwarding service, revoking the token with its
+	/// provider
+	Logout(TunnelUserLogoutArgs),
 
-	/// Show the account that's logged into port forwarding service
-	Show,
+	/// Show the account that's logged into port forwarding service. Pass
+	/// `--verbose` to also show details of the stored token.
+	Show(TunnelUserShowArgs),
+
+	/// Set the provider used for logins where none is chosen, such as by the
+	/// service. Interactive logins start with it selected.
+	SetDefaultProvider(TunnelUserSetDefaultProviderArgs),
+
+	/// Make a profile the one used when `--profile` is not given
+	Switch(TunnelUserSwitchArgs),
+
+	/// List the auth profiles that have been logged into
+	List,
+
+	/// Move stored credentials to a different storage backend, and use it from
+	/// now on
+	MigrateStorage(TunnelUserMigrateStorageArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelUserMigrateStorageArgs {
+	/// Storage backend to move credentials to.
+	#[clap(value_enum)]
+	pub to: auth::TokenStorageKind,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelUserLoginArgs {
+	#[clap(flatten)]
+	pub login: LoginArgs,
+
+	/// Read a GitHub personal access token from stdin instead of logging in
+	/// interactively. The token can also be given in the VSCODE_CLI_GITHUB_TOKEN
+	/// environment variable. It's checked for the scopes the tunnel needs.
+	#[clap(long, conflicts_with = "access_token")]
+	pub access_token_stdin: bool,
+
+	/// Request a device code and print what's needed to complete the login,
+	/// then exit without waiting. Finish the login with `--continue`.
+	#[clap(long, conflicts_with_all = ["access_token", "access_token_stdin", "continue_login"])]
+	pub start: bool,
+
+	/// Wait for a login begun with `--start` to be completed, using the handle
+	/// it printed, and store the resulting token.
+	#[clap(long = "continue", value_name = "handle", conflicts_with_all = ["access_token", "access_token_stdin", "provider"])]
+	pub continue_login: Option<String>,
+
+	/// Print the details of a login begun with `--start` as JSON.
+	#[clap(long, requires = "start")]
+	pub json: bool,
+
+	/// Scopes to request in addition to the ones needed to host a tunnel,
+	/// separated by commas.
+	#[clap(long, value_name = "scopes", value_delimiter = ',')]
+	pub scopes: Vec<String>,
+
+	/// Log in again with the current provider to grant scopes the stored
+	/// credentials are missing, keeping the ones they already have.
+	#[clap(long, conflicts_with_all = ["access_token", "access_token_stdin", "start", "continue_login", "provider"])]
+	pub upgrade_scopes: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelUserLogoutArgs {
+	/// Only remove the credentials from this machine, without revoking them.
+	/// Use this when offline.
+	#[clap(long)]
+	pub local_only: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelUserShowArgs {
+	/// Print the details of the stored credentials as JSON.
+	#[clap(long)]
+	pub json: bool,
+
+	/// Check the credentials with the provider. Otherwise only what's stored
+	/// on this machine is shown, without any network requests.
+	#[clap(long)]
+	pub validate: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelUserSetDefaultProviderArgs {
+	#[clap(value_enum)]
+	pub provider: AuthProvider,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelUserSwitchArgs {
+	/// Name of the profile to switch to.
+	#[clap(value_parser = parse_auth_profile)]
+	pub profile: String,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -806,3 +2084,385 @@ pub enum AuthProvider {
 	Microsoft,
 	Github,
 }
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use clap::{CommandFactory, FromArgMatches};
+
+	use super::*;
+	use crate::util::test_env::in_child_with_env;
+
+	/// Calls the function with each subcommand, and the path to it.
+	fn visit(
+		cmd: &clap::Command,
+		path: &mut Vec<String>,
+		f: &mut impl FnMut(&[String], &clap::Command),
+	) {
+		f(path, cmd);
+		for sub in cmd.get_subcommands() {
+			path.push(sub.get_name().to_string());
+			visit(sub, path, f);
+			path.pop();
+		}
+	}
+
+	#[test]
+	fn test_env_var_name() {
+		assert_eq!(env_var_name(&[], "verbose"), "VSCODE_CLI_VERBOSE");
+		assert_eq!(
+			env_var_name(&["serve-web"], "server-base-path"),
+			"VSCODE_CLI_SERVE_WEB_SERVER_BASE_PATH"
+		);
+	}
+
+	#[test]
+	fn test_every_flag_has_an_env_var() {
+		for cmd in [IntegratedCli::command(), StandaloneCli::command()] {
+			let cmd = add_env_bindings(cmd);
+			visit(&cmd, &mut vec![], &mut |path, cmd| {
+				let mut seen = HashMap::new();
+				for arg in cmd.get_arguments().filter(|a| is_user_facing_flag(a)) {
+					let env = arg.get_env().unwrap_or_else(|| {
+						panic!("--{} in {:?} has no env var", arg.get_long().unwrap(), path)
+					});
+					let env = env.to_string_lossy().to_string();
+					assert!(env.starts_with(ENV_PREFIX), "{} isn't prefixed", env);
+					if let Some(other) = seen.insert(env.clone(), arg.get_id().clone()) {
+						panic!("{} is bound to both {} and {}", env, other, arg.get_id());
+					}
+				}
+			});
+		}
+	}
+
+	#[test]
+	fn test_flags_from_env_accept_numbers() {
+		const ENV: &str = "VSCODE_CLI_SERVE_WEB_WITHOUT_CONNECTION_TOKEN";
+		for value in ["1", "true", "0", "false"] {
+			if !in_child_with_env(
+				concat!(module_path!(), "::test_flags_from_env_accept_numbers"),
+				&[(ENV, value)],
+			) {
+				continue;
+			}
+
+			// in the child, the value is whichever it was started with
+			let value = std::env::var(ENV).unwrap();
+			let matches = add_env_bindings(StandaloneCli::command())
+				.try_get_matches_from(["code", "serve-web"])
+				.unwrap();
+			let cli = StandaloneCli::from_arg_matches(&matches).unwrap();
+			match cli.core.subcommand {
+				Some(Commands::ServeWeb(args)) => {
+					let expected = value == "1" || value == "true";
+					assert_eq!(args.without_connection_token, expected, "{}", value)
+				}
+				_ => panic!("expected serve-web"),
+			}
+			return;
+		}
+	}
+
+	#[test]
+	fn test_flags_from_env_reject_other_values() {
+		if !in_child_with_env(
+			concat!(module_path!(), "::test_flags_from_env_reject_other_values"),
+			&[("VSCODE_CLI_SERVE_WEB_OPEN", "maybe")],
+		) {
+			return;
+		}
+
+		let result =
+			add_env_bindings(StandaloneCli::command()).try_get_matches_from(["code", "serve-web"]);
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn test_parse_since() {
+		assert_eq!(parse_since("90s"), Ok(Duration::from_secs(90)));
+		assert_eq!(parse_since("30m"), Ok(Duration::from_secs(30 * 60)));
+		assert_eq!(parse_since("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+		assert_eq!(parse_since("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+		for bad in ["", "2", "h", "2w", "-1h", "1.5h", "99999999999999999999d"] {
+			assert!(parse_since(bad).is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn test_metrics_log_interval() {
+		let parse = |interval: &str| {
+			StandaloneCli::try_parse_from([
+				"code",
+				"tunnel",
+				"service",
+				"internal-run",
+				"--metrics-log-interval",
+				interval,
+			])
+		};
+
+		assert!(parse("5").is_ok());
+		assert!(parse("0").is_err());
+	}
+
+	#[test]
+	fn test_service_log_args() {
+		let service_args = |argv: &[&str]| {
+			let cli = StandaloneCli::try_parse_from(argv).unwrap();
+			let mut args = vec![];
+			cli.core.global_options.add_service_log_args(&mut args);
+			args
+		};
+
+		let args = service_args(&["code", "tunnel", "--log-sink=file", "--log-to-file=t.log"]);
+		assert_eq!(args[0], "--log-sink=file");
+		let file = args[1].strip_prefix("--log-to-file=").unwrap();
+		assert!(std::path::Path::new(file).is_absolute(), "{}", file);
+		assert!(file.ends_with("t.log"), "{}", file);
+
+		// without the file sink, the service keeps its own log file
+		let args = service_args(&["code", "tunnel", "--log-sink=stderr", "--log-to-file=t.log"]);
+		assert_eq!(args, vec!["--log-sink=stderr"]);
+	}
+
+	#[test]
+	fn test_service_server_args() {
+		let service_args = |argv: &[&str]| {
+			let cli = StandaloneCli::try_parse_from(argv).unwrap();
+			let mut args = vec![];
+			cli.core.global_options.add_service_server_args(&mut args);
+			args
+		};
+
+		assert!(service_args(&["code", "tunnel", "service", "install"]).is_empty());
+
+		let args = service_args(&[
+			"code",
+			"tunnel",
+			"service",
+			"install",
+			"--skip-requirements-check",
+			"--arch=armhf",
+			"--assume-platform=linux-legacy-x64",
+			"--ignore-preflight",
+		]);
+		assert_eq!(
+			args,
+			vec![
+				"--skip-requirements-check",
+				"--arch=armhf",
+				"--assume-platform=linux-legacy-x64",
+				"--ignore-preflight",
+			]
+		);
+
+		// which parse again as the same options
+		let mut argv = vec!["code".to_string()];
+		argv.extend(args);
+		argv.extend(["tunnel", "service", "internal-run"].map(String::from));
+		let cli = StandaloneCli::try_parse_from(argv).unwrap();
+		let options = &cli.core.global_options;
+		assert!(options.skip_requirements_check && options.ignore_preflight);
+		assert_eq!(options.arch, Some(Arch::Armhf));
+		assert_eq!(options.assume_platform, Some(Platform::LinuxX64Legacy));
+	}
+
+	#[test]
+	fn test_log_file_max_size() {
+		let parse = |size: &str| {
+			StandaloneCli::try_parse_from(["code", "tunnel", "--log-file-max-size", size])
+				.map(|cli| cli.core.global_options.log_rotation().max_size)
+		};
+
+		assert_eq!(parse("2").unwrap(), 2 * 1024 * 1024);
+		assert_eq!(parse(&u64::MAX.to_string()).unwrap(), u64::MAX);
+		assert!(parse("0").is_err());
+	}
+
+	#[test]
+	fn test_status_commands() {
+		let cli = StandaloneCli::try_parse_from(["code", "status"]).unwrap();
+		assert!(matches!(cli.core.subcommand, Some(Commands::Status)));
+
+		let cli = StandaloneCli::try_parse_from(["code", "machine-status", "--json"]).unwrap();
+		match cli.core.subcommand {
+			Some(Commands::MachineStatus(args)) => assert!(args.json),
+			other => panic!("expected machine-status, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn test_verbosity() {
+		let verbosity = |args: &[&str]| {
+			StandaloneCli::try_parse_from(args.iter())
+				.map(|cli| cli.core.global_options.verbosity())
+		};
+
+		assert_eq!(verbosity(&["code", "tunnel"]).unwrap(), Verbosity::Normal);
+		assert_eq!(
+			verbosity(&["code", "tunnel", "--quiet"]).unwrap(),
+			Verbosity::Quiet
+		);
+		assert_eq!(
+			verbosity(&["code", "--verbose", "update"]).unwrap(),
+			Verbosity::Verbose
+		);
+		assert!(verbosity(&["code", "--quiet", "--verbose", "update"]).is_err());
+	}
+
+	const TEST_ALIASES: &[Alias] = &[
+		Alias {
+			old: &["tunnel", "logs"],
+			new: "log",
+			removed_in: "2.0",
+		},
+		Alias {
+			old: &["serve-web", "--listen-port"],
+			new: "--port",
+			removed_in: "2.0",
+		},
+		Alias {
+			old: &["--log-level"],
+			new: "--log",
+			removed_in: "2.0",
+		},
+	];
+
+	fn resolve(
+		args: &[&str],
+		version: Option<&str>,
+	) -> Result<(StandaloneCli, Vec<Deprecation>), Deprecation> {
+		let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+		let (args, deprecations) =
+			resolve_aliases(&StandaloneCli::command(), TEST_ALIASES, version, &args)?;
+		Ok((StandaloneCli::try_parse_from(args).unwrap(), deprecations))
+	}
+
+	fn deprecation(old: &str, new: &str) -> Deprecation {
+		Deprecation {
+			old: old.to_string(),
+			new: new.to_string(),
+			removed_in: "2.0".to_string(),
+		}
+	}
+
+	#[test]
+	fn test_alias_dispatch() {
+		// the log level isn't taken for a command
+		let (cli, deprecations) =
+			resolve(&["code", "--log", "info", "tunnel", "logs"], Some("1.90.0")).unwrap();
+		assert!(matches!(
+			cli.core.subcommand,
+			Some(Commands::Tunnel(TunnelArgs {
+				subcommand: Some(TunnelSubcommand::Log(_)),
+				..
+			}))
+		));
+		assert_eq!(deprecations, vec![deprecation("tunnel logs", "tunnel log")]);
+
+		let (cli, deprecations) =
+			resolve(&["code", "serve-web", "--listen-port=9000"], None).unwrap();
+		match cli.core.subcommand {
+			Some(Commands::ServeWeb(args)) => assert_eq!(args.port, 9000),
+			_ => panic!("expected serve-web"),
+		}
+		assert_eq!(
+			deprecations,
+			vec![deprecation("serve-web --listen-port", "serve-web --port")]
+		);
+
+		// global flags are renamed in subcommands, and keep their value
+		let (cli, deprecations) =
+			resolve(&["code", "tunnel", "--log-level", "debug", "status"], None).unwrap();
+		assert_eq!(cli.core.global_options.log_level(), Some(log::Level::Debug));
+		assert_eq!(
+			deprecations,
+			vec![deprecation("tunnel --log-level", "tunnel --log")]
+		);
+
+		// names are only aliased where they were defined
+		let (_, deprecations) = resolve(&["code", "logs"], None).unwrap();
+		assert!(deprecations.is_empty());
+	}
+
+	#[test]
+	fn test_removed_alias() {
+		let removed = resolve(&["code", "tunnel", "logs"], Some("2.0.1")).unwrap_err();
+		assert_eq!(
+			removed.removed_message(),
+			"`tunnel logs` was removed in 2.0, use `tunnel log` instead"
+		);
+		assert_eq!(
+			removed.removed_error_kind(),
+			clap::error::ErrorKind::InvalidSubcommand
+		);
+
+		let removed =
+			resolve(&["code", "serve-web", "--listen-port", "1"], Some("2.0")).unwrap_err();
+		assert_eq!(
+			removed.removed_error_kind(),
+			clap::error::ErrorKind::UnknownArgument
+		);
+	}
+
+	#[test]
+	fn test_aliases_point_to_existing_names() {
+		let cmd = StandaloneCli::command();
+		for alias in ALIASES.iter().chain(TEST_ALIASES) {
+			let (old, path) = alias.old.split_last().unwrap();
+			let parent = path.iter().fold(&cmd, |c, p| {
+				c.find_subcommand(p)
+					.unwrap_or_else(|| panic!("no command {} for {:?}", p, alias.old))
+			});
+			let has_flag = |name: &str| {
+				parent
+					.get_arguments()
+					.any(|a| a.get_long() == name.strip_prefix("--"))
+			};
+
+			if alias.new.starts_with("--") {
+				assert!(has_flag(alias.new), "no flag {}", alias.new);
+				assert!(!has_flag(old), "{} still exists", old);
+			} else {
+				assert!(
+					parent.find_subcommand(alias.new).is_some(),
+					"no command {}",
+					alias.new
+				);
+				assert!(
+					parent.find_subcommand(old).is_none(),
+					"{} still exists",
+					old
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn test_deprecation_warnings() {
+		let deprecations = vec![deprecation("tunnel logs", "tunnel log")];
+
+		let mut out = vec![];
+		warn_deprecations(&deprecations, &mut out);
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			"warning: `tunnel logs` is deprecated and will be removed in 2.0, use `tunnel log` instead\n"
+		);
+	}
+
+	#[test]
+	fn test_deprecation_warnings_disabled() {
+		if !in_child_with_env(
+			concat!(module_path!(), "::test_deprecation_warnings_disabled"),
+			&[(NO_DEPRECATION_WARNINGS_ENV, "1")],
+		) {
+			return;
+		}
+
+		let mut out = vec![];
+		warn_deprecations(&[deprecation("tunnel logs", "tunnel log")], &mut out);
+		assert!(out.is_empty());
+	}
+}
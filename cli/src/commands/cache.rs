@@ -0,0 +1,34 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use crate::{tunnels::paths::list_installed_builds, util::errors::AnyError};
+
+use super::{
+	args::{CacheStatsArgs, CacheSubcommand},
+	output::CacheStatsOutput,
+	CommandContext,
+};
+
+pub async fn cache(ctx: CommandContext, subcommand: CacheSubcommand) -> Result<i32, AnyError> {
+	match subcommand {
+		CacheSubcommand::Stats(args) => stats(ctx, args),
+	}
+}
+
+fn stats(ctx: CommandContext, args: CacheStatsArgs) -> Result<i32, AnyError> {
+	let servers = list_installed_builds(&ctx.log, &ctx.paths);
+	let output = CacheStatsOutput {
+		total_bytes: servers.iter().map(|b| b.size).sum(),
+		servers,
+	};
+	ctx.log.result(
+		ctx.args
+			.global_options
+			.output_format(args.json)
+			.render(&output),
+	);
+
+	Ok(0)
+}
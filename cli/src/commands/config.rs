@@ -0,0 +1,639 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	ffi::OsString,
+	fs,
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+use clap::{ArgAction, CommandFactory, Parser};
+
+use crate::{
+	constants::VSCODE_CLI_VERSION,
+	state::LauncherPaths,
+	util::{
+		errors::{AnyError, CodeError},
+		is_integrated_cli,
+	},
+};
+
+use super::{
+	args::{
+		add_env_bindings, resolve_aliases, warn_deprecations, ConfigSubcommand, IntegratedCli,
+		StandaloneCli, ALIASES,
+	},
+	output::set_deprecations,
+	CommandContext,
+};
+
+/// Name of the config file in the data directory.
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+const CONFIG_ENV: &str = "VSCODE_CLI_CONFIG";
+const DATA_DIR_ENV: &str = "VSCODE_CLI_DATA_DIR";
+
+/// File of default flags. Top-level keys are global flags, and tables are
+/// subcommands, so that `[tunnel] name = "x"` works like `tunnel --name x`.
+#[derive(Default, Debug)]
+pub struct ConfigFile {
+	path: PathBuf,
+	table: toml::Table,
+}
+
+/// A flag set in the config file.
+#[derive(Debug, PartialEq)]
+pub struct ConfigEntry {
+	/// Subcommands the flag is under.
+	pub path: Vec<String>,
+	/// Long name of the flag.
+	pub flag: String,
+	pub value: toml::Value,
+}
+
+impl ConfigEntry {
+	/// Gets the dotted key of the entry, such as `tunnel.name`.
+	pub fn key(&self) -> String {
+		let mut parts = self.path.clone();
+		parts.push(self.flag.clone());
+		parts.join(".")
+	}
+}
+
+impl ConfigFile {
+	/// Loads the config file. A file that doesn't exist is empty.
+	pub fn load(path: &Path) -> Result<Self, CodeError> {
+		let table = match fs::read_to_string(path) {
+			Ok(s) => s.parse::<toml::Table>().map_err(|e| {
+				CodeError::CouldNotReadConfig(path.display().to_string(), e.to_string())
+			})?,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => toml::Table::new(),
+			Err(e) => {
+				return Err(CodeError::CouldNotReadConfig(
+					path.display().to_string(),
+					e.to_string(),
+				))
+			}
+		};
+
+		Ok(Self {
+			path: path.to_path_buf(),
+			table,
+		})
+	}
+
+	/// Gets every flag set in the file.
+	pub fn entries(&self) -> Vec<ConfigEntry> {
+		let mut entries = vec![];
+		collect_entries(&self.table, &mut vec![], &mut entries);
+		entries
+	}
+
+	pub fn get(&self, key: &str) -> Option<&toml::Value> {
+		let parts: Vec<&str> = key.split('.').collect();
+		let (flag, path) = parts.split_last()?;
+		let mut table = &self.table;
+		for p in path {
+			table = table.get(*p)?.as_table()?;
+		}
+		table.get(*flag).filter(|v| !v.is_table())
+	}
+
+	pub fn set(&mut self, key: &str, value: toml::Value) {
+		let parts: Vec<&str> = key.split('.').collect();
+		let (flag, path) = parts.split_last().expect("expected a key");
+		let mut table = &mut self.table;
+		for p in path {
+			let entry = table
+				.entry(p.to_string())
+				.or_insert_with(|| toml::Value::Table(toml::Table::new()));
+			if !entry.is_table() {
+				*entry = toml::Value::Table(toml::Table::new());
+			}
+			table = entry.as_table_mut().unwrap();
+		}
+		table.insert(flag.to_string(), value);
+	}
+
+	/// Removes the key, and any tables left empty. Returns whether it was set.
+	pub fn unset(&mut self, key: &str) -> bool {
+		let parts: Vec<&str> = key.split('.').collect();
+		remove_key(&mut self.table, &parts)
+	}
+
+	/// Saves the file by replacing it, so that it's never left half-written.
+	pub fn save(&self) -> Result<(), CodeError> {
+		let write_err = |e| CodeError::CouldNotWriteConfig(self.path.display().to_string(), e);
+		let dir = match self.path.parent() {
+			Some(d) if !d.as_os_str().is_empty() => d,
+			_ => Path::new("."),
+		};
+		fs::create_dir_all(dir).map_err(write_err)?;
+
+		let mut file = tempfile::NamedTempFile::new_in(dir).map_err(write_err)?;
+		file.write_all(self.table.to_string().as_bytes())
+			.map_err(write_err)?;
+		file.persist(&self.path).map_err(|e| write_err(e.error))?;
+		Ok(())
+	}
+}
+
+fn collect_entries(table: &toml::Table, path: &mut Vec<String>, out: &mut Vec<ConfigEntry>) {
+	for (key, value) in table {
+		match value {
+			toml::Value::Table(t) => {
+				path.push(key.clone());
+				collect_entries(t, path, out);
+				path.pop();
+			}
+			v => out.push(ConfigEntry {
+				path: path.clone(),
+				flag: key.clone(),
+				value: v.clone(),
+			}),
+		}
+	}
+}
+
+fn remove_key(table: &mut toml::Table, parts: &[&str]) -> bool {
+	match parts {
+		[] => false,
+		[flag] => match table.get(*flag) {
+			Some(v) if !v.is_table() => table.remove(*flag).is_some(),
+			_ => false,
+		},
+		[first, rest @ ..] => {
+			let removed = match table.get_mut(*first).and_then(|v| v.as_table_mut()) {
+				Some(t) => remove_key(t, rest),
+				None => return false,
+			};
+			if table
+				.get(*first)
+				.and_then(|v| v.as_table())
+				.map(|t| t.is_empty())
+				.unwrap_or(false)
+			{
+				table.remove(*first);
+			}
+			removed
+		}
+	}
+}
+
+/// Gets the config file to use for the command line: the one given with
+/// `--config`, or the one in the data directory.
+pub fn config_path_for_args(args: &[OsString]) -> Option<PathBuf> {
+	if let Some(p) = flag_value(args, "config").or_else(|| std::env::var(CONFIG_ENV).ok()) {
+		return Some(PathBuf::from(p));
+	}
+
+	let data_dir = flag_value(args, "cli-data-dir").or_else(|| std::env::var(DATA_DIR_ENV).ok());
+	LauncherPaths::resolve_root(data_dir)
+		.ok()
+		.map(|r| r.join(CONFIG_FILE_NAME))
+}
+
+/// Finds the value of a long flag before the command line is parsed.
+fn flag_value(args: &[OsString], name: &str) -> Option<String> {
+	let raw = clap_lex::RawArgs::new(args.iter().cloned());
+	let mut cursor = raw.cursor();
+	raw.next(&mut cursor); // Skip the bin
+
+	while let Some(arg) = raw.next(&mut cursor) {
+		if arg.is_escape() {
+			return None;
+		}
+		if let Some((Ok(long), value)) = arg.to_long() {
+			if long == name {
+				return match value {
+					Some(v) => Some(v.to_string_lossy().to_string()),
+					None => raw
+						.next_os(&mut cursor)
+						.map(|v| v.to_string_lossy().to_string()),
+				};
+			}
+		}
+	}
+
+	None
+}
+
+/// Finds the flag in the schema, following the subcommands in its path.
+fn find_arg<'a>(cmd: &'a clap::Command, path: &[String], flag: &str) -> Option<&'a clap::Arg> {
+	let mut cmd = cmd;
+	for p in path {
+		cmd = cmd.find_subcommand(p)?;
+	}
+	cmd.get_arguments().find(|a| a.get_long() == Some(flag))
+}
+
+fn mut_arg_at(
+	cmd: clap::Command,
+	path: &[String],
+	id: clap::Id,
+	f: impl FnOnce(clap::Arg) -> clap::Arg,
+) -> clap::Command {
+	match path.split_first() {
+		None => cmd.mut_arg(id, f),
+		Some((first, rest)) => cmd.mut_subcommand(first, |sc| mut_arg_at(sc, rest, id, f)),
+	}
+}
+
+fn value_strings(value: &toml::Value) -> Vec<String> {
+	match value {
+		toml::Value::String(s) => vec![s.clone()],
+		toml::Value::Array(a) => a.iter().flat_map(value_strings).collect(),
+		v => vec![v.to_string()],
+	}
+}
+
+/// Makes the flags in the config file the defaults of the command, so that
+/// flags given on the command line or through the environment still win.
+/// Returns warnings for keys that aren't flags.
+pub fn apply_defaults(mut cmd: clap::Command, config: &ConfigFile) -> (clap::Command, Vec<String>) {
+	let mut warnings = vec![];
+	for entry in config.entries() {
+		let id = match find_arg(&cmd, &entry.path, &entry.flag) {
+			Some(a) => a.get_id().clone(),
+			None => {
+				warnings.push(format!(
+					"ignoring unknown key `{}` in {}",
+					entry.key(),
+					config.path.display()
+				));
+				continue;
+			}
+		};
+
+		let values = value_strings(&entry.value);
+		cmd = mut_arg_at(cmd, &entry.path, id, |a| a.default_values(values));
+	}
+
+	(cmd, warnings)
+}
+
+/// Parses the command line, with the defaults from the config file.
+pub fn parse_with_config<T: Parser>(args: &[OsString]) -> T {
+	let config = match config_path_for_args(args).map(|p| ConfigFile::load(&p)) {
+		Some(Ok(c)) => c,
+		Some(Err(e)) => {
+			// no logger exists at this point in the lifecycle, so just log to stderr
+			eprintln!("warning: {}, ignoring it", e);
+			ConfigFile::default()
+		}
+		None => ConfigFile::default(),
+	};
+
+	let (mut cmd, warnings) = apply_defaults(add_env_bindings(T::command()), &config);
+	for w in warnings {
+		eprintln!("warning: {}", w);
+	}
+
+	let (args, deprecations) = match resolve_aliases(&cmd, ALIASES, VSCODE_CLI_VERSION, args) {
+		Ok(r) => r,
+		Err(removed) => cmd
+			.error(removed.removed_error_kind(), removed.removed_message())
+			.exit(),
+	};
+	warn_deprecations(&deprecations, &mut std::io::stderr());
+	set_deprecations(deprecations);
+
+	let matches = cmd.get_matches_from(args);
+	T::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
+
+/// Checks the value against the parser of the flag in the command, returning
+/// it as it's stored.
+fn validate(cmd: &clap::Command, key: &str, value: &str) -> Result<toml::Value, CodeError> {
+	let parts: Vec<String> = key.split('.').map(|s| s.to_string()).collect();
+	let (flag, path) = parts.split_last().expect("expected a key");
+	let arg = find_arg(cmd, path, flag)
+		.filter(|a| a.get_long() != Some("config"))
+		.ok_or_else(|| CodeError::UnknownConfigKey(key.to_string()))?;
+
+	let values: Vec<&str> = match (arg.get_action(), arg.get_value_delimiter()) {
+		(ArgAction::Append, Some(d)) => value.split(d).collect(),
+		_ => vec![value],
+	};
+	// parsed on their own, since the flag's relations to other flags don't
+	// matter for a default
+	let check = clap::Command::new(key.to_string())
+		.no_binary_name(true)
+		.arg(clap::Arg::new("value").value_parser(arg.get_value_parser().clone()));
+	let mut parsed = vec![];
+	for v in &values {
+		parsed.push(check.clone().try_get_matches_from([*v]).map_err(|e| {
+			CodeError::InvalidConfigValue {
+				key: key.to_string(),
+				message: clap_error_message(&e),
+			}
+		})?);
+	}
+
+	Ok(match arg.get_action() {
+		ArgAction::SetTrue | ArgAction::SetFalse => {
+			toml::Value::Boolean(parsed[0].get_one::<bool>("value") == Some(&true))
+		}
+		ArgAction::Append => toml::Value::Array(
+			values
+				.into_iter()
+				.map(|v| toml::Value::String(v.to_string()))
+				.collect(),
+		),
+		_ => toml::Value::String(value.to_string()),
+	})
+}
+
+/// Gets the first line of the error, without its `error: ` prefix.
+fn clap_error_message(e: &clap::Error) -> String {
+	let rendered = e.render().to_string();
+	let line = rendered.lines().next().unwrap_or_default();
+	line.strip_prefix("error: ").unwrap_or(line).to_string()
+}
+
+fn format_value(value: &toml::Value) -> String {
+	match value {
+		toml::Value::String(s) => s.clone(),
+		v => v.to_string(),
+	}
+}
+
+pub async fn config(ctx: CommandContext, command: ConfigSubcommand) -> Result<i32, AnyError> {
+	let path = ctx
+		.args
+		.global_options
+		.config
+		.clone()
+		.unwrap_or_else(|| ctx.paths.root().join(CONFIG_FILE_NAME));
+	let mut config = ConfigFile::load(&path)?;
+
+	match command {
+		ConfigSubcommand::Get(args) => match config.get(&args.key) {
+			Some(v) => ctx.log.result(format_value(v)),
+			None => return Ok(1),
+		},
+		ConfigSubcommand::List => {
+			for entry in config.entries() {
+				ctx.log.result(format!("{} = {}", entry.key(), entry.value));
+			}
+		}
+		ConfigSubcommand::Set(args) => {
+			// the integrated CLI has fewer commands, such as no `update`
+			let cmd = if matches!(is_integrated_cli(), Ok(true)) {
+				IntegratedCli::command()
+			} else {
+				StandaloneCli::command()
+			};
+			let value = validate(&add_env_bindings(cmd), &args.key, &args.value)?;
+			config.set(&args.key, value);
+			config.save()?;
+		}
+		ConfigSubcommand::Unset(args) => {
+			if !config.unset(&args.key) {
+				return Ok(1);
+			}
+			config.save()?;
+		}
+	}
+
+	Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use clap::FromArgMatches;
+
+	use super::*;
+	use crate::{commands::args::Commands, util::test_env::in_child_with_env};
+
+	fn config(contents: &str) -> ConfigFile {
+		ConfigFile {
+			path: PathBuf::from(CONFIG_FILE_NAME),
+			table: contents.parse().unwrap(),
+		}
+	}
+
+	fn validate_standalone(key: &str, value: &str) -> Result<toml::Value, CodeError> {
+		validate(&add_env_bindings(StandaloneCli::command()), key, value)
+	}
+
+	fn parse(config: &ConfigFile, args: &[&str]) -> IntegratedCli {
+		let (cmd, warnings) = apply_defaults(add_env_bindings(IntegratedCli::command()), config);
+		assert_eq!(warnings, Vec::<String>::new());
+		let matches = cmd.try_get_matches_from(args).unwrap();
+		IntegratedCli::from_arg_matches(&matches).unwrap()
+	}
+
+	#[test]
+	fn test_version_and_help_exit_while_parsing() {
+		// answered before a command context, and so any credential store, exists
+		for (args, kind) in [
+			(
+				&["code", "--version"][..],
+				clap::error::ErrorKind::DisplayVersion,
+			),
+			(
+				&["code", "tunnel", "--help"][..],
+				clap::error::ErrorKind::DisplayHelp,
+			),
+		] {
+			let (cmd, _) = apply_defaults(add_env_bindings(IntegratedCli::command()), &config(""));
+			let err = cmd.try_get_matches_from(args).unwrap_err();
+			assert_eq!(err.kind(), kind, "{:?}", args);
+		}
+	}
+
+	fn tunnel_name(cli: &IntegratedCli) -> Option<String> {
+		match &cli.core.subcommand {
+			Some(Commands::Tunnel(t)) => t.serve_args.name.clone(),
+			_ => panic!("expected a tunnel command"),
+		}
+	}
+
+	#[test]
+	fn test_config_is_used_as_defaults() {
+		let config = config(
+			r#"
+			verbose = true
+			log-sink = ["stderr", "syslog"]
+			telemetry-level = "off"
+
+			[tunnel]
+			name = "from-config"
+			"#,
+		);
+
+		let cli = parse(&config, &["code", "tunnel"]);
+		assert!(cli.core.global_options.verbose);
+		assert_eq!(cli.core.global_options.log_sink.len(), 2);
+		assert!(cli.core.global_options.telemetry_level.is_some());
+		assert_eq!(tunnel_name(&cli).as_deref(), Some("from-config"));
+
+		// the command line wins
+		let cli = parse(&config, &["code", "tunnel", "--name", "from-cli"]);
+		assert_eq!(tunnel_name(&cli).as_deref(), Some("from-cli"));
+		let cli = parse(&config, &["code", "--log-sink", "stderr", "tunnel"]);
+		assert_eq!(cli.core.global_options.log_sink.len(), 1);
+
+		// and defaults apply without a config
+		let cli = parse(&ConfigFile::default(), &["code", "tunnel"]);
+		assert!(!cli.core.global_options.verbose);
+		assert_eq!(tunnel_name(&cli), None);
+	}
+
+	#[test]
+	fn test_config_without_environment() {
+		let config = config("keyring-timeout = 5\n[serve-web]\nport = 9000");
+		let cli = parse(&config, &["code", "tunnel"]);
+		assert_eq!(cli.core.global_options.keyring_timeout, Some(5));
+		assert_eq!(serve_web_port(parse(&config, &["code", "serve-web"])), 9000);
+	}
+
+	fn serve_web_port(cli: IntegratedCli) -> u16 {
+		match cli.core.subcommand {
+			Some(Commands::ServeWeb(a)) => a.port,
+			_ => panic!("expected a serve-web command"),
+		}
+	}
+
+	#[test]
+	fn test_environment_wins_over_config() {
+		let vars = [
+			("VSCODE_CLI_KEYRING_TIMEOUT", "10"),
+			// as does an environment variable bound by its flag's name
+			("VSCODE_CLI_SERVE_WEB_PORT", "9001"),
+		];
+		if !in_child_with_env(
+			concat!(module_path!(), "::test_environment_wins_over_config"),
+			&vars,
+		) {
+			return;
+		}
+
+		let config = config("keyring-timeout = 5\n[serve-web]\nport = 9000");
+		let from_env = parse(&config, &["code", "tunnel"]);
+		let from_cli = parse(&config, &["code", "--keyring-timeout", "20", "tunnel"]);
+		assert_eq!(from_env.core.global_options.keyring_timeout, Some(10));
+		assert_eq!(from_cli.core.global_options.keyring_timeout, Some(20));
+
+		let from_env = parse(&config, &["code", "serve-web"]);
+		let from_cli = parse(&config, &["code", "serve-web", "--port", "9002"]);
+		assert_eq!(serve_web_port(from_env), 9001);
+		assert_eq!(serve_web_port(from_cli), 9002);
+	}
+
+	#[test]
+	fn test_unknown_keys_warn() {
+		let config = config(
+			r#"
+			not-a-flag = 1
+			[tunnel]
+			name = "a"
+			[not-a-command]
+			name = "b"
+			"#,
+		);
+
+		let (cmd, warnings) = apply_defaults(IntegratedCli::command(), &config);
+		assert_eq!(
+			warnings,
+			vec![
+				"ignoring unknown key `not-a-command.name` in config.toml",
+				"ignoring unknown key `not-a-flag` in config.toml",
+			]
+		);
+		assert!(cmd.try_get_matches_from(["code", "tunnel"]).is_ok());
+	}
+
+	#[test]
+	fn test_flag_value() {
+		let args = |a: &[&str]| a.iter().map(OsString::from).collect::<Vec<_>>();
+		assert_eq!(
+			flag_value(&args(&["code", "--config", "a.toml", "tunnel"]), "config"),
+			Some("a.toml".to_string())
+		);
+		assert_eq!(
+			flag_value(&args(&["code", "tunnel", "--config=b.toml"]), "config"),
+			Some("b.toml".to_string())
+		);
+		assert_eq!(
+			flag_value(&args(&["code", "--", "--config", "c.toml"]), "config"),
+			None
+		);
+	}
+
+	#[test]
+	fn test_editing() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("nested").join(CONFIG_FILE_NAME);
+		let mut config = ConfigFile::load(&path).unwrap();
+		assert!(config.entries().is_empty());
+
+		config.set("log", validate_standalone("log", "debug").unwrap());
+		config.set(
+			"tunnel.name",
+			validate_standalone("tunnel.name", "my-machine").unwrap(),
+		);
+		config.set("verbose", validate_standalone("verbose", "1").unwrap());
+		config.set(
+			"log-sink",
+			validate_standalone("log-sink", "stderr,syslog").unwrap(),
+		);
+		config.save().unwrap();
+
+		let mut config = ConfigFile::load(&path).unwrap();
+		assert_eq!(
+			config.get("tunnel.name"),
+			Some(&toml::Value::String("my-machine".to_string()))
+		);
+		assert_eq!(config.get("verbose"), Some(&toml::Value::Boolean(true)));
+		assert_eq!(config.get("tunnel"), None);
+		assert_eq!(
+			config.entries().iter().map(|e| e.key()).collect::<Vec<_>>(),
+			vec!["log", "log-sink", "tunnel.name", "verbose"]
+		);
+
+		assert!(config.unset("tunnel.name"));
+		assert!(!config.unset("tunnel.name"));
+		assert!(!config.unset("tunnel"));
+		config.save().unwrap();
+
+		let contents = fs::read_to_string(&path).unwrap();
+		assert!(!contents.contains("tunnel"), "{}", contents);
+		assert!(contents.contains("log = \"debug\""), "{}", contents);
+	}
+
+	#[test]
+	fn test_validate_against_schema() {
+		assert!(matches!(
+			validate_standalone("not-a-flag", "1"),
+			Err(CodeError::UnknownConfigKey(_))
+		));
+		assert!(matches!(
+			validate_standalone("config", "other.toml"),
+			Err(CodeError::UnknownConfigKey(_))
+		));
+		assert!(matches!(
+			validate_standalone("keyring-timeout", "soon"),
+			Err(CodeError::InvalidConfigValue { .. })
+		));
+		assert!(matches!(
+			validate_standalone("log-sink", "stderr,carrier-pigeon"),
+			Err(CodeError::InvalidConfigValue { .. })
+		));
+		assert_eq!(
+			validate_standalone("keyring-timeout", "5").unwrap(),
+			toml::Value::String("5".to_string())
+		);
+
+		// keys are checked against the CLI that's running
+		assert!(validate_standalone("update.check", "true").is_ok());
+		assert!(matches!(
+			validate(&IntegratedCli::command(), "update.check", "true"),
+			Err(CodeError::UnknownConfigKey(_))
+		));
+	}
+}
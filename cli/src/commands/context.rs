@@ -3,7 +3,7 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use crate::{log, state::LauncherPaths};
+use crate::{log, state::LauncherPaths, util::prereqs::PreReqChecker};
 
 use super::args::CliCore;
 
@@ -13,3 +13,17 @@ pub struct CommandContext {
 	pub args: CliCore,
 	pub http: reqwest::Client,
 }
+
+impl CommandContext {
+	/// Gets a checker for the server's requirements that logs to the command's
+	/// logger and respects `--skip-requirements-check`, `--arch`, and
+	/// `--assume-platform`.
+	pub fn prereqs(&self) -> PreReqChecker {
+		let options = &self.args.global_options;
+		PreReqChecker::new()
+			.with_log(self.log.clone())
+			.skip_requirements_check(options.skip_requirements_check)
+			.with_arch(options.arch)
+			.assume_platform(options.assume_platform)
+	}
+}
@@ -0,0 +1,893 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::{HashMap, HashSet},
+	path::{Path, PathBuf},
+	process::Output,
+};
+
+use futures::{Future, StreamExt};
+use hyper::header::CONTENT_LENGTH;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::util::{
+	errors::{wrap, AnyError, CodeError},
+	http::{download_into_file, BoxedHttp},
+	io::SilentCopyProgress,
+};
+
+use super::{
+	args::InstallExtensionArgs,
+	output::{
+		ExtensionInstallOutput, ExtensionInstallResult, ExtensionInstallStatus, ExtensionListOutput,
+	},
+};
+
+/// Something to install, as given on the command line.
+#[derive(Debug, PartialEq, Eq)]
+enum ExtensionSource {
+	/// A marketplace id, optionally with an `@version`.
+	Marketplace(String),
+	/// A VSIX on disk.
+	Vsix(PathBuf),
+	/// A VSIX to download.
+	VsixUrl(url::Url),
+}
+
+impl ExtensionSource {
+	fn parse(value: &str) -> Self {
+		let is_vsix = value.to_ascii_lowercase().ends_with(".vsix");
+		if let Ok(url) = url::Url::parse(value) {
+			if matches!(url.scheme(), "http" | "https") {
+				return ExtensionSource::VsixUrl(url);
+			}
+		}
+
+		if is_vsix {
+			ExtensionSource::Vsix(PathBuf::from(value))
+		} else {
+			ExtensionSource::Marketplace(value.to_string())
+		}
+	}
+}
+
+/// An extension passed to the editor, and how to find it once installed.
+struct PendingInstall {
+	requested: String,
+	editor_arg: String,
+	/// Name the editor reports the extension by: the id without a version, or
+	/// the file name of a VSIX.
+	reported_as: String,
+	/// The `publisher.name` id, if it's known before installing.
+	id: Option<String>,
+	/// The version asked for with `@version`, if any.
+	version: Option<String>,
+	/// Ids of the extensions it declares it depends on.
+	dependencies: Vec<String>,
+}
+
+impl PendingInstall {
+	fn depends_on(&self, other: &PendingInstall) -> bool {
+		match &other.id {
+			Some(id) => self.dependencies.iter().any(|d| d.eq_ignore_ascii_case(id)),
+			None => false,
+		}
+	}
+}
+
+/// The part of a VSIX's package.json that says what it is and needs.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VsixManifest {
+	publisher: String,
+	name: String,
+	#[serde(default)]
+	extension_dependencies: Vec<String>,
+}
+
+/// Reads the id and dependencies from the VSIX, if it has a readable manifest.
+fn read_vsix_manifest(path: &Path) -> Option<VsixManifest> {
+	let file = std::fs::File::open(path).ok()?;
+	let mut archive = zip::ZipArchive::new(file).ok()?;
+	let manifest = archive.by_name("extension/package.json").ok()?;
+	serde_json::from_reader(manifest).ok()
+}
+
+/// Splits the installs into groups that can run at the same time. Extensions
+/// that depend on each other are kept in one group, with the dependencies
+/// first, since the editor installs one run's extensions in order.
+fn group_installs(pending: Vec<PendingInstall>) -> Vec<Vec<PendingInstall>> {
+	let n = pending.len();
+	let mut group_of: Vec<usize> = (0..n).collect();
+	fn root(group_of: &[usize], mut i: usize) -> usize {
+		while group_of[i] != i {
+			i = group_of[i];
+		}
+		i
+	}
+
+	for i in 0..n {
+		for j in 0..n {
+			if i != j && pending[i].depends_on(&pending[j]) {
+				let (a, b) = (root(&group_of, i), root(&group_of, j));
+				group_of[a.max(b)] = a.min(b);
+			}
+		}
+	}
+
+	// orders each group so dependencies come before what needs them
+	fn visit(
+		i: usize,
+		pending: &[PendingInstall],
+		seen: &mut HashSet<usize>,
+		order: &mut Vec<usize>,
+	) {
+		if !seen.insert(i) {
+			return;
+		}
+		for j in 0..pending.len() {
+			if i != j && pending[i].depends_on(&pending[j]) {
+				visit(j, pending, seen, order);
+			}
+		}
+		order.push(i);
+	}
+
+	let mut seen = HashSet::new();
+	let mut order = vec![];
+	for i in 0..n {
+		visit(i, &pending, &mut seen, &mut order);
+	}
+
+	let mut groups: Vec<(usize, Vec<usize>)> = vec![];
+	for i in order {
+		let r = root(&group_of, i);
+		match groups.iter_mut().find(|(g, _)| *g == r) {
+			Some((_, members)) => members.push(i),
+			None => groups.push((r, vec![i])),
+		}
+	}
+	groups.sort_by_key(|(r, _)| *r);
+
+	let mut pending: Vec<Option<PendingInstall>> = pending.into_iter().map(Some).collect();
+	groups
+		.into_iter()
+		.map(|(_, members)| {
+			members
+				.into_iter()
+				.map(|i| pending[i].take().unwrap())
+				.collect()
+		})
+		.collect()
+}
+
+/// Installs the extensions, running the editor for each group of them with
+/// `run` given the arguments for the run. It should give up on an editor that
+/// hangs. VSIX URLs are downloaded first, up
+/// to `args.concurrency` at once. Runs are one at a time, since each writes
+/// the same extensions dir and its extensions.json. Every extension is
+/// attempted, even once one has failed, and the results come from listing
+/// the installed extensions before and after.
+pub async fn install<R, F>(
+	http: BoxedHttp,
+	base_args: Vec<String>,
+	args: &InstallExtensionArgs,
+	run: R,
+) -> Result<ExtensionInstallOutput, AnyError>
+where
+	R: Fn(Vec<String>) -> F,
+	F: Future<Output = Result<Output, AnyError>>,
+{
+	let download_dir = tempfile::tempdir().map_err(|e| wrap(e, "error creating temp dir"))?;
+	let (http, dir) = (&http, download_dir.path());
+	let mut downloads: HashMap<usize, Result<PathBuf, AnyError>> =
+		futures::stream::iter(args.id_or_path.iter().enumerate().filter_map(|(i, value)| {
+			match ExtensionSource::parse(value) {
+				ExtensionSource::VsixUrl(url) => {
+					Some(async move { (i, download_vsix(http, &url, dir, i).await) })
+				}
+				_ => None,
+			}
+		}))
+		.buffer_unordered(args.concurrency.into())
+		.collect()
+		.await;
+
+	let mut pending = vec![];
+	let mut results = vec![];
+	for (i, value) in args.id_or_path.iter().enumerate() {
+		let path = match ExtensionSource::parse(value) {
+			ExtensionSource::Marketplace(id) => {
+				let (reported_as, version) = match id.split_once('@') {
+					Some((id, version)) => (id.to_string(), Some(version.to_string())),
+					None => (id.clone(), None),
+				};
+				pending.push(PendingInstall {
+					requested: value.clone(),
+					editor_arg: id,
+					id: Some(reported_as.clone()),
+					reported_as,
+					version,
+					dependencies: vec![],
+				});
+				continue;
+			}
+			ExtensionSource::Vsix(path) => path,
+			ExtensionSource::VsixUrl(_) => {
+				match downloads
+					.remove(&i)
+					.expect("expected the VSIX to be downloaded")
+				{
+					Ok(p) => p,
+					Err(e) => {
+						results.push(ExtensionInstallResult {
+							extension: value.clone(),
+							success: false,
+							status: ExtensionInstallStatus::Failed,
+							message: e.to_string(),
+						});
+						continue;
+					}
+				}
+			}
+		};
+
+		let reported_as = path
+			.file_name()
+			.map(|f| f.to_string_lossy().to_string())
+			.unwrap_or_default();
+		let manifest = read_vsix_manifest(&path);
+		pending.push(PendingInstall {
+			requested: value.clone(),
+			editor_arg: path.to_string_lossy().to_string(),
+			reported_as,
+			id: manifest
+				.as_ref()
+				.map(|m| format!("{}.{}", m.publisher, m.name)),
+			version: None,
+			dependencies: manifest
+				.map(|m| m.extension_dependencies)
+				.unwrap_or_default(),
+		});
+	}
+
+	let before = list_installed(&run, &base_args).await?;
+	let mut outcomes = vec![];
+	for group in group_installs(pending) {
+		let mut editor_args = base_args.clone();
+		for p in &group {
+			editor_args.push(format!("--install-extension={}", p.editor_arg));
+		}
+		if args.pre_release {
+			editor_args.push("--pre-release".to_string());
+		}
+		if args.force {
+			editor_args.push("--force".to_string());
+		}
+
+		let output = run(editor_args).await;
+		outcomes.push(RunOutcome::new(group, output));
+	}
+
+	let after = list_installed(&run, &base_args).await?;
+	for outcome in &outcomes {
+		for p in &outcome.group {
+			results.push(result_of(p, outcome, &before, &after, args.force));
+		}
+	}
+
+	// report in the order the extensions were given
+	results.sort_by_key(|r| args.id_or_path.iter().position(|v| *v == r.extension));
+	Ok(ExtensionInstallOutput(results))
+}
+
+/// Downloads the VSIX into the directory, checking that all of it arrived.
+async fn download_vsix(
+	http: &BoxedHttp,
+	url: &url::Url,
+	dir: &Path,
+	index: usize,
+) -> Result<PathBuf, AnyError> {
+	let res = http.make_request("GET", url.to_string()).await?;
+	if !res.status_code.is_success() {
+		return Err(res.into_err().await.into());
+	}
+
+	let expected = res
+		.headers
+		.get(CONTENT_LENGTH)
+		.and_then(|h| h.to_str().ok())
+		.and_then(|s| s.parse::<u64>().ok());
+
+	// the editor only installs files named .vsix, and the index keeps files
+	// with the same name apart
+	let name = res
+		.url_path_basename()
+		.filter(|n| n.to_ascii_lowercase().ends_with(".vsix"))
+		.unwrap_or_else(|| "extension.vsix".to_string());
+	let path = dir.join(index.to_string()).join(name);
+	tokio::fs::create_dir_all(path.parent().unwrap())
+		.await
+		.map_err(|e| wrap(e, "error creating download dir"))?;
+
+	let mut file = download_into_file(&path, SilentCopyProgress(), res).await?;
+	file.flush()
+		.await
+		.map_err(|e| wrap(e, "error writing downloaded file"))?;
+	let actual = file
+		.metadata()
+		.await
+		.map_err(|e| wrap(e, "error reading downloaded file"))?
+		.len();
+	if let Some(expected) = expected {
+		if actual != expected {
+			return Err(CodeError::IncompleteDownload {
+				url: url.to_string(),
+				expected,
+				actual,
+			}
+			.into());
+		}
+	}
+
+	Ok(path)
+}
+
+/// What one run of the editor did for a group of extensions.
+struct RunOutcome {
+	group: Vec<PendingInstall>,
+	/// Why the run failed, if the editor couldn't be run or exited with an
+	/// error.
+	failure: Option<String>,
+	/// What the editor printed on stderr, to explain failures.
+	stderr: String,
+}
+
+impl RunOutcome {
+	fn new(group: Vec<PendingInstall>, output: Result<Output, AnyError>) -> Self {
+		let (failure, stderr) = match output {
+			Ok(output) if output.status.success() => {
+				(None, String::from_utf8_lossy(&output.stderr).to_string())
+			}
+			Ok(output) => (
+				Some(format!(
+					"the editor exited with code {}",
+					output.status.code().unwrap_or(1)
+				)),
+				String::from_utf8_lossy(&output.stderr).to_string(),
+			),
+			Err(e) => (Some(e.to_string()), String::new()),
+		};
+
+		Self {
+			group,
+			failure,
+			stderr,
+		}
+	}
+
+	/// Explains why the extension wasn't installed, with what the editor
+	/// printed about it if anything.
+	fn failure_for(&self, pending: &PendingInstall) -> String {
+		let name = pending.reported_as.to_lowercase();
+		let printed = self
+			.stderr
+			.lines()
+			.map(|l| l.trim())
+			.rfind(|l| l.to_lowercase().contains(&name));
+		match (printed, &self.failure) {
+			(Some(l), _) => l.to_string(),
+			(None, Some(f)) => f.clone(),
+			(None, None) => "the editor exited successfully but did not install it".to_string(),
+		}
+	}
+}
+
+/// Installed extensions, by lowercase id, and their versions.
+type InstalledVersions = HashMap<String, Option<String>>;
+
+/// Lists the installed extensions, as `--list-extensions --show-versions`
+/// reports them.
+async fn list_installed<R, F>(run: &R, base_args: &[String]) -> Result<InstalledVersions, AnyError>
+where
+	R: Fn(Vec<String>) -> F,
+	F: Future<Output = Result<Output, AnyError>>,
+{
+	let mut args = base_args.to_vec();
+	args.push("--list-extensions".to_string());
+	args.push("--show-versions".to_string());
+	let output = run(args).await?;
+	if !output.status.success() {
+		return Err(CodeError::CommandFailed {
+			command: "--list-extensions".to_string(),
+			code: output.status.code().unwrap_or(1),
+			output: String::from_utf8_lossy(&output.stderr).to_string(),
+		}
+		.into());
+	}
+
+	Ok(
+		ExtensionListOutput::parse(&String::from_utf8_lossy(&output.stdout))
+			.0
+			.into_iter()
+			.map(|e| (e.id.to_lowercase(), e.version))
+			.collect(),
+	)
+}
+
+/// Gets the extension's result by comparing what was installed before and
+/// after its run. An extension that didn't change only counts as already
+/// installed if its run succeeded, since a failed run may have been trying
+/// to update it. A VSIX without a readable id only has its run's exit status
+/// to go on.
+fn result_of(
+	pending: &PendingInstall,
+	run: &RunOutcome,
+	before: &InstalledVersions,
+	after: &InstalledVersions,
+	force: bool,
+) -> ExtensionInstallResult {
+	let result = |status: ExtensionInstallStatus, message: String| ExtensionInstallResult {
+		extension: pending.requested.clone(),
+		success: status != ExtensionInstallStatus::Failed,
+		status,
+		message,
+	};
+	let failed = || result(ExtensionInstallStatus::Failed, run.failure_for(pending));
+
+	let id = match &pending.id {
+		Some(id) => id.to_lowercase(),
+		None if run.failure.is_none() => {
+			return result(ExtensionInstallStatus::Installed, "installed".to_string())
+		}
+		None => return failed(),
+	};
+
+	let now = match after.get(&id) {
+		Some(now) => now,
+		None => return failed(),
+	};
+	if pending.version.is_some() && *now != pending.version {
+		return failed();
+	}
+
+	let version = now.as_ref().map(|v| format!("v{}", v));
+	let unchanged = before.get(&id) == Some(now) && !force;
+	match (unchanged, &run.failure) {
+		(true, None) => result(
+			ExtensionInstallStatus::AlreadyInstalled,
+			match version {
+				Some(v) => format!("{} was already installed", v),
+				None => "already installed".to_string(),
+			},
+		),
+		(true, Some(_)) => failed(),
+		(false, _) => result(
+			ExtensionInstallStatus::Installed,
+			match version {
+				Some(v) => format!("installed {}", v),
+				None => "installed".to_string(),
+			},
+		),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::future;
+	use std::sync::{Arc, Mutex};
+
+	use async_trait::async_trait;
+	use hyper::{HeaderMap, StatusCode};
+
+	use crate::util::http::{SimpleHttp, SimpleResponse};
+
+	use super::*;
+
+	/// Serves the body for every request, reporting the given length.
+	struct MockHttp {
+		body: Vec<u8>,
+		content_length: usize,
+	}
+
+	#[async_trait]
+	impl SimpleHttp for MockHttp {
+		async fn make_request(
+			&self,
+			_method: &'static str,
+			url: String,
+		) -> Result<SimpleResponse, AnyError> {
+			let mut headers = HeaderMap::new();
+			headers.insert(CONTENT_LENGTH, self.content_length.into());
+			Ok(SimpleResponse {
+				status_code: StatusCode::OK,
+				headers,
+				read: Box::pin(std::io::Cursor::new(self.body.clone())),
+				url: url::Url::parse(&url).ok(),
+			})
+		}
+	}
+
+	fn http(body: &[u8], content_length: usize) -> BoxedHttp {
+		Arc::new(MockHttp {
+			body: body.to_vec(),
+			content_length,
+		})
+	}
+
+	fn install_args(ids: &[&str], pre_release: bool) -> InstallExtensionArgs {
+		InstallExtensionArgs {
+			id_or_path: ids.iter().map(|s| s.to_string()).collect(),
+			pre_release,
+			force: false,
+			json: false,
+			concurrency: 3,
+		}
+	}
+
+	fn editor_output(code: i32, stdout: &str, stderr: &str) -> Output {
+		#[cfg(unix)]
+		use std::os::unix::process::ExitStatusExt;
+		#[cfg(windows)]
+		use std::os::windows::process::ExitStatusExt;
+
+		#[cfg(unix)]
+		let status = std::process::ExitStatus::from_raw(code << 8);
+		#[cfg(windows)]
+		let status = std::process::ExitStatus::from_raw(code as u32);
+
+		Output {
+			status,
+			stdout: stdout.as_bytes().to_vec(),
+			stderr: stderr.as_bytes().to_vec(),
+		}
+	}
+
+	#[test]
+	fn test_parse_source() {
+		assert_eq!(
+			ExtensionSource::parse("ms-python.python@1.2.3"),
+			ExtensionSource::Marketplace("ms-python.python@1.2.3".to_string())
+		);
+		assert_eq!(
+			ExtensionSource::parse("./dist/My.VSIX"),
+			ExtensionSource::Vsix(PathBuf::from("./dist/My.VSIX"))
+		);
+		assert_eq!(
+			ExtensionSource::parse("https://example.com/a.vsix?sig=1"),
+			ExtensionSource::VsixUrl(url::Url::parse("https://example.com/a.vsix?sig=1").unwrap())
+		);
+	}
+
+	/// Stands in for the editor, keeping the installed extensions in memory.
+	/// `id@version` installs that version, and anything else 1.0.0, with a
+	/// VSIX installed by the id in its manifest or else its file name. Ids
+	/// starting with "missing" aren't found.
+	#[derive(Clone, Default)]
+	struct FakeEditor {
+		installed: Arc<Mutex<Vec<(String, String)>>>,
+		runs: Arc<Mutex<Vec<Vec<String>>>>,
+	}
+
+	impl FakeEditor {
+		fn with_installed(installed: &[(&str, &str)]) -> Self {
+			let editor = Self::default();
+			*editor.installed.lock().unwrap() = installed
+				.iter()
+				.map(|(id, v)| (id.to_string(), v.to_string()))
+				.collect();
+			editor
+		}
+
+		fn runs(&self) -> Vec<Vec<String>> {
+			self.runs.lock().unwrap().clone()
+		}
+
+		fn run(&self, args: &[String]) -> Output {
+			let mut installed = self.installed.lock().unwrap();
+			if args.iter().any(|a| a == "--list-extensions") {
+				let listed: String = installed
+					.iter()
+					.map(|(id, v)| format!("{}@{}\n", id, v))
+					.collect();
+				return editor_output(0, &listed, "");
+			}
+
+			self.runs.lock().unwrap().push(args.to_vec());
+			let mut stderr = String::new();
+			for arg in args
+				.iter()
+				.filter_map(|a| a.strip_prefix("--install-extension="))
+			{
+				let (id, version) = match read_vsix_manifest(Path::new(arg)) {
+					Some(m) => (format!("{}.{}", m.publisher, m.name), "1.0.0"),
+					None if arg.ends_with(".vsix") => (
+						Path::new(arg)
+							.file_stem()
+							.unwrap()
+							.to_string_lossy()
+							.to_string(),
+						"1.0.0",
+					),
+					None => match arg.split_once('@') {
+						Some((id, version)) => (id.to_string(), version),
+						None => (arg.to_string(), "1.0.0"),
+					},
+				};
+				if id.starts_with("missing") {
+					stderr.push_str(&format!("Extension '{}' not found.\n", id));
+					continue;
+				}
+				installed.retain(|(i, _)| *i != id);
+				installed.push((id, version.to_string()));
+			}
+
+			match stderr.is_empty() {
+				true => editor_output(0, "", ""),
+				false => editor_output(1, "", &stderr),
+			}
+		}
+	}
+
+	/// Writes a VSIX with the given id and dependencies into the directory.
+	fn write_vsix(dir: &Path, publisher: &str, name: &str, dependencies: &[&str]) -> String {
+		let path = dir.join(format!("{}.vsix", name));
+		let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+		zip.start_file("extension/package.json", zip::write::FileOptions::default())
+			.unwrap();
+		let manifest = serde_json::json!({
+			"publisher": publisher,
+			"name": name,
+			"extensionDependencies": dependencies,
+		});
+		std::io::Write::write_all(&mut zip, manifest.to_string().as_bytes()).unwrap();
+		zip.finish().unwrap();
+		path.to_string_lossy().to_string()
+	}
+
+	#[tokio::test]
+	async fn test_installs_each_extension_in_its_own_run() {
+		let editor = FakeEditor::default();
+		let editor_in_run = editor.clone();
+		let output = install(
+			http(b"vsix", 4),
+			vec!["--base".to_string()],
+			&install_args(
+				&[
+					"ms-python.python",
+					"https://example.com/dl/remote.vsix",
+					"missing.ext@1.0.0",
+				],
+				true,
+			),
+			move |args| future::ready(Ok(editor_in_run.run(&args))),
+		)
+		.await
+		.unwrap();
+
+		let mut runs = editor.runs();
+		runs.sort();
+		assert_eq!(runs.len(), 3);
+		for run in &runs {
+			assert_eq!(run.len(), 3, "{:?}", run);
+			assert_eq!(run[0], "--base");
+			assert_eq!(run[2], "--pre-release");
+		}
+		// the downloaded VSIX is under an absolute path, which sorts first
+		assert!(runs[0][1].ends_with("remote.vsix"), "{}", runs[0][1]);
+		assert_eq!(runs[1][1], "--install-extension=missing.ext@1.0.0");
+		assert_eq!(runs[2][1], "--install-extension=ms-python.python");
+
+		assert!(output.any_failed());
+		let results = &output.0;
+		assert_eq!(results.len(), 3);
+		assert_eq!(results[0].status, ExtensionInstallStatus::Installed);
+		assert_eq!(results[0].message, "installed v1.0.0");
+		assert_eq!(results[1].extension, "https://example.com/dl/remote.vsix");
+		assert_eq!(results[1].status, ExtensionInstallStatus::Installed);
+		assert_eq!(results[2].status, ExtensionInstallStatus::Failed);
+		assert_eq!(results[2].message, "Extension 'missing.ext' not found.");
+	}
+
+	#[tokio::test]
+	async fn test_runs_sharing_extensions_dir_are_one_at_a_time() {
+		let running = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+		let most = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+		let (running_in_run, most_in_run) = (running.clone(), most.clone());
+		let editor = FakeEditor::default();
+
+		let args = install_args(&["a.a", "b.b", "c.c", "d.d", "e.e", "f.f"], false);
+		let output = install(http(b"", 0), vec![], &args, move |args| {
+			use std::sync::atomic::Ordering;
+			let now = running_in_run.fetch_add(1, Ordering::SeqCst) + 1;
+			most_in_run.fetch_max(now, Ordering::SeqCst);
+			std::thread::sleep(std::time::Duration::from_millis(50));
+			running_in_run.fetch_sub(1, Ordering::SeqCst);
+			future::ready(Ok(editor.run(&args)))
+		})
+		.await
+		.unwrap();
+
+		assert_eq!(most.load(std::sync::atomic::Ordering::SeqCst), 1);
+		assert!(!output.any_failed());
+		let order: Vec<&str> = output.0.iter().map(|r| r.extension.as_str()).collect();
+		assert_eq!(order, vec!["a.a", "b.b", "c.c", "d.d", "e.e", "f.f"]);
+	}
+
+	#[tokio::test]
+	async fn test_failed_run_fails_only_its_extensions() {
+		let editor = FakeEditor::with_installed(&[("c.c", "1.0.0")]);
+		let output = install(
+			http(b"", 0),
+			vec![],
+			&install_args(&["a.a", "b.b", "c.c"], false),
+			move |args| {
+				future::ready(if args.iter().any(|a| a.contains("b.b")) {
+					Err(CodeError::CommandFailed {
+						command: "code".to_string(),
+						code: 1,
+						output: "crashed".to_string(),
+					}
+					.into())
+				} else {
+					Ok(editor.run(&args))
+				})
+			},
+		)
+		.await
+		.unwrap();
+
+		let results = &output.0;
+		assert_eq!(results[0].status, ExtensionInstallStatus::Installed);
+		assert_eq!(results[1].status, ExtensionInstallStatus::Failed);
+		assert!(
+			results[1].message.contains("crashed"),
+			"{}",
+			results[1].message
+		);
+		assert_eq!(results[2].status, ExtensionInstallStatus::AlreadyInstalled);
+		assert_eq!(results[2].message, "v1.0.0 was already installed");
+		assert!(results[2].success);
+		assert!(output.any_failed());
+	}
+
+	#[tokio::test]
+	async fn test_failed_exit_status_fails_unchanged_extensions() {
+		// the editor exits with an error without saying why, leaving the
+		// installed version in place
+		let editor = FakeEditor::with_installed(&[("a.b", "1.0.0")]);
+		let output = install(
+			http(b"", 0),
+			vec![],
+			&install_args(&["a.b", "c.d"], false),
+			move |args| {
+				future::ready(Ok(if args.iter().any(|a| a.contains("a.b")) {
+					editor_output(1, "", "")
+				} else {
+					editor.run(&args)
+				}))
+			},
+		)
+		.await
+		.unwrap();
+
+		let results = &output.0;
+		assert_eq!(results[0].status, ExtensionInstallStatus::Failed);
+		assert_eq!(results[0].message, "the editor exited with code 1");
+		assert_eq!(results[1].status, ExtensionInstallStatus::Installed);
+	}
+
+	#[tokio::test]
+	async fn test_dependencies_install_first_in_one_run() {
+		let dir = tempfile::tempdir().unwrap();
+		let app = write_vsix(dir.path(), "acme", "app", &["acme.lib"]);
+		let lib = write_vsix(dir.path(), "acme", "lib", &[]);
+
+		let editor = FakeEditor::default();
+		let editor_in_run = editor.clone();
+		let output = install(
+			http(b"", 0),
+			vec![],
+			&install_args(&[app.as_str(), "other.ext", lib.as_str()], false),
+			move |args| future::ready(Ok(editor_in_run.run(&args))),
+		)
+		.await
+		.unwrap();
+
+		let mut runs = editor.runs();
+		runs.sort_by_key(|r| r.len());
+		assert_eq!(
+			runs,
+			vec![
+				vec!["--install-extension=other.ext".to_string()],
+				vec![
+					format!("--install-extension={}", lib),
+					format!("--install-extension={}", app),
+				],
+			]
+		);
+		assert!(!output.any_failed());
+		assert_eq!(output.0[0].extension, app);
+		assert_eq!(output.0[2].extension, lib);
+	}
+
+	#[tokio::test]
+	async fn test_incomplete_download_fails_only_that_extension() {
+		let editor = FakeEditor::with_installed(&[("a.b", "1.0.0")]);
+		let editor_in_run = editor.clone();
+		let output = install(
+			http(b"vs", 4),
+			vec![],
+			&install_args(&["https://example.com/a.vsix", "a.b"], false),
+			move |args| future::ready(Ok(editor_in_run.run(&args))),
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(
+			editor.runs(),
+			vec![vec!["--install-extension=a.b".to_string()]]
+		);
+		let results = &output.0;
+		assert_eq!(results[0].extension, "https://example.com/a.vsix");
+		assert!(!results[0].success);
+		assert!(
+			results[0].message.contains("2 of the 4 bytes"),
+			"{}",
+			results[0].message
+		);
+		assert!(results[1].success);
+	}
+
+	#[tokio::test]
+	async fn test_extension_not_installed_fails() {
+		// the editor exits successfully without installing anything
+		let output = install(http(b"", 0), vec![], &install_args(&["a.b"], false), |_| {
+			future::ready(Ok(editor_output(0, "", "")))
+		})
+		.await
+		.unwrap();
+
+		assert!(output.any_failed());
+		assert_eq!(
+			output.0[0].message,
+			"the editor exited successfully but did not install it"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_wrong_version_fails() {
+		let editor = FakeEditor::with_installed(&[("a.b", "1.0.0")]);
+		let output = install(
+			http(b"", 0),
+			vec![],
+			&install_args(&["a.b@2.0.0"], false),
+			move |args| {
+				future::ready(Ok(if args.iter().any(|a| a == "--list-extensions") {
+					editor.run(&args)
+				} else {
+					// the editor keeps the installed version
+					editor_output(0, "", "")
+				}))
+			},
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(output.0[0].status, ExtensionInstallStatus::Failed);
+	}
+
+	#[tokio::test]
+	async fn test_listing_failure_is_an_error() {
+		let result = install(http(b"", 0), vec![], &install_args(&["a.b"], false), |_| {
+			future::ready(Ok(editor_output(1, "", "no editor")))
+		})
+		.await;
+
+		assert!(result.is_err());
+	}
+}
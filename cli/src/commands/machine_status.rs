@@ -0,0 +1,492 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fmt::Write,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::{
+	constants::{QUALITY, VSCODE_CLI_COMMIT, VSCODE_CLI_VERSION},
+	log,
+	singleton::connect_as_client,
+	state::LauncherPaths,
+	tunnels::{
+		create_service_manager, protocol,
+		singleton_client::{connect_singleton_rpc, connect_to_running_tunnel},
+		ServiceManager, ServiceScope, ServiceStatus,
+	},
+	util::{
+		errors::{AnyError, CodeError},
+		io::dir_size,
+		os::{collect_host_info, HostInfo},
+	},
+};
+
+use super::{
+	args::MachineStatusArgs,
+	output::{format_bytes, CommandOutput},
+	CommandContext,
+};
+
+/// How long an instance has to answer before it's reported as unreachable.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Output of `machine-status`: what the CLI is running on this machine.
+#[derive(Serialize)]
+pub struct MachineStatusOutput {
+	pub version: String,
+	pub commit: Option<String>,
+	pub quality: String,
+	pub data_dirs: Vec<DataDirStatus>,
+	pub service: ServiceStatus,
+	/// Details of the machine, shown with `--verbose`.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub host: Option<HostInfo>,
+}
+
+#[derive(Serialize)]
+pub struct DataDirStatus {
+	pub path: PathBuf,
+	pub instances: Vec<InstanceStatus>,
+	/// Bytes used by downloaded servers.
+	pub server_cache_bytes: u64,
+	/// Bytes used by downloaded CLI updates.
+	pub cli_cache_bytes: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceKind {
+	Tunnel,
+	Forwarding,
+}
+
+impl InstanceKind {
+	/// Gets the kind of singleton the lock file belongs to, from its name.
+	fn from_lock_file(name: &str) -> Option<Self> {
+		if !name.ends_with(".lock") {
+			None
+		} else if name.starts_with("tunnel-") {
+			Some(InstanceKind::Tunnel)
+		} else if name.starts_with("forwarding-") {
+			Some(InstanceKind::Forwarding)
+		} else {
+			None
+		}
+	}
+}
+
+#[derive(Serialize)]
+pub struct InstanceStatus {
+	pub kind: InstanceKind,
+	pub lock_file: PathBuf,
+	#[serde(flatten)]
+	pub state: InstanceState,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum InstanceState {
+	Running {
+		/// Summary the tunnel reported about itself.
+		#[serde(skip_serializing_if = "Option::is_none")]
+		tunnel: Option<Box<protocol::singleton::StatusWithTunnelName>>,
+	},
+	/// The lock is held, but its owner couldn't be queried.
+	Unreachable { error: String },
+}
+
+/// Asks the owner of a singleton lock what it's doing.
+#[async_trait]
+pub trait InstanceProbe {
+	/// Returns None if nothing is running, as when the lock file was left by a
+	/// process that exited.
+	async fn probe(&self, kind: InstanceKind, lock_file: &Path) -> Option<InstanceState>;
+}
+
+/// Probes instances over their singleton sockets.
+pub struct RpcInstanceProbe {
+	pub log: log::Logger,
+}
+
+#[async_trait]
+impl InstanceProbe for RpcInstanceProbe {
+	async fn probe(&self, kind: InstanceKind, lock_file: &Path) -> Option<InstanceState> {
+		let result = tokio::time::timeout(QUERY_TIMEOUT, async {
+			match kind {
+				InstanceKind::Tunnel => query_tunnel(lock_file, self.log.clone()).await.map(|s| {
+					Some(InstanceState::Running {
+						tunnel: Some(Box::new(s)),
+					})
+				}),
+				InstanceKind::Forwarding => match connect_as_client(lock_file).await {
+					Ok(_) => Ok(Some(InstanceState::Running { tunnel: None })),
+					Err(CodeError::SingletonLockedProcessExited(_)) => Ok(None),
+					Err(e) => Err(e),
+				},
+			}
+		})
+		.await;
+
+		match result {
+			Ok(Ok(state)) => state,
+			Ok(Err(CodeError::NoRunningTunnel)) => None,
+			Ok(Err(e)) => Some(InstanceState::Unreachable {
+				error: e.to_string(),
+			}),
+			Err(_) => Some(InstanceState::Unreachable {
+				error: format!("no response after {}s", QUERY_TIMEOUT.as_secs()),
+			}),
+		}
+	}
+}
+
+async fn query_tunnel(
+	lock_file: &Path,
+	log: log::Logger,
+) -> Result<protocol::singleton::StatusWithTunnelName, CodeError> {
+	// checked first, so that a stale lock isn't reported as unreachable
+	drop(connect_to_running_tunnel(lock_file).await?);
+
+	let client = connect_singleton_rpc(lock_file, log).await?;
+	match client
+		.caller
+		.call(protocol::singleton::METHOD_STATUS, protocol::EmptyObject {})
+		.await
+	{
+		Ok(r) => r.map_err(CodeError::TunnelRpcCallFailed),
+		Err(_) => Err(CodeError::NoRunningTunnel),
+	}
+}
+
+/// Gets the data directories to look for instances in: the one in use, and
+/// the default one if that's different.
+fn known_data_dirs(current: &Path) -> Vec<PathBuf> {
+	let mut dirs = vec![current.to_path_buf()];
+	if let Ok(default) = LauncherPaths::resolve_root(None) {
+		if default != current && default.exists() {
+			dirs.push(default);
+		}
+	}
+	dirs
+}
+
+/// Finds the singleton lock files in the directory, in name order.
+fn find_lock_files(dir: &Path) -> Vec<(InstanceKind, PathBuf)> {
+	let mut locks: Vec<(InstanceKind, PathBuf)> = std::fs::read_dir(dir)
+		.into_iter()
+		.flatten()
+		.flatten()
+		.filter_map(|e| {
+			let name = e.file_name().to_string_lossy().to_string();
+			InstanceKind::from_lock_file(&name).map(|k| (k, e.path()))
+		})
+		.collect();
+	locks.sort_by(|a, b| a.1.cmp(&b.1));
+	locks
+}
+
+async fn data_dir_status(root: PathBuf, probe: &impl InstanceProbe) -> DataDirStatus {
+	let mut instances = vec![];
+	for (kind, lock_file) in find_lock_files(&root) {
+		if let Some(state) = probe.probe(kind, &lock_file).await {
+			instances.push(InstanceStatus {
+				kind,
+				lock_file,
+				state,
+			});
+		}
+	}
+
+	let paths = LauncherPaths::inspect(root);
+	DataDirStatus {
+		server_cache_bytes: dir_size(paths.server_cache.path()),
+		cli_cache_bytes: dir_size(paths.cli_cache.path()),
+		path: paths.root().to_path_buf(),
+		instances,
+	}
+}
+
+pub async fn status(ctx: CommandContext, args: MachineStatusArgs) -> Result<i32, AnyError> {
+	let probe = RpcInstanceProbe {
+		log: ctx.log.clone(),
+	};
+	let mut data_dirs = vec![];
+	for dir in known_data_dirs(ctx.paths.root()) {
+		data_dirs.push(data_dir_status(dir, &probe).await);
+	}
+
+	let manager = create_service_manager(ctx.log.clone(), &ctx.paths, ServiceScope::User);
+	let service = manager.status().await.unwrap_or_else(|e| ServiceStatus {
+		notes: vec![format!("could not get the service status: {}", e)],
+		..Default::default()
+	});
+
+	let output = MachineStatusOutput {
+		version: VSCODE_CLI_VERSION.unwrap_or("dev").to_string(),
+		commit: VSCODE_CLI_COMMIT.map(|c| c.to_string()),
+		quality: QUALITY.to_string(),
+		data_dirs,
+		service,
+		host: ctx.args.global_options.verbose.then(collect_host_info),
+	};
+	ctx.log.result(
+		ctx.args
+			.global_options
+			.output_format(args.json)
+			.render(&output),
+	);
+
+	Ok(0)
+}
+
+impl CommandOutput for MachineStatusOutput {
+	fn human(&self) -> String {
+		let mut out = format!("CLI {} ({})", self.version, self.quality);
+		if let Some(commit) = &self.commit {
+			write!(out, ", commit {}", commit).unwrap();
+		}
+		if let Some(host) = &self.host {
+			write!(out, "\nHost: {}", host).unwrap();
+		}
+
+		for dir in &self.data_dirs {
+			write!(out, "\n\nData directory {}", dir.path.display()).unwrap();
+			if dir.instances.is_empty() {
+				out.push_str("\n  nothing running");
+			}
+			for instance in &dir.instances {
+				let kind = match instance.kind {
+					InstanceKind::Tunnel => "tunnel",
+					InstanceKind::Forwarding => "port forwarding",
+				};
+				let state = match &instance.state {
+					InstanceState::Running { tunnel: Some(t) } => {
+						let mut s = format!(
+							"{}, {:?} since {}",
+							t.name.as_deref().unwrap_or("unnamed"),
+							t.status.tunnel,
+							t.status.started_at.format("%Y-%m-%d %H:%M:%S UTC"),
+						);
+						if let Some(c) = &t.credentials {
+							write!(s, ", credentials {}", c).unwrap();
+						}
+						s
+					}
+					InstanceState::Running { tunnel: None } => "running".to_string(),
+					InstanceState::Unreachable { error } => format!("unreachable ({})", error),
+				};
+				write!(out, "\n  {}: {}", kind, state).unwrap();
+			}
+			write!(
+				out,
+				"\n  cached servers: {}, cached CLI updates: {}",
+				format_bytes(dir.server_cache_bytes),
+				format_bytes(dir.cli_cache_bytes)
+			)
+			.unwrap();
+		}
+
+		out.push_str("\n\nService: ");
+		if !self.service.installed {
+			out.push_str("not installed");
+		} else {
+			out.push_str(&self.service.name);
+			match self.service.running {
+				Some(true) => out.push_str(", running"),
+				Some(false) => out.push_str(", stopped"),
+				None => {}
+			}
+		}
+		for note in &self.service.notes {
+			write!(out, "\n  {}", note).unwrap();
+		}
+
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use chrono::TimeZone;
+
+	use super::*;
+	use crate::auth::CredentialStoreState;
+	use crate::singleton::{acquire_singleton, SingletonConnection};
+
+	/// Answers with fixed states by lock file name, as if each were a running
+	/// instance.
+	struct FakeProbe(HashMap<&'static str, Result<Option<&'static str>, &'static str>>);
+
+	#[async_trait]
+	impl InstanceProbe for FakeProbe {
+		async fn probe(&self, _kind: InstanceKind, lock_file: &Path) -> Option<InstanceState> {
+			let name = lock_file.file_name().unwrap().to_str().unwrap();
+			match self.0.get(name)? {
+				Ok(Some(tunnel)) => Some(InstanceState::Running {
+					tunnel: Some(Box::new(protocol::singleton::StatusWithTunnelName {
+						name: Some(tunnel.to_string()),
+						status: protocol::singleton::Status {
+							started_at: chrono::Utc.timestamp_opt(0, 0).unwrap(),
+							tunnel: protocol::singleton::TunnelState::Connected,
+							last_connected_at: None,
+							last_disconnected_at: None,
+							..Default::default()
+						},
+						credentials: Some(CredentialStoreState::NotInitialized),
+						..Default::default()
+					})),
+				}),
+				Ok(None) => Some(InstanceState::Running { tunnel: None }),
+				Err(e) => Some(InstanceState::Unreachable {
+					error: e.to_string(),
+				}),
+			}
+		}
+	}
+
+	fn touch(dir: &Path, name: &str, len: usize) {
+		std::fs::create_dir_all(dir.join(name).parent().unwrap()).unwrap();
+		std::fs::write(dir.join(name), vec![0; len]).unwrap();
+	}
+
+	fn output(data_dirs: Vec<DataDirStatus>) -> MachineStatusOutput {
+		MachineStatusOutput {
+			version: "1.0.0".to_string(),
+			commit: None,
+			quality: "stable".to_string(),
+			data_dirs,
+			service: ServiceStatus::default(),
+			host: None,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_reports_each_lock() {
+		let dir = tempfile::tempdir().unwrap();
+		touch(dir.path(), "tunnel-stable.lock", 0);
+		touch(dir.path(), "tunnel-insider.lock", 0);
+		touch(dir.path(), "forwarding-stable.lock", 0);
+		touch(dir.path(), "tunnel-exploration.lock", 0);
+		touch(dir.path(), "settings.json", 0);
+		touch(dir.path(), "servers/Stable-abc/server.tar", 2048);
+		touch(dir.path(), "cli/update", 10);
+
+		let probe = FakeProbe(HashMap::from([
+			("tunnel-stable.lock", Ok(Some("my-machine"))),
+			("tunnel-insider.lock", Err("connection refused")),
+			("forwarding-stable.lock", Ok(None)),
+		]));
+		let status = data_dir_status(dir.path().to_path_buf(), &probe).await;
+
+		// the exploration lock was left by an exited process
+		let kinds: Vec<_> = status
+			.instances
+			.iter()
+			.map(|i| (i.kind, i.lock_file.file_name().unwrap().to_str().unwrap()))
+			.collect();
+		assert_eq!(
+			kinds,
+			vec![
+				(InstanceKind::Forwarding, "forwarding-stable.lock"),
+				(InstanceKind::Tunnel, "tunnel-insider.lock"),
+				(InstanceKind::Tunnel, "tunnel-stable.lock"),
+			]
+		);
+		assert_eq!(status.server_cache_bytes, 2048);
+		assert_eq!(status.cli_cache_bytes, 10);
+
+		let path = status.path.display().to_string();
+		assert_eq!(
+			output(vec![status]).human(),
+			format!(
+				"CLI 1.0.0 (stable)\n\nData directory {}\n  port forwarding: running\n  tunnel: unreachable (connection refused)\n  tunnel: my-machine, Connected since 1970-01-01 00:00:00 UTC, credentials not yet initialized\n  cached servers: 2.0 KB, cached CLI updates: 10 B\n\nService: not installed",
+				path
+			)
+		);
+	}
+
+	#[tokio::test]
+	async fn test_json_output() {
+		let dir = tempfile::tempdir().unwrap();
+		touch(dir.path(), "tunnel-stable.lock", 0);
+		let probe = FakeProbe(HashMap::from([("tunnel-stable.lock", Err("timed out"))]));
+		let status = data_dir_status(dir.path().to_path_buf(), &probe).await;
+
+		let json = serde_json::to_value(output(vec![status])).unwrap();
+		let instance = &json["data_dirs"][0]["instances"][0];
+		assert_eq!(instance["kind"], "tunnel");
+		assert_eq!(instance["state"], "unreachable");
+		assert_eq!(instance["error"], "timed out");
+		assert_eq!(json["quality"], "stable");
+		assert_eq!(json["service"]["installed"], false);
+		assert!(json.get("host").is_none());
+	}
+
+	#[test]
+	fn test_verbose_output_describes_host() {
+		let mut status = output(vec![]);
+		status.host = Some(HostInfo {
+			os: "linux".to_string(),
+			arch: "aarch64".to_string(),
+			kernel: Some("6.1.0".to_string()),
+			..Default::default()
+		});
+
+		assert!(status
+			.human()
+			.starts_with("CLI 1.0.0 (stable)\nHost: linux aarch64, kernel 6.1.0\n"));
+		let json = serde_json::to_value(&status).unwrap();
+		assert_eq!(json["host"]["arch"], "aarch64");
+		assert_eq!(json["host"]["wsl"], false);
+	}
+
+	#[tokio::test]
+	async fn test_rpc_probe_reports_unresponsive_instances() {
+		let dir = tempfile::tempdir().unwrap();
+		let probe = RpcInstanceProbe {
+			log: log::Logger::test(),
+		};
+
+		// a lock that can't be read
+		let garbage = dir.path().join("tunnel-garbage.lock");
+		std::fs::write(&garbage, b"not a lock").unwrap();
+		assert!(matches!(
+			probe.probe(InstanceKind::Tunnel, &garbage).await,
+			Some(InstanceState::Unreachable { .. })
+		));
+
+		// a singleton that accepts connections but hangs up without answering
+		let lock = dir.path().join("tunnel-test.lock");
+		match acquire_singleton(&log::Logger::test(), &lock)
+			.await
+			.unwrap()
+		{
+			SingletonConnection::Singleton(mut server) => {
+				tokio::spawn(async move {
+					while let Ok(c) = server.accept().await {
+						drop(c);
+					}
+				});
+			}
+			_ => panic!("expected to be the singleton"),
+		}
+		assert!(matches!(
+			probe.probe(InstanceKind::Tunnel, &lock).await,
+			Some(InstanceState::Unreachable { .. })
+		));
+
+		// and a missing lock isn't an instance
+		let missing = dir.path().join("tunnel-missing.lock");
+		assert!(probe.probe(InstanceKind::Tunnel, &missing).await.is_none());
+	}
+}
@@ -0,0 +1,184 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{fs, path::Path};
+
+use clap::CommandFactory;
+
+use crate::util::errors::{wrap, AnyError};
+
+use super::{
+	args::{add_env_bindings, GenerateManpagesArgs, StandaloneCli},
+	CommandContext,
+};
+
+/// A rendered man page.
+pub struct Manpage {
+	/// File name of the page, such as `code-tunnel.1`.
+	pub file_name: String,
+	pub contents: Vec<u8>,
+}
+
+/// Renders a page for the command and each of its visible subcommands. Pages
+/// are named after their command path, like `code-tunnel-service.1`, and
+/// don't include the date, so they're the same each time they're rendered.
+pub fn render(mut cmd: clap::Command) -> Vec<Manpage> {
+	// propagates global flags, so that each page lists the flags it takes
+	cmd.build();
+
+	let mut pages = vec![];
+	render_into(&cmd, cmd.get_name().to_string(), &mut pages);
+	pages
+}
+
+fn render_into(cmd: &clap::Command, name: String, pages: &mut Vec<Manpage>) {
+	let page_cmd = cmd.clone().name(name.clone());
+	let mut contents = vec![];
+	clap_mangen::Man::new(page_cmd)
+		.render(&mut contents)
+		.expect("writing to a vec does not fail");
+	pages.push(Manpage {
+		file_name: format!("{}.1", name),
+		contents,
+	});
+
+	for sub in cmd.get_subcommands() {
+		if sub.is_hide_set() || sub.get_name() == "help" {
+			continue;
+		}
+		render_into(sub, format!("{}-{}", name, sub.get_name()), pages);
+	}
+}
+
+pub async fn generate_manpages(
+	ctx: CommandContext,
+	args: GenerateManpagesArgs,
+) -> Result<i32, AnyError> {
+	write_pages(
+		&args.dir,
+		&render(add_env_bindings(StandaloneCli::command())),
+	)?;
+	ctx.log
+		.result(format!("Wrote man pages to {}", args.dir.display()));
+	Ok(0)
+}
+
+fn write_pages(dir: &Path, pages: &[Manpage]) -> Result<(), AnyError> {
+	fs::create_dir_all(dir).map_err(|e| wrap(e, format!("error creating {}", dir.display())))?;
+	for page in pages {
+		let path = dir.join(&page.file_name);
+		fs::write(&path, &page.contents)
+			.map_err(|e| wrap(e, format!("error writing {}", path.display())))?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use super::*;
+
+	/// Renders pages with a fixed name and version, which otherwise depend on
+	/// the build.
+	fn pages() -> Vec<Manpage> {
+		let cmd = StandaloneCli::command().name("code").version("test");
+		render(add_env_bindings(cmd))
+	}
+
+	fn page(pages: &[Manpage], file_name: &str) -> String {
+		let page = pages
+			.iter()
+			.find(|p| p.file_name == file_name)
+			.unwrap_or_else(|| panic!("no page {}", file_name));
+		String::from_utf8(page.contents.clone()).unwrap()
+	}
+
+	/// Compares the page with the one in `src/commands/snapshots`. Snapshots
+	/// are only written when UPDATE_SNAPSHOTS is set, so that intended changes
+	/// to help text can be reviewed in the diff, and a missing one fails.
+	fn assert_snapshot(file_name: &str, contents: &str) {
+		let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+			.join("src/commands/snapshots")
+			.join(file_name);
+		if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(&path, contents).unwrap();
+			return;
+		}
+
+		let expected = fs::read_to_string(&path).unwrap_or_else(|e| {
+			panic!(
+				"could not read the snapshot {}, run with UPDATE_SNAPSHOTS=1 to write it: {}",
+				path.display(),
+				e
+			)
+		});
+		assert!(
+			expected == contents,
+			"{} changed, run with UPDATE_SNAPSHOTS=1 if that's intended:\n{}",
+			file_name,
+			contents
+		);
+	}
+
+	#[test]
+	fn test_renders_visible_subcommands() {
+		let pages = pages();
+		let names: Vec<&str> = pages.iter().map(|p| p.file_name.as_str()).collect();
+		for name in [
+			"code.1",
+			"code-tunnel.1",
+			"code-tunnel-service-install.1",
+			"code-serve-web.1",
+			"code-config-set.1",
+		] {
+			assert!(names.contains(&name), "{} not in {:?}", name, names);
+		}
+		assert!(!names.contains(&"code-command-shell.1"));
+		assert!(!names.iter().any(|n| n.contains("help")));
+	}
+
+	#[test]
+	fn test_is_deterministic() {
+		let a = pages();
+		let b = pages();
+		assert_eq!(a.len(), b.len());
+		for (a, b) in a.iter().zip(b.iter()) {
+			assert_eq!(a.file_name, b.file_name);
+			assert_eq!(a.contents, b.contents);
+		}
+	}
+
+	#[test]
+	fn test_serve_web_page() {
+		let contents = page(&pages(), "code-serve-web.1");
+		assert!(contents.contains(".TH"), "{}", contents);
+		assert!(contents.contains("port"), "{}", contents);
+		assert!(
+			contents.contains("VSCODE_CLI_SERVE_WEB_PORT"),
+			"{}",
+			contents
+		);
+		assert_snapshot("code-serve-web.1", &contents);
+	}
+
+	#[test]
+	fn test_tunnel_page() {
+		let contents = page(&pages(), "code-tunnel.1");
+		assert!(contents.contains(".TH"), "{}", contents);
+		assert!(contents.contains("service"), "{}", contents);
+		assert_snapshot("code-tunnel.1", &contents);
+	}
+
+	#[test]
+	fn test_write_pages() {
+		let dir = tempfile::tempdir().unwrap();
+		let out = dir.path().join("man1");
+		write_pages(&out, &pages()).unwrap();
+		assert!(out.join("code.1").exists());
+		assert!(out.join("code-tunnel-user-login.1").exists());
+	}
+}
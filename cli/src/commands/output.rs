@@ -4,10 +4,548 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::RwLock;
 
 use std::io::{BufWriter, Write};
 
-use super::args::OutputFormat;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::{
+	auth::{AuthProvider, CredentialInfo, CredentialValidation},
+	desktop::InstallKind,
+	log,
+	tunnels::{paths::InstalledBuild, ServiceStatus},
+	util::prereqs::Requirement,
+};
+
+use super::{
+	args::{Deprecation, OutputFormat},
+	tunnels::StatusOutput,
+};
+
+/// Results of an informational command. They're printed as text by default,
+/// or as JSON with `--output json`, where their serialization is the schema.
+pub trait CommandOutput: Serialize {
+	/// Gets the human-readable text of the results.
+	fn human(&self) -> String;
+}
+
+lazy_static! {
+	static ref DEPRECATIONS: RwLock<Vec<Deprecation>> = RwLock::new(vec![]);
+}
+
+/// Sets the deprecated names used to run the command, which JSON results
+/// report.
+pub fn set_deprecations(deprecations: Vec<Deprecation>) {
+	*DEPRECATIONS.write().unwrap() = deprecations;
+}
+
+impl OutputFormat {
+	/// Renders the command's results in the format.
+	pub fn render(&self, output: &impl CommandOutput) -> String {
+		match self {
+			OutputFormat::Json => to_json(output),
+			OutputFormat::Text => output.human(),
+		}
+	}
+}
+
+/// Serializes results printed as JSON with the deprecated names used to run
+/// the command. Commands that print JSON without a `CommandOutput`, such as
+/// `tunnel ports`, use this rather than `serde_json` so scripts see them too.
+pub fn to_json(value: &impl Serialize) -> String {
+	let (json, notice) = to_json_with(value, &DEPRECATIONS.read().unwrap());
+	if let Some(notice) = notice {
+		eprintln!("{}", notice);
+	}
+	json
+}
+
+/// Adds the deprecations as `deprecations` to JSON objects, so that scripts
+/// can find what to change. Other results, such as lists, can't hold them,
+/// so they're returned as a JSON object of their own to write to stderr.
+fn to_json_with(value: &impl Serialize, deprecations: &[Deprecation]) -> (String, Option<String>) {
+	if deprecations.is_empty() {
+		return (
+			serde_json::to_string(value).expect("expected to serialize"),
+			None,
+		);
+	}
+
+	let deprecations = serde_json::to_value(deprecations).expect("expected to serialize");
+	let mut value = serde_json::to_value(value).expect("expected to serialize");
+	match value.as_object_mut() {
+		Some(obj) => {
+			obj.insert("deprecations".to_string(), deprecations);
+			(value.to_string(), None)
+		}
+		None => (
+			value.to_string(),
+			Some(serde_json::json!({ "deprecations": deprecations }).to_string()),
+		),
+	}
+}
+
+/// How much a command prints besides its result. Results go to stdout at
+/// every level. Notices, such as progress and instructions, and logs go to
+/// stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Verbosity {
+	/// Only results and errors, from `--quiet`.
+	Quiet,
+	/// Results, notices, and logs at the `--log` level.
+	#[default]
+	Normal,
+	/// Everything, including debug and trace logs, from `--verbose`.
+	Verbose,
+}
+
+impl Verbosity {
+	/// Gets the filter for logs at this verbosity. The configured filter is
+	/// only used at the normal verbosity.
+	pub fn log_filter(self, configured: Option<log::LogFilter>) -> log::LogFilter {
+		match self {
+			Verbosity::Quiet => log::LogFilter::from(log::Level::Error),
+			Verbosity::Normal => configured.unwrap_or_default(),
+			Verbosity::Verbose => log::LogFilter::from(log::Level::Trace),
+		}
+	}
+}
+
+/// Output of `version show`.
+#[derive(Serialize, Debug)]
+pub struct VersionOutput {
+	/// Version that's used, such as `stable` or `1.80.0`.
+	pub version: String,
+	/// Installation of the version, if there's one.
+	pub install_path: Option<PathBuf>,
+	/// How the installation was packaged, such as `flatpak`.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub install_kind: Option<InstallKind>,
+}
+
+impl CommandOutput for VersionOutput {
+	fn human(&self) -> String {
+		let install = match (&self.install_path, self.install_kind) {
+			(Some(p), Some(kind)) => format!("Installation path: {} ({})", p.display(), kind),
+			(Some(p), None) => format!("Installation path: {}", p.display()),
+			(None, _) => "No existing installation found".to_string(),
+		};
+		format!("Current quality: {}\n{}", self.version, install)
+	}
+}
+
+/// Output of `version list`.
+#[derive(Serialize, Debug)]
+#[serde(transparent)]
+pub struct VersionListOutput(pub Vec<InstallOutput>);
+
+/// An editor install in `version list`.
+#[derive(Serialize, Debug)]
+pub struct InstallOutput {
+	pub quality: Option<String>,
+	pub version: Option<String>,
+	pub commit: Option<String>,
+	/// The install's CLI.
+	pub path: PathBuf,
+	pub kind: InstallKind,
+	/// Whether it's the install the CLI uses.
+	pub selected: bool,
+	/// Why the install's details couldn't be read.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+}
+
+impl CommandOutput for VersionListOutput {
+	fn human(&self) -> String {
+		if self.0.is_empty() {
+			return "No installations found".to_string();
+		}
+
+		self.0
+			.iter()
+			.map(|i| {
+				let marker = if i.selected { "*" } else { " " };
+				let known = format!(
+					"{} {} ({})",
+					i.quality.as_deref().unwrap_or("unknown quality"),
+					i.version.as_deref().unwrap_or("unknown version"),
+					match &i.commit {
+						Some(c) => c.chars().take(10).collect(),
+						None => "unknown commit".to_string(),
+					},
+				);
+				// details the installer registered are shown with why the files couldn't be read
+				let details = match &i.error {
+					Some(e) if i.quality.is_none() && i.version.is_none() => {
+						format!("error: {}", e)
+					}
+					Some(e) => format!("{}, error: {}", known, e),
+					None => known,
+				};
+				format!("{} {} [{}] {}", marker, details, i.kind, i.path.display())
+			})
+			.collect::<Vec<_>>()
+			.join("\n")
+	}
+}
+
+/// Output of `version clean`.
+#[derive(Serialize, Debug)]
+pub struct VersionCleanOutput {
+	pub dry_run: bool,
+	pub entries: Vec<CleanedEntryOutput>,
+	pub builds: Vec<CleanedBuildOutput>,
+}
+
+/// A stored version whose install is gone.
+#[derive(Serialize, Debug)]
+pub struct CleanedEntryOutput {
+	pub version: String,
+	pub path: PathBuf,
+	pub selected: bool,
+	pub removed: bool,
+}
+
+/// A downloaded build no version uses.
+#[derive(Serialize, Debug)]
+pub struct CleanedBuildOutput {
+	pub path: PathBuf,
+	pub removed: bool,
+}
+
+impl CommandOutput for VersionCleanOutput {
+	fn human(&self) -> String {
+		if self.entries.is_empty() && self.builds.is_empty() {
+			return "Nothing to clean up".to_string();
+		}
+
+		let verb = |removed: bool| match (removed, self.dry_run) {
+			(true, _) => "Removed",
+			(false, true) => "Would remove",
+			(false, false) => "Kept",
+		};
+		let entries = self.entries.iter().map(|e| {
+			format!(
+				"{} {} at {}{}",
+				verb(e.removed),
+				e.version,
+				e.path.display(),
+				if e.selected { " (selected)" } else { "" }
+			)
+		});
+		let builds = self
+			.builds
+			.iter()
+			.map(|b| format!("{} unused download {}", verb(b.removed), b.path.display()));
+		entries.chain(builds).collect::<Vec<_>>().join("\n")
+	}
+}
+
+/// Output of `cache stats`.
+#[derive(Serialize, Debug)]
+pub struct CacheStatsOutput {
+	pub servers: Vec<InstalledBuild>,
+	/// Bytes used by all server builds.
+	pub total_bytes: u64,
+}
+
+impl CommandOutput for CacheStatsOutput {
+	fn human(&self) -> String {
+		if self.servers.is_empty() {
+			return "No servers are installed".to_string();
+		}
+
+		let mut lines: Vec<String> = self
+			.servers
+			.iter()
+			.map(|b| {
+				let mut notes = vec![];
+				if let Some(pid) = b.running_pid {
+					notes.push(format!("running as {}", pid));
+				}
+				if !b.complete {
+					notes.push("incomplete".to_string());
+				}
+				format!(
+					"{} {}{}: {}, last used {}{}",
+					b.server.quality,
+					b.server.commit,
+					if b.server.headless { "" } else { " (web)" },
+					format_bytes(b.size),
+					b.last_used
+						.map(|t| t.to_rfc3339())
+						.unwrap_or_else(|| "never".to_string()),
+					if notes.is_empty() {
+						String::new()
+					} else {
+						format!(" [{}]", notes.join(", "))
+					}
+				)
+			})
+			.collect();
+		lines.push(format!("Total: {}", format_bytes(self.total_bytes)));
+		lines.join("\n")
+	}
+}
+
+/// Formats a byte count with the largest unit that keeps it above 1.
+pub fn format_bytes(bytes: u64) -> String {
+	const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+	if bytes < 1024 {
+		return format!("{} B", bytes);
+	}
+
+	let mut value = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", value, UNITS[unit])
+}
+
+// `tunnel status` has always printed JSON, so its text output is JSON too.
+impl CommandOutput for StatusOutput {
+	fn human(&self) -> String {
+		to_json(self)
+	}
+}
+
+/// Output of `tunnel user show`. It's serialized as the credential details,
+/// or null when not logged in.
+#[derive(Serialize, Debug)]
+#[serde(transparent)]
+pub struct UserShowOutput {
+	pub info: Option<CredentialInfo>,
+	/// Provider logins use by default, shown when not logged in.
+	#[serde(skip)]
+	pub default_provider: Option<AuthProvider>,
+	/// Whether to show every detail of the credentials as text.
+	#[serde(skip)]
+	pub verbose: bool,
+}
+
+impl CommandOutput for UserShowOutput {
+	fn human(&self) -> String {
+		let info = match (&self.info, self.default_provider) {
+			(Some(info), _) => info,
+			(None, None) => return "not logged in".to_string(),
+			(None, Some(p)) => return format!("not logged in, logins use {} by default", p),
+		};
+
+		if self.verbose {
+			return format_credential_info(info);
+		}
+
+		match &info.validation {
+			Some(CredentialValidation {
+				valid: false,
+				reason,
+				..
+			}) => format!(
+				"logged in, but the token is not valid: {}",
+				reason.as_deref().unwrap_or("unknown reason")
+			),
+			_ => "logged in".to_string(),
+		}
+	}
+}
+
+fn format_credential_info(info: &CredentialInfo) -> String {
+	let optional_time = |t: Option<chrono::DateTime<chrono::Utc>>, none: &str| {
+		t.map(|t| t.to_rfc3339())
+			.unwrap_or_else(|| none.to_string())
+	};
+
+	let mut lines = vec![
+		"logged in".to_string(),
+		format!("  Profile: {}", info.profile),
+		format!("  Provider: {}", info.provider),
+		format!("  Cloud: {}", info.cloud),
+		format!(
+			"  Account: {}",
+			info.account.as_deref().unwrap_or("unknown")
+		),
+		format!(
+			"  Scopes: {}",
+			info.scopes
+				.as_ref()
+				.map(|s| s.join(", "))
+				.unwrap_or_else(|| "unknown".to_string())
+		),
+		format!("  Expires: {}", optional_time(info.expires_at, "never")),
+		format!(
+			"  Refresh token: {}",
+			if info.has_refresh_token { "yes" } else { "no" }
+		),
+		format!(
+			"  Personal access token: {}",
+			if info.personal_access_token {
+				"yes"
+			} else {
+				"no"
+			}
+		),
+		format!("  Storage: {}", info.storage),
+		format!(
+			"  Last refreshed: {}",
+			optional_time(info.refreshed_at, "unknown")
+		),
+		format!(
+			"  Default provider: {}",
+			info.default_provider
+				.map(|p| p.to_string())
+				.unwrap_or_else(|| "none".to_string())
+		),
+	];
+
+	if let Some(v) = &info.validation {
+		match &v.reason {
+			None => lines.push("  Valid: yes".to_string()),
+			Some(r) => lines.push(format!("  Valid: no, {}", r)),
+		}
+		if let Some(a) = &v.account {
+			lines.push(format!("  Provider account: {}", a));
+		}
+	}
+
+	lines.join("\n")
+}
+
+/// Printed with `--output json` when a command fails because the machine
+/// doesn't meet the server's prerequisites, in addition to the error.
+#[derive(Serialize, Debug)]
+pub struct PrerequisitesFailedOutput {
+	pub error: String,
+	pub requirements: Vec<Requirement>,
+}
+
+/// Output of `ext list`.
+#[derive(Serialize, Debug, Default)]
+pub struct ExtensionListOutput(pub Vec<InstalledExtension>);
+
+#[derive(Serialize, Debug)]
+pub struct InstalledExtension {
+	pub id: String,
+	pub version: Option<String>,
+}
+
+impl ExtensionListOutput {
+	/// Parses the `id@version` lines that `--list-extensions --show-versions`
+	/// prints.
+	pub fn parse(listed: &str) -> Self {
+		let extensions = listed
+			.lines()
+			.map(|l| l.trim())
+			.filter(|l| !l.is_empty())
+			.map(|l| match l.split_once('@') {
+				Some((id, version)) => InstalledExtension {
+					id: id.to_string(),
+					version: Some(version.to_string()),
+				},
+				None => InstalledExtension {
+					id: l.to_string(),
+					version: None,
+				},
+			})
+			.collect();
+		Self(extensions)
+	}
+}
+
+impl CommandOutput for ExtensionListOutput {
+	fn human(&self) -> String {
+		self.0
+			.iter()
+			.map(|e| match &e.version {
+				Some(v) => format!("{}@{}", e.id, v),
+				None => e.id.clone(),
+			})
+			.collect::<Vec<_>>()
+			.join("\n")
+	}
+}
+
+/// Output of `ext install`, with a result for each extension.
+#[derive(Serialize, Debug, Default)]
+pub struct ExtensionInstallOutput(pub Vec<ExtensionInstallResult>);
+
+#[derive(Serialize, Debug)]
+pub struct ExtensionInstallResult {
+	/// The extension as it was given on the command line.
+	pub extension: String,
+	pub success: bool,
+	pub status: ExtensionInstallStatus,
+	pub message: String,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtensionInstallStatus {
+	Installed,
+	AlreadyInstalled,
+	Failed,
+}
+
+impl Display for ExtensionInstallStatus {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.pad(match self {
+			ExtensionInstallStatus::Installed => "installed",
+			ExtensionInstallStatus::AlreadyInstalled => "already installed",
+			ExtensionInstallStatus::Failed => "failed",
+		})
+	}
+}
+
+impl ExtensionInstallOutput {
+	pub fn any_failed(&self) -> bool {
+		self.0.iter().any(|r| !r.success)
+	}
+
+	fn count(&self, status: ExtensionInstallStatus) -> usize {
+		self.0.iter().filter(|r| r.status == status).count()
+	}
+}
+
+impl CommandOutput for ExtensionInstallOutput {
+	fn human(&self) -> String {
+		let width = self
+			.0
+			.iter()
+			.map(|r| r.extension.len())
+			.chain(["Extension".len()])
+			.max()
+			.unwrap_or_default();
+		let status_width = "already installed".len();
+
+		let mut lines = vec![format!(
+			"{:width$}  {:status_width$}  Details",
+			"Extension", "Result"
+		)];
+		for r in &self.0 {
+			lines.push(format!(
+				"{:width$}  {:status_width$}  {}",
+				r.extension, r.status, r.message
+			));
+		}
+		lines.push(format!(
+			"\n{} installed, {} already installed, {} failed",
+			self.count(ExtensionInstallStatus::Installed),
+			self.count(ExtensionInstallStatus::AlreadyInstalled),
+			self.count(ExtensionInstallStatus::Failed)
+		));
+		lines.join("\n")
+	}
+}
+
+impl CommandOutput for ServiceStatus {
+	fn human(&self) -> String {
+		self.to_string().trim_end().to_string()
+	}
+}
 
 pub struct Column {
 	max_width: usize,
@@ -133,3 +671,510 @@ where
 	}
 	w.write_all(b"\r\n")
 }
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Mutex;
+
+	use crate::{
+		auth::{AuthCloud, TokenStorageKind},
+		log::{LogFormat, Logger, StdioLogSink},
+		options::Quality,
+		tunnels::paths::InstalledServer,
+	};
+
+	use super::*;
+
+	/// Writes one of each kind of output at the verbosity through the stdio
+	/// sink, returning what was printed to stdout and stderr without
+	/// timestamps.
+	fn output_at(verbosity: Verbosity) -> (Vec<String>, Vec<String>) {
+		let filter = verbosity.log_filter(None);
+		let (sink, captured) = StdioLogSink::capturing(filter.max_level(), LogFormat::Human);
+		let log = Logger::test().with_sink(sink).filtered(filter);
+
+		log.emit(log::Level::Trace, "resolved the server");
+		log.emit(log::Level::Info, "downloading");
+		log.notice("Open the link to sign in");
+		log.emit(log::Level::Error, "download failed");
+		log.result("the result");
+
+		let lines = |s: &Mutex<String>| -> Vec<String> {
+			s.lock()
+				.unwrap()
+				.lines()
+				.map(|l| match l.split_once("] ") {
+					Some((ts, rest)) if ts.starts_with('[') => rest.to_string(),
+					_ => l.to_string(),
+				})
+				.collect()
+		};
+		(lines(&captured.stdout), lines(&captured.stderr))
+	}
+
+	#[test]
+	fn test_verbosity_streams() {
+		let (stdout, stderr) = output_at(Verbosity::Quiet);
+		assert_eq!(stdout, vec!["the result"]);
+		assert_eq!(stderr, vec!["error download failed"]);
+
+		let (stdout, stderr) = output_at(Verbosity::Normal);
+		assert_eq!(stdout, vec!["the result"]);
+		assert_eq!(
+			stderr,
+			vec![
+				"info downloading",
+				"Open the link to sign in",
+				"error download failed"
+			]
+		);
+
+		let (stdout, stderr) = output_at(Verbosity::Verbose);
+		assert_eq!(stdout, vec!["the result"]);
+		assert_eq!(
+			stderr,
+			vec![
+				"trace resolved the server",
+				"info downloading",
+				"Open the link to sign in",
+				"error download failed"
+			]
+		);
+	}
+
+	#[test]
+	fn test_json_deprecation_notice() {
+		let output = VersionOutput {
+			version: "stable".to_string(),
+			install_path: None,
+			install_kind: None,
+		};
+		let deprecations = vec![Deprecation {
+			old: "tunnel logs".to_string(),
+			new: "tunnel log".to_string(),
+			removed_in: "2.0".to_string(),
+		}];
+
+		let expected =
+			serde_json::json!([{"old": "tunnel logs", "new": "tunnel log", "removed_in": "2.0"}]);
+
+		let (json, notice) = to_json_with(&output, &deprecations);
+		let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+		assert_eq!(json["version"], "stable");
+		assert_eq!(json["deprecations"], expected);
+		assert_eq!(notice, None);
+
+		// lists keep their shape, with the deprecations written separately
+		let (json, notice) = to_json_with(&vec!["a", "b"], &deprecations);
+		assert_eq!(json, r#"["a","b"]"#);
+		let notice: serde_json::Value = serde_json::from_str(&notice.unwrap()).unwrap();
+		assert_eq!(notice["deprecations"], expected);
+
+		let (json, notice) = to_json_with(&output, &[]);
+		assert!(!json.contains("deprecations"), "{}", json);
+		assert_eq!(notice, None);
+	}
+
+	#[test]
+	fn test_version_list_output() {
+		let output = VersionListOutput(vec![
+			InstallOutput {
+				quality: Some("stable".to_string()),
+				version: Some("1.90.0".to_string()),
+				commit: Some("0123456789abcdef".to_string()),
+				path: PathBuf::from("/usr/share/code/bin/code"),
+				kind: InstallKind::Standard,
+				selected: true,
+				error: None,
+			},
+			InstallOutput {
+				quality: None,
+				version: None,
+				commit: None,
+				path: PathBuf::from("/opt/custom/bin/code"),
+				kind: InstallKind::Portable,
+				selected: false,
+				error: Some("error parsing product.json".to_string()),
+			},
+			InstallOutput {
+				quality: Some("insider".to_string()),
+				version: Some("1.91.0-insider".to_string()),
+				commit: None,
+				path: PathBuf::from("C:/Code/bin/code-insiders.cmd"),
+				kind: InstallKind::Standard,
+				selected: false,
+				error: Some("could not find the product.json".to_string()),
+			},
+		]);
+
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			"* stable 1.90.0 (0123456789) [standard] /usr/share/code/bin/code\n  error: error parsing product.json [portable] /opt/custom/bin/code\n  insider 1.91.0-insider (unknown commit), error: could not find the product.json [standard] C:/Code/bin/code-insiders.cmd"
+		);
+		let json: serde_json::Value =
+			serde_json::from_str(&OutputFormat::Json.render(&output)).unwrap();
+		assert_eq!(json[0]["selected"], true);
+		assert_eq!(json[0]["kind"], "standard");
+		assert!(json[0].get("error").is_none());
+		assert_eq!(json[1]["error"], "error parsing product.json");
+		assert_eq!(json[1]["quality"], serde_json::Value::Null);
+
+		assert_eq!(
+			OutputFormat::Text.render(&VersionListOutput(vec![])),
+			"No installations found"
+		);
+	}
+
+	#[test]
+	fn test_version_clean_output() {
+		let output = VersionCleanOutput {
+			dry_run: false,
+			entries: vec![
+				CleanedEntryOutput {
+					version: "stable/c0ffee".to_string(),
+					path: PathBuf::from("/old/bin/code"),
+					selected: false,
+					removed: true,
+				},
+				CleanedEntryOutput {
+					version: "stable/decaf".to_string(),
+					path: PathBuf::from("/gone/bin/code"),
+					selected: true,
+					removed: false,
+				},
+			],
+			builds: vec![CleanedBuildOutput {
+				path: PathBuf::from("/data/desktop/stable-c0ffee"),
+				removed: true,
+			}],
+		};
+
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			"Removed stable/c0ffee at /old/bin/code\nKept stable/decaf at /gone/bin/code (selected)\nRemoved unused download /data/desktop/stable-c0ffee"
+		);
+		let json: serde_json::Value =
+			serde_json::from_str(&OutputFormat::Json.render(&output)).unwrap();
+		assert_eq!(json["dry_run"], false);
+		assert_eq!(json["entries"][1]["selected"], true);
+		assert_eq!(
+			OutputFormat::Text.render(&VersionCleanOutput {
+				dry_run: true,
+				entries: vec![],
+				builds: vec![],
+			}),
+			"Nothing to clean up"
+		);
+	}
+
+	#[test]
+	fn test_verbosity_log_filter() {
+		let configured: log::LogFilter = "warn".parse().unwrap();
+		assert_eq!(
+			Verbosity::Normal
+				.log_filter(Some(configured.clone()))
+				.default_level(),
+			log::Level::Warn
+		);
+		assert_eq!(
+			Verbosity::Quiet
+				.log_filter(Some(configured.clone()))
+				.default_level(),
+			log::Level::Error
+		);
+		assert_eq!(
+			Verbosity::Verbose
+				.log_filter(Some(configured))
+				.default_level(),
+			log::Level::Trace
+		);
+	}
+
+	fn credential_info() -> CredentialInfo {
+		CredentialInfo {
+			profile: "default".to_string(),
+			provider: AuthProvider::Github,
+			cloud: AuthCloud::Public,
+			account: Some("octocat".to_string()),
+			scopes: Some(vec!["read:user".to_string()]),
+			expires_at: None,
+			has_refresh_token: true,
+			personal_access_token: false,
+			storage: TokenStorageKind::Keyring,
+			refreshed_at: None,
+			default_provider: None,
+			validation: None,
+		}
+	}
+
+	#[test]
+	fn test_version_output() {
+		let output = VersionOutput {
+			version: "stable".to_string(),
+			install_path: Some(PathBuf::from("/opt/code")),
+			install_kind: None,
+		};
+		assert_eq!(
+			OutputFormat::Json.render(&output),
+			r#"{"version":"stable","install_path":"/opt/code"}"#
+		);
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			"Current quality: stable\nInstallation path: /opt/code"
+		);
+
+		let output = VersionOutput {
+			install_kind: Some(InstallKind::Flatpak),
+			..output
+		};
+		assert_eq!(
+			OutputFormat::Json.render(&output),
+			r#"{"version":"stable","install_path":"/opt/code","install_kind":"flatpak"}"#
+		);
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			"Current quality: stable\nInstallation path: /opt/code (flatpak)"
+		);
+
+		let output = VersionOutput {
+			version: "1.80.0".to_string(),
+			install_path: None,
+			install_kind: None,
+		};
+		assert_eq!(
+			OutputFormat::Json.render(&output),
+			r#"{"version":"1.80.0","install_path":null}"#
+		);
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			"Current quality: 1.80.0\nNo existing installation found"
+		);
+	}
+
+	#[test]
+	fn test_status_output() {
+		let output = StatusOutput {
+			tunnel: None,
+			service_installed: true,
+			interop: None,
+			restarts: None,
+		};
+		let expected = r#"{"tunnel":null,"service_installed":true}"#;
+		assert_eq!(OutputFormat::Json.render(&output), expected);
+		assert_eq!(OutputFormat::Text.render(&output), expected);
+	}
+
+	#[test]
+	fn test_user_show_output() {
+		let output = UserShowOutput {
+			info: Some(credential_info()),
+			default_provider: None,
+			verbose: false,
+		};
+		assert_eq!(
+			OutputFormat::Json.render(&output),
+			concat!(
+				r#"{"profile":"default","provider":"Github","cloud":"public","account":"octocat","#,
+				r#""scopes":["read:user"],"expires_at":null,"has_refresh_token":true,"#,
+				r#""personal_access_token":false,"storage":"keyring","refreshed_at":null,"#,
+				r#""default_provider":null}"#
+			)
+		);
+		assert_eq!(OutputFormat::Text.render(&output), "logged in");
+
+		let output = UserShowOutput {
+			info: None,
+			default_provider: Some(AuthProvider::Github),
+			verbose: false,
+		};
+		assert_eq!(OutputFormat::Json.render(&output), "null");
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			"not logged in, logins use GitHub Account by default"
+		);
+	}
+
+	#[test]
+	fn test_extension_list_output() {
+		let output = ExtensionListOutput::parse(
+			"ms-python.python@2024.1.0\n\nesbenp.prettier-vscode@10.1.0\n",
+		);
+		assert_eq!(
+			OutputFormat::Json.render(&output),
+			concat!(
+				r#"[{"id":"ms-python.python","version":"2024.1.0"},"#,
+				r#"{"id":"esbenp.prettier-vscode","version":"10.1.0"}]"#
+			)
+		);
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			"ms-python.python@2024.1.0\nesbenp.prettier-vscode@10.1.0"
+		);
+		assert_eq!(
+			OutputFormat::Json.render(&ExtensionListOutput::default()),
+			"[]"
+		);
+	}
+
+	#[test]
+	fn test_extension_install_output() {
+		let output = ExtensionInstallOutput(vec![
+			ExtensionInstallResult {
+				extension: "a.b".to_string(),
+				success: true,
+				status: ExtensionInstallStatus::Installed,
+				message: "installed v1.0.0".to_string(),
+			},
+			ExtensionInstallResult {
+				extension: "c.d".to_string(),
+				success: false,
+				status: ExtensionInstallStatus::Failed,
+				message: "Extension 'c.d' not found.".to_string(),
+			},
+		]);
+		assert_eq!(
+			OutputFormat::Json.render(&output),
+			concat!(
+				r#"[{"extension":"a.b","success":true,"status":"installed","message":"installed v1.0.0"},"#,
+				r#"{"extension":"c.d","success":false,"status":"failed","message":"Extension 'c.d' not found."}]"#
+			)
+		);
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			concat!(
+				"Extension  Result             Details\n",
+				"a.b        installed          installed v1.0.0\n",
+				"c.d        failed             Extension 'c.d' not found.\n",
+				"\n",
+				"1 installed, 0 already installed, 1 failed"
+			)
+		);
+	}
+
+	#[test]
+	fn test_service_status_output() {
+		let output = ServiceStatus {
+			name: "code-tunnel.service".to_string(),
+			installed: true,
+			enabled: Some(true),
+			running: Some(true),
+			pid: Some(42),
+			..Default::default()
+		};
+		assert_eq!(
+			OutputFormat::Json.render(&output),
+			concat!(
+				r#"{"name":"code-tunnel.service","installed":true,"enabled":true,"running":true,"#,
+				r#""pid":42,"binary_path":null,"binary_matches_current":null,"last_exit_code":null,"#,
+				r#""notes":[]}"#
+			)
+		);
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			"Service: code-tunnel.service\nInstalled: yes\nEnabled: yes\nRunning: yes (pid 42)"
+		);
+	}
+
+	#[test]
+	fn test_cache_stats_output() {
+		let output = CacheStatsOutput {
+			servers: vec![InstalledBuild {
+				server: InstalledServer {
+					quality: Quality::Stable,
+					commit: "c0ffee".to_string(),
+					headless: true,
+				},
+				folder: "Stable-c0ffee".to_string(),
+				size: 2048,
+				last_used: None,
+				lru_rank: Some(0),
+				complete: true,
+				running_pid: Some(42),
+			}],
+			total_bytes: 2048,
+		};
+		assert_eq!(
+			OutputFormat::Json.render(&output),
+			concat!(
+				r#"{"servers":[{"quality":"stable","commit":"c0ffee","headless":true,"#,
+				r#""folder":"Stable-c0ffee","size":2048,"last_used":null,"lru_rank":0,"#,
+				r#""complete":true,"running_pid":42}],"total_bytes":2048}"#
+			)
+		);
+		assert_eq!(
+			OutputFormat::Text.render(&output),
+			"Stable c0ffee: 2.0 KB, last used never [running as 42]\nTotal: 2.0 KB"
+		);
+		assert_eq!(
+			OutputFormat::Text.render(&CacheStatsOutput {
+				servers: vec![],
+				total_bytes: 0,
+			}),
+			"No servers are installed"
+		);
+	}
+
+	/// Checks that every key in the JSON is snake_case, as `tunnel status`
+	/// has always written them.
+	fn assert_snake_case_keys(value: &serde_json::Value) {
+		match value {
+			serde_json::Value::Object(obj) => {
+				for (k, v) in obj {
+					assert!(
+						k.chars()
+							.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'),
+						"{} in {}",
+						k,
+						value
+					);
+					assert_snake_case_keys(v);
+				}
+			}
+			serde_json::Value::Array(items) => items.iter().for_each(assert_snake_case_keys),
+			_ => {}
+		}
+	}
+
+	#[test]
+	fn test_json_keys_are_snake_case() {
+		let mut credentials = credential_info();
+		credentials.validation = Some(CredentialValidation {
+			valid: false,
+			account: Some("octocat".to_string()),
+			scopes: Some(vec![]),
+			missing_scopes: vec!["read:org".to_string()],
+			reason: Some("expired".to_string()),
+		});
+		let outputs: Vec<serde_json::Value> = vec![
+			serde_json::to_value(VersionOutput {
+				version: "stable".to_string(),
+				install_path: Some(PathBuf::from("/opt/code")),
+				install_kind: Some(InstallKind::Flatpak),
+			})
+			.unwrap(),
+			serde_json::to_value(UserShowOutput {
+				info: Some(credentials),
+				default_provider: None,
+				verbose: false,
+			})
+			.unwrap(),
+			serde_json::to_value(VersionCleanOutput {
+				dry_run: true,
+				entries: vec![],
+				builds: vec![],
+			})
+			.unwrap(),
+			serde_json::to_value(StatusOutput {
+				tunnel: Some(Default::default()),
+				service_installed: true,
+				interop: None,
+				restarts: None,
+			})
+			.unwrap(),
+			serde_json::to_value(ServiceStatus::default()).unwrap(),
+		];
+		for output in &outputs {
+			assert_snake_case_keys(output);
+		}
+	}
+}
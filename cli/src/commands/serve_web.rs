@@ -3,18 +3,30 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+mod access_log;
+mod caching;
+mod compression;
+mod websocket;
+
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fs;
 use std::io::{Read, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use futures::FutureExt;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
+use serde::Serialize;
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+
+use self::access_log::AccessLog;
 use tokio::pin;
 
 use crate::async_pipe::{
@@ -24,22 +36,25 @@ use crate::constants::VSCODE_CLI_QUALITY;
 use crate::download_cache::DownloadCache;
 use crate::log;
 use crate::options::Quality;
+use crate::policy::{self, PolicyFeature};
 use crate::state::{LauncherPaths, PersistedState};
-use crate::tunnels::shutdown_signal::ShutdownRequest;
+use crate::tunnels::shutdown_signal::{Drain, DEFAULT_DRAIN_TIMEOUT};
 use crate::update_service::{
 	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService,
 };
+use crate::util::browser;
 use crate::util::command::new_script_command;
+use crate::util::crypto::constant_time_eq;
 use crate::util::errors::AnyError;
 use crate::util::http::{self, ReqwestSimpleHttp};
 use crate::util::io::SilentCopyProgress;
-use crate::util::sync::{new_barrier, Barrier, BarrierOpener};
-use crate::{
-	tunnels::legal,
-	util::{errors::CodeError, prereqs::PreReqChecker},
-};
+use crate::util::preflight::{self, PreflightNeeds, SystemProbe};
+use crate::util::progress;
+use crate::util::sync::{new_barrier, ActivityTracker, Barrier, BarrierOpener};
+use crate::util::tls::{self, CertificatePaths, ReloadableCertificate, TlsIncoming};
+use crate::{tunnels::legal, util::errors::CodeError};
 
-use super::{args::ServeWebArgs, CommandContext};
+use super::{args::ServeWebArgs, output::to_json, CommandContext};
 
 /// Length of a commit hash, for validation
 const COMMIT_HASH_LEN: usize = 40;
@@ -49,17 +64,33 @@ const SERVER_IDLE_TIMEOUT_SECS: u64 = 60 * 60;
 /// Number of seconds in which the server times out when there is a connection
 /// (should be large enough to basically never happen)
 const SERVER_ACTIVE_TIMEOUT_SECS: u64 = SERVER_IDLE_TIMEOUT_SECS * 24 * 30 * 12;
+/// Number of seconds to wait for servers to exit once they're told to stop
+/// on shutdown.
+const SERVER_STOP_TIMEOUT_SECS: u64 = 10;
 /// How long to cache the "latest" version we get from the update service.
 const RELEASE_CACHE_SECS: u64 = 60 * 60;
+/// How long to serve substitutes for a version that failed to download
+/// before trying to download it again.
+const UNAVAILABLE_RETRY_SECS: u64 = 10 * 60;
+/// Response header set to the commit that was served when the requested one
+/// was unavailable.
+const SUBSTITUTE_COMMIT_HEADER: &str = "x-vscode-substitute-commit";
 
 /// Number of bytes for the secret keys. See workbench.ts for their usage.
 const SECRET_KEY_BYTES: usize = 32;
 /// Path to mint the key combining server and client parts.
 const SECRET_KEY_MINT_PATH: &str = "_vscode-cli/mint-key";
+/// Path, under the base path, that load balancers can check the server with.
+const HEALTH_PATH: &str = "_vscode-cli/health";
 /// Cookie set to the `SECRET_KEY_MINT_PATH`
 const PATH_COOKIE_NAME: &str = "vscode-secret-key-path";
 /// HTTP-only cookie where the client's secret half is stored.
 const SECRET_KEY_COOKIE_NAME: &str = "vscode-cli-secret-half";
+/// Query parameter the connection token is given in, as in the printed URL.
+const CONNECTION_TOKEN_QUERY_PARAM: &str = "tkn";
+/// Cookie the connection token is kept in, which is the same one the VS Code
+/// server uses.
+const CONNECTION_TOKEN_COOKIE_NAME: &str = "vscode-tkn";
 
 /// Implements the vscode "server of servers". Clients who go to the URI get
 /// served the latest version of the VS Code server whenever they load the
@@ -67,10 +98,27 @@ const SECRET_KEY_COOKIE_NAME: &str = "vscode-cli-secret-half";
 /// its version string, so existing clients can continue to get served even
 /// while new clients get new VS Code Server versions.
 pub async fn serve_web(ctx: CommandContext, mut args: ServeWebArgs) -> Result<i32, AnyError> {
+	if args.without_connection_token {
+		policy::current().ensure_allowed(PolicyFeature::ServeWebWithoutToken)?;
+	}
+
 	legal::require_consent(&ctx.paths, args.accept_server_license_terms)?;
 
-	let platform: crate::update_service::Platform = PreReqChecker::new().verify().await?;
-	if !args.without_connection_token {
+	let platform: crate::update_service::Platform = ctx.prereqs().verify().await?;
+	if args.without_connection_token {
+		warning!(
+			ctx.log,
+			"Running without a connection token: anyone who can reach the server can run code on this machine. Only do this if access is secured by other means."
+		);
+	} else if let Some(file) = &args.connection_token_file {
+		args.connection_token = Some(read_connection_token_file(Path::new(file))?);
+	} else {
+		if let Some(t) = &args.connection_token {
+			if !is_valid_connection_token(t) {
+				return Err(CodeError::InvalidConnectionToken.into());
+			}
+		}
+
 		// Ensure there's a defined connection token, since if multiple server versions
 		// are excuted, they will need to have a single shared token.
 		let token_path = ctx.paths.root().join("serve-web-token");
@@ -80,64 +128,403 @@ pub async fn serve_web(ctx: CommandContext, mut args: ServeWebArgs) -> Result<i3
 		args.connection_token_file = Some(token_path.to_string_lossy().to_string());
 	}
 
+	if let Some(t) = &args.connection_token {
+		log::redact::register_secret(t);
+	}
+
+	args.server_base_path = args
+		.server_base_path
+		.map(|p| normalize_base_path(&p))
+		.filter(|p| p != "/");
+
+	let access_log = match &args.access_log {
+		Some(target) => Some(AccessLog::open(target).await?),
+		None => None,
+	};
+
 	let cm = ConnectionManager::new(&ctx, platform, args.clone());
+	let drain = cm.drain.clone();
+	let cm_for_shutdown = cm.clone();
 	let key = get_server_key_half(&ctx.paths);
-	let make_svc = move || {
+	let make_svc = move |peer: Option<SocketAddr>| {
 		let ctx = HandleContext {
 			cm: cm.clone(),
 			log: cm.log.clone(),
 			server_secret_key: key.clone(),
+			access_log: access_log.clone(),
+			peer,
 		};
 		let service = service_fn(move |req| handle(ctx.clone(), req));
 		async move { Ok::<_, Infallible>(service) }
 	};
 
-	let mut shutdown = ShutdownRequest::create_rx([ShutdownRequest::CtrlC]);
-	let r = if let Some(s) = args.socket_path {
-		let s = PathBuf::from(&s);
+	// servers stop accepting connections once the drain starts
+	let shutdown = tokio::spawn(drain_on_signal(ctx.log.clone(), drain.clone()));
+	if let Some(s) = &args.socket_path {
+		let s = PathBuf::from(s);
 		let socket = listen_socket_rw_stream(&s).await?;
-		ctx.log
-			.result(format!("Web UI available on {}", s.display()));
+		if args.json {
+			let output = StartedOutput {
+				socket_path: Some(s.to_string_lossy().to_string()),
+				..Default::default()
+			};
+			ctx.log.result(to_json(&output));
+		} else {
+			ctx.log
+				.result(format!("Web UI available on {}", s.display()));
+		}
 		let r = Server::builder(socket.into_pollable())
-			.serve(make_service_fn(|_| make_svc()))
+			.serve(make_service_fn(|_| make_svc(None)))
 			.with_graceful_shutdown(async {
-				let _ = shutdown.wait().await;
+				drain.started().await;
 			})
 			.await;
 		let _ = std::fs::remove_file(&s); // cleanup
-		r
+		r.map_err(CodeError::CouldNotListenOnInterface)?;
 	} else {
-		let addr: SocketAddr = match &args.host {
-			Some(h) => {
-				SocketAddr::new(h.parse().map_err(CodeError::InvalidHostAddress)?, args.port)
+		let addrs = resolve_listen_addrs(&args.host, args.port)?;
+		let listeners = bind_listeners(&ctx.log, &addrs).await?;
+		let cert = get_tls_certificate(&ctx, &args)?;
+		let scheme = if cert.is_some() { "https" } else { "http" };
+		let bound: Vec<SocketAddr> = listeners
+			.iter()
+			.filter_map(|l| l.local_addr().ok())
+			.collect();
+		let fingerprint = cert
+			.as_ref()
+			.filter(|_| args.self_signed)
+			.map(|c| c.fingerprint());
+
+		// printed all at once, before the browser is opened, for wrappers
+		// that read the URL from the output
+		for line in startup_output(scheme, &bound, fingerprint, &args) {
+			ctx.log.result(line);
+		}
+		if let (true, false, Some(addr)) = (args.open, args.no_open, bound.first()) {
+			open_in_browser(&ctx.log, &web_ui_url(scheme, browsable_addr(*addr), &args));
+		}
+
+		let reloader = cert
+			.as_ref()
+			.map(|c| reload_on_sighup(ctx.log.clone(), c.clone()));
+		let acceptor = cert.map(tls::make_acceptor);
+		let servers = listeners.into_iter().map(|listener| {
+			let make_svc = make_svc.clone();
+			let drain = drain.clone();
+			let shutdown = async move {
+				drain.started().await;
+			};
+
+			match &acceptor {
+				Some(acceptor) => {
+					let incoming = TlsIncoming::new(listener, acceptor.clone(), ctx.log.clone());
+					Server::builder(incoming)
+						.serve(make_service_fn(move |conn: &TlsStream<TcpStream>| {
+							make_svc(conn.get_ref().0.peer_addr().ok())
+						}))
+						.with_graceful_shutdown(shutdown)
+						.boxed()
+				}
+				None => async move {
+					Server::builder(AddrIncoming::from_listener(listener)?)
+						.serve(make_service_fn(move |conn: &AddrStream| {
+							make_svc(Some(conn.remote_addr()))
+						}))
+						.with_graceful_shutdown(shutdown)
+						.await
+				}
+				.boxed(),
 			}
-			None => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), args.port),
+		});
+
+		let results = futures::future::join_all(servers).await;
+		if let Some(r) = reloader {
+			r.abort();
+		}
+		for r in results {
+			r.map_err(CodeError::CouldNotListenOnInterface)?;
+		}
+	}
+
+	// servers are only stopped once clients are done with them
+	let _ = shutdown.await;
+	cm_for_shutdown.stop_servers().await;
+
+	Ok(0)
+}
+
+/// Resolves on Ctrl+C, or on SIGTERM on unix.
+async fn termination_signal() {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{signal, SignalKind};
+		if let Ok(mut sigterm) = signal(SignalKind::terminate()) {
+			tokio::select! {
+				_ = tokio::signal::ctrl_c() => {},
+				_ = sigterm.recv() => {},
+			}
+			return;
+		}
+	}
+
+	tokio::signal::ctrl_c().await.ok();
+}
+
+/// Starts draining connections on the first termination signal. Resolves
+/// once they've all closed or the drain timed out, or right away on a second
+/// signal.
+async fn drain_on_signal(log: log::Logger, drain: Drain) {
+	termination_signal().await;
+	drain.start();
+	info!(
+		log,
+		"Waiting for connections to close before exiting, press Ctrl+C again to exit now"
+	);
+
+	tokio::select! {
+		_ = termination_signal() => info!(log, "Exiting without waiting for connections to close"),
+		drained = drain.finished() => if !drained {
+			info!(log, "Connections were still open when the drain timed out, exiting");
+		},
+	}
+}
+
+/// Gets the addresses to listen on from the `--host`s given. `localhost` is
+/// both the IPv4 and IPv6 loopback, and IPv6 addresses may be in brackets.
+fn resolve_listen_addrs(hosts: &[String], port: u16) -> Result<Vec<SocketAddr>, CodeError> {
+	if hosts.is_empty() {
+		return Ok(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)]);
+	}
+
+	let mut addrs = Vec::with_capacity(hosts.len());
+	for host in hosts {
+		let ips = if host.eq_ignore_ascii_case("localhost") {
+			vec![
+				IpAddr::V4(Ipv4Addr::LOCALHOST),
+				IpAddr::V6(Ipv6Addr::LOCALHOST),
+			]
+		} else {
+			let ip = host
+				.trim_start_matches('[')
+				.trim_end_matches(']')
+				.parse()
+				.map_err(CodeError::InvalidHostAddress)?;
+			vec![ip]
 		};
-		let builder = Server::try_bind(&addr).map_err(CodeError::CouldNotListenOnInterface)?;
 
-		let mut listening = format!("Web UI available at http://{}", addr);
-		if let Some(base) = args.server_base_path {
-			if !base.starts_with('/') {
-				listening.push('/');
+		for ip in ips {
+			let addr = SocketAddr::new(ip, port);
+			if !addrs.contains(&addr) {
+				addrs.push(addr);
 			}
-			listening.push_str(&base);
 		}
-		if let Some(ct) = args.connection_token {
-			listening.push_str(&format!("?tkn={}", ct));
+	}
+
+	Ok(addrs)
+}
+
+/// Binds a listener to each address. Addresses that can't be bound are warned
+/// about, unless none of them can. When any free port is asked for, the port
+/// picked for the first listener is used for the rest, so they share a port.
+async fn bind_listeners(
+	log: &log::Logger,
+	addrs: &[SocketAddr],
+) -> Result<Vec<TcpListener>, CodeError> {
+	let mut listeners: Vec<TcpListener> = Vec::with_capacity(addrs.len());
+	let mut failures = vec![];
+	for addr in addrs {
+		let mut addr = *addr;
+		if addr.port() == 0 {
+			if let Some(port) = listeners
+				.first()
+				.and_then(|l| l.local_addr().ok())
+				.map(|a| a.port())
+			{
+				addr.set_port(port);
+			}
 		}
-		ctx.log.result(listening);
 
-		builder
-			.serve(make_service_fn(|_| make_svc()))
-			.with_graceful_shutdown(async {
-				let _ = shutdown.wait().await;
-			})
-			.await
+		match TcpListener::bind(addr).await {
+			Ok(l) => listeners.push(l),
+			Err(e) => failures.push(CodeError::CouldNotBindAddress(addr, e)),
+		}
+	}
+
+	if listeners.is_empty() {
+		if let Some(e) = failures.pop() {
+			return Err(e);
+		}
+	}
+
+	for e in failures {
+		warning!(log, "{}", e);
+	}
+
+	Ok(listeners)
+}
+
+/// Gets the URL of the web UI on the address, with the connection token.
+fn web_ui_url(scheme: &str, addr: SocketAddr, args: &ServeWebArgs) -> String {
+	let mut url = format!("{}://{}", scheme, addr);
+	match &args.server_base_path {
+		Some(base) => url.push_str(base),
+		None => url.push('/'),
+	}
+	if let Some(ct) = &args.connection_token {
+		url.push_str(&format!("?tkn={}", ct));
+	}
+	url
+}
+
+/// Unspecified addresses can't be browsed to, but their loopback can.
+fn browsable_addr(addr: SocketAddr) -> SocketAddr {
+	match addr.ip() {
+		IpAddr::V4(ip) if ip.is_unspecified() => {
+			SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+		}
+		IpAddr::V6(ip) if ip.is_unspecified() => {
+			SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+		}
+		_ => addr,
+	}
+}
+
+#[derive(Serialize, Default)]
+struct StartedOutput {
+	/// URL to open the web UI at
+	#[serde(skip_serializing_if = "Option::is_none")]
+	url: Option<String>,
+	/// URLs for each address that's listened on
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	urls: Vec<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	socket_path: Option<String>,
+	/// SHA-256 fingerprint of the self-signed certificate
+	#[serde(skip_serializing_if = "Option::is_none")]
+	certificate_fingerprint: Option<String>,
+}
+
+/// Gets the lines to print once listening on the addresses. With `--json`,
+/// that's a single line with a JSON object.
+fn startup_output(
+	scheme: &str,
+	bound: &[SocketAddr],
+	fingerprint: Option<String>,
+	args: &ServeWebArgs,
+) -> Vec<String> {
+	let urls: Vec<String> = bound.iter().map(|a| web_ui_url(scheme, *a, args)).collect();
+
+	if args.json {
+		let output = StartedOutput {
+			url: bound
+				.first()
+				.map(|a| web_ui_url(scheme, browsable_addr(*a), args)),
+			urls,
+			socket_path: None,
+			certificate_fingerprint: fingerprint,
+		};
+		return vec![to_json(&output)];
+	}
+
+	let mut lines = vec![];
+	if let Some(f) = fingerprint {
+		lines.push(format!(
+			"Using a self-signed certificate, its SHA-256 fingerprint is {}",
+			f
+		));
+	}
+	lines.extend(urls.iter().map(|u| format!("Web UI available at {}", u)));
+	lines
+}
+
+fn open_in_browser(log: &log::Logger, url: &str) {
+	if !browser::has_display() {
+		info!(
+			log,
+			"No display is available, open the URL in a browser to continue"
+		);
+		return;
+	}
+
+	let log = log.clone();
+	let url = url.to_string();
+	tokio::spawn(async move {
+		if let Err(e) = browser::open(&url).await {
+			warning!(log, "{}, open the URL in a browser to continue", e);
+		}
+	});
+}
+
+/// Gets the certificate to serve over HTTPS with, if one was asked for.
+fn get_tls_certificate(
+	ctx: &CommandContext,
+	args: &ServeWebArgs,
+) -> Result<Option<Arc<ReloadableCertificate>>, CodeError> {
+	let paths = if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+		CertificatePaths {
+			cert: cert.clone(),
+			key: key.clone(),
+		}
+	} else if args.self_signed {
+		let mut hosts = vec![
+			"localhost".to_string(),
+			"127.0.0.1".to_string(),
+			"::1".to_string(),
+		];
+		for h in args.host.iter().rev() {
+			let h = h.trim_start_matches('[').trim_end_matches(']');
+			if !hosts.iter().any(|e| e == h) {
+				hosts.insert(0, h.to_string());
+			}
+		}
+		tls::ensure_self_signed(&ctx.paths.root().join("serve-web-tls"), &hosts)?
+	} else {
+		return Ok(None);
 	};
 
-	r.map_err(CodeError::CouldNotListenOnInterface)?;
+	Ok(Some(ReloadableCertificate::load(paths)?))
+}
 
-	Ok(0)
+/// Reloads the certificate from disk whenever the process gets a SIGHUP.
+#[cfg(unix)]
+fn reload_on_sighup(
+	log: log::Logger,
+	cert: Arc<ReloadableCertificate>,
+) -> tokio::task::JoinHandle<()> {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	tokio::spawn(async move {
+		let mut hangup = match signal(SignalKind::hangup()) {
+			Ok(s) => s,
+			Err(e) => {
+				warning!(
+					log,
+					"Could not listen for SIGHUP to reload the certificate: {}",
+					e
+				);
+				return;
+			}
+		};
+
+		while hangup.recv().await.is_some() {
+			match cert.reload() {
+				Ok(()) => info!(
+					log,
+					"Reloaded the TLS certificate, its SHA-256 fingerprint is {}",
+					cert.fingerprint()
+				),
+				Err(e) => warning!(log, "{}, keeping the previous certificate", e),
+			}
+		}
+	})
+}
+
+#[cfg(not(unix))]
+fn reload_on_sighup(
+	_log: log::Logger,
+	_cert: Arc<ReloadableCertificate>,
+) -> tokio::task::JoinHandle<()> {
+	tokio::spawn(async {})
 }
 
 #[derive(Clone)]
@@ -145,32 +532,179 @@ struct HandleContext {
 	cm: Arc<ConnectionManager>,
 	log: log::Logger,
 	server_secret_key: SecretKeyPart,
+	access_log: Option<AccessLog>,
+	/// Address of the connected client, if it's not over a socket file.
+	peer: Option<SocketAddr>,
 }
 
 /// Handler function for an inbound request
 async fn handle(ctx: HandleContext, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-	let client_key_half = get_client_key_half(&req);
-	let path = req.uri().path();
+	let access = ctx.access_log.as_ref().map(|log| {
+		let info = access_log::RequestInfo::new(ctx.peer, &req, ctx.cm.args.trusted_proxy);
+		(log, info)
+	});
+
+	let res = handle_request(&ctx, req).await;
+	Ok(match access {
+		Some((log, info)) => log.record(info, res),
+		None => res,
+	})
+}
 
-	let mut res = if path.starts_with(&ctx.cm.base_path)
-		&& path.get(ctx.cm.base_path.len()..).unwrap_or_default() == SECRET_KEY_MINT_PATH
-	{
-		handle_secret_mint(&ctx, req)
+async fn handle_request(ctx: &HandleContext, req: Request<Body>) -> Response<Body> {
+	let route = route(&ctx.cm.base_path, req.uri().path());
+	match route {
+		Route::Redirect(mut location) => {
+			if let Some(q) = req.uri().query() {
+				location.push('?');
+				location.push_str(q);
+			}
+			return response::redirect(&location);
+		}
+		Route::NotFound => return response::not_found(),
+		// health checks usually come from a proxy which doesn't have the token
+		Route::Health => return response::health(),
+		Route::MintKey | Route::Proxied => {}
+	}
+
+	let token_check = match &ctx.cm.args.connection_token {
+		Some(token) => check_connection_token(&req, token),
+		None => TokenCheck::Allowed,
+	};
+	if token_check == TokenCheck::Denied {
+		return response::forbidden();
+	}
+
+	let client_key_half = get_client_key_half(&req);
+	let mut res = if route == Route::MintKey {
+		handle_secret_mint(ctx, req)
 	} else {
-		handle_proxied(&ctx, req).await
+		handle_proxied(ctx, req).await
 	};
 
 	append_secret_headers(&ctx.cm.base_path, &mut res, &client_key_half);
+	if let (TokenCheck::AllowedFromQuery, Some(token)) =
+		(token_check, &ctx.cm.args.connection_token)
+	{
+		append_token_cookie(&mut res, token);
+	}
 
-	Ok(res)
+	res
 }
 
-async fn handle_proxied(ctx: &HandleContext, req: Request<Body>) -> Response<Body> {
-	let release = if let Some((r, _)) = get_release_from_path(req.uri().path(), ctx.cm.platform) {
-		r
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+	/// The base path without its trailing slash, which is redirected to the
+	/// canonical form given here.
+	Redirect(String),
+	/// The path is outside of the base path.
+	NotFound,
+	Health,
+	MintKey,
+	/// Forwarded to a VS Code server, which is also given the base path.
+	Proxied,
+}
+
+/// Routes the request path. The `base_path` is normalized and so always
+/// starts and ends with a `/`.
+fn route(base_path: &str, path: &str) -> Route {
+	let rest = match path.strip_prefix(base_path) {
+		Some(r) => r,
+		None if base_path.len() > 1 && path == &base_path[..base_path.len() - 1] => {
+			return Route::Redirect(base_path.to_string())
+		}
+		None => return Route::NotFound,
+	};
+
+	match rest {
+		SECRET_KEY_MINT_PATH => Route::MintKey,
+		HEALTH_PATH => Route::Health,
+		_ => Route::Proxied,
+	}
+}
+
+/// Gets the path of a proxied request relative to the base path, keeping its
+/// leading `/`.
+fn path_within_base<'a>(base_path: &str, path: &'a str) -> &'a str {
+	path.get(base_path.len() - 1..).unwrap_or("/")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenCheck {
+	Allowed,
+	/// The token was given in the query, and should be set as a cookie so
+	/// that later requests, such as the workbench's websockets, carry it.
+	AllowedFromQuery,
+	Denied,
+}
+
+/// Checks that the request has the connection token in either its query or
+/// its cookies.
+fn check_connection_token(req: &Request<Body>, token: &str) -> TokenCheck {
+	if let Some(c) = extract_cookie(req, CONNECTION_TOKEN_COOKIE_NAME) {
+		if constant_time_eq(c.as_bytes(), token.as_bytes()) {
+			return TokenCheck::Allowed;
+		}
+	}
+
+	let in_query = req.uri().query().into_iter().flat_map(|q| {
+		url::form_urlencoded::parse(q.as_bytes())
+			.filter(|(k, _)| k == CONNECTION_TOKEN_QUERY_PARAM)
+			.map(|(_, v)| v.into_owned())
+	});
+	for t in in_query {
+		if constant_time_eq(t.as_bytes(), token.as_bytes()) {
+			return TokenCheck::AllowedFromQuery;
+		}
+	}
+
+	TokenCheck::Denied
+}
+
+fn append_token_cookie(res: &mut Response<Body>, token: &str) {
+	res.headers_mut().append(
+		hyper::header::SET_COOKIE,
+		format!(
+			"{}={}; SameSite=Lax; HttpOnly; Max-Age=604800; Path=/",
+			CONNECTION_TOKEN_COOKIE_NAME, token
+		)
+		.parse()
+		.unwrap(),
+	);
+}
+
+async fn handle_proxied(ctx: &HandleContext, mut req: Request<Body>) -> Response<Body> {
+	let encoding = if compression::bypasses_compression(&req) {
+		None
+	} else {
+		Some(compression::negotiate(req.headers()))
+	};
+
+	let conditional = caching::Conditional::from_request(req.headers());
+	let path = path_within_base(&ctx.cm.base_path, req.uri().path());
+	let (release, immutable_etag) = if let Some((r, rest)) =
+		get_release_from_path(path, ctx.cm.platform)
+	{
+		let etag = encoding
+			.filter(|_| caching::is_static_asset(&rest))
+			.map(|e| caching::commit_etag(&r.commit, &rest, e));
+		if let Some(etag) = &etag {
+			if conditional.has_etag(etag) {
+				return caching::not_modified_immutable(etag);
+			}
+		}
+
+		if let (Some(encoding), Some(asset)) = (encoding, rest.strip_prefix("/static/")) {
+			if let Some(dir) = ctx.cm.cache.exists(&r.commit) {
+				if let Some(res) = compression::serve_precompressed(&dir, asset, encoding).await {
+					return caching::apply(&conditional, etag.as_deref(), res).await;
+				}
+			}
+		}
+		(r, etag)
 	} else {
 		match ctx.cm.get_latest_release().await {
-			Ok(r) => r,
+			Ok(r) => (r, None),
 			Err(e) => {
 				error!(ctx.log, "error getting latest version: {}", e);
 				return response::code_err(e);
@@ -178,19 +712,86 @@ async fn handle_proxied(ctx: &HandleContext, req: Request<Body>) -> Response<Bod
 		}
 	};
 
-	match ctx.cm.get_connection(release).await {
-		Ok(rw) => {
-			if req.headers().contains_key(hyper::header::UPGRADE) {
-				forward_ws_req_to_server(ctx.log.clone(), rw, req).await
-			} else {
-				forward_http_req_to_server(rw, req).await
+	match ctx.cm.get_connection_or_substitute(release.clone()).await {
+		Ok((served, rw)) => {
+			// a substitute's content can't be cached as that of the requested commit
+			let substituted = served.commit != release.commit;
+			let immutable_etag = immutable_etag.filter(|_| !substituted);
+			if substituted {
+				rewrite_commit_path(&mut req, &ctx.cm.base_path, &release.commit, &served.commit);
+			}
+
+			let res =
+				forward_req_to_server(ctx.log.clone(), rw, req, ctx.cm.websocket_options()).await;
+			let mut res = match encoding {
+				Some(e) => {
+					let res = compression::compress_response(e, res);
+					caching::apply(&conditional, immutable_etag.as_deref(), res).await
+				}
+				None => res,
+			};
+			if substituted {
+				res.headers_mut().insert(
+					SUBSTITUTE_COMMIT_HEADER,
+					hyper::header::HeaderValue::from_str(&served.commit).unwrap(),
+				);
 			}
+			res
 		}
 		Err(CodeError::ServerNotYetDownloaded) => response::wait_for_download(),
 		Err(e) => response::code_err(e),
 	}
 }
 
+/// Points a request under the `from` commit's path to the same path under the
+/// `to` commit, since each server only serves its own commit's path.
+fn rewrite_commit_path(req: &mut Request<Body>, base_path: &str, from: &str, to: &str) {
+	let path = req.uri().path();
+	let rest = match path.get(base_path.len()..) {
+		Some(r) => r,
+		None => return,
+	};
+	let prefix_len = rest.find('/').unwrap_or(rest.len());
+	let quality = match rest[..prefix_len].strip_suffix(from) {
+		Some(q) => q,
+		None => return,
+	};
+
+	let mut rewritten = format!("{}{}{}{}", base_path, quality, to, &rest[prefix_len..]);
+	if let Some(q) = req.uri().query() {
+		rewritten.push('?');
+		rewritten.push_str(q);
+	}
+	if let Ok(uri) = rewritten.parse() {
+		*req.uri_mut() = uri;
+	}
+}
+
+/// How websockets are proxied to the server.
+#[derive(Clone)]
+struct WebsocketOptions {
+	/// Interval to ping clients at, if any.
+	keepalive: Option<Duration>,
+	/// Drain the websockets are tracked in. New ones are refused once it starts,
+	/// and open ones are asked to close.
+	drain: Drain,
+}
+
+/// Proxies the request, which keeps its full path since the server is also
+/// given the base path, to the server on the other end of the pipe.
+async fn forward_req_to_server(
+	log: log::Logger,
+	rw: (AsyncPipe, ConnectionHandle),
+	req: Request<Body>,
+	ws: WebsocketOptions,
+) -> Response<Body> {
+	if req.headers().contains_key(hyper::header::UPGRADE) {
+		forward_ws_req_to_server(log, rw, req, ws).await
+	} else {
+		forward_http_req_to_server(rw, req).await
+	}
+}
+
 fn handle_secret_mint(ctx: &HandleContext, req: Request<Body>) -> Response<Body> {
 	use sha2::{Digest, Sha256};
 
@@ -245,6 +846,7 @@ fn get_release_from_path(path: &str, platform: Platform) -> Option<(Release, Str
 
 	let (quality_commit, remaining) = path.split_at(i);
 	let (quality, commit) = quality_commit.split_at(quality_commit_sep);
+	let commit = &commit[1..]; // skip the '-' separating them
 
 	if !is_commit_hash(commit) {
 		return None;
@@ -295,7 +897,13 @@ async fn forward_ws_req_to_server(
 	log: log::Logger,
 	(rw, handle): (AsyncPipe, ConnectionHandle),
 	mut req: Request<Body>,
+	ws: WebsocketOptions,
 ) -> Response<Body> {
+	let client = match ws.drain.admit() {
+		Some(c) => c,
+		None => return response::shutting_down(),
+	};
+
 	// splicing of client and servers inspired by https://github.com/hyperium/hyper/blob/fece9f7f50431cf9533cfe7106b53a77b48db699/examples/upgrades.rs
 	let (mut request_sender, connection) =
 		match hyper::client::conn::Builder::new().handshake(rw).await {
@@ -323,6 +931,11 @@ async fn forward_ws_req_to_server(
 
 	// only start upgrade at this point in case the server decides to deny socket
 	if res.status() == hyper::StatusCode::SWITCHING_PROTOCOLS {
+		let (closed, close) = new_barrier();
+		proxied_res
+			.extensions_mut()
+			.insert(websocket::Closed(closed));
+
 		tokio::spawn(async move {
 			let (s_req, s_res) =
 				tokio::join!(hyper::upgrade::on(&mut req), hyper::upgrade::on(&mut res));
@@ -334,14 +947,32 @@ async fn forward_ws_req_to_server(
 				),
 				(Err(e1), _) => debug!(log, "client ({}) websocket upgrade failed", e1),
 				(_, Err(e2)) => debug!(log, "server ({}) websocket upgrade failed", e2),
-				(Ok(mut s_req), Ok(mut s_res)) => {
-					trace!(log, "websocket upgrade succeeded");
-					let r = tokio::io::copy_bidirectional(&mut s_req, &mut s_res).await;
-					trace!(log, "websocket closed (error: {:?})", r.err());
+				(Ok(s_req), Ok(s_res)) => {
+					debug!(log, "websocket opened");
+					let opened = Instant::now();
+					let going_away = async {
+						ws.drain.started().await;
+					};
+					match websocket::splice(s_req, s_res, ws.keepalive, going_away).await {
+						Ok(end) => debug!(
+							log,
+							"websocket {} after {}s",
+							end,
+							opened.elapsed().as_secs()
+						),
+						Err(e) => debug!(
+							log,
+							"websocket closed after {}s: {}",
+							opened.elapsed().as_secs(),
+							e
+						),
+					}
 				}
 			}
 
 			drop(handle);
+			drop(client);
+			close.open(());
 		});
 	}
 
@@ -454,6 +1085,45 @@ mod response {
 			.unwrap()
 	}
 
+	pub fn redirect(location: &str) -> Response<Body> {
+		Response::builder()
+			.status(308)
+			.header(hyper::header::LOCATION, location)
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	pub fn shutting_down() -> Response<Body> {
+		Response::builder()
+			.status(503)
+			.body(Body::from("The server is shutting down"))
+			.unwrap()
+	}
+
+	pub fn not_found() -> Response<Body> {
+		Response::builder()
+			.status(404)
+			.body(Body::from("Not found"))
+			.unwrap()
+	}
+
+	pub fn health() -> Response<Body> {
+		Response::builder()
+			.status(200)
+			.header("Content-Type", "application/json")
+			.body(Body::from(r#"{"status":"ok"}"#))
+			.unwrap()
+	}
+
+	pub fn forbidden() -> Response<Body> {
+		Response::builder()
+			.status(403)
+			.body(Body::from(
+				"Forbidden. Open the web UI using the URL that includes the connection token.",
+			))
+			.unwrap()
+	}
+
 	pub fn secret_key(hash: Vec<u8>) -> Response<Body> {
 		Response::builder()
 			.status(200)
@@ -512,12 +1182,94 @@ struct ConnectionManager {
 	update_service: UpdateService,
 	/// Cache of the latest released version, storing the time we checked as well
 	latest_version: tokio::sync::Mutex<Option<(Instant, Release)>>,
+	/// Versions that failed to download, and when they did
+	unavailable: Arc<Mutex<HashMap<(Quality, String), Instant>>>,
+	/// Drain that websocket clients are tracked in
+	drain: Drain,
+	/// Running servers, which are tracked until their process exits
+	running: ActivityTracker,
+	/// Opened to stop all running servers
+	stopped: Barrier<()>,
+	stop: BarrierOpener<()>,
+	/// Whether to skip disk space and memory checks before downloads
+	ignore_preflight: bool,
 }
 
 fn key_for_release(release: &Release) -> (Quality, String) {
 	(release.quality, release.commit.clone())
 }
 
+/// A server build in the download cache.
+struct CachedBuild {
+	commit: String,
+	quality: Option<Quality>,
+	/// Build date from the product.json, an ISO 8601 string.
+	date: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct CachedProductJson {
+	quality: Option<String>,
+	date: Option<String>,
+}
+
+fn list_cached_builds(cache_dir: &Path) -> Vec<CachedBuild> {
+	let entries = match fs::read_dir(cache_dir) {
+		Ok(e) => e,
+		Err(_) => return vec![],
+	};
+
+	entries
+		.filter_map(|e| e.ok())
+		.filter_map(|e| {
+			let commit = e.file_name().to_string_lossy().to_string();
+			if !is_commit_hash(&commit) {
+				return None; // also skips staging directories
+			}
+
+			let product = fs::read(e.path().join("product.json"))
+				.ok()
+				.and_then(|b| serde_json::from_slice::<CachedProductJson>(&b).ok());
+			Some(CachedBuild {
+				commit,
+				quality: product
+					.as_ref()
+					.and_then(|p| p.quality.as_deref())
+					.and_then(|q| Quality::try_from(q).ok()),
+				date: product.and_then(|p| p.date),
+			})
+		})
+		.collect()
+}
+
+/// Picks the build to serve instead of one that can't be downloaded: the
+/// newest cached build of the same quality, otherwise the latest release.
+fn choose_substitute(
+	requested: &Release,
+	cached: &[CachedBuild],
+	latest: Option<&Release>,
+	is_unavailable: impl Fn(&(Quality, String)) -> bool,
+) -> Option<Release> {
+	let usable = |quality: Quality, commit: &str| {
+		quality == requested.quality
+			&& commit != requested.commit
+			&& !is_unavailable(&(quality, commit.to_string()))
+	};
+
+	let newest_cached = cached
+		.iter()
+		.filter(|b| b.quality.map(|q| usable(q, &b.commit)).unwrap_or(false))
+		.max_by(|a, b| a.date.cmp(&b.date));
+	if let Some(b) = newest_cached {
+		return Some(Release {
+			commit: b.commit.clone(),
+			..requested.clone()
+		});
+	}
+
+	latest.filter(|l| usable(l.quality, &l.commit)).cloned()
+}
+
 fn normalize_base_path(p: &str) -> String {
 	let p = p.trim_matches('/');
 
@@ -530,23 +1282,80 @@ fn normalize_base_path(p: &str) -> String {
 
 impl ConnectionManager {
 	pub fn new(ctx: &CommandContext, platform: Platform, args: ServeWebArgs) -> Arc<Self> {
+		Self::with_services(
+			ctx.log.clone(),
+			platform,
+			args,
+			DownloadCache::new(ctx.paths.web_server_storage()),
+			UpdateService::new(
+				ctx.log.clone(),
+				Arc::new(ReqwestSimpleHttp::with_client(ctx.http.clone())),
+			),
+			ctx.args.global_options.ignore_preflight,
+		)
+	}
+
+	fn with_services(
+		log: log::Logger,
+		platform: Platform,
+		args: ServeWebArgs,
+		cache: DownloadCache,
+		update_service: UpdateService,
+		ignore_preflight: bool,
+	) -> Arc<Self> {
 		let base_path = normalize_base_path(args.server_base_path.as_deref().unwrap_or_default());
+		let drain = Drain::new(
+			args.drain_timeout
+				.map(Duration::from_secs)
+				.unwrap_or(DEFAULT_DRAIN_TIMEOUT),
+		);
+		let (stopped, stop) = new_barrier();
 
 		Arc::new(Self {
 			platform,
 			args,
 			base_path,
-			log: ctx.log.clone(),
-			cache: DownloadCache::new(ctx.paths.web_server_storage()),
-			update_service: UpdateService::new(
-				ctx.log.clone(),
-				Arc::new(ReqwestSimpleHttp::with_client(ctx.http.clone())),
-			),
+			log,
+			cache,
+			update_service,
 			state: ConnectionStateMap::default(),
 			latest_version: tokio::sync::Mutex::default(),
+			unavailable: Arc::default(),
+			drain,
+			running: ActivityTracker::default(),
+			stopped,
+			stop,
+			ignore_preflight,
 		})
 	}
 
+	pub fn websocket_options(&self) -> WebsocketOptions {
+		WebsocketOptions {
+			keepalive: match self.args.ws_keepalive {
+				0 => None,
+				secs => Some(Duration::from_secs(secs)),
+			},
+			drain: self.drain.clone(),
+		}
+	}
+
+	/// Stops running servers, resolving once they've exited or the stop
+	/// timed out.
+	pub async fn stop_servers(&self) {
+		self.stop.open(());
+		let timeout = Duration::from_secs(SERVER_STOP_TIMEOUT_SECS);
+		if tokio::time::timeout(timeout, self.running.wait_inactive())
+			.await
+			.is_err()
+		{
+			warning!(
+				self.log,
+				"servers did not stop within {}s, exiting anyway",
+				SERVER_STOP_TIMEOUT_SECS
+			);
+		}
+	}
+
 	/// Gets a connection to a server version
 	pub async fn get_connection(
 		&self,
@@ -560,6 +1369,65 @@ impl ConnectionManager {
 		Ok((rw, handle))
 	}
 
+	/// Gets a connection to a server for the release. If the release can't be
+	/// downloaded, a substitute build is connected to instead, unless
+	/// `--strict-commit` was given. Returns the release that was connected to.
+	pub async fn get_connection_or_substitute(
+		&self,
+		release: Release,
+	) -> Result<(Release, (AsyncPipe, ConnectionHandle)), CodeError> {
+		if self.args.strict_commit {
+			return self
+				.get_connection(release.clone())
+				.await
+				.map(|c| (release, c));
+		}
+
+		let key = key_for_release(&release);
+		if !self.is_unavailable(&key) {
+			match self.get_connection(release.clone()).await {
+				Err(CodeError::ServerDownloadError(_)) => {
+					self.unavailable.lock().unwrap().insert(key, Instant::now());
+				}
+				r => return r.map(|c| (release, c)),
+			}
+		}
+
+		let latest = self.get_latest_release().await.ok();
+		let cached = list_cached_builds(self.cache.path());
+		let substitute = choose_substitute(&release, &cached, latest.as_ref(), |k| {
+			self.is_unavailable(k)
+		})
+		.ok_or_else(|| {
+			CodeError::ServerDownloadError(format!(
+				"server {} could not be downloaded, and there is no other build to use instead",
+				release.commit
+			))
+		})?;
+
+		warning!(
+			self.log,
+			"Server {} is unavailable, serving {} instead",
+			release.commit,
+			substitute.commit
+		);
+		let c = self.get_connection(substitute.clone()).await?;
+		Ok((substitute, c))
+	}
+
+	/// Gets whether the release failed to download recently.
+	fn is_unavailable(&self, key: &(Quality, String)) -> bool {
+		let mut unavailable = self.unavailable.lock().unwrap();
+		match unavailable.get(key) {
+			Some(t) if t.elapsed() < Duration::from_secs(UNAVAILABLE_RETRY_SECS) => true,
+			Some(_) => {
+				unavailable.remove(key);
+				false
+			}
+			None => false,
+		}
+	}
+
 	/// Gets the latest release for the CLI quality, caching its result for some
 	/// time to allow for fast loads.
 	pub async fn get_latest_release(&self) -> Result<Release, CodeError> {
@@ -629,9 +1497,12 @@ impl ConnectionManager {
 		let state_map_dup = self.state.clone();
 		let args = StartArgs {
 			args: self.args.clone(),
-			log: self.log.clone(),
+			log: self.log.with_field("commit", &release.commit),
 			opener,
 			release,
+			running: self.running.clone(),
+			stopped: self.stopped.clone(),
+			ignore_preflight: self.ignore_preflight,
 		};
 
 		if let Some(p) = self.cache.exists(&args.release.commit) {
@@ -658,48 +1529,77 @@ impl ConnectionManager {
 			);
 			let update_service = self.update_service.clone();
 			let cache = self.cache.clone();
+			let unavailable = self.unavailable.clone();
 			tokio::spawn(async move {
-				Self::download_version(args, update_service.clone(), cache.clone()).await;
+				if !Self::download_version(args, update_service.clone(), cache.clone()).await {
+					unavailable
+						.lock()
+						.unwrap()
+						.insert(key.clone(), Instant::now());
+				}
 				state_map_dup.lock().unwrap().remove(&key);
 			});
 			Err(CodeError::ServerNotYetDownloaded)
 		}
 	}
 
-	/// Downloads a server version into the cache and starts it.
+	/// Downloads a server version into the cache and starts it. Returns false
+	/// if it could not be downloaded.
 	async fn download_version(
 		args: StartArgs,
 		update_service: UpdateService,
 		cache: DownloadCache,
-	) {
+	) -> bool {
 		let release_for_fut = args.release.clone();
 		let log_for_fut = args.log.clone();
+		let ignore_preflight = args.ignore_preflight;
 		let dir_fut = cache.create(&args.release.commit, |target_dir| async move {
 			info!(log_for_fut, "Downloading server {}", release_for_fut.commit);
 			let tmpdir = tempfile::tempdir().unwrap();
 			let response = update_service.get_download_stream(&release_for_fut).await?;
 
+			if !ignore_preflight {
+				let needs = PreflightNeeds::for_download(tmpdir.path(), &target_dir, &response);
+				for w in preflight::check(&SystemProbe, &needs)? {
+					warning!(log_for_fut, "{}", w);
+				}
+			}
+
 			let name = response.url_path_basename().unwrap();
 			let archive_path = tmpdir.path().join(name);
 			http::download_into_file(
 				&archive_path,
-				log_for_fut.get_download_logger("Downloading server:"),
+				progress::reporter(&log_for_fut, "Downloading server"),
 				response,
 			)
 			.await?;
-			unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress())?;
+			unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress()).await?;
 			Ok(())
 		});
 
 		match dir_fut.await {
-			Err(e) => args.opener.open(Err(e.to_string())),
-			Ok(dir) => Self::start_version(args, dir).await,
+			Err(e) => {
+				warning!(
+					args.log,
+					"Error downloading server {}: {}",
+					args.release.commit,
+					e
+				);
+				args.opener.open(Err(e.to_string()));
+				false
+			}
+			Ok(dir) => {
+				Self::start_version(args, dir).await;
+				true
+			}
 		}
 	}
 
 	/// Starts a downloaded server that can be found in the given `path`.
 	async fn start_version(args: StartArgs, path: PathBuf) {
 		info!(args.log, "Starting server {}", args.release.commit);
+		let _running = args.running.start();
+		let mut stopped = args.stopped;
 
 		let executable = path
 			.join("bin")
@@ -718,8 +1618,9 @@ impl ConnectionManager {
 		cmd.args(["--accept-server-license-terms"]);
 
 		if let Some(a) = &args.args.server_base_path {
+			// normalized when starting, given here without the trailing slash
 			cmd.arg("--server-base-path");
-			cmd.arg(a);
+			cmd.arg(a.trim_end_matches('/'));
 		}
 		if let Some(a) = &args.args.server_data_dir {
 			cmd.arg("--server-data-dir");
@@ -798,6 +1699,11 @@ impl ConnectionManager {
 					let _ = child.kill().await;
 					break;
 				}
+				_ = stopped.wait() => {
+					info!(args.log, "[{} process]: shutting down, ending", commit_prefix);
+					let _ = child.kill().await;
+					break;
+				}
 				e = child.wait() => {
 					info!(args.log, "[{} process]: exited: {:?}", commit_prefix, e);
 					break;
@@ -812,6 +1718,28 @@ struct StartArgs {
 	args: ServeWebArgs,
 	release: Release,
 	opener: BarrierOpener<Result<StartData, String>>,
+	running: ActivityTracker,
+	stopped: Barrier<()>,
+	ignore_preflight: bool,
+}
+
+/// Tokens are also accepted by the VS Code server, which only allows these
+/// characters, and are safe to put in URLs and cookies as-is.
+fn is_valid_connection_token(token: &str) -> bool {
+	!token.is_empty()
+		&& token
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn read_connection_token_file(path: &Path) -> Result<String, CodeError> {
+	let token = fs::read_to_string(path).map_err(CodeError::CouldNotReadConnectionTokenFile)?;
+	let token = token.trim();
+	if !is_valid_connection_token(token) {
+		return Err(CodeError::InvalidConnectionToken);
+	}
+
+	Ok(token.to_string())
 }
 
 fn mint_connection_token(path: &Path, prefer_token: Option<String>) -> std::io::Result<String> {
@@ -840,3 +1768,767 @@ fn mint_connection_token(path: &Path, prefer_token: Option<String>) -> std::io::
 	f.write_all(prefer_token.as_bytes())?;
 	Ok(prefer_token)
 }
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	use super::*;
+
+	const TOKEN: &str = "abc-123";
+	const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+	fn request(uri: &str, cookie: Option<&str>) -> Request<Body> {
+		let mut req = Request::builder().uri(uri);
+		if let Some(c) = cookie {
+			req = req.header(hyper::header::COOKIE, c);
+		}
+		req.body(Body::empty()).unwrap()
+	}
+
+	fn websocket_request(uri: &str, cookie: Option<&str>) -> Request<Body> {
+		let mut req = request(uri, cookie);
+		let headers = req.headers_mut();
+		headers.insert(hyper::header::CONNECTION, "Upgrade".parse().unwrap());
+		headers.insert(hyper::header::UPGRADE, "websocket".parse().unwrap());
+		req
+	}
+
+	#[test]
+	fn test_token_allowed() {
+		assert_eq!(
+			check_connection_token(&request("/?tkn=abc-123", None), TOKEN),
+			TokenCheck::AllowedFromQuery
+		);
+		assert_eq!(
+			check_connection_token(&request("/a/b?folder=%2Fx&tkn=abc%2D123", None), TOKEN),
+			TokenCheck::AllowedFromQuery
+		);
+		assert_eq!(
+			check_connection_token(&request("/", Some("other=1; vscode-tkn=abc-123")), TOKEN),
+			TokenCheck::Allowed
+		);
+	}
+
+	#[test]
+	fn test_token_missing_or_wrong() {
+		assert_eq!(
+			check_connection_token(&request("/", None), TOKEN),
+			TokenCheck::Denied
+		);
+		assert_eq!(
+			check_connection_token(&request("/?tkn=abc-124", None), TOKEN),
+			TokenCheck::Denied
+		);
+		assert_eq!(
+			check_connection_token(&request("/?tkn=abc-1234", None), TOKEN),
+			TokenCheck::Denied
+		);
+		assert_eq!(
+			check_connection_token(&request("/?token=abc-123", Some("vscode-tkn=nope")), TOKEN),
+			TokenCheck::Denied
+		);
+	}
+
+	#[tokio::test]
+	async fn test_token_websocket() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(serve_web_args(&[]), start_fake_server().await, dir.path());
+
+		// the workbench's websockets carry the cookie set on the first load
+		let path = format!("/stable-{}/?reconnectionToken=x", COMMIT);
+		let req = websocket_request(&path, Some("vscode-tkn=abc-123"));
+		assert_eq!(handle_request(&ctx, req).await.status(), 101);
+
+		for cookie in [None, Some("vscode-tkn=wrong")] {
+			let req = websocket_request(&path, cookie);
+			assert_eq!(handle_request(&ctx, req).await.status(), 403);
+		}
+	}
+
+	#[test]
+	fn test_token_cookie() {
+		let mut res = Response::new(Body::empty());
+		append_token_cookie(&mut res, TOKEN);
+		let cookie = res.headers()[hyper::header::SET_COOKIE].to_str().unwrap();
+		assert!(cookie.starts_with("vscode-tkn=abc-123;"));
+		assert!(cookie.contains("HttpOnly"));
+	}
+
+	#[test]
+	fn test_normalize_base_path() {
+		assert_eq!(normalize_base_path(""), "/");
+		assert_eq!(normalize_base_path("/"), "/");
+		assert_eq!(normalize_base_path("vscode"), "/vscode/");
+		assert_eq!(normalize_base_path("/vscode/"), "/vscode/");
+		assert_eq!(normalize_base_path("/a/b"), "/a/b/");
+	}
+
+	#[test]
+	fn test_route() {
+		assert_eq!(route("/", "/"), Route::Proxied);
+		assert_eq!(route("/", "/_vscode-cli/health"), Route::Health);
+		assert_eq!(route("/", "/_vscode-cli/mint-key"), Route::MintKey);
+
+		assert_eq!(
+			route("/vscode/", "/vscode"),
+			Route::Redirect("/vscode/".to_string())
+		);
+		assert_eq!(route("/vscode/", "/vscode/"), Route::Proxied);
+		assert_eq!(
+			route("/vscode/", "/vscode/_vscode-cli/health"),
+			Route::Health
+		);
+		assert_eq!(
+			route("/vscode/", "/vscode/_vscode-cli/mint-key"),
+			Route::MintKey
+		);
+		assert_eq!(route("/vscode/", "/_vscode-cli/health"), Route::NotFound);
+		assert_eq!(route("/vscode/", "/vscodex/"), Route::NotFound);
+		assert_eq!(route("/vscode/", "/"), Route::NotFound);
+		assert_eq!(route("/a/b/", "/a/b"), Route::Redirect("/a/b/".to_string()));
+	}
+
+	#[test]
+	fn test_release_under_base_path() {
+		let path = format!("/vscode/stable-{}/static/out/main.js", COMMIT);
+		let (release, rest) =
+			get_release_from_path(path_within_base("/vscode/", &path), Platform::LinuxX64).unwrap();
+		assert_eq!(release.commit, COMMIT);
+		assert_eq!(rest, "/static/out/main.js");
+
+		assert!(get_release_from_path(
+			path_within_base("/vscode/", "/vscode/"),
+			Platform::LinuxX64
+		)
+		.is_none());
+	}
+
+	/// Fails if any request is made.
+	struct NoHttp;
+
+	#[async_trait::async_trait]
+	impl crate::util::http::SimpleHttp for NoHttp {
+		async fn make_request(
+			&self,
+			_method: &'static str,
+			url: String,
+		) -> Result<crate::util::http::SimpleResponse, AnyError> {
+			panic!("unexpected request to {}", url)
+		}
+	}
+
+	/// Gets the context requests are handled in when serve-web is run with
+	/// the args, where `COMMIT` is already running on the `server` socket.
+	fn test_context(args: ServeWebArgs, server: PathBuf, cache_dir: &Path) -> HandleContext {
+		let cm = ConnectionManager::with_services(
+			log::Logger::test(),
+			Platform::LinuxX64,
+			args,
+			DownloadCache::new(cache_dir.to_owned()),
+			UpdateService::new(log::Logger::test(), Arc::new(NoHttp)),
+			true,
+		);
+		let (socket_path, opener) = new_barrier();
+		opener.open(Ok((server, Arc::new(tokio::sync::watch::channel(0).0))));
+		cm.state.lock().unwrap().insert(
+			(Quality::Stable, COMMIT.to_string()),
+			VersionState {
+				downloaded: true,
+				socket_path,
+			},
+		);
+
+		HandleContext {
+			log: cm.log.clone(),
+			cm,
+			server_secret_key: SecretKeyPart::new(),
+			access_log: None,
+			peer: None,
+		}
+	}
+
+	/// Serves requests like a VS Code server started with the base path: it
+	/// responds with the path of plain requests, and echoes upgraded ones
+	/// until they're closed.
+	async fn start_fake_server() -> PathBuf {
+		let path = get_socket_name();
+		let listener = listen_socket_rw_stream(&path).await.unwrap();
+		tokio::spawn(
+			Server::builder(listener.into_pollable()).serve(make_service_fn(|_| async {
+				Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+					if !req.headers().contains_key(hyper::header::UPGRADE) {
+						let path = req.uri().path().to_string();
+						return Ok::<_, Infallible>(Response::new(Body::from(path)));
+					}
+
+					tokio::spawn(async move {
+						let upgraded = hyper::upgrade::on(req).await.unwrap();
+						let (mut read, mut write) = tokio::io::split(upgraded);
+						let _ = tokio::io::copy(&mut read, &mut write).await;
+					});
+
+					Ok(Response::builder()
+						.status(101)
+						.header(hyper::header::CONNECTION, "upgrade")
+						.header(hyper::header::UPGRADE, "websocket")
+						.body(Body::empty())
+						.unwrap())
+				}))
+			})),
+		);
+		path
+	}
+
+	/// Starts a server that handles requests in the context, like `serve_web`
+	/// does. It stops accepting connections once the drain starts.
+	fn start_proxy(ctx: HandleContext) -> SocketAddr {
+		let drain = ctx.cm.drain.clone();
+		let make_svc = make_service_fn(move |_| {
+			let ctx = ctx.clone();
+			async move { Ok::<_, Infallible>(service_fn(move |req| handle(ctx.clone(), req))) }
+		});
+
+		let proxy =
+			Server::bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).serve(make_svc);
+		let addr = proxy.local_addr();
+		tokio::spawn(proxy.with_graceful_shutdown(async move {
+			drain.started().await;
+		}));
+		addr
+	}
+
+	async fn send(addr: SocketAddr, req: Request<Body>) -> Response<Body> {
+		let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+		let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+		tokio::spawn(connection);
+		sender.send_request(req).await.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_proxies_under_base_path() {
+		let dir = tempfile::tempdir().unwrap();
+		let addr = start_proxy(test_context(
+			serve_web_args(&[
+				"--without-connection-token",
+				"--server-base-path",
+				"/vscode",
+			]),
+			start_fake_server().await,
+			dir.path(),
+		));
+
+		// assets keep the base path when they're forwarded
+		let asset = format!("/vscode/stable-{}/static/out/main.js", COMMIT);
+		let res = send(addr, Request::get(&asset).body(Body::empty()).unwrap()).await;
+		assert_eq!(res.status(), 200);
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		assert_eq!(body, asset.as_bytes());
+
+		let res = send(
+			addr,
+			Request::get("/static/out/main.js")
+				.body(Body::empty())
+				.unwrap(),
+		)
+		.await;
+		assert_eq!(res.status(), 404);
+
+		// websockets are spliced through the prefix
+		let req = Request::get(format!("/vscode/stable-{}/?reconnectionToken=x", COMMIT))
+			.header(hyper::header::CONNECTION, "Upgrade")
+			.header(hyper::header::UPGRADE, "websocket")
+			.body(Body::empty())
+			.unwrap();
+		let res = send(addr, req).await;
+		assert_eq!(res.status(), 101);
+
+		let mut upgraded = hyper::upgrade::on(res).await.unwrap();
+		upgraded.write_all(b"ping").await.unwrap();
+		let mut buf = [0u8; 4];
+		upgraded.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"ping");
+	}
+
+	#[tokio::test]
+	async fn test_only_static_assets_are_immutable() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(
+			serve_web_args(&["--without-connection-token"]),
+			start_fake_server().await,
+			dir.path(),
+		);
+		let get = |path: String, etag: Option<&str>| {
+			let mut req = Request::get(path);
+			if let Some(e) = etag {
+				req = req.header(hyper::header::IF_NONE_MATCH, e);
+			}
+			req.body(Body::empty()).unwrap()
+		};
+
+		let asset = format!("/stable-{}/static/out/main.js", COMMIT);
+		let res = handle_request(&ctx, get(asset.clone(), None)).await;
+		assert_eq!(res.status(), 200);
+		let etag = res.headers()[hyper::header::ETAG]
+			.to_str()
+			.unwrap()
+			.to_string();
+		let res = handle_request(&ctx, get(asset, Some(&etag))).await;
+		assert_eq!(res.status(), 304);
+
+		// each asset has its own etag
+		let other = format!("/stable-{}/static/out/nls.messages.js", COMMIT);
+		let res = handle_request(&ctx, get(other, Some(&etag))).await;
+		assert_eq!(res.status(), 200);
+
+		// workspace files are always fetched from the server
+		let resource = format!("/stable-{}/vscode-remote-resource?path=/a.txt", COMMIT);
+		let res = handle_request(&ctx, get(resource, Some(&etag))).await;
+		assert_eq!(res.status(), 200);
+		assert!(!res.headers().contains_key(hyper::header::ETAG));
+		assert!(!res.headers().contains_key(hyper::header::CACHE_CONTROL));
+	}
+
+	#[test]
+	fn test_rewrite_commit_path() {
+		let rewrite = |base_path: &str, uri: &str| {
+			let mut req = request(uri, None);
+			rewrite_commit_path(&mut req, base_path, OLD, COMMIT);
+			req.uri().to_string()
+		};
+
+		assert_eq!(
+			rewrite("/", &format!("/stable-{}/static/a.js?x=1", OLD)),
+			format!("/stable-{}/static/a.js?x=1", COMMIT)
+		);
+		assert_eq!(
+			rewrite("/vscode/", &format!("/vscode/insider-{}", OLD)),
+			format!("/vscode/insider-{}", COMMIT)
+		);
+		// paths of other commits, or none, are left alone
+		assert_eq!(rewrite("/", "/"), "/");
+		assert_eq!(
+			rewrite("/", &format!("/stable-{}/", COMMIT)),
+			format!("/stable-{}/", COMMIT)
+		);
+	}
+
+	#[tokio::test]
+	async fn test_substitute_is_served_its_own_path() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(
+			serve_web_args(&["--without-connection-token"]),
+			start_fake_server().await,
+			dir.path(),
+		);
+		*ctx.cm.latest_version.try_lock().unwrap() = Some((
+			Instant::now(),
+			Release {
+				quality: Quality::Stable,
+				commit: COMMIT.to_string(),
+				platform: Platform::LinuxX64,
+				target: TargetKind::Web,
+				name: "".to_string(),
+			},
+		));
+		ctx.cm
+			.unavailable
+			.lock()
+			.unwrap()
+			.insert((Quality::Stable, OLD.to_string()), Instant::now());
+
+		let req = request(&format!("/stable-{}/static/out/main.js", OLD), None);
+		let res = handle_request(&ctx, req).await;
+		assert_eq!(res.status(), 200);
+		assert_eq!(res.headers()[SUBSTITUTE_COMMIT_HEADER], COMMIT);
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		assert_eq!(
+			body,
+			format!("/stable-{}/static/out/main.js", COMMIT).as_bytes()
+		);
+	}
+
+	#[tokio::test]
+	async fn test_drain_keeps_open_websockets() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = test_context(
+			serve_web_args(&["--without-connection-token", "--drain-timeout", "10"]),
+			start_fake_server().await,
+			dir.path(),
+		);
+		let drain = ctx.cm.drain.clone();
+		let addr = start_proxy(ctx);
+
+		let req = Request::get(format!("/stable-{}/?reconnectionToken=x", COMMIT))
+			.header(hyper::header::CONNECTION, "Upgrade")
+			.header(hyper::header::UPGRADE, "websocket")
+			.body(Body::empty())
+			.unwrap();
+		let res = send(addr, req).await;
+		assert_eq!(res.status(), 101);
+		let mut upgraded = hyper::upgrade::on(res).await.unwrap();
+
+		let waiter = drain.clone();
+		let finished = tokio::spawn(async move { waiter.finished().await });
+		drain.start();
+
+		// the client is asked to close
+		let mut buf = [0u8; 4];
+		upgraded.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf, [0x88, 0x02, 0x03, 0xE9]);
+
+		// new connections are refused, once the listener is closed
+		let mut refused = false;
+		for _ in 0..50 {
+			if tokio::net::TcpStream::connect(addr).await.is_err() {
+				refused = true;
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		assert!(refused, "expected new connections to be refused");
+
+		// but the open websocket stays up until the client answers the close
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert!(!finished.is_finished());
+		upgraded.write_all(&[0x88, 0x80, 0, 0, 0, 0]).await.unwrap();
+		drop(upgraded);
+		let drained = tokio::time::timeout(Duration::from_secs(1), finished)
+			.await
+			.expect("expected the drain to finish")
+			.unwrap();
+		assert!(drained);
+	}
+
+	#[test]
+	fn test_resolve_listen_addrs() {
+		let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+		let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+		let hosts = |h: &[&str]| h.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+
+		assert_eq!(
+			resolve_listen_addrs(&[], 8000).unwrap(),
+			vec![SocketAddr::new(v4, 8000)]
+		);
+		assert_eq!(
+			resolve_listen_addrs(&hosts(&["localhost", "127.0.0.1"]), 0).unwrap(),
+			vec![SocketAddr::new(v4, 0), SocketAddr::new(v6, 0)]
+		);
+		assert_eq!(
+			resolve_listen_addrs(&hosts(&["::", "[::1]", "0.0.0.0"]), 80).unwrap(),
+			vec![
+				SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 80),
+				SocketAddr::new(v6, 80),
+				SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 80),
+			]
+		);
+		assert!(resolve_listen_addrs(&hosts(&["example.com"]), 80).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_listens_on_both_loopbacks() {
+		let addrs = resolve_listen_addrs(&["localhost".to_string()], 0).unwrap();
+		let listeners = bind_listeners(&log::Logger::test(), &addrs).await.unwrap();
+		assert_eq!(listeners.len(), 2);
+
+		let bound: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+		assert!(bound[0].ip().is_ipv4() && bound[1].ip().is_ipv6());
+		assert_ne!(bound[0].port(), 0);
+		assert_eq!(bound[0].port(), bound[1].port());
+
+		// every address is reported, with the port that was picked
+		let args = serve_web_args(&["--host", "localhost", "--port", "0", "--json"]);
+		let lines = startup_output("http", &bound, None, &args);
+		let output: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+		let port = bound[0].port();
+		assert_eq!(
+			output["urls"],
+			serde_json::json!([
+				format!("http://127.0.0.1:{}/?tkn={}", port, TOKEN),
+				format!("http://[::1]:{}/?tkn={}", port, TOKEN),
+			])
+		);
+
+		for listener in listeners {
+			let make_svc = make_service_fn(|_| async {
+				Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+					Ok::<_, Infallible>(Response::new(Body::from("hello")))
+				}))
+			});
+			let server = Server::builder(AddrIncoming::from_listener(listener).unwrap());
+			tokio::spawn(server.serve(make_svc));
+		}
+
+		for addr in bound {
+			let res = send(addr, request("/", None)).await;
+			let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+			assert_eq!(body, "hello");
+		}
+	}
+
+	fn serve_web_args(argv: &[&str]) -> ServeWebArgs {
+		use clap::{Args, FromArgMatches};
+
+		let cmd = ServeWebArgs::augment_args(clap::Command::new("serve-web"));
+		let mut args =
+			ServeWebArgs::from_arg_matches(&cmd.get_matches_from([&["serve-web"], argv].concat()))
+				.unwrap();
+		if !args.without_connection_token {
+			args.connection_token = Some(TOKEN.to_string());
+		}
+		args
+	}
+
+	#[tokio::test]
+	async fn test_startup_output_with_free_port() {
+		let args = serve_web_args(&["--host", "0.0.0.0", "--port", "0", "--json"]);
+		let addrs = resolve_listen_addrs(&args.host, args.port).unwrap();
+		let listeners = bind_listeners(&log::Logger::test(), &addrs).await.unwrap();
+		let bound: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+		let port = bound[0].port();
+		assert_ne!(port, 0);
+
+		// a single line, with the port that was picked
+		let lines = startup_output("http", &bound, None, &args);
+		assert_eq!(lines.len(), 1);
+		let output: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+		assert_eq!(
+			output["url"],
+			format!("http://127.0.0.1:{}/?tkn={}", port, TOKEN)
+		);
+		assert_eq!(
+			output["urls"],
+			serde_json::json!([format!("http://0.0.0.0:{}/?tkn={}", port, TOKEN)])
+		);
+		assert!(output.get("certificate_fingerprint").is_none());
+	}
+
+	#[test]
+	fn test_startup_output_text() {
+		let args = serve_web_args(&["--host", "localhost", "--server-base-path", "/vscode/"]);
+		let bound = [
+			SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8000),
+			SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8000),
+		];
+
+		assert_eq!(
+			startup_output("https", &bound, Some("AB:CD".to_string()), &args),
+			vec![
+				"Using a self-signed certificate, its SHA-256 fingerprint is AB:CD".to_string(),
+				format!(
+					"Web UI available at https://127.0.0.1:8000/vscode/?tkn={}",
+					TOKEN
+				),
+				format!(
+					"Web UI available at https://[::1]:8000/vscode/?tkn={}",
+					TOKEN
+				),
+			]
+		);
+	}
+
+	#[test]
+	fn test_no_open_overrides_open() {
+		let args = serve_web_args(&["--open", "--no-open"]);
+		assert!(!args.open && args.no_open);
+		let args = serve_web_args(&["--no-open", "--open"]);
+		assert!(args.open && !args.no_open);
+	}
+
+	#[tokio::test]
+	async fn test_bind_listeners_partial_failure() {
+		let existing = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+		let taken = existing.local_addr().unwrap();
+		let free = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+		let listeners = bind_listeners(&log::Logger::test(), &[taken, free])
+			.await
+			.unwrap();
+		assert_eq!(listeners.len(), 1);
+		assert_ne!(listeners[0].local_addr().unwrap(), taken);
+
+		match bind_listeners(&log::Logger::test(), &[taken]).await {
+			Err(CodeError::CouldNotBindAddress(addr, _)) => assert_eq!(addr, taken),
+			_ => panic!("expected the bind to fail"),
+		}
+	}
+
+	fn release(quality: Quality, commit: &str) -> Release {
+		Release {
+			name: String::new(),
+			platform: Platform::LinuxX64,
+			target: TargetKind::Web,
+			quality,
+			commit: commit.to_string(),
+		}
+	}
+
+	fn cached(quality: Option<Quality>, commit: &str, date: &str) -> CachedBuild {
+		CachedBuild {
+			commit: commit.to_string(),
+			quality,
+			date: Some(date.to_string()),
+		}
+	}
+
+	const OLD: &str = "1111111111111111111111111111111111111111";
+	const NEW: &str = "2222222222222222222222222222222222222222";
+	const LATEST: &str = "3333333333333333333333333333333333333333";
+
+	#[test]
+	fn test_substitute_prefers_newest_cached() {
+		let requested = release(Quality::Stable, COMMIT);
+		let builds = [
+			cached(Some(Quality::Stable), OLD, "2023-01-01T00:00:00.000Z"),
+			cached(Some(Quality::Stable), NEW, "2023-06-01T00:00:00.000Z"),
+			cached(Some(Quality::Insiders), LATEST, "2023-12-01T00:00:00.000Z"),
+			cached(None, LATEST, "2023-12-01T00:00:00.000Z"),
+		];
+		let latest = release(Quality::Stable, LATEST);
+
+		let s = choose_substitute(&requested, &builds, Some(&latest), |_| false).unwrap();
+		assert_eq!(s.commit, NEW);
+		assert_eq!(s.quality, Quality::Stable);
+
+		// builds that also failed to download are skipped
+		let s = choose_substitute(&requested, &builds, Some(&latest), |k| k.1 == NEW).unwrap();
+		assert_eq!(s.commit, OLD);
+	}
+
+	#[test]
+	fn test_substitute_falls_back_to_latest() {
+		let requested = release(Quality::Stable, COMMIT);
+		let latest = release(Quality::Stable, LATEST);
+		let builds = [
+			cached(Some(Quality::Insiders), OLD, "2023-01-01T00:00:00.000Z"),
+			cached(Some(Quality::Stable), COMMIT, "2023-01-01T00:00:00.000Z"),
+		];
+
+		let s = choose_substitute(&requested, &builds, Some(&latest), |_| false).unwrap();
+		assert_eq!(s.commit, LATEST);
+	}
+
+	#[test]
+	fn test_substitute_unavailable() {
+		let requested = release(Quality::Stable, COMMIT);
+
+		// nothing cached, and no latest release known
+		assert!(choose_substitute(&requested, &[], None, |_| false).is_none());
+
+		// the latest release is the one that's unavailable
+		let latest = release(Quality::Stable, COMMIT);
+		assert!(choose_substitute(&requested, &[], Some(&latest), |_| false).is_none());
+
+		// the latest release is of another quality, or failed to download too
+		let latest = release(Quality::Insiders, LATEST);
+		assert!(choose_substitute(&requested, &[], Some(&latest), |_| false).is_none());
+		let latest = release(Quality::Stable, LATEST);
+		assert!(choose_substitute(&requested, &[], Some(&latest), |_| true).is_none());
+	}
+
+	#[test]
+	fn test_list_cached_builds() {
+		let dir = tempfile::tempdir().unwrap();
+		for (name, product) in [
+			(
+				COMMIT,
+				Some(r#"{"quality":"stable","date":"2023-06-01T00:00:00.000Z"}"#),
+			),
+			(OLD, None),
+			(
+				"2222222222222222222222222222222222222222.staging",
+				Some("{}"),
+			),
+			("lru.json", None),
+		] {
+			fs::create_dir_all(dir.path().join(name)).unwrap();
+			if let Some(p) = product {
+				fs::write(dir.path().join(name).join("product.json"), p).unwrap();
+			}
+		}
+
+		let mut builds = list_cached_builds(dir.path());
+		builds.sort_by(|a, b| a.commit.cmp(&b.commit));
+		assert_eq!(builds.len(), 2);
+		assert_eq!(builds[0].commit, COMMIT);
+		assert_eq!(builds[0].quality, Some(Quality::Stable));
+		assert_eq!(builds[0].date.as_deref(), Some("2023-06-01T00:00:00.000Z"));
+		assert_eq!(builds[1].commit, OLD);
+		assert_eq!(builds[1].quality, None);
+	}
+
+	#[test]
+	fn test_read_connection_token_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("token");
+
+		fs::write(&path, "from-a-vault\n").unwrap();
+		assert_eq!(read_connection_token_file(&path).unwrap(), "from-a-vault");
+
+		fs::write(&path, "  \n").unwrap();
+		assert!(matches!(
+			read_connection_token_file(&path),
+			Err(CodeError::InvalidConnectionToken)
+		));
+
+		fs::write(&path, "has spaces in it").unwrap();
+		assert!(matches!(
+			read_connection_token_file(&path),
+			Err(CodeError::InvalidConnectionToken)
+		));
+
+		assert!(matches!(
+			read_connection_token_file(&dir.path().join("missing")),
+			Err(CodeError::CouldNotReadConnectionTokenFile(_))
+		));
+	}
+
+	#[test]
+	fn test_mint_connection_token() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("serve-web-token");
+
+		let minted = mint_connection_token(&path, None).unwrap();
+		assert!(is_valid_connection_token(&minted));
+		assert_eq!(mint_connection_token(&path, None).unwrap(), minted);
+		assert_eq!(
+			mint_connection_token(&path, Some("given".to_string())).unwrap(),
+			"given"
+		);
+		assert_eq!(mint_connection_token(&path, None).unwrap(), "given");
+	}
+
+	#[tokio::test]
+	async fn test_policy_requires_connection_token() {
+		// configures the policy, so it's run in a child
+		if !crate::util::test_env::in_child_with_env(
+			concat!(module_path!(), "::test_policy_requires_connection_token"),
+			&[],
+		) {
+			return;
+		}
+		policy::configure(policy::Policy {
+			source: Some("test".to_string()),
+			disable_serve_web_without_token: true,
+			..Default::default()
+		});
+
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = CommandContext {
+			log: log::Logger::test(),
+			paths: crate::state::LauncherPaths::new_without_replacements(dir.path().to_owned()),
+			args: Default::default(),
+			http: reqwest::Client::new(),
+		};
+		let err = serve_web(ctx, serve_web_args(&["--without-connection-token"]))
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::DisabledByPolicy(
+				PolicyFeature::ServeWebWithoutToken,
+				_
+			))
+		));
+	}
+}
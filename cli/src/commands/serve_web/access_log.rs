@@ -0,0 +1,362 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	net::SocketAddr,
+	pin::Pin,
+	task::{Context, Poll},
+	time::Instant,
+};
+
+use chrono::{DateTime, Local};
+use futures::Stream;
+use hyper::{
+	body::{Bytes, HttpBody},
+	header, Body, HeaderMap, Request, Response, StatusCode, Uri,
+};
+use tokio::{
+	fs::OpenOptions,
+	io::{AsyncWrite, AsyncWriteExt, BufWriter},
+	sync::mpsc,
+};
+
+use crate::util::errors::CodeError;
+
+use super::{websocket, CONNECTION_TOKEN_QUERY_PARAM};
+
+const REDACTED: &str = "REDACTED";
+/// Lines queued for writing, past which new ones are dropped.
+const MAX_QUEUED_LINES: usize = 4096;
+
+/// Writes a line per request, in the combined log format followed by the
+/// time it took. Lines are written in the background, so a slow disk doesn't
+/// hold up requests, and are dropped if it falls too far behind.
+#[derive(Clone)]
+pub struct AccessLog {
+	tx: mpsc::Sender<String>,
+}
+
+impl AccessLog {
+	/// Opens the log at the path for appending, or stdout for `-`.
+	pub async fn open(target: &str) -> Result<Self, CodeError> {
+		let out: Box<dyn AsyncWrite + Send + Unpin> = if target == "-" {
+			Box::new(tokio::io::stdout())
+		} else {
+			let file = OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(target)
+				.await
+				.map_err(|e| CodeError::CouldNotOpenAccessLog(target.to_string(), e))?;
+			Box::new(file)
+		};
+
+		let (tx, rx) = mpsc::channel(MAX_QUEUED_LINES);
+		tokio::spawn(write_lines(BufWriter::new(out), rx));
+		Ok(Self { tx })
+	}
+
+	fn write(&self, line: String) {
+		self.tx.try_send(line).ok();
+	}
+
+	/// Logs the response to the request once its body has been sent. Upgraded
+	/// connections are logged when they're opened and again when they close.
+	pub fn record(&self, req: RequestInfo, mut res: Response<Body>) -> Response<Body> {
+		let status = res.status();
+		if status == StatusCode::SWITCHING_PROTOCOLS {
+			self.write(req.line(status, None, Some("ws-open")));
+			if let Some(mut closed) = res.extensions_mut().remove::<websocket::Closed>() {
+				let log = self.clone();
+				tokio::spawn(async move {
+					let _ = closed.0.wait().await;
+					log.write(req.line(status, None, Some("ws-close")));
+				});
+			}
+			return res;
+		}
+
+		let (parts, body) = res.into_parts();
+		let body = CountedBody {
+			inner: body,
+			bytes: 0,
+			done: Some((self.clone(), req, status)),
+		};
+		Response::from_parts(parts, Body::wrap_stream(body))
+	}
+}
+
+async fn write_lines(
+	mut out: BufWriter<Box<dyn AsyncWrite + Send + Unpin>>,
+	mut rx: mpsc::Receiver<String>,
+) {
+	while let Some(line) = rx.recv().await {
+		if out.write_all(line.as_bytes()).await.is_err() {
+			return;
+		}
+
+		// flush once caught up, rather than after every line
+		while let Ok(line) = rx.try_recv() {
+			if out.write_all(line.as_bytes()).await.is_err() {
+				return;
+			}
+		}
+		if out.flush().await.is_err() {
+			return;
+		}
+	}
+}
+
+/// Parts of a request that are logged, taken before it's handled.
+pub struct RequestInfo {
+	started: Instant,
+	time: DateTime<Local>,
+	client: String,
+	method: String,
+	target: String,
+	version: String,
+	referer: String,
+	user_agent: String,
+}
+
+impl RequestInfo {
+	pub fn new(peer: Option<SocketAddr>, req: &Request<Body>, trusted_proxy: bool) -> Self {
+		let header = |name: header::HeaderName| {
+			req.headers()
+				.get(name)
+				.and_then(|v| v.to_str().ok())
+				.map(escape)
+				.unwrap_or_else(|| "-".to_string())
+		};
+
+		Self {
+			started: Instant::now(),
+			time: Local::now(),
+			client: client_address(peer, req.headers(), trusted_proxy),
+			method: req.method().to_string(),
+			target: redact_target(req.uri()),
+			version: format!("{:?}", req.version()),
+			referer: header(header::REFERER),
+			user_agent: header(header::USER_AGENT),
+		}
+	}
+
+	fn line(&self, status: StatusCode, bytes: Option<u64>, event: Option<&str>) -> String {
+		let mut line = format!(
+			"{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {}ms",
+			self.client,
+			self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+			self.method,
+			self.target,
+			self.version,
+			status.as_u16(),
+			bytes
+				.map(|b| b.to_string())
+				.unwrap_or_else(|| "-".to_string()),
+			self.referer,
+			self.user_agent,
+			self.started.elapsed().as_millis(),
+		);
+		if let Some(event) = event {
+			line.push(' ');
+			line.push_str(event);
+		}
+		line.push('\n');
+		line
+	}
+}
+
+/// Gets the address of the client. The X-Forwarded-For header is only used
+/// from a trusted proxy, since anyone else could set it to anything. Even
+/// then, only its last entry is used, which is the one the proxy added: the
+/// client can put anything before it.
+fn client_address(peer: Option<SocketAddr>, headers: &HeaderMap, trusted_proxy: bool) -> String {
+	if trusted_proxy {
+		let forwarded = headers
+			.get_all("x-forwarded-for")
+			.iter()
+			.next_back()
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.rsplit(',').next())
+			.map(|v| v.trim())
+			.filter(|v| !v.is_empty());
+		if let Some(f) = forwarded {
+			return escape(f);
+		}
+	}
+
+	peer.map(|p| p.ip().to_string())
+		.unwrap_or_else(|| "-".to_string())
+}
+
+/// Gets the path and query of the URI, without the connection token. Names
+/// are decoded before they're compared, as they are when the token is checked.
+fn redact_target(uri: &Uri) -> String {
+	let query = match uri.query() {
+		Some(q) => q,
+		None => return uri.path().to_string(),
+	};
+
+	let query = query
+		.split('&')
+		.map(|pair| {
+			let is_token = url::form_urlencoded::parse(pair.as_bytes())
+				.next()
+				.map(|(k, _)| k == CONNECTION_TOKEN_QUERY_PARAM)
+				.unwrap_or(false);
+			if is_token {
+				format!("{}={}", CONNECTION_TOKEN_QUERY_PARAM, REDACTED)
+			} else {
+				pair.to_string()
+			}
+		})
+		.collect::<Vec<_>>()
+		.join("&");
+
+	format!("{}?{}", uri.path(), query)
+}
+
+fn escape(s: &str) -> String {
+	s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Body that counts the bytes sent through it, logging the request when it's
+/// done or dropped.
+struct CountedBody {
+	inner: Body,
+	bytes: u64,
+	done: Option<(AccessLog, RequestInfo, StatusCode)>,
+}
+
+impl CountedBody {
+	fn finish(&mut self) {
+		if let Some((log, req, status)) = self.done.take() {
+			log.write(req.line(status, Some(self.bytes), None));
+		}
+	}
+}
+
+impl Stream for CountedBody {
+	type Item = Result<Bytes, hyper::Error>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let r = match Pin::new(&mut self.inner).poll_data(cx) {
+			Poll::Ready(r) => r,
+			Poll::Pending => return Poll::Pending,
+		};
+
+		match &r {
+			Some(Ok(b)) => self.bytes += b.len() as u64,
+			_ => self.finish(),
+		}
+
+		Poll::Ready(r)
+	}
+}
+
+impl Drop for CountedBody {
+	fn drop(&mut self) {
+		self.finish();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		net::{IpAddr, Ipv4Addr},
+		time::Duration,
+	};
+
+	use super::*;
+
+	fn peer() -> Option<SocketAddr> {
+		Some(SocketAddr::new(
+			IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+			1234,
+		))
+	}
+
+	fn forwarded(value: &str) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert("x-forwarded-for", value.parse().unwrap());
+		headers
+	}
+
+	#[test]
+	fn test_redact_target() {
+		let uri: Uri = "/stable-abc/?tkn=secret&folder=/home/tkn%3Dx"
+			.parse()
+			.unwrap();
+		assert_eq!(
+			redact_target(&uri),
+			"/stable-abc/?tkn=REDACTED&folder=/home/tkn%3Dx"
+		);
+
+		let uri: Uri = "/a?x=1&tkn=secret".parse().unwrap();
+		assert_eq!(redact_target(&uri), "/a?x=1&tkn=REDACTED");
+
+		let uri: Uri = "/a?xtkn=1".parse().unwrap();
+		assert_eq!(redact_target(&uri), "/a?xtkn=1");
+
+		// the token is accepted with an encoded name, so it's redacted too
+		let uri: Uri = "/a?%74kn=secret&t%6Bn=secret".parse().unwrap();
+		assert_eq!(redact_target(&uri), "/a?tkn=REDACTED&tkn=REDACTED");
+
+		let uri: Uri = "/a".parse().unwrap();
+		assert_eq!(redact_target(&uri), "/a");
+	}
+
+	#[test]
+	fn test_client_address_trusted_proxy() {
+		let headers = forwarded("203.0.113.7");
+		assert_eq!(client_address(peer(), &headers, false), "10.0.0.1");
+		assert_eq!(client_address(peer(), &headers, true), "203.0.113.7");
+
+		// entries before the one the proxy added are the client's own
+		let headers = forwarded("198.51.100.1, 203.0.113.7");
+		assert_eq!(client_address(peer(), &headers, true), "203.0.113.7");
+		let mut headers = forwarded("198.51.100.1");
+		headers.append("x-forwarded-for", "203.0.113.7".parse().unwrap());
+		assert_eq!(client_address(peer(), &headers, true), "203.0.113.7");
+
+		// falls back to the peer without the header
+		assert_eq!(client_address(peer(), &HeaderMap::new(), true), "10.0.0.1");
+		assert_eq!(client_address(None, &HeaderMap::new(), false), "-");
+	}
+
+	#[tokio::test]
+	async fn test_logs_after_body_is_sent() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("access.log");
+		let log = AccessLog::open(path.to_str().unwrap()).await.unwrap();
+
+		let req = Request::get("/a?tkn=secret")
+			.header(header::USER_AGENT, "test \"agent\"")
+			.body(Body::empty())
+			.unwrap();
+		let info = RequestInfo::new(peer(), &req, false);
+		let res = log.record(info, Response::new(Body::from("hello")));
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		assert_eq!(body, "hello");
+
+		let mut contents = String::new();
+		for _ in 0..50 {
+			contents = std::fs::read_to_string(&path).unwrap();
+			if !contents.is_empty() {
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+
+		assert!(contents.starts_with("10.0.0.1 - - ["), "{}", contents);
+		assert!(
+			contents
+				.contains("\"GET /a?tkn=REDACTED HTTP/1.1\" 200 5 \"-\" \"test \\\"agent\\\"\""),
+			"{}",
+			contents
+		);
+		assert!(!contents.contains("secret"));
+	}
+}
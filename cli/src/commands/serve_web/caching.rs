@@ -0,0 +1,372 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use chrono::{DateTime, FixedOffset};
+use hyper::{
+	header::{self, HeaderMap, HeaderValue},
+	Body, Response, StatusCode,
+};
+use sha2::{Digest, Sha256};
+
+use super::compression::Encoding;
+
+/// For static assets under a commit path, which never change.
+const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// For the workbench HTML, which points to the assets of whichever version
+/// is current and so must be revalidated on every load.
+const CACHE_REVALIDATE: &str = "no-cache";
+/// Pages larger than this are sent without an ETag rather than buffered.
+const MAX_HASHED_BODY: u64 = 4 * 1024 * 1024;
+
+/// Headers a 304 response carries over from the full response.
+const NOT_MODIFIED_HEADERS: [header::HeaderName; 5] = [
+	header::CACHE_CONTROL,
+	header::ETAG,
+	header::LAST_MODIFIED,
+	header::VARY,
+	header::EXPIRES,
+];
+
+/// Validators sent by the client with a request.
+#[derive(Debug, Default)]
+pub struct Conditional {
+	if_none_match: Option<String>,
+	if_modified_since: Option<DateTime<FixedOffset>>,
+}
+
+impl Conditional {
+	pub fn from_request(headers: &HeaderMap) -> Self {
+		let get = |name: header::HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+		Self {
+			if_none_match: get(header::IF_NONE_MATCH).map(|s| s.to_string()),
+			if_modified_since: get(header::IF_MODIFIED_SINCE).and_then(parse_http_date),
+		}
+	}
+
+	/// Gets whether the client already has the representation with the ETag.
+	/// Uses the weak comparison, as `If-None-Match` does.
+	pub fn has_etag(&self, etag: &str) -> bool {
+		let etag = strip_weak(etag);
+		match &self.if_none_match {
+			Some(m) => m.split(',').any(|t| strip_weak(t.trim()) == etag),
+			None => false,
+		}
+	}
+
+	/// Gets whether the client's copy of the response is still fresh.
+	fn is_fresh(&self, res: &Response<Body>) -> bool {
+		// If-Modified-Since is ignored when If-None-Match is given (RFC 9110)
+		if let Some(m) = &self.if_none_match {
+			return m.trim() == "*"
+				|| res
+					.headers()
+					.get(header::ETAG)
+					.and_then(|v| v.to_str().ok())
+					.map(|e| self.has_etag(e))
+					.unwrap_or(false);
+		}
+
+		match (
+			self.if_modified_since,
+			res.headers()
+				.get(header::LAST_MODIFIED)
+				.and_then(|v| v.to_str().ok())
+				.and_then(parse_http_date),
+		) {
+			(Some(since), Some(modified)) => modified <= since,
+			_ => false,
+		}
+	}
+}
+
+fn strip_weak(etag: &str) -> &str {
+	etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+fn parse_http_date(s: &str) -> Option<DateTime<FixedOffset>> {
+	DateTime::parse_from_rfc2822(s).ok()
+}
+
+/// Gets whether the path, relative to a commit path, is one of the server's
+/// static assets. Others, such as `vscode-remote-resource`, can be files in
+/// the workspace that change while the server runs.
+pub fn is_static_asset(path: &str) -> bool {
+	path.starts_with("/static/")
+}
+
+/// Gets the ETag of a static asset under the commit's path, which identifies
+/// its content since the path is never reused by another version.
+pub fn commit_etag(commit: &str, path: &str, encoding: Encoding) -> String {
+	let hash = Sha256::digest(path.as_bytes());
+	let hash: String = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+	format!("\"{}-{}-{}\"", commit, hash, encoding.token())
+}
+
+/// Makes a 304 response with the validators and caching headers of `res`.
+fn not_modified_from(res: &Response<Body>) -> Response<Body> {
+	let mut not_modified = Response::new(Body::empty());
+	*not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+	for name in NOT_MODIFIED_HEADERS {
+		for v in res.headers().get_all(&name) {
+			not_modified.headers_mut().append(&name, v.clone());
+		}
+	}
+	not_modified
+}
+
+/// Makes a 304 response for a static asset under a commit path.
+pub fn not_modified_immutable(etag: &str) -> Response<Body> {
+	let mut res = Response::new(Body::empty());
+	set_immutable(&mut res, etag);
+	let mut res = not_modified_from(&res);
+	res.headers_mut()
+		.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+	res
+}
+
+fn set_immutable(res: &mut Response<Body>, etag: &str) {
+	let headers = res.headers_mut();
+	headers.insert(
+		header::CACHE_CONTROL,
+		HeaderValue::from_static(CACHE_IMMUTABLE),
+	);
+	if let Ok(v) = HeaderValue::from_str(etag) {
+		headers.insert(header::ETAG, v);
+	}
+}
+
+fn is_html(res: &Response<Body>) -> bool {
+	res.headers()
+		.get(header::CONTENT_TYPE)
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v.trim_start().to_ascii_lowercase().starts_with("text/html"))
+		.unwrap_or(false)
+}
+
+/// Sets caching headers on the response, answering with a 304 instead if the
+/// client's copy is still fresh. `immutable_etag` is given for static assets
+/// under a commit path.
+pub async fn apply(
+	conditional: &Conditional,
+	immutable_etag: Option<&str>,
+	mut res: Response<Body>,
+) -> Response<Body> {
+	if res.status() != StatusCode::OK {
+		return res;
+	}
+
+	if is_html(&res) {
+		res.headers_mut().insert(
+			header::CACHE_CONTROL,
+			HeaderValue::from_static(CACHE_REVALIDATE),
+		);
+		return with_content_etag(conditional, res).await;
+	}
+
+	if let Some(etag) = immutable_etag {
+		set_immutable(&mut res, etag);
+	}
+
+	if conditional.is_fresh(&res) {
+		return not_modified_from(&res);
+	}
+
+	res
+}
+
+/// Buffers the body to give it an ETag of its hash.
+async fn with_content_etag(conditional: &Conditional, res: Response<Body>) -> Response<Body> {
+	let len = res
+		.headers()
+		.get(header::CONTENT_LENGTH)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.parse::<u64>().ok());
+	if matches!(len, Some(l) if l > MAX_HASHED_BODY) {
+		return res;
+	}
+
+	let (mut parts, body) = res.into_parts();
+	let body = match hyper::body::to_bytes(body).await {
+		Ok(b) => b,
+		Err(e) => {
+			return Response::builder()
+				.status(StatusCode::BAD_GATEWAY)
+				.body(Body::from(format!("Error reading response: {}", e)))
+				.unwrap()
+		}
+	};
+
+	let hash = Sha256::digest(&body);
+	let etag: String = hash[..16].iter().map(|b| format!("{:02x}", b)).collect();
+	parts.headers.insert(
+		header::ETAG,
+		HeaderValue::from_str(&format!("\"{}\"", etag)).unwrap(),
+	);
+	parts
+		.headers
+		.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+	let res = Response::from_parts(parts, Body::from(body));
+	if conditional.is_fresh(&res) {
+		return not_modified_from(&res);
+	}
+
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use hyper::Request;
+
+	use super::*;
+
+	const COMMIT_A: &str = "0123456789abcdef0123456789abcdef01234567";
+	const COMMIT_B: &str = "fedcba9876543210fedcba9876543210fedcba98";
+
+	fn conditional(headers: &[(header::HeaderName, &str)]) -> Conditional {
+		let mut req = Request::get("/");
+		for (k, v) in headers {
+			req = req.header(k, *v);
+		}
+		Conditional::from_request(req.body(()).unwrap().headers())
+	}
+
+	fn html(body: &str) -> Response<Body> {
+		Response::builder()
+			.header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+			.body(Body::from(body.to_string()))
+			.unwrap()
+	}
+
+	fn script() -> Response<Body> {
+		Response::builder()
+			.header(header::CONTENT_TYPE, "application/javascript")
+			.body(Body::from("console.log(1)"))
+			.unwrap()
+	}
+
+	const MAIN_JS: &str = "/static/out/main.js";
+
+	#[test]
+	fn test_is_static_asset() {
+		assert!(is_static_asset(MAIN_JS));
+		assert!(!is_static_asset("/vscode-remote-resource"));
+		assert!(!is_static_asset("/"));
+		assert!(!is_static_asset("/staticfoo"));
+	}
+
+	#[test]
+	fn test_commit_etag() {
+		let a = commit_etag(COMMIT_A, MAIN_JS, Encoding::Gzip);
+		assert!(a.starts_with(&format!("\"{}-", COMMIT_A)));
+		assert!(a.ends_with("-gzip\""));
+		assert_eq!(a, commit_etag(COMMIT_A, MAIN_JS, Encoding::Gzip));
+		assert_ne!(a, commit_etag(COMMIT_B, MAIN_JS, Encoding::Gzip));
+		assert_ne!(a, commit_etag(COMMIT_A, MAIN_JS, Encoding::Brotli));
+		assert_ne!(
+			a,
+			commit_etag(COMMIT_A, "/static/out/nls.messages.js", Encoding::Gzip)
+		);
+	}
+
+	#[test]
+	fn test_has_etag() {
+		let c = conditional(&[(header::IF_NONE_MATCH, "\"a\", W/\"b\"")]);
+		assert!(c.has_etag("\"a\""));
+		assert!(c.has_etag("\"b\""));
+		assert!(c.has_etag("W/\"a\""));
+		assert!(!c.has_etag("\"c\""));
+		assert!(!conditional(&[]).has_etag("\"a\""));
+	}
+
+	#[tokio::test]
+	async fn test_immutable_asset_flow() {
+		let etag = commit_etag(COMMIT_A, MAIN_JS, Encoding::Brotli);
+
+		let res = apply(&conditional(&[]), Some(&etag), script()).await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(res.headers()[header::CACHE_CONTROL], CACHE_IMMUTABLE);
+		assert_eq!(res.headers()[header::ETAG], etag.as_str());
+
+		// the next request is answered before it reaches the server
+		let c = conditional(&[(header::IF_NONE_MATCH, &etag)]);
+		assert!(c.has_etag(&etag));
+		let res = not_modified_immutable(&etag);
+		assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+		assert_eq!(res.headers()[header::ETAG], etag.as_str());
+		assert_eq!(res.headers()[header::CACHE_CONTROL], CACHE_IMMUTABLE);
+
+		// a new version has a different path, and so a different etag
+		assert!(!c.has_etag(&commit_etag(COMMIT_B, MAIN_JS, Encoding::Brotli)));
+	}
+
+	#[tokio::test]
+	async fn test_html_flow() {
+		let page = format!("<script src=\"/stable-{}/out/main.js\"></script>", COMMIT_A);
+
+		let res = apply(&conditional(&[]), None, html(&page)).await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(res.headers()[header::CACHE_CONTROL], CACHE_REVALIDATE);
+		let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		assert_eq!(body, page.as_bytes());
+
+		let c = conditional(&[(header::IF_NONE_MATCH, &etag)]);
+		let res = apply(&c, None, html(&page)).await;
+		assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+		assert_eq!(res.headers()[header::CACHE_CONTROL], CACHE_REVALIDATE);
+		assert_eq!(res.headers()[header::ETAG], etag.as_str());
+		assert!(hyper::body::to_bytes(res.into_body())
+			.await
+			.unwrap()
+			.is_empty());
+
+		// once the server is updated, the page changes and is sent again
+		let updated = page.replace(COMMIT_A, COMMIT_B);
+		let res = apply(&c, None, html(&updated)).await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_ne!(res.headers()[header::ETAG], etag.as_str());
+	}
+
+	#[tokio::test]
+	async fn test_html_under_commit_path_is_revalidated() {
+		let etag = commit_etag(COMMIT_A, MAIN_JS, Encoding::Identity);
+		let res = apply(&conditional(&[]), Some(&etag), html("<html></html>")).await;
+		assert_eq!(res.headers()[header::CACHE_CONTROL], CACHE_REVALIDATE);
+		assert_ne!(res.headers()[header::ETAG], etag.as_str());
+	}
+
+	#[tokio::test]
+	async fn test_last_modified_flow() {
+		let with_date = || {
+			let mut res = script();
+			res.headers_mut().insert(
+				header::LAST_MODIFIED,
+				HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+			);
+			res
+		};
+
+		let c = conditional(&[(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")]);
+		let res = apply(&c, None, with_date()).await;
+		assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+		assert_eq!(
+			res.headers()[header::LAST_MODIFIED],
+			"Wed, 21 Oct 2015 07:28:00 GMT"
+		);
+
+		let c = conditional(&[(header::IF_MODIFIED_SINCE, "Tue, 20 Oct 2015 07:28:00 GMT")]);
+		assert_eq!(apply(&c, None, with_date()).await.status(), StatusCode::OK);
+
+		// non-200 responses are passed through
+		let mut missing = with_date();
+		*missing.status_mut() = StatusCode::NOT_FOUND;
+		let c = conditional(&[(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")]);
+		assert_eq!(
+			apply(&c, None, missing).await.status(),
+			StatusCode::NOT_FOUND
+		);
+	}
+}
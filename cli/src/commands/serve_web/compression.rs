@@ -0,0 +1,415 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::{Component, Path};
+
+use async_compression::{
+	tokio::bufread::{BrotliEncoder, GzipEncoder},
+	Level,
+};
+use futures::TryStreamExt;
+use hyper::{
+	header::{self, HeaderMap, HeaderValue},
+	Body, Request, Response, StatusCode,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Responses smaller than this aren't worth compressing.
+const MIN_COMPRESS_SIZE: u64 = 1024;
+/// Brotli's default level is too slow to use on the fly.
+const BROTLI_LEVEL: i32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+	Identity,
+	Gzip,
+	Brotli,
+}
+
+impl Encoding {
+	pub fn token(&self) -> &'static str {
+		match self {
+			Encoding::Identity => "identity",
+			Encoding::Gzip => "gzip",
+			Encoding::Brotli => "br",
+		}
+	}
+
+	/// Extension of the pre-compressed sibling of a file.
+	fn extension(&self) -> Option<&'static str> {
+		match self {
+			Encoding::Identity => None,
+			Encoding::Gzip => Some("gz"),
+			Encoding::Brotli => Some("br"),
+		}
+	}
+}
+
+/// Encodings in order of preference when the client weighs them equally.
+const PREFERRED: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Identity];
+
+/// Picks the encoding to respond with from the request's `Accept-Encoding`.
+pub fn negotiate(headers: &HeaderMap) -> Encoding {
+	let accept = match headers
+		.get(header::ACCEPT_ENCODING)
+		.and_then(|v| v.to_str().ok())
+	{
+		Some(a) => a,
+		None => return Encoding::Identity,
+	};
+
+	let mut wildcard = None;
+	let mut weights: Vec<(&str, f32)> = vec![];
+	for part in accept.split(',') {
+		let mut params = part.split(';');
+		let name = params.next().unwrap_or_default().trim();
+		let q = params
+			.filter_map(|p| p.trim().strip_prefix("q="))
+			.find_map(|q| q.parse::<f32>().ok())
+			.unwrap_or(1.0);
+
+		if name == "*" {
+			wildcard = Some(q);
+		} else {
+			weights.push((name, q));
+		}
+	}
+
+	let weight = |e: Encoding| {
+		weights
+			.iter()
+			.find(|(n, _)| n.eq_ignore_ascii_case(e.token()))
+			.map(|(_, q)| *q)
+			.or(wildcard)
+			// identity is acceptable unless it's explicitly refused
+			.unwrap_or(if e == Encoding::Identity { 0.001 } else { 0.0 })
+	};
+
+	let mut best = (Encoding::Identity, 0.0);
+	for e in PREFERRED {
+		let q = weight(e);
+		if q > best.1 {
+			best = (e, q);
+		}
+	}
+
+	best.0
+}
+
+/// Gets whether the response for the request must be passed through as-is.
+/// Ranges are of the uncompressed content, and upgraded connections carry
+/// their own framing.
+pub fn bypasses_compression(req: &Request<Body>) -> bool {
+	req.headers().contains_key(header::RANGE) || req.headers().contains_key(header::UPGRADE)
+}
+
+/// Gets whether the content type is text-like, rather than a format like
+/// images or fonts that's compressed already.
+pub fn is_compressible(content_type: &str) -> bool {
+	let mime = content_type
+		.split(';')
+		.next()
+		.unwrap_or_default()
+		.trim()
+		.to_ascii_lowercase();
+
+	mime.starts_with("text/")
+		|| mime.ends_with("+json")
+		|| mime.ends_with("+xml")
+		|| matches!(
+			mime.as_str(),
+			"application/javascript"
+				| "application/json"
+				| "application/xml"
+				| "application/wasm"
+				| "application/manifest+json"
+				| "image/svg+xml"
+		)
+}
+
+fn content_type_for(path: &str) -> &'static str {
+	let ext = path.rsplit('.').next().unwrap_or_default();
+	match ext {
+		"js" | "mjs" => "application/javascript",
+		"css" => "text/css",
+		"html" => "text/html",
+		"json" | "map" => "application/json",
+		"svg" => "image/svg+xml",
+		"wasm" => "application/wasm",
+		"txt" | "md" => "text/plain",
+		_ => "application/octet-stream",
+	}
+}
+
+fn append_vary(headers: &mut HeaderMap) {
+	headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+}
+
+/// Compresses the response body with the encoding, if it's one whose content
+/// is worth compressing.
+pub fn compress_response(encoding: Encoding, mut res: Response<Body>) -> Response<Body> {
+	let headers = res.headers();
+	let compressible = res.status() == StatusCode::OK
+		&& !headers.contains_key(header::CONTENT_ENCODING)
+		&& !headers.contains_key(header::CONTENT_RANGE)
+		&& headers
+			.get(header::CONTENT_TYPE)
+			.and_then(|v| v.to_str().ok())
+			.map(is_compressible)
+			.unwrap_or(false);
+	if !compressible {
+		return res;
+	}
+
+	append_vary(res.headers_mut());
+
+	let len = res
+		.headers()
+		.get(header::CONTENT_LENGTH)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.parse::<u64>().ok());
+	if encoding == Encoding::Identity || matches!(len, Some(l) if l < MIN_COMPRESS_SIZE) {
+		return res;
+	}
+
+	let (mut parts, body) = res.into_parts();
+	let reader = StreamReader::new(body.map_err(std::io::Error::other));
+	let body = match encoding {
+		Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+		Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::with_quality(
+			reader,
+			Level::Precise(BROTLI_LEVEL),
+		))),
+		Encoding::Identity => unreachable!(),
+	};
+
+	parts.headers.remove(header::CONTENT_LENGTH);
+	parts.headers.insert(
+		header::CONTENT_ENCODING,
+		HeaderValue::from_static(encoding.token()),
+	);
+	// the compressed bytes differ from the original, so strong validators no
+	// longer match them
+	if let Some(etag) = parts.headers.get(header::ETAG).cloned() {
+		if !etag.as_bytes().starts_with(b"W/") {
+			let mut weak = b"W/".to_vec();
+			weak.extend_from_slice(etag.as_bytes());
+			if let Ok(v) = HeaderValue::from_bytes(&weak) {
+				parts.headers.insert(header::ETAG, v);
+			}
+		}
+	}
+
+	Response::from_parts(parts, body)
+}
+
+/// Serves a static asset from the server's build directory using a file that
+/// was compressed ahead of time with the given encoding, if there is one.
+/// `asset` is the path under the `static/` route, relative to `root`.
+pub async fn serve_precompressed(
+	root: &Path,
+	asset: &str,
+	encoding: Encoding,
+) -> Option<Response<Body>> {
+	let ext = encoding.extension()?;
+
+	// only plain relative paths, so requests can't escape the root
+	let relative = Path::new(asset);
+	if asset.contains('\\')
+		|| !relative
+			.components()
+			.all(|c| matches!(c, Component::Normal(_)))
+	{
+		return None;
+	}
+
+	let path = root.join(format!("{}.{}", asset, ext));
+	let file = tokio::fs::File::open(&path).await.ok()?;
+	let len = file.metadata().await.ok()?.len();
+
+	let mut res = Response::new(Body::wrap_stream(ReaderStream::new(file)));
+	let headers = res.headers_mut();
+	headers.insert(
+		header::CONTENT_TYPE,
+		HeaderValue::from_static(content_type_for(asset)),
+	);
+	headers.insert(
+		header::CONTENT_ENCODING,
+		HeaderValue::from_static(encoding.token()),
+	);
+	headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+	append_vary(headers);
+
+	Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+
+	use async_compression::tokio::bufread::BrotliDecoder;
+	use tokio::io::AsyncReadExt;
+
+	use super::*;
+
+	fn accepting(value: &str) -> HeaderMap {
+		let mut h = HeaderMap::new();
+		h.insert(header::ACCEPT_ENCODING, value.parse().unwrap());
+		h
+	}
+
+	fn text_response(content_type: &str, body: String) -> Response<Body> {
+		Response::builder()
+			.header(header::CONTENT_TYPE, content_type)
+			.header(header::CONTENT_LENGTH, body.len())
+			.body(Body::from(body))
+			.unwrap()
+	}
+
+	#[test]
+	fn test_negotiate() {
+		assert_eq!(negotiate(&HeaderMap::new()), Encoding::Identity);
+		assert_eq!(negotiate(&accepting("gzip")), Encoding::Gzip);
+		assert_eq!(negotiate(&accepting("gzip, deflate, br")), Encoding::Brotli);
+		assert_eq!(
+			negotiate(&accepting("br;q=0.5, gzip;q=0.8")),
+			Encoding::Gzip
+		);
+		assert_eq!(negotiate(&accepting("GZIP")), Encoding::Gzip);
+		assert_eq!(negotiate(&accepting("deflate")), Encoding::Identity);
+		assert_eq!(negotiate(&accepting("identity")), Encoding::Identity);
+		assert_eq!(
+			negotiate(&accepting("br;q=0, gzip;q=0")),
+			Encoding::Identity
+		);
+		assert_eq!(negotiate(&accepting("*")), Encoding::Brotli);
+		assert_eq!(negotiate(&accepting("*;q=0.1, br;q=0")), Encoding::Gzip);
+	}
+
+	#[test]
+	fn test_is_compressible() {
+		assert!(is_compressible("application/javascript"));
+		assert!(is_compressible("text/html; charset=utf-8"));
+		assert!(is_compressible("image/svg+xml"));
+		assert!(is_compressible("application/vnd.api+json"));
+
+		assert!(!is_compressible("image/png"));
+		assert!(!is_compressible("font/woff2"));
+		assert!(!is_compressible("application/octet-stream"));
+		assert!(!is_compressible("application/zip"));
+	}
+
+	#[test]
+	fn test_bypasses_compression() {
+		let plain = Request::get("/").body(Body::empty()).unwrap();
+		assert!(!bypasses_compression(&plain));
+
+		let range = Request::get("/")
+			.header(header::RANGE, "bytes=0-10")
+			.body(Body::empty())
+			.unwrap();
+		assert!(bypasses_compression(&range));
+
+		let ws = Request::get("/")
+			.header(header::UPGRADE, "websocket")
+			.body(Body::empty())
+			.unwrap();
+		assert!(bypasses_compression(&ws));
+	}
+
+	#[tokio::test]
+	async fn test_compress_gzip() {
+		let text = "console.log('hello');\n".repeat(200);
+		let res = compress_response(
+			Encoding::Gzip,
+			text_response("application/javascript", text.clone()),
+		);
+
+		assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+		assert_eq!(res.headers()[header::VARY], "accept-encoding");
+		assert!(!res.headers().contains_key(header::CONTENT_LENGTH));
+
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		assert!(body.len() < text.len());
+		let mut decoded = String::new();
+		flate2::read::GzDecoder::new(&body[..])
+			.read_to_string(&mut decoded)
+			.unwrap();
+		assert_eq!(decoded, text);
+	}
+
+	#[tokio::test]
+	async fn test_compress_brotli() {
+		let text = "body { color: red; }\n".repeat(200);
+		let res = compress_response(Encoding::Brotli, text_response("text/css", text.clone()));
+		assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		let mut decoded = String::new();
+		BrotliDecoder::new(&body[..])
+			.read_to_string(&mut decoded)
+			.await
+			.unwrap();
+		assert_eq!(decoded, text);
+	}
+
+	#[tokio::test]
+	async fn test_skips_identity_small_and_binary() {
+		let text = "x".repeat(4096);
+
+		let res = compress_response(
+			Encoding::Identity,
+			text_response("text/plain", text.clone()),
+		);
+		assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+		assert_eq!(res.headers()[header::VARY], "accept-encoding");
+
+		let res = compress_response(Encoding::Gzip, text_response("text/plain", "x".into()));
+		assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+
+		let res = compress_response(Encoding::Gzip, text_response("image/png", text.clone()));
+		assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+		assert!(!res.headers().contains_key(header::VARY));
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		assert_eq!(body, text.as_bytes());
+	}
+
+	#[tokio::test]
+	async fn test_serve_precompressed() {
+		let dir = tempfile::tempdir().unwrap();
+		std::fs::create_dir_all(dir.path().join("out")).unwrap();
+		std::fs::write(dir.path().join("out/main.js"), "plain").unwrap();
+		std::fs::write(dir.path().join("out/main.js.br"), "brotli").unwrap();
+
+		let res = serve_precompressed(dir.path(), "out/main.js", Encoding::Brotli)
+			.await
+			.unwrap();
+		assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+		assert_eq!(
+			res.headers()[header::CONTENT_TYPE],
+			"application/javascript"
+		);
+		assert_eq!(res.headers()[header::CONTENT_LENGTH], "6");
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		assert_eq!(body, "brotli");
+
+		// no gzip sibling, or no encoding, means it's proxied as usual
+		assert!(
+			serve_precompressed(dir.path(), "out/main.js", Encoding::Gzip)
+				.await
+				.is_none()
+		);
+		assert!(
+			serve_precompressed(dir.path(), "out/main.js", Encoding::Identity)
+				.await
+				.is_none()
+		);
+		assert!(
+			serve_precompressed(dir.path(), "../out/main.js", Encoding::Brotli)
+				.await
+				.is_none()
+		);
+	}
+}
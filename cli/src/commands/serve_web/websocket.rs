@@ -0,0 +1,453 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fmt,
+	future::Future,
+	sync::atomic::{AtomicBool, Ordering},
+	time::Duration,
+};
+
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	time::{interval_at, Instant, Interval},
+};
+
+use crate::util::sync::Barrier;
+
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PONG: u8 = 0xA;
+/// An unmasked ping with no payload, as sent by servers.
+const PING_FRAME: [u8; 2] = [0x89, 0x00];
+/// A close frame with the 1001 "going away" status, sent when shutting down.
+const GOING_AWAY_FRAME: [u8; 4] = [0x88, 0x02, 0x03, 0xE9];
+
+/// Why a spliced websocket ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SpliceEnd {
+	ClientClosed,
+	ServerClosed,
+	/// The client didn't answer a ping before the next one was due.
+	MissedPong,
+}
+
+impl fmt::Display for SpliceEnd {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SpliceEnd::ClientClosed => write!(f, "closed by the client"),
+			SpliceEnd::ServerClosed => write!(f, "closed by the server"),
+			SpliceEnd::MissedPong => write!(f, "closed, the client did not answer a ping"),
+		}
+	}
+}
+
+/// Put in the extensions of an upgraded response, opened once the websocket
+/// has closed.
+pub struct Closed(pub Barrier<()>);
+
+/// Parses a frame header, returning its opcode and payload length once
+/// enough of it has been read.
+fn parse_header(h: &[u8]) -> Option<(u8, u64)> {
+	if h.len() < 2 {
+		return None;
+	}
+
+	let opcode = h[0] & 0x0F;
+	let mask_len = if h[1] & 0x80 != 0 { 4 } else { 0 };
+	let (len_len, short_len) = match h[1] & 0x7F {
+		126 => (2, None),
+		127 => (8, None),
+		n => (0, Some(n as u64)),
+	};
+
+	if h.len() < 2 + len_len + mask_len {
+		return None;
+	}
+
+	let len = match short_len {
+		Some(l) => l,
+		None => h[2..2 + len_len]
+			.iter()
+			.fold(0u64, |acc, b| (acc << 8) | *b as u64),
+	};
+
+	Some((opcode, len))
+}
+
+/// Tracks where frames start in one direction of a websocket, so that frames
+/// can be inserted between them or left out.
+#[derive(Default)]
+struct FrameTracker {
+	header: Vec<u8>,
+	remaining: u64,
+	/// Whether the payload of the current frame is kept.
+	keep: bool,
+}
+
+impl FrameTracker {
+	/// Advances over the bytes, calling `on_frame` with the opcode of every
+	/// frame that starts in them, and appending the bytes of the frames it
+	/// returns true for to `out`. Headers are only appended once they're
+	/// complete.
+	fn advance(
+		&mut self,
+		mut data: &[u8],
+		out: &mut Vec<u8>,
+		mut on_frame: impl FnMut(u8) -> bool,
+	) {
+		while !data.is_empty() {
+			if self.remaining > 0 {
+				let n = self.remaining.min(data.len() as u64) as usize;
+				if self.keep {
+					out.extend_from_slice(&data[..n]);
+				}
+				self.remaining -= n as u64;
+				data = &data[n..];
+				continue;
+			}
+
+			self.header.push(data[0]);
+			data = &data[1..];
+			if let Some((opcode, len)) = parse_header(&self.header) {
+				self.keep = on_frame(opcode);
+				if self.keep {
+					out.extend_from_slice(&self.header);
+				}
+				self.remaining = len;
+				self.header.clear();
+			}
+		}
+	}
+
+	fn at_boundary(&self) -> bool {
+		self.remaining == 0 && self.header.is_empty()
+	}
+}
+
+async fn tick(ticker: &mut Option<Interval>) {
+	match ticker {
+		Some(t) => {
+			t.tick().await;
+		}
+		None => std::future::pending().await,
+	}
+}
+
+/// Copies frames between the upgraded client and server connections. With a
+/// keepalive interval, the client is pinged when the interval passes, and the
+/// connection is ended if it hasn't answered by the next one. Once `going_away`
+/// resolves, the client is sent a close frame, and its frames keep being
+/// copied until either side closes. Nothing is sent to the client after a
+/// close frame, whether it's the server's or the proxy's.
+pub async fn splice(
+	client: impl AsyncRead + AsyncWrite,
+	server: impl AsyncRead + AsyncWrite,
+	keepalive: Option<Duration>,
+	going_away: impl Future<Output = ()>,
+) -> std::io::Result<SpliceEnd> {
+	let (mut client_read, mut client_write) = tokio::io::split(client);
+	let (mut server_read, mut server_write) = tokio::io::split(server);
+	let awaiting_pong = AtomicBool::new(false);
+
+	let to_client = async {
+		let mut tracker = FrameTracker::default();
+		let mut ticker = keepalive.map(|k| interval_at(Instant::now() + k, k));
+		let mut ping_due = false;
+		let (mut close_due, mut close_sent) = (false, false);
+		let mut buf = vec![0u8; 8192];
+		let mut out = Vec::with_capacity(buf.len());
+		tokio::pin!(going_away);
+
+		loop {
+			tokio::select! {
+				n = server_read.read(&mut buf) => {
+					let n = n?;
+					if n == 0 {
+						return Ok(SpliceEnd::ServerClosed);
+					}
+					out.clear();
+					tracker.advance(&buf[..n], &mut out, |opcode| {
+						if close_sent {
+							return false;
+						}
+						close_sent = opcode == OPCODE_CLOSE;
+						true
+					});
+					if !out.is_empty() {
+						client_write.write_all(&out).await?;
+					}
+				},
+				// nothing but the close handshake should follow a close frame
+				_ = tick(&mut ticker), if !close_sent => {
+					if awaiting_pong.load(Ordering::Relaxed) {
+						return Ok(SpliceEnd::MissedPong);
+					}
+					ping_due = true;
+				},
+				_ = &mut going_away, if !close_due && !close_sent => {
+					close_due = true;
+				},
+			}
+
+			// frames can't be sent in the middle of another one
+			if !tracker.at_boundary() {
+				continue;
+			}
+			if close_sent {
+				close_due = false;
+				ping_due = false;
+			} else if close_due {
+				client_write.write_all(&GOING_AWAY_FRAME).await?;
+				close_due = false;
+				close_sent = true;
+				ping_due = false;
+			} else if ping_due {
+				client_write.write_all(&PING_FRAME).await?;
+				awaiting_pong.store(true, Ordering::Relaxed);
+				ping_due = false;
+			}
+		}
+	};
+
+	let to_server = async {
+		let mut tracker = FrameTracker::default();
+		let mut buf = vec![0u8; 8192];
+		let mut out = Vec::with_capacity(buf.len());
+
+		loop {
+			let n = client_read.read(&mut buf).await?;
+			if n == 0 {
+				return Ok(SpliceEnd::ClientClosed);
+			}
+
+			out.clear();
+			tracker.advance(&buf[..n], &mut out, |opcode| {
+				// a pong answering the proxy's ping isn't for the server
+				!(opcode == OPCODE_PONG && awaiting_pong.swap(false, Ordering::Relaxed))
+			});
+			if !out.is_empty() {
+				server_write.write_all(&out).await?;
+			}
+		}
+	};
+
+	tokio::select! {
+		r = to_client => r,
+		r = to_server => r,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::DuplexStream;
+
+	use super::*;
+
+	const INTERVAL: Duration = Duration::from_millis(50);
+	/// A masked pong with no payload, as sent by clients.
+	const PONG_FRAME: [u8; 6] = [0x8A, 0x80, 1, 2, 3, 4];
+
+	fn start(
+		keepalive: Option<Duration>,
+	) -> (
+		DuplexStream,
+		DuplexStream,
+		tokio::task::JoinHandle<std::io::Result<SpliceEnd>>,
+	) {
+		let (client, client_proxy) = tokio::io::duplex(1024);
+		let (server, server_proxy) = tokio::io::duplex(1024);
+		let task = tokio::spawn(splice(
+			client_proxy,
+			server_proxy,
+			keepalive,
+			std::future::pending(),
+		));
+		(client, server, task)
+	}
+
+	#[test]
+	fn test_parse_header() {
+		assert_eq!(parse_header(&[0x81]), None);
+		assert_eq!(parse_header(&[0x81, 0x05]), Some((1, 5)));
+		assert_eq!(parse_header(&[0x82, 0x7E, 0x01]), None);
+		assert_eq!(parse_header(&[0x82, 0x7E, 0x01, 0x00]), Some((2, 256)));
+		assert_eq!(parse_header(&[0x8A, 0x80, 1, 2, 3]), None);
+		assert_eq!(parse_header(&PONG_FRAME), Some((OPCODE_PONG, 0)));
+
+		let mut long = vec![0x82, 0xFF];
+		long.extend_from_slice(&(1u64 << 40).to_be_bytes());
+		long.extend_from_slice(&[0; 4]);
+		assert_eq!(parse_header(&long), Some((2, 1 << 40)));
+	}
+
+	#[test]
+	fn test_frame_tracker() {
+		let mut t = FrameTracker::default();
+		let mut opcodes = vec![];
+		let mut out = vec![];
+		let mut on_frame = |o| {
+			opcodes.push(o);
+			o != OPCODE_PONG
+		};
+		t.advance(&[0x81, 0x03, b'a'], &mut out, &mut on_frame);
+		assert!(!t.at_boundary());
+		t.advance(&[b'b', b'c', 0x8A], &mut out, &mut on_frame);
+		assert!(!t.at_boundary());
+		t.advance(&[0x01, b'p', 0x82, 0x01, b'd'], &mut out, &mut on_frame);
+		assert!(t.at_boundary());
+		assert_eq!(opcodes, vec![1, OPCODE_PONG, 2]);
+		// the pong is left out
+		assert_eq!(out, [0x81, 0x03, b'a', b'b', b'c', 0x82, 0x01, b'd']);
+	}
+
+	#[tokio::test]
+	async fn test_passes_frames_through() {
+		let (mut client, mut server, task) = start(None);
+
+		server.write_all(&[0x81, 0x02, b'h', b'i']).await.unwrap();
+		let mut buf = [0u8; 4];
+		client.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf, [0x81, 0x02, b'h', b'i']);
+
+		client
+			.write_all(&[0x81, 0x81, 0, 0, 0, 0, b'x'])
+			.await
+			.unwrap();
+		let mut buf = [0u8; 7];
+		server.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf[6], b'x');
+
+		drop(client);
+		assert_eq!(task.await.unwrap().unwrap(), SpliceEnd::ClientClosed);
+	}
+
+	#[tokio::test]
+	async fn test_keepalive_with_answering_client() {
+		let (mut client, mut server, task) = start(Some(INTERVAL));
+
+		for _ in 0..3 {
+			let mut buf = [0u8; 2];
+			client.read_exact(&mut buf).await.unwrap();
+			assert_eq!(buf, PING_FRAME);
+			client.write_all(&PONG_FRAME).await.unwrap();
+		}
+		assert!(!task.is_finished());
+
+		// the pongs answered the proxy, so the server only gets what follows
+		let frame = [0x81, 0x81, 0, 0, 0, 0, b'x'];
+		client.write_all(&frame).await.unwrap();
+		let mut buf = [0u8; 7];
+		server.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf, frame);
+
+		task.abort();
+	}
+
+	#[tokio::test]
+	async fn test_missed_pong_closes_connection() {
+		let (mut client, _server, task) = start(Some(INTERVAL));
+
+		let mut buf = [0u8; 2];
+		client.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf, PING_FRAME);
+
+		// stop answering: the connection is cleaned up by the next interval
+		let end = tokio::time::timeout(INTERVAL * 4, task)
+			.await
+			.expect("expected the connection to be closed")
+			.unwrap()
+			.unwrap();
+		assert_eq!(end, SpliceEnd::MissedPong);
+		assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_ping_waits_for_frame_boundary() {
+		let (mut client, mut server, task) = start(Some(INTERVAL));
+
+		// start a frame, and finish it only once a ping is due
+		server.write_all(&[0x82, 0x04, 1, 2]).await.unwrap();
+		tokio::time::sleep(INTERVAL * 2).await;
+		server.write_all(&[3, 4]).await.unwrap();
+
+		let mut buf = [0u8; 8];
+		client.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf, [0x82, 0x04, 1, 2, 3, 4, 0x89, 0x00]);
+
+		task.abort();
+	}
+
+	#[tokio::test]
+	async fn test_going_away_sends_close() {
+		let (client, client_proxy) = tokio::io::duplex(1024);
+		let (mut server, server_proxy) = tokio::io::duplex(1024);
+		let (mut client_read, mut client_write) = tokio::io::split(client);
+		let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+		let task = tokio::spawn(splice(client_proxy, server_proxy, Some(INTERVAL), async {
+			rx.await.ok();
+		}));
+
+		// waits for the frame in progress to finish, once it's been read
+		server.write_all(&[0x82, 0x02, 1]).await.unwrap();
+		tokio::time::sleep(INTERVAL / 5).await;
+		tx.send(()).unwrap();
+		tokio::time::sleep(INTERVAL / 2).await;
+		server.write_all(&[2]).await.unwrap();
+
+		let mut buf = [0u8; 8];
+		client_read.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf[..4], [0x82, 0x02, 1, 2]);
+		assert_eq!(buf[4..], GOING_AWAY_FRAME);
+
+		// the connection stays up for the client to answer, without pings
+		tokio::time::sleep(INTERVAL * 3).await;
+		assert!(!task.is_finished());
+		client_write
+			.write_all(&[0x88, 0x80, 0, 0, 0, 0])
+			.await
+			.unwrap();
+		let mut buf = [0u8; 6];
+		server.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf[0], 0x88);
+
+		// the client already has a close frame, so nothing else is sent to it
+		server
+			.write_all(&[0x81, 0x01, b'x', 0x88, 0x00])
+			.await
+			.unwrap();
+		drop(server);
+		assert_eq!(task.await.unwrap().unwrap(), SpliceEnd::ServerClosed);
+		let mut rest = vec![];
+		client_read.read_to_end(&mut rest).await.unwrap();
+		assert!(rest.is_empty(), "{:?}", rest);
+	}
+
+	#[tokio::test]
+	async fn test_no_close_after_server_close() {
+		let (mut client, client_proxy) = tokio::io::duplex(1024);
+		let (mut server, server_proxy) = tokio::io::duplex(1024);
+		let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+		let task = tokio::spawn(splice(client_proxy, server_proxy, Some(INTERVAL), async {
+			rx.await.ok();
+		}));
+
+		server
+			.write_all(&[0x88, 0x00, 0x81, 0x01, b'x'])
+			.await
+			.unwrap();
+		let mut buf = [0u8; 2];
+		client.read_exact(&mut buf).await.unwrap();
+		assert_eq!(buf, [0x88, 0x00]);
+
+		// going away, or pings coming due, send nothing more
+		tx.send(()).unwrap();
+		tokio::time::sleep(INTERVAL * 3).await;
+		drop(server);
+		assert_eq!(task.await.unwrap().unwrap(), SpliceEnd::ServerClosed);
+		let mut rest = vec![];
+		client.read_to_end(&mut rest).await.unwrap();
+		assert!(rest.is_empty(), "{:?}", rest);
+	}
+}
@@ -9,8 +9,10 @@ use futures::{stream::FuturesUnordered, StreamExt};
 use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::{
+	io::Read,
 	net::{IpAddr, Ipv4Addr, SocketAddr},
 	str::FromStr,
+	sync::Arc,
 	time::Duration,
 };
 use sysinfo::Pid;
@@ -21,47 +23,72 @@ use tokio::{
 
 use super::{
 	args::{
-		AuthProvider, CliCore, CommandShellArgs, ExistingTunnelArgs, TunnelForwardArgs,
-		TunnelRenameArgs, TunnelServeArgs, TunnelServiceSubCommands, TunnelUserSubCommands,
+		AuthProvider, CliCore, CommandShellArgs, ExistingTunnelArgs, GlobalOptions, OutputFormat,
+		PortVisibility, TunnelDoctorArgs, TunnelForwardArgs, TunnelKillArgs, TunnelLogArgs,
+		TunnelMetricsArgs, TunnelPingArgs, TunnelPortsArgs, TunnelRenameArgs, TunnelRpcMethodsArgs,
+		TunnelServeArgs, TunnelServerSubCommands, TunnelServerUninstallArgs,
+		TunnelServiceInstallArgs, TunnelServiceInternalRunArgs, TunnelServiceSubCommands,
+		TunnelStatusArgs, TunnelUserLoginArgs, TunnelUserSubCommands,
 	},
+	output::{to_json, UserShowOutput},
 	CommandContext,
 };
 
 use crate::{
-	async_pipe::{get_socket_name, listen_socket_rw_stream, AsyncRWAccepter},
-	auth::Auth,
+	async_pipe::{
+		get_socket_name, get_socket_rw_stream, listen_socket_rw_stream, socket_stream_split,
+		AsyncRWAccepter,
+	},
+	auth::{
+		probe_keyring, set_configured_token_storage, set_default_auth_provider, Auth, AuthOptions,
+		AuthProfiles, Revocation, DEFAULT_KEYRING_TIMEOUT,
+	},
 	constants::{
-		APPLICATION_NAME, CONTROL_PORT, IS_A_TTY, TUNNEL_CLI_LOCK_NAME, TUNNEL_SERVICE_LOCK_NAME,
+		APPLICATION_NAME, CONTROL_PORT, DEFAULT_DATA_PARENT_DIR, IS_A_TTY, TUNNEL_CLI_LOCK_NAME,
+		TUNNEL_SERVICE_LOCK_NAME,
 	},
 	log,
+	policy::{self, PolicyFeature},
+	rpc::{MethodInfo, RpcMetrics, METHOD_LIST_METHODS},
 	state::LauncherPaths,
 	tunnels::{
+		challenge_limiter::{ChallengeLimiter, ChallengeLimits},
 		code_server::CodeServerArgs,
 		create_service_manager,
 		dev_tunnels::{self, DevTunnels},
-		legal, local_forwarding,
-		paths::get_all_servers,
-		protocol, serve_stream,
-		shutdown_signal::ShutdownRequest,
-		singleton_client::do_single_rpc_call,
+		export_logs, install_panic_logger, legal, local_forwarding, measure_ping,
+		paths::{prune_stopped_servers, uninstall_server, CachedServerRegistry},
+		prepare_service_install, protocol, restart_service, serve_stream,
+		shutdown_signal::{Drain, ShutdownRequest, DEFAULT_DRAIN_TIMEOUT},
+		singleton_client::{connect_singleton_rpc, do_single_rpc_call},
 		singleton_server::{
 			make_singleton_server, start_singleton_server, BroadcastLogSink, SingletonServerArgs,
 		},
-		AuthRequired, Next, ServeStreamParams, ServiceContainer, ServiceManager,
+		supervise_tunnel, warn_on_interop_tunnel, AuthRequired, CachedInteropProbe,
+		CommandInteropProbe, ControlConnection, FailureHistory, FailureSummary, InteropProbe,
+		InteropTunnel, Next, PingSummary, RestartBackoff, ServeStreamParams, ServiceContainer,
+		ServiceLogOptions, ServiceManager, ServiceScope, CONNECTION_ACTIVITY, CONTROL_RPC_STATS,
+		SERVER_USAGE,
 	},
 	util::{
 		app_lock::AppMutex,
 		command::new_std_command,
 		errors::{wrap, AnyError, CodeError},
-		machine::canonical_exe,
-		prereqs::PreReqChecker,
+		machine::{canonical_exe, get_user_home_dir},
+		os::collect_host_info,
+		prereqs::{PreReqChecker, ProbeOutput, Requirement},
 	},
 };
 use crate::{
-	singleton::{acquire_singleton, SingletonConnection},
+	singleton::{
+		acquire_singleton, acquire_singleton_with, kill_singleton, ActivatedSocket, KillStep,
+		SingletonConnection,
+	},
 	tunnels::{
 		dev_tunnels::ActiveTunnel,
-		singleton_client::{start_singleton_client, SingletonClientArgs},
+		singleton_client::{
+			connect_to_running_tunnel, start_singleton_client, SingletonClientArgs,
+		},
 		SleepInhibitor,
 	},
 };
@@ -105,11 +132,12 @@ fn fulfill_existing_tunnel_args(
 
 struct TunnelServiceContainer {
 	args: CliCore,
+	run_args: TunnelServiceInternalRunArgs,
 }
 
 impl TunnelServiceContainer {
-	fn new(args: CliCore) -> Self {
-		Self { args }
+	fn new(args: CliCore, run_args: TunnelServiceInternalRunArgs) -> Self {
+		Self { args, run_args }
 	}
 }
 
@@ -120,24 +148,81 @@ impl ServiceContainer for TunnelServiceContainer {
 		log: log::Logger,
 		launcher_paths: LauncherPaths,
 	) -> Result<(), AnyError> {
-		let csa = (&self.args).into();
-		serve_with_csa(
-			launcher_paths,
-			log,
-			TunnelServeArgs {
-				random_name: true, // avoid prompting
-				..Default::default()
+		// the service can't prompt, so consent must have been given on install
+		legal::require_persisted_consent(&launcher_paths)?;
+
+		install_panic_logger(log.clone());
+
+		let csa: CodeServerArgs = (&self.args).into();
+		let auth_options = auth_options(&self.args.global_options);
+		let idle_timeout = self.run_args.idle_timeout;
+		let drain_timeout = self.run_args.drain_timeout;
+		let history = FailureHistory::new(&launcher_paths);
+		let tunnel_log = log.clone();
+		let metrics_log = self
+			.run_args
+			.metrics_log_interval
+			.and_then(|m| m.checked_mul(60))
+			.filter(|s| *s > 0)
+			.map(|s| tokio::spawn(log_rpc_metrics(log.clone(), Duration::from_secs(s))));
+
+		let r = supervise_tunnel(
+			&log,
+			RestartBackoff::new(self.run_args.restart_limit),
+			&history,
+			move || {
+				serve_with_csa(
+					launcher_paths.clone(),
+					tunnel_log.clone(),
+					auth_options.clone(),
+					TunnelServeArgs {
+						random_name: true, // avoid prompting
+						idle_timeout,
+						drain_timeout,
+						..Default::default()
+					},
+					csa.clone(),
+					TUNNEL_SERVICE_LOCK_NAME,
+				)
 			},
-			csa,
-			TUNNEL_SERVICE_LOCK_NAME,
 		)
-		.await?;
-		Ok(())
+		.await;
+
+		if let Some(m) = metrics_log {
+			m.abort();
+		}
+
+		match r? {
+			0 => Ok(()),
+			code => Err(CodeError::TunnelExitedWithCode(code).into()),
+		}
+	}
+
+	fn log_rotation(&self) -> log::LogRotation {
+		self.args.global_options.log_rotation()
+	}
+}
+
+async fn log_rpc_metrics(log: log::Logger, interval: Duration) {
+	let mut interval = tokio::time::interval(interval);
+	interval.tick().await; // the first tick completes immediately
+	loop {
+		interval.tick().await;
+		trace!(
+			log,
+			"rpc metrics: {}",
+			serde_json::to_string(&CONTROL_RPC_STATS.snapshot()).unwrap()
+		);
 	}
 }
 
 pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Result<i32, AnyError> {
-	let platform = PreReqChecker::new().verify().await?;
+	let platform = ctx.prereqs().verify().await?;
+	let limiter = Arc::new(ChallengeLimiter::new(
+		ctx.log.clone(),
+		args.challenge_limits(),
+		Some(ctx.paths.auth_audit_log()),
+	));
 	let mut shutdown_reqs = vec![ShutdownRequest::CtrlC];
 	if let Some(p) = args.parent_process_id.and_then(|p| Pid::from_str(&p).ok()) {
 		shutdown_reqs.push(ShutdownRequest::ParentProcessKilled(p));
@@ -153,6 +238,8 @@ pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Resul
 			.unwrap_or(AuthRequired::VSDA),
 		exit_barrier: ShutdownRequest::create_rx(shutdown_reqs),
 		code_server_args: (&ctx.args).into(),
+		drain: None,
+		challenge_limiter: None,
 	};
 
 	let mut listener: Box<dyn AsyncRWAccepter> = match (args.on_port, &args.on_host, args.on_socket)
@@ -188,6 +275,8 @@ pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Resul
 			Box::new(listener)
 		}
 		_ => {
+			params.challenge_limiter =
+				Some(limiter.for_peer(format!("stdio:pid-{}", std::process::id())));
 			serve_stream(tokio::io::stdin(), tokio::io::stderr(), params).await;
 			return Ok(0);
 		}
@@ -200,7 +289,10 @@ pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Resul
 			Some(_) = servers.next() => {},
 			socket = listener.accept_rw() => {
 				match socket {
-					Ok((read, write)) => servers.push(serve_stream(read, write, params.clone())),
+					Ok((read, write, peer)) => servers.push(serve_stream(read, write, ServeStreamParams {
+						challenge_limiter: Some(limiter.for_peer(peer)),
+						..params.clone()
+					})),
 					Err(e) => {
 						error!(params.log, &format!("Error accepting connection: {}", e));
 						return Ok(1);
@@ -220,10 +312,22 @@ pub async fn service(
 	ctx: CommandContext,
 	service_args: TunnelServiceSubCommands,
 ) -> Result<i32, AnyError> {
-	let manager = create_service_manager(ctx.log.clone(), &ctx.paths);
+	let manager = create_service_manager(ctx.log.clone(), &ctx.paths, ServiceScope::User);
+	if let TunnelServiceSubCommands::Install(_) = &service_args {
+		policy::current().ensure_allowed(PolicyFeature::TunnelHosting)?;
+	}
+
 	match service_args {
+		TunnelServiceSubCommands::Install(args) if args.system => {
+			install_system_service(&ctx, args).await?;
+		}
 		TunnelServiceSubCommands::Install(args) => {
-			let auth = Auth::new(&ctx.paths, ctx.log.clone());
+			// the service can't ask which provider to log in with
+			if let Some(provider) = args.default_provider {
+				set_default_auth_provider(&ctx.paths, provider.into())?;
+			}
+
+			let auth = get_auth(&ctx);
 
 			if let Some(name) = &args.name {
 				// ensure the name matches, and tunnel exists
@@ -239,31 +343,99 @@ pub async fn service(
 			legal::require_consent(&ctx.paths, args.accept_server_license_terms)?;
 
 			let current_exe = canonical_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+			prepare_service_install(&ctx.log, &manager, &current_exe, args.force).await?;
+			register_event_log_source(&ctx);
+
+			let data_dir = ctx.paths.root().as_os_str().to_string_lossy().to_string();
+			let idle_timeout = args.idle_timeout.to_string();
+			let drain_timeout = args.drain_timeout.map(|t| t.to_string());
+			let token_storage = ctx.args.global_options.token_storage.map(|s| s.to_string());
+			let mut log_args = vec![];
+			ctx.args.global_options.add_service_log_args(&mut log_args);
+			ctx.args
+				.global_options
+				.add_service_config_args(&mut log_args);
+			ctx.args
+				.global_options
+				.add_service_server_args(&mut log_args);
+			let mut run_args = vec!["--verbose", "--cli-data-dir", data_dir.as_str()];
+			if let Some(profile) = &ctx.args.global_options.profile {
+				run_args.extend_from_slice(&["--profile", profile.as_str()]);
+			}
+			if let Some(storage) = &token_storage {
+				run_args.extend_from_slice(&["--token-storage", storage.as_str()]);
+			}
+			run_args.extend(log_args.iter().map(|a| a.as_str()));
+			run_args.extend_from_slice(&["tunnel", "service", "internal-run"]);
+			if let Some(t) = &drain_timeout {
+				run_args.extend_from_slice(&["--drain-timeout", t.as_str()]);
+			}
 
-			manager
-				.register(
-					current_exe,
-					&[
-						"--verbose",
-						"--cli-data-dir",
-						ctx.paths.root().as_os_str().to_string_lossy().as_ref(),
-						"tunnel",
-						"service",
-						"internal-run",
-					],
-				)
-				.await?;
-			ctx.log.result(format!("Service successfully installed! You can use `{} tunnel service log` to monitor it, and `{} tunnel service uninstall` to remove it.", APPLICATION_NAME, APPLICATION_NAME));
+			let mut env = args.env.clone();
+			ctx.args.global_options.add_service_env(&mut env);
+			if args.socket_activated {
+				run_args.extend_from_slice(&["--idle-timeout", idle_timeout.as_str()]);
+				manager
+					.register_socket_activated(current_exe, &run_args, &env)
+					.await?;
+			} else {
+				manager.register(current_exe, &run_args, &env).await?;
+			}
+			ctx.log.result("Service successfully installed!");
+			ctx.log.notice(format!("You can use `{} tunnel service log` to monitor it, and `{} tunnel service uninstall` to remove it.", APPLICATION_NAME, APPLICATION_NAME));
+		}
+		TunnelServiceSubCommands::Uninstall(args) if args.system => {
+			create_service_manager(
+				ctx.log.clone(),
+				&ctx.paths,
+				ServiceScope::System { user: None },
+			)
+			.unregister()
+			.await?;
 		}
-		TunnelServiceSubCommands::Uninstall => {
+		TunnelServiceSubCommands::Uninstall(_) => {
 			manager.unregister().await?;
 		}
-		TunnelServiceSubCommands::Log => {
-			manager.show_logs().await?;
+		TunnelServiceSubCommands::Restart(args) => {
+			let lock_file = ctx.paths.tunnel_lockfile();
+			let restarted_at = chrono::Utc::now();
+			let status = restart_service(&manager, Duration::from_secs(args.timeout), || {
+				is_tunnel_ready(&lock_file, &ctx.log, restarted_at)
+			})
+			.await?;
+			ctx.log.result(status.to_string().trim_end());
+		}
+		TunnelServiceSubCommands::Log(args) => match &args.output_file {
+			Some(path) => {
+				let since = args
+					.since
+					.and_then(|d| chrono::Duration::from_std(d).ok())
+					.and_then(|d| chrono::Utc::now().checked_sub_signed(d));
+				let summary = export_logs(&manager, since, path).await?;
+				ctx.log.result(summary.to_string());
+			}
+			None => {
+				manager
+					.show_logs(&ServiceLogOptions {
+						follow: args.follow,
+						lines: args.lines,
+					})
+					.await?;
+			}
+		},
+		TunnelServiceSubCommands::Status(args) => {
+			let status = manager.status().await?;
+			let format = ctx.args.global_options.output_format(args.json);
+			ctx.log.result(format.render(&status));
+
+			return Ok(if status.installed { 0 } else { 1 });
 		}
-		TunnelServiceSubCommands::InternalRun => {
+		TunnelServiceSubCommands::InternalRun(args) => {
 			manager
-				.run(ctx.paths.clone(), TunnelServiceContainer::new(ctx.args))
+				.run(
+					ctx.paths.clone(),
+					TunnelServiceContainer::new(ctx.args, args),
+				)
 				.await?;
 		}
 	}
@@ -271,28 +443,344 @@ pub async fn service(
 	Ok(0)
 }
 
+/// Gets whether a tunnel started after `since` is running and connected.
+async fn is_tunnel_ready(
+	lock_file: &std::path::Path,
+	log: &log::Logger,
+	since: chrono::DateTime<chrono::Utc>,
+) -> bool {
+	match do_single_rpc_call::<_, protocol::singleton::StatusWithTunnelName>(
+		lock_file,
+		log.clone(),
+		protocol::singleton::METHOD_STATUS,
+		protocol::EmptyObject {},
+	)
+	.await
+	{
+		Ok(s) => {
+			s.status.started_at >= since
+				&& matches!(s.status.tunnel, protocol::singleton::TunnelState::Connected)
+		}
+		Err(_) => false,
+	}
+}
+
+/// Installs a system-level service that runs as the `--service-user`. The
+/// service can't log in interactively, so the user must already have
+/// credentials stored in their data directory.
+async fn install_system_service(
+	ctx: &CommandContext,
+	args: TunnelServiceInstallArgs,
+) -> Result<(), AnyError> {
+	let scope = ServiceScope::System {
+		user: args.service_user.clone(),
+	};
+	scope.ensure_permitted()?;
+
+	let user = args.service_user.expect("expected service user"); // enforced by clap
+	let home =
+		get_user_home_dir(&user).ok_or_else(|| CodeError::ServiceUserNotFound(user.clone()))?;
+	let paths =
+		LauncherPaths::new_without_replacements(home.join(DEFAULT_DATA_PARENT_DIR).join("cli"));
+
+	let profile = ctx
+		.args
+		.global_options
+		.profile
+		.clone()
+		.unwrap_or_else(|| AuthProfiles::new(&paths).active());
+	if !Auth::file_storage_path(&paths, &profile).exists()
+		&& !Auth::encrypted_file_storage_path(&paths, &profile).exists()
+	{
+		return Err(CodeError::ServiceUserNotLoggedIn {
+			user,
+			data_dir: paths.root().display().to_string(),
+		}
+		.into());
+	}
+
+	legal::require_consent(&paths, args.accept_server_license_terms)?;
+
+	let current_exe = canonical_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+	let manager = create_service_manager(ctx.log.clone(), &paths, scope);
+	prepare_service_install(&ctx.log, &manager, &current_exe, args.force).await?;
+	register_event_log_source(ctx);
+
+	let data_dir = paths.root().as_os_str().to_string_lossy().to_string();
+	let drain_timeout = args.drain_timeout.map(|t| t.to_string());
+	let mut log_args = vec![];
+	ctx.args.global_options.add_service_log_args(&mut log_args);
+	ctx.args
+		.global_options
+		.add_service_config_args(&mut log_args);
+	ctx.args
+		.global_options
+		.add_service_server_args(&mut log_args);
+	let mut run_args = vec![
+		"--verbose",
+		"--cli-data-dir",
+		data_dir.as_str(),
+		"--profile",
+		profile.as_str(),
+	];
+	run_args.extend(log_args.iter().map(|a| a.as_str()));
+	run_args.extend_from_slice(&["tunnel", "service", "internal-run"]);
+	if let Some(t) = &drain_timeout {
+		run_args.extend_from_slice(&["--drain-timeout", t.as_str()]);
+	}
+
+	let mut env = args.env.clone();
+	ctx.args.global_options.add_service_env(&mut env);
+	manager.register(current_exe, &run_args, &env).await?;
+	ctx.log.result(format!(
+		"System service successfully installed to run as {}!",
+		user
+	));
+	ctx.log.notice(format!(
+		"You can use `sudo {} tunnel service uninstall --system` to remove it.",
+		APPLICATION_NAME
+	));
+
+	Ok(())
+}
+
+/// Registers the Event Log source if the service logs to it. Writing to the
+/// log works without this, but needs administrator rights, so it's done on
+/// install rather than when the service starts.
+#[cfg(windows)]
+fn register_event_log_source(ctx: &CommandContext) {
+	if !ctx
+		.args
+		.global_options
+		.log_sink
+		.contains(&log::LogSinkKind::Eventlog)
+	{
+		return;
+	}
+
+	if let Err(e) = log::system::register_event_source(crate::constants::PRODUCT_NAME_LONG) {
+		warning!(
+			ctx.log,
+			"Could not register the event log source, so its messages may not show in the Event Viewer. Install the service as an administrator to fix this: {}",
+			e
+		);
+	}
+}
+
+#[cfg(not(windows))]
+fn register_event_log_source(_ctx: &CommandContext) {}
+
+/// Reads a token piped to stdin, so that it doesn't show up in process arguments.
+fn read_access_token_from_stdin() -> Result<String, AnyError> {
+	let mut token = String::new();
+	std::io::stdin()
+		.read_to_string(&mut token)
+		.map_err(|e| wrap(e, "error reading access token from stdin"))?;
+
+	let token = token.trim();
+	if token.is_empty() {
+		return Err(CodeError::EmptyAccessToken.into());
+	}
+
+	Ok(token.to_string())
+}
+
+fn auth_options(options: &GlobalOptions) -> AuthOptions {
+	AuthOptions {
+		profile: options.profile.clone(),
+		token_storage: options.token_storage,
+		token_passphrase: options.token_passphrase.clone(),
+		refresh_margin: None,
+		auth_cloud: options.auth_cloud,
+		keyring_timeout: options.keyring_timeout.map(Duration::from_secs),
+		extra_scopes: vec![],
+		default_provider: None,
+	}
+}
+
+/// Gets an Auth for the profile and storage given in the global options.
+fn get_auth(ctx: &CommandContext) -> Auth {
+	Auth::with_options(
+		&ctx.paths,
+		ctx.log.clone(),
+		&auth_options(&ctx.args.global_options),
+	)
+}
+
 pub async fn user(ctx: CommandContext, user_args: TunnelUserSubCommands) -> Result<i32, AnyError> {
-	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	// a storage chosen when logging in is used from then on
+	if let (TunnelUserSubCommands::Login(_), Some(kind)) =
+		(&user_args, ctx.args.global_options.token_storage)
+	{
+		set_configured_token_storage(&ctx.paths, kind)?;
+	}
+
+	let auth = match &user_args {
+		TunnelUserSubCommands::Login(args) => Auth::with_options(
+			&ctx.paths,
+			ctx.log.clone(),
+			&AuthOptions {
+				extra_scopes: args.scopes.clone(),
+				..auth_options(&ctx.args.global_options)
+			},
+		),
+		_ => get_auth(&ctx),
+	};
 	match user_args {
-		TunnelUserSubCommands::Login(mut login_args) => {
-			auth.login(
-				login_args.provider.map(|p| p.into()),
-				login_args.access_token.take(),
-				login_args.refresh_token.take(),
-			)
-			.await?;
+		TunnelUserSubCommands::Login(args) if args.start => {
+			let started = auth
+				.start_login(args.login.provider.map(|p| p.into()))
+				.await?;
+			if args.json {
+				ctx.log.result(to_json(&started));
+			} else {
+				ctx.log.result(format!(
+					"To grant access to the server, please log into {} and use code {}, then run `{} tunnel user login --continue {}`",
+					started.verification_uri, started.user_code, APPLICATION_NAME, started.handle
+				));
+			}
 		}
-		TunnelUserSubCommands::Logout => {
-			auth.clear_credentials()?;
+		TunnelUserSubCommands::Login(TunnelUserLoginArgs {
+			continue_login: Some(handle),
+			..
+		}) => {
+			auth.continue_login(&handle).await?;
+		}
+		TunnelUserSubCommands::Login(args) if args.upgrade_scopes => {
+			let creds = auth.upgrade_scopes().await?;
+			if let Some(scopes) = creds.scopes() {
+				ctx.log
+					.result(format!("Logged in with scopes: {}", scopes.join(", ")));
+			}
 		}
-		TunnelUserSubCommands::Show => {
-			if let Ok(Some(_)) = auth.get_current_credential() {
-				ctx.log.result("logged in");
+		TunnelUserSubCommands::Login(mut args) => {
+			let personal_access_token = if args.access_token_stdin {
+				Some(read_access_token_from_stdin()?)
+			} else if args.login.access_token.is_none() {
+				std::env::var("VSCODE_CLI_GITHUB_TOKEN")
+					.ok()
+					.filter(|t| !t.is_empty())
 			} else {
-				ctx.log.result("not logged in");
+				None
+			};
+
+			match personal_access_token {
+				Some(_) if matches!(args.login.provider, Some(AuthProvider::Microsoft)) => {
+					return Err(CodeError::AccessTokenStdinNeedsGithub.into());
+				}
+				Some(token) => {
+					auth.login_with_personal_access_token(token).await?;
+				}
+				None => {
+					auth.login(
+						args.login.provider.map(|p| p.into()),
+						args.login.access_token.take(),
+						args.login.refresh_token.take(),
+					)
+					.await?;
+				}
+			}
+		}
+		TunnelUserSubCommands::Logout(args) => {
+			// revoke first, since the token is needed to do so, but clear the
+			// credentials regardless of whether it worked
+			let revoked = match args.local_only {
+				true => None,
+				false => Some(auth.revoke_credentials().await),
+			};
+			auth.clear_credentials()?;
+
+			match revoked {
+				None => ctx.log.result("Logged out on this machine"),
+				Some(Ok(None)) => ctx.log.result("Not logged in"),
+				Some(Ok(Some(Revocation::Revoked))) => {
+					ctx.log.result("Revoked the token and logged out")
+				}
+				Some(Ok(Some(Revocation::AlreadyRevoked))) => ctx
+					.log
+					.result("The token was already revoked, logged out"),
+				Some(Ok(Some(Revocation::Unsupported))) => ctx.log.result(
+					"Logged out. Microsoft tokens can't be revoked from here and stay valid until they expire. To end all sessions now, sign out everywhere at https://myaccount.microsoft.com",
+				),
+				Some(Err(e)) => return Err(CodeError::TokenRevocationFailed(e.to_string()).into()),
+			}
+		}
+		TunnelUserSubCommands::Show(args) => {
+			let format = ctx.args.global_options.output_format(args.json);
+			let mut output = UserShowOutput {
+				// a store that can't be read is an error, not a logged out user
+				info: auth.get_credential_info()?,
+				default_provider: None,
+				verbose: ctx.args.global_options.verbose,
+			};
+
+			let valid = match &mut output.info {
+				Some(info) => {
+					if args.validate {
+						info.validation = auth.validate_credential().await?;
+					}
+					info.validation.as_ref().map(|v| v.valid).unwrap_or(true)
+				}
+				None => {
+					output.default_provider = auth.default_provider();
+					false
+				}
+			};
+
+			ctx.log.result(format.render(&output));
+			if !valid {
 				return Ok(1);
 			}
 		}
+		TunnelUserSubCommands::SetDefaultProvider(args) => {
+			set_default_auth_provider(&ctx.paths, args.provider.into())?;
+			ctx.log.result(format!(
+				"Logins will use {} when no provider is chosen",
+				crate::auth::AuthProvider::from(args.provider)
+			));
+		}
+		TunnelUserSubCommands::Switch(args) => {
+			AuthProfiles::new(&ctx.paths).switch(&args.profile)?;
+			ctx.log
+				.result(format!("Switched to auth profile {}", args.profile));
+		}
+		TunnelUserSubCommands::List => {
+			let profiles = AuthProfiles::new(&ctx.paths);
+			let active = profiles.active();
+			for name in profiles.list() {
+				let options = AuthOptions {
+					profile: Some(name.clone()),
+					..auth_options(&ctx.args.global_options)
+				};
+				let status = match Auth::with_options(&ctx.paths, ctx.log.clone(), &options)
+					.get_current_credential()
+				{
+					Ok(Some(c)) => format!("logged in with a {}", c.provider()),
+					Ok(None) => "not logged in".to_string(),
+					Err(e) => format!("credentials could not be read: {}", e),
+				};
+				let marker = if name == active { '*' } else { ' ' };
+				ctx.log.result(format!("{} {} ({})", marker, name, status));
+			}
+		}
+		TunnelUserSubCommands::MigrateStorage(args) => {
+			for name in AuthProfiles::new(&ctx.paths).list() {
+				let options = AuthOptions {
+					profile: Some(name.clone()),
+					..auth_options(&ctx.args.global_options)
+				};
+				if Auth::with_options(&ctx.paths, ctx.log.clone(), &options)
+					.migrate_storage(args.to)?
+				{
+					ctx.log.result(format!(
+						"Moved credentials for profile {} to {} storage",
+						name, args.to
+					));
+				}
+			}
+
+			set_configured_token_storage(&ctx.paths, args.to)?;
+		}
 	}
 
 	Ok(0)
@@ -300,7 +788,7 @@ pub async fn user(ctx: CommandContext, user_args: TunnelUserSubCommands) -> Resu
 
 /// Remove the tunnel used by this tunnel, if any.
 pub async fn rename(ctx: CommandContext, rename_args: TunnelRenameArgs) -> Result<i32, AnyError> {
-	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let auth = get_auth(&ctx);
 	let mut dt = dev_tunnels::DevTunnels::new_remote_tunnel(&ctx.log, auth, &ctx.paths);
 	dt.rename_tunnel(&rename_args.name).await?;
 	ctx.log.result(format!(
@@ -313,7 +801,7 @@ pub async fn rename(ctx: CommandContext, rename_args: TunnelRenameArgs) -> Resul
 
 /// Remove the tunnel used by this tunnel, if any.
 pub async fn unregister(ctx: CommandContext) -> Result<i32, AnyError> {
-	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let auth = get_auth(&ctx);
 	let mut dt = dev_tunnels::DevTunnels::new_remote_tunnel(&ctx.log, auth, &ctx.paths);
 	dt.remove_tunnel().await?;
 	Ok(0)
@@ -331,25 +819,60 @@ pub async fn restart(ctx: CommandContext) -> Result<i32, AnyError> {
 	.map_err(|e| e.into())
 }
 
-pub async fn kill(ctx: CommandContext) -> Result<i32, AnyError> {
-	do_single_rpc_call::<_, ()>(
-		&ctx.paths.tunnel_lockfile(),
-		ctx.log,
-		protocol::singleton::METHOD_SHUTDOWN,
-		protocol::EmptyObject {},
+pub async fn kill(ctx: CommandContext, args: TunnelKillArgs) -> Result<i32, AnyError> {
+	let lock_file = ctx.paths.tunnel_lockfile();
+	let graceful = match args.force {
+		true => None,
+		false => Some(do_single_rpc_call::<_, ()>(
+			&lock_file,
+			ctx.log.clone(),
+			protocol::singleton::METHOD_SHUTDOWN,
+			protocol::EmptyObject {},
+		)),
+	};
+
+	let steps = kill_singleton(
+		&ctx.log,
+		&lock_file,
+		graceful,
+		Duration::from_secs(args.timeout),
 	)
-	.await
-	.map(|_| 0)
-	.map_err(|e| e.into())
+	.await?;
+
+	if steps.is_empty() {
+		ctx.log.result("No tunnel is running");
+	}
+
+	for step in steps {
+		ctx.log.result(match step {
+			KillStep::ShutDownGracefully => "The tunnel shut down".to_string(),
+			KillStep::GracefulShutdownFailed(reason) => {
+				format!("The tunnel didn't shut down when asked: {}", reason)
+			}
+			KillStep::Terminated(pid) => format!("Terminated the tunnel process {}", pid),
+			KillStep::OwnerNotRunning(pid) => {
+				format!("The tunnel process {} is no longer running", pid)
+			}
+			KillStep::RemovedFile(path) => format!("Removed {}", path.display()),
+		});
+	}
+
+	Ok(0)
 }
 
 #[derive(Serialize)]
 pub struct StatusOutput {
 	pub tunnel: Option<protocol::singleton::StatusWithTunnelName>,
 	pub service_installed: bool,
+	/// Tunnel running on the other side of WSL, if none is running here.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub interop: Option<InteropTunnel>,
+	/// Failures of the tunnel that the service restarted it after.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub restarts: Option<FailureSummary>,
 }
 
-pub async fn status(ctx: CommandContext) -> Result<i32, AnyError> {
+pub async fn status(ctx: CommandContext, args: TunnelStatusArgs) -> Result<i32, AnyError> {
 	let tunnel = do_single_rpc_call::<_, protocol::singleton::StatusWithTunnelName>(
 		&ctx.paths.tunnel_lockfile(),
 		ctx.log.clone(),
@@ -358,40 +881,456 @@ pub async fn status(ctx: CommandContext) -> Result<i32, AnyError> {
 	)
 	.await;
 
-	let service_installed = create_service_manager(ctx.log.clone(), &ctx.paths)
+	let service_installed = create_service_manager(ctx.log.clone(), &ctx.paths, ServiceScope::User)
 		.is_installed()
 		.await
 		.unwrap_or(false);
 
-	ctx.log.result(
-		serde_json::to_string(&StatusOutput {
-			service_installed,
-			tunnel: match tunnel {
-				Ok(s) => Some(s),
-				Err(CodeError::NoRunningTunnel | CodeError::AsyncPipeFailed(_)) => None,
-				Err(e) => return Err(e.into()),
+	let tunnel = match tunnel {
+		Ok(s) => Some(s),
+		Err(CodeError::NoRunningTunnel | CodeError::AsyncPipeFailed(_)) => None,
+		Err(e) => return Err(e.into()),
+	};
+
+	let interop = if tunnel.is_none() && !args.ignore_interop {
+		CachedInteropProbe::new(&ctx.paths, CommandInteropProbe::new(ctx.log.clone()))
+			.probe()
+			.await
+	} else {
+		None
+	};
+
+	let output = StatusOutput {
+		service_installed,
+		tunnel,
+		interop,
+		restarts: FailureHistory::new(&ctx.paths).summary(chrono::Utc::now()),
+	};
+	ctx.log
+		.result(ctx.args.global_options.output_format(false).render(&output));
+
+	Ok(0)
+}
+
+/// Lists the methods the running tunnel's singleton socket supports.
+pub async fn rpc_methods(ctx: CommandContext, args: TunnelRpcMethodsArgs) -> Result<i32, AnyError> {
+	let methods = do_single_rpc_call::<_, Vec<MethodInfo>>(
+		&ctx.paths.tunnel_lockfile(),
+		ctx.log.clone(),
+		METHOD_LIST_METHODS,
+		protocol::EmptyObject {},
+	)
+	.await?;
+
+	if ctx.args.global_options.output_format(args.json) == OutputFormat::Json {
+		ctx.log.result(to_json(&methods));
+	} else {
+		for method in &methods {
+			match &method.since {
+				Some(v) => ctx.log.result(format!("{} (since {})", method.name, v)),
+				None => ctx.log.result(&method.name),
+			}
+		}
+	}
+
+	Ok(0)
+}
+
+/// Prints call counts and latency buckets of the running tunnel's control
+/// server methods.
+pub async fn metrics(ctx: CommandContext, args: TunnelMetricsArgs) -> Result<i32, AnyError> {
+	let metrics = do_single_rpc_call::<_, RpcMetrics>(
+		&ctx.paths.tunnel_lockfile(),
+		ctx.log.clone(),
+		protocol::singleton::METHOD_METRICS,
+		protocol::EmptyObject {},
+	)
+	.await?;
+
+	if ctx.args.global_options.output_format(args.json) == OutputFormat::Json {
+		ctx.log.result(to_json(&metrics));
+		return Ok(0);
+	}
+
+	let mut buckets = metrics
+		.latency_buckets_ms
+		.iter()
+		.map(|b| format!("<={}ms", b))
+		.collect::<Vec<_>>();
+	buckets.push("more".to_string());
+
+	for method in &metrics.methods {
+		let latency = buckets
+			.iter()
+			.zip(method.latency.iter())
+			.filter(|(_, n)| **n > 0)
+			.map(|(b, n)| format!("{}: {}", b, n))
+			.collect::<Vec<_>>();
+		ctx.log.result(format!(
+			"{}: {} calls, {} errors ({})",
+			method.name,
+			method.calls,
+			method.errors,
+			latency.join(", ")
+		));
+	}
+
+	Ok(0)
+}
+
+/// Lists the ports the running tunnel's clients have forwarded.
+pub async fn ports(ctx: CommandContext, args: TunnelPortsArgs) -> Result<i32, AnyError> {
+	let tunnel = do_single_rpc_call::<_, protocol::singleton::StatusWithTunnelName>(
+		&ctx.paths.tunnel_lockfile(),
+		ctx.log.clone(),
+		protocol::singleton::METHOD_STATUS,
+		protocol::EmptyObject {},
+	)
+	.await?;
+	let ports = tunnel.status.ports;
+
+	if ctx.args.global_options.output_format(args.json) == OutputFormat::Json {
+		ctx.log.result(to_json(&ports));
+		return Ok(0);
+	}
+
+	if ports.is_empty() {
+		ctx.log.result("No ports are forwarded");
+		return Ok(0);
+	}
+
+	for port in &ports {
+		ctx.log.result(format!(
+			"{} ({}, {}): {}",
+			port.number,
+			port.privacy,
+			port.protocol,
+			port.uri.as_deref().unwrap_or("-")
+		));
+	}
+
+	Ok(0)
+}
+
+/// Measures the round-trip time of RPC calls to the running tunnel, or to a
+/// control server at the given address or socket.
+pub async fn ping(ctx: CommandContext, args: TunnelPingArgs) -> Result<i32, AnyError> {
+	let count = args.count.max(1);
+	let interval = Duration::from_secs(1);
+	let samples = match (&args.address, &args.socket) {
+		(Some(address), _) => {
+			let stream = tokio::net::TcpStream::connect(address.as_str())
+				.await
+				.map_err(|e| wrap(e, format!("could not connect to {}", address)))?;
+			let (read, write) = stream.into_split();
+			let connection = ControlConnection::new(ctx.log.clone(), read, write);
+			measure_ping(&connection.caller, protocol::METHOD_PING, count, interval).await?
+		}
+		(None, Some(socket)) => {
+			let (read, write) = socket_stream_split(get_socket_rw_stream(socket).await?);
+			let connection = ControlConnection::new(ctx.log.clone(), read, write);
+			measure_ping(&connection.caller, protocol::METHOD_PING, count, interval).await?
+		}
+		(None, None) => {
+			let client =
+				connect_singleton_rpc(&ctx.paths.tunnel_lockfile(), ctx.log.clone()).await?;
+			measure_ping(&client.caller, protocol::METHOD_PING, count, interval).await?
+		}
+	};
+	let summary = PingSummary::new(&samples).unwrap();
+
+	if ctx.args.global_options.output_format(args.json) == OutputFormat::Json {
+		ctx.log.result(to_json(&serde_json::json!({
+			"samples": samples,
+			"summary": summary,
+		})));
+		return Ok(0);
+	}
+
+	for (i, s) in samples.iter().enumerate() {
+		ctx.log.result(format!(
+			"ping {}: time={:.3}ms",
+			i + 1,
+			s.rtt_us as f64 / 1000.0
+		));
+	}
+	ctx.log.result(format!(
+		"rtt min/avg/max = {:.3}/{:.3}/{:.3}ms, clock skew {:.3}ms",
+		summary.min_rtt_ms, summary.avg_rtt_ms, summary.max_rtt_ms, summary.clock_skew_ms
+	));
+
+	Ok(0)
+}
+
+pub async fn logs(ctx: CommandContext, args: TunnelLogArgs) -> Result<i32, AnyError> {
+	let client = connect_singleton_rpc(&ctx.paths.tunnel_lockfile(), ctx.log.clone()).await?;
+	let (mut records, result) = client
+		.caller
+		.call_streaming::<_, _, protocol::singleton::LogRecord, protocol::EmptyObject>(
+			protocol::singleton::METHOD_STREAM_LOGS,
+			protocol::singleton::StreamLogsParams {
+				lines: args.lines,
+				follow: args.follow,
+				level: args.level,
+				server: args.server,
 			},
-		})
-		.unwrap(),
+		);
+
+	loop {
+		let record = tokio::select! {
+			r = records.next() => r,
+			_ = tokio::signal::ctrl_c() => return Ok(0),
+		};
+
+		match record {
+			Some(Ok(r)) => match r.level {
+				Some(level) => log::emit(level, &r.prefix, &r.message),
+				None => println!("{}{}", r.prefix, r.message),
+			},
+			Some(Err(e)) => return Err(CodeError::TunnelRpcCallFailed(e).into()),
+			None => break,
+		}
+	}
+
+	match result.await {
+		Ok(Err(e)) => Err(CodeError::TunnelRpcCallFailed(e).into()),
+		_ => Ok(0),
+	}
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DoctorStatus {
+	Ok,
+	Warning,
+	Error,
+}
+
+#[derive(Serialize)]
+pub struct DoctorCheck {
+	pub name: &'static str,
+	pub status: DoctorStatus,
+	pub detail: String,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub requirements: Vec<Requirement>,
+	/// What the requirements' versions were found from.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub probes: Vec<ProbeOutput>,
+}
+
+fn check_keyring(ctx: &CommandContext) -> DoctorCheck {
+	let timeout = ctx
+		.args
+		.global_options
+		.keyring_timeout
+		.map(Duration::from_secs)
+		.unwrap_or(DEFAULT_KEYRING_TIMEOUT);
+	let probe = probe_keyring(&ctx.paths, timeout);
+
+	match probe.error {
+		None => DoctorCheck {
+			name: "keyring",
+			status: DoctorStatus::Ok,
+			detail: format!("available, responded in {}ms", probe.took.as_millis()),
+			requirements: vec![],
+			probes: vec![],
+		},
+		Some(e) => DoctorCheck {
+			name: "keyring",
+			status: DoctorStatus::Warning,
+			detail: format!(
+				"unavailable, credentials will be stored in a file instead: {}",
+				e
+			),
+			requirements: vec![],
+			probes: vec![],
+		},
+	}
+}
+
+/// Shows what the machine's policy restricts. A policy that stops the tunnel
+/// from being hosted is an error, and one that turns off other features is
+/// a warning.
+fn check_policy(policy: &policy::Policy) -> DoctorCheck {
+	let restrictions = policy.restrictions();
+	let status = if policy.is_disabled(PolicyFeature::TunnelHosting) {
+		DoctorStatus::Error
+	} else if [
+		PolicyFeature::PublicPorts,
+		PolicyFeature::RemoteExec,
+		PolicyFeature::ServeWebWithoutToken,
+	]
+	.iter()
+	.any(|f| policy.is_disabled(*f))
+	{
+		DoctorStatus::Warning
+	} else {
+		DoctorStatus::Ok
+	};
+
+	let detail = match &policy.source {
+		Some(source) if !restrictions.is_empty() => {
+			format!("set by {}: {}", source, restrictions.join("; "))
+		}
+		Some(source) => format!("set by {}, with no restrictions", source),
+		None => "none set".to_string(),
+	};
+
+	DoctorCheck {
+		name: "policy",
+		status,
+		detail,
+		requirements: vec![],
+		probes: vec![],
+	}
+}
+
+/// Describes the machine, which isn't a check but helps make sense of the rest.
+fn check_host() -> DoctorCheck {
+	DoctorCheck {
+		name: "host",
+		status: DoctorStatus::Ok,
+		detail: collect_host_info().to_string(),
+		requirements: vec![],
+		probes: vec![],
+	}
+}
+
+/// Checks which server build can run on the machine.
+async fn check_server_prereqs(ctx: &CommandContext) -> DoctorCheck {
+	#[cfg(target_os = "linux")]
+	let (detected, check) = (
+		format!(", detected {}", crate::util::prereqs::detect_libc().await),
+		crate::util::prereqs::check_requirements(ctx.args.global_options.arch).await,
+	);
+	#[cfg(not(target_os = "linux"))]
+	let (detected, check) = (
+		String::new(),
+		crate::util::prereqs::RequirementsCheck::default(),
 	);
 
+	match PreReqChecker::new()
+		.with_log(ctx.log.clone())
+		.with_arch(ctx.args.global_options.arch)
+		.verify()
+		.await
+	{
+		Ok(platform) => DoctorCheck {
+			name: "server prerequisites",
+			status: DoctorStatus::Ok,
+			detail: format!("using the {} server{}", platform.headless(), detected),
+			requirements: check.requirements,
+			probes: check.probes,
+		},
+		// the unmet requirements are listed on their own
+		Err(CodeError::PrerequisitesFailed { .. }) => DoctorCheck {
+			name: "server prerequisites",
+			status: DoctorStatus::Error,
+			detail: format!("not met{}", detected),
+			requirements: check.requirements,
+			probes: check.probes,
+		},
+		Err(e) => DoctorCheck {
+			name: "server prerequisites",
+			status: DoctorStatus::Error,
+			detail: e.to_string(),
+			requirements: check.requirements,
+			probes: check.probes,
+		},
+	}
+}
+
+/// Runs diagnostics on the machine's tunnel setup.
+pub async fn doctor(ctx: CommandContext, args: TunnelDoctorArgs) -> Result<i32, AnyError> {
+	let checks = vec![
+		check_host(),
+		check_keyring(&ctx),
+		check_server_prereqs(&ctx).await,
+		check_policy(&policy::current()),
+	];
+
+	if ctx.args.global_options.output_format(args.json) == OutputFormat::Json {
+		ctx.log.result(to_json(&checks));
+	} else {
+		for check in &checks {
+			let label = match check.status {
+				DoctorStatus::Ok => "ok",
+				DoctorStatus::Warning => "warning",
+				DoctorStatus::Error => "error",
+			};
+			ctx.log
+				.result(format!("[{}] {}: {}", label, check.name, check.detail));
+			for r in &check.requirements {
+				ctx.log.result(format!("  - {} ({})", r, r.status));
+			}
+			if ctx.args.global_options.verbose {
+				for p in &check.probes {
+					ctx.log.result(format!(
+						"    {}: {}",
+						p.source,
+						p.output.replace('\n', " / ")
+					));
+				}
+			}
+		}
+	}
+
+	let failed = checks.iter().any(|c| c.status == DoctorStatus::Error);
+	Ok(if failed { 1 } else { 0 })
+}
+
+pub async fn server(
+	ctx: CommandContext,
+	server_args: TunnelServerSubCommands,
+) -> Result<i32, AnyError> {
+	match server_args {
+		TunnelServerSubCommands::Uninstall(args) => uninstall_server_command(ctx, args).await,
+	}
+}
+
+/// Uninstalls a server through the running tunnel, which knows whether
+/// clients are using it, or directly if no tunnel is running.
+async fn uninstall_server_command(
+	ctx: CommandContext,
+	args: TunnelServerUninstallArgs,
+) -> Result<i32, AnyError> {
+	let params = protocol::UninstallServerParams {
+		commit: args.commit,
+		force: args.force,
+	};
+	let result = match do_single_rpc_call::<_, protocol::UninstallServerResult>(
+		&ctx.paths.tunnel_lockfile(),
+		ctx.log.clone(),
+		protocol::METHOD_UNINSTALL_SERVER,
+		params.clone(),
+	)
+	.await
+	{
+		Err(CodeError::NoRunningTunnel) => {
+			let registry = CachedServerRegistry::new(&ctx.log, &ctx.paths, &SERVER_USAGE);
+			uninstall_server(&registry, &params.commit, params.force).await?
+		}
+		r => r?,
+	};
+
+	for pid in result.stopped {
+		ctx.log.result(format!("Stopped server process {}", pid));
+	}
+	for name in result.removed {
+		ctx.log.result(format!("Removed {}", name));
+	}
+
 	Ok(0)
 }
 
 /// Removes unused servers.
 pub async fn prune(ctx: CommandContext) -> Result<i32, AnyError> {
-	get_all_servers(&ctx.paths)
-		.into_iter()
-		.map(|s| s.server_paths(&ctx.paths))
-		.filter(|s| s.get_running_pid().is_none())
-		.try_for_each(|s| {
-			ctx.log
-				.result(format!("Deleted {}", s.server_dir.display()));
-			s.delete()
-		})
-		.map_err(AnyError::from)?;
+	for s in prune_stopped_servers(&ctx.log, &ctx.paths)? {
+		ctx.log
+			.result(format!("Deleted {}", s.server_dir.display()));
+	}
 
-	ctx.log.result("Successfully removed all unused servers");
+	ctx.log.notice("Successfully removed all unused servers");
 
 	Ok(0)
 }
@@ -402,6 +1341,22 @@ pub async fn serve(ctx: CommandContext, gateway_args: TunnelServeArgs) -> Result
 		log, paths, args, ..
 	} = ctx;
 
+	if gateway_args.attach {
+		return attach(log, &paths).await;
+	}
+
+	let policy = policy::current();
+	policy.ensure_allowed(PolicyFeature::TunnelHosting)?;
+	if gateway_args.enable_remote_exec {
+		policy.ensure_allowed(PolicyFeature::RemoteExec)?;
+	}
+	if let Some(t) = &gateway_args.remote_exec_token {
+		log::redact::register_secret(t);
+	}
+	if gateway_args.auto_forward_visibility == Some(PortVisibility::Public) {
+		policy.ensure_allowed(PolicyFeature::PublicPorts)?;
+	}
+
 	let no_sleep = match gateway_args.no_sleep.then(SleepInhibitor::new) {
 		Some(i) => match i.await {
 			Ok(i) => Some(i),
@@ -417,12 +1372,35 @@ pub async fn serve(ctx: CommandContext, gateway_args: TunnelServeArgs) -> Result
 
 	let mut csa = (&args).into();
 	gateway_args.apply_to_server_args(&mut csa);
-	let result = serve_with_csa(paths, log, gateway_args, csa, TUNNEL_CLI_LOCK_NAME).await;
+	let auth_options = auth_options(&args.global_options);
+	let result = serve_with_csa(
+		paths,
+		log,
+		auth_options,
+		gateway_args,
+		csa,
+		TUNNEL_CLI_LOCK_NAME,
+	)
+	.await;
 	drop(no_sleep);
 
 	result
 }
 
+/// Follows the output of the running tunnel until Ctrl+C, leaving it running.
+async fn attach(log: log::Logger, paths: &LauncherPaths) -> Result<i32, AnyError> {
+	let stream = connect_to_running_tunnel(&paths.tunnel_lockfile()).await?;
+	start_singleton_client(SingletonClientArgs {
+		log,
+		stream,
+		shutdown: ShutdownRequest::create_rx([ShutdownRequest::CtrlC]),
+		attach_only: true,
+	})
+	.await;
+
+	Ok(0)
+}
+
 /// Internal command used by port forwarding. It reads requests for forwarded ports
 /// on lines from stdin, as JSON. It uses singleton logic as well (though on
 /// a different tunnel than the main one used for the control server) so that
@@ -433,6 +1411,8 @@ pub async fn forward(
 	ctx: CommandContext,
 	mut forward_args: TunnelForwardArgs,
 ) -> Result<i32, AnyError> {
+	policy::current().ensure_allowed(PolicyFeature::TunnelHosting)?;
+
 	// Spooky: check IS_A_TTY before starting the stdin reader, since IS_A_TTY will
 	// access stdin but a lock will later be held on stdin by the line-reader.
 	if *IS_A_TTY {
@@ -455,13 +1435,13 @@ pub async fn forward(
 	});
 
 	// #region singleton acquisition
-	let shutdown = ShutdownRequest::create_rx([ShutdownRequest::CtrlC]);
+	let mut shutdown = ShutdownRequest::create_rx([ShutdownRequest::CtrlC]);
 	let server = loop {
 		if shutdown.is_open() {
 			return Ok(0);
 		}
 
-		match acquire_singleton(&ctx.paths.forwarding_lockfile()).await {
+		match acquire_singleton(&ctx.log, &ctx.paths.forwarding_lockfile()).await {
 			Ok(SingletonConnection::Client(stream)) => {
 				debug!(ctx.log, "starting as client to singleton");
 				let r = local_forwarding::client(local_forwarding::SingletonClientArgs {
@@ -478,13 +1458,14 @@ pub async fn forward(
 			Ok(SingletonConnection::Singleton(server)) => break server,
 			Err(e) => {
 				warning!(ctx.log, "error access singleton, retrying: {}", e);
-				tokio::time::sleep(Duration::from_secs(2)).await
+				// checked at the top of the loop
+				shutdown.wait_timeout(Duration::from_secs(2)).await;
 			}
 		}
 	};
 
 	// #region singleton handler
-	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let auth = get_auth(&ctx);
 	if let (Some(p), Some(at)) = (
 		forward_args.login.provider.take(),
 		forward_args.login.access_token.take(),
@@ -522,14 +1503,63 @@ fn get_connection_token(tunnel: &ActiveTunnel) -> String {
 async fn serve_with_csa(
 	paths: LauncherPaths,
 	mut log: log::Logger,
+	auth_options: AuthOptions,
 	gateway_args: TunnelServeArgs,
-	mut csa: CodeServerArgs,
+	csa: CodeServerArgs,
 	app_mutex_name: Option<&'static str>,
 ) -> Result<i32, AnyError> {
+	policy::current().ensure_allowed(PolicyFeature::TunnelHosting)?;
+
 	let log_broadcast = BroadcastLogSink::new();
 	log = log.tee(log_broadcast.clone());
 	log::install_global_logger(log.clone()); // re-install so that library logs are captured
 
+	serve_with_broadcast(
+		paths,
+		log,
+		log_broadcast.clone(),
+		auth_options,
+		gateway_args,
+		csa,
+		app_mutex_name,
+	)
+	.await
+	.map_err(|e| with_recent_errors(e, &log_broadcast))
+}
+
+/// Number of recent errors included when the tunnel exits with an error.
+const EXIT_ERROR_LINES: usize = 5;
+
+/// Adds the last errors that were logged to the error the tunnel exited
+/// with, since the error itself often doesn't say how it was reached.
+fn with_recent_errors(e: AnyError, log_broadcast: &BroadcastLogSink) -> AnyError {
+	let problems = log_broadcast.recent_problems(log::Level::Error, EXIT_ERROR_LINES);
+	if problems.is_empty() {
+		return e;
+	}
+
+	let logs = problems
+		.iter()
+		.map(|r| format!("    {}", r.message.trim_end().replace('\n', "\n    ")))
+		.collect::<Vec<_>>()
+		.join("\n");
+
+	CodeError::TunnelFailedWithLogs {
+		error: Box::new(e),
+		logs,
+	}
+	.into()
+}
+
+async fn serve_with_broadcast(
+	paths: LauncherPaths,
+	log: log::Logger,
+	log_broadcast: BroadcastLogSink,
+	auth_options: AuthOptions,
+	gateway_args: TunnelServeArgs,
+	mut csa: CodeServerArgs,
+	app_mutex_name: Option<&'static str>,
+) -> Result<i32, AnyError> {
 	debug!(
 		log,
 		"Starting tunnel with `{} {}`",
@@ -542,8 +1572,15 @@ async fn serve_with_csa(
 	// current_exe will point to the wrong path.
 	let current_exe = std::env::current_exe().unwrap();
 
+	let drain = Drain::new(
+		gateway_args
+			.drain_timeout
+			.map(Duration::from_secs)
+			.unwrap_or(DEFAULT_DRAIN_TIMEOUT),
+	);
 	let mut vec = vec![
 		ShutdownRequest::CtrlC,
+		ShutdownRequest::Terminate(drain.clone()),
 		ShutdownRequest::ExeUninstalled(current_exe.to_owned()),
 	];
 	if let Some(p) = gateway_args
@@ -552,14 +1589,27 @@ async fn serve_with_csa(
 	{
 		vec.push(ShutdownRequest::ParentProcessKilled(p));
 	}
+	if let Some(t) = gateway_args.idle_timeout {
+		vec.push(ShutdownRequest::Idle(
+			CONNECTION_ACTIVITY.clone(),
+			Duration::from_secs(t),
+		));
+	}
 	let mut shutdown = ShutdownRequest::create_rx(vec);
 
+	// when socket activated, the singleton listens on the socket passed by
+	// systemd instead of creating its own
+	let mut activated = ActivatedSocket::take()?;
+	if let Some(a) = &activated {
+		debug!(log, "using activated socket at {}", a.path().display());
+	}
+
 	let server = loop {
 		if shutdown.is_open() {
 			return Ok(0);
 		}
 
-		match acquire_singleton(&paths.tunnel_lockfile()).await {
+		match acquire_singleton_with(&log, &paths.tunnel_lockfile(), activated.take()).await {
 			Ok(SingletonConnection::Client(stream)) => {
 				debug!(log, "starting as client to singleton");
 				if gateway_args.name.is_some()
@@ -576,6 +1626,7 @@ async fn serve_with_csa(
 					log: log.clone(),
 					shutdown: shutdown.clone(),
 					stream,
+					attach_only: false,
 				})
 				.await;
 				if should_exit {
@@ -585,20 +1636,50 @@ async fn serve_with_csa(
 			Ok(SingletonConnection::Singleton(server)) => break server,
 			Err(e) => {
 				warning!(log, "error access singleton, retrying: {}", e);
-				tokio::time::sleep(Duration::from_secs(2)).await
+				// checked at the top of the loop
+				shutdown.wait_timeout(Duration::from_secs(2)).await;
 			}
 		}
 	};
 
 	debug!(log, "starting as new singleton");
 
-	let mut server =
-		make_singleton_server(log_broadcast.clone(), log.clone(), server, shutdown.clone());
-	let platform = spanf!(log, log.span("prereq"), PreReqChecker::new().verify())?;
+	if !gateway_args.ignore_interop && gateway_args.tunnel.tunnel_id.is_none() {
+		let probe = CachedInteropProbe::new(&paths, CommandInteropProbe::new(log.clone()));
+		warn_on_interop_tunnel(&log, &probe).await;
+	}
+
+	let mut server = make_singleton_server(
+		log_broadcast.clone(),
+		log.clone(),
+		server,
+		shutdown.clone(),
+		paths.clone(),
+	);
+	let platform = spanf!(
+		log,
+		log.span("prereq"),
+		PreReqChecker::new()
+			.with_log(log.clone())
+			.skip_requirements_check(csa.skip_requirements_check)
+			.with_arch(csa.arch)
+			.assume_platform(csa.assume_platform)
+			.verify()
+	)?;
 	let _lock = app_mutex_name.map(AppMutex::new);
 
-	let auth = Auth::new(&paths, log.clone());
-	let mut dt = dev_tunnels::DevTunnels::new_remote_tunnel(&log, auth, &paths);
+	let auth_options = AuthOptions {
+		refresh_margin: gateway_args.token_refresh_margin.map(Duration::from_secs),
+		..auth_options
+	};
+	let auth = Auth::with_options(&paths, log.clone(), &auth_options);
+	let mut dt = dev_tunnels::DevTunnels::new_remote_tunnel(&log, auth.clone(), &paths);
+	// kept across restarts, so that reconnecting doesn't lift a lockout
+	let challenge_limiter = Arc::new(ChallengeLimiter::new(
+		log.clone(),
+		ChallengeLimits::default(),
+		Some(paths.auth_audit_log()),
+	));
 	loop {
 		let tunnel = if let Some(t) =
 			fulfill_existing_tunnel_args(gateway_args.tunnel.clone(), &gateway_args.name)
@@ -611,7 +1692,9 @@ async fn serve_with_csa(
 			}
 		}?;
 
-		csa.connection_token = Some(get_connection_token(&tunnel));
+		let connection_token = get_connection_token(&tunnel);
+		log::redact::register_secret(&connection_token);
+		csa.connection_token = Some(connection_token);
 
 		let mut r = start_singleton_server(SingletonServerArgs {
 			log: log.clone(),
@@ -619,8 +1702,11 @@ async fn serve_with_csa(
 			paths: &paths,
 			code_server_args: &csa,
 			platform,
+			drain: &drain,
 			log_broadcast: &log_broadcast,
 			shutdown: shutdown.clone(),
+			auth: auth.clone(),
+			challenge_limiter: &challenge_limiter,
 			server: &mut server,
 		})
 		.await?;
@@ -649,3 +1735,76 @@ async fn serve_with_csa(
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_context(dir: &std::path::Path) -> CommandContext {
+		CommandContext {
+			log: log::Logger::test(),
+			paths: LauncherPaths::new_without_replacements(dir.to_owned()),
+			args: CliCore::default(),
+			http: reqwest::Client::new(),
+		}
+	}
+
+	fn assert_disabled(err: AnyError, feature: PolicyFeature) {
+		assert!(
+			matches!(err, AnyError::CodeError(CodeError::DisabledByPolicy(f, _)) if f == feature),
+			"{}",
+			err
+		);
+	}
+
+	#[tokio::test]
+	async fn test_policy_restricts_serve() {
+		// configures the policy, so it's run in a child
+		if !crate::util::test_env::in_child_with_env(
+			concat!(module_path!(), "::test_policy_restricts_serve"),
+			&[],
+		) {
+			return;
+		}
+		let dir = tempfile::tempdir().unwrap();
+
+		policy::configure(policy::Policy {
+			source: Some("test".to_string()),
+			disable_tunnel_hosting: true,
+			..Default::default()
+		});
+		let err = serve(test_context(dir.path()), TunnelServeArgs::default())
+			.await
+			.unwrap_err();
+		assert_disabled(err, PolicyFeature::TunnelHosting);
+
+		// flags asking for what the policy turns off fail before the tunnel is hosted
+		policy::configure(policy::Policy {
+			source: Some("test".to_string()),
+			disable_remote_exec: true,
+			disable_public_ports: true,
+			..Default::default()
+		});
+		let err = serve(
+			test_context(dir.path()),
+			TunnelServeArgs {
+				enable_remote_exec: true,
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap_err();
+		assert_disabled(err, PolicyFeature::RemoteExec);
+
+		let err = serve(
+			test_context(dir.path()),
+			TunnelServeArgs {
+				auto_forward_visibility: Some(PortVisibility::Public),
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap_err();
+		assert_disabled(err, PolicyFeature::PublicPorts);
+	}
+}
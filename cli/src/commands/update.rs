@@ -5,13 +5,11 @@
 
 use std::sync::Arc;
 
-use indicatif::ProgressBar;
-
 use crate::{
 	constants::PRODUCT_NAME_LONG,
 	self_update::SelfUpdate,
 	update_service::UpdateService,
-	util::{errors::AnyError, http::ReqwestSimpleHttp, input::ProgressBarReporter},
+	util::{errors::AnyError, http::ReqwestSimpleHttp, progress},
 };
 
 use super::{args::StandaloneUpdateArgs, CommandContext};
@@ -40,10 +38,11 @@ pub async fn update(ctx: CommandContext, args: StandaloneUpdateArgs) -> Result<i
 		return Ok(0);
 	}
 
-	let pb = ProgressBar::new(1);
-	pb.set_message("Downloading...");
 	update_service
-		.do_update(&current_version, ProgressBarReporter::from(pb))
+		.do_update(
+			&current_version,
+			progress::reporter(&ctx.log, "Downloading update"),
+		)
 		.await?;
 	ctx.log
 		.result(format!("Successfully updated to {}", current_version));
@@ -3,31 +3,53 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::path::{Path, PathBuf};
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+};
 
 use crate::{
-	desktop::{prompt_to_install, CodeVersionManager, RequestedVersion},
+	desktop::{prompt_to_install, CodeVersionManager, InstallKind, RequestedVersion},
 	log,
+	update_service::UpdateService,
 	util::{
 		errors::{AnyError, NoInstallInUserProvidedPath},
-		prereqs::PreReqChecker,
+		http::ReqwestSimpleHttp,
+		input::{is_interactive, prompt_yn},
 	},
 };
 
-use super::{args::UseVersionArgs, CommandContext};
+use super::{
+	args::{UseVersionArgs, VersionCleanArgs, VersionListArgs},
+	output::{
+		CleanedBuildOutput, CleanedEntryOutput, InstallOutput, VersionCleanOutput,
+		VersionListOutput, VersionOutput,
+	},
+	CommandContext,
+};
 
 pub async fn switch_to(ctx: CommandContext, args: UseVersionArgs) -> Result<i32, AnyError> {
-	let platform = PreReqChecker::new().verify().await?;
+	let platform = ctx.prereqs().verify().await?;
 	let vm = CodeVersionManager::new(ctx.log.clone(), &ctx.paths, platform);
 	let version = RequestedVersion::try_from(args.name.as_str())?;
 
 	let maybe_path = match args.install_dir {
 		Some(d) => Some(
-			CodeVersionManager::get_entrypoint_for_install_dir(&PathBuf::from(&d))
+			CodeVersionManager::get_install_in_dir(&PathBuf::from(&d))
 				.await
-				.ok_or(NoInstallInUserProvidedPath(d))?,
+				.ok_or(NoInstallInUserProvidedPath(d))?
+				.entrypoint,
 		),
-		None => vm.try_get_entrypoint(&version).await,
+		None => match vm.try_get_entrypoint(&version).await {
+			None if version.commit().is_some() => {
+				let update_service = UpdateService::new(
+					ctx.log.clone(),
+					Arc::new(ReqwestSimpleHttp::with_client(ctx.http.clone())),
+				);
+				Some(vm.install_commit(&update_service, &version).await?)
+			}
+			p => p,
+		},
 	};
 
 	match maybe_path {
@@ -44,19 +66,138 @@ pub async fn switch_to(ctx: CommandContext, args: UseVersionArgs) -> Result<i32,
 }
 
 pub async fn show(ctx: CommandContext) -> Result<i32, AnyError> {
-	let platform = PreReqChecker::new().verify().await?;
+	let platform = ctx.prereqs().verify().await?;
 	let vm = CodeVersionManager::new(ctx.log.clone(), &ctx.paths, platform);
 
 	let version = vm.get_preferred_version();
-	println!("Current quality: {}", version);
-	match vm.try_get_entrypoint(&version).await {
-		Some(p) => println!("Installation path: {}", p.display()),
-		None => println!("No existing installation found"),
+	let install_path = vm.try_get_entrypoint(&version).await;
+	let output = VersionOutput {
+		install_kind: install_path.as_deref().map(InstallKind::of),
+		install_path,
+		version: version.to_string(),
+	};
+	println!(
+		"{}",
+		ctx.args.global_options.output_format(false).render(&output)
+	);
+
+	Ok(0)
+}
+
+pub async fn list(ctx: CommandContext, args: VersionListArgs) -> Result<i32, AnyError> {
+	let platform = ctx.prereqs().verify().await?;
+	let vm = CodeVersionManager::new(ctx.log.clone(), &ctx.paths, platform);
+
+	let selected = vm
+		.try_get_entrypoint(&vm.get_preferred_version())
+		.await
+		.map(|p| std::fs::canonicalize(&p).unwrap_or(p));
+	let output = VersionListOutput(
+		vm.list_installs()
+			.into_iter()
+			.map(|i| {
+				let real =
+					std::fs::canonicalize(&i.entrypoint).unwrap_or_else(|_| i.entrypoint.clone());
+				InstallOutput {
+					quality: i.details.quality,
+					version: i.details.version,
+					commit: i.details.commit,
+					selected: selected.as_ref() == Some(&real),
+					path: i.entrypoint,
+					kind: i.kind,
+					error: i.error,
+				}
+			})
+			.collect(),
+	);
+
+	ctx.log.result(
+		ctx.args
+			.global_options
+			.output_format(args.json)
+			.render(&output),
+	);
+	Ok(0)
+}
+
+pub async fn clean(ctx: CommandContext, args: VersionCleanArgs) -> Result<i32, AnyError> {
+	let platform = ctx.prereqs().verify().await?;
+	let vm = CodeVersionManager::new(ctx.log.clone(), &ctx.paths, platform);
+
+	let stale = vm.stale_entries();
+	let builds = vm.unused_builds();
+
+	let mut remove = !(args.dry_run || stale.is_empty() && builds.is_empty());
+	if remove && !args.yes {
+		remove = prompt_yn(
+			&format!(
+				"Remove {} stored versions and {} unused downloads?",
+				stale.len(),
+				builds.len()
+			),
+			"--yes",
+		)?;
 	}
 
+	// --yes doesn't answer this, since it changes the version in use
+	let remove_selected = match stale.iter().find(|e| e.selected) {
+		Some(selected) if remove => {
+			args.remove_selected
+				|| (is_interactive()
+					&& prompt_yn(
+						&format!(
+							"{} is the version in use. Remove it too? The default version will be used after.",
+							selected.version
+						),
+						"--remove-selected",
+					)?)
+		}
+		_ => false,
+	};
+
+	let removed_entries: Vec<_> = stale
+		.iter()
+		.filter(|e| remove && (!e.selected || remove_selected))
+		.cloned()
+		.collect();
+	if remove {
+		vm.remove_entries(&removed_entries)?;
+		vm.remove_builds(&builds)?;
+	}
+
+	let output = VersionCleanOutput {
+		dry_run: args.dry_run,
+		entries: stale
+			.into_iter()
+			.map(|e| CleanedEntryOutput {
+				removed: removed_entries.contains(&e),
+				version: e.version.to_string(),
+				path: e.path,
+				selected: e.selected,
+			})
+			.collect(),
+		builds: builds
+			.into_iter()
+			.map(|path| CleanedBuildOutput {
+				path,
+				removed: remove,
+			})
+			.collect(),
+	};
+	ctx.log.result(
+		ctx.args
+			.global_options
+			.output_format(args.json)
+			.render(&output),
+	);
 	Ok(0)
 }
 
 fn print_now_using(log: &log::Logger, version: &RequestedVersion, path: &Path) {
-	log.result(format!("Now using {} from {}", version, path.display()));
+	log.result(format!(
+		"Now using {} from {} ({})",
+		version,
+		path.display(),
+		InstallKind::of(path)
+	));
 }
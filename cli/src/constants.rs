@@ -4,7 +4,7 @@
  *--------------------------------------------------------------------------------------------*/
 
 use serde::Deserialize;
-use std::{collections::HashMap, io::IsTerminal};
+use std::{collections::HashMap, io::IsTerminal, time::Duration};
 
 use const_format::concatcp;
 use lazy_static::lazy_static;
@@ -13,6 +13,82 @@ use crate::options::Quality;
 
 pub const CONTROL_PORT: u16 = 31545;
 
+/// Maximum number of async requests a control connection can run at once.
+pub const CONTROL_MAX_IN_FLIGHT_REQUESTS: usize = 64;
+
+/// Default maximum size of a single RPC message. Generous, since server
+/// messages and HTTP bodies are forwarded through the control connection.
+pub const DEFAULT_MAX_RPC_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+/// Default number of consecutive times a crashed code server is restarted.
+pub const DEFAULT_SERVER_RESTART_LIMIT: u32 = 5;
+
+/// Default number of consecutive times the service restarts a failed tunnel
+/// before exiting and leaving it to the service manager.
+pub const DEFAULT_TUNNEL_RESTART_LIMIT: u32 = 5;
+
+/// How often a line with a transfer's progress is printed when output isn't a
+/// terminal, unless set with --progress-interval.
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a helper command, such as `systemctl`, may run before it's killed.
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bytes kept of each of a helper command's stdout and stderr.
+pub const DEFAULT_COMMAND_OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// How long output of a helper command is still read after it exits, from
+/// children it left running that share its pipes.
+pub const COMMAND_OUTPUT_DRAIN: Duration = Duration::from_millis(500);
+
+/// How long the editor may take to install a group of extensions, which it
+/// may download from the marketplace, before it's killed.
+pub const EXTENSION_INSTALL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Bytes of journal output collected when exporting the service's logs.
+pub const SERVICE_LOG_EXPORT_LIMIT: usize = 256 * 1024 * 1024;
+
+/// How long exporting the service's logs from the journal may take, which
+/// reads all of them when there's no `--since`.
+pub const SERVICE_LOG_EXPORT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long installing extensions with the server's CLI may take before it's killed.
+pub const SERVER_EXTENSION_INSTALL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long to wait for another process to finish creating a download cache
+/// entry, such as a server it's downloading, before giving up.
+pub const DOWNLOAD_CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long a command run by a client with remote exec may run before it's killed.
+pub const DEFAULT_REMOTE_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes of stdout and stderr a command run with remote exec may print before it's killed.
+pub const DEFAULT_REMOTE_EXEC_OUTPUT_LIMIT: usize = 1024 * 1024;
+
+/// How often the tunnel scans for ports the server listens on, with --auto-forward-ports.
+pub const DEFAULT_AUTO_FORWARD_SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long an auto-forwarded port may stop listening before it's unforwarded.
+pub const DEFAULT_AUTO_FORWARD_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Time a peer must wait after its first failed auth challenge. It doubles
+/// with each consecutive failure, up to `MAX_AUTH_RETRY_DELAY`.
+pub const DEFAULT_AUTH_RETRY_DELAY: Duration = Duration::from_millis(500);
+pub const MAX_AUTH_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Number of consecutive failed auth challenges after which a peer is locked out.
+pub const DEFAULT_AUTH_LOCKOUT_AFTER: u32 = 10;
+
+/// How long a peer is locked out after too many failed auth challenges.
+pub const DEFAULT_AUTH_LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// Default number of idle seconds after which a socket activated tunnel exits.
+pub const DEFAULT_SOCKET_IDLE_TIMEOUT_SECS: u64 = 30 * 60;
+
+/// File name of the socket a socket activated tunnel service listens on, in
+/// the runtime directory (`%t`) of its service manager.
+pub const ACTIVATION_SOCKET_NAME: &str = concatcp!(APPLICATION_NAME, "-tunnel.sock");
+
 /// Protocol version sent to clients. This can be used to indiciate new or
 /// changed capabilities that clients may wish to leverage.
 ///  1 - Initial protocol version
@@ -20,7 +96,14 @@ pub const CONTROL_PORT: u16 = 31545;
 ///      are compressed bidirectionally.
 ///  3 - The server's connection token is set to a SHA256 hash of the tunnel ID
 ///  4 - The server's msgpack messages are no longer length-prefixed
-pub const PROTOCOL_VERSION: u32 = 4;
+///  5 - Clients can negotiate the version and capabilities with a `hello`
+///  6 - `ping` returns the times the host received and answered it
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// Oldest protocol version of a client that negotiates it with a `hello`.
+/// Clients that don't are treated as using version 0, and can only use
+/// methods that don't need a negotiated capability.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
 
 /// Prefix for the tunnel tag that includes the version.
 pub const PROTOCOL_VERSION_TAG_PREFIX: &str = "protocolv";
@@ -97,7 +180,16 @@ pub fn get_default_user_agent() -> String {
 	)
 }
 
-const NO_COLOR_ENV: &str = "NO_COLOR";
+/// Disables colors when set to anything, https://no-color.org
+pub const NO_COLOR_ENV: &str = "NO_COLOR";
+/// Enables colors even when not writing to a terminal.
+pub const FORCE_COLOR_ENV: &str = "FORCE_COLOR";
+
+/// Set by flatpak to the id of the app running in the sandbox, whose files
+/// are under `/app`.
+pub const FLATPAK_ID_ENV: &str = "FLATPAK_ID";
+/// Set by snapd to the directory of the running snap.
+pub const SNAP_ENV: &str = "SNAP";
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -119,9 +211,6 @@ lazy_static! {
 	/// Whether i/o interactions are allowed in the current CLI.
 	pub static ref IS_A_TTY: bool = std::io::stdin().is_terminal();
 
-	/// Whether i/o interactions are allowed in the current CLI.
-	pub static ref COLORS_ENABLED: bool = *IS_A_TTY && std::env::var(NO_COLOR_ENV).is_err();
-
 	/// Whether i/o interactions are allowed in the current CLI.
 	pub static ref IS_INTERACTIVE_CLI: bool = *IS_A_TTY && std::env::var(NONINTERACTIVE_VAR).is_err();
 
@@ -5,4 +5,7 @@
 
 mod version_manager;
 
-pub use version_manager::{prompt_to_install, CodeVersionManager, RequestedVersion};
+pub use version_manager::{
+	prompt_to_install, CodeVersionManager, DesktopInstall, InstallKind, RequestedVersion,
+	StaleEntry,
+};
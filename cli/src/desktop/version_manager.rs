@@ -14,13 +14,17 @@ use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-	constants::{PRODUCT_DOWNLOAD_URL, QUALITY, QUALITYLESS_PRODUCT_NAME},
-	log,
+	constants::{
+		FLATPAK_ID_ENV, PRODUCT_DOWNLOAD_URL, QUALITY, QUALITYLESS_PRODUCT_NAME, SNAP_ENV,
+	},
+	download_cache::DownloadCache,
+	log, options,
 	state::{LauncherPaths, PersistedState},
-	update_service::Platform,
+	update_service::{unzip_downloaded_release, Platform, Release, TargetKind, UpdateService},
 	util::{
 		command::new_std_command,
-		errors::{AnyError, InvalidRequestedVersion},
+		errors::{wrap, AnyError, CodeError, InvalidRequestedVersion, NoInstallInUserProvidedPath},
+		http, progress,
 	},
 };
 
@@ -29,26 +33,32 @@ use crate::{
 #[serde(tag = "t", content = "c")]
 pub enum RequestedVersion {
 	Default,
+	/// A build of this CLI's quality, by commit.
 	Commit(String),
+	/// A build of another quality, by commit.
+	QualityCommit(options::Quality, String),
 	Path(String),
 }
 
 lazy_static! {
-	static ref COMMIT_RE: Regex = Regex::new(r"^[a-e0-f]{40}$").unwrap();
+	static ref COMMIT_RE: Regex = Regex::new(r"^[a-fA-F0-9]{40}$").unwrap();
 }
 
 impl RequestedVersion {
 	pub fn get_command(&self) -> String {
+		format!("code version use {}", self)
+	}
+
+	/// Gets the quality and commit of the build, if a commit was requested.
+	pub fn commit(&self) -> Option<(Result<options::Quality, String>, &str)> {
 		match self {
-			RequestedVersion::Default => {
-				format!("code version use {}", QUALITY)
-			}
 			RequestedVersion::Commit(commit) => {
-				format!("code version use {}/{}", QUALITY, commit)
+				Some((options::Quality::try_from(QUALITY), commit.as_str()))
 			}
-			RequestedVersion::Path(path) => {
-				format!("code version use {}", path)
+			RequestedVersion::QualityCommit(quality, commit) => {
+				Some((Ok(*quality), commit.as_str()))
 			}
+			_ => None,
 		}
 	}
 }
@@ -62,6 +72,9 @@ impl std::fmt::Display for RequestedVersion {
 			RequestedVersion::Commit(commit) => {
 				write!(f, "{}/{}", QUALITY, commit)
 			}
+			RequestedVersion::QualityCommit(quality, commit) => {
+				write!(f, "{}/{}", quality.get_machine_name(), commit)
+			}
 			RequestedVersion::Path(path) => write!(f, "{}", path),
 		}
 	}
@@ -80,13 +93,581 @@ impl TryFrom<&str> for RequestedVersion {
 		}
 
 		if COMMIT_RE.is_match(s) {
-			return Ok(RequestedVersion::Commit(s.to_string()));
+			return Ok(RequestedVersion::Commit(s.to_lowercase()));
+		}
+
+		// `<quality>/<commit>`, as versions are displayed
+		if let Some((quality, commit)) = s.split_once('/') {
+			if COMMIT_RE.is_match(commit) {
+				let commit = commit.to_lowercase();
+				if quality == QUALITY {
+					return Ok(RequestedVersion::Commit(commit));
+				}
+				if let Ok(quality) = options::Quality::try_from(quality) {
+					return Ok(RequestedVersion::QualityCommit(quality, commit));
+				}
+			}
 		}
 
 		Err(InvalidRequestedVersion())
 	}
 }
 
+/// How a desktop install was packaged, which decides where its CLI is.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallKind {
+	Standard,
+	/// An install with a `data` folder next to it, or `code-portable-data`
+	/// next to the app on macOS, where it keeps its user data.
+	Portable,
+	Flatpak,
+	Snap,
+}
+
+impl fmt::Display for InstallKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			InstallKind::Standard => write!(f, "standard"),
+			InstallKind::Portable => write!(f, "portable"),
+			InstallKind::Flatpak => write!(f, "flatpak"),
+			InstallKind::Snap => write!(f, "snap"),
+		}
+	}
+}
+
+impl InstallKind {
+	/// Gets the kind of install the CLI entrypoint is in. A CLI running in a
+	/// flatpak or snap is known by the variables their sandboxes set, and the
+	/// commands they put in the PATH by what they run. Installs found in an
+	/// app's directory get their kind from its layout instead, see
+	/// `find_install_in_dir`.
+	pub fn of(entrypoint: &Path) -> InstallKind {
+		Self::of_with_env(entrypoint, |name| std::env::var_os(name))
+	}
+
+	fn of_with_env(entrypoint: &Path, env: impl Fn(&str) -> Option<OsString>) -> InstallKind {
+		let in_snap = env(SNAP_ENV).is_some_and(|snap| entrypoint.starts_with(snap));
+		if in_snap || is_snap_command(entrypoint) {
+			return InstallKind::Snap;
+		}
+
+		let in_flatpak = env(FLATPAK_ID_ENV).is_some() && entrypoint.starts_with("/app");
+		if in_flatpak || is_flatpak_export(entrypoint) {
+			return InstallKind::Flatpak;
+		}
+
+		match portable_data_dir(entrypoint) {
+			Some(d) if d.is_dir() => InstallKind::Portable,
+			_ => InstallKind::Standard,
+		}
+	}
+}
+
+/// Gets whether the command is one a snap puts in the PATH, which is a link
+/// to `snap` that runs the app in its sandbox.
+fn is_snap_command(entrypoint: &Path) -> bool {
+	entrypoint.is_symlink()
+		&& std::fs::canonicalize(entrypoint)
+			.is_ok_and(|p| p.file_name().is_some_and(|n| n == "snap"))
+}
+
+/// Gets whether the command is one flatpak exports for an app, which is a
+/// short script that runs it with `flatpak run`.
+fn is_flatpak_export(entrypoint: &Path) -> bool {
+	const MAX_EXPORT_LEN: u64 = 4096;
+	match std::fs::metadata(entrypoint) {
+		Ok(m) if m.is_file() && m.len() <= MAX_EXPORT_LEN => std::fs::read(entrypoint)
+			.is_ok_and(|c| String::from_utf8_lossy(&c).contains("flatpak run")),
+		_ => false,
+	}
+}
+
+/// Gets where a portable install would keep its data, given its CLI.
+fn portable_data_dir(entrypoint: &Path) -> Option<PathBuf> {
+	let mut levels = DESKTOP_CLI_RELATIVE_PATH
+		.split(',')
+		.next()
+		.unwrap_or_default()
+		.split('/')
+		.count();
+	if cfg!(target_os = "macos") {
+		// the data goes next to the .app rather than in it
+		levels += 1;
+		return entrypoint
+			.ancestors()
+			.nth(levels)
+			.map(|d| d.join("code-portable-data"));
+	}
+
+	entrypoint.ancestors().nth(levels).map(|d| d.join("data"))
+}
+
+/// Directories, relative to a packaged app, that the install is in. The app
+/// directory of a flatpak is like `/var/lib/flatpak/app/com.visualstudio.code`
+/// and that of a snap like `/snap/code`.
+const PACKAGED_INSTALL_DIRS: &[(InstallKind, &str)] = &[
+	(InstallKind::Flatpak, "current/active/files/extra/vscode"),
+	(InstallKind::Flatpak, "files/extra/vscode"),
+	(InstallKind::Snap, "current/usr/share/code"),
+	(InstallKind::Snap, "usr/share/code"),
+];
+
+/// A desktop install, and the CLI in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopInstall {
+	pub kind: InstallKind,
+	pub entrypoint: PathBuf,
+}
+
+/// Finds the install in the directory. This may be the install itself, the
+/// `data` folder of a portable install, or the app directory of a flatpak or
+/// snap.
+pub fn find_install_in_dir(path: &Path) -> Option<DesktopInstall> {
+	let is_data_dir = path
+		.file_name()
+		.is_some_and(|n| n == "data" || n == "code-portable-data");
+	let dir = match path.parent() {
+		Some(parent) if is_data_dir => parent,
+		_ => path,
+	};
+
+	// the kind is known from the layout for packaged installs
+	let mut dirs = vec![(None, dir.to_path_buf())];
+	if cfg!(target_os = "macos") && is_data_dir {
+		// the portable data folder is next to the .app
+		if let Ok(entries) = std::fs::read_dir(dir) {
+			dirs.extend(
+				entries
+					.flatten()
+					.map(|e| e.path())
+					.filter(|p| p.extension().is_some_and(|e| e == "app"))
+					.map(|p| (None, p)),
+			);
+		}
+	}
+	for (kind, relative) in PACKAGED_INSTALL_DIRS {
+		dirs.push((Some(*kind), dir.join(relative)));
+	}
+
+	for (kind, dir) in dirs {
+		for entry in DESKTOP_CLI_RELATIVE_PATH.split(',') {
+			let entrypoint = dir.join(entry);
+			if !entrypoint.is_file() {
+				continue;
+			}
+
+			return Some(match kind {
+				Some(InstallKind::Flatpak) => DesktopInstall {
+					kind: InstallKind::Flatpak,
+					entrypoint: flatpak_export(&entrypoint).unwrap_or(entrypoint),
+				},
+				Some(kind) => DesktopInstall { kind, entrypoint },
+				None => DesktopInstall {
+					kind: InstallKind::of(&entrypoint),
+					entrypoint,
+				},
+			});
+		}
+	}
+
+	None
+}
+
+/// Gets the command flatpak exports for the app the CLI is in. The CLI in the
+/// flatpak needs the sandbox's libraries, which the export runs it with.
+fn flatpak_export(entrypoint: &Path) -> Option<PathBuf> {
+	let app_dir = entrypoint.ancestors().find(|a| {
+		a.parent()
+			.and_then(|p| p.file_name())
+			.is_some_and(|n| n == "app")
+	})?;
+	let app_id = app_dir.file_name()?;
+	let export = app_dir.parent()?.parent()?.join("exports/bin").join(app_id);
+	if export.is_file() {
+		Some(export)
+	} else {
+		None
+	}
+}
+
+/// Gets app directories of flatpak and snap installs of the application,
+/// which may not be in the PATH.
+#[allow(dead_code)] // only used on Linux
+fn packaged_app_dirs(application_name: &str, home: Option<&Path>) -> Vec<PathBuf> {
+	// named like com.visualstudio.code and com.visualstudio.code.insiders
+	let flatpak_id = format!("com.visualstudio.{}", application_name.replace('-', "."));
+	let mut dirs = vec![PathBuf::from("/var/lib/flatpak/app").join(&flatpak_id)];
+	if let Some(home) = home {
+		dirs.push(home.join(".local/share/flatpak/app").join(&flatpak_id));
+	}
+	dirs.push(PathBuf::from("/snap").join(application_name));
+	dirs
+}
+
+/// Names of the desktop app of a quality.
+struct QualityApp {
+	#[allow(dead_code)] // only used on Windows
+	quality: &'static str,
+	application_name: &'static str,
+	mac_name: &'static str,
+	/// Folder it's installed to on Windows.
+	windows_folder: &'static str,
+	/// Name the Windows installer registers it under, which user installs
+	/// follow with ` (User)`.
+	#[allow(dead_code)] // only used on Windows
+	windows_display_name: &'static str,
+}
+
+const QUALITY_APPS: &[QualityApp] = &[
+	QualityApp {
+		quality: "stable",
+		application_name: "code",
+		mac_name: "Visual Studio Code",
+		windows_folder: "Microsoft VS Code",
+		windows_display_name: "Microsoft Visual Studio Code",
+	},
+	QualityApp {
+		quality: "insider",
+		application_name: "code-insiders",
+		mac_name: "Visual Studio Code - Insiders",
+		windows_folder: "Microsoft VS Code Insiders",
+		windows_display_name: "Microsoft Visual Studio Code Insiders",
+	},
+	QualityApp {
+		quality: "exploration",
+		application_name: "code-exploration",
+		mac_name: "Visual Studio Code - Exploration",
+		windows_folder: "Microsoft VS Code Exploration",
+		windows_display_name: "Microsoft Visual Studio Code Exploration",
+	},
+];
+
+/// Gets where the CLI of each quality is when it's installed to the usual
+/// place. They may not exist.
+fn standard_install_candidates(home: Option<&Path>) -> Vec<PathBuf> {
+	let mut candidates = vec![];
+	for app in QUALITY_APPS {
+		if cfg!(target_os = "macos") {
+			let bundle = format!("{}.app", app.mac_name);
+			let mut dirs = vec![PathBuf::from("/Applications").join(&bundle)];
+			dirs.extend(home.map(|h| h.join("Applications").join(&bundle)));
+			candidates.extend(dirs.into_iter().map(|d| d.join(DESKTOP_CLI_RELATIVE_PATH)));
+		} else if cfg!(windows) {
+			let cli = format!("bin/{}.cmd", app.application_name);
+			for var in ["LOCALAPPDATA", "ProgramFiles"] {
+				if let Some(root) = std::env::var_os(var) {
+					let mut dir = PathBuf::from(root);
+					if var == "LOCALAPPDATA" {
+						dir.push("Programs");
+					}
+					candidates.push(dir.join(app.windows_folder).join(&cli));
+				}
+			}
+		} else {
+			candidates.push(
+				PathBuf::from("/usr/share")
+					.join(app.application_name)
+					.join("bin")
+					.join(app.application_name),
+			);
+			candidates.extend(
+				packaged_app_dirs(app.application_name, home)
+					.iter()
+					.filter_map(|d| find_install_in_dir(d))
+					.map(|i| i.entrypoint),
+			);
+		}
+	}
+
+	candidates
+}
+
+/// Details of a desktop install, read from the files it ships with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstallDetails {
+	pub quality: Option<String>,
+	pub version: Option<String>,
+	pub commit: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProductJson {
+	quality: Option<String>,
+	commit: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PackageJson {
+	version: Option<String>,
+}
+
+/// Reads the details of the install from its product.json and package.json.
+pub fn read_install_details(entrypoint: &Path) -> Result<InstallDetails, AnyError> {
+	let product_path = app_dir_of(entrypoint)
+		.and_then(|d| find_product_json(&d))
+		.ok_or_else(|| CodeError::ProductJsonNotFound(entrypoint.display().to_string()))?;
+
+	let product: ProductJson = read_json(&product_path)?;
+	let package: Option<PackageJson> = read_json(&product_path.with_file_name("package.json")).ok();
+	Ok(InstallDetails {
+		quality: product.quality,
+		commit: product.commit,
+		version: package.and_then(|p| p.version),
+	})
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, AnyError> {
+	let contents =
+		std::fs::read(path).map_err(|e| wrap(e, format!("error reading {}", path.display())))?;
+	serde_json::from_slice(&contents)
+		.map_err(|e| wrap(e, format!("error parsing {}", path.display())).into())
+}
+
+/// Gets the directory the CLI's `bin` folder is in, following flatpak
+/// exports, snap wrappers, and symlinks to where the app is installed.
+fn app_dir_of(entrypoint: &Path) -> Option<PathBuf> {
+	let parent = entrypoint.parent()?;
+	let name = entrypoint.file_name()?;
+	if parent.ends_with("exports/bin") {
+		let root = parent.parent()?.parent()?;
+		return Some(root.join("app").join(name).join(PACKAGED_INSTALL_DIRS[0].1));
+	}
+	if parent.ends_with("snap/bin") {
+		return Some(parent.parent()?.join(name).join(PACKAGED_INSTALL_DIRS[2].1));
+	}
+
+	let real = std::fs::canonicalize(entrypoint).unwrap_or_else(|_| entrypoint.to_path_buf());
+	real.parent()?.parent().map(|p| p.to_path_buf())
+}
+
+/// Finds the product.json in the app directory. It's next to `bin` on macOS
+/// and in `resources/app` elsewhere, which newer Windows installs put in a
+/// folder named after the commit.
+fn find_product_json(app_dir: &Path) -> Option<PathBuf> {
+	let direct = [
+		app_dir.join("product.json"),
+		app_dir.join("resources/app/product.json"),
+	];
+	if let Some(p) = direct.into_iter().find(|p| p.is_file()) {
+		return Some(p);
+	}
+
+	std::fs::read_dir(app_dir)
+		.ok()?
+		.flatten()
+		.map(|e| e.path().join("resources/app/product.json"))
+		.find(|p| p.is_file())
+}
+
+/// Hive of the registry that installers register in.
+#[allow(dead_code)] // only used on Windows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegistryHive {
+	/// Per-machine installs.
+	LocalMachine,
+	/// Per-user installs.
+	CurrentUser,
+}
+
+/// View of the registry, since 32-bit installers write to a different one.
+#[allow(dead_code)] // only used on Windows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegistryView {
+	Bits64,
+	Bits32,
+}
+
+#[cfg(windows)]
+const UNINSTALL_KEY: &str = "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Uninstall";
+
+#[allow(dead_code)] // only used on Windows
+const REGISTRY_SCOPES: [(RegistryHive, RegistryView); 4] = [
+	(RegistryHive::LocalMachine, RegistryView::Bits64),
+	(RegistryHive::LocalMachine, RegistryView::Bits32),
+	(RegistryHive::CurrentUser, RegistryView::Bits64),
+	(RegistryHive::CurrentUser, RegistryView::Bits32),
+];
+
+/// An app registered under an Uninstall key.
+#[allow(dead_code)] // only used on Windows
+#[derive(Debug, Clone, Default)]
+pub struct UninstallEntry {
+	/// Name of the key, which is the app ID for the editor's installer.
+	pub key: String,
+	pub display_name: Option<String>,
+	pub display_version: Option<String>,
+	pub install_location: Option<String>,
+}
+
+/// Reads the apps registered under the Uninstall key.
+#[allow(dead_code)] // only used on Windows
+pub trait RegistryReader {
+	/// Gets the entries in the hive and view. Entries whose values can't be
+	/// read are left out, or have them missing.
+	fn uninstall_entries(
+		&self,
+		hive: RegistryHive,
+		view: RegistryView,
+	) -> io::Result<Vec<UninstallEntry>>;
+}
+
+/// An editor install found in the registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryInstall {
+	pub quality: &'static str,
+	pub application_name: &'static str,
+	pub entrypoint: PathBuf,
+	pub version: Option<String>,
+}
+
+/// Finds editor installs of each quality in the Uninstall keys of both hives
+/// and views. Installs are recognized by their display name, or by the app
+/// IDs of this build; those are installs of its own quality. This is best
+/// effort, since restricted tokens may not read some of the registry.
+#[allow(dead_code)] // only used on Windows
+pub fn scan_registry(reader: &dyn RegistryReader, app_ids: &[String]) -> Vec<RegistryInstall> {
+	let own_quality = QUALITY_APPS
+		.iter()
+		.find(|a| a.application_name == crate::constants::APPLICATION_NAME);
+
+	let mut installs: Vec<RegistryInstall> = vec![];
+	for (hive, view) in REGISTRY_SCOPES {
+		let entries = match reader.uninstall_entries(hive, view) {
+			Ok(e) => e,
+			Err(_) => continue,
+		};
+
+		for entry in entries {
+			let location = match entry.install_location.as_deref() {
+				Some(l) if !l.trim().is_empty() => l.trim(),
+				_ => continue,
+			};
+
+			let by_id = app_ids.iter().any(|id| entry.key.contains(id.as_str()));
+			let app = match (by_id, own_quality) {
+				(true, Some(app)) => app,
+				_ => match entry.display_name.as_deref().and_then(quality_app_named) {
+					Some(app) => app,
+					None => continue,
+				},
+			};
+
+			let install = RegistryInstall {
+				quality: app.quality,
+				application_name: app.application_name,
+				entrypoint: PathBuf::from(location)
+					.join("bin")
+					.join(format!("{}.cmd", app.application_name)),
+				version: entry.display_version.clone(),
+			};
+			// the same install may be in both views
+			let seen = installs.iter().any(|i| {
+				i.entrypoint.to_string_lossy().to_lowercase()
+					== install.entrypoint.to_string_lossy().to_lowercase()
+			});
+			if !seen {
+				installs.push(install);
+			}
+		}
+	}
+
+	installs
+}
+
+/// Gets the quality of the app the installer registered with the display
+/// name. User installs have a " (User)" suffix.
+#[allow(dead_code)] // only used on Windows
+fn quality_app_named(display_name: &str) -> Option<&'static QualityApp> {
+	let name = display_name.trim();
+	let name = name.strip_suffix("(User)").unwrap_or(name).trim_end();
+	QUALITY_APPS
+		.iter()
+		.find(|a| name.eq_ignore_ascii_case(a.windows_display_name))
+}
+
+#[cfg(windows)]
+struct WindowsRegistry;
+
+#[cfg(windows)]
+impl RegistryReader for WindowsRegistry {
+	fn uninstall_entries(
+		&self,
+		hive: RegistryHive,
+		view: RegistryView,
+	) -> io::Result<Vec<UninstallEntry>> {
+		use winreg::enums::{
+			HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE, KEY_READ, KEY_WOW64_32KEY, KEY_WOW64_64KEY,
+		};
+		use winreg::RegKey;
+
+		let hive = match hive {
+			RegistryHive::LocalMachine => HKEY_LOCAL_MACHINE,
+			RegistryHive::CurrentUser => HKEY_CURRENT_USER,
+		};
+		let flags = KEY_READ
+			| match view {
+				RegistryView::Bits64 => KEY_WOW64_64KEY,
+				RegistryView::Bits32 => KEY_WOW64_32KEY,
+			};
+
+		let uninstall = RegKey::predef(hive).open_subkey_with_flags(UNINSTALL_KEY, flags)?;
+		Ok(uninstall
+			.enum_keys()
+			.flatten()
+			.filter_map(|key| {
+				let sk = uninstall.open_subkey_with_flags(&key, flags).ok()?;
+				Some(UninstallEntry {
+					display_name: sk.get_value("DisplayName").ok(),
+					display_version: sk.get_value("DisplayVersion").ok(),
+					install_location: sk.get_value("InstallLocation").ok(),
+					key,
+				})
+			})
+			.collect())
+	}
+}
+
+/// Gets the installs registered in the Windows registry.
+fn registry_installs() -> Vec<RegistryInstall> {
+	#[cfg(windows)]
+	{
+		use crate::constants::WIN32_APP_IDS;
+		scan_registry(
+			&WindowsRegistry,
+			WIN32_APP_IDS.as_deref().unwrap_or_default(),
+		)
+	}
+
+	#[cfg(not(windows))]
+	vec![]
+}
+
+/// A stored version whose install no longer exists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaleEntry {
+	pub version: RequestedVersion,
+	pub path: PathBuf,
+	/// Whether it's the version in use.
+	pub selected: bool,
+}
+
+/// A desktop install found on the machine.
+#[derive(Debug)]
+pub struct FoundInstall {
+	pub entrypoint: PathBuf,
+	pub kind: InstallKind,
+	/// Details of the install. Those its files don't have may come from
+	/// what its installer registered.
+	pub details: InstallDetails,
+	/// Why the install's files couldn't be read, if they couldn't.
+	pub error: Option<String>,
+}
+
+/// Index of `Stored::current` when no stored version is selected, so that
+/// the default one is used.
+const NO_CURRENT: usize = usize::MAX;
+
 #[derive(Serialize, Deserialize, Clone, Default)]
 struct Stored {
 	/// Map of requested versions to locations where those versions are installed.
@@ -97,20 +678,28 @@ struct Stored {
 pub struct CodeVersionManager {
 	state: PersistedState<Stored>,
 	log: log::Logger,
+	platform: Platform,
+	/// Builds downloaded by commit.
+	cache: DownloadCache,
 }
 
 impl CodeVersionManager {
-	pub fn new(log: log::Logger, lp: &LauncherPaths, _platform: Platform) -> Self {
+	pub fn new(log: log::Logger, lp: &LauncherPaths, platform: Platform) -> Self {
 		CodeVersionManager {
 			log,
 			state: PersistedState::new(lp.root().join("versions.json")),
+			platform,
+			cache: DownloadCache::new(lp.root().join("desktop")),
 		}
 	}
 
 	/// Tries to find the binary entrypoint for VS Code installed in the path.
 	pub async fn get_entrypoint_for_install_dir(path: &Path) -> Option<PathBuf> {
-		use tokio::sync::mpsc;
+		Self::get_install_in_dir(path).await.map(|i| i.entrypoint)
+	}
 
+	/// Like `get_entrypoint_for_install_dir`, also giving the kind of install.
+	pub async fn get_install_in_dir(path: &Path) -> Option<DesktopInstall> {
 		// Check whether the user is supplying a path to the CLI directly (e.g. #164622)
 		if let Ok(true) = path.metadata().map(|m| m.is_file()) {
 			let result = new_std_command(path)
@@ -119,26 +708,18 @@ impl CodeVersionManager {
 				.map(|o| o.status.success());
 
 			if let Ok(true) = result {
-				return Some(path.to_owned());
+				return Some(DesktopInstall {
+					kind: InstallKind::of(path),
+					entrypoint: path.to_owned(),
+				});
 			}
 		}
 
-		let (tx, mut rx) = mpsc::channel(1);
-
-		// Look for all the possible paths in parallel
-		for entry in DESKTOP_CLI_RELATIVE_PATH.split(',') {
-			let my_path = path.join(entry);
-			let my_tx = tx.clone();
-			tokio::spawn(async move {
-				if tokio::fs::metadata(&my_path).await.is_ok() {
-					my_tx.send(my_path).await.ok();
-				}
-			});
-		}
-
-		drop(tx); // drop so rx gets None if no sender emits
-
-		rx.recv().await
+		let path = path.to_owned();
+		tokio::task::spawn_blocking(move || find_install_in_dir(&path))
+			.await
+			.ok()
+			.flatten()
 	}
 
 	/// Sets the "version" as the persisted one for the user.
@@ -172,6 +753,210 @@ impl CodeVersionManager {
 		}
 	}
 
+	/// Downloads the build of the requested commit, if it isn't already, and
+	/// stores it as the install for the version so later runs reuse it.
+	pub async fn install_commit(
+		&self,
+		update_service: &UpdateService,
+		version: &RequestedVersion,
+	) -> Result<PathBuf, AnyError> {
+		let (quality, commit) = match version.commit() {
+			Some((Ok(quality), commit)) => (quality, commit),
+			Some((Err(_), _)) => return Err(CodeError::NoQualityToDownload.into()),
+			None => return Err(InvalidRequestedVersion().into()),
+		};
+		self.check_publishes_desktop()?;
+
+		let release = update_service
+			.get_release_by_commit(self.platform, TargetKind::Archive, quality, commit)
+			.await?;
+
+		self.install_release(update_service, version, release).await
+	}
+
+	/// Downloads a build for the version when none is installed: the commit of
+	/// commit versions, or the latest build of this CLI's quality otherwise.
+	pub async fn provision(
+		&self,
+		update_service: &UpdateService,
+		version: &RequestedVersion,
+	) -> Result<PathBuf, AnyError> {
+		match version {
+			RequestedVersion::Default => {
+				self.check_publishes_desktop()?;
+				let quality = options::Quality::try_from(QUALITY)
+					.map_err(|_| CodeError::NoQualityToDownload)?;
+				let release = update_service
+					.get_latest_commit(self.platform, TargetKind::Archive, quality)
+					.await?;
+				self.install_release(update_service, version, release).await
+			}
+			RequestedVersion::Path(p) => Err(NoInstallInUserProvidedPath(p.clone()).into()),
+			_ => self.install_commit(update_service, version).await,
+		}
+	}
+
+	/// Fails if desktop builds aren't published for the platform, such as the
+	/// legacy Linux ones that only have servers.
+	fn check_publishes_desktop(&self) -> Result<(), CodeError> {
+		match self.platform.archive() {
+			Some(_) => Ok(()),
+			None => Err(CodeError::NoDesktopBuildForPlatform(
+				self.platform.to_string(),
+			)),
+		}
+	}
+
+	/// Downloads the release into the cache, if it isn't already, and stores
+	/// it as the install for the version.
+	async fn install_release(
+		&self,
+		update_service: &UpdateService,
+		version: &RequestedVersion,
+		release: Release,
+	) -> Result<PathBuf, AnyError> {
+		let cache_name = format!("{}-{}", release.quality.get_machine_name(), release.commit);
+		let dir = self
+			.cache
+			.create(&cache_name, |target_dir| async move {
+				self.log.notice(format!("Downloading {}...", release));
+				let tmpdir =
+					tempfile::tempdir().map_err(|e| wrap(e, "error creating temp download dir"))?;
+				let response = update_service
+					.get_download_stream(&release)
+					.await
+					.map_err(|e| download_error(e, &release))?;
+
+				let name = response
+					.url_path_basename()
+					.unwrap_or_else(|| "archive".to_string());
+				let archive_path = tmpdir.path().join(name);
+				http::download_into_file(
+					&archive_path,
+					progress::reporter(&self.log, "Downloading"),
+					response,
+				)
+				.await?;
+				unzip_downloaded_release(
+					&archive_path,
+					&target_dir,
+					progress::reporter(&self.log, "Extracting").counting("files"),
+				)
+				.await?;
+				Ok(())
+			})
+			.await?;
+
+		let install = match find_extracted_install(&dir) {
+			Some(i) => i,
+			None => {
+				let _ = self.cache.delete(&cache_name);
+				return Err(CodeError::CorruptDownload("no editor found in the download").into());
+			}
+		};
+
+		info!(
+			self.log,
+			"Using {} from {}",
+			version,
+			install.entrypoint.display()
+		);
+		let mut state = self.state.load();
+		self.store_version_path(&mut state, version.clone(), install.entrypoint.clone());
+		self.state.save(state)?;
+
+		Ok(install.entrypoint)
+	}
+
+	/// Gets the stored versions whose install no longer exists.
+	pub fn stale_entries(&self) -> Vec<StaleEntry> {
+		let stored = self.state.load();
+		stored
+			.versions
+			.iter()
+			.enumerate()
+			.map(|(i, (version, path))| StaleEntry {
+				version: version.clone(),
+				path: PathBuf::from(path),
+				selected: i == stored.current,
+			})
+			.filter(|e| !e.path.exists())
+			.collect()
+	}
+
+	/// Removes the stored versions. If the selected one is removed, the default
+	/// version is used after.
+	pub fn remove_entries(&self, entries: &[StaleEntry]) -> Result<(), AnyError> {
+		self.state.update(|stored| {
+			let current = stored.versions.get(stored.current).cloned();
+			stored.versions.retain(|(version, path)| {
+				!entries
+					.iter()
+					.any(|e| e.version == *version && e.path.as_os_str() == path)
+			});
+			stored.current = current
+				.and_then(|c| stored.versions.iter().position(|v| *v == c))
+				.unwrap_or(NO_CURRENT);
+		})?;
+		Ok(())
+	}
+
+	/// Gets the downloaded builds that no stored version uses.
+	pub fn unused_builds(&self) -> Vec<PathBuf> {
+		let stored = self.state.load();
+		let live: Vec<PathBuf> = stored
+			.versions
+			.into_iter()
+			.map(|(_, p)| PathBuf::from(p))
+			.filter(|p| p.exists())
+			.collect();
+
+		self.cache
+			.entries()
+			.into_iter()
+			.map(|name| self.cache.path().join(name))
+			.filter(|dir| !live.iter().any(|p| p.starts_with(dir)))
+			.collect()
+	}
+
+	/// Deletes downloaded builds, as given by `unused_builds`.
+	pub fn remove_builds(&self, builds: &[PathBuf]) -> Result<(), AnyError> {
+		for build in builds {
+			if let Some(name) = build.file_name() {
+				self.cache.delete(&name.to_string_lossy())?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Finds the installs the user has chosen and those in the usual places,
+	/// in that order. Installs whose details can't be read are still listed.
+	pub fn list_installs(&self) -> Vec<FoundInstall> {
+		let mut candidates: Vec<PathBuf> = self
+			.state
+			.load()
+			.versions
+			.into_iter()
+			.map(|(_, p)| PathBuf::from(p))
+			.collect();
+		match detect_installed_program(&self.log) {
+			Ok(found) => candidates.extend(found),
+			Err(e) => warning!(self.log, "error looking up installed applications: {}", e),
+		}
+		candidates.extend(
+			standard_install_candidates(dirs::home_dir().as_deref())
+				.into_iter()
+				.filter(|p| p.exists()),
+		);
+
+		let registry = registry_installs();
+		candidates.extend(registry.iter().map(|i| i.entrypoint.clone()));
+
+		let mut installs = list_candidates(candidates);
+		fill_from_registry(&mut installs, &registry);
+		installs
+	}
+
 	/// Gets the currently preferred version based on set_preferred_version.
 	pub fn get_preferred_version(&self) -> RequestedVersion {
 		let stored = self.state.load();
@@ -190,25 +975,39 @@ impl CodeVersionManager {
 			if p.exists() {
 				return Some(p);
 			}
+
+			// the install was updated or removed since it was chosen
+			warning!(
+				self.log,
+				"The install of {} at {} no longer exists, looking for it again",
+				version,
+				p.display()
+			);
 		}
 
 		// For simple quality requests, see if that's installed already on the system
-		let candidates = match &version {
+		let found = match &version {
 			RequestedVersion::Default => match detect_installed_program(&self.log) {
-				Ok(p) => p,
+				Ok(p) => p.into_iter().next(),
 				Err(e) => {
 					warning!(self.log, "error looking up installed applications: {}", e);
 					return None;
 				}
 			},
-			_ => return None,
+			RequestedVersion::Path(_) => None,
+			// other installs may have the commit, such as one in the cache
+			_ => matching_install(version, &self.list_installs()),
 		};
 
-		let found = match candidates.into_iter().next() {
+		let found = match found {
 			Some(p) => p,
-			None => return None,
+			None => {
+				info!(self.log, "No install of {} was found", version);
+				return None;
+			}
 		};
 
+		info!(self.log, "Found {} at {}", version, found.display());
 		// stash the found path for faster lookup
 		self.store_version_path(&mut state, version.clone(), found.clone());
 		if let Err(e) = self.state.save(state) {
@@ -222,7 +1021,7 @@ impl CodeVersionManager {
 /// Shows a nice UI prompt to users asking them if they want to install the
 /// requested version.
 pub fn prompt_to_install(version: &RequestedVersion) {
-	println!(
+	eprintln!(
 		"No installation of {} {} was found.",
 		QUALITYLESS_PRODUCT_NAME, version
 	);
@@ -232,16 +1031,16 @@ pub fn prompt_to_install(version: &RequestedVersion) {
 			// todo: on some platforms, we may be able to help automate installation. For example,
 			// we can unzip the app ourselves on macOS and on windows we can download and spawn the GUI installer
 			#[cfg(target_os = "linux")]
-			println!("Install it from your system's package manager or {}, restart your shell, and try again.", uri);
+			eprintln!("Install it from your system's package manager or {}, restart your shell, and try again.", uri);
 			#[cfg(target_os = "macos")]
-			println!("Download and unzip it from {} and try again.", uri);
+			eprintln!("Download and unzip it from {} and try again.", uri);
 			#[cfg(target_os = "windows")]
-			println!("Install it from {} and try again.", uri);
+			eprintln!("Install it from {} and try again.", uri);
 		}
 	}
 
-	println!();
-	println!("If you already installed {} and we didn't detect it, run `{} --install-dir /path/to/installation`", QUALITYLESS_PRODUCT_NAME, version.get_command());
+	eprintln!();
+	eprintln!("If you already installed {} and we didn't detect it, run `{} --install-dir /path/to/installation`", QUALITYLESS_PRODUCT_NAME, version.get_command());
 }
 
 #[cfg(target_os = "macos")]
@@ -316,56 +1115,13 @@ fn detect_installed_program(log: &log::Logger) -> io::Result<Vec<PathBuf>> {
 
 #[cfg(windows)]
 fn detect_installed_program(_log: &log::Logger) -> io::Result<Vec<PathBuf>> {
-	use crate::constants::{APPLICATION_NAME, WIN32_APP_IDS};
-	use winreg::enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE};
-	use winreg::RegKey;
-
-	let mut output: Vec<PathBuf> = vec![];
-	let app_ids = match WIN32_APP_IDS.as_ref() {
-		Some(ids) => ids,
-		None => return Ok(output),
-	};
-
-	let scopes = [
-		(
-			HKEY_LOCAL_MACHINE,
-			"SOFTWARE\\Wow6432Node\\Microsoft\\Windows\\CurrentVersion\\Uninstall",
-		),
-		(
-			HKEY_LOCAL_MACHINE,
-			"SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Uninstall",
-		),
-		(
-			HKEY_CURRENT_USER,
-			"SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Uninstall",
-		),
-	];
-
-	for (scope, key) in scopes {
-		let cur_ver = match RegKey::predef(scope).open_subkey(key) {
-			Ok(k) => k,
-			Err(_) => continue,
-		};
-
-		for key in cur_ver.enum_keys().flatten() {
-			if app_ids.iter().any(|id| key.contains(id)) {
-				let sk = cur_ver.open_subkey(&key)?;
-				if let Ok(location) = sk.get_value::<String, _>("InstallLocation") {
-					output.push(
-						[
-							location.as_str(),
-							"bin",
-							&format!("{}.cmd", APPLICATION_NAME),
-						]
-						.iter()
-						.collect(),
-					)
-				}
-			}
-		}
-	}
+	use crate::constants::APPLICATION_NAME;
 
-	Ok(output)
+	Ok(registry_installs()
+		.into_iter()
+		.filter(|i| i.application_name == APPLICATION_NAME)
+		.map(|i| i.entrypoint)
+		.collect())
 }
 
 // Looks for the given binary name in the PATH, returning all candidate matches.
@@ -398,9 +1154,114 @@ fn detect_installed_program(log: &log::Logger) -> io::Result<Vec<PathBuf>> {
 		output.push(target);
 	}
 
+	// flatpaks aren't on the PATH, and snaps may not be
+	let home = dirs::home_dir();
+	for dir in packaged_app_dirs(APPLICATION_NAME, home.as_deref()) {
+		if let Some(install) = find_install_in_dir(&dir) {
+			if !output.contains(&install.entrypoint) {
+				output.push(install.entrypoint);
+			}
+		}
+	}
+
 	Ok(output)
 }
 
+/// Reads the candidates, leaving out those that are the same install.
+fn list_candidates(candidates: Vec<PathBuf>) -> Vec<FoundInstall> {
+	let mut seen = vec![];
+	let mut installs = vec![];
+	for entrypoint in candidates {
+		let real = std::fs::canonicalize(&entrypoint).unwrap_or_else(|_| entrypoint.clone());
+		if seen.contains(&real) {
+			continue;
+		}
+		seen.push(real);
+
+		let (details, error) = match read_install_details(&entrypoint) {
+			Ok(d) => (d, None),
+			Err(e) => (Default::default(), Some(e.to_string())),
+		};
+		installs.push(FoundInstall {
+			kind: InstallKind::of(&entrypoint),
+			details,
+			error,
+			entrypoint,
+		});
+	}
+
+	installs
+}
+
+/// Finds an install of the commit version that can be read.
+fn matching_install(version: &RequestedVersion, installs: &[FoundInstall]) -> Option<PathBuf> {
+	let (quality, commit) = version.commit()?;
+	let quality = quality.ok();
+	installs
+		.iter()
+		.filter(|i| i.entrypoint.exists())
+		.filter(|i| i.error.is_none())
+		.find(|i| {
+			i.details.commit.as_deref() == Some(commit)
+				&& match (quality, i.details.quality.as_deref()) {
+					(Some(q), Some(dq)) => options::Quality::try_from(dq).ok() == Some(q),
+					_ => true,
+				}
+		})
+		.map(|i| i.entrypoint.clone())
+}
+
+/// Finds the install in an extracted archive, which may be in a folder of it,
+/// such as the .app on macOS.
+fn find_extracted_install(dir: &Path) -> Option<DesktopInstall> {
+	find_install_in_dir(dir).or_else(|| {
+		std::fs::read_dir(dir)
+			.ok()?
+			.flatten()
+			.map(|e| e.path())
+			.filter(|p| p.is_dir())
+			.find_map(|p| find_install_in_dir(&p))
+	})
+}
+
+/// Distinguishes builds that were taken down from other download failures.
+fn download_error(e: AnyError, release: &Release) -> AnyError {
+	match e {
+		AnyError::StatusError(s) if s.status_code == 404 || s.status_code == 410 => {
+			CodeError::CommitNotDownloadable {
+				quality: release.quality.get_machine_name().to_string(),
+				commit: release.commit.clone(),
+			}
+			.into()
+		}
+		e => e,
+	}
+}
+
+/// Fills in what couldn't be read from the install's files with what its
+/// installer registered. Errors reading the files are kept, so they're still
+/// shown for installs the registry knows about.
+fn fill_from_registry(installs: &mut [FoundInstall], registry: &[RegistryInstall]) {
+	for install in installs.iter_mut() {
+		let path = install.entrypoint.to_string_lossy().to_lowercase();
+		let registered = match registry
+			.iter()
+			.find(|r| r.entrypoint.to_string_lossy().to_lowercase() == path)
+		{
+			Some(r) => r,
+			None => continue,
+		};
+
+		let details = &mut install.details;
+		details
+			.quality
+			.get_or_insert_with(|| registered.quality.to_string());
+		if details.version.is_none() {
+			details.version = registered.version.clone();
+		}
+	}
+}
+
 const DESKTOP_CLI_RELATIVE_PATH: &str = if cfg!(target_os = "macos") {
 	"Contents/Resources/app/bin/code"
 } else if cfg!(target_os = "windows") {
@@ -435,6 +1296,12 @@ mod tests {
 			.expect("expected to write binary");
 	}
 
+	/// Makes the install, returning its CLI.
+	fn make_install_at(path: &Path) -> PathBuf {
+		make_fake_vscode_install(path);
+		path.join(DESKTOP_CLI_RELATIVE_PATH.split(',').next().unwrap())
+	}
+
 	fn make_multiple_vscode_install() -> tempfile::TempDir {
 		let dir = tempfile::tempdir().expect("expected to make temp dir");
 		make_fake_vscode_install(&dir.path().join("desktop/stable"));
@@ -451,6 +1318,119 @@ mod tests {
 		assert!(result.is_ok());
 	}
 
+	#[test]
+	fn test_parses_requested_versions() {
+		let commit = "6c3e3dba23e8fadc360aed75ce363ba185c49794";
+		assert_eq!(
+			RequestedVersion::try_from(QUALITY).unwrap(),
+			RequestedVersion::Default
+		);
+		assert_eq!(
+			RequestedVersion::try_from(commit).unwrap(),
+			RequestedVersion::Commit(commit.to_string())
+		);
+		assert_eq!(
+			RequestedVersion::try_from(commit.to_uppercase().as_str()).unwrap(),
+			RequestedVersion::Commit(commit.to_string())
+		);
+		assert_eq!(
+			RequestedVersion::try_from(format!("{}/{}", QUALITY, commit).as_str()).unwrap(),
+			RequestedVersion::Commit(commit.to_string())
+		);
+		if QUALITY != "insider" {
+			assert_eq!(
+				RequestedVersion::try_from(format!("insider/{}", commit).as_str()).unwrap(),
+				RequestedVersion::QualityCommit(options::Quality::Insiders, commit.to_string())
+			);
+		}
+
+		#[cfg(unix)]
+		let path = "/opt/code";
+		#[cfg(windows)]
+		let path = "C:\\code";
+		assert_eq!(
+			RequestedVersion::try_from(path).unwrap(),
+			RequestedVersion::Path(path.to_string())
+		);
+
+		for invalid in [
+			"6c3e3dba",
+			"nightly/6c3e3dba23e8fadc360aed75ce363ba185c49794",
+			"stable/not-a-commit",
+			"gc3e3dba23e8fadc360aed75ce363ba185c49794",
+		] {
+			assert!(RequestedVersion::try_from(invalid).is_err(), "{}", invalid);
+		}
+	}
+
+	#[test]
+	fn test_commit_versions_round_trip() {
+		let commit = "6c3e3dba23e8fadc360aed75ce363ba185c49794".to_string();
+		let mut versions = vec![RequestedVersion::Commit(commit.clone())];
+		if QUALITY != "exploration" {
+			versions.push(RequestedVersion::QualityCommit(
+				options::Quality::Exploration,
+				commit.clone(),
+			));
+		}
+
+		for version in versions {
+			assert_eq!(
+				RequestedVersion::try_from(version.to_string().as_str()).unwrap(),
+				version
+			);
+			assert_eq!(version.commit().map(|(_, c)| c), Some(commit.as_str()));
+		}
+		assert!(RequestedVersion::Default.commit().is_none());
+	}
+
+	#[test]
+	fn test_download_error() {
+		let release = Release {
+			name: "1.90.0".to_string(),
+			platform: Platform::LinuxX64,
+			target: TargetKind::Archive,
+			quality: options::Quality::Stable,
+			commit: "6c3e3dba23e8fadc360aed75ce363ba185c49794".to_string(),
+		};
+		let status = |status_code| {
+			AnyError::from(crate::util::errors::StatusError {
+				url: "https://update.example.com".to_string(),
+				status_code,
+				body: String::new(),
+			})
+		};
+
+		for code in [404, 410] {
+			assert!(matches!(
+				download_error(status(code), &release),
+				AnyError::CodeError(CodeError::CommitNotDownloadable { .. })
+			));
+		}
+		assert!(matches!(
+			download_error(status(500), &release),
+			AnyError::StatusError(_)
+		));
+	}
+
+	#[test]
+	fn test_finds_extracted_install() {
+		// archives on macOS have the .app in them, and other archives may be in
+		// a folder of their own
+		let dir = tempfile::tempdir().expect("expected to make temp dir");
+		assert!(find_extracted_install(dir.path()).is_none());
+
+		make_fake_vscode_install(&dir.path().join("VSCode-linux-x64"));
+		let install = find_extracted_install(dir.path()).expect("expected install");
+		assert!(install
+			.entrypoint
+			.starts_with(dir.path().join("VSCode-linux-x64")));
+
+		let flat = tempfile::tempdir().expect("expected to make temp dir");
+		make_fake_vscode_install(flat.path());
+		assert!(find_extracted_install(flat.path()).is_some());
+	}
+
 	#[tokio::test]
 	async fn test_set_preferred_version() {
 		let dir = make_multiple_vscode_install();
@@ -500,6 +1480,281 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_finds_flatpak_install() {
+		let dir = tempfile::tempdir().unwrap();
+		let app = dir.path().join("flatpak/app/com.visualstudio.code");
+		let cli = make_install_at(&app.join("current/active/files/extra/vscode"));
+
+		let expected = DesktopInstall {
+			kind: InstallKind::Flatpak,
+			entrypoint: cli,
+		};
+		assert_eq!(find_install_in_dir(&app), Some(expected.clone()));
+		assert_eq!(
+			find_install_in_dir(&app.join("current/active")),
+			Some(expected)
+		);
+
+		// the export runs the CLI in the sandbox, so it's preferred
+		let export = dir.path().join("flatpak/exports/bin/com.visualstudio.code");
+		create_dir_all(export.parent().unwrap()).unwrap();
+		std::fs::write(
+			&export,
+			"#!/bin/sh\nexec /usr/bin/flatpak run --branch=stable --command=code com.visualstudio.code \"$@\"\n",
+		)
+		.unwrap();
+		assert_eq!(
+			find_install_in_dir(&app),
+			Some(DesktopInstall {
+				kind: InstallKind::Flatpak,
+				entrypoint: export.clone(),
+			})
+		);
+		assert_eq!(InstallKind::of(&export), InstallKind::Flatpak);
+	}
+
+	#[test]
+	fn test_install_kind_from_env() {
+		let no_env = |_: &str| None;
+		let env = |vars: &'static [(&'static str, &'static str)]| {
+			move |name: &str| {
+				vars.iter()
+					.find(|(k, _)| *k == name)
+					.map(|(_, v)| OsString::from(v))
+			}
+		};
+
+		let cli = Path::new("/snap/code/123/usr/share/code/bin/code");
+		assert_eq!(
+			InstallKind::of_with_env(cli, env(&[(SNAP_ENV, "/snap/code/123")])),
+			InstallKind::Snap
+		);
+		assert_eq!(InstallKind::of_with_env(cli, no_env), InstallKind::Standard);
+
+		let cli = Path::new("/app/extra/vscode/bin/code");
+		assert_eq!(
+			InstallKind::of_with_env(cli, env(&[(FLATPAK_ID_ENV, "com.visualstudio.code")])),
+			InstallKind::Flatpak
+		);
+		assert_eq!(InstallKind::of_with_env(cli, no_env), InstallKind::Standard);
+
+		// another install, seen from inside a sandbox, keeps its own kind
+		assert_eq!(
+			InstallKind::of_with_env(
+				Path::new("/usr/share/code/bin/code"),
+				env(&[(SNAP_ENV, "/snap/code/123"), (FLATPAK_ID_ENV, "a.b")])
+			),
+			InstallKind::Standard
+		);
+	}
+
+	#[test]
+	fn test_install_under_packaging_names_is_standard() {
+		// directories named like the packagings don't make an install one
+		let dir = tempfile::tempdir().unwrap();
+		let cli = make_install_at(&dir.path().join("snap/flatpak/code"));
+		assert_eq!(InstallKind::of(&cli), InstallKind::Standard);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_snap_command() {
+		let dir = tempfile::tempdir().unwrap();
+		let snap = dir.path().join("usr/bin/snap");
+		create_dir_all(snap.parent().unwrap()).unwrap();
+		File::create(&snap).unwrap();
+		let command = dir.path().join("code");
+		std::os::unix::fs::symlink(&snap, &command).unwrap();
+		assert_eq!(InstallKind::of(&command), InstallKind::Snap);
+	}
+
+	#[test]
+	fn test_finds_snap_install() {
+		let dir = tempfile::tempdir().unwrap();
+		let snap = dir.path().join("snap/code");
+		let cli = make_install_at(&snap.join("current/usr/share/code"));
+
+		let expected = Some(DesktopInstall {
+			kind: InstallKind::Snap,
+			entrypoint: cli,
+		});
+		assert_eq!(find_install_in_dir(&snap), expected);
+		assert_eq!(find_install_in_dir(&snap.join("current")), expected);
+	}
+
+	#[test]
+	fn test_finds_portable_install() {
+		let dir = tempfile::tempdir().unwrap();
+		let install = dir.path().join("Code.app");
+		let cli = make_install_at(&install);
+		assert_eq!(InstallKind::of(&cli), InstallKind::Standard);
+
+		let data = portable_data_dir(&cli).unwrap();
+		create_dir_all(&data).unwrap();
+		let expected = Some(DesktopInstall {
+			kind: InstallKind::Portable,
+			entrypoint: cli,
+		});
+		assert_eq!(find_install_in_dir(&install), expected);
+		// pointing at the data folder finds the install it belongs to
+		assert_eq!(find_install_in_dir(&data), expected);
+	}
+
+	#[tokio::test]
+	async fn test_gets_packaged_entrypoint() {
+		let dir = tempfile::tempdir().unwrap();
+		let app = dir.path().join("flatpak/app/com.visualstudio.code");
+		let cli = make_install_at(&app.join("current/active/files/extra/vscode"));
+
+		let install = CodeVersionManager::get_install_in_dir(&app).await.unwrap();
+		assert_eq!(install.kind, InstallKind::Flatpak);
+		assert_eq!(
+			CodeVersionManager::get_entrypoint_for_install_dir(&app).await,
+			Some(cli)
+		);
+		assert!(find_install_in_dir(&dir.path().join("flatpak")).is_none());
+	}
+
+	#[test]
+	fn test_packaged_app_dirs() {
+		let dirs = packaged_app_dirs("code-insiders", Some(Path::new("/home/me")));
+		assert_eq!(
+			dirs,
+			vec![
+				PathBuf::from("/var/lib/flatpak/app/com.visualstudio.code.insiders"),
+				PathBuf::from("/home/me/.local/share/flatpak/app/com.visualstudio.code.insiders"),
+				PathBuf::from("/snap/code-insiders"),
+			]
+		);
+	}
+
+	/// Makes an empty file, and the directories it's in.
+	fn touch(path: &Path) {
+		create_dir_all(path.parent().unwrap()).unwrap();
+		File::create(path).unwrap();
+	}
+
+	fn write_product(resources_app: &Path, quality: &str, version: &str) {
+		create_dir_all(resources_app).unwrap();
+		std::fs::write(
+			resources_app.join("product.json"),
+			format!(
+				r#"{{"quality":"{}","commit":"c0ffee","nameShort":"Code"}}"#,
+				quality
+			),
+		)
+		.unwrap();
+		std::fs::write(
+			resources_app.join("package.json"),
+			format!(r#"{{"name":"code","version":"{}"}}"#, version),
+		)
+		.unwrap();
+	}
+
+	#[test]
+	fn test_reads_details_for_each_layout() {
+		let dir = tempfile::tempdir().unwrap();
+		let d = dir.path();
+		let expected = |quality: &str, version: &str| InstallDetails {
+			quality: Some(quality.to_string()),
+			version: Some(version.to_string()),
+			commit: Some("c0ffee".to_string()),
+		};
+
+		// Linux, and Windows before versioned folders
+		touch(&d.join("linux/bin/code"));
+		write_product(&d.join("linux/resources/app"), "stable", "1.90.0");
+		assert_eq!(
+			read_install_details(&d.join("linux/bin/code")).unwrap(),
+			expected("stable", "1.90.0")
+		);
+
+		let app = d.join("Visual Studio Code - Insiders.app/Contents/Resources/app");
+		touch(&app.join("bin/code"));
+		write_product(&app, "insider", "1.91.0-insider");
+		assert_eq!(
+			read_install_details(&app.join("bin/code")).unwrap(),
+			expected("insider", "1.91.0-insider")
+		);
+
+		touch(&d.join("windows/bin/code.cmd"));
+		write_product(&d.join("windows/c0ffee/resources/app"), "stable", "1.92.0");
+		assert_eq!(
+			read_install_details(&d.join("windows/bin/code.cmd")).unwrap(),
+			expected("stable", "1.92.0")
+		);
+
+		let export = d.join("flatpak/exports/bin/com.visualstudio.code");
+		touch(&export);
+		write_product(
+			&d.join(
+				"flatpak/app/com.visualstudio.code/current/active/files/extra/vscode/resources/app",
+			),
+			"stable",
+			"1.89.0",
+		);
+		assert_eq!(
+			read_install_details(&export).unwrap(),
+			expected("stable", "1.89.0")
+		);
+
+		touch(&d.join("snap/bin/code"));
+		write_product(
+			&d.join("snap/code/current/usr/share/code/resources/app"),
+			"stable",
+			"1.88.0",
+		);
+		assert_eq!(
+			read_install_details(&d.join("snap/bin/code")).unwrap(),
+			expected("stable", "1.88.0")
+		);
+	}
+
+	#[test]
+	fn test_lists_unreadable_installs_inline() {
+		let dir = tempfile::tempdir().unwrap();
+		let d = dir.path();
+		touch(&d.join("good/bin/code"));
+		write_product(&d.join("good/resources/app"), "stable", "1.90.0");
+		touch(&d.join("broken/bin/code"));
+		create_dir_all(d.join("broken/resources/app")).unwrap();
+		std::fs::write(d.join("broken/resources/app/product.json"), "{").unwrap();
+
+		let installs = list_candidates(vec![
+			d.join("good/bin/code"),
+			d.join("broken/bin/code"),
+			d.join("missing/bin/code"),
+			d.join("good/bin/code"),
+		]);
+
+		assert_eq!(installs.len(), 3);
+		assert_eq!(installs[0].details.version.as_deref(), Some("1.90.0"));
+		assert_eq!(installs[0].error, None);
+		assert_eq!(installs[0].kind, InstallKind::Standard);
+		let err = installs[1].error.as_ref().unwrap();
+		assert!(err.contains("error parsing"), "{}", err);
+		let err = installs[2].error.as_ref().unwrap();
+		assert!(err.contains("could not find the product.json"), "{}", err);
+	}
+
+	#[tokio::test]
+	async fn test_lists_chosen_installs_first() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let vm = CodeVersionManager::new(log::Logger::test(), &lp, Platform::LinuxARM64);
+		let custom = dir.path().join("custom/bin/code");
+		touch(&custom);
+		write_product(&dir.path().join("custom/resources/app"), "stable", "1.80.0");
+		vm.set_preferred_version(RequestedVersion::Path("custom".to_string()), custom.clone())
+			.await
+			.unwrap();
+
+		let installs = vm.list_installs();
+		assert_eq!(installs[0].entrypoint, custom);
+		assert!(installs[0].error.is_none());
+	}
+
 	#[tokio::test]
 	async fn test_gets_entrypoint_as_binary() {
 		let dir = tempfile::tempdir().expect("expected to make temp dir");
@@ -532,4 +1787,487 @@ mod tests {
 			Some(binary_file_path)
 		);
 	}
+
+	/// Serves entries per scope, failing for scopes that aren't given.
+	struct MockRegistry(Vec<(RegistryHive, RegistryView, Vec<UninstallEntry>)>);
+
+	impl RegistryReader for MockRegistry {
+		fn uninstall_entries(
+			&self,
+			hive: RegistryHive,
+			view: RegistryView,
+		) -> io::Result<Vec<UninstallEntry>> {
+			self.0
+				.iter()
+				.find(|(h, v, _)| *h == hive && *v == view)
+				.map(|(_, _, e)| e.clone())
+				.ok_or_else(|| io::Error::from(io::ErrorKind::PermissionDenied))
+		}
+	}
+
+	fn entry(key: &str, name: &str, version: &str, location: Option<&str>) -> UninstallEntry {
+		UninstallEntry {
+			key: key.to_string(),
+			display_name: Some(name.to_string()),
+			display_version: Some(version.to_string()),
+			install_location: location.map(|l| l.to_string()),
+		}
+	}
+
+	#[test]
+	fn test_scan_registry() {
+		let stable = entry(
+			"{EA457B21-F73E-494C-ACAB-524FDE069978}_is1",
+			"Microsoft Visual Studio Code",
+			"1.90.0",
+			Some("C:\\Program Files\\Microsoft VS Code"),
+		);
+		let reader = MockRegistry(vec![
+			(
+				RegistryHive::LocalMachine,
+				RegistryView::Bits64,
+				vec![
+					stable.clone(),
+					entry("Git_is1", "Git", "2.45.0", Some("C:\\Program Files\\Git")),
+					entry(
+						"{1287CAD5-7C8D-410D-88B9-0D1EE4A83FF2}_is1",
+						"Microsoft Visual Studio Code Exploration",
+						"1.91.0",
+						None,
+					),
+				],
+			),
+			// the 64-bit view is also visible in the 32-bit one on some machines
+			(
+				RegistryHive::LocalMachine,
+				RegistryView::Bits32,
+				vec![UninstallEntry {
+					install_location: Some("c:\\program files\\microsoft vs code".to_string()),
+					..stable
+				}],
+			),
+			(
+				RegistryHive::CurrentUser,
+				RegistryView::Bits64,
+				vec![entry(
+					"{217B4C08-948D-4276-BFBB-BEE930AE5A2C}_is1",
+					"Microsoft Visual Studio Code Insiders (User)",
+					"1.91.0-insider",
+					Some("C:\\Users\\me\\AppData\\Local\\Programs\\Microsoft VS Code Insiders"),
+				)],
+			),
+			// CurrentUser/Bits32 is denied
+		]);
+
+		let installs = scan_registry(&reader, &[]);
+		assert_eq!(
+			installs,
+			vec![
+				RegistryInstall {
+					quality: "stable",
+					application_name: "code",
+					entrypoint: PathBuf::from("C:\\Program Files\\Microsoft VS Code")
+						.join("bin")
+						.join("code.cmd"),
+					version: Some("1.90.0".to_string()),
+				},
+				RegistryInstall {
+					quality: "insider",
+					application_name: "code-insiders",
+					entrypoint: PathBuf::from(
+						"C:\\Users\\me\\AppData\\Local\\Programs\\Microsoft VS Code Insiders"
+					)
+					.join("bin")
+					.join("code-insiders.cmd"),
+					version: Some("1.91.0-insider".to_string()),
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn test_scan_registry_by_app_id() {
+		use crate::constants::APPLICATION_NAME;
+
+		let reader = MockRegistry(vec![(
+			RegistryHive::CurrentUser,
+			RegistryView::Bits64,
+			vec![entry(
+				"{771FD6B0-FA20-440A-A002-3B3BAC16DC50}_is1",
+				"Renamed Editor",
+				"1.90.0",
+				Some("D:\\Editor"),
+			)],
+		)]);
+
+		assert!(scan_registry(&reader, &[]).is_empty());
+		let installs = scan_registry(
+			&reader,
+			&["{771FD6B0-FA20-440A-A002-3B3BAC16DC50}".to_string()],
+		);
+
+		// builds of no known quality, like OSS ones, can't tell what it is
+		if QUALITY_APPS
+			.iter()
+			.any(|a| a.application_name == APPLICATION_NAME)
+		{
+			assert_eq!(installs.len(), 1);
+			assert_eq!(installs[0].application_name, APPLICATION_NAME);
+		} else {
+			assert!(installs.is_empty());
+		}
+	}
+
+	#[test]
+	fn test_fill_from_registry() {
+		let registered = RegistryInstall {
+			quality: "insider",
+			application_name: "code-insiders",
+			entrypoint: PathBuf::from("/a/bin/code-insiders.cmd"),
+			version: Some("1.91.0-insider".to_string()),
+		};
+		let mut installs = vec![
+			FoundInstall {
+				entrypoint: PathBuf::from("/A/bin/code-insiders.cmd"),
+				kind: InstallKind::Standard,
+				details: Default::default(),
+				error: Some("no product.json".to_string()),
+			},
+			FoundInstall {
+				entrypoint: PathBuf::from("/b/bin/code.cmd"),
+				kind: InstallKind::Standard,
+				details: Default::default(),
+				error: Some("no product.json".to_string()),
+			},
+		];
+
+		fill_from_registry(&mut installs, &[registered]);
+		assert_eq!(
+			installs[0].details,
+			InstallDetails {
+				quality: Some("insider".to_string()),
+				version: Some("1.91.0-insider".to_string()),
+				commit: None,
+			}
+		);
+		// the files still couldn't be read, which is shown with what was filled in
+		assert_eq!(installs[0].error.as_deref(), Some("no product.json"));
+		assert_eq!(installs[1].details, InstallDetails::default());
+		assert_eq!(installs[1].error.as_deref(), Some("no product.json"));
+	}
+
+	#[tokio::test]
+	async fn test_recovers_dangling_install() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let vm = CodeVersionManager::new(log::Logger::test(), &lp, Platform::LinuxX64);
+
+		// the chosen build was removed, but is also kept at another path
+		let version =
+			RequestedVersion::QualityCommit(options::Quality::Stable, "c0ffee".to_string());
+		let moved = dir.path().join("moved/bin/code");
+		touch(&moved);
+		write_product(&dir.path().join("moved/resources/app"), "stable", "1.90.0");
+		vm.set_preferred_version(version.clone(), dir.path().join("gone/bin/code"))
+			.await
+			.unwrap();
+		vm.set_preferred_version(RequestedVersion::Path("moved".to_string()), moved.clone())
+			.await
+			.unwrap();
+
+		assert_eq!(vm.try_get_entrypoint(&version).await, Some(moved.clone()));
+		let stored = vm.state.load();
+		assert!(stored
+			.versions
+			.iter()
+			.any(|(v, p)| *v == version && *p == moved));
+
+		// nothing else has the commit
+		let other = RequestedVersion::QualityCommit(options::Quality::Stable, "decaf".to_string());
+		vm.set_preferred_version(other.clone(), dir.path().join("gone/bin/code"))
+			.await
+			.unwrap();
+		assert_eq!(vm.try_get_entrypoint(&other).await, None);
+	}
+
+	/// Fails if any request is made.
+	struct NoHttp;
+
+	#[async_trait::async_trait]
+	impl crate::util::http::SimpleHttp for NoHttp {
+		async fn make_request(
+			&self,
+			_method: &'static str,
+			url: String,
+		) -> Result<crate::util::http::SimpleResponse, AnyError> {
+			panic!("unexpected request to {}", url)
+		}
+	}
+
+	/// Serves an update service that knows one commit, whose download is the
+	/// tarball, or fails with `download_status` if it's set.
+	struct MockUpdates {
+		commit: String,
+		tarball: Vec<u8>,
+		download_status: Option<hyper::StatusCode>,
+		requests: std::sync::Mutex<Vec<String>>,
+	}
+
+	#[async_trait::async_trait]
+	impl crate::util::http::SimpleHttp for MockUpdates {
+		async fn make_request(
+			&self,
+			_method: &'static str,
+			url: String,
+		) -> Result<crate::util::http::SimpleResponse, AnyError> {
+			self.requests.lock().unwrap().push(url.clone());
+			let (status_code, body) = if url.contains("/api/versions/") {
+				match url.contains(&format!("commit:{}/", self.commit)) {
+					true => (
+						hyper::StatusCode::OK,
+						format!(r#"{{"version":"{}","name":"1.90.0"}}"#, self.commit).into_bytes(),
+					),
+					false => (hyper::StatusCode::NOT_FOUND, vec![]),
+				}
+			} else {
+				match self.download_status {
+					Some(s) => (s, vec![]),
+					None => (hyper::StatusCode::OK, self.tarball.clone()),
+				}
+			};
+
+			Ok(crate::util::http::SimpleResponse {
+				status_code,
+				headers: hyper::HeaderMap::new(),
+				read: Box::pin(std::io::Cursor::new(body)),
+				url: url::Url::parse("https://update.test/code-stable.tar.gz").ok(),
+			})
+		}
+	}
+
+	/// Makes a tarball of a desktop build in a top-level folder, as they're
+	/// published.
+	fn desktop_tarball() -> Vec<u8> {
+		let bin = DESKTOP_CLI_RELATIVE_PATH.split(',').next().unwrap();
+		let gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+		let mut builder = ::tar::Builder::new(gz);
+		let mut header = ::tar::Header::new_gnu();
+		header.set_size(0);
+		header.set_mode(0o755);
+		header.set_cksum();
+		builder
+			.append_data(
+				&mut header,
+				format!("VSCode-linux-x64/{}", bin),
+				std::io::empty(),
+			)
+			.unwrap();
+		builder.into_inner().unwrap().finish().unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_provisions_by_download() {
+		// configures the update service in the policy, so it's run in a child
+		if !crate::util::test_env::in_child_with_env(
+			concat!(module_path!(), "::test_provisions_by_download"),
+			&[],
+		) {
+			return;
+		}
+		crate::policy::configure(crate::policy::Policy {
+			update_endpoint: Some("https://update.test".to_string()),
+			..Default::default()
+		});
+
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let vm = CodeVersionManager::new(log::Logger::test(), &lp, Platform::LinuxX64);
+		let commit = "6c3e3dba23e8fadc360aed75ce363ba185c49794";
+		let version = RequestedVersion::QualityCommit(options::Quality::Stable, commit.to_string());
+		let http = std::sync::Arc::new(MockUpdates {
+			commit: commit.to_string(),
+			tarball: desktop_tarball(),
+			download_status: None,
+			requests: Default::default(),
+		});
+		let update_service = UpdateService::new(log::Logger::test(), http.clone());
+
+		// the dangling install is replaced by the download
+		vm.set_preferred_version(version.clone(), dir.path().join("gone/bin/code"))
+			.await
+			.unwrap();
+		let entrypoint = vm.provision(&update_service, &version).await.unwrap();
+		assert!(entrypoint.starts_with(dir.path().join("desktop")));
+		assert!(entrypoint.exists());
+		assert_eq!(vm.try_get_entrypoint(&version).await, Some(entrypoint));
+		assert_eq!(
+			*http.requests.lock().unwrap(),
+			vec![
+				format!(
+					"https://update.test/api/versions/commit:{}/linux-x64/stable",
+					commit
+				),
+				format!("https://update.test/commit:{}/linux-x64/stable", commit),
+			]
+		);
+
+		// commits the service doesn't know, and builds that were taken down
+		let unknown = RequestedVersion::QualityCommit(
+			options::Quality::Stable,
+			"0000000000000000000000000000000000000000".to_string(),
+		);
+		let err = vm.provision(&update_service, &unknown).await.unwrap_err();
+		assert!(
+			matches!(err, AnyError::CodeError(CodeError::UnknownCommit { .. })),
+			"{}",
+			err
+		);
+
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let vm = CodeVersionManager::new(log::Logger::test(), &lp, Platform::LinuxX64);
+		let http = std::sync::Arc::new(MockUpdates {
+			commit: commit.to_string(),
+			tarball: vec![],
+			download_status: Some(hyper::StatusCode::GONE),
+			requests: Default::default(),
+		});
+		let update_service = UpdateService::new(log::Logger::test(), http);
+		let err = vm.provision(&update_service, &version).await.unwrap_err();
+		assert!(
+			matches!(
+				err,
+				AnyError::CodeError(CodeError::CommitNotDownloadable { .. })
+			),
+			"{}",
+			err
+		);
+		assert_eq!(vm.try_get_entrypoint(&version).await, None);
+	}
+
+	#[tokio::test]
+	async fn test_provisions_into_cache() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let vm = CodeVersionManager::new(log::Logger::test(), &lp, Platform::LinuxX64);
+		let update_service = UpdateService::new(log::Logger::test(), std::sync::Arc::new(NoHttp));
+
+		let commit = "6c3e3dba23e8fadc360aed75ce363ba185c49794";
+		let release = Release {
+			name: "1.90.0".to_string(),
+			platform: Platform::LinuxX64,
+			target: TargetKind::Archive,
+			quality: options::Quality::Stable,
+			commit: commit.to_string(),
+		};
+
+		// downloaded before, so the cached build is used
+		make_fake_vscode_install(
+			&dir.path()
+				.join("desktop")
+				.join(format!("stable-{}", commit))
+				.join("VSCode-linux-x64"),
+		);
+		vm.set_preferred_version(RequestedVersion::Default, dir.path().join("gone/bin/code"))
+			.await
+			.unwrap();
+
+		let entrypoint = vm
+			.install_release(&update_service, &RequestedVersion::Default, release)
+			.await
+			.unwrap();
+		assert!(entrypoint.starts_with(dir.path().join("desktop")));
+		assert_eq!(
+			vm.try_get_entrypoint(&RequestedVersion::Default).await,
+			Some(entrypoint)
+		);
+	}
+
+	#[tokio::test]
+	async fn test_no_desktop_builds_for_legacy_platforms() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let vm = CodeVersionManager::new(log::Logger::test(), &lp, Platform::LinuxX64Legacy);
+		let update_service = UpdateService::new(log::Logger::test(), std::sync::Arc::new(NoHttp));
+
+		let version = RequestedVersion::QualityCommit(
+			options::Quality::Stable,
+			"6c3e3dba23e8fadc360aed75ce363ba185c49794".to_string(),
+		);
+		for version in [RequestedVersion::Default, version] {
+			let err = vm.provision(&update_service, &version).await.unwrap_err();
+			assert!(
+				matches!(
+					err,
+					AnyError::CodeError(CodeError::NoDesktopBuildForPlatform(_))
+				),
+				"{}",
+				err
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_cleans_stale_entries() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let vm = CodeVersionManager::new(log::Logger::test(), &lp, Platform::LinuxX64);
+
+		let used = dir.path().join("desktop/stable-used/bin/code");
+		touch(&used);
+		touch(&dir.path().join("desktop/stable-unused/bin/code"));
+		std::fs::create_dir_all(dir.path().join("desktop/stable-next.staging")).unwrap();
+
+		let live = RequestedVersion::Path("used".to_string());
+		let dead = RequestedVersion::Commit("a".to_string());
+		let dead_selected = RequestedVersion::Commit("b".to_string());
+		vm.set_preferred_version(live.clone(), used.clone())
+			.await
+			.unwrap();
+		vm.set_preferred_version(dead.clone(), dir.path().join("old/bin/code"))
+			.await
+			.unwrap();
+		vm.set_preferred_version(dead_selected.clone(), dir.path().join("gone/bin/code"))
+			.await
+			.unwrap();
+
+		let stale = vm.stale_entries();
+		assert_eq!(
+			stale,
+			vec![
+				StaleEntry {
+					version: dead.clone(),
+					path: dir.path().join("old/bin/code"),
+					selected: false,
+				},
+				StaleEntry {
+					version: dead_selected.clone(),
+					path: dir.path().join("gone/bin/code"),
+					selected: true,
+				},
+			]
+		);
+		assert_eq!(
+			vm.unused_builds(),
+			vec![dir.path().join("desktop/stable-unused")]
+		);
+
+		// the selected version stays in use until it's removed too
+		vm.remove_entries(&stale[..1]).unwrap();
+		assert_eq!(vm.get_preferred_version(), dead_selected);
+		assert_eq!(vm.stale_entries(), stale[1..].to_vec());
+
+		vm.remove_entries(&stale[1..]).unwrap();
+		assert_eq!(vm.get_preferred_version(), RequestedVersion::Default);
+		let stored = vm.state.load();
+		assert_eq!(
+			stored.versions,
+			vec![(live.clone(), used.clone().into_os_string())]
+		);
+
+		vm.remove_builds(&vm.unused_builds()).unwrap();
+		assert!(!dir.path().join("desktop/stable-unused").exists());
+		assert!(used.exists());
+		assert_eq!(vm.try_get_entrypoint(&live).await, Some(used));
+	}
 }
@@ -12,12 +12,17 @@ use futures::Future;
 use tokio::fs::remove_dir_all;
 
 use crate::{
+	constants::DOWNLOAD_CACHE_LOCK_TIMEOUT,
 	state::PersistedState,
-	util::errors::{wrap, AnyError, WrappedError},
+	util::{
+		errors::{wrap, AnyError, WrappedError},
+		file_lock,
+	},
 };
 
 const KEEP_LRU: usize = 5;
 const STAGING_SUFFIX: &str = ".staging";
+const LOCK_SUFFIX: &str = ".lock";
 const RENAME_ATTEMPTS: u32 = 20;
 const RENAME_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
 
@@ -53,6 +58,31 @@ impl DownloadCache {
 		Some(p)
 	}
 
+	/// Gets the names of the items in the cache, leaving out ones still being
+	/// created.
+	pub fn entries(&self) -> Vec<String> {
+		let dir = match std::fs::read_dir(&self.path) {
+			Ok(d) => d,
+			Err(_) => return vec![],
+		};
+
+		let mut names: Vec<String> = dir
+			.flatten()
+			.filter(|e| e.path().is_dir())
+			.map(|e| e.file_name().to_string_lossy().to_string())
+			.filter(|n| !n.ends_with(STAGING_SUFFIX))
+			.collect();
+		names.sort();
+		names
+	}
+
+	/// Gets the names of the items in the cache that were used recently, most
+	/// recent first. Items used long ago, or whose use wasn't tracked, aren't
+	/// included.
+	pub fn recently_used(&self) -> Vec<String> {
+		self.state.load()
+	}
+
 	/// Removes the item from the cache, if it exists
 	pub fn delete(&self, name: &str) -> Result<(), WrappedError> {
 		let f = self.path.join(name);
@@ -65,10 +95,27 @@ impl DownloadCache {
 		})
 	}
 
+	/// Removes the item from the cache unless it's being created, returning
+	/// whether it was removed.
+	pub fn delete_unless_creating(&self, name: &str) -> Result<bool, AnyError> {
+		let _lock = match file_lock::try_acquire(&self.lock_path(name))? {
+			Some(l) => l.remove_on_release(),
+			None => return Ok(false),
+		};
+		self.delete(name)?;
+		Ok(true)
+	}
+
+	/// Gets the path of the lock held while the item is created.
+	fn lock_path(&self, name: &str) -> PathBuf {
+		self.path.join(format!("{}{}", name, LOCK_SUFFIX))
+	}
+
 	/// Calls the function to create the cached folder if it doesn't exist,
 	/// returning the path where the folder is. Note that the path passed to
 	/// the `do_create` method is a staging path and will not be the same as the
-	/// final returned path.
+	/// final returned path. If another process is creating the same folder,
+	/// this waits for it to finish instead.
 	pub async fn create<F, T>(
 		&self,
 		name: impl AsRef<str>,
@@ -84,8 +131,17 @@ impl DownloadCache {
 			return Ok(target_dir);
 		}
 
+		create_dir_all(&self.path).map_err(|e| wrap(e, "error creating cache directory"))?;
+		let _lock = file_lock::acquire(&self.lock_path(name), DOWNLOAD_CACHE_LOCK_TIMEOUT)
+			.await?
+			.remove_on_release();
+		if target_dir.exists() {
+			let _ = self.touch(name.to_string());
+			return Ok(target_dir);
+		}
+
 		let temp_dir = self.path.join(format!("{}{}", name, STAGING_SUFFIX));
-		let _ = remove_dir_all(&temp_dir).await; // cleanup any existing
+		let _ = remove_dir_all(&temp_dir).await; // cleanup any left by a failed create
 
 		create_dir_all(&temp_dir).map_err(|e| wrap(e, "error creating server directory"))?;
 		do_create(temp_dir.clone()).await?;
@@ -3,16 +3,22 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use futures::{future, FutureExt};
+use serde::Serialize;
 use tokio::{
-	io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+	io::{
+		AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+		BufReader,
+	},
 	pin,
 	sync::mpsc,
 };
 
 use crate::{
-	rpc::{self, MaybeSync, Serialization},
+	rpc::{self, MaybeSync, PartialIncoming, ResponseError, Serialization},
+	rpc_trace::{ConnectionTrace, Format},
 	util::{
-		errors::InvalidRpcDataError,
+		errors::{CodeError, InvalidRpcDataError},
 		sync::{Barrier, Receivable},
 	},
 };
@@ -36,6 +42,19 @@ impl Serialization for JsonRpcSerializer {
 	}
 }
 
+/// Error code for a batch member that is not a valid request, or an empty batch.
+const ERROR_CODE_INVALID_REQUEST: i32 = -32600;
+
+/// Error code for a batch that is not valid JSON.
+const ERROR_CODE_PARSE_ERROR: i32 = -32700;
+
+/// Error for a request whose ID could not be determined.
+#[derive(Serialize)]
+struct NullIdErrorResponse {
+	id: (),
+	error: ResponseError,
+}
+
 /// Creates a new RPC Builder that serializes to JSON.
 #[allow(dead_code)]
 pub fn new_json_rpc() -> rpc::RpcBuilder<JsonRpcSerializer> {
@@ -52,8 +71,10 @@ pub async fn start_json_rpc<C: Send + Sync + 'static, S: Clone>(
 ) -> io::Result<Option<S>> {
 	let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(8);
 	let mut read = BufReader::new(read);
+	let max_frame_size = dispatcher.max_frame_size();
+	let trace = ConnectionTrace::new(Format::Json);
 
-	let mut read_buf = String::new();
+	let mut read_buf = Vec::new();
 	let shutdown_fut = shutdown_rx.wait();
 	pin!(shutdown_fut);
 
@@ -61,15 +82,24 @@ pub async fn start_json_rpc<C: Send + Sync + 'static, S: Clone>(
 		tokio::select! {
 			r = &mut shutdown_fut => return Ok(r.ok()),
 			Some(w) = write_rx.recv() => {
+				trace.outbound(&w);
 				write.write_all(&w).await?;
 			},
 			Some(w) = msg_rx.recv_msg() => {
+				trace.outbound(&w);
 				write.write_all(&w).await?;
 			},
-			n = read.read_line(&mut read_buf) => {
+			n = read_line_limited(&mut read, &mut read_buf, max_frame_size) => {
 				let r = match n {
 					Ok(0) => return Ok(None),
-					Ok(n) => dispatcher.dispatch(read_buf[..n].as_bytes()),
+					Ok(_) => {
+						trace.inbound(&read_buf);
+						if is_batch(&read_buf) {
+							dispatch_batch(&dispatcher, &read_buf, &write_tx).await
+						} else {
+							dispatcher.dispatch(&read_buf)
+						}
+					}
 					Err(e) => return Err(e)
 				};
 
@@ -77,6 +107,7 @@ pub async fn start_json_rpc<C: Send + Sync + 'static, S: Clone>(
 
 				match r {
 					MaybeSync::Sync(Some(v)) => {
+						trace.outbound(&v);
 						write.write_all(&v).await?;
 					},
 					MaybeSync::Sync(None) => continue,
@@ -88,6 +119,9 @@ pub async fn start_json_rpc<C: Send + Sync + 'static, S: Clone>(
 							}
 						});
 					},
+					MaybeSync::Partial(results) => {
+						dispatcher.register_partials(write_tx.clone(), results);
+					},
 					MaybeSync::Stream((dto, fut)) => {
 						if let Some(dto) = dto {
 							dispatcher.register_stream(write_tx.clone(), dto).await;
@@ -104,3 +138,273 @@ pub async fn start_json_rpc<C: Send + Sync + 'static, S: Clone>(
 		}
 	}
 }
+
+fn is_batch(line: &[u8]) -> bool {
+	line.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[')
+}
+
+fn invalid_request(code: i32, message: impl Into<String>) -> Vec<u8> {
+	JsonRpcSerializer {}.serialize(NullIdErrorResponse {
+		id: (),
+		error: ResponseError {
+			code,
+			message: message.into(),
+		},
+	})
+}
+
+/// Dispatches the members of a JSON-RPC batch concurrently, resolving to the
+/// batch response once they've all completed. Members that are notifications
+/// don't get a response, and the batch isn't answered if all of them are.
+/// Streaming and duplex methods are dispatched as usual, with everything but
+/// their final response sent outside of the batch.
+async fn dispatch_batch<C: Send + Sync + 'static>(
+	dispatcher: &rpc::RpcDispatcher<JsonRpcSerializer, C>,
+	body: &[u8],
+	write_tx: &mpsc::Sender<Vec<u8>>,
+) -> MaybeSync {
+	let members = match serde_json::from_slice::<Vec<serde_json::Value>>(body) {
+		Ok(m) => m,
+		Err(e) => {
+			return MaybeSync::Sync(Some(invalid_request(ERROR_CODE_PARSE_ERROR, e.to_string())))
+		}
+	};
+
+	if members.is_empty() {
+		return MaybeSync::Sync(Some(invalid_request(
+			ERROR_CODE_INVALID_REQUEST,
+			"empty batch",
+		)));
+	}
+
+	let mut responses = Vec::with_capacity(members.len());
+	for member in members {
+		let body = serde_json::to_vec(&member).unwrap();
+		let partial = match serde_json::from_value::<PartialIncoming>(member) {
+			Ok(p) if p.method.is_some() => p,
+			_ => {
+				let r = invalid_request(ERROR_CODE_INVALID_REQUEST, "invalid request");
+				responses.push(future::ready(Some(r)).boxed());
+				continue;
+			}
+		};
+
+		match dispatcher.dispatch_with_partial(&body, partial) {
+			MaybeSync::Sync(r) => responses.push(future::ready(r).boxed()),
+			MaybeSync::Future(fut) => responses.push(fut),
+			MaybeSync::Partial(results) => {
+				dispatcher.register_partials(write_tx.clone(), results);
+			}
+			MaybeSync::Stream((dto, fut)) => {
+				if let Some(dto) = dto {
+					dispatcher.register_stream(write_tx.clone(), dto).await;
+				}
+				responses.push(fut);
+			}
+		}
+	}
+
+	MaybeSync::Future(
+		async move {
+			let responses = future::join_all(responses).await;
+			let mut out = vec![b'['];
+			for r in responses.iter().flatten() {
+				if out.len() > 1 {
+					out.push(b',');
+				}
+				out.extend_from_slice(r.strip_suffix(b"\n").unwrap_or(r));
+			}
+
+			if out.len() == 1 {
+				return None;
+			}
+
+			out.extend_from_slice(b"]\n");
+			Some(out)
+		}
+		.boxed(),
+	)
+}
+
+/// Reads a line into the buffer, failing with `CodeError::RpcFrameTooLarge`
+/// rather than buffering lines longer than the limit. Partially-read lines
+/// are kept in the buffer if the future is cancelled.
+async fn read_line_limited(
+	read: &mut (impl AsyncBufRead + Unpin),
+	buf: &mut Vec<u8>,
+	limit: usize,
+) -> io::Result<usize> {
+	let remaining = (limit + 1).saturating_sub(buf.len()) as u64;
+	let n = read.take(remaining).read_until(b'\n', buf).await?;
+	if buf.len() > limit && buf.last() != Some(&b'\n') {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			CodeError::RpcFrameTooLarge {
+				size: buf.len() as u64,
+				limit,
+			},
+		));
+	}
+
+	Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use serde_json::{json, Value};
+	use tokio::io::{DuplexStream, Lines, ReadHalf, WriteHalf};
+
+	use super::*;
+	use crate::{
+		log,
+		rpc::ERROR_CODE_METHOD_NOT_FOUND,
+		util::sync::{new_barrier, BarrierOpener},
+	};
+
+	struct TestClient {
+		write: WriteHalf<DuplexStream>,
+		lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+		_close: BarrierOpener<()>,
+	}
+
+	impl TestClient {
+		fn new() -> Self {
+			let mut rpc = new_json_rpc().methods(());
+			rpc.register_sync("subtract", |[a, b]: [i32; 2], _| Ok(a - b));
+			rpc.register_async("sum", |n: Vec<i32>, _| async move {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+				Ok(n.iter().sum::<i32>())
+			});
+
+			let (server_io, client_io) = tokio::io::duplex(4096);
+			let (closed, close) = new_barrier::<()>();
+			let (read, write) = tokio::io::split(server_io);
+			tokio::spawn(start_json_rpc(
+				rpc.build(log::Logger::test()),
+				read,
+				write,
+				(),
+				closed,
+			));
+
+			let (read, write) = tokio::io::split(client_io);
+			Self {
+				write,
+				lines: BufReader::new(read).lines(),
+				_close: close,
+			}
+		}
+
+		async fn exchange(&mut self, request: &str) -> Value {
+			self.write
+				.write_all(format!("{}\n", request).as_bytes())
+				.await
+				.unwrap();
+			let line = self.lines.next_line().await.unwrap().unwrap();
+			serde_json::from_str(&line).unwrap()
+		}
+	}
+
+	fn error_code(v: &Value) -> i64 {
+		v["error"]["code"].as_i64().unwrap()
+	}
+
+	// examples from https://www.jsonrpc.org/specification#examples, with
+	// numeric IDs since that's what the dispatcher supports
+
+	#[tokio::test]
+	async fn test_batch() {
+		let mut c = TestClient::new();
+		let r = c
+			.exchange(
+				r#"[
+				{"jsonrpc": "2.0", "method": "sum", "params": [1,2,4], "id": 1},
+				{"jsonrpc": "2.0", "method": "notify_hello", "params": [7]},
+				{"jsonrpc": "2.0", "method": "subtract", "params": [42,23], "id": 2},
+				{"foo": "boo"},
+				{"jsonrpc": "2.0", "method": "foo.get", "params": {"name": "myself"}, "id": 5},
+				{"jsonrpc": "2.0", "method": "get_data", "id": 9}
+			]"#
+				.replace('\n', "")
+				.as_str(),
+			)
+			.await;
+
+		let r = r.as_array().unwrap();
+		assert_eq!(r.len(), 5);
+		assert_eq!(r[0], json!({ "id": 1, "result": 7 }));
+		assert_eq!(r[1], json!({ "id": 2, "result": 19 }));
+		assert_eq!(r[2]["id"], Value::Null);
+		assert_eq!(error_code(&r[2]), ERROR_CODE_INVALID_REQUEST as i64);
+		assert_eq!(r[3]["id"], 5);
+		assert_eq!(error_code(&r[3]), ERROR_CODE_METHOD_NOT_FOUND as i64);
+		assert_eq!(r[4]["id"], 9);
+	}
+
+	#[tokio::test]
+	async fn test_invalid_batches() {
+		let mut c = TestClient::new();
+
+		let r = c.exchange(r#"[{"jsonrpc": "2.0", "method": "sum", "params": [1,2,4], "id": 1},{"jsonrpc": "2.0", "method"]"#).await;
+		assert_eq!(error_code(&r), ERROR_CODE_PARSE_ERROR as i64);
+
+		let r = c.exchange("[]").await;
+		assert_eq!(r["id"], Value::Null);
+		assert_eq!(error_code(&r), ERROR_CODE_INVALID_REQUEST as i64);
+
+		let r = c.exchange("[1]").await;
+		assert_eq!(r.as_array().unwrap().len(), 1);
+		assert_eq!(error_code(&r[0]), ERROR_CODE_INVALID_REQUEST as i64);
+
+		let r = c.exchange("[1,2,3]").await;
+		let r = r.as_array().unwrap();
+		assert_eq!(r.len(), 3);
+		assert!(r
+			.iter()
+			.all(|m| error_code(m) == ERROR_CODE_INVALID_REQUEST as i64));
+	}
+
+	#[tokio::test]
+	async fn test_notification_batch() {
+		let mut c = TestClient::new();
+
+		// nothing is returned for the batch, so the next line is the response
+		// to the request that follows it
+		let batch = r#"[{"jsonrpc": "2.0", "method": "notify_sum", "params": [1,2,4]},{"jsonrpc": "2.0", "method": "notify_hello", "params": [7]}]"#;
+		c.write
+			.write_all(format!("{}\n", batch).as_bytes())
+			.await
+			.unwrap();
+		let r = c
+			.exchange(r#"{"method": "subtract", "params": [1, 2], "id": 3}"#)
+			.await;
+		assert_eq!(r, json!({ "id": 3, "result": -1 }));
+	}
+
+	#[tokio::test]
+	async fn test_read_line_limited() {
+		let input = format!("{}\n{}\n", "a".repeat(10), "b".repeat(11));
+		let mut read = BufReader::new(input.as_bytes());
+		let mut buf = Vec::new();
+
+		assert_eq!(
+			read_line_limited(&mut read, &mut buf, 10).await.unwrap(),
+			11
+		);
+		assert_eq!(buf, format!("{}\n", "a".repeat(10)).as_bytes());
+
+		buf.clear();
+		let e = read_line_limited(&mut read, &mut buf, 10)
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			e.get_ref().and_then(|e| e.downcast_ref::<CodeError>()),
+			Some(CodeError::RpcFrameTooLarge {
+				size: 11,
+				limit: 10
+			})
+		));
+	}
+}
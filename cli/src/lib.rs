@@ -12,6 +12,8 @@ pub mod log;
 pub mod commands;
 pub mod desktop;
 pub mod options;
+pub mod policy;
+pub mod rpc_trace;
 pub mod self_update;
 pub mod state;
 pub mod tunnels;
@@ -0,0 +1,172 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::sync::{
+	atomic::{AtomicU64, Ordering},
+	mpsc, Arc,
+};
+
+use super::{Level, LogFields, LogSink};
+
+/// Number of logs that can wait for a queued sink before new ones are dropped.
+pub const DEFAULT_LOG_QUEUE_SIZE: usize = 1024;
+
+struct Record {
+	level: Level,
+	target: String,
+	prefix: String,
+	fields: Vec<(&'static str, String)>,
+	message: String,
+}
+
+/// Sink that hands logs to another sink on a background thread, so that a
+/// sink that blocks, such as a syslog daemon that's not reading its socket,
+/// can't stall whatever is logging. Once the queue is full, new logs are
+/// dropped, and the number dropped is logged when there's room again.
+#[derive(Clone)]
+pub struct QueuedLogSink {
+	level: Level,
+	tx: mpsc::SyncSender<Record>,
+	dropped: Arc<AtomicU64>,
+}
+
+impl QueuedLogSink {
+	pub fn new(level: Level, capacity: usize, sink: impl LogSink + 'static) -> Self {
+		let (tx, rx) = mpsc::sync_channel::<Record>(capacity);
+		let dropped = Arc::new(AtomicU64::new(0));
+
+		let dropped_ = dropped.clone();
+		std::thread::spawn(move || {
+			while let Ok(r) = rx.recv() {
+				let n = dropped_.swap(0, Ordering::SeqCst);
+				if n > 0 {
+					sink.write_log(
+						Level::Warn,
+						"",
+						&format!("{} log messages were dropped while the log was busy", n),
+					);
+				}
+
+				sink.write_log_with(r.level, &r.target, &r.prefix, &r.fields, &r.message);
+			}
+		});
+
+		Self { level, tx, dropped }
+	}
+}
+
+impl LogSink for QueuedLogSink {
+	fn write_log(&self, level: Level, prefix: &str, message: &str) {
+		self.write_log_from(level, super::DEFAULT_TARGET, prefix, message)
+	}
+
+	fn write_log_from(&self, level: Level, target: &str, prefix: &str, message: &str) {
+		self.write_log_with(level, target, prefix, &[], message)
+	}
+
+	fn write_log_with(
+		&self,
+		level: Level,
+		target: &str,
+		prefix: &str,
+		fields: &LogFields,
+		message: &str,
+	) {
+		if level < self.level {
+			return;
+		}
+
+		let record = Record {
+			level,
+			target: target.to_string(),
+			prefix: prefix.to_string(),
+			fields: fields.to_vec(),
+			message: message.to_string(),
+		};
+		if let Err(mpsc::TrySendError::Full(_)) = self.tx.try_send(record) {
+			self.dropped.fetch_add(1, Ordering::SeqCst);
+		}
+	}
+
+	fn write_result(&self, _message: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		sync::Mutex,
+		time::{Duration, Instant},
+	};
+
+	use super::*;
+
+	/// Sink that records messages, waiting for a go-ahead before each one.
+	#[derive(Clone)]
+	struct BlockingSink {
+		written: Arc<Mutex<Vec<String>>>,
+		entered: mpsc::SyncSender<()>,
+		proceed: Arc<Mutex<mpsc::Receiver<()>>>,
+	}
+
+	impl LogSink for BlockingSink {
+		fn write_log(&self, level: Level, _prefix: &str, message: &str) {
+			self.entered.send(()).ok();
+			self.proceed.lock().unwrap().recv().ok();
+			self.written
+				.lock()
+				.unwrap()
+				.push(format!("{} {}", level, message));
+		}
+
+		fn write_result(&self, _message: &str) {}
+	}
+
+	#[test]
+	fn test_drops_logs_when_full() {
+		let written = Arc::new(Mutex::new(vec![]));
+		let (entered_tx, entered) = mpsc::sync_channel(10);
+		let (proceed, proceed_rx) = mpsc::channel();
+		let sink = QueuedLogSink::new(
+			Level::Debug,
+			2,
+			BlockingSink {
+				written: written.clone(),
+				entered: entered_tx,
+				proceed: Arc::new(Mutex::new(proceed_rx)),
+			},
+		);
+
+		sink.write_log(Level::Trace, "", "below the level");
+		sink.write_log(Level::Info, "", "1");
+		// the first is taken off the queue and blocks the sink...
+		entered.recv_timeout(Duration::from_secs(5)).unwrap();
+		// ...so two fill up the queue and the rest are dropped, without waiting
+		let start = Instant::now();
+		for i in 2..=5 {
+			sink.write_log(Level::Info, "", &i.to_string());
+		}
+		assert!(start.elapsed() < Duration::from_secs(1));
+
+		for _ in 0..4 {
+			proceed.send(()).unwrap();
+		}
+		for _ in 0..50 {
+			if written.lock().unwrap().len() == 4 {
+				break;
+			}
+			std::thread::sleep(Duration::from_millis(10));
+		}
+
+		assert_eq!(
+			*written.lock().unwrap(),
+			vec![
+				"info 1",
+				"warn 2 log messages were dropped while the log was busy",
+				"info 2",
+				"info 3",
+			]
+		);
+	}
+}
@@ -0,0 +1,326 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Sinks that write to the operating system's log: journald or syslog on
+//! Unix, and the Event Log on Windows.
+
+use super::Level;
+
+/// Gets the syslog severity for the level, which journald also uses as its
+/// priority.
+pub fn syslog_severity(level: Level) -> Option<u8> {
+	match level {
+		Level::Trace | Level::Debug => Some(7), // debug
+		Level::Info => Some(6),                 // informational
+		Level::Warn => Some(4),                 // warning
+		Level::Error => Some(3),                // error
+		Level::Critical => Some(2),             // critical
+		Level::Off => None,
+	}
+}
+
+pub const EVENTLOG_ERROR_TYPE: u16 = 0x0001;
+pub const EVENTLOG_WARNING_TYPE: u16 = 0x0002;
+pub const EVENTLOG_INFORMATION_TYPE: u16 = 0x0004;
+
+/// Gets the Event Log entry type for the level. The Event Log has no debug
+/// type, so verbose logs are informational.
+pub fn event_log_type(level: Level) -> Option<u16> {
+	match level {
+		Level::Trace | Level::Debug | Level::Info => Some(EVENTLOG_INFORMATION_TYPE),
+		Level::Warn => Some(EVENTLOG_WARNING_TYPE),
+		Level::Error | Level::Critical => Some(EVENTLOG_ERROR_TYPE),
+		Level::Off => None,
+	}
+}
+
+/// Facility for messages from user programs.
+#[cfg_attr(not(unix), allow(dead_code))]
+const SYSLOG_FACILITY_USER: u8 = 1;
+
+#[cfg_attr(not(unix), allow(dead_code))]
+/// Formats a message for the journal's native protocol, which keeps the
+/// severity and multi-line messages intact.
+fn format_journal(severity: u8, ident: &str, message: &str) -> Vec<u8> {
+	let mut out = format!("PRIORITY={}\nSYSLOG_IDENTIFIER={}\n", severity, ident).into_bytes();
+	// values with newlines are written as a length followed by the raw bytes
+	out.extend_from_slice(b"MESSAGE\n");
+	out.extend_from_slice(&(message.len() as u64).to_le_bytes());
+	out.extend_from_slice(message.as_bytes());
+	out.push(b'\n');
+	out
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+/// Formats a message as a BSD syslog line, as local daemons accept.
+fn format_syslog(severity: u8, ident: &str, pid: u32, message: &str) -> Vec<u8> {
+	format!(
+		"<{}>{}[{}]: {}",
+		SYSLOG_FACILITY_USER * 8 + severity,
+		ident,
+		pid,
+		message
+	)
+	.into_bytes()
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+/// Cuts the message to at most `max_len` bytes, at a character boundary, and
+/// notes that it was cut.
+fn truncate_message(message: &str, max_len: usize) -> String {
+	const NOTE: &str = "... [truncated]";
+	if message.len() <= max_len {
+		return message.to_string();
+	}
+
+	let mut end = max_len.saturating_sub(NOTE.len());
+	while !message.is_char_boundary(end) {
+		end -= 1;
+	}
+	format!("{}{}", &message[..end], NOTE)
+}
+
+#[cfg(unix)]
+pub use unix::SyslogSink;
+
+#[cfg(unix)]
+mod unix {
+	use std::{os::unix::net::UnixDatagram, sync::Arc};
+
+	use super::{format_journal, format_syslog, syslog_severity, truncate_message};
+	use crate::log::{Level, LogSink};
+
+	const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+	const SYSLOG_SOCKETS: &[&str] = &["/dev/log", "/var/run/syslog", "/var/run/log"];
+
+	/// Length a message too big for one datagram is cut down to at most,
+	/// halving it until it fits. macOS only allows 2KB datagrams by default.
+	const MIN_TRUNCATED_LEN: usize = 1024;
+
+	#[derive(Clone, Copy)]
+	enum Protocol {
+		Journal,
+		Syslog,
+	}
+
+	/// Writes logs to journald if it's running, or the local syslog daemon.
+	#[derive(Clone)]
+	pub struct SyslogSink {
+		socket: Arc<UnixDatagram>,
+		protocol: Protocol,
+		ident: &'static str,
+	}
+
+	impl SyslogSink {
+		pub fn connect(ident: &'static str) -> std::io::Result<Self> {
+			let socket = UnixDatagram::unbound()?;
+			if socket.connect(JOURNAL_SOCKET).is_ok() {
+				return Ok(Self {
+					socket: Arc::new(socket),
+					protocol: Protocol::Journal,
+					ident,
+				});
+			}
+
+			let mut last_err = None;
+			for path in SYSLOG_SOCKETS {
+				match socket.connect(path) {
+					Ok(()) => {
+						return Ok(Self {
+							socket: Arc::new(socket),
+							protocol: Protocol::Syslog,
+							ident,
+						})
+					}
+					Err(e) => last_err = Some(e),
+				}
+			}
+
+			Err(last_err.unwrap())
+		}
+	}
+
+	impl LogSink for SyslogSink {
+		fn write_log(&self, level: Level, prefix: &str, message: &str) {
+			let severity = match syslog_severity(level) {
+				Some(s) => s,
+				None => return,
+			};
+
+			let full = format!("{}{}", prefix, message);
+			let mut message = full.clone();
+			loop {
+				let datagram = match self.protocol {
+					Protocol::Journal => format_journal(severity, self.ident, &message),
+					Protocol::Syslog => {
+						format_syslog(severity, self.ident, std::process::id(), &message)
+					}
+				};
+
+				// a message too big for the socket is cut down rather than lost;
+				// ignore other errors, not much we can do if logging fails...
+				match self.socket.send(&datagram) {
+					Err(e)
+						if e.raw_os_error() == Some(libc::EMSGSIZE)
+							&& message.len() > MIN_TRUNCATED_LEN =>
+					{
+						message =
+							truncate_message(&full, (message.len() / 2).max(MIN_TRUNCATED_LEN));
+					}
+					_ => break,
+				}
+			}
+		}
+
+		fn write_result(&self, _message: &str) {}
+	}
+}
+
+#[cfg(windows)]
+pub use windows::{register_event_source, EventLogSink};
+
+#[cfg(windows)]
+mod windows {
+	use std::{ffi::OsStr, os::windows::ffi::OsStrExt, ptr, sync::Arc};
+
+	use winapi::{
+		shared::ntdef::HANDLE,
+		um::winbase::{DeregisterEventSource, RegisterEventSourceW, ReportEventW},
+	};
+	use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
+
+	use super::event_log_type;
+	use crate::log::{Level, LogSink};
+
+	/// Message file whose messages are all just their first string, which .NET
+	/// also uses for sources that don't have their own.
+	const EVENT_MESSAGE_FILE: &str =
+		r"%SystemRoot%\Microsoft.NET\Framework\v4.0.30319\EventLogMessages.dll";
+
+	fn wide(s: &str) -> Vec<u16> {
+		OsStr::new(s).encode_wide().chain(Some(0)).collect()
+	}
+
+	/// Registers the source with the Application log, so that its entries
+	/// show their messages. This needs administrator rights.
+	pub fn register_event_source(source: &str) -> std::io::Result<()> {
+		let (key, _) = RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey(format!(
+			r"SYSTEM\CurrentControlSet\Services\EventLog\Application\{}",
+			source
+		))?;
+		key.set_value("EventMessageFile", &EVENT_MESSAGE_FILE)?;
+		key.set_value("TypesSupported", &7u32)?;
+		Ok(())
+	}
+
+	struct Source(HANDLE);
+
+	// the handle may be used from any thread
+	unsafe impl Send for Source {}
+	unsafe impl Sync for Source {}
+
+	impl Drop for Source {
+		fn drop(&mut self) {
+			unsafe { DeregisterEventSource(self.0) };
+		}
+	}
+
+	/// Writes logs to the Windows Application event log.
+	#[derive(Clone)]
+	pub struct EventLogSink {
+		source: Arc<Source>,
+	}
+
+	impl EventLogSink {
+		pub fn open(source: &str) -> std::io::Result<Self> {
+			let name = wide(source);
+			let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+			if handle.is_null() {
+				return Err(std::io::Error::last_os_error());
+			}
+
+			Ok(Self {
+				source: Arc::new(Source(handle)),
+			})
+		}
+	}
+
+	impl LogSink for EventLogSink {
+		fn write_log(&self, level: Level, prefix: &str, message: &str) {
+			let event_type = match event_log_type(level) {
+				Some(t) => t,
+				None => return,
+			};
+
+			let message = wide(&format!("{}{}", prefix, message));
+			let mut strings = [message.as_ptr()];
+			// ignore any errors, not much we can do if logging fails...
+			unsafe {
+				ReportEventW(
+					self.source.0,
+					event_type,
+					0,
+					0,
+					ptr::null_mut(),
+					1,
+					0,
+					strings.as_mut_ptr(),
+					ptr::null_mut(),
+				)
+			};
+		}
+
+		fn write_result(&self, _message: &str) {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_severity_mapping() {
+		let cases = [
+			(Level::Trace, Some(7), Some(EVENTLOG_INFORMATION_TYPE)),
+			(Level::Debug, Some(7), Some(EVENTLOG_INFORMATION_TYPE)),
+			(Level::Info, Some(6), Some(EVENTLOG_INFORMATION_TYPE)),
+			(Level::Warn, Some(4), Some(EVENTLOG_WARNING_TYPE)),
+			(Level::Error, Some(3), Some(EVENTLOG_ERROR_TYPE)),
+			(Level::Critical, Some(2), Some(EVENTLOG_ERROR_TYPE)),
+			(Level::Off, None, None),
+		];
+		for (level, severity, event_type) in cases {
+			assert_eq!(syslog_severity(level), severity, "{}", level);
+			assert_eq!(event_log_type(level), event_type, "{}", level);
+		}
+	}
+
+	#[test]
+	fn test_format_syslog() {
+		assert_eq!(
+			format_syslog(3, "code", 42, "[tunnel.0] failed"),
+			b"<11>code[42]: [tunnel.0] failed".to_vec()
+		);
+	}
+
+	#[test]
+	fn test_truncate_message() {
+		assert_eq!(truncate_message("short", 100), "short");
+
+		let cut = truncate_message(&"é".repeat(100), 51);
+		assert!(cut.len() <= 51, "{}", cut);
+		assert!(cut.starts_with("é"));
+		assert!(cut.ends_with("... [truncated]"));
+	}
+
+	#[test]
+	fn test_format_journal() {
+		let out = format_journal(4, "code", "a\nb");
+		let mut expected = b"PRIORITY=4\nSYSLOG_IDENTIFIER=code\nMESSAGE\n".to_vec();
+		expected.extend_from_slice(&3u64.to_le_bytes());
+		expected.extend_from_slice(b"a\nb\n");
+		assert_eq!(out, expected);
+	}
+}
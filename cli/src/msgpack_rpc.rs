@@ -3,7 +3,6 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use bytes::Buf;
 use serde::de::DeserializeOwned;
 use tokio::{
 	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
@@ -14,12 +13,13 @@ use tokio_util::codec::Decoder;
 
 use crate::{
 	rpc::{self, MaybeSync, Serialization},
+	rpc_trace::{ConnectionTrace, Format},
 	util::{
-		errors::{AnyError, InvalidRpcDataError},
+		errors::{AnyError, CodeError, InvalidRpcDataError},
 		sync::{Barrier, Receivable},
 	},
 };
-use std::io::{self, Cursor, ErrorKind};
+use std::io::{self, ErrorKind};
 
 #[derive(Copy, Clone)]
 pub struct MsgPackSerializer {}
@@ -36,6 +36,56 @@ impl Serialization for MsgPackSerializer {
 
 pub type MsgPackCaller = rpc::RpcCaller<MsgPackSerializer>;
 
+/// Bytes of frames that are collected before they're written.
+const MAX_COALESCED_BYTES: usize = 64 * 1024;
+
+/// Initial size of the buffer that frames are read into.
+pub(crate) const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Collects outgoing frames so that a burst of them is written and flushed
+/// once, instead of once per frame. Frames are self-delimiting and are only
+/// concatenated, so what's sent is the same as writing them one at a time.
+pub(crate) struct FrameBuffer {
+	buf: Vec<u8>,
+	trace: ConnectionTrace,
+}
+
+impl FrameBuffer {
+	pub(crate) fn new(trace: ConnectionTrace) -> Self {
+		Self {
+			buf: Vec::with_capacity(MAX_COALESCED_BYTES),
+			trace,
+		}
+	}
+
+	pub(crate) fn push(&mut self, frame: &[u8]) {
+		self.trace.outbound(frame);
+		self.buf.extend_from_slice(frame);
+	}
+
+	pub(crate) fn is_full(&self) -> bool {
+		self.buf.len() >= MAX_COALESCED_BYTES
+	}
+
+	pub(crate) async fn write_to(
+		&mut self,
+		write: &mut (impl AsyncWrite + Unpin),
+	) -> io::Result<()> {
+		if self.buf.is_empty() {
+			return Ok(());
+		}
+
+		write.write_all(&self.buf).await?;
+		write.flush().await?;
+		self.buf.clear();
+		// don't hold on to the memory of one large frame
+		if self.buf.capacity() > MAX_COALESCED_BYTES * 4 {
+			self.buf = Vec::with_capacity(MAX_COALESCED_BYTES);
+		}
+		Ok(())
+	}
+}
+
 /// Creates a new RPC Builder that serializes to msgpack.
 pub fn new_msgpack_rpc() -> rpc::RpcBuilder<MsgPackSerializer> {
 	rpc::RpcBuilder::new(MsgPackSerializer {})
@@ -57,8 +107,10 @@ pub async fn start_msgpack_rpc<
 	mut shutdown_rx: Barrier<X>,
 ) -> io::Result<(Option<X>, Read, Write)> {
 	let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(8);
-	let mut decoder = MsgPackCodec::new();
-	let mut decoder_buf = bytes::BytesMut::new();
+	let mut decoder = MsgPackCodec::with_max_frame_size(dispatcher.max_frame_size());
+	let mut decoder_buf = bytes::BytesMut::with_capacity(READ_BUFFER_SIZE);
+	let trace = ConnectionTrace::new(Format::MsgPack);
+	let mut out = FrameBuffer::new(trace);
 
 	let shutdown_fut = shutdown_rx.wait();
 	pin!(shutdown_fut);
@@ -69,10 +121,10 @@ pub async fn start_msgpack_rpc<
 				r?;
 
 				while let Some(frame) = decoder.decode(&mut decoder_buf)? {
+					trace.inbound(&frame.vec);
 					match dispatcher.dispatch_with_partial(&frame.vec, frame.obj) {
-						MaybeSync::Sync(Some(v)) => {
-							let _ = write_tx.send(v).await;
-						},
+						// answered with the others read in this batch
+						MaybeSync::Sync(Some(v)) => out.push(&v),
 						MaybeSync::Sync(None) => continue,
 						MaybeSync::Future(fut) => {
 							let write_tx = write_tx.clone();
@@ -82,6 +134,9 @@ pub async fn start_msgpack_rpc<
 								}
 							});
 						}
+						MaybeSync::Partial(results) => {
+							dispatcher.register_partials(write_tx.clone(), results);
+						}
 						MaybeSync::Stream((stream, fut)) => {
 							if let Some(stream) = stream {
 								dispatcher.register_stream(write_tx.clone(), stream).await;
@@ -97,39 +152,138 @@ pub async fn start_msgpack_rpc<
 				};
 			},
 			Some(m) = write_rx.recv() => {
-				write.write_all(&m).await?;
+				out.push(&m);
+				while !out.is_full() {
+					match write_rx.try_recv() {
+						Ok(m) => out.push(&m),
+						Err(_) => break,
+					}
+				}
 			},
-			Some(m) = msg_rx.recv_msg() => {
-				write.write_all(&m).await?;
+			Some(m) = msg_rx.recv_msg() => out.push(&m),
+			r = &mut shutdown_fut => {
+				out.write_to(&mut write).await?;
+				return Ok((r.ok(), read, write));
 			},
-			r = &mut shutdown_fut => return Ok((r.ok(), read, write)),
 		}
 
-		write.flush().await?;
+		out.write_to(&mut write).await?;
 	}
 }
 
 /// Reader that reads msgpack object messages in a cancellation-safe way using Tokio's codecs.
 ///
 /// rmp_serde does not support async reads, and does not plan to. But we know every
-/// type in protocol is some kind of object, so by asking to deserialize the
-/// requested object from a reader (repeatedly, if incomplete) we can
-/// accomplish streaming.
+/// type in protocol is some kind of object, so by scanning the object's
+/// markers we can tell when it's been fully read, and only then deserialize it.
 pub struct MsgPackCodec<T> {
+	max_frame_size: usize,
 	_marker: std::marker::PhantomData<T>,
 }
 
 impl<T> MsgPackCodec<T> {
+	#[cfg(test)]
 	pub fn new() -> Self {
+		Self::with_max_frame_size(crate::constants::DEFAULT_MAX_RPC_FRAME_SIZE)
+	}
+
+	/// Creates a codec that fails with `CodeError::RpcFrameTooLarge` on
+	/// objects larger than the given number of bytes.
+	pub fn with_max_frame_size(max_frame_size: usize) -> Self {
 		Self {
+			max_frame_size,
 			_marker: std::marker::PhantomData,
 		}
 	}
 }
 
+fn read_be(buf: &[u8], width: usize) -> Option<u64> {
+	let bytes = buf.get(..width)?;
+	Some(bytes.iter().fold(0, |n, b| (n << 8) | *b as u64))
+}
+
+/// Gets the header length, payload length, and number of child objects of
+/// the msgpack object with the given marker. Returns None if the header is
+/// not yet fully in `rest`, which holds the bytes following the marker.
+fn object_size(marker: u8, rest: &[u8]) -> Option<(u64, u64, u64)> {
+	Some(match marker {
+		0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (1, 0, 0),
+		0x80..=0x8f => (1, 0, 2 * (marker & 0x0f) as u64),
+		0x90..=0x9f => (1, 0, (marker & 0x0f) as u64),
+		0xa0..=0xbf => (1, (marker & 0x1f) as u64, 0),
+		0xc4 | 0xd9 => (2, read_be(rest, 1)?, 0),
+		0xc5 | 0xda => (3, read_be(rest, 2)?, 0),
+		0xc6 | 0xdb => (5, read_be(rest, 4)?, 0),
+		// ext types have their length followed by a type byte
+		0xc7 => (3, read_be(rest, 1)?, 0),
+		0xc8 => (4, read_be(rest, 2)?, 0),
+		0xc9 => (6, read_be(rest, 4)?, 0),
+		0xcc | 0xd0 => (1, 1, 0),
+		0xcd | 0xd1 => (1, 2, 0),
+		0xca | 0xce | 0xd2 => (1, 4, 0),
+		0xcb | 0xcf | 0xd3 => (1, 8, 0),
+		0xd4..=0xd8 => (2, 1 << (marker - 0xd4), 0),
+		0xdc => (3, 0, read_be(rest, 2)?),
+		0xdd => (5, 0, read_be(rest, 4)?),
+		0xde => (3, 0, 2 * read_be(rest, 2)?),
+		0xdf => (5, 0, 2 * read_be(rest, 4)?),
+		0xc1 => unreachable!("checked by the caller"),
+	})
+}
+
+/// Gets the length of the msgpack object at the start of the buffer, or None
+/// if it's incomplete. Sizes are checked against the limit as soon as they're
+/// read, so a corrupt length is rejected instead of waiting for (and
+/// buffering) data that will never make a valid object.
+fn frame_len(buf: &[u8], limit: usize) -> io::Result<Option<usize>> {
+	let mut pos: u64 = 0;
+	let mut pending: u64 = 1;
+	while pending > 0 {
+		pending -= 1;
+
+		let marker = match buf.get(pos as usize) {
+			Some(0xc1) => {
+				return Err(io::Error::new(
+					ErrorKind::InvalidData,
+					"invalid msgpack marker 0xc1",
+				))
+			}
+			Some(m) => *m,
+			None => return Ok(None),
+		};
+
+		let (header, payload, children) = match object_size(marker, &buf[pos as usize + 1..]) {
+			Some(s) => s,
+			None => return Ok(None),
+		};
+
+		pos += header + payload;
+		pending += children;
+
+		// every pending object takes at least one byte
+		let min_size = pos + pending;
+		if min_size > limit as u64 {
+			return Err(io::Error::new(
+				ErrorKind::InvalidData,
+				CodeError::RpcFrameTooLarge {
+					size: min_size,
+					limit,
+				},
+			));
+		}
+	}
+
+	if pos > buf.len() as u64 {
+		Ok(None)
+	} else {
+		Ok(Some(pos as usize))
+	}
+}
+
 pub struct MsgPackDecoded<T> {
 	pub obj: T,
-	pub vec: Vec<u8>,
+	/// Bytes of the frame, which share the read buffer rather than copying it.
+	pub vec: bytes::Bytes,
 }
 
 impl<T: DeserializeOwned> tokio_util::codec::Decoder for MsgPackCodec<T> {
@@ -137,36 +291,39 @@ impl<T: DeserializeOwned> tokio_util::codec::Decoder for MsgPackCodec<T> {
 	type Error = io::Error;
 
 	fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-		let bytes_ref = src.as_ref();
-		let mut cursor = Cursor::new(bytes_ref);
-
-		match rmp_serde::decode::from_read::<_, T>(&mut cursor) {
-			Err(
-				rmp_serde::decode::Error::InvalidDataRead(e)
-				| rmp_serde::decode::Error::InvalidMarkerRead(e),
-			) if e.kind() == ErrorKind::UnexpectedEof => {
+		let len = match frame_len(src.as_ref(), self.max_frame_size)? {
+			Some(len) => len,
+			None => {
 				src.reserve(1024);
-				Ok(None)
+				return Ok(None);
 			}
+		};
+
+		let vec = src.split_to(len).freeze();
+		match rmp_serde::from_slice::<T>(&vec) {
 			Err(e) => Err(std::io::Error::new(
 				std::io::ErrorKind::InvalidData,
 				e.to_string(),
 			)),
-			Ok(obj) => {
-				let len = cursor.position() as usize;
-				let vec = src[..len].to_vec();
-				src.advance(len);
-				Ok(Some(MsgPackDecoded { obj, vec }))
-			}
+			Ok(obj) => Ok(Some(MsgPackDecoded { obj, vec })),
 		}
 	}
 }
 
 #[cfg(test)]
 mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System},
+		cell::Cell,
+		pin::Pin,
+		sync::{Arc, Mutex},
+		task::{Context, Poll},
+	};
+
 	use serde::{Deserialize, Serialize};
 
 	use super::*;
+	use crate::{log, rpc::FullRequest, util::sync::new_barrier};
 
 	#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 	pub struct Msg {
@@ -192,4 +349,297 @@ mod tests {
 			Msg { x: 2 }
 		);
 	}
+
+	fn assert_too_large(e: &io::Error, limit: usize) {
+		match e.get_ref().and_then(|e| e.downcast_ref::<CodeError>()) {
+			Some(CodeError::RpcFrameTooLarge { size, limit: l }) => {
+				assert!(*size > limit as u64);
+				assert_eq!(*l, limit);
+			}
+			_ => panic!("unexpected error {:?}", e),
+		}
+	}
+
+	#[test]
+	fn test_frame_boundaries() {
+		let msgs: Vec<Vec<u8>> = (0..3)
+			.map(|x| rmp_serde::to_vec_named(&Msg { x: x * 1000 }).unwrap())
+			.collect();
+		let all = msgs.concat();
+
+		// frames split at every point, including inside headers
+		for chunk_size in 1..all.len() {
+			let mut c = MsgPackCodec::<Msg>::new();
+			let mut buf = bytes::BytesMut::new();
+			let mut decoded = vec![];
+			for chunk in all.chunks(chunk_size) {
+				buf.extend_from_slice(chunk);
+				while let Some(frame) = c.decode(&mut buf).unwrap() {
+					decoded.push(frame);
+				}
+			}
+
+			assert_eq!(decoded.len(), 3, "chunk size {}", chunk_size);
+			for (i, frame) in decoded.iter().enumerate() {
+				assert_eq!(frame.obj, Msg { x: i as i32 * 1000 });
+				assert_eq!(frame.vec, msgs[i]);
+			}
+			assert!(buf.is_empty());
+		}
+	}
+
+	#[test]
+	fn test_incremental_frames() {
+		let mut c = MsgPackCodec::<Vec<String>>::new();
+		let msg = rmp_serde::to_vec_named(&vec!["a".repeat(300), "b".repeat(70000)]).unwrap();
+
+		let mut buf = bytes::BytesMut::new();
+		for chunk in msg.chunks(999) {
+			assert!(c.decode(&mut buf).unwrap().is_none());
+			buf.extend_from_slice(chunk);
+		}
+
+		let decoded = c.decode(&mut buf).unwrap().expect("expected msg");
+		assert_eq!(decoded.obj[1].len(), 70000);
+		assert_eq!(decoded.vec, msg);
+		assert!(buf.is_empty());
+	}
+
+	#[test]
+	fn test_rejects_oversized_frames() {
+		let limit = 1024;
+
+		// str32, bin32, and array32 headers with huge lengths, before any
+		// of the data arrives
+		for header in [
+			&[0x81, 0xa1, b'x', 0xdb, 0xff, 0xff, 0xff, 0xf0][..],
+			&[0x81, 0xa1, b'x', 0xc6, 0x7f, 0x00, 0x00, 0x00],
+			&[0xdd, 0xff, 0xff, 0xff, 0xff],
+			&[0xdf, 0x00, 0x01, 0x00, 0x00],
+		] {
+			let mut c = MsgPackCodec::<Msg>::with_max_frame_size(limit);
+			let mut buf = bytes::BytesMut::from(header);
+			assert_too_large(&c.decode(&mut buf).err().unwrap(), limit);
+		}
+
+		let mut c = MsgPackCodec::<Msg>::with_max_frame_size(limit);
+		let small = rmp_serde::to_vec_named(&Msg { x: 1 }).unwrap();
+		let mut buf = bytes::BytesMut::from(&small[..]);
+		assert_eq!(c.decode(&mut buf).unwrap().unwrap().obj, Msg { x: 1 });
+	}
+
+	#[test]
+	fn test_random_input_fails_gracefully() {
+		use rand::{Rng, SeedableRng};
+
+		let limit = 64 * 1024;
+		let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+		for _ in 0..10_000 {
+			let len = rng.gen_range(1..64);
+			let mut input: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+			// make the first marker a random length prefix
+			input[0] = [0xc6, 0xc9, 0xdb, 0xdd, 0xdf][rng.gen_range(0..5)];
+
+			let mut c = MsgPackCodec::<Msg>::with_max_frame_size(limit);
+			let mut buf = bytes::BytesMut::from(&input[..]);
+			while let Ok(Some(_)) = c.decode(&mut buf) {}
+			assert!(buf.capacity() < limit);
+		}
+	}
+
+	/// Transport that records what's written to it, and in how many writes.
+	#[derive(Clone, Default)]
+	struct CountingWriter(Arc<Mutex<(usize, Vec<u8>)>>);
+
+	impl AsyncWrite for CountingWriter {
+		fn poll_write(
+			self: Pin<&mut Self>,
+			_cx: &mut Context<'_>,
+			buf: &[u8],
+		) -> Poll<io::Result<usize>> {
+			let mut s = self.0.lock().unwrap();
+			s.0 += 1;
+			s.1.extend_from_slice(buf);
+			Poll::Ready(Ok(buf.len()))
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	fn echo_dispatcher() -> rpc::RpcDispatcher<MsgPackSerializer, ()> {
+		let mut rpc = new_msgpack_rpc().methods(());
+		rpc.register_sync("echo", |m: Msg, _| Ok(m));
+		rpc.build(log::Logger::test())
+	}
+
+	#[tokio::test]
+	async fn test_burst_of_responses_is_written_once() {
+		const REQUESTS: u32 = 50;
+		let requests: Vec<Vec<u8>> = (1..=REQUESTS)
+			.map(|id| {
+				rmp_serde::to_vec_named(&FullRequest {
+					id: Some(id),
+					method: "echo",
+					params: Msg { x: id as i32 },
+				})
+				.unwrap()
+			})
+			.collect();
+
+		// responses as they'd be written one at a time
+		let dispatcher = echo_dispatcher();
+		let expected: Vec<u8> = requests
+			.iter()
+			.flat_map(|r| match dispatcher.dispatch(r) {
+				MaybeSync::Sync(Some(v)) => v,
+				_ => panic!("expected a sync response"),
+			})
+			.collect();
+
+		let (server_io, mut client_io) = tokio::io::duplex(64 * 1024);
+		let writer = CountingWriter::default();
+		let (closed, close) = new_barrier::<()>();
+		let server = tokio::spawn(start_msgpack_rpc(
+			dispatcher,
+			server_io,
+			writer.clone(),
+			(),
+			closed,
+		));
+
+		client_io.write_all(&requests.concat()).await.unwrap();
+		while writer.0.lock().unwrap().1.len() < expected.len() {
+			tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+		}
+		close.open(());
+		server.await.unwrap().unwrap();
+
+		let (writes, written) = writer.0.lock().unwrap().clone();
+		assert_eq!(written, expected);
+		assert!(
+			writes <= 2,
+			"expected {} responses in at most 2 writes, got {}",
+			REQUESTS,
+			writes
+		);
+	}
+
+	/// Counts the allocations made on each thread, so that a test can measure
+	/// those of what it runs without counting other tests'.
+	struct CountingAllocator;
+
+	thread_local! {
+		static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+	}
+
+	fn count_allocation() {
+		let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+	}
+
+	unsafe impl GlobalAlloc for CountingAllocator {
+		unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+			count_allocation();
+			System.alloc(layout)
+		}
+
+		unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+			System.dealloc(ptr, layout)
+		}
+
+		unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+			count_allocation();
+			System.realloc(ptr, layout, new_size)
+		}
+	}
+
+	#[global_allocator]
+	static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+	fn allocations_in(f: impl FnOnce()) -> usize {
+		let before = ALLOCATIONS.with(|a| a.get());
+		f();
+		ALLOCATIONS.with(|a| a.get()) - before
+	}
+
+	#[test]
+	fn test_buffers_are_reused() {
+		const FRAMES: usize = 1000;
+		let frames: Vec<Vec<u8>> = (0..FRAMES)
+			.map(|x| rmp_serde::to_vec_named(&Msg { x: x as i32 }).unwrap())
+			.collect();
+		let all = frames.concat();
+
+		// reading frames as they arrive in the read buffer, which would
+		// otherwise be grown or copied for each
+		let mut codec = MsgPackCodec::<Msg>::new();
+		let mut buf = bytes::BytesMut::with_capacity(READ_BUFFER_SIZE);
+		let mut decoded = 0;
+		let reading = allocations_in(|| {
+			for chunk in all.chunks(READ_BUFFER_SIZE / 2) {
+				buf.extend_from_slice(chunk);
+				while let Some(frame) = codec.decode(&mut buf).unwrap() {
+					assert_eq!(frame.obj.x, decoded as i32);
+					decoded += 1;
+				}
+			}
+		});
+		assert_eq!(decoded, FRAMES);
+		assert!(
+			reading < FRAMES / 10,
+			"reading {} frames made {} allocations",
+			FRAMES,
+			reading
+		);
+
+		// writing bursts of frames, after the first has sized the buffer
+		let mut out = FrameBuffer::new(ConnectionTrace::new(Format::MsgPack));
+		let mut sink = tokio::io::sink();
+		let mut write_bursts = || {
+			futures::executor::block_on(async {
+				for burst in frames.chunks(100) {
+					for frame in burst {
+						out.push(frame);
+					}
+					out.write_to(&mut sink).await.unwrap();
+				}
+			})
+		};
+		write_bursts();
+		let writing = allocations_in(write_bursts);
+		assert_eq!(
+			writing, 0,
+			"writing {} frames made {} allocations",
+			FRAMES, writing
+		);
+	}
+
+	#[tokio::test]
+	async fn test_oversized_frame_closes_connection() {
+		let mut rpc = new_msgpack_rpc().methods(());
+		rpc.set_max_frame_size(1024);
+
+		let (server_io, mut client_io) = tokio::io::duplex(4096);
+		let (read, write) = tokio::io::split(server_io);
+		let (closed, _close) = new_barrier::<()>();
+		let server = tokio::spawn(start_msgpack_rpc(
+			rpc.build(log::Logger::test()),
+			read,
+			write,
+			(),
+			closed,
+		));
+
+		client_io
+			.write_all(&[0xdb, 0xff, 0xff, 0xff, 0xff])
+			.await
+			.unwrap();
+		let e = server.await.unwrap().expect_err("expected an error");
+		assert_too_large(&e, 1024);
+	}
 }
@@ -0,0 +1,465 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Policies that an administrator sets for all users of a machine. They take
+//! precedence over flags and the config file.
+
+use std::{
+	fmt,
+	sync::{Arc, RwLock},
+};
+
+use clap::ValueEnum;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::AuthProvider, util::errors::CodeError};
+
+/// Location of the policy file on macOS and Linux.
+#[cfg(not(windows))]
+pub const SYSTEM_POLICY_PATH: &str = "/etc/vscode-cli/policy.json";
+
+/// Registry key under HKEY_LOCAL_MACHINE whose values are the policy on Windows.
+#[cfg(windows)]
+pub const SYSTEM_POLICY_KEY: &str = r"SOFTWARE\Policies\Microsoft\VSCodeCLI";
+
+/// A feature that a policy can turn off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyFeature {
+	TunnelHosting,
+	PublicPorts,
+	RemoteExec,
+	ServeWebWithoutToken,
+}
+
+impl fmt::Display for PolicyFeature {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PolicyFeature::TunnelHosting => write!(f, "Hosting tunnels"),
+			PolicyFeature::PublicPorts => write!(f, "Forwarding ports publicly"),
+			PolicyFeature::RemoteExec => write!(f, "Remote exec"),
+			PolicyFeature::ServeWebWithoutToken => {
+				write!(f, "Running serve-web without a connection token")
+			}
+		}
+	}
+}
+
+/// The policy file's contents, such as
+/// `{ "disableTunnelHosting": true, "allowedAuthProviders": ["microsoft"] }`.
+/// On Windows the same names are values of the policy key, with DWORDs for
+/// flags and a multi-string for the provider list. Values that are there but
+/// can't be read are taken as the most restrictive setting.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PolicyFile {
+	#[serde(default)]
+	disable_tunnel_hosting: bool,
+	#[serde(default)]
+	disable_public_ports: bool,
+	#[serde(default)]
+	disable_remote_exec: bool,
+	#[serde(default)]
+	disable_serve_web_without_token: bool,
+	update_endpoint: Option<String>,
+	allowed_auth_providers: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Policy {
+	/// Where the policy was read from, or None if no policy is set.
+	pub source: Option<String>,
+	pub disable_tunnel_hosting: bool,
+	pub disable_public_ports: bool,
+	pub disable_remote_exec: bool,
+	pub disable_serve_web_without_token: bool,
+	/// Update service to use instead of the built-in one.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub update_endpoint: Option<String>,
+	/// Providers users may sign in with, or None for any.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub allowed_auth_providers: Option<Vec<AuthProvider>>,
+}
+
+impl Policy {
+	/// Parses a policy file read from the source.
+	pub fn parse(source: &str, contents: &str) -> Result<Self, CodeError> {
+		let file: PolicyFile = serde_json::from_str(contents)
+			.map_err(|e| CodeError::CouldNotReadPolicy(source.to_string(), e.to_string()))?;
+		Self::from_file(source, file)
+	}
+
+	fn from_file(source: &str, file: PolicyFile) -> Result<Self, CodeError> {
+		let allowed_auth_providers = match file.allowed_auth_providers {
+			Some(names) => Some(
+				names
+					.iter()
+					.map(|n| {
+						AuthProvider::from_str(n, true).map_err(|_| {
+							CodeError::CouldNotReadPolicy(
+								source.to_string(),
+								format!("unknown auth provider '{}'", n),
+							)
+						})
+					})
+					.collect::<Result<Vec<_>, _>>()?,
+			),
+			None => None,
+		};
+
+		Ok(Self {
+			source: Some(source.to_string()),
+			disable_tunnel_hosting: file.disable_tunnel_hosting,
+			disable_public_ports: file.disable_public_ports,
+			disable_remote_exec: file.disable_remote_exec,
+			disable_serve_web_without_token: file.disable_serve_web_without_token,
+			update_endpoint: file.update_endpoint,
+			allowed_auth_providers,
+		})
+	}
+
+	/// Gets whether the policy turns off the feature.
+	pub fn is_disabled(&self, feature: PolicyFeature) -> bool {
+		match feature {
+			PolicyFeature::TunnelHosting => self.disable_tunnel_hosting,
+			PolicyFeature::PublicPorts => self.disable_public_ports,
+			PolicyFeature::RemoteExec => self.disable_remote_exec,
+			PolicyFeature::ServeWebWithoutToken => self.disable_serve_web_without_token,
+		}
+	}
+
+	/// Fails with an error naming the policy if it turns off the feature.
+	pub fn ensure_allowed(&self, feature: PolicyFeature) -> Result<(), CodeError> {
+		if self.is_disabled(feature) {
+			return Err(CodeError::DisabledByPolicy(feature, self.source_name()));
+		}
+		Ok(())
+	}
+
+	/// Fails if users may not sign in with the provider.
+	pub fn ensure_auth_provider(&self, provider: AuthProvider) -> Result<(), CodeError> {
+		match &self.allowed_auth_providers {
+			Some(allowed) if !allowed.contains(&provider) => Err(
+				CodeError::AuthProviderDisabledByPolicy(provider, self.source_name()),
+			),
+			_ => Ok(()),
+		}
+	}
+
+	/// Filters the providers to those users may sign in with.
+	pub fn allowed_auth_providers(&self, providers: &[AuthProvider]) -> Vec<AuthProvider> {
+		providers
+			.iter()
+			.filter(|p| self.ensure_auth_provider(**p).is_ok())
+			.copied()
+			.collect()
+	}
+
+	/// Gets the update service to use, which the policy may pin.
+	pub fn update_endpoint<'a>(&'a self, built_in: Option<&'a str>) -> Option<&'a str> {
+		self.update_endpoint.as_deref().or(built_in)
+	}
+
+	/// Describes what the policy restricts, for display.
+	pub fn restrictions(&self) -> Vec<String> {
+		let mut r = vec![];
+		for feature in [
+			PolicyFeature::TunnelHosting,
+			PolicyFeature::PublicPorts,
+			PolicyFeature::RemoteExec,
+			PolicyFeature::ServeWebWithoutToken,
+		] {
+			if self.is_disabled(feature) {
+				r.push(format!("{} is disabled", feature));
+			}
+		}
+		if let Some(e) = &self.update_endpoint {
+			r.push(format!("Updates come from {}", e));
+		}
+		if let Some(p) = &self.allowed_auth_providers {
+			r.push(format!(
+				"Sign-in is limited to {}",
+				p.iter()
+					.map(|p| p.to_string())
+					.collect::<Vec<_>>()
+					.join(", ")
+			));
+		}
+		r
+	}
+
+	fn source_name(&self) -> String {
+		self.source.clone().unwrap_or_else(|| "unknown".to_string())
+	}
+}
+
+/// Reads the machine's policy. A machine without a policy gets the default,
+/// which allows everything.
+pub fn load_system_policy() -> Result<Policy, CodeError> {
+	#[cfg(not(windows))]
+	{
+		match std::fs::read_to_string(SYSTEM_POLICY_PATH) {
+			Ok(s) => Policy::parse(SYSTEM_POLICY_PATH, &s),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Policy::default()),
+			Err(e) => Err(CodeError::CouldNotReadPolicy(
+				SYSTEM_POLICY_PATH.to_string(),
+				e.to_string(),
+			)),
+		}
+	}
+
+	#[cfg(windows)]
+	{
+		use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
+
+		let source = format!(r"HKEY_LOCAL_MACHINE\{}", SYSTEM_POLICY_KEY);
+		let key = match RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey(SYSTEM_POLICY_KEY) {
+			Ok(k) => k,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Policy::default()),
+			Err(e) => return Err(CodeError::CouldNotReadPolicy(source, e.to_string())),
+		};
+
+		let flag = |name: &str| registry_flag(key.get_value::<u32, _>(name));
+		let file = PolicyFile {
+			disable_tunnel_hosting: flag("disableTunnelHosting"),
+			disable_public_ports: flag("disablePublicPorts"),
+			disable_remote_exec: flag("disableRemoteExec"),
+			disable_serve_web_without_token: flag("disableServeWebWithoutToken"),
+			update_endpoint: registry_update_endpoint(&source, key.get_value("updateEndpoint"))?,
+			allowed_auth_providers: registry_auth_providers(key.get_value("allowedAuthProviders")),
+		};
+		Policy::from_file(&source, file)
+	}
+}
+
+/// Reads a flag from the registry. A value that's there but can't be read,
+/// such as one of the wrong type, turns the feature off.
+#[cfg(any(windows, test))]
+fn registry_flag(value: std::io::Result<u32>) -> bool {
+	match value {
+		Ok(v) => v != 0,
+		Err(e) => e.kind() != std::io::ErrorKind::NotFound,
+	}
+}
+
+/// Reads the pinned update service from the registry, failing if the value
+/// is there but can't be read, since there's no service it could fall back to.
+#[cfg(any(windows, test))]
+fn registry_update_endpoint(
+	source: &str,
+	value: std::io::Result<String>,
+) -> Result<Option<String>, CodeError> {
+	match value {
+		Ok(v) => Ok(Some(v)),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(CodeError::CouldNotReadPolicy(
+			source.to_string(),
+			format!("updateEndpoint: {}", e),
+		)),
+	}
+}
+
+/// Reads the allowed providers from the registry. A value that's there but
+/// can't be read allows none of them.
+#[cfg(any(windows, test))]
+fn registry_auth_providers(value: std::io::Result<Vec<String>>) -> Option<Vec<String>> {
+	match value {
+		Ok(v) => Some(v),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+		Err(_) => Some(vec![]),
+	}
+}
+
+lazy_static! {
+	static ref POLICY: RwLock<Arc<Policy>> = RwLock::new(Arc::new(Policy::default()));
+}
+
+/// Sets the policy that applies for the rest of the process.
+pub fn configure(policy: Policy) {
+	*POLICY.write().unwrap() = Arc::new(policy);
+}
+
+/// Gets the policy that applies to the process.
+pub fn current() -> Arc<Policy> {
+	POLICY.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SOURCE: &str = "/etc/vscode-cli/policy.json";
+
+	#[test]
+	fn test_parses_policy() {
+		let policy = Policy::parse(
+			SOURCE,
+			r#"{
+				"disableTunnelHosting": true,
+				"disableServeWebWithoutToken": true,
+				"updateEndpoint": "https://updates.contoso.com",
+				"allowedAuthProviders": ["Microsoft"]
+			}"#,
+		)
+		.unwrap();
+
+		assert_eq!(
+			policy,
+			Policy {
+				source: Some(SOURCE.to_string()),
+				disable_tunnel_hosting: true,
+				disable_public_ports: false,
+				disable_remote_exec: false,
+				disable_serve_web_without_token: true,
+				update_endpoint: Some("https://updates.contoso.com".to_string()),
+				allowed_auth_providers: Some(vec![AuthProvider::Microsoft]),
+			}
+		);
+	}
+
+	#[test]
+	fn test_empty_policy_allows_everything() {
+		let policy = Policy::parse(SOURCE, "{}").unwrap();
+		assert!(policy.restrictions().is_empty());
+		for feature in [
+			PolicyFeature::TunnelHosting,
+			PolicyFeature::PublicPorts,
+			PolicyFeature::RemoteExec,
+			PolicyFeature::ServeWebWithoutToken,
+		] {
+			policy.ensure_allowed(feature).unwrap();
+		}
+		policy.ensure_auth_provider(AuthProvider::Github).unwrap();
+	}
+
+	#[test]
+	fn test_rejects_invalid_policy() {
+		let err = Policy::parse(SOURCE, "{ not json").unwrap_err();
+		assert!(matches!(err, CodeError::CouldNotReadPolicy(ref s, _) if s == SOURCE));
+
+		let err = Policy::parse(SOURCE, r#"{ "allowedAuthProviders": ["myspace"] }"#)
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("unknown auth provider 'myspace'"), "{}", err);
+	}
+
+	#[test]
+	fn test_disabled_features_name_the_policy() {
+		let policy = Policy::parse(
+			SOURCE,
+			r#"{ "disablePublicPorts": true, "disableRemoteExec": true }"#,
+		)
+		.unwrap();
+
+		policy.ensure_allowed(PolicyFeature::TunnelHosting).unwrap();
+		policy
+			.ensure_allowed(PolicyFeature::ServeWebWithoutToken)
+			.unwrap();
+
+		let err = policy
+			.ensure_allowed(PolicyFeature::PublicPorts)
+			.unwrap_err()
+			.to_string();
+		assert_eq!(
+			err,
+			format!(
+				"Forwarding ports publicly is disabled by your organization (policy: {})",
+				SOURCE
+			)
+		);
+		assert!(policy.ensure_allowed(PolicyFeature::RemoteExec).is_err());
+		assert_eq!(
+			policy.restrictions(),
+			vec![
+				"Forwarding ports publicly is disabled",
+				"Remote exec is disabled",
+			]
+		);
+	}
+
+	#[test]
+	fn test_auth_provider_allowlist() {
+		let policy = Policy::parse(SOURCE, r#"{ "allowedAuthProviders": ["github"] }"#).unwrap();
+
+		policy.ensure_auth_provider(AuthProvider::Github).unwrap();
+		let err = policy
+			.ensure_auth_provider(AuthProvider::Microsoft)
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			CodeError::AuthProviderDisabledByPolicy(AuthProvider::Microsoft, _)
+		));
+		assert_eq!(
+			policy.allowed_auth_providers(&[AuthProvider::Microsoft, AuthProvider::Github]),
+			vec![AuthProvider::Github]
+		);
+	}
+
+	#[test]
+	fn test_unreadable_registry_values_are_restrictive() {
+		use std::io::{Error, ErrorKind};
+
+		let missing = || Error::from(ErrorKind::NotFound);
+		let wrong_type = || Error::from(ErrorKind::InvalidData);
+
+		assert!(registry_flag(Ok(1)));
+		assert!(!registry_flag(Ok(0)));
+		assert!(!registry_flag(Err(missing())));
+		assert!(registry_flag(Err(wrong_type())));
+
+		assert_eq!(
+			registry_update_endpoint(SOURCE, Ok("https://updates.contoso.com".to_string()))
+				.unwrap(),
+			Some("https://updates.contoso.com".to_string())
+		);
+		assert_eq!(
+			registry_update_endpoint(SOURCE, Err(missing())).unwrap(),
+			None
+		);
+		assert!(matches!(
+			registry_update_endpoint(SOURCE, Err(wrong_type())),
+			Err(CodeError::CouldNotReadPolicy(_, _))
+		));
+
+		assert_eq!(registry_auth_providers(Err(missing())), None);
+		let policy = Policy::from_file(
+			SOURCE,
+			PolicyFile {
+				allowed_auth_providers: registry_auth_providers(Err(wrong_type())),
+				..Default::default()
+			},
+		)
+		.unwrap();
+		assert!(policy.ensure_auth_provider(AuthProvider::Github).is_err());
+		assert!(policy
+			.ensure_auth_provider(AuthProvider::Microsoft)
+			.is_err());
+	}
+
+	#[test]
+	fn test_update_endpoint_overrides_built_in() {
+		let pinned = Policy::parse(
+			SOURCE,
+			r#"{ "updateEndpoint": "https://updates.contoso.com" }"#,
+		)
+		.unwrap();
+		assert_eq!(
+			pinned.update_endpoint(Some("https://update.code.visualstudio.com")),
+			Some("https://updates.contoso.com")
+		);
+		assert_eq!(
+			pinned.update_endpoint(None),
+			Some("https://updates.contoso.com")
+		);
+
+		let unpinned = Policy::default();
+		assert_eq!(
+			unpinned.update_endpoint(Some("https://update.code.visualstudio.com")),
+			Some("https://update.code.visualstudio.com")
+		);
+		assert_eq!(unpinned.update_endpoint(None), None);
+	}
+}
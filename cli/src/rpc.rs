@@ -6,35 +6,48 @@
 use std::{
 	collections::HashMap,
 	future,
+	marker::PhantomData,
+	pin::Pin,
 	sync::{
-		atomic::{AtomicU32, Ordering},
+		atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
 		Arc, Mutex,
 	},
+	task::{ready, Context, Poll},
+	time::Instant,
 };
 
-use crate::log;
-use futures::{future::BoxFuture, Future, FutureExt};
+use crate::{constants::DEFAULT_MAX_RPC_FRAME_SIZE, log};
+use futures::{future::BoxFuture, Future, FutureExt, Stream};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::{
 	io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf},
-	sync::{mpsc, oneshot},
+	sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
 };
+use tokio_util::sync::CancellationToken;
 
-use crate::util::errors::AnyError;
+use crate::util::errors::{AnyError, CodeError};
 
-pub type SyncMethod = Arc<dyn Send + Sync + Fn(Option<u32>, &[u8]) -> Option<Vec<u8>>>;
-pub type AsyncMethod =
-	Arc<dyn Send + Sync + Fn(Option<u32>, &[u8]) -> BoxFuture<'static, Option<Vec<u8>>>>;
+pub type SyncMethod =
+	Arc<dyn Send + Sync + Fn(Option<u32>, &[u8], &ConnectionRequests) -> Option<Vec<u8>>>;
+pub type AsyncMethod = Arc<
+	dyn Send
+		+ Sync
+		+ Fn(Option<u32>, &[u8], &ConnectionRequests) -> BoxFuture<'static, Option<Vec<u8>>>,
+>;
 pub type Duplex = Arc<
 	dyn Send
 		+ Sync
 		+ Fn(Option<u32>, &[u8]) -> (Option<StreamDto>, BoxFuture<'static, Option<Vec<u8>>>),
 >;
+pub type StreamingMethod =
+	Arc<dyn Send + Sync + Fn(Option<u32>, &[u8], &ConnectionRequests) -> mpsc::Receiver<Vec<u8>>>;
+type NotificationHandler = Arc<dyn Send + Sync + Fn(&[u8])>;
 
 pub enum Method {
 	Sync(SyncMethod),
 	Async(AsyncMethod),
 	Duplex(Duplex),
+	Streaming(StreamingMethod),
 }
 
 /// Serialization is given to the RpcBuilder and defines how data gets serialized
@@ -50,8 +63,86 @@ pub struct RpcBuilder<S> {
 	serializer: Arc<S>,
 	methods: HashMap<&'static str, Method>,
 	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	partials: PartialsMap,
+	notifications: NotificationsMap,
+}
+
+/// Error code sent in response to a request for a method that doesn't exist,
+/// as in JSON-RPC 2.0.
+pub const ERROR_CODE_METHOD_NOT_FOUND: i32 = -32601;
+
+/// Error code sent in response to a request that was cancelled by the caller.
+pub const ERROR_CODE_CANCELLED: i32 = -32800;
+
+/// Error code sent in response to a request that was rejected because too
+/// many requests were already running.
+pub const ERROR_CODE_SERVER_BUSY: i32 = -32801;
+
+/// Error code sent in response to a `METHOD_HELLO` from a remote whose
+/// protocol version is too old.
+pub const ERROR_CODE_INCOMPATIBLE: i32 = -32802;
+
+/// Error code sent in response to a request for a method that requires a
+/// capability the remote did not negotiate.
+pub const ERROR_CODE_MISSING_CAPABILITY: i32 = -32803;
+
+/// Error code sent when a peer is locked out after failing too many auth
+/// challenges, so clients can tell it from a bad response.
+pub const ERROR_CODE_AUTH_LOCKED_OUT: i32 = -32804;
+
+/// Gets the code sent in response to a request whose method failed.
+fn error_code(err: &AnyError) -> i32 {
+	match err {
+		AnyError::CodeError(CodeError::AuthChallengeLockedOut(_)) => ERROR_CODE_AUTH_LOCKED_OUT,
+		_ => -1,
+	}
+}
+
+/// Maximum number of partial results of a call that can be sent before the
+/// caller acknowledges them.
+pub const PARTIAL_RESULTS_WINDOW: u32 = 16;
+
+type InFlightMap = Arc<Mutex<HashMap<u32, CancellationToken>>>;
+
+/// Entry of a request in the `InFlightMap`, which is removed when this is
+/// dropped. That's once the request completes, or if its future is dropped
+/// before then, such as when the connection closes or the handler panics.
+struct InFlightRegistration {
+	in_flight: InFlightMap,
+	id: u32,
+}
+
+impl InFlightRegistration {
+	fn new(in_flight: &InFlightMap, id: u32, token: &CancellationToken) -> Self {
+		in_flight.lock().unwrap().insert(id, token.clone());
+		Self {
+			in_flight: in_flight.clone(),
+			id,
+		}
+	}
+}
+
+impl Drop for InFlightRegistration {
+	fn drop(&mut self) {
+		self.in_flight.lock().unwrap().remove(&self.id);
+	}
+}
+
+/// Running requests of one connection that the remote refers to by ID, to
+/// cancel them or acknowledge their partial results. IDs are chosen by each
+/// remote, so every connection has its own.
+#[derive(Clone, Default)]
+pub struct ConnectionRequests {
+	/// Cancellation tokens of running cancellable requests.
+	in_flight: InFlightMap,
+	/// Send windows of running streaming requests.
+	windows: WindowsMap,
 }
 
+type WindowsMap = Arc<Mutex<HashMap<u32, Arc<Semaphore>>>>;
+type PartialsMap = Arc<Mutex<HashMap<u32, mpsc::Sender<Vec<u8>>>>>;
+type NotificationsMap = Arc<Mutex<HashMap<String, NotificationHandler>>>;
+
 impl<S: Serialization> RpcBuilder<S> {
 	/// Creates a new empty RPC builder.
 	pub fn new(serializer: S) -> Self {
@@ -59,6 +150,8 @@ impl<S: Serialization> RpcBuilder<S> {
 			serializer: Arc::new(serializer),
 			methods: HashMap::new(),
 			calls: Arc::new(std::sync::Mutex::new(HashMap::new())),
+			partials: Arc::new(Mutex::new(HashMap::new())),
+			notifications: Arc::new(Mutex::new(HashMap::new())),
 		}
 	}
 
@@ -68,6 +161,8 @@ impl<S: Serialization> RpcBuilder<S> {
 		RpcCaller {
 			serializer: self.serializer.clone(),
 			calls: self.calls.clone(),
+			partials: self.partials.clone(),
+			notifications: self.notifications.clone(),
 			sender,
 		}
 	}
@@ -79,6 +174,14 @@ impl<S: Serialization> RpcBuilder<S> {
 			serializer: self.serializer,
 			methods: self.methods,
 			calls: self.calls,
+			partials: self.partials,
+			notifications: self.notifications,
+			since: HashMap::new(),
+			max_in_flight: None,
+			protocol: None,
+			required: HashMap::new(),
+			stats: RpcStats::default(),
+			max_frame_size: DEFAULT_MAX_RPC_FRAME_SIZE,
 		}
 	}
 }
@@ -88,6 +191,16 @@ pub struct RpcMethodBuilder<S, C> {
 	serializer: Arc<S>,
 	methods: HashMap<&'static str, Method>,
 	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	partials: PartialsMap,
+	notifications: NotificationsMap,
+	/// Versions in which methods were introduced, for `METHOD_LIST_METHODS`.
+	since: HashMap<&'static str, &'static str>,
+	max_in_flight: Option<usize>,
+	protocol: Option<ProtocolSupport>,
+	/// Capabilities that must be negotiated before methods can be called.
+	required: HashMap<&'static str, &'static str>,
+	stats: RpcStats,
+	max_frame_size: usize,
 }
 
 #[derive(Serialize)]
@@ -97,12 +210,69 @@ struct DuplexStreamStarted {
 }
 
 impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
+	/// Records method calls in the given stats, instead of ones private to
+	/// this dispatcher. Must be called before methods are registered.
+	pub fn set_stats(&mut self, stats: RpcStats) {
+		self.stats = stats;
+	}
+
+	/// Makes the dispatcher answer `METHOD_HELLO` requests, negotiating the
+	/// protocol version and capabilities with the remote.
+	pub fn set_protocol(&mut self, protocol: ProtocolSupport) {
+		self.protocol = Some(protocol);
+	}
+
+	/// Makes the method fail with `ERROR_CODE_MISSING_CAPABILITY` unless the
+	/// remote negotiated the capability. Unlike `set_since`, this can also be
+	/// used for built-in methods that are only registered in `build()`.
+	pub fn require_capability(&mut self, method_name: &'static str, capability: &'static str) {
+		self.required.insert(method_name, capability);
+	}
+
+	/// Limits the number of async requests the dispatcher runs at once. Further
+	/// requests are rejected with `ERROR_CODE_SERVER_BUSY` until some finish,
+	/// and further notifications to async methods are dropped. Sync methods
+	/// and responses to our own calls are not limited, so methods can safely
+	/// call back into the remote.
+	pub fn set_max_in_flight(&mut self, limit: usize) {
+		self.max_in_flight = Some(limit);
+	}
+
+	/// Sets the size of the largest message the transport reads before
+	/// failing the connection with `CodeError::RpcFrameTooLarge`.
+	#[allow(dead_code)] // the default limit suits the CLI's own servers
+	pub fn set_max_frame_size(&mut self, limit: usize) {
+		self.max_frame_size = limit;
+	}
+
+	/// Tags a registered method with the version it was introduced in, which
+	/// is listed by `METHOD_LIST_METHODS`.
+	#[allow(dead_code)] // none of the CLI's own methods are tagged yet
+	pub fn set_since(&mut self, method_name: &'static str, version: &'static str) {
+		if !self.methods.contains_key(method_name) {
+			panic!("Method not registered: {}", method_name);
+		}
+
+		self.since.insert(method_name, version);
+	}
+
 	/// Registers a synchronous rpc call that returns its result directly.
 	pub fn register_sync<P, R, F>(&mut self, method_name: &'static str, callback: F)
 	where
 		P: DeserializeOwned,
 		R: Serialize,
 		F: Fn(P, &C) -> Result<R, AnyError> + Send + Sync + 'static,
+	{
+		self.register_sync_with_requests(method_name, move |p, c, _| callback(p, c));
+	}
+
+	/// Like `register_sync`, for built-in methods that act on the running
+	/// requests of the connection they're called on.
+	fn register_sync_with_requests<P, R, F>(&mut self, method_name: &'static str, callback: F)
+	where
+		P: DeserializeOwned,
+		R: Serialize,
+		F: Fn(P, &C, &ConnectionRequests) -> Result<R, AnyError> + Send + Sync + 'static,
 	{
 		if self.methods.contains_key(method_name) {
 			panic!("Method already registered: {}", method_name);
@@ -110,12 +280,15 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 
 		let serial = self.serializer.clone();
 		let context = self.context.clone();
+		let stats = self.stats.method(method_name);
 		self.methods.insert(
 			method_name,
-			Method::Sync(Arc::new(move |id, body| {
+			Method::Sync(Arc::new(move |id, body, requests: &ConnectionRequests| {
+				let started = Instant::now();
 				let param = match serial.deserialize::<RequestParams<P>>(body) {
 					Ok(p) => p,
 					Err(err) => {
+						stats.record(started, false);
 						return id.map(|id| {
 							serial.serialize(ErrorResponse {
 								id,
@@ -124,17 +297,19 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 									message: format!("{:?}", err),
 								},
 							})
-						})
+						});
 					}
 				};
 
-				match callback(param.params, &context) {
+				let r = callback(param.params, &context, requests);
+				stats.record(started, r.is_ok());
+				match r {
 					Ok(result) => id.map(|id| serial.serialize(&SuccessResponse { id, result })),
 					Err(err) => id.map(|id| {
 						serial.serialize(ErrorResponse {
 							id,
 							error: ResponseError {
-								code: -1,
+								code: error_code(&err),
 								message: format!("{:?}", err),
 							},
 						})
@@ -154,12 +329,15 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 	{
 		let serial = self.serializer.clone();
 		let context = self.context.clone();
+		let stats = self.stats.method(method_name);
 		self.methods.insert(
 			method_name,
-			Method::Async(Arc::new(move |id, body| {
+			Method::Async(Arc::new(move |id, body, _: &ConnectionRequests| {
+				let started = Instant::now();
 				let param = match serial.deserialize::<RequestParams<P>>(body) {
 					Ok(p) => p,
 					Err(err) => {
+						stats.record(started, false);
 						return future::ready(id.map(|id| {
 							serial.serialize(ErrorResponse {
 								id,
@@ -176,8 +354,11 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 				let callback = callback.clone();
 				let serial = serial.clone();
 				let context = context.clone();
+				let stats = stats.clone();
 				let fut = async move {
-					match callback(param.params, context).await {
+					let r = callback(param.params, context).await;
+					stats.record(started, r.is_ok());
+					match r {
 						Ok(result) => {
 							id.map(|id| serial.serialize(&SuccessResponse { id, result }))
 						}
@@ -185,7 +366,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 							serial.serialize(ErrorResponse {
 								id,
 								error: ResponseError {
-									code: -1,
+									code: error_code(&err),
 									message: format!("{:?}", err),
 								},
 							})
@@ -198,6 +379,173 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 		);
 	}
 
+	/// Registers an async rpc call that can be cancelled by the caller with a
+	/// `$/cancel` notification. The handler is given a token that's cancelled
+	/// when that happens, and if it then fails, the caller gets an error with
+	/// the `ERROR_CODE_CANCELLED` code.
+	pub fn register_async_cancellable<P, R, Fut, F>(
+		&mut self,
+		method_name: &'static str,
+		callback: F,
+	) where
+		P: DeserializeOwned + Send + 'static,
+		R: Serialize + Send + Sync + 'static,
+		Fut: Future<Output = Result<R, AnyError>> + Send,
+		F: (Fn(P, Arc<C>, CancellationToken) -> Fut) + Clone + Send + Sync + 'static,
+	{
+		let serial = self.serializer.clone();
+		let context = self.context.clone();
+		let stats = self.stats.method(method_name);
+		self.methods.insert(
+			method_name,
+			Method::Async(Arc::new(move |id, body, requests: &ConnectionRequests| {
+				let started = Instant::now();
+				let param = match serial.deserialize::<RequestParams<P>>(body) {
+					Ok(p) => p,
+					Err(err) => {
+						stats.record(started, false);
+						return future::ready(id.map(|id| {
+							serial.serialize(ErrorResponse {
+								id,
+								error: ResponseError {
+									code: 0,
+									message: format!("{:?}", err),
+								},
+							})
+						}))
+						.boxed();
+					}
+				};
+
+				// registered before the future runs, so that a cancellation
+				// that arrives right after the request is not missed
+				let token = CancellationToken::new();
+				let registration =
+					id.map(|id| InFlightRegistration::new(&requests.in_flight, id, &token));
+
+				let callback = callback.clone();
+				let serial = serial.clone();
+				let context = context.clone();
+				let stats = stats.clone();
+				let fut = async move {
+					let r = callback(param.params, context, token.clone()).await;
+					stats.record(started, r.is_ok());
+					drop(registration);
+
+					match r {
+						Ok(result) => {
+							id.map(|id| serial.serialize(&SuccessResponse { id, result }))
+						}
+						Err(err) => id.map(|id| {
+							serial.serialize(ErrorResponse {
+								id,
+								error: if token.is_cancelled() {
+									ResponseError {
+										code: ERROR_CODE_CANCELLED,
+										message: "request was cancelled".to_string(),
+									}
+								} else {
+									ResponseError {
+										code: error_code(&err),
+										message: format!("{:?}", err),
+									}
+								},
+							})
+						}),
+					}
+				};
+
+				fut.boxed()
+			})),
+		);
+	}
+
+	/// Registers an async rpc call that can send partial results before its
+	/// final result, using the given `PartialSender`. Results are delivered in
+	/// order, and at most `PARTIAL_RESULTS_WINDOW` of them can be unconsumed by
+	/// the caller at a time, after which sending waits.
+	pub fn register_async_streaming<P, T, R, Fut, F>(
+		&mut self,
+		method_name: &'static str,
+		callback: F,
+	) where
+		P: DeserializeOwned + Send + 'static,
+		T: Serialize + Send + 'static,
+		R: Serialize + Send + Sync + 'static,
+		Fut: Future<Output = Result<R, AnyError>> + Send + 'static,
+		F: (Fn(P, Arc<C>, PartialSender<S, T>) -> Fut) + Clone + Send + Sync + 'static,
+	{
+		let serial = self.serializer.clone();
+		let context = self.context.clone();
+		let stats = self.stats.method(method_name);
+		self.methods.insert(
+			method_name,
+			Method::Streaming(Arc::new(move |id, body, requests: &ConnectionRequests| {
+				let started = Instant::now();
+				let (tx, rx) = mpsc::channel(PARTIAL_RESULTS_WINDOW as usize);
+				let param = match serial.deserialize::<RequestParams<P>>(body) {
+					Ok(p) => p,
+					Err(err) => {
+						stats.record(started, false);
+						if let Some(id) = id {
+							tx.try_send(serial.serialize(ErrorResponse {
+								id,
+								error: ResponseError {
+									code: 0,
+									message: format!("{:?}", err),
+								},
+							}))
+							.ok();
+						}
+						return rx;
+					}
+				};
+
+				let windows = requests.windows.clone();
+				let window = Arc::new(Semaphore::new(PARTIAL_RESULTS_WINDOW as usize));
+				if let Some(id) = id {
+					windows.lock().unwrap().insert(id, window.clone());
+				}
+
+				let sender = PartialSender {
+					id,
+					tx: tx.clone(),
+					serializer: serial.clone(),
+					window,
+					_marker: PhantomData,
+				};
+
+				let callback = callback.clone();
+				let serial = serial.clone();
+				let context = context.clone();
+				let stats = stats.clone();
+				tokio::spawn(async move {
+					let r = callback(param.params, context, sender).await;
+					stats.record(started, r.is_ok());
+					let id = match id {
+						Some(id) => id,
+						None => return,
+					};
+
+					windows.lock().unwrap().remove(&id);
+					let body = match r {
+						Ok(result) => serial.serialize(&SuccessResponse { id, result }),
+						Err(err) => serial.serialize(ErrorResponse {
+							id,
+							error: ResponseError {
+								code: error_code(&err),
+								message: format!("{:?}", err),
+							},
+						}),
+					};
+					tx.send(body).await.ok();
+				});
+
+				rx
+			})),
+		);
+	}
+
 	/// Registers an async rpc call that returns a Future containing a duplex
 	/// stream that should be handled by the client.
 	pub fn register_duplex<P, R, Fut, F>(
@@ -213,12 +561,15 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 	{
 		let serial = self.serializer.clone();
 		let context = self.context.clone();
+		let stats = self.stats.method(method_name);
 		self.methods.insert(
 			method_name,
 			Method::Duplex(Arc::new(move |id, body| {
+				let started = Instant::now();
 				let param = match serial.deserialize::<RequestParams<P>>(body) {
 					Ok(p) => p,
 					Err(err) => {
+						stats.record(started, false);
 						return (
 							None,
 							future::ready(id.map(|id| {
@@ -238,6 +589,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 				let callback = callback.clone();
 				let serial = serial.clone();
 				let context = context.clone();
+				let stats = stats.clone();
 
 				let mut dto = StreamDto {
 					req_id: id.unwrap_or(0),
@@ -252,13 +604,15 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 				}
 
 				let fut = async move {
-					match callback(servers, param.params, context).await {
+					let r = callback(servers, param.params, context).await;
+					stats.record(started, r.is_ok());
+					match r {
 						Ok(r) => id.map(|id| serial.serialize(&SuccessResponse { id, result: r })),
 						Err(err) => id.map(|id| {
 							serial.serialize(ErrorResponse {
 								id,
 								error: ResponseError {
-									code: -1,
+									code: error_code(&err),
 									message: format!("{:?}", err),
 								},
 							})
@@ -290,13 +644,88 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 			Ok(())
 		});
 
+		// unknown or already-completed requests are ignored
+		self.register_sync_with_requests(METHOD_CANCEL, |m: CancelParams, _, requests| {
+			if let Some(token) = requests.in_flight.lock().unwrap().remove(&m.id) {
+				token.cancel();
+			}
+			Ok(())
+		});
+
+		self.register_sync_with_requests(METHOD_PARTIAL_ACK, |m: PartialAckParams, _, requests| {
+			if let Some(window) = requests.windows.lock().unwrap().get(&m.id) {
+				window.add_permits(m.count as usize);
+			}
+			Ok(())
+		});
+
+		// handled by hand, since the value is only deserialized once it's taken
+		// from the stream returned by `call_streaming`
+		let partials = self.partials.clone();
+		let serial = self.serializer.clone();
+		self.methods.insert(
+			METHOD_PARTIAL,
+			Method::Sync(Arc::new(move |_, body, _: &ConnectionRequests| {
+				let target = match serial.deserialize::<RequestParams<PartialTarget>>(body) {
+					Ok(p) => p.params.for_request_id,
+					Err(_) => return None,
+				};
+
+				// the send window keeps the channel from filling up, unless
+				// the remote ignores it
+				if let Some(tx) = partials.lock().unwrap().get(&target) {
+					tx.try_send(body.to_vec()).ok();
+				}
+				None
+			})),
+		);
+
+		let load = Arc::new(LoadCounters::default());
+		let max_in_flight = self.max_in_flight;
+		let l = load.clone();
+		self.register_sync(METHOD_LOAD, move |_: serde::de::IgnoredAny, _| {
+			Ok(RpcLoad {
+				in_flight: l.in_flight.load(Ordering::Relaxed),
+				max_in_flight,
+				rejected: l.rejected.load(Ordering::Relaxed),
+			})
+		});
+
+		let stats = self.stats.clone();
+		self.register_sync(METHOD_METRICS, move |_: serde::de::IgnoredAny, _| {
+			Ok(stats.snapshot())
+		});
+
+		let mut listed: Vec<MethodInfo> = self
+			.methods
+			.keys()
+			.chain(std::iter::once(&METHOD_LIST_METHODS))
+			.chain(self.protocol.as_ref().map(|_| &METHOD_HELLO))
+			.map(|name| MethodInfo {
+				name: name.to_string(),
+				since: self.since.get(name).map(|v| v.to_string()),
+			})
+			.collect();
+		listed.sort_by(|a, b| a.name.cmp(&b.name));
+		self.register_sync(METHOD_LIST_METHODS, move |_: serde::de::IgnoredAny, _| {
+			Ok(listed.clone())
+		});
+
 		RpcDispatcher {
 			log,
 			context: self.context,
 			calls: self.calls,
+			notifications: self.notifications,
 			serializer: self.serializer,
 			methods: Arc::new(self.methods),
 			streams,
+			limit: max_in_flight.map(|n| Arc::new(Semaphore::new(n))),
+			load,
+			protocol: self.protocol,
+			required: Arc::new(self.required),
+			negotiated: Arc::default(),
+			requests: ConnectionRequests::default(),
+			max_frame_size: self.max_frame_size,
 		}
 	}
 }
@@ -310,6 +739,8 @@ type DispatchMethod = Box<dyn Send + Sync + FnOnce(Outcome)>;
 pub struct RpcCaller<S: Serialization> {
 	serializer: Arc<S>,
 	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	partials: PartialsMap,
+	notifications: NotificationsMap,
 	sender: mpsc::UnboundedSender<Vec<u8>>,
 }
 
@@ -345,8 +776,150 @@ impl<S: Serialization> RpcCaller<S> {
 		A: Serialize,
 		R: DeserializeOwned + Send + 'static,
 	{
-		let (tx, rx) = oneshot::channel();
+		self.call_with_id(method, params).1
+	}
+
+	/// Negotiates the protocol with the remote, failing with
+	/// `IncompatibleProtocolVersion` if either side is too old for the other.
+	pub async fn hello(&self, protocol: &ProtocolSupport) -> Result<Negotiated, CodeError> {
+		let r = self
+			.call::<_, _, Negotiated>(
+				METHOD_HELLO,
+				HelloParams {
+					protocol_version: protocol.version,
+					capabilities: protocol
+						.capabilities
+						.iter()
+						.map(|c| c.to_string())
+						.collect(),
+				},
+			)
+			.await;
+
+		let negotiated = match r {
+			Ok(Ok(n)) => n,
+			Ok(Err(e)) if e.code == ERROR_CODE_INCOMPATIBLE => {
+				return Err(CodeError::IncompatibleProtocolVersion(e.message))
+			}
+			// remotes from before negotiation don't know the method, and older
+			// ones report that with a generic code
+			Ok(Err(e))
+				if e.code == ERROR_CODE_METHOD_NOT_FOUND
+					|| e.message.starts_with("Method not found") =>
+			{
+				Negotiated::default()
+			}
+			Ok(Err(e)) => return Err(CodeError::TunnelRpcCallFailed(e)),
+			Err(_) => {
+				return Err(CodeError::TunnelRpcCallFailed(ResponseError {
+					code: 0,
+					message: "connection closed".to_string(),
+				}))
+			}
+		};
+
+		if negotiated.protocol_version < protocol.min_version {
+			return Err(CodeError::IncompatibleProtocolVersion(format!(
+				"update the host (it uses protocol version {}, but at least {} is required)",
+				negotiated.protocol_version, protocol.min_version
+			)));
+		}
+
+		Ok(negotiated)
+	}
+
+	/// Asks the remote to cancel the call with the given ID.
+	pub fn cancel(&self, id: u32) -> bool {
+		self.notify(METHOD_CANCEL, CancelParams { id })
+	}
+
+	/// Like `call`, but also returns the ID of the call for use with `cancel`.
+	pub fn call_with_id<M, A, R>(
+		&self,
+		method: M,
+		params: A,
+	) -> (u32, oneshot::Receiver<Result<R, ResponseError>>)
+	where
+		M: AsRef<str> + serde::Serialize,
+		A: Serialize,
+		R: DeserializeOwned + Send + 'static,
+	{
 		let id = next_message_id();
+		(id, self.start_call(id, method, params, || {}))
+	}
+
+	/// Enqueues an outbound call to a method registered with
+	/// `register_async_streaming`, returning a stream of its partial results
+	/// along with its final result. The stream ends once the final result is
+	/// received.
+	pub fn call_streaming<M, A, T, R>(
+		&self,
+		method: M,
+		params: A,
+	) -> (
+		PartialResults<S, T>,
+		oneshot::Receiver<Result<R, ResponseError>>,
+	)
+	where
+		M: AsRef<str> + serde::Serialize,
+		A: Serialize,
+		T: DeserializeOwned,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let id = next_message_id();
+		let (tx, rx) = mpsc::channel(PARTIAL_RESULTS_WINDOW as usize);
+		self.partials.lock().unwrap().insert(id, tx);
+
+		let partials = self.partials.clone();
+		let result = self.start_call(id, method, params, move || {
+			partials.lock().unwrap().remove(&id);
+		});
+
+		let results = PartialResults {
+			id,
+			rx,
+			serializer: self.serializer.clone(),
+			sender: self.sender.clone(),
+			unacked: 0,
+			_marker: PhantomData,
+		};
+
+		(results, result)
+	}
+
+	/// Registers a handler for notifications of the given method sent by the
+	/// remote, replacing any previous handler. Methods registered on the
+	/// dispatcher take precedence.
+	pub fn on_notification<P, F>(&self, method: &str, callback: F)
+	where
+		P: DeserializeOwned,
+		F: Fn(P) + Send + Sync + 'static,
+	{
+		let serializer = self.serializer.clone();
+		self.notifications.lock().unwrap().insert(
+			method.to_string(),
+			Arc::new(move |body| {
+				if let Ok(p) = serializer.deserialize::<RequestParams<P>>(body) {
+					callback(p.params);
+				}
+			}),
+		);
+	}
+
+	/// Sends the call, running `on_done` once it completes or fails to send.
+	fn start_call<M, A, R>(
+		&self,
+		id: u32,
+		method: M,
+		params: A,
+		on_done: impl FnOnce() + Send + Sync + 'static,
+	) -> oneshot::Receiver<Result<R, ResponseError>>
+	where
+		M: AsRef<str> + serde::Serialize,
+		A: Serialize,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let (tx, rx) = oneshot::channel();
 		let body = self.serializer.serialize(&FullRequest {
 			id: Some(id),
 			method,
@@ -355,6 +928,7 @@ impl<S: Serialization> RpcCaller<S> {
 
 		if self.sender.send(body).is_err() {
 			drop(tx);
+			on_done();
 			return rx;
 		}
 
@@ -362,6 +936,7 @@ impl<S: Serialization> RpcCaller<S> {
 		self.calls.lock().unwrap().insert(
 			id,
 			Box::new(move |body| {
+				on_done();
 				match body {
 					Outcome::Error(e) => tx.send(Err(e)).ok(),
 					Outcome::Success(r) => match serializer.deserialize::<SuccessResponse<R>>(&r) {
@@ -381,17 +956,159 @@ impl<S: Serialization> RpcCaller<S> {
 	}
 }
 
+/// Sends partial results of a call registered with `register_async_streaming`.
+pub struct PartialSender<S, T> {
+	id: Option<u32>,
+	tx: mpsc::Sender<Vec<u8>>,
+	serializer: Arc<S>,
+	window: Arc<Semaphore>,
+	_marker: PhantomData<fn(T)>,
+}
+
+impl<S: Serialization, T: Serialize> PartialSender<S, T> {
+	/// Sends a partial result, waiting while the caller has too many results
+	/// it has not consumed. Returns false if the connection was closed.
+	pub async fn send(&self, value: T) -> bool {
+		// notifications have nowhere to send results to
+		let id = match self.id {
+			Some(id) => id,
+			None => return true,
+		};
+
+		tokio::select! {
+			permit = self.window.acquire() => match permit {
+				Ok(p) => p.forget(),
+				Err(_) => return false,
+			},
+			_ = self.tx.closed() => return false,
+		}
+
+		let body = self.serializer.serialize(&FullRequest {
+			id: None,
+			method: METHOD_PARTIAL,
+			params: PartialParams {
+				for_request_id: id,
+				value,
+			},
+		});
+		self.tx.send(body).await.is_ok()
+	}
+}
+
+/// Partial results of a call started with `RpcCaller::call_streaming`.
+/// Results are acknowledged to the remote as they're taken from the stream.
+pub struct PartialResults<S: Serialization, T> {
+	id: u32,
+	rx: mpsc::Receiver<Vec<u8>>,
+	serializer: Arc<S>,
+	sender: mpsc::UnboundedSender<Vec<u8>>,
+	unacked: u32,
+	_marker: PhantomData<fn() -> T>,
+}
+
+impl<S: Serialization, T> PartialResults<S, T> {
+	/// Gets the ID of the call, for use with `RpcCaller::cancel`.
+	pub fn id(&self) -> u32 {
+		self.id
+	}
+}
+
+impl<S: Serialization, T: DeserializeOwned> Stream for PartialResults<S, T> {
+	type Item = Result<T, ResponseError>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let body = match ready!(self.rx.poll_recv(cx)) {
+			Some(b) => b,
+			None => return Poll::Ready(None),
+		};
+
+		// acknowledged in batches, which is fine as long as the batch is
+		// smaller than the window
+		self.unacked += 1;
+		if self.unacked >= PARTIAL_RESULTS_WINDOW / 2 {
+			let ack = RpcCaller::serialize_notify(
+				&*self.serializer,
+				METHOD_PARTIAL_ACK,
+				PartialAckParams {
+					id: self.id,
+					count: self.unacked,
+				},
+			);
+			self.sender.send(ack).ok();
+			self.unacked = 0;
+		}
+
+		let r = self
+			.serializer
+			.deserialize::<RequestParams<PartialParams<T>>>(&body)
+			.map(|p| p.params.value)
+			.map_err(|e| ResponseError {
+				code: 0,
+				message: e.to_string(),
+			});
+		Poll::Ready(Some(r))
+	}
+}
+
 /// Dispatcher returned from a Builder that provides a transport-agnostic way to
 /// deserialize and handle RPC calls. This structure may get more advanced as
 /// time goes on...
-#[derive(Clone)]
 pub struct RpcDispatcher<S, C> {
 	log: log::Logger,
 	context: Arc<C>,
 	serializer: Arc<S>,
 	methods: Arc<HashMap<&'static str, Method>>,
 	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	notifications: NotificationsMap,
 	streams: Streams,
+	limit: Option<Arc<Semaphore>>,
+	load: Arc<LoadCounters>,
+	protocol: Option<ProtocolSupport>,
+	required: Arc<HashMap<&'static str, &'static str>>,
+	negotiated: Arc<Mutex<Negotiated>>,
+	requests: ConnectionRequests,
+	max_frame_size: usize,
+}
+
+// implemented by hand, since deriving would require the serializer and
+// context to be Clone, though they're only shared
+impl<S, C> Clone for RpcDispatcher<S, C> {
+	fn clone(&self) -> Self {
+		Self {
+			log: self.log.clone(),
+			context: self.context.clone(),
+			serializer: self.serializer.clone(),
+			methods: self.methods.clone(),
+			calls: self.calls.clone(),
+			notifications: self.notifications.clone(),
+			streams: self.streams.clone(),
+			limit: self.limit.clone(),
+			load: self.load.clone(),
+			protocol: self.protocol,
+			required: self.required.clone(),
+			negotiated: self.negotiated.clone(),
+			requests: self.requests.clone(),
+			max_frame_size: self.max_frame_size,
+		}
+	}
+}
+
+#[derive(Default)]
+struct LoadCounters {
+	in_flight: AtomicUsize,
+	rejected: AtomicU64,
+}
+
+/// Held while an async request runs, counting it against the limit.
+struct RequestGuard {
+	load: Arc<LoadCounters>,
+	_permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for RequestGuard {
+	fn drop(&mut self) {
+		self.load.in_flight.fetch_sub(1, Ordering::Relaxed);
+	}
 }
 
 static MESSAGE_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
@@ -416,21 +1133,198 @@ impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
 		}
 	}
 
+	/// Counts a new async request against the limit, or returns the error
+	/// response to send if too many are running. Notifications over the limit
+	/// are dropped, since there's nothing to respond to.
+	fn admit(&self, id: Option<u32>) -> Result<RequestGuard, Option<Vec<u8>>> {
+		let permit = match &self.limit {
+			Some(limit) => match limit.clone().try_acquire_owned() {
+				Ok(p) => Some(p),
+				Err(_) => {
+					self.load.rejected.fetch_add(1, Ordering::Relaxed);
+					return Err(id.map(|id| {
+						self.serializer.serialize(ErrorResponse {
+							id,
+							error: ResponseError {
+								code: ERROR_CODE_SERVER_BUSY,
+								message: "server busy, too many requests in flight".to_string(),
+							},
+						})
+					}));
+				}
+			},
+			None => None,
+		};
+
+		self.load.in_flight.fetch_add(1, Ordering::Relaxed);
+		Ok(RequestGuard {
+			load: self.load.clone(),
+			_permit: permit,
+		})
+	}
+
+	/// Gets a copy of the dispatcher for a new connection, which negotiates
+	/// its protocol and tracks its running requests separately from other
+	/// connections.
+	pub fn for_connection(&self) -> Self {
+		Self {
+			negotiated: Arc::default(),
+			requests: ConnectionRequests::default(),
+			..self.clone()
+		}
+	}
+
+	/// Gets the size of the largest message transports should read.
+	pub fn max_frame_size(&self) -> usize {
+		self.max_frame_size
+	}
+
+	/// Gets what was negotiated with the remote. Until it sends a
+	/// `METHOD_HELLO`, this is protocol version 0 without capabilities.
+	#[cfg(test)]
+	pub fn negotiated(&self) -> Negotiated {
+		self.negotiated.lock().unwrap().clone()
+	}
+
+	fn handle_hello(
+		&self,
+		id: Option<u32>,
+		body: &[u8],
+		protocol: &ProtocolSupport,
+	) -> Option<Vec<u8>> {
+		let params = match self
+			.serializer
+			.deserialize::<RequestParams<HelloParams>>(body)
+		{
+			Ok(p) => p.params,
+			Err(err) => {
+				return id.map(|id| {
+					self.serializer.serialize(ErrorResponse {
+						id,
+						error: ResponseError {
+							code: 0,
+							message: format!("{:?}", err),
+						},
+					})
+				})
+			}
+		};
+
+		if params.protocol_version < protocol.min_version {
+			return id.map(|id| {
+				self.serializer.serialize(ErrorResponse {
+					id,
+					error: ResponseError {
+						code: ERROR_CODE_INCOMPATIBLE,
+						message: format!(
+							"update the client (it uses protocol version {}, but at least {} is required)",
+							params.protocol_version, protocol.min_version
+						),
+					},
+				})
+			});
+		}
+
+		let negotiated = Negotiated {
+			protocol_version: params.protocol_version.min(protocol.version),
+			capabilities: protocol
+				.capabilities
+				.iter()
+				.filter(|c| params.capabilities.iter().any(|p| p == *c))
+				.map(|c| c.to_string())
+				.collect(),
+		};
+		*self.negotiated.lock().unwrap() = negotiated.clone();
+
+		id.map(|id| {
+			self.serializer.serialize(&SuccessResponse {
+				id,
+				result: negotiated,
+			})
+		})
+	}
+
 	/// Like dispatch, but allows passing an existing PartialIncoming.
 	pub fn dispatch_with_partial(&self, body: &[u8], partial: PartialIncoming) -> MaybeSync {
 		let id = partial.id;
 
 		if let Some(method_name) = partial.method {
+			if let Some(protocol) = &self.protocol {
+				if method_name == METHOD_HELLO {
+					return MaybeSync::Sync(self.handle_hello(id, body, protocol));
+				}
+			}
+
+			if let Some(capability) = self.required.get(method_name.as_str()) {
+				if !self.negotiated.lock().unwrap().has(capability) {
+					return MaybeSync::Sync(id.map(|id| {
+						self.serializer.serialize(ErrorResponse {
+							id,
+							error: ResponseError {
+								code: ERROR_CODE_MISSING_CAPABILITY,
+								message: format!(
+									"{} requires the '{}' capability, which was not negotiated",
+									method_name, capability
+								),
+							},
+						})
+					}));
+				}
+			}
+
 			let method = self.methods.get(method_name.as_str());
 			match method {
-				Some(Method::Sync(callback)) => MaybeSync::Sync(callback(id, body)),
-				Some(Method::Async(callback)) => MaybeSync::Future(callback(id, body)),
-				Some(Method::Duplex(callback)) => MaybeSync::Stream(callback(id, body)),
+				Some(Method::Sync(callback)) => MaybeSync::Sync(callback(id, body, &self.requests)),
+				Some(Method::Async(callback)) => match self.admit(id) {
+					Ok(guard) => {
+						let fut = callback(id, body, &self.requests);
+						MaybeSync::Future(
+							async move {
+								let r = fut.await;
+								drop(guard);
+								r
+							}
+							.boxed(),
+						)
+					}
+					Err(busy) => MaybeSync::Sync(busy),
+				},
+				Some(Method::Duplex(callback)) => match self.admit(id) {
+					Ok(guard) => {
+						let (dto, fut) = callback(id, body);
+						let fut = async move {
+							let r = fut.await;
+							drop(guard);
+							r
+						};
+						MaybeSync::Stream((dto, fut.boxed()))
+					}
+					Err(busy) => MaybeSync::Sync(busy),
+				},
+				Some(Method::Streaming(callback)) => match self.admit(id) {
+					Ok(guard) => MaybeSync::Partial(PartialStream {
+						rx: callback(id, body, &self.requests),
+						_guard: guard,
+					}),
+					Err(busy) => MaybeSync::Sync(busy),
+				},
+				None if id.is_none() => {
+					let handler = self
+						.notifications
+						.lock()
+						.unwrap()
+						.get(method_name.as_str())
+						.cloned();
+					if let Some(handler) = handler {
+						handler(body);
+					}
+					MaybeSync::Sync(None)
+				}
 				None => MaybeSync::Sync(id.map(|id| {
 					self.serializer.serialize(ErrorResponse {
 						id,
 						error: ResponseError {
-							code: -1,
+							code: ERROR_CODE_METHOD_NOT_FOUND,
 							message: format!("Method not found: {}", method_name),
 						},
 					})
@@ -523,6 +1417,23 @@ impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
 		}
 	}
 
+	/// Forwards the results of a streaming call returned from dispatch(), in
+	/// order. Forwarding waits while `write_tx` is full, which in turn makes
+	/// the method wait to send further results.
+	pub fn register_partials(
+		&self,
+		write_tx: mpsc::Sender<impl 'static + From<Vec<u8>> + Send>,
+		mut results: PartialStream,
+	) {
+		tokio::spawn(async move {
+			while let Some(v) = results.rx.recv().await {
+				if write_tx.send(v.into()).await.is_err() {
+					return;
+				}
+			}
+		});
+	}
+
 	pub fn context(&self) -> Arc<C> {
 		self.context.clone()
 	}
@@ -633,6 +1544,17 @@ async fn write_loop(
 const METHOD_STREAMS_STARTED: &str = "streams_started";
 const METHOD_STREAM_DATA: &str = "stream_data";
 const METHOD_STREAM_ENDED: &str = "stream_ended";
+const METHOD_CANCEL: &str = "$/cancel";
+/// Lists the methods the dispatcher supports, as `MethodInfo`s.
+pub const METHOD_LIST_METHODS: &str = "rpc.methods";
+/// Gets the dispatcher's request counters, as an `RpcLoad`.
+pub const METHOD_LOAD: &str = "rpc.load";
+/// Gets the dispatcher's per-method call counts and latencies, as `RpcMetrics`.
+pub const METHOD_METRICS: &str = "rpc.metrics";
+/// Negotiates the protocol, see `RpcMethodBuilder::set_protocol`.
+pub const METHOD_HELLO: &str = "hello";
+const METHOD_PARTIAL: &str = "$/partial";
+const METHOD_PARTIAL_ACK: &str = "$/partial_ack";
 
 #[allow(dead_code)] // false positive
 trait AssertIsSync: Sync {}
@@ -665,6 +1587,169 @@ struct StreamEndedParams {
 	pub stream: u32,
 }
 
+/// Protocol that a dispatcher negotiates with its remote in `METHOD_HELLO`.
+#[derive(Clone, Copy, Debug)]
+pub struct ProtocolSupport {
+	pub version: u32,
+	/// Oldest protocol version of the remote that can still be talked to.
+	pub min_version: u32,
+	pub capabilities: &'static [&'static str],
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelloParams {
+	pub protocol_version: u32,
+	#[serde(default)]
+	pub capabilities: Vec<String>,
+}
+
+/// Result of `METHOD_HELLO`: the lower of both protocol versions, and the
+/// capabilities both sides support. Remotes that predate negotiation are
+/// treated as protocol version 0 without capabilities.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Negotiated {
+	pub protocol_version: u32,
+	#[serde(default)]
+	pub capabilities: Vec<String>,
+}
+
+impl Negotiated {
+	pub fn has(&self, capability: &str) -> bool {
+		self.capabilities.iter().any(|c| c == capability)
+	}
+}
+
+/// Upper bounds of the latency buckets in `MethodMetrics`.
+pub const LATENCY_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1000, 5000];
+
+/// Call counts and latencies of a method. Calls only touch atomics, and are
+/// summed up when the metrics are read.
+#[derive(Default)]
+struct MethodStats {
+	calls: AtomicU64,
+	errors: AtomicU64,
+	/// One more than `LATENCY_BUCKETS_MS`, for the calls slower than all.
+	buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+impl MethodStats {
+	fn record(&self, started: Instant, ok: bool) {
+		let elapsed = started.elapsed().as_millis() as u64;
+		let bucket = LATENCY_BUCKETS_MS
+			.iter()
+			.position(|b| elapsed <= *b)
+			.unwrap_or(LATENCY_BUCKETS_MS.len());
+
+		self.calls.fetch_add(1, Ordering::Relaxed);
+		self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+		if !ok {
+			self.errors.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+}
+
+/// Per-method call metrics, which can be shared between dispatchers with
+/// `RpcMethodBuilder::set_stats` to sum them up across connections.
+#[derive(Clone, Default)]
+pub struct RpcStats {
+	methods: Arc<Mutex<HashMap<&'static str, Arc<MethodStats>>>>,
+}
+
+impl RpcStats {
+	fn method(&self, name: &'static str) -> Arc<MethodStats> {
+		self.methods
+			.lock()
+			.unwrap()
+			.entry(name)
+			.or_default()
+			.clone()
+	}
+
+	/// Gets the metrics of the methods that were called at least once.
+	pub fn snapshot(&self) -> RpcMetrics {
+		let mut methods: Vec<_> = self
+			.methods
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|(_, s)| s.calls.load(Ordering::Relaxed) > 0)
+			.map(|(name, s)| MethodMetrics {
+				name: name.to_string(),
+				calls: s.calls.load(Ordering::Relaxed),
+				errors: s.errors.load(Ordering::Relaxed),
+				latency: s
+					.buckets
+					.iter()
+					.map(|b| b.load(Ordering::Relaxed))
+					.collect(),
+			})
+			.collect();
+		methods.sort_by(|a, b| a.name.cmp(&b.name));
+
+		RpcMetrics {
+			latency_buckets_ms: LATENCY_BUCKETS_MS.to_vec(),
+			methods,
+		}
+	}
+}
+
+/// Metrics returned from `METHOD_METRICS`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RpcMetrics {
+	pub latency_buckets_ms: Vec<u64>,
+	pub methods: Vec<MethodMetrics>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MethodMetrics {
+	pub name: String,
+	pub calls: u64,
+	pub errors: u64,
+	/// Number of calls in each of the `latency_buckets_ms`, followed by the
+	/// number of calls slower than the last bucket.
+	pub latency: Vec<u64>,
+}
+
+/// Request counters returned from `METHOD_LOAD`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RpcLoad {
+	/// Number of async requests currently running.
+	pub in_flight: usize,
+	pub max_in_flight: Option<usize>,
+	/// Number of requests rejected because the limit was reached.
+	pub rejected: u64,
+}
+
+/// Describes a method returned from `METHOD_LIST_METHODS`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MethodInfo {
+	pub name: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub since: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CancelParams {
+	pub id: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PartialParams<T> {
+	pub for_request_id: u32,
+	pub value: T,
+}
+
+#[derive(Deserialize)]
+struct PartialTarget {
+	pub for_request_id: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PartialAckParams {
+	pub id: u32,
+	pub count: u32,
+}
+
 #[derive(Serialize)]
 pub struct FullRequest<M: AsRef<str>, P> {
 	pub id: Option<u32>,
@@ -705,15 +1790,577 @@ pub struct StreamDto {
 	streams: Vec<(u32, DuplexStream)>,
 }
 
+/// Results of a streaming call, see `RpcDispatcher::register_partials`.
+pub struct PartialStream {
+	rx: mpsc::Receiver<Vec<u8>>,
+	_guard: RequestGuard,
+}
+
 pub enum MaybeSync {
 	Stream((Option<StreamDto>, BoxFuture<'static, Option<Vec<u8>>>)),
 	Future(BoxFuture<'static, Option<Vec<u8>>>),
+	/// Partial results followed by the final result, see `register_partials`.
+	Partial(PartialStream),
 	Sync(Option<Vec<u8>>),
 }
 
 #[cfg(test)]
 mod tests {
+	use std::time::Duration;
+
+	use futures::StreamExt;
+
 	use super::*;
+	use crate::{
+		json_rpc::JsonRpcSerializer,
+		msgpack_rpc::{new_msgpack_rpc, start_msgpack_rpc, MsgPackSerializer},
+		tunnels::protocol::EmptyObject,
+		util::{errors::CodeError, sync::new_barrier},
+	};
+
+	fn cancellable_dispatcher() -> RpcDispatcher<JsonRpcSerializer, ()> {
+		let mut rpc = RpcBuilder::new(JsonRpcSerializer {}).methods(());
+		rpc.register_async_cancellable("slow", |_: EmptyObject, _, cancel| async move {
+			tokio::select! {
+				_ = tokio::time::sleep(Duration::from_secs(30)) => Ok(EmptyObject {}),
+				_ = cancel.cancelled() => Err(CodeError::RequestCancelled.into()),
+			}
+		});
+		rpc.register_async_cancellable(
+			"fast",
+			|_: EmptyObject, _, _| async move { Ok(EmptyObject {}) },
+		);
+		rpc.build(log::Logger::test())
+	}
+
+	fn request(id: Option<u32>, method: &str, params: impl Serialize) -> Vec<u8> {
+		serde_json::to_vec(&FullRequest { id, method, params }).unwrap()
+	}
+
+	fn spawn_call(
+		dispatcher: &RpcDispatcher<JsonRpcSerializer, ()>,
+		id: u32,
+		method: &str,
+	) -> tokio::task::JoinHandle<Option<Vec<u8>>> {
+		match dispatcher.dispatch(&request(Some(id), method, EmptyObject {})) {
+			MaybeSync::Future(f) => tokio::spawn(f),
+			_ => panic!("expected an async method"),
+		}
+	}
+
+	fn cancel(dispatcher: &RpcDispatcher<JsonRpcSerializer, ()>, id: u32) {
+		let r = dispatcher.dispatch(&request(None, METHOD_CANCEL, CancelParams { id }));
+		assert!(matches!(r, MaybeSync::Sync(None)));
+	}
+
+	#[tokio::test]
+	async fn test_cancel_in_flight_request() {
+		let dispatcher = cancellable_dispatcher();
+		let call = spawn_call(&dispatcher, 1, "slow");
+		cancel(&dispatcher, 1);
+
+		let response = tokio::time::timeout(Duration::from_secs(5), call)
+			.await
+			.expect("expected the request to be cancelled")
+			.unwrap()
+			.unwrap();
+		let response: ErrorResponse = serde_json::from_slice(&response).unwrap();
+		assert_eq!(response.id, 1);
+		assert_eq!(response.error.code, ERROR_CODE_CANCELLED);
+	}
+
+	#[tokio::test]
+	async fn test_cancellable_requests_are_unregistered() {
+		let mut rpc = RpcBuilder::new(JsonRpcSerializer {}).methods(());
+		rpc.register_async_cancellable("fail", |_: EmptyObject, _, _| async move {
+			Err::<EmptyObject, _>(CodeError::NoRunningTunnel.into())
+		});
+		rpc.register_async_cancellable("slow", |_: EmptyObject, _, _| async move {
+			tokio::time::sleep(Duration::from_secs(30)).await;
+			Ok(EmptyObject {})
+		});
+		let dispatcher = rpc.build(log::Logger::test());
+		let in_flight = dispatcher.requests.in_flight.clone();
+
+		spawn_call(&dispatcher, 1, "fail").await.unwrap().unwrap();
+		assert!(in_flight.lock().unwrap().is_empty());
+
+		// such as when the connection closes while the request is running
+		let call = spawn_call(&dispatcher, 2, "slow");
+		assert_eq!(in_flight.lock().unwrap().len(), 1);
+		call.abort();
+		let _ = call.await;
+		assert!(in_flight.lock().unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_cancel_ignores_unknown_requests() {
+		let dispatcher = cancellable_dispatcher();
+		cancel(&dispatcher, 42);
+
+		let response = spawn_call(&dispatcher, 2, "fast").await.unwrap().unwrap();
+		cancel(&dispatcher, 2);
+
+		let response: SuccessResponse<EmptyObject> = serde_json::from_slice(&response).unwrap();
+		assert_eq!(response.id, 2);
+	}
+
+	#[test]
+	fn test_list_methods() {
+		let mut rpc = RpcBuilder::new(JsonRpcSerializer {}).methods(());
+		rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
+		rpc.register_async("fetch", |_: EmptyObject, _| async { Ok(EmptyObject {}) });
+		rpc.set_since("fetch", "1.90");
+		let dispatcher = rpc.build(log::Logger::test());
+
+		let response =
+			match dispatcher.dispatch(&request(Some(1), METHOD_LIST_METHODS, EmptyObject {})) {
+				MaybeSync::Sync(Some(r)) => r,
+				_ => panic!("expected a sync response"),
+			};
+		let response: SuccessResponse<Vec<MethodInfo>> = serde_json::from_slice(&response).unwrap();
+		let methods: Vec<_> = response
+			.result
+			.into_iter()
+			.filter(|m| !m.name.starts_with("$/") && !m.name.starts_with("stream"))
+			.collect();
+		assert_eq!(
+			methods,
+			vec![
+				MethodInfo {
+					name: "fetch".to_string(),
+					since: Some("1.90".to_string()),
+				},
+				MethodInfo {
+					name: "ping".to_string(),
+					since: None,
+				},
+				MethodInfo {
+					name: METHOD_LOAD.to_string(),
+					since: None,
+				},
+				MethodInfo {
+					name: METHOD_LIST_METHODS.to_string(),
+					since: None,
+				},
+				MethodInfo {
+					name: METHOD_METRICS.to_string(),
+					since: None,
+				},
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_method_metrics() {
+		let mut rpc = RpcBuilder::new(JsonRpcSerializer {}).methods(());
+		rpc.register_sync("fast", |_: EmptyObject, _| Ok(EmptyObject {}));
+		rpc.register_sync("fail", |_: EmptyObject, _| -> Result<(), AnyError> {
+			Err(CodeError::RequestCancelled.into())
+		});
+		rpc.register_async("sleep", |ms: u64, _| async move {
+			tokio::time::sleep(Duration::from_millis(ms)).await;
+			Ok(EmptyObject {})
+		});
+		let dispatcher = rpc.build(log::Logger::test());
+
+		for (method, params) in [
+			("fast", "{}"),
+			("fast", "{}"),
+			("fail", "{}"),
+			("fail", "1"),
+		] {
+			let body = format!(r#"{{"id":1,"method":"{}","params":{}}}"#, method, params);
+			assert!(matches!(
+				dispatcher.dispatch(body.as_bytes()),
+				MaybeSync::Sync(Some(_))
+			));
+		}
+		for ms in [20, 120] {
+			match dispatcher.dispatch(&request(Some(1), "sleep", ms)) {
+				MaybeSync::Future(f) => f.await.unwrap(),
+				_ => panic!("expected an async method"),
+			};
+		}
+
+		let response = match dispatcher.dispatch(&request(Some(2), METHOD_METRICS, EmptyObject {}))
+		{
+			MaybeSync::Sync(Some(r)) => r,
+			_ => panic!("expected a sync response"),
+		};
+		let metrics = serde_json::from_slice::<SuccessResponse<RpcMetrics>>(&response)
+			.unwrap()
+			.result;
+		assert_eq!(metrics.latency_buckets_ms, LATENCY_BUCKETS_MS.to_vec());
+
+		let mut methods = metrics.methods.into_iter();
+		let fail = methods.next().unwrap();
+		assert_eq!(
+			(fail.name.as_str(), fail.calls, fail.errors),
+			("fail", 2, 2)
+		);
+		let fast = methods.next().unwrap();
+		assert_eq!(
+			(fast.name.as_str(), fast.calls, fast.errors),
+			("fast", 2, 0)
+		);
+		assert_eq!(fast.latency, vec![2, 0, 0, 0, 0, 0, 0, 0, 0]);
+		let sleep = methods.next().unwrap();
+		assert_eq!(
+			(sleep.name.as_str(), sleep.calls, sleep.errors),
+			("sleep", 2, 0)
+		);
+		assert_eq!(sleep.latency, vec![0, 0, 0, 1, 0, 1, 0, 0, 0]);
+		assert!(methods.next().is_none(), "only called methods are listed");
+	}
+
+	#[test]
+	fn test_shared_stats() {
+		let stats = RpcStats::default();
+		for _ in 0..2 {
+			let mut rpc = RpcBuilder::new(JsonRpcSerializer {}).methods(());
+			rpc.set_stats(stats.clone());
+			rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
+			rpc.build(log::Logger::test())
+				.dispatch(&request(Some(1), "ping", EmptyObject {}));
+		}
+
+		assert_eq!(stats.snapshot().methods[0].calls, 2);
+	}
+
+	const TEST_PROTOCOL: ProtocolSupport = ProtocolSupport {
+		version: 3,
+		min_version: 2,
+		capabilities: &["introspection", "cancel"],
+	};
+
+	fn negotiating_dispatcher() -> RpcDispatcher<JsonRpcSerializer, ()> {
+		let mut rpc = RpcBuilder::new(JsonRpcSerializer {}).methods(());
+		rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
+		rpc.set_protocol(TEST_PROTOCOL);
+		rpc.require_capability(METHOD_LIST_METHODS, "introspection");
+		rpc.build(log::Logger::test())
+	}
+
+	fn dispatch_sync(dispatcher: &RpcDispatcher<JsonRpcSerializer, ()>, body: &str) -> Vec<u8> {
+		match dispatcher.dispatch(body.as_bytes()) {
+			MaybeSync::Sync(Some(r)) => r,
+			_ => panic!("expected a sync response"),
+		}
+	}
+
+	#[test]
+	fn test_lockout_has_own_code() {
+		let mut rpc = RpcBuilder::new(JsonRpcSerializer {}).methods(());
+		rpc.register_sync("locked", |_: EmptyObject, _| {
+			Err::<EmptyObject, _>(CodeError::AuthChallengeLockedOut(300).into())
+		});
+		rpc.register_sync("bad", |_: EmptyObject, _| {
+			Err::<EmptyObject, _>(CodeError::AuthChallengeBadToken.into())
+		});
+		let dispatcher = rpc.build(log::Logger::test());
+
+		let r = dispatch_sync(&dispatcher, r#"{"id":1,"method":"locked","params":{}}"#);
+		let r: ErrorResponse = serde_json::from_slice(&r).unwrap();
+		assert_eq!(r.error.code, ERROR_CODE_AUTH_LOCKED_OUT);
+
+		let r = dispatch_sync(&dispatcher, r#"{"id":2,"method":"bad","params":{}}"#);
+		let r: ErrorResponse = serde_json::from_slice(&r).unwrap();
+		assert_eq!(r.error.code, -1);
+	}
+
+	#[test]
+	fn test_hello_from_old_client() {
+		let dispatcher = negotiating_dispatcher();
+
+		// recorded from a client that predates negotiation
+		let r = dispatch_sync(&dispatcher, r#"{"id":1,"method":"ping","params":{}}"#);
+		assert!(serde_json::from_slice::<SuccessResponse<EmptyObject>>(&r).is_ok());
+
+		let r = dispatch_sync(
+			&dispatcher,
+			r#"{"id":2,"method":"rpc.methods","params":{}}"#,
+		);
+		let r: ErrorResponse = serde_json::from_slice(&r).unwrap();
+		assert_eq!(r.error.code, ERROR_CODE_MISSING_CAPABILITY);
+
+		let r = dispatch_sync(
+			&dispatcher,
+			r#"{"id":3,"method":"hello","params":{"protocol_version":1}}"#,
+		);
+		let r: ErrorResponse = serde_json::from_slice(&r).unwrap();
+		assert_eq!(r.error.code, ERROR_CODE_INCOMPATIBLE);
+		assert!(r.error.message.starts_with("update the client"));
+		assert_eq!(dispatcher.negotiated(), Negotiated::default());
+	}
+
+	#[test]
+	fn test_hello_from_new_client() {
+		let dispatcher = negotiating_dispatcher();
+
+		// a newer client, with capabilities and fields this version doesn't know
+		let r = dispatch_sync(
+			&dispatcher,
+			r#"{"id":1,"method":"hello","params":{"protocol_version":9,"capabilities":["compression","introspection"],"compression":"zstd"}}"#,
+		);
+		let expected = Negotiated {
+			protocol_version: 3,
+			capabilities: vec!["introspection".to_string()],
+		};
+		let r: SuccessResponse<Negotiated> = serde_json::from_slice(&r).unwrap();
+		assert_eq!(r.result, expected);
+		assert_eq!(dispatcher.negotiated(), expected);
+
+		let r = dispatch_sync(
+			&dispatcher,
+			r#"{"id":2,"method":"rpc.methods","params":{}}"#,
+		);
+		assert!(serde_json::from_slice::<SuccessResponse<Vec<MethodInfo>>>(&r).is_ok());
+
+		// negotiation is per connection
+		assert_eq!(
+			dispatcher.for_connection().negotiated(),
+			Negotiated::default()
+		);
+	}
+
+	#[derive(Deserialize)]
+	struct RequestWithId<P> {
+		id: u32,
+		params: P,
+	}
+
+	/// Runs `RpcCaller::hello` against the given recorded response.
+	async fn hello_with_response(response: &str) -> Result<Negotiated, CodeError> {
+		let mut builder = RpcBuilder::new(JsonRpcSerializer {});
+		let (tx, mut rx) = mpsc::unbounded_channel();
+		let caller = builder.get_caller(tx);
+		let dispatcher = builder.methods(()).build(log::Logger::test());
+
+		let hello = tokio::spawn(async move { caller.hello(&TEST_PROTOCOL).await });
+		let request: RequestWithId<HelloParams> =
+			serde_json::from_slice(&rx.recv().await.unwrap()).unwrap();
+		assert_eq!(request.params.protocol_version, TEST_PROTOCOL.version);
+
+		let response = response.replace("$ID", &request.id.to_string());
+		dispatcher.dispatch(response.as_bytes());
+		hello.await.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_hello_to_old_host() {
+		// recorded from a host that predates negotiation
+		let r = hello_with_response(
+			r#"{"id":$ID,"error":{"code":-1,"message":"Method not found: hello"}}"#,
+		)
+		.await;
+		match r {
+			Err(CodeError::IncompatibleProtocolVersion(m)) => {
+				assert!(m.starts_with("update the host"))
+			}
+			r => panic!("unexpected result {:?}", r.map(|_| ())),
+		}
+
+		let r = hello_with_response(
+			r#"{"id":$ID,"result":{"protocol_version":2,"capabilities":["cancel"],"extra":1}}"#,
+		)
+		.await
+		.unwrap();
+		assert_eq!(
+			r,
+			Negotiated {
+				protocol_version: 2,
+				capabilities: vec!["cancel".to_string()],
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn test_hello_rejected_by_host() {
+		let r = hello_with_response(&format!(
+			r#"{{"id":$ID,"error":{{"code":{},"message":"update the client"}}}}"#,
+			ERROR_CODE_INCOMPATIBLE
+		))
+		.await;
+		assert!(matches!(r, Err(CodeError::IncompatibleProtocolVersion(_))));
+	}
+
+	fn limited_dispatcher(limit: usize) -> RpcDispatcher<JsonRpcSerializer, Semaphore> {
+		let mut rpc = RpcBuilder::new(JsonRpcSerializer {}).methods(Semaphore::new(0));
+		rpc.set_max_in_flight(limit);
+		// waits until the test releases it
+		rpc.register_async("wait", |_: EmptyObject, release| async move {
+			release.acquire().await.unwrap().forget();
+			Ok(EmptyObject {})
+		});
+		rpc.build(log::Logger::test())
+	}
+
+	fn get_load(dispatcher: &RpcDispatcher<JsonRpcSerializer, Semaphore>) -> RpcLoad {
+		match dispatcher.dispatch(&request(Some(0), METHOD_LOAD, EmptyObject {})) {
+			MaybeSync::Sync(Some(r)) => {
+				serde_json::from_slice::<SuccessResponse<RpcLoad>>(&r)
+					.unwrap()
+					.result
+			}
+			_ => panic!("expected a sync response"),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_max_in_flight_rejects_excess() {
+		let dispatcher = limited_dispatcher(8);
+		let mut running = vec![];
+		let mut rejected = 0;
+		for id in 1..=100 {
+			match dispatcher.dispatch(&request(Some(id), "wait", EmptyObject {})) {
+				MaybeSync::Future(f) => running.push(tokio::spawn(f)),
+				MaybeSync::Sync(Some(r)) => {
+					let r: ErrorResponse = serde_json::from_slice(&r).unwrap();
+					assert_eq!(r.error.code, ERROR_CODE_SERVER_BUSY);
+					rejected += 1;
+				}
+				_ => panic!("unexpected dispatch result"),
+			}
+		}
+
+		assert_eq!(running.len(), 8);
+		assert_eq!(rejected, 92);
+		assert_eq!(
+			get_load(&dispatcher),
+			RpcLoad {
+				in_flight: 8,
+				max_in_flight: Some(8),
+				rejected: 92,
+			}
+		);
+
+		// notifications count against the limit too, and are dropped over it
+		assert!(matches!(
+			dispatcher.dispatch(&request(None, "wait", EmptyObject {})),
+			MaybeSync::Sync(None)
+		));
+		assert_eq!(get_load(&dispatcher).rejected, 93);
+
+		dispatcher.context().add_permits(8);
+		for r in running {
+			r.await.unwrap().unwrap();
+		}
+		assert_eq!(get_load(&dispatcher).in_flight, 0);
+	}
+
+	#[tokio::test]
+	async fn test_max_in_flight_allows_sequential_traffic() {
+		let dispatcher = limited_dispatcher(2);
+		for id in 1..=200 {
+			dispatcher.context().add_permits(1);
+			match dispatcher.dispatch(&request(Some(id), "wait", EmptyObject {})) {
+				MaybeSync::Future(f) => {
+					let r: SuccessResponse<EmptyObject> =
+						serde_json::from_slice(&f.await.unwrap()).unwrap();
+					assert_eq!(r.id, id);
+				}
+				_ => panic!("expected the request to be accepted"),
+			}
+		}
+
+		assert_eq!(get_load(&dispatcher).rejected, 0);
+	}
+
+	async fn assert_partials_round_trip<S: Serialization + Clone>(serializer: S) {
+		let mut builder = RpcBuilder::new(serializer.clone());
+		let (tx, mut rx) = mpsc::unbounded_channel();
+		let caller = builder.get_caller(tx);
+		let dispatcher = builder.methods(()).build(log::Logger::test());
+
+		let (results, done) = caller.call_streaming::<_, _, u32, String>("count", EmptyObject {});
+		let id = results.id();
+		rx.recv().await.unwrap(); // the request itself
+
+		for value in 0..3 {
+			let partial = RpcCaller::serialize_notify(
+				&serializer,
+				METHOD_PARTIAL,
+				PartialParams {
+					for_request_id: id,
+					value,
+				},
+			);
+			assert!(matches!(
+				dispatcher.dispatch(&partial),
+				MaybeSync::Sync(None)
+			));
+		}
+		dispatcher.dispatch(&serializer.serialize(&SuccessResponse { id, result: "done" }));
+
+		let values: Vec<u32> = results.map(|r| r.unwrap()).collect().await;
+		assert_eq!(values, vec![0, 1, 2]);
+		assert_eq!(done.await.unwrap().unwrap(), "done");
+	}
+
+	#[tokio::test]
+	async fn test_partials_round_trip() {
+		assert_partials_round_trip(JsonRpcSerializer {}).await;
+		assert_partials_round_trip(MsgPackSerializer {}).await;
+	}
+
+	#[tokio::test]
+	async fn test_streaming_end_to_end() {
+		let sent = Arc::new(AtomicU32::new(0));
+		let mut server = new_msgpack_rpc();
+		let (server_msg_tx, server_msg_rx) = mpsc::unbounded_channel();
+		let server_caller = server.get_caller(server_msg_tx);
+		let mut server = server.methods(sent.clone());
+		server.register_async_streaming("count", |n: u32, sent, partials| async move {
+			for i in 0..n {
+				if !partials.send(i).await {
+					break;
+				}
+				sent.fetch_add(1, Ordering::SeqCst);
+			}
+			Ok(n)
+		});
+
+		let (server_io, client_io) = tokio::io::duplex(4096);
+		let (closed, _close) = new_barrier::<()>();
+		let (read, write) = tokio::io::split(server_io);
+		tokio::spawn(start_msgpack_rpc(
+			server.build(log::Logger::test()),
+			read,
+			write,
+			server_msg_rx,
+			closed.clone(),
+		));
+
+		let mut client = new_msgpack_rpc();
+		let (client_msg_tx, client_msg_rx) = mpsc::unbounded_channel();
+		let caller = client.get_caller(client_msg_tx);
+		let (read, write) = tokio::io::split(client_io);
+		tokio::spawn(start_msgpack_rpc(
+			client.methods(()).build(log::Logger::test()),
+			read,
+			write,
+			client_msg_rx,
+			closed,
+		));
+
+		let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+		caller.on_notification("progress", move |p: String| {
+			progress_tx.send(p).ok();
+		});
+		server_caller.notify("progress", "starting");
+		assert_eq!(progress_rx.recv().await.unwrap(), "starting");
+
+		let (results, done) = caller.call_streaming::<_, _, u32, u32>("count", 100);
+
+		// nothing was consumed yet, so the server stops once the window is full
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert_eq!(sent.load(Ordering::SeqCst), PARTIAL_RESULTS_WINDOW);
+
+		let values: Vec<u32> = results.map(|r| r.unwrap()).collect().await;
+		assert_eq!(values, (0..100).collect::<Vec<_>>());
+		assert_eq!(done.await.unwrap().unwrap(), 100);
+	}
 
 	#[tokio::test]
 	async fn test_remove() {
@@ -0,0 +1,402 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fmt,
+	fs::{File, OpenOptions},
+	io::{LineWriter, Write},
+	path::Path,
+	sync::{
+		atomic::{AtomicBool, AtomicU32, Ordering},
+		Mutex,
+	},
+	time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use serde::{
+	de::{self, MapAccess, SeqAccess, Visitor},
+	Deserialize, Deserializer,
+};
+use serde_json::{json, Map, Value};
+
+/// Strings longer than this many characters are truncated in the trace.
+const MAX_STRING_LEN: usize = 256;
+
+/// Values of keys that contain any of these (ignoring case) are redacted.
+const SECRET_KEY_PARTS: &[&str] = &[
+	"token",
+	"secret",
+	"password",
+	"passphrase",
+	"authorization",
+	"credential",
+	"cookie",
+];
+
+/// Checked before doing any work, so tracing costs a load when it's off.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_CONNECTION_ID: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+	static ref TRACER: Mutex<Option<Tracer>> = Mutex::new(None);
+}
+
+struct Tracer {
+	out: LineWriter<File>,
+	started: Instant,
+}
+
+/// Starts writing the frames of all RPC connections to the file as JSON
+/// lines, replacing its contents. Only the user can read the file, since
+/// frames can hold more than the redacted fields.
+pub fn install(path: &Path) -> std::io::Result<()> {
+	let file = create_private_file(path)?;
+	*TRACER.lock().unwrap() = Some(Tracer {
+		out: LineWriter::new(file),
+		started: Instant::now(),
+	});
+	ENABLED.store(true, Ordering::Release);
+	Ok(())
+}
+
+fn create_private_file(path: &Path) -> std::io::Result<File> {
+	#[cfg(not(windows))]
+	use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+	let mut f = OpenOptions::new();
+	f.create(true).write(true).truncate(true);
+	#[cfg(not(windows))]
+	f.mode(0o600);
+
+	let file = f.open(path)?;
+	// the mode only applies to new files
+	#[cfg(not(windows))]
+	file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+	Ok(file)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+	Json,
+	MsgPack,
+}
+
+/// Traces the frames read and written on one connection.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionTrace {
+	id: u32,
+	format: Format,
+}
+
+impl ConnectionTrace {
+	pub fn new(format: Format) -> Self {
+		Self {
+			id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+			format,
+		}
+	}
+
+	/// Traces a frame read from the connection.
+	#[inline]
+	pub fn inbound(&self, frame: &[u8]) {
+		if ENABLED.load(Ordering::Relaxed) {
+			self.write("in", frame);
+		}
+	}
+
+	/// Traces a frame written to the connection.
+	#[inline]
+	pub fn outbound(&self, frame: &[u8]) {
+		if ENABLED.load(Ordering::Relaxed) {
+			self.write("out", frame);
+		}
+	}
+
+	fn write(&self, direction: &'static str, frame: &[u8]) {
+		let decoded = match self.format {
+			Format::Json => serde_json::from_slice::<Traced>(frame).map_err(|e| e.to_string()),
+			Format::MsgPack => rmp_serde::from_slice::<Traced>(frame).map_err(|e| e.to_string()),
+		};
+
+		if let Some(t) = TRACER.lock().unwrap().as_mut() {
+			let r = record(
+				t.started.elapsed(),
+				direction,
+				self.id,
+				frame.len(),
+				decoded.map(|d| d.0),
+			);
+			writeln!(t.out, "{}", r).ok();
+		}
+	}
+}
+
+fn record(
+	elapsed: Duration,
+	direction: &str,
+	connection: u32,
+	len: usize,
+	decoded: Result<Value, String>,
+) -> Value {
+	let mut r = json!({
+		"time_ms": elapsed.as_millis() as u64,
+		"dir": direction,
+		"conn": connection,
+		"len": len,
+	});
+
+	match decoded {
+		Ok(Value::Object(mut frame)) => {
+			for key in ["method", "id", "params", "result", "error"] {
+				if let Some(v) = frame.remove(key) {
+					r[key] = scrub(v);
+				}
+			}
+		}
+		Ok(other) => r["frame"] = scrub(other),
+		Err(e) => r["decode_error"] = Value::String(e),
+	}
+
+	r
+}
+
+fn is_secret_key(key: &str) -> bool {
+	let key = key.to_ascii_lowercase();
+	SECRET_KEY_PARTS.iter().any(|p| key.contains(p))
+}
+
+/// Redacts the values of secret-looking keys, and truncates long strings.
+fn scrub(v: Value) -> Value {
+	match v {
+		Value::String(s) => {
+			let len = s.chars().count();
+			if len > MAX_STRING_LEN {
+				let start: String = s.chars().take(MAX_STRING_LEN).collect();
+				Value::String(format!("{}... ({} chars)", start, len))
+			} else {
+				Value::String(s)
+			}
+		}
+		Value::Array(a) => Value::Array(a.into_iter().map(scrub).collect()),
+		Value::Object(o) => Value::Object(
+			o.into_iter()
+				.map(|(k, v)| {
+					let v = if is_secret_key(&k) {
+						Value::String("<redacted>".to_string())
+					} else {
+						scrub(v)
+					};
+					(k, v)
+				})
+				.collect(),
+		),
+		v => v,
+	}
+}
+
+/// A frame decoded from either format, with binary data summarized by its
+/// length, since serde_json values can't hold bytes.
+struct Traced(Value);
+
+impl<'de> Deserialize<'de> for Traced {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		deserializer.deserialize_any(TracedVisitor).map(Traced)
+	}
+}
+
+struct TracedVisitor;
+
+impl<'de> Visitor<'de> for TracedVisitor {
+	type Value = Value;
+
+	fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("any value")
+	}
+
+	fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+		Ok(Value::Bool(v))
+	}
+
+	fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+		Ok(v.into())
+	}
+
+	fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+		Ok(v.into())
+	}
+
+	fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+		Ok(v.into())
+	}
+
+	fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+		Ok(Value::String(v.to_string()))
+	}
+
+	fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Value, E> {
+		Ok(Value::String(format!("<{} bytes>", v.len())))
+	}
+
+	fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+		Ok(Value::Null)
+	}
+
+	fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+		Ok(Value::Null)
+	}
+
+	fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+		deserializer.deserialize_any(self)
+	}
+
+	fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+		let mut out = vec![];
+		while let Some(Traced(v)) = seq.next_element()? {
+			out.push(v);
+		}
+		Ok(Value::Array(out))
+	}
+
+	fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+		let mut out = Map::new();
+		while let Some((Traced(k), Traced(v))) = map.next_entry()? {
+			let k = match k {
+				Value::String(s) => s,
+				k => k.to_string(),
+			};
+			out.insert(k, v);
+		}
+		Ok(Value::Object(out))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde::Serialize;
+	use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+	use super::*;
+	use crate::{
+		json_rpc::{new_json_rpc, start_json_rpc},
+		log,
+		util::sync::new_barrier,
+	};
+
+	#[test]
+	fn test_redacts_and_truncates() {
+		let frame = json!({
+			"id": 1,
+			"method": "login",
+			"params": {
+				"accessToken": "abc",
+				"nested": [{ "client_secret": "def", "name": "ghi" }],
+				"long": "x".repeat(1000),
+			},
+			"extra": "dropped",
+		});
+
+		let r = record(Duration::from_millis(5), "in", 3, 42, Ok(frame));
+		assert_eq!(
+			r,
+			json!({
+				"time_ms": 5,
+				"dir": "in",
+				"conn": 3,
+				"len": 42,
+				"id": 1,
+				"method": "login",
+				"params": {
+					"accessToken": "<redacted>",
+					"nested": [{ "client_secret": "<redacted>", "name": "ghi" }],
+					"long": format!("{}... (1000 chars)", "x".repeat(MAX_STRING_LEN)),
+				},
+			})
+		);
+	}
+
+	#[test]
+	fn test_decodes_msgpack_bytes() {
+		#[derive(Serialize)]
+		struct Body {
+			#[serde(with = "serde_bytes")]
+			body: Vec<u8>,
+		}
+
+		let frame = rmp_serde::to_vec_named(&Body { body: vec![0; 100] }).unwrap();
+		let decoded = rmp_serde::from_slice::<Traced>(&frame).unwrap();
+		assert_eq!(decoded.0, json!({ "body": "<100 bytes>" }));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_trace_file_is_private() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("trace.jsonl");
+		std::fs::write(&path, "old").unwrap();
+		std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+		create_private_file(&path).unwrap();
+		let metadata = std::fs::metadata(&path).unwrap();
+		assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+		assert_eq!(metadata.len(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_traces_frames_in_order() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("trace.jsonl");
+		install(&path).unwrap();
+
+		let mut rpc = new_json_rpc().methods(());
+		rpc.register_sync("trace_test", |_: Value, _| Ok(()));
+
+		let (server_io, client_io) = tokio::io::duplex(4096);
+		let (closed, _close) = new_barrier::<()>();
+		let (read, write) = tokio::io::split(server_io);
+		tokio::spawn(start_json_rpc(
+			rpc.build(log::Logger::test()),
+			read,
+			write,
+			(),
+			closed,
+		));
+
+		let (read, mut write) = tokio::io::split(client_io);
+		let mut lines = BufReader::new(read).lines();
+		for id in [1, 2] {
+			let req = json!({ "id": id, "method": "trace_test", "params": { "token": "hunter2" } });
+			write
+				.write_all(format!("{}\n", req).as_bytes())
+				.await
+				.unwrap();
+			lines.next_line().await.unwrap().unwrap();
+		}
+
+		// other tests may be tracing at the same time, so only look at the
+		// frames of this connection
+		let trace = std::fs::read_to_string(&path).unwrap();
+		let frames: Vec<Value> = trace
+			.lines()
+			.map(|l| serde_json::from_str(l).unwrap())
+			.collect();
+		let conn = frames
+			.iter()
+			.find(|f| f["method"] == "trace_test")
+			.map(|f| f["conn"].clone())
+			.unwrap();
+		let frames: Vec<_> = frames.into_iter().filter(|f| f["conn"] == conn).collect();
+
+		let summary: Vec<_> = frames
+			.iter()
+			.map(|f| format!("{} {}", f["dir"].as_str().unwrap(), f["id"]))
+			.collect();
+		assert_eq!(summary, vec!["in 1", "out 1", "in 2", "out 2"]);
+		assert_eq!(frames[0]["params"]["token"], "<redacted>");
+		assert!(!trace.contains("hunter2"));
+	}
+}
@@ -92,7 +92,8 @@ impl<'a> SelfUpdate<'a> {
 		let staging_path = target_path.with_extension(".update");
 		let archive_contents_path = tempdir.path().join("content");
 		// unzipping the single binary is pretty small and fast--don't bother with passing progress
-		unzip_downloaded_release(&archive_path, &archive_contents_path, SilentCopyProgress())?;
+		unzip_downloaded_release(&archive_path, &archive_contents_path, SilentCopyProgress())
+			.await?;
 		copy_updated_cli_to_path(&archive_contents_path, &staging_path)?;
 
 		// 3. Copy file metadata, make sure the new binary is executable\
@@ -6,7 +6,7 @@
 use serde::{Deserialize, Serialize};
 use std::{
 	fs::{File, OpenOptions},
-	io::{Seek, SeekFrom, Write},
+	io,
 	path::{Path, PathBuf},
 	time::Duration,
 };
@@ -14,19 +14,26 @@ use sysinfo::{Pid, PidExt};
 
 use crate::{
 	async_pipe::{
-		get_socket_name, get_socket_rw_stream, listen_socket_rw_stream, AsyncPipe,
+		get_socket_name_for, get_socket_rw_stream, listen_socket_rw_stream, AsyncPipe,
 		AsyncPipeListener,
 	},
+	log,
 	util::{
 		errors::CodeError,
-		file_lock::{FileLock, Lock, PREFIX_LOCKED_BYTES},
-		machine::wait_until_process_exits,
+		file_lock::{self, FileLockGuard},
+		machine::{wait_until_process_exits, ProcessInfo, SystemProcessInfo},
 	},
 };
 
+#[cfg(unix)]
+use crate::{
+	async_pipe::{socket_dirs_for, socket_file_name_for},
+	util::socket_activation::{adopt_unix_listener, take_listen_fds},
+};
+
 pub struct SingletonServer {
 	server: AsyncPipeListener,
-	_lock: FileLock,
+	_lock: FileLockGuard,
 }
 
 impl SingletonServer {
@@ -44,32 +51,184 @@ pub enum SingletonConnection {
 }
 
 /// Contents of the lock file; the listening socket ID and process ID
-/// doing the listening.
+/// doing the listening. The process' start time and executable tell it apart
+/// from a later process that got the same PID. They're missing in lock files
+/// written by older versions.
 #[derive(Deserialize, Serialize)]
 struct LockFileMatter {
 	socket_path: String,
 	pid: u32,
+	#[serde(default)]
+	start_time: Option<u64>,
+	#[serde(default)]
+	exe: Option<String>,
+}
+
+/// Start times are reported in whole seconds, and can be rounded differently
+/// each time they're read.
+const START_TIME_TOLERANCE_SECS: u64 = 1;
+
+/// Whether the process named in a lock file is still the one that wrote it.
+#[derive(Debug, PartialEq, Eq)]
+enum LockOwner {
+	Live,
+	Exited,
+	/// The PID belongs to a different process now, for the given reason.
+	Replaced(String),
+}
+
+fn check_lock_owner(matter: &LockFileMatter, processes: &dyn ProcessInfo) -> LockOwner {
+	let identity = match processes.identity(matter.pid) {
+		Some(i) => i,
+		None => return LockOwner::Exited,
+	};
+
+	if let Some(start_time) = matter.start_time {
+		if identity.start_time.abs_diff(start_time) > START_TIME_TOLERANCE_SECS {
+			return LockOwner::Replaced(format!(
+				"it started at {} rather than {}",
+				identity.start_time, start_time
+			));
+		}
+	}
+
+	if let (Some(expected), Some(actual)) = (&matter.exe, &identity.exe) {
+		if Path::new(expected) != actual {
+			return LockOwner::Replaced(format!(
+				"it runs {} rather than {}",
+				actual.display(),
+				expected
+			));
+		}
+	}
+
+	LockOwner::Live
+}
+
+fn read_lock_matter(file: &mut File) -> Result<LockFileMatter, rmp_serde::decode::Error> {
+	let data = file_lock::read_data(file).map_err(rmp_serde::decode::Error::InvalidDataRead)?;
+	rmp_serde::from_slice::<LockFileMatter>(&data)
+}
+
+/// A singleton socket passed to the process by its service manager through
+/// socket activation, which is used instead of creating a new socket.
+#[allow(dead_code)] // only constructed on unix
+pub struct ActivatedSocket {
+	listener: AsyncPipeListener,
+	path: PathBuf,
+}
+
+impl ActivatedSocket {
+	/// Takes the socket passed to this process by systemd, if any.
+	#[cfg(unix)]
+	pub fn take() -> Result<Option<ActivatedSocket>, CodeError> {
+		let fd = match take_listen_fds().into_iter().next() {
+			Some(f) => f.fd,
+			None => return Ok(None),
+		};
+
+		let listener = adopt_unix_listener(fd).map_err(CodeError::AsyncPipeListenerFailed)?;
+		let path = listener
+			.local_addr()
+			.ok()
+			.and_then(|a| a.as_pathname().map(|p| p.to_owned()))
+			.ok_or_else(|| {
+				CodeError::AsyncPipeListenerFailed(std::io::Error::new(
+					std::io::ErrorKind::InvalidInput,
+					"activated socket is not bound to a path",
+				))
+			})?;
+
+		Ok(Some(ActivatedSocket {
+			listener: listener.into(),
+			path,
+		}))
+	}
+
+	#[cfg(windows)]
+	pub fn take() -> Result<Option<ActivatedSocket>, CodeError> {
+		Ok(None)
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
 }
 
 /// Tries to acquire the singleton homed at the given lock file, either starting
 /// a new singleton if it doesn't exist, or connecting otherwise.
-pub async fn acquire_singleton(lock_file: &Path) -> Result<SingletonConnection, CodeError> {
-	let file = OpenOptions::new()
-		.read(true)
-		.write(true)
-		.create(true)
-		.truncate(false)
-		.open(lock_file)
-		.map_err(CodeError::SingletonLockfileOpenFailed)?;
+pub async fn acquire_singleton(
+	log: &log::Logger,
+	lock_file: &Path,
+) -> Result<SingletonConnection, CodeError> {
+	acquire_singleton_with(log, lock_file, None).await
+}
 
-	match FileLock::acquire(file) {
-		Ok(Lock::AlreadyLocked(mut file)) => connect_as_client_with_file(&mut file)
-			.await
-			.map(SingletonConnection::Client),
-		Ok(Lock::Acquired(lock)) => start_singleton_server(lock)
+/// Like `acquire_singleton`, but listens on the activated socket, if given,
+/// when this instance becomes the singleton.
+pub async fn acquire_singleton_with(
+	log: &log::Logger,
+	lock_file: &Path,
+	activated: Option<ActivatedSocket>,
+) -> Result<SingletonConnection, CodeError> {
+	acquire_singleton_checked(log, lock_file, activated, &SystemProcessInfo).await
+}
+
+async fn acquire_singleton_checked(
+	log: &log::Logger,
+	lock_file: &Path,
+	activated: Option<ActivatedSocket>,
+	processes: &dyn ProcessInfo,
+) -> Result<SingletonConnection, CodeError> {
+	let mut took_over = false;
+	loop {
+		if let Some(lock) = file_lock::try_acquire(lock_file)? {
+			return start_singleton_server(lock, lock_file, activated)
+				.await
+				.map(SingletonConnection::Singleton);
+		}
+
+		let mut file = match File::open(lock_file) {
+			Ok(f) => f,
+			// released and removed since
+			Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+			Err(e) => return Err(CodeError::SingletonLockfileOpenFailed(e)),
+		};
+
+		// The lock can outlive its owner if a child process inherited it. Once
+		// the owner is gone, remove the file so a new lock can be made in its
+		// place; the stale one can't be taken while it's still held.
+		if !took_over {
+			let stale = match read_lock_matter(&mut file) {
+				Ok(m) => match check_lock_owner(&m, processes) {
+					LockOwner::Live => None,
+					LockOwner::Exited => Some((m.pid, "it exited".to_string())),
+					LockOwner::Replaced(reason) => Some((m.pid, reason)),
+				},
+				// possibly still being written
+				Err(_) => None,
+			};
+
+			if let Some((pid, reason)) = stale {
+				info!(
+					log,
+					"Taking over the stale singleton lock at {}: process {} no longer holds it, {}",
+					lock_file.display(),
+					pid,
+					reason
+				);
+				drop(file);
+				took_over = true;
+				if std::fs::remove_file(lock_file).is_ok() {
+					continue;
+				}
+				return Err(CodeError::SingletonLockedProcessExited(pid));
+			}
+		}
+
+		return connect_as_client_with_file(&mut file, processes)
 			.await
-			.map(SingletonConnection::Singleton),
-		Err(e) => Err(e),
+			.map(SingletonConnection::Client);
 	}
 }
 
@@ -80,42 +239,250 @@ pub async fn connect_as_client(lock_file: &Path) -> Result<AsyncPipe, CodeError>
 		.open(lock_file)
 		.map_err(CodeError::SingletonLockfileOpenFailed)?;
 
-	connect_as_client_with_file(&mut file).await
+	connect_as_client_with_file(&mut file, &SystemProcessInfo).await
 }
 
-async fn start_singleton_server(mut lock: FileLock) -> Result<SingletonServer, CodeError> {
-	let socket_path = get_socket_name();
+async fn start_singleton_server(
+	mut lock: FileLockGuard,
+	lock_file: &Path,
+	activated: Option<ActivatedSocket>,
+) -> Result<SingletonServer, CodeError> {
+	let socket_path = match &activated {
+		Some(a) => a.path.clone(),
+		None => {
+			let path = get_socket_name_for(lock_file);
+			// a socket left behind by a previous owner of the lock, which is
+			// safe to replace since the lock is now held
+			#[cfg(unix)]
+			let _ = std::fs::remove_file(&path);
+			path
+		}
+	};
 
-	let mut vec = Vec::with_capacity(128);
-	let _ = vec.write(&[0; PREFIX_LOCKED_BYTES]);
+	let pid = std::process::id();
+	let identity = SystemProcessInfo.identity(pid);
+	let mut vec = Vec::with_capacity(256);
 	let _ = rmp_serde::encode::write(
 		&mut vec,
 		&LockFileMatter {
 			socket_path: socket_path.to_string_lossy().to_string(),
-			pid: std::process::id(),
+			pid,
+			start_time: identity.as_ref().map(|i| i.start_time),
+			exe: identity
+				.and_then(|i| i.exe)
+				.map(|e| e.to_string_lossy().to_string()),
 		},
 	);
 
-	lock.file_mut()
-		.write_all(&vec)
+	lock.write_data(&vec)
 		.map_err(CodeError::SingletonLockfileOpenFailed)?;
 
-	let server = listen_socket_rw_stream(&socket_path).await?;
+	let server = match activated {
+		Some(a) => a.listener,
+		None => listen_socket_rw_stream(&socket_path).await?,
+	};
 	Ok(SingletonServer {
 		server,
 		_lock: lock,
 	})
 }
 
+/// Step taken while stopping the process that holds a singleton lock.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KillStep {
+	/// The process shut down after being asked to over RPC.
+	ShutDownGracefully,
+	/// Asking the process to shut down failed, for the given reason.
+	GracefulShutdownFailed(String),
+	/// The process didn't shut down gracefully and was terminated.
+	Terminated(u32),
+	/// The process named in the lock file had already exited, or its PID now
+	/// belongs to a different process which was left alone.
+	OwnerNotRunning(u32),
+	/// A lock file or socket left behind by the process was deleted.
+	RemovedFile(PathBuf),
+}
+
+/// How often to check whether a stopped process exited.
+const KILL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Stops the process holding the singleton lock. If given, the graceful
+/// future is used to ask it to shut down first. If that fails or takes longer
+/// than the timeout, the process is terminated, after checking it is still
+/// the one that wrote the lock. Lock files owned by another user are refused.
+/// The lock file and socket are then removed.
+/// Returns the steps taken, which are empty if nothing was running.
+pub async fn kill_singleton(
+	log: &log::Logger,
+	lock_file: &Path,
+	graceful: Option<impl std::future::Future<Output = Result<(), CodeError>>>,
+	timeout: Duration,
+) -> Result<Vec<KillStep>, CodeError> {
+	kill_singleton_checked(log, lock_file, graceful, timeout, &SystemProcessInfo).await
+}
+
+async fn kill_singleton_checked(
+	log: &log::Logger,
+	lock_file: &Path,
+	graceful: Option<impl std::future::Future<Output = Result<(), CodeError>>>,
+	timeout: Duration,
+	processes: &dyn ProcessInfo,
+) -> Result<Vec<KillStep>, CodeError> {
+	let mut steps = vec![];
+
+	if let Some(graceful) = graceful {
+		let shutdown = async {
+			graceful.await?;
+			wait_for_lock_release(lock_file).await;
+			Ok::<_, CodeError>(())
+		};
+
+		match tokio::time::timeout(timeout, shutdown).await {
+			Ok(Ok(())) => {
+				steps.push(KillStep::ShutDownGracefully);
+				return Ok(steps);
+			}
+			// nothing to shut down, but a stale lock may still need cleaning up
+			Ok(Err(CodeError::NoRunningTunnel)) => {}
+			Ok(Err(e)) => steps.push(KillStep::GracefulShutdownFailed(e.to_string())),
+			Err(_) => steps.push(KillStep::GracefulShutdownFailed(format!(
+				"no response after {}s",
+				timeout.as_secs_f32()
+			))),
+		}
+	}
+
+	let mut file = match OpenOptions::new().read(true).write(true).open(lock_file) {
+		Ok(f) => f,
+		Err(_) => return Ok(steps),
+	};
+
+	// the PID in the file is only trusted if it was written by this user, so a
+	// lock file planted by someone else can't get another process terminated
+	check_lock_file_owner(&file)?;
+
+	// an unlocked file is left over from a process that exited normally
+	if !file_lock::is_held(lock_file)? {
+		return Ok(steps);
+	}
+
+	let matter = match read_lock_matter(&mut file) {
+		Ok(m) => m,
+		Err(e) => return Err(CodeError::SingletonLockfileReadFailed(e)),
+	};
+	drop(file);
+
+	match check_lock_owner(&matter, processes) {
+		LockOwner::Live => {
+			info!(log, "Terminating tunnel process {}", matter.pid);
+			if !processes.terminate(matter.pid) || !wait_for_exit(processes, &matter, timeout).await
+			{
+				return Err(CodeError::SingletonKillFailed(matter.pid));
+			}
+			steps.push(KillStep::Terminated(matter.pid));
+		}
+		LockOwner::Exited => steps.push(KillStep::OwnerNotRunning(matter.pid)),
+		LockOwner::Replaced(reason) => {
+			info!(
+				log,
+				"Not terminating process {}, which didn't create the lock: {}", matter.pid, reason
+			);
+			steps.push(KillStep::OwnerNotRunning(matter.pid));
+		}
+	}
+
+	if std::fs::remove_file(lock_file).is_ok() {
+		steps.push(KillStep::RemovedFile(lock_file.to_owned()));
+	}
+
+	let socket_path = PathBuf::from(&matter.socket_path);
+	if is_own_socket_file(&socket_path, lock_file) && std::fs::remove_file(&socket_path).is_ok() {
+		steps.push(KillStep::RemovedFile(socket_path));
+	}
+
+	Ok(steps)
+}
+
+#[cfg(unix)]
+fn check_lock_file_owner(file: &File) -> Result<(), CodeError> {
+	use std::os::unix::fs::MetadataExt;
+
+	let uid = file
+		.metadata()
+		.map_err(CodeError::SingletonLockfileOpenFailed)?
+		.uid();
+	let own_uid = unsafe { libc::geteuid() };
+	if uid != own_uid {
+		return Err(CodeError::SingletonLockfileNotOwned(uid));
+	}
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_lock_file_owner(_file: &File) -> Result<(), CodeError> {
+	Ok(())
+}
+
+/// Gets whether the path is a socket file created by `get_socket_name_for`.
+/// Activated sockets belong to the service manager and are left in place,
+/// and named pipes on Windows go away with the process.
+#[cfg(unix)]
+fn is_own_socket_file(path: &Path, lock_file: &Path) -> bool {
+	let in_socket_dir = path
+		.parent()
+		.map(|p| socket_dirs_for(lock_file).iter().any(|d| d == p))
+		.unwrap_or(false);
+	in_socket_dir
+		&& path
+			.file_name()
+			.map(|n| n.to_string_lossy() == socket_file_name_for(lock_file))
+			.unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_own_socket_file(_path: &Path, _lock_file: &Path) -> bool {
+	false
+}
+
+async fn wait_for_lock_release(lock_file: &Path) {
+	loop {
+		if !file_lock::is_held(lock_file).unwrap_or(false) {
+			return;
+		}
+		tokio::time::sleep(KILL_POLL_INTERVAL).await;
+	}
+}
+
+/// Waits for the process to exit, returning false if it didn't in time.
+async fn wait_for_exit(
+	processes: &dyn ProcessInfo,
+	matter: &LockFileMatter,
+	timeout: Duration,
+) -> bool {
+	let deadline = tokio::time::Instant::now() + timeout;
+	while check_lock_owner(matter, processes) == LockOwner::Live {
+		if tokio::time::Instant::now() >= deadline {
+			return false;
+		}
+		tokio::time::sleep(KILL_POLL_INTERVAL).await;
+	}
+	true
+}
+
 const MAX_CLIENT_ATTEMPTS: i32 = 10;
 
-async fn connect_as_client_with_file(mut file: &mut File) -> Result<AsyncPipe, CodeError> {
+async fn connect_as_client_with_file(
+	file: &mut File,
+	processes: &dyn ProcessInfo,
+) -> Result<AsyncPipe, CodeError> {
 	// retry, since someone else could get a lock and we could read it before
 	// the JSON info was finished writing out
 	let mut attempt = 0;
 	loop {
-		let _ = file.seek(SeekFrom::Start(PREFIX_LOCKED_BYTES as u64));
-		let r = match rmp_serde::from_read::<_, LockFileMatter>(&mut file) {
+		let r = match read_lock_matter(file) {
+			Ok(prev) if check_lock_owner(&prev, processes) != LockOwner::Live => {
+				return Err(CodeError::SingletonLockedProcessExited(prev.pid));
+			}
 			Ok(prev) => {
 				let socket_path = PathBuf::from(prev.socket_path);
 
@@ -155,11 +522,16 @@ async fn retry_get_socket_rw_stream(
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::util::{
+		file_lock::{FileLock, Lock, PREFIX_LOCKED_BYTES},
+		machine::ProcessIdentity,
+	};
+	use std::io::{Seek, SeekFrom, Write};
 
 	#[tokio::test]
 	async fn test_acquires_singleton() {
 		let dir = tempfile::tempdir().expect("expected to make temp dir");
-		let s = acquire_singleton(&dir.path().join("lock"))
+		let s = acquire_singleton(&log::Logger::test(), &dir.path().join("lock"))
 			.await
 			.expect("expected to acquire");
 
@@ -173,7 +545,7 @@ mod tests {
 	async fn test_acquires_client() {
 		let dir = tempfile::tempdir().expect("expected to make temp dir");
 		let lockfile = dir.path().join("lock");
-		let s1 = acquire_singleton(&lockfile)
+		let s1 = acquire_singleton(&log::Logger::test(), &lockfile)
 			.await
 			.expect("expected to acquire1");
 		match s1 {
@@ -183,7 +555,7 @@ mod tests {
 			_ => panic!("expected to be singleton"),
 		};
 
-		let s2 = acquire_singleton(&lockfile)
+		let s2 = acquire_singleton(&log::Logger::test(), &lockfile)
 			.await
 			.expect("expected to acquire2");
 		match s2 {
@@ -191,4 +563,341 @@ mod tests {
 			_ => panic!("expected to be client"),
 		}
 	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_acquires_with_activated_socket() {
+		let dir = tempfile::tempdir().expect("expected to make temp dir");
+		let lockfile = dir.path().join("lock");
+		let socket_path = dir.path().join("activated.sock");
+		let activated = ActivatedSocket {
+			listener: tokio::net::UnixListener::bind(&socket_path)
+				.expect("expected to bind")
+				.into(),
+			path: socket_path.clone(),
+		};
+
+		let s1 = acquire_singleton_with(&log::Logger::test(), &lockfile, Some(activated))
+			.await
+			.expect("expected to acquire1");
+		match s1 {
+			SingletonConnection::Singleton(mut l) => tokio::spawn(async move {
+				l.accept().await.expect("expected to accept");
+			}),
+			_ => panic!("expected to be singleton"),
+		};
+
+		let mut file = File::open(&lockfile).expect("expected to open lock");
+		file.seek(SeekFrom::Start(PREFIX_LOCKED_BYTES as u64))
+			.unwrap();
+		let matter: LockFileMatter = rmp_serde::from_read(&mut file).unwrap();
+		assert_eq!(PathBuf::from(matter.socket_path), socket_path);
+
+		connect_as_client(&lockfile)
+			.await
+			.expect("expected to connect to activated socket");
+	}
+
+	#[tokio::test]
+	async fn test_instances_with_different_data_dirs() {
+		let dir_a = tempfile::tempdir().expect("expected to make temp dir");
+		let dir_b = tempfile::tempdir().expect("expected to make temp dir");
+		let lock_a = dir_a.path().join("tunnel-oss.lock");
+		let lock_b = dir_b.path().join("tunnel-oss.lock");
+
+		for lockfile in [&lock_a, &lock_b] {
+			match acquire_singleton(&log::Logger::test(), lockfile)
+				.await
+				.expect("expected to acquire")
+			{
+				SingletonConnection::Singleton(mut l) => tokio::spawn(async move {
+					l.accept().await.expect("expected to accept");
+				}),
+				_ => panic!("expected each instance to be a singleton"),
+			};
+		}
+
+		let socket_a = read_lock_matter(&mut File::open(&lock_a).unwrap())
+			.unwrap()
+			.socket_path;
+		let socket_b = read_lock_matter(&mut File::open(&lock_b).unwrap())
+			.unwrap()
+			.socket_path;
+		assert_ne!(socket_a, socket_b);
+		assert_eq!(PathBuf::from(&socket_a), get_socket_name_for(&lock_a));
+
+		// the socket is in a directory other users can't get into
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let parent = Path::new(&socket_a).parent().unwrap();
+			assert!(socket_dirs_for(&lock_a).iter().any(|d| d == parent));
+			let mode = std::fs::metadata(parent).unwrap().permissions().mode();
+			assert_eq!(mode & 0o077, 0, "{:o}", mode);
+		}
+
+		connect_as_client(&lock_a)
+			.await
+			.expect("expected to connect to the first instance");
+		connect_as_client(&lock_b)
+			.await
+			.expect("expected to connect to the second instance");
+	}
+
+	struct FakeProcesses(Option<ProcessIdentity>);
+
+	impl ProcessInfo for FakeProcesses {
+		fn identity(&self, _pid: u32) -> Option<ProcessIdentity> {
+			self.0.clone()
+		}
+
+		fn terminate(&self, _pid: u32) -> bool {
+			panic!("unexpected terminate")
+		}
+	}
+
+	fn matter(start_time: Option<u64>, exe: Option<&str>) -> LockFileMatter {
+		LockFileMatter {
+			socket_path: "sock".to_string(),
+			pid: 42,
+			start_time,
+			exe: exe.map(|e| e.to_string()),
+		}
+	}
+
+	fn running(start_time: u64, exe: &str) -> FakeProcesses {
+		FakeProcesses(Some(ProcessIdentity {
+			start_time,
+			exe: Some(PathBuf::from(exe)),
+		}))
+	}
+
+	#[test]
+	fn test_check_lock_owner() {
+		let m = matter(Some(1000), Some("/bin/code"));
+		assert_eq!(
+			check_lock_owner(&m, &running(1000, "/bin/code")),
+			LockOwner::Live
+		);
+		assert_eq!(
+			check_lock_owner(&m, &running(1001, "/bin/code")),
+			LockOwner::Live
+		);
+		assert_eq!(
+			check_lock_owner(&m, &FakeProcesses(None)),
+			LockOwner::Exited
+		);
+		assert!(matches!(
+			check_lock_owner(&m, &running(5000, "/bin/code")),
+			LockOwner::Replaced(_)
+		));
+		assert!(matches!(
+			check_lock_owner(&m, &running(1000, "/usr/sbin/sshd")),
+			LockOwner::Replaced(_)
+		));
+
+		// lock files from older versions only have the PID to go on
+		assert_eq!(
+			check_lock_owner(&matter(None, None), &running(5000, "/usr/sbin/sshd")),
+			LockOwner::Live
+		);
+	}
+
+	#[test]
+	fn test_reads_old_lock_format() {
+		#[derive(Serialize)]
+		struct OldLockFileMatter {
+			socket_path: String,
+			pid: u32,
+		}
+
+		let mut file = tempfile::tempfile().unwrap();
+		file.write_all(&[0; PREFIX_LOCKED_BYTES]).unwrap();
+		rmp_serde::encode::write(
+			&mut file,
+			&OldLockFileMatter {
+				socket_path: "sock".to_string(),
+				pid: 42,
+			},
+		)
+		.unwrap();
+
+		let m = read_lock_matter(&mut file).unwrap();
+		assert_eq!((m.socket_path.as_str(), m.pid), ("sock", 42));
+		assert_eq!((m.start_time, m.exe), (None, None));
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_takes_over_lock_with_reused_pid() {
+		let dir = tempfile::tempdir().expect("expected to make temp dir");
+		let lockfile = dir.path().join("lock");
+
+		// a lock that outlived its owner, such as through a child that inherited it
+		let _held = hold_lock(&lockfile, &matter(Some(1000), Some("/bin/code")));
+
+		// the PID now belongs to an unrelated process
+		let s = acquire_singleton_checked(
+			&log::Logger::test(),
+			&lockfile,
+			None,
+			&running(9000, "/usr/sbin/sshd"),
+		)
+		.await
+		.expect("expected to acquire");
+		assert!(matches!(s, SingletonConnection::Singleton(_)));
+
+		let mut file = File::open(&lockfile).unwrap();
+		let m = read_lock_matter(&mut file).unwrap();
+		assert_eq!(m.pid, std::process::id());
+		assert!(m.start_time.is_some());
+	}
+
+	fn hold_lock(lockfile: &Path, matter: &LockFileMatter) -> FileLock {
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(lockfile)
+			.unwrap();
+		let mut held = match FileLock::acquire(file).unwrap() {
+			Lock::Acquired(l) => l,
+			Lock::AlreadyLocked(_) => panic!("expected to lock"),
+		};
+		let mut vec = vec![0; PREFIX_LOCKED_BYTES];
+		rmp_serde::encode::write(&mut vec, matter).unwrap();
+		held.file_mut().write_all(&vec).unwrap();
+		held
+	}
+
+	/// A process that holds the lock until it's terminated.
+	struct KillableProcess {
+		lock: std::sync::Mutex<Option<FileLock>>,
+		terminated: std::sync::Mutex<Vec<u32>>,
+	}
+
+	impl ProcessInfo for KillableProcess {
+		fn identity(&self, _pid: u32) -> Option<ProcessIdentity> {
+			self.lock.lock().unwrap().as_ref().map(|_| ProcessIdentity {
+				start_time: 1000,
+				exe: Some(PathBuf::from("/bin/code")),
+			})
+		}
+
+		fn terminate(&self, pid: u32) -> bool {
+			self.terminated.lock().unwrap().push(pid);
+			self.lock.lock().unwrap().take().is_some()
+		}
+	}
+
+	#[tokio::test]
+	async fn test_kill_graceful() {
+		let dir = tempfile::tempdir().expect("expected to make temp dir");
+		let lockfile = dir.path().join("lock");
+		let held = hold_lock(&lockfile, &matter(Some(1000), Some("/bin/code")));
+
+		// the process releases its lock once asked to shut down
+		let steps = kill_singleton_checked(
+			&log::Logger::test(),
+			&lockfile,
+			Some(async move {
+				drop(held);
+				Ok(())
+			}),
+			Duration::from_secs(5),
+			&FakeProcesses(None),
+		)
+		.await
+		.unwrap();
+		assert_eq!(steps, vec![KillStep::ShutDownGracefully]);
+	}
+
+	#[tokio::test]
+	async fn test_kill_terminates_after_timeout() {
+		let dir = tempfile::tempdir().expect("expected to make temp dir");
+		let lockfile = dir.path().join("lock");
+		let process = KillableProcess {
+			lock: std::sync::Mutex::new(Some(hold_lock(
+				&lockfile,
+				&matter(Some(1000), Some("/bin/code")),
+			))),
+			terminated: std::sync::Mutex::new(vec![]),
+		};
+
+		let steps = kill_singleton_checked(
+			&log::Logger::test(),
+			&lockfile,
+			Some(std::future::pending::<Result<(), CodeError>>()),
+			Duration::from_millis(50),
+			&process,
+		)
+		.await
+		.unwrap();
+
+		assert!(matches!(steps[0], KillStep::GracefulShutdownFailed(_)));
+		assert_eq!(
+			steps[1..],
+			[
+				KillStep::Terminated(42),
+				KillStep::RemovedFile(lockfile.clone())
+			]
+		);
+		assert_eq!(*process.terminated.lock().unwrap(), vec![42]);
+		assert!(!lockfile.exists());
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_kill_cleans_up_stale_lock() {
+		let dir = tempfile::tempdir().expect("expected to make temp dir");
+		let lockfile = dir.path().join("lock");
+		let socket_dir = dir.path().join("sockets");
+		std::fs::create_dir(&socket_dir).unwrap();
+		let socket = socket_dir.join(socket_file_name_for(&lockfile));
+		std::fs::write(&socket, "").unwrap();
+		let _held = hold_lock(
+			&lockfile,
+			&LockFileMatter {
+				socket_path: socket.to_string_lossy().to_string(),
+				..matter(Some(1000), Some("/bin/code"))
+			},
+		);
+
+		// the PID was reused, so the unrelated process is left alone
+		let steps = kill_singleton_checked(
+			&log::Logger::test(),
+			&lockfile,
+			None::<std::future::Ready<Result<(), CodeError>>>,
+			Duration::from_secs(5),
+			&running(9000, "/usr/sbin/sshd"),
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(
+			steps,
+			vec![
+				KillStep::OwnerNotRunning(42),
+				KillStep::RemovedFile(lockfile.clone()),
+				KillStep::RemovedFile(socket.clone()),
+			]
+		);
+		assert!(!socket.exists());
+	}
+
+	#[tokio::test]
+	async fn test_kill_nothing_running() {
+		let dir = tempfile::tempdir().expect("expected to make temp dir");
+		let steps = kill_singleton_checked(
+			&log::Logger::test(),
+			&dir.path().join("lock"),
+			Some(async { Err(CodeError::NoRunningTunnel) }),
+			Duration::from_secs(5),
+			&FakeProcesses(None),
+		)
+		.await
+		.unwrap();
+		assert!(steps.is_empty());
+	}
 }
@@ -165,6 +165,11 @@ impl LauncherPaths {
 	}
 
 	pub fn new(root: Option<String>) -> Result<LauncherPaths, AnyError> {
+		Self::new_for_path(Self::resolve_root(root)?)
+	}
+
+	/// Gets the data directory for the given root, without creating it.
+	pub fn resolve_root(root: Option<String>) -> Result<PathBuf, AnyError> {
 		let root = root.unwrap_or_else(|| format!("~/{}/cli", DEFAULT_DATA_PARENT_DIR));
 		let mut replaced = root.to_owned();
 		for token in HOME_DIR_ALTS {
@@ -177,7 +182,7 @@ impl LauncherPaths {
 			}
 		}
 
-		Self::new_for_path(PathBuf::from(replaced))
+		Ok(PathBuf::from(replaced))
 	}
 
 	fn new_for_path(root: PathBuf) -> Result<LauncherPaths, AnyError> {
@@ -194,6 +199,12 @@ impl LauncherPaths {
 		let _ = std::fs::remove_dir_all(root.join("server-insiders"));
 		let _ = std::fs::remove_dir_all(root.join("server-stable"));
 
+		Self::inspect(root)
+	}
+
+	/// Gets the paths in a data directory without creating or cleaning it up,
+	/// such as to look at another directory than the one in use.
+	pub fn inspect(root: PathBuf) -> LauncherPaths {
 		LauncherPaths {
 			server_cache: DownloadCache::new(root.join("servers")),
 			cli_cache: DownloadCache::new(root.join("cli")),
@@ -222,6 +233,21 @@ impl LauncherPaths {
 		))
 	}
 
+	/// Log of the peers locked out after failing auth challenges
+	pub fn auth_audit_log(&self) -> PathBuf {
+		self.root.join("auth-audit.log")
+	}
+
+	/// Log of the commands clients ran with remote exec
+	pub fn remote_exec_audit_log(&self) -> PathBuf {
+		self.root.join("remote-exec-audit.log")
+	}
+
+	/// Last result of looking for a tunnel on the other side of WSL
+	pub fn interop_probe_cache(&self) -> PathBuf {
+		self.root.join("interop-probe.json")
+	}
+
 	/// Suggested path for tunnel service logs, when using file logs
 	pub fn service_log_file(&self) -> PathBuf {
 		self.root.join("tunnel-service.log")
@@ -3,12 +3,15 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+pub mod challenge_limiter;
 pub mod code_server;
 pub mod dev_tunnels;
 pub mod legal;
 pub mod local_forwarding;
 pub mod paths;
+pub mod port_watcher;
 pub mod protocol;
+pub mod remote_exec;
 pub mod shutdown_signal;
 pub mod singleton_client;
 pub mod singleton_server;
@@ -22,9 +25,11 @@ mod nosleep_linux;
 mod nosleep_macos;
 #[cfg(target_os = "windows")]
 mod nosleep_windows;
+mod ping;
 mod port_forwarder;
 mod server_bridge;
 mod server_multiplexer;
+mod server_watchdog;
 mod service;
 #[cfg(target_os = "linux")]
 mod service_linux;
@@ -33,10 +38,26 @@ mod service_macos;
 #[cfg(target_os = "windows")]
 mod service_windows;
 mod socket_signal;
+mod tunnel_supervisor;
 mod wsl_detect;
 
-pub use control_server::{serve, serve_stream, AuthRequired, Next, ServeStreamParams};
+pub use control_server::{
+	serve, serve_stream, AuthRequired, Next, ServeStreamParams, CONNECTION_ACTIVITY,
+	CONTROL_RPC_STATS, SERVER_USAGE,
+};
 pub use nosleep::SleepInhibitor;
+pub use ping::{measure_ping, ControlConnection, PingSummary};
+pub use server_watchdog::RestartBackoff;
 pub use service::{
-	create_service_manager, ServiceContainer, ServiceManager, SERVICE_LOG_FILE_NAME,
+	create_service_manager, export_logs, prepare_service_install, restart_service, CollectedLogs,
+	LogExportSummary, ServiceContainer, ServiceLogOptions, ServiceManager, ServiceScope,
+	ServiceStatus, SERVICE_LOG_FILE_NAME,
+};
+pub use tunnel_supervisor::{
+	install_panic_logger, supervise_tunnel, FailureHistory, FailureSummary,
+};
+#[cfg(target_os = "linux")]
+pub use wsl_detect::is_wsl;
+pub use wsl_detect::{
+	warn_on_interop_tunnel, CachedInteropProbe, CommandInteropProbe, InteropProbe, InteropTunnel,
 };
@@ -0,0 +1,464 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::HashMap,
+	fs::OpenOptions,
+	io::Write,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	auth::{Clock, SystemClock},
+	constants::{
+		DEFAULT_AUTH_LOCKOUT_AFTER, DEFAULT_AUTH_LOCKOUT_DURATION, DEFAULT_AUTH_RETRY_DELAY,
+		MAX_AUTH_RETRY_DELAY,
+	},
+	log,
+	util::errors::CodeError,
+};
+
+/// How failed auth challenges of a peer are penalized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChallengeLimits {
+	/// Time a peer must wait after its first failure. It doubles with each
+	/// consecutive failure, up to the maximum.
+	pub retry_delay: Duration,
+	pub max_retry_delay: Duration,
+	/// Number of consecutive failures after which the peer is locked out.
+	pub lockout_after: u32,
+	/// How long a lockout lasts. A streak of failures is also forgotten once
+	/// the peer hasn't failed for this long.
+	pub lockout_duration: Duration,
+}
+
+impl Default for ChallengeLimits {
+	fn default() -> Self {
+		Self {
+			retry_delay: DEFAULT_AUTH_RETRY_DELAY,
+			max_retry_delay: MAX_AUTH_RETRY_DELAY,
+			lockout_after: DEFAULT_AUTH_LOCKOUT_AFTER,
+			lockout_duration: DEFAULT_AUTH_LOCKOUT_DURATION,
+		}
+	}
+}
+
+impl ChallengeLimits {
+	/// Gets the time a peer must wait after the given number of consecutive
+	/// failures.
+	pub fn delay_after(&self, failures: u32) -> Duration {
+		if failures == 0 {
+			return Duration::ZERO;
+		}
+
+		self.retry_delay
+			.checked_mul(1 << (failures - 1).min(31))
+			.unwrap_or(self.max_retry_delay)
+			.min(self.max_retry_delay)
+	}
+}
+
+/// Totals across all peers since the limiter was created.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChallengeStats {
+	pub failures: u64,
+	pub lockouts: u64,
+}
+
+/// Whether a peer was locked out or unlocked, as written to the audit log.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LockoutEvent {
+	LockedOut,
+	/// The peer tried again after its lockout ended.
+	Unlocked,
+}
+
+/// A line in the auth audit log.
+#[derive(Serialize, Debug)]
+pub struct LockoutAuditEntry<'a> {
+	pub time: DateTime<Utc>,
+	pub peer: &'a str,
+	pub event: LockoutEvent,
+	pub failures: u32,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct PeerFailures {
+	consecutive: u32,
+	last_failure: Option<DateTime<Utc>>,
+	retry_at: Option<DateTime<Utc>>,
+	locked_until: Option<DateTime<Utc>>,
+}
+
+/// Tracks failed auth challenges of each peer, making peers wait longer
+/// after each consecutive failure and locking them out after too many.
+pub struct ChallengeLimiter {
+	log: log::Logger,
+	limits: ChallengeLimits,
+	clock: Arc<dyn Clock>,
+	/// Appended to with one JSON object per line, if set.
+	audit_log: Option<PathBuf>,
+	peers: Mutex<HashMap<String, PeerFailures>>,
+	failures: AtomicU64,
+	lockouts: AtomicU64,
+}
+
+impl ChallengeLimiter {
+	pub fn new(log: log::Logger, limits: ChallengeLimits, audit_log: Option<PathBuf>) -> Self {
+		Self::with_clock(log, limits, audit_log, Arc::new(SystemClock))
+	}
+
+	pub fn with_clock(
+		log: log::Logger,
+		limits: ChallengeLimits,
+		audit_log: Option<PathBuf>,
+		clock: Arc<dyn Clock>,
+	) -> Self {
+		Self {
+			log,
+			limits,
+			clock,
+			audit_log,
+			peers: Mutex::default(),
+			failures: AtomicU64::new(0),
+			lockouts: AtomicU64::new(0),
+		}
+	}
+
+	/// Gets a handle for the connection of a peer.
+	pub fn for_peer(self: &Arc<Self>, peer: impl Into<String>) -> PeerChallengeLimiter {
+		PeerChallengeLimiter {
+			limiter: self.clone(),
+			peer: peer.into(),
+		}
+	}
+
+	pub fn stats(&self) -> ChallengeStats {
+		ChallengeStats {
+			failures: self.failures.load(Ordering::SeqCst),
+			lockouts: self.lockouts.load(Ordering::SeqCst),
+		}
+	}
+
+	/// Returns an error if the peer is locked out or must still wait before
+	/// its next attempt.
+	pub fn check(&self, peer: &str) -> Result<(), CodeError> {
+		let now = self.clock.now();
+		let mut peers = self.peers.lock().unwrap();
+		let failures = match peers.get_mut(peer) {
+			Some(f) => f,
+			None => return Ok(()),
+		};
+
+		if let Some(until) = failures.locked_until {
+			if until > now {
+				return Err(CodeError::AuthChallengeLockedOut(
+					(until - now).num_seconds().max(1) as u64,
+				));
+			}
+
+			let consecutive = failures.consecutive;
+			peers.remove(peer);
+			drop(peers);
+			self.audit(peer, LockoutEvent::Unlocked, consecutive, None);
+			return Ok(());
+		}
+
+		match failures.retry_at {
+			Some(at) if at > now => Err(CodeError::AuthChallengeThrottled(
+				(at - now).num_milliseconds().max(1) as u64,
+			)),
+			_ => Ok(()),
+		}
+	}
+
+	/// Records a failed attempt of the peer, returning the error to send it.
+	pub fn record_failure(&self, peer: &str, error: CodeError) -> CodeError {
+		let now = self.clock.now();
+		let forget_before = now - chrono_duration(self.limits.lockout_duration);
+		self.failures.fetch_add(1, Ordering::SeqCst);
+
+		let mut peers = self.peers.lock().unwrap();
+		peers.retain(|p, f| {
+			p == peer
+				|| f.locked_until.map(|u| u > now).unwrap_or(false)
+				|| f.last_failure.map(|t| t > forget_before).unwrap_or(false)
+		});
+
+		let failures = peers.entry(peer.to_string()).or_default();
+		if failures.last_failure.map(|t| t <= forget_before) == Some(true) {
+			*failures = PeerFailures::default();
+		}
+
+		failures.consecutive += 1;
+		failures.last_failure = Some(now);
+		let consecutive = failures.consecutive;
+		if consecutive < self.limits.lockout_after {
+			failures.retry_at = Some(now + chrono_duration(self.limits.delay_after(consecutive)));
+			return error;
+		}
+
+		let until = now + chrono_duration(self.limits.lockout_duration);
+		failures.locked_until = Some(until);
+		drop(peers);
+
+		let lockouts = self.lockouts.fetch_add(1, Ordering::SeqCst) + 1;
+		warning!(
+			self.log,
+			"Locked out {} until {} after {} failed auth attempts ({} lockouts so far)",
+			peer,
+			until.to_rfc3339(),
+			consecutive,
+			lockouts
+		);
+		self.audit(peer, LockoutEvent::LockedOut, consecutive, Some(until));
+		CodeError::AuthChallengeLockedOut(self.limits.lockout_duration.as_secs())
+	}
+
+	/// Forgets the failures of a peer that authenticated.
+	pub fn record_success(&self, peer: &str) {
+		self.peers.lock().unwrap().remove(peer);
+	}
+
+	fn audit(&self, peer: &str, event: LockoutEvent, failures: u32, until: Option<DateTime<Utc>>) {
+		let path = match &self.audit_log {
+			Some(p) => p,
+			None => return,
+		};
+
+		let entry = LockoutAuditEntry {
+			time: self.clock.now(),
+			peer,
+			event,
+			failures,
+			until,
+		};
+		let mut line = serde_json::to_vec(&entry).expect("expected to serialize");
+		line.push(b'\n');
+		let written = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.and_then(|mut f| f.write_all(&line));
+		if let Err(e) = written {
+			warning!(
+				self.log,
+				"Could not write to the auth audit log {}: {}",
+				path.display(),
+				e
+			);
+		}
+	}
+}
+
+/// A limiter bound to the peer of a connection.
+#[derive(Clone)]
+pub struct PeerChallengeLimiter {
+	limiter: Arc<ChallengeLimiter>,
+	peer: String,
+}
+
+impl PeerChallengeLimiter {
+	pub fn check(&self) -> Result<(), CodeError> {
+		self.limiter.check(&self.peer)
+	}
+
+	pub fn record_failure(&self, error: CodeError) -> CodeError {
+		self.limiter.record_failure(&self.peer, error)
+	}
+
+	pub fn record_success(&self) {
+		self.limiter.record_success(&self.peer)
+	}
+}
+
+/// Converts the duration, capping it at a year so that times stay in range.
+fn chrono_duration(d: Duration) -> chrono::Duration {
+	let year = chrono::Duration::days(365);
+	chrono::Duration::from_std(d).unwrap_or(year).min(year)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_trait::async_trait;
+	use chrono::TimeZone;
+
+	struct FakeClock(Mutex<DateTime<Utc>>);
+
+	impl FakeClock {
+		fn advance(&self, d: Duration) {
+			*self.0.lock().unwrap() += chrono_duration(d);
+		}
+	}
+
+	#[async_trait]
+	impl Clock for FakeClock {
+		fn now(&self) -> DateTime<Utc> {
+			*self.0.lock().unwrap()
+		}
+
+		async fn sleep(&self, duration: Duration) {
+			self.advance(duration);
+		}
+	}
+
+	fn limits() -> ChallengeLimits {
+		ChallengeLimits {
+			retry_delay: Duration::from_secs(1),
+			max_retry_delay: Duration::from_secs(8),
+			lockout_after: 6,
+			lockout_duration: Duration::from_secs(300),
+		}
+	}
+
+	fn limiter(audit_log: Option<PathBuf>) -> (Arc<ChallengeLimiter>, Arc<FakeClock>) {
+		let clock = Arc::new(FakeClock(Mutex::new(
+			Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+		)));
+		let limiter =
+			ChallengeLimiter::with_clock(log::Logger::test(), limits(), audit_log, clock.clone());
+		(Arc::new(limiter), clock)
+	}
+
+	fn fail(limiter: &ChallengeLimiter, peer: &str) -> CodeError {
+		limiter.record_failure(peer, CodeError::AuthChallengeBadToken)
+	}
+
+	#[test]
+	fn test_delay_schedule() {
+		let l = limits();
+		let delays: Vec<_> = (0..7).map(|n| l.delay_after(n).as_secs()).collect();
+		assert_eq!(delays, vec![0, 1, 2, 4, 8, 8, 8]);
+		assert_eq!(l.delay_after(u32::MAX), Duration::from_secs(8));
+	}
+
+	#[test]
+	fn test_throttles_after_failures() {
+		let (limiter, clock) = limiter(None);
+
+		for expected in [1000, 2000, 4000, 8000] {
+			assert!(limiter.check("a").is_ok());
+			assert!(matches!(
+				fail(&limiter, "a"),
+				CodeError::AuthChallengeBadToken
+			));
+			assert!(matches!(
+				limiter.check("a"),
+				Err(CodeError::AuthChallengeThrottled(ms)) if ms == expected
+			));
+
+			clock.advance(Duration::from_millis(expected - 1));
+			assert!(limiter.check("a").is_err());
+			clock.advance(Duration::from_millis(1));
+		}
+
+		// other peers aren't affected
+		assert!(limiter.check("b").is_ok());
+	}
+
+	#[test]
+	fn test_locks_out_and_unlocks() {
+		let dir = tempfile::tempdir().unwrap();
+		let audit_log = dir.path().join("audit.log");
+		let (limiter, clock) = limiter(Some(audit_log.clone()));
+
+		for _ in 0..5 {
+			fail(&limiter, "a");
+			clock.advance(Duration::from_secs(8));
+		}
+		assert!(matches!(
+			fail(&limiter, "a"),
+			CodeError::AuthChallengeLockedOut(300)
+		));
+
+		clock.advance(Duration::from_secs(100));
+		assert!(matches!(
+			limiter.check("a"),
+			Err(CodeError::AuthChallengeLockedOut(200))
+		));
+		clock.advance(Duration::from_secs(199));
+		assert!(limiter.check("a").is_err());
+		clock.advance(Duration::from_secs(1));
+		assert!(limiter.check("a").is_ok());
+
+		// the streak was reset with the unlock
+		fail(&limiter, "a");
+		assert!(matches!(
+			limiter.check("a"),
+			Err(CodeError::AuthChallengeThrottled(1000))
+		));
+
+		assert_eq!(
+			limiter.stats(),
+			ChallengeStats {
+				failures: 7,
+				lockouts: 1
+			}
+		);
+
+		let events: Vec<serde_json::Value> = std::fs::read_to_string(&audit_log)
+			.unwrap()
+			.lines()
+			.map(|l| serde_json::from_str(l).unwrap())
+			.collect();
+		assert_eq!(events.len(), 2);
+		assert_eq!(events[0]["event"], "locked_out");
+		assert_eq!(events[0]["peer"], "a");
+		assert_eq!(events[0]["failures"], 6);
+		assert_eq!(events[0]["until"], "2024-01-01T00:05:40Z");
+		assert_eq!(events[1]["event"], "unlocked");
+		assert!(events[1].get("until").is_none());
+	}
+
+	#[test]
+	fn test_success_resets_failures() {
+		let (limiter, clock) = limiter(None);
+		let peer = limiter.for_peer("a");
+
+		for _ in 0..5 {
+			peer.record_failure(CodeError::AuthChallengeBadToken);
+			clock.advance(Duration::from_secs(8));
+		}
+		peer.record_success();
+
+		peer.record_failure(CodeError::AuthChallengeBadToken);
+		assert!(matches!(
+			peer.check(),
+			Err(CodeError::AuthChallengeThrottled(1000))
+		));
+	}
+
+	#[test]
+	fn test_forgets_old_failures() {
+		let (limiter, clock) = limiter(None);
+
+		for _ in 0..5 {
+			fail(&limiter, "a");
+			clock.advance(Duration::from_secs(8));
+		}
+		clock.advance(Duration::from_secs(300));
+
+		assert!(matches!(
+			fail(&limiter, "a"),
+			CodeError::AuthChallengeBadToken
+		));
+		assert!(matches!(
+			limiter.check("a"),
+			Err(CodeError::AuthChallengeThrottled(1000))
+		));
+	}
+}
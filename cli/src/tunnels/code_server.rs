@@ -3,9 +3,12 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use super::paths::{InstalledServer, ServerPaths};
+use super::port_watcher::AutoForwardPolicy;
+use super::remote_exec::RemoteExecPolicy;
 use crate::async_pipe::get_socket_name;
 use crate::constants::{
 	APPLICATION_NAME, EDITOR_WEB_URL, QUALITYLESS_PRODUCT_NAME, QUALITYLESS_SERVER_NAME,
+	SERVER_EXTENSION_INSTALL_TIMEOUT,
 };
 use crate::download_cache::DownloadCache;
 use crate::options::{Quality, TelemetryLevel};
@@ -15,14 +18,16 @@ use crate::update_service::{
 	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService,
 };
 use crate::util::command::{
-	capture_command, capture_command_and_check_status, check_output_status, kill_tree,
-	new_script_command,
+	capture_command, kill_tree, new_script_command, run_and_check, run_command, RunOptions,
 };
 use crate::util::errors::{wrap, AnyError, CodeError, ExtensionInstallFailed, WrappedError};
 use crate::util::http::{self, BoxedHttp};
 use crate::util::io::SilentCopyProgress;
 use crate::util::machine::process_exists;
-use crate::util::prereqs::skip_requirements_check;
+use crate::util::preflight::{self, PreflightNeeds, SystemProbe};
+use crate::util::prereqs::{skip_requirements_check, Arch};
+use crate::util::progress;
+use crate::util::ring_buffer::RingBuffer;
 use crate::{debug, info, log, spanf, trace, warning};
 use lazy_static::lazy_static;
 use opentelemetry::KeyValue;
@@ -32,12 +37,13 @@ use std::fs;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::fs::remove_file;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::sync::oneshot::Receiver;
+use tokio::sync::{mpsc, watch};
 use tokio::time::{interval, timeout};
 
 lazy_static! {
@@ -46,6 +52,14 @@ lazy_static! {
 	static ref WEB_UI_RE: Regex = Regex::new(r"Web UI available at (.+)").unwrap();
 }
 
+/// Target of the lines a spawned server writes, so that they can be told
+/// apart from the logs about it.
+pub const SERVER_OUTPUT_TARGET: &str = concat!(module_path!(), "::output");
+
+/// Number of stderr lines from a spawned server that are kept to report if
+/// it exits unexpectedly.
+const STDERR_TAIL_LINES: usize = 20;
+
 #[derive(Clone, Debug, Default)]
 pub struct CodeServerArgs {
 	pub host: Option<String>,
@@ -73,6 +87,16 @@ pub struct CodeServerArgs {
 	pub connection_token: Option<String>,
 	pub connection_token_file: Option<String>,
 	pub without_connection_token: bool,
+	// supervision, not passed to the server
+	pub restart_limit: u32,
+	pub skip_requirements_check: bool,
+	pub arch: Option<Arch>,
+	pub assume_platform: Option<Platform>,
+	pub ignore_preflight: bool,
+	/// Commands clients may run with `exec`, if remote exec is enabled.
+	pub remote_exec: Option<RemoteExecPolicy>,
+	/// How ports the server listens on are announced, if they're watched.
+	pub auto_forward: Option<AutoForwardPolicy>,
 }
 
 impl CodeServerArgs {
@@ -171,6 +195,7 @@ pub struct ServerParamsRaw {
 }
 
 /// Server params that can be used to start a VS Code server.
+#[derive(Clone)]
 pub struct ResolvedServerParams {
 	pub release: Release,
 	pub code_server_args: CodeServerArgs,
@@ -258,16 +283,82 @@ pub enum AnyCodeServer {
 
 pub enum CodeServerOrigin {
 	/// A new code server, that opens the barrier when it exits.
-	New(Box<Child>),
+	New(SpawnedServer),
 	/// An existing code server with a PID.
 	Existing(u32),
 }
 
+/// Describes how a spawned code server exited.
+#[derive(Clone, Debug)]
+pub struct ServerExit {
+	/// Exit code of the process, or None if it was terminated by a signal.
+	pub code: Option<i32>,
+	/// Whether the exit was requested through `CodeServerOrigin::kill`.
+	pub deliberate: bool,
+	/// The last lines the server wrote to stderr.
+	pub stderr_tail: Vec<String>,
+}
+
+impl ServerExit {
+	/// Gets whether the server stopped on its own accord. Servers exit cleanly
+	/// when they auto-shutdown after their last client disconnects.
+	pub fn is_expected(&self) -> bool {
+		self.deliberate || self.code == Some(0)
+	}
+}
+
+/// Handle to a server process spawned by the CLI. The process is owned by a
+/// task that reports its exit.
+pub struct SpawnedServer {
+	pid: Option<u32>,
+	kill_tx: mpsc::Sender<()>,
+	exit_rx: watch::Receiver<Option<ServerExit>>,
+}
+
+impl SpawnedServer {
+	async fn wait_for_exit(&self) -> Option<ServerExit> {
+		wait_for_server_exit(self.exit_rx.clone()).await
+	}
+}
+
+/// Waits until the server reports its exit. Returns None if the task watching
+/// the process went away without reporting.
+pub async fn wait_for_server_exit(
+	mut rx: watch::Receiver<Option<ServerExit>>,
+) -> Option<ServerExit> {
+	loop {
+		if let Some(exit) = &*rx.borrow() {
+			return Some(exit.clone());
+		}
+
+		if rx.changed().await.is_err() {
+			return None;
+		}
+	}
+}
+
 impl CodeServerOrigin {
+	/// Gets a receiver that's notified when the server exits, if it was
+	/// spawned by this process.
+	pub fn exited(&self) -> Option<watch::Receiver<Option<ServerExit>>> {
+		match self {
+			CodeServerOrigin::New(s) => Some(s.exit_rx.clone()),
+			CodeServerOrigin::Existing(_) => None,
+		}
+	}
+
+	/// Gets the PID of the server process, if known.
+	pub fn pid(&self) -> Option<u32> {
+		match self {
+			CodeServerOrigin::New(s) => s.pid,
+			CodeServerOrigin::Existing(pid) => Some(*pid),
+		}
+	}
+
 	pub async fn wait_for_exit(&mut self) {
 		match self {
-			CodeServerOrigin::New(child) => {
-				child.wait().await.ok();
+			CodeServerOrigin::New(s) => {
+				s.wait_for_exit().await;
 			}
 			CodeServerOrigin::Existing(pid) => {
 				let mut interval = interval(Duration::from_secs(30));
@@ -280,8 +371,9 @@ impl CodeServerOrigin {
 
 	pub async fn kill(&mut self) {
 		match self {
-			CodeServerOrigin::New(child) => {
-				child.kill().await.ok();
+			CodeServerOrigin::New(s) => {
+				s.kill_tx.send(()).await.ok();
+				s.wait_for_exit().await;
 			}
 			CodeServerOrigin::Existing(pid) => {
 				kill_tree(*pid).await.ok();
@@ -356,7 +448,7 @@ impl<'a> ServerBuilder<'a> {
 			self.server_paths.pidfile.display()
 		);
 
-		let pid = match self.server_paths.get_running_pid() {
+		let pid = match self.server_paths.get_running_pid(self.logger) {
 			Some(pid) => pid,
 			None => return Ok(None),
 		};
@@ -429,6 +521,15 @@ impl<'a> ServerBuilder<'a> {
 					.await?;
 				let archive_path = tmpdir.path().join(response.url_path_basename().unwrap());
 
+				if self.server_params.code_server_args.ignore_preflight {
+					info!(self.logger, "Skipping disk space and memory checks");
+				} else {
+					let needs = PreflightNeeds::for_download(tmpdir.path(), &target_dir, &response);
+					for w in preflight::check(&SystemProbe, &needs)? {
+						warning!(self.logger, "{}", w);
+					}
+				}
+
 				info!(
 					self.logger,
 					"Downloading {} server -> {}",
@@ -438,7 +539,7 @@ impl<'a> ServerBuilder<'a> {
 
 				http::download_into_file(
 					&archive_path,
-					self.logger.get_download_logger("server download progress:"),
+					progress::reporter(self.logger, "Downloading server"),
 					response,
 				)
 				.await?;
@@ -447,15 +548,18 @@ impl<'a> ServerBuilder<'a> {
 				unzip_downloaded_release(
 					&archive_path,
 					&server_dir,
-					self.logger.get_download_logger("server inflate progress:"),
-				)?;
+					progress::reporter(self.logger, "Extracting server").counting("files"),
+				)
+				.await?;
 
-				if !skip_requirements_check().await {
-					let output = capture_command_and_check_status(
+				if !self.server_params.code_server_args.skip_requirements_check
+					&& !skip_requirements_check().await
+				{
+					let output = run_and_check(
 						server_dir
 							.join("bin")
 							.join(self.server_params.release.quality.server_entrypoint()),
-						&["--version"],
+						["--version"],
 					)
 					.await
 					.map_err(|e| wrap(e, "error checking server integrity"))?;
@@ -491,7 +595,11 @@ impl<'a> ServerBuilder<'a> {
 
 		let child = self.spawn_server_process(cmd).await?;
 		let log_file = self.get_logfile()?;
-		let plog = self.logger.prefixed(&log::new_code_server_prefix());
+		let plog = self
+			.logger
+			.prefixed(&log::new_code_server_prefix())
+			.with_field("commit", &self.server_params.release.commit)
+			.with_field("port", port);
 
 		let (mut origin, listen_rx) =
 			monitor_server::<PortMatcher, u16>(child, Some(log_file), plog, false);
@@ -520,21 +628,13 @@ impl<'a> ServerBuilder<'a> {
 	/// Runs the command that just installs extensions and exits.
 	pub async fn install_extensions(&self) -> Result<(), AnyError> {
 		// cmd already has --install-extensions from base
-		let mut cmd = self.get_base_command();
-		let cmd_str = || {
-			self.server_params
-				.code_server_args
-				.command_arguments()
-				.join(" ")
-		};
-
-		let r = cmd.output().await.map_err(|e| CodeError::CommandFailed {
-			command: cmd_str(),
-			code: -1,
-			output: e.to_string(),
-		})?;
-
-		check_output_status(r, cmd_str)?;
+		let cmd = self.get_base_command();
+		run_command(
+			cmd,
+			&RunOptions::with_timeout(SERVER_EXTENSION_INSTALL_TIMEOUT),
+		)
+		.await?
+		.check()?;
 
 		Ok(())
 	}
@@ -565,7 +665,10 @@ impl<'a> ServerBuilder<'a> {
 
 		let child = self.spawn_server_process(cmd).await?;
 		let log_file = self.get_logfile()?;
-		let plog = self.logger.prefixed(&log::new_code_server_prefix());
+		let plog = self
+			.logger
+			.prefixed(&log::new_code_server_prefix())
+			.with_field("commit", &self.server_params.release.commit);
 
 		let (mut origin, listen_rx) =
 			monitor_server::<SocketMatcher, PathBuf>(child, Some(log_file), plog, false);
@@ -644,7 +747,7 @@ impl<'a> ServerBuilder<'a> {
 	}
 }
 
-fn monitor_server<M, R>(
+pub(crate) fn monitor_server<M, R>(
 	mut child: Child,
 	log_file: Option<File>,
 	plog: log::Logger,
@@ -654,6 +757,7 @@ where
 	M: ServerOutputMatcher<R>,
 	R: 'static + Send + std::fmt::Debug,
 {
+	let pid = child.id();
 	let stdout = child
 		.stdout
 		.take()
@@ -665,13 +769,18 @@ where
 		.expect("child did not have a handle to stdout");
 
 	let (listen_tx, listen_rx) = tokio::sync::oneshot::channel();
+	let stderr_tail = Arc::new(Mutex::new(RingBuffer::new(STDERR_TAIL_LINES)));
 
 	// Handle stderr and stdout in a separate task. Initially scan lines looking
 	// for the listening port. Afterwards, just scan and write out to the file.
-	tokio::spawn(async move {
+	let output_stderr_tail = stderr_tail.clone();
+	let output_task = tokio::spawn(async move {
 		let mut stdout_reader = BufReader::new(stdout).lines();
 		let mut stderr_reader = BufReader::new(stderr).lines();
-		let write_line = |line: &str| -> std::io::Result<()> {
+		let write_line = |line: &str, is_stderr: bool| -> std::io::Result<()> {
+			if is_stderr {
+				output_stderr_tail.lock().unwrap().push(line.to_string());
+			}
 			if let Some(mut f) = log_file.as_ref() {
 				f.write_all(line.as_bytes())?;
 				f.write_all(&[b'\n'])?;
@@ -679,15 +788,15 @@ where
 			if write_directly {
 				println!("{}", line);
 			} else {
-				trace!(plog, line);
+				plog.emit_from(SERVER_OUTPUT_TARGET, log::Level::Trace, line);
 			}
 			Ok(())
 		};
 
 		loop {
-			let line = tokio::select! {
-				l = stderr_reader.next_line() => l,
-				l = stdout_reader.next_line() => l,
+			let (line, is_stderr) = tokio::select! {
+				l = stderr_reader.next_line() => (l, true),
+				l = stdout_reader.next_line() => (l, false),
 			};
 
 			match line {
@@ -697,7 +806,7 @@ where
 				}
 				Ok(None) => break,
 				Ok(Some(l)) => {
-					write_line(&l).ok();
+					write_line(&l, is_stderr).ok();
 
 					if let Some(listen_on) = M::match_line(&l) {
 						trace!(plog, "parsed location: {:?}", listen_on);
@@ -709,9 +818,9 @@ where
 		}
 
 		loop {
-			let line = tokio::select! {
-				l = stderr_reader.next_line() => l,
-				l = stdout_reader.next_line() => l,
+			let (line, is_stderr) = tokio::select! {
+				l = stderr_reader.next_line() => (l, true),
+				l = stdout_reader.next_line() => (l, false),
 			};
 
 			match line {
@@ -721,13 +830,43 @@ where
 				}
 				Ok(None) => break,
 				Ok(Some(l)) => {
-					write_line(&l).ok();
+					write_line(&l, is_stderr).ok();
 				}
 			}
 		}
 	});
 
-	let origin = CodeServerOrigin::New(Box::new(child));
+	// Own the child in its own task, so that its exit can be observed by
+	// anyone holding the origin, and killing it can be told apart from a crash.
+	let (kill_tx, mut kill_rx) = mpsc::channel::<()>(1);
+	let (exit_tx, exit_rx) = watch::channel(None);
+	tokio::spawn(async move {
+		let (status, deliberate) = tokio::select! {
+			s = child.wait() => (s, false),
+			Some(_) = kill_rx.recv() => {
+				child.kill().await.ok();
+				(child.wait().await, true)
+			}
+		};
+
+		// give the output task a moment to drain the remaining stderr lines
+		timeout(Duration::from_secs(1), output_task).await.ok();
+
+		let stderr_tail = stderr_tail.lock().unwrap().iter().cloned().collect();
+		exit_tx
+			.send(Some(ServerExit {
+				code: status.ok().and_then(|s| s.code()),
+				deliberate,
+				stderr_tail,
+			}))
+			.ok();
+	});
+
+	let origin = CodeServerOrigin::New(SpawnedServer {
+		pid,
+		kill_tx,
+		exit_rx,
+	});
 	(origin, listen_rx)
 }
 
@@ -851,7 +990,7 @@ pub async fn download_cli_into_cache(
 			let name = response.url_path_basename().unwrap();
 			let archive_path = tmpdir.path().join(name);
 			http::download_into_file(&archive_path, SilentCopyProgress(), response).await?;
-			unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress())?;
+			unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress()).await?;
 			Ok(())
 		})
 		.await?;
@@ -3,14 +3,24 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use crate::async_pipe::get_socket_rw_stream;
-use crate::constants::{CONTROL_PORT, PRODUCT_NAME_LONG};
+use crate::constants::{CONTROL_MAX_IN_FLIGHT_REQUESTS, CONTROL_PORT, PRODUCT_NAME_LONG};
 use crate::log;
-use crate::msgpack_rpc::{new_msgpack_rpc, start_msgpack_rpc, MsgPackCodec, MsgPackSerializer};
+use crate::msgpack_rpc::{
+	new_msgpack_rpc, start_msgpack_rpc, FrameBuffer, MsgPackCodec, MsgPackSerializer,
+	READ_BUFFER_SIZE,
+};
 use crate::options::Quality;
-use crate::rpc::{MaybeSync, RpcBuilder, RpcCaller, RpcDispatcher};
+use crate::rpc::{
+	MaybeSync, RpcBuilder, RpcCaller, RpcDispatcher, RpcStats, METHOD_LIST_METHODS, METHOD_LOAD,
+	METHOD_METRICS,
+};
+use crate::rpc_trace::{ConnectionTrace, Format};
 use crate::self_update::SelfUpdate;
 use crate::state::LauncherPaths;
-use crate::tunnels::protocol::{HttpRequestParams, PortPrivacy, METHOD_CHALLENGE_ISSUE};
+use crate::tunnels::protocol::{
+	HttpRequestParams, PortPrivacy, PortProtocol, UninstallServerParams, METHOD_CHALLENGE_ISSUE,
+	METHOD_UNINSTALL_SERVER,
+};
 use crate::tunnels::socket_signal::CloseReason;
 use crate::update_service::{Platform, Release, TargetKind, UpdateService};
 use crate::util::command::new_tokio_command;
@@ -24,10 +34,12 @@ use crate::util::io::SilentCopyProgress;
 use crate::util::is_integrated_cli;
 use crate::util::machine::kill_pid;
 use crate::util::os::os_release;
-use crate::util::sync::{new_barrier, Barrier, BarrierOpener};
+use crate::util::sync::{new_barrier, ActivityTracker, Barrier, BarrierOpener};
 
+use async_trait::async_trait;
 use futures::stream::FuturesUnordered;
 use futures::FutureExt;
+use lazy_static::lazy_static;
 use opentelemetry::trace::SpanKind;
 use opentelemetry::KeyValue;
 use std::collections::HashMap;
@@ -42,32 +54,59 @@ use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, watch, Mutex};
 
 use super::challenge::{create_challenge, sign_challenge, verify_challenge};
+use super::challenge_limiter::{ChallengeLimiter, PeerChallengeLimiter};
 use super::code_server::{
-	download_cli_into_cache, AnyCodeServer, CodeServerArgs, ServerBuilder, ServerParamsRaw,
-	SocketCodeServer,
+	download_cli_into_cache, AnyCodeServer, CodeServerArgs, ResolvedServerParams, ServerBuilder,
+	ServerExit, ServerParamsRaw, SocketCodeServer,
 };
 use super::dev_tunnels::ActiveTunnel;
-use super::paths::prune_stopped_servers;
-use super::port_forwarder::{PortForwarding, PortForwardingProcessor};
+use super::paths::{
+	prune_stopped_servers, uninstall_server, CachedServerRegistry, ServerUsage, ServerUsageGuard,
+};
+use super::ping::handle_ping;
+use super::port_forwarder::{ForwardedBy, PortForwarding, PortForwardingProcessor};
+use super::port_watcher::{watch_ports, AutoForwardPolicy, PortWatchHost, SystemPortScanner};
+use super::protocol::singleton::DrainStatus;
 use super::protocol::{
 	AcquireCliParams, CallServerHttpParams, CallServerHttpResult, ChallengeIssueParams,
-	ChallengeIssueResponse, ChallengeVerifyParams, ClientRequestMethod, EmptyObject, ForwardParams,
-	ForwardResult, FsReadDirEntry, FsReadDirResponse, FsRenameRequest, FsSinglePathRequest,
-	FsStatResponse, GetEnvResponse, GetHostnameResponse, HttpBodyParams, HttpHeadersParams,
-	NetConnectRequest, ServeParams, ServerLog, ServerMessageParams, SpawnParams, SpawnResult,
-	SysKillRequest, SysKillResponse, ToClientRequest, UnforwardParams, UpdateParams, UpdateResult,
-	VersionResponse, METHOD_CHALLENGE_VERIFY,
+	ChallengeIssueResponse, ChallengeVerifyParams, ClientRequestMethod, EmptyObject, ExecParams,
+	ExecResult, ForwardParams, ForwardResult, FsReadDirEntry, FsReadDirResponse, FsRenameRequest,
+	FsSinglePathRequest, FsStatResponse, GetEnvResponse, GetHostnameResponse, HttpBodyParams,
+	HttpHeadersParams, NetConnectRequest, PingParams, PortClosedParams, PortDetectedParams,
+	ServeParams, ServerLog, ServerMessageParams, ServerRestartParams, ServerShutdownParams,
+	SpawnParams, SpawnResult, SysKillRequest, SysKillResponse, ToClientRequest, UnforwardParams,
+	UpdateParams, UpdateResult, VersionResponse, CAPABILITY_INTROSPECTION, CONTROL_PROTOCOL,
+	METHOD_CHALLENGE_VERIFY, METHOD_PING,
 };
+use super::remote_exec::{run_exec, ExecAuditLog, RemoteExecPolicy};
 use super::server_bridge::ServerBridge;
 use super::server_multiplexer::ServerMultiplexer;
-use super::shutdown_signal::ShutdownSignal;
+use super::server_watchdog::{supervise, RestartBackoff, SupervisedServer, WatchdogEvent};
+use super::shutdown_signal::{Drain, ShutdownSignal};
 use super::socket_signal::{
 	ClientMessageDecoder, ServerMessageDestination, ServerMessageSink, SocketSignal,
 };
 
+/// Peer that failed auth challenges of tunnel connections are counted
+/// against. The tunnel service already authorized each of them as the
+/// tunnel's owner, and they come through its relay without a client address.
+const TUNNEL_PEER: &str = "tunnel";
+
+lazy_static! {
+	/// Tracks connections to the control server, so that the tunnel can exit
+	/// once it's been idle for a while.
+	pub static ref CONNECTION_ACTIVITY: ActivityTracker = ActivityTracker::default();
+
+	/// Call metrics of the control server's methods, across all connections.
+	pub static ref CONTROL_RPC_STATS: RpcStats = RpcStats::default();
+
+	/// Connections using each server commit, across all connections.
+	pub static ref SERVER_USAGE: ServerUsage = ServerUsage::default();
+}
+
 type HttpRequestsMap = Arc<std::sync::Mutex<HashMap<u32, DelegatedHttpRequest>>>;
 type CodeServerCell = Arc<Mutex<Option<SocketCodeServer>>>;
 
@@ -96,16 +135,27 @@ struct HandlerContext {
 	http: Arc<FallbackSimpleHttp>,
 	/// requests being served by the client
 	http_requests: HttpRequestsMap,
+	/// The tunnel's port watcher, which the connection's server is added to.
+	port_watcher: Option<TunnelPortWatcher>,
+	/// Marks the connection's server commit as in use.
+	server_usage: std::sync::Mutex<Option<ServerUsageGuard>>,
+	/// Penalizes the connection's peer for failed auth challenges.
+	challenge_limiter: Option<PeerChallengeLimiter>,
 }
 
 /// Handler auth state.
 enum AuthState {
 	/// Auth is required, we're waiting for the client to send its challenge optionally bearing a token.
 	WaitingForChallenge(Option<String>),
-	/// A challenge has been issued. Waiting for a verification.
-	ChallengeIssued(String),
+	/// A challenge has been issued, with the token the client sent. Waiting
+	/// for a verification.
+	ChallengeIssued(String, Option<String>),
 	/// Auth is no longer required.
 	Authenticated,
+	/// The client answered a challenge, which remote exec needs even on
+	/// connections that don't otherwise require auth. Remote exec also checks
+	/// the token sent with the challenge against the host's.
+	ChallengeVerified(Option<String>),
 }
 
 static MESSAGE_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
@@ -173,18 +223,31 @@ async fn preload_extensions(
 // Runs the launcher server. Exits on a ctrl+c or when requested by a user.
 // Note that client connections may not be closed when this returns; use
 // `close_all_clients()` on the ServerTermination to make this happen.
+#[allow(clippy::too_many_arguments)]
 pub async fn serve(
 	log: &log::Logger,
 	mut tunnel: ActiveTunnel,
 	launcher_paths: &LauncherPaths,
 	code_server_args: &CodeServerArgs,
 	platform: Platform,
+	drain: &Drain,
+	challenge_limiter: &Arc<ChallengeLimiter>,
 	mut shutdown_rx: Barrier<ShutdownSignal>,
 ) -> Result<ServerTermination, AnyError> {
 	let mut port = tunnel.add_port_direct(CONTROL_PORT).await?;
 	let mut forwarding = PortForwardingProcessor::new();
+	let port_watcher = code_server_args.auto_forward.as_ref().map(|policy| {
+		let watcher = TunnelPortWatcher::default();
+		let task = tokio::spawn(watcher.clone().run(
+			log.clone(),
+			policy.clone(),
+			forwarding.handle(),
+		));
+		(watcher, task)
+	});
 	let (tx, mut rx) = mpsc::channel::<ServerSignal>(4);
 	let (exit_barrier, signal_exit) = new_barrier();
+	let mut draining = false;
 
 	if !code_server_args.install_extensions.is_empty() {
 		info!(
@@ -206,40 +269,46 @@ pub async fn serve(
 		});
 	}
 
-	loop {
+	let next = loop {
 		tokio::select! {
 			Ok(reason) = shutdown_rx.wait() => {
 				info!(log, "Shutting down: {}", reason);
-				drop(signal_exit);
-				return Ok(ServerTermination {
-					next: match reason {
-						ShutdownSignal::RpcRestartRequested => Next::Restart,
-						_ => Next::Exit,
-					},
-					tunnel,
-				});
+				break match reason {
+					ShutdownSignal::RpcRestartRequested => Next::Restart,
+					_ => Next::Exit,
+				};
 			},
 			c = rx.recv() => {
 				if let Some(ServerSignal::Respawn) = c {
-					drop(signal_exit);
-					return Ok(ServerTermination {
-						next: Next::Respawn,
-						tunnel,
-					});
+					break Next::Respawn;
 				}
 			},
 			Some(w) = forwarding.recv() => {
 				forwarding.process(w, &mut tunnel).await;
 			},
+			status = drain.started(), if !draining => {
+				draining = true;
+				info!(
+					log,
+					"Draining clients, shutting down by {}",
+					status.deadline.to_rfc3339()
+				);
+				tunnel.status().draining(status);
+			},
 			l = port.recv() => {
 				let socket = match l {
 					Some(p) => p,
 					None => {
 						warning!(log, "ssh tunnel disposed, tearing down");
-						return Ok(ServerTermination {
-							next: Next::Restart,
-							tunnel,
-						});
+						break Next::Restart;
+					}
+				};
+
+				let client = match drain.admit() {
+					Some(c) => c,
+					None => {
+						debug!(log, "Rejecting new connection while draining");
+						continue;
 					}
 				};
 
@@ -249,9 +318,15 @@ pub async fn serve(
 				let own_exit = exit_barrier.clone();
 				let own_code_server_args = code_server_args.clone();
 				let own_forwarding = forwarding.handle();
+				let own_watcher = port_watcher.as_ref().map(|(w, _)| w.clone());
+				let own_drain = drain.clone();
+				let own_limiter = challenge_limiter.for_peer(TUNNEL_PEER);
+				let activity = CONNECTION_ACTIVITY.start();
 
 				tokio::spawn(async move {
 					use opentelemetry::trace::{FutureExt, TraceContextExt};
+					let _activity = activity;
+					let _client = client;
 
 					let span = own_log.span("server.socket").with_kind(SpanKind::Consumer).start(own_log.tracer());
 					let cx = opentelemetry::Context::current_with_span(span);
@@ -260,13 +335,15 @@ pub async fn serve(
 					debug!(own_log, "Serving new connection");
 
 					let (writehalf, readhalf) = socket.into_split();
-					let stats = process_socket(readhalf, writehalf, own_tx, Some(own_forwarding), ServeStreamParams {
+					let stats = process_socket(readhalf, writehalf, own_tx, Some(own_forwarding), own_watcher, ServeStreamParams {
 						log: own_log,
 						launcher_paths: own_paths,
 						code_server_args: own_code_server_args,
 						platform,
 						exit_barrier: own_exit,
 						requires_auth: AuthRequired::None,
+						drain: Some(own_drain),
+						challenge_limiter: Some(own_limiter),
 					}).with_context(cx.clone()).await;
 
 					cx.span().add_event(
@@ -281,7 +358,16 @@ pub async fn serve(
 				});
 			}
 		}
+	};
+
+	drop(signal_exit);
+	// ports the watcher forwarded aren't left open once it's no longer running
+	if let Some((_, task)) = port_watcher {
+		task.abort();
 	}
+	forwarding.unforward_detected(&mut tunnel).await;
+
+	Ok(ServerTermination { next, tunnel })
 }
 
 #[derive(Clone)]
@@ -299,6 +385,10 @@ pub struct ServeStreamParams {
 	pub platform: Platform,
 	pub requires_auth: AuthRequired,
 	pub exit_barrier: Barrier<ShutdownSignal>,
+	/// If set, the client is asked to disconnect once a drain starts.
+	pub drain: Option<Drain>,
+	/// If set, failed auth challenges of the client's peer are penalized.
+	pub challenge_limiter: Option<PeerChallengeLimiter>,
 }
 
 pub async fn serve_stream(
@@ -311,7 +401,7 @@ pub async fn serve_stream(
 	let (server_rx, server_tx) = mpsc::channel(1);
 	drop(server_tx);
 
-	process_socket(readhalf, writehalf, server_rx, None, params).await
+	process_socket(readhalf, writehalf, server_rx, None, None, params).await
 }
 
 pub struct SocketStats {
@@ -330,6 +420,8 @@ fn make_socket_rpc(
 	requires_auth: AuthRequired,
 	platform: Platform,
 	http_requests: HttpRequestsMap,
+	challenge_limiter: Option<PeerChallengeLimiter>,
+	port_watcher: Option<TunnelPortWatcher>,
 ) -> RpcDispatcher<MsgPackSerializer, HandlerContext> {
 	let server_bridges = ServerMultiplexer::new();
 	let mut rpc = RpcBuilder::new(MsgPackSerializer {}).methods(HandlerContext {
@@ -352,9 +444,13 @@ fn make_socket_rpc(
 			http_delegated,
 		)),
 		http_requests,
+		port_watcher,
+		server_usage: std::sync::Mutex::new(None),
+		challenge_limiter,
 	});
 
-	rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
+	rpc.set_stats(CONTROL_RPC_STATS.clone());
+	rpc.register_sync(METHOD_PING, |p: PingParams, _| Ok(handle_ping(p)));
 	rpc.register_sync("gethostname", |_: EmptyObject, _| handle_get_hostname());
 	rpc.register_sync("sys_kill", |p: SysKillRequest, c| {
 		ensure_auth(&c.auth_state)?;
@@ -417,17 +513,28 @@ fn make_socket_rpc(
 		handle_get_env()
 	});
 	rpc.register_sync(METHOD_CHALLENGE_ISSUE, |p: ChallengeIssueParams, c| {
-		handle_challenge_issue(p, &c.auth_state)
+		handle_challenge_issue(
+			p,
+			&c.auth_state,
+			&c.challenge_limiter,
+			c.code_server_args.remote_exec.as_ref(),
+		)
 	});
 	rpc.register_sync(METHOD_CHALLENGE_VERIFY, |p: ChallengeVerifyParams, c| {
-		handle_challenge_verify(p.response, &c.auth_state)
+		handle_challenge_verify(p.response, &c.auth_state, &c.challenge_limiter)
 	});
-	rpc.register_async("serve", move |params: ServeParams, c| async move {
+	rpc.register_async_cancellable("serve", move |params: ServeParams, c, cancel| async move {
 		ensure_auth(&c.auth_state)?;
-		handle_serve(c, params).await
+		tokio::select! {
+			r = handle_serve(c, params) => r,
+			_ = cancel.cancelled() => Err(CodeError::RequestCancelled.into()),
+		}
 	});
-	rpc.register_async("update", |p: UpdateParams, c| async move {
-		handle_update(&c.http, &c.log, &c.did_update, &p).await
+	rpc.register_async_cancellable("update", |p: UpdateParams, c, cancel| async move {
+		tokio::select! {
+			r = handle_update(&c.http, &c.log, &c.did_update, &p) => r,
+			_ = cancel.cancelled() => Err(CodeError::RequestCancelled.into()),
+		}
 	});
 	rpc.register_sync("servermsg", |m: ServerMessageParams, c| {
 		if let Err(e) = handle_server_message(&c.log, &c.server_bridges, m) {
@@ -435,7 +542,17 @@ fn make_socket_rpc(
 		}
 		Ok(EmptyObject {})
 	});
-	rpc.register_sync("prune", |_: EmptyObject, c| handle_prune(&c.launcher_paths));
+	rpc.register_sync("prune", |_: EmptyObject, c| {
+		handle_prune(&c.log, &c.launcher_paths)
+	});
+	rpc.register_async(
+		METHOD_UNINSTALL_SERVER,
+		|p: UninstallServerParams, c| async move {
+			ensure_auth(&c.auth_state)?;
+			let registry = CachedServerRegistry::new(&c.log, &c.launcher_paths, &SERVER_USAGE);
+			uninstall_server(&registry, &p.commit, p.force).await
+		},
+	);
 	rpc.register_async("callserverhttp", |p: CallServerHttpParams, c| async move {
 		let code_server = c.code_server.lock().await.clone();
 		handle_call_server_http(code_server, p).await
@@ -463,6 +580,10 @@ fn make_socket_rpc(
 		)
 		.await
 	});
+	rpc.register_duplex("exec", 2, |mut streams, p: ExecParams, c| async move {
+		ensure_auth(&c.auth_state)?;
+		handle_exec(&c, p, streams.remove(0), streams.remove(0)).await
+	});
 	rpc.register_duplex(
 		"spawn_cli",
 		3,
@@ -505,14 +626,18 @@ fn make_socket_rpc(
 		|_: EmptyObject, _| Ok(VersionResponse::default()),
 	);
 
+	rpc.set_max_in_flight(CONTROL_MAX_IN_FLIGHT_REQUESTS);
+	rpc.set_protocol(CONTROL_PROTOCOL);
+	rpc.require_capability(METHOD_LIST_METHODS, CAPABILITY_INTROSPECTION);
+	rpc.require_capability(METHOD_LOAD, CAPABILITY_INTROSPECTION);
+	rpc.require_capability(METHOD_METRICS, CAPABILITY_INTROSPECTION);
 	rpc.build(log)
 }
 
 fn ensure_auth(is_authed: &Arc<std::sync::Mutex<AuthState>>) -> Result<(), AnyError> {
-	if let AuthState::Authenticated = &*is_authed.lock().unwrap() {
-		Ok(())
-	} else {
-		Err(CodeError::ServerAuthRequired.into())
+	match &*is_authed.lock().unwrap() {
+		AuthState::Authenticated | AuthState::ChallengeVerified(_) => Ok(()),
+		_ => Err(CodeError::ServerAuthRequired.into()),
 	}
 }
 
@@ -522,6 +647,7 @@ async fn process_socket(
 	mut writehalf: impl AsyncWrite + Unpin,
 	server_tx: mpsc::Sender<ServerSignal>,
 	port_forwarding: Option<PortForwarding>,
+	port_watcher: Option<TunnelPortWatcher>,
 	params: ServeStreamParams,
 ) -> SocketStats {
 	let ServeStreamParams {
@@ -531,6 +657,8 @@ async fn process_socket(
 		code_server_args,
 		platform,
 		requires_auth,
+		drain,
+		challenge_limiter,
 	} = params;
 
 	let (http_delegated, mut http_rx) = DelegatedSimpleHttp::new(log.clone());
@@ -549,8 +677,11 @@ async fn process_socket(
 		requires_auth,
 		platform,
 		http_requests.clone(),
+		challenge_limiter,
+		port_watcher,
 	);
 
+	let trace = ConnectionTrace::new(Format::MsgPack);
 	{
 		let log = log.clone();
 		let rx_counter = rx_counter.clone();
@@ -561,8 +692,16 @@ async fn process_socket(
 				send_version(&socket_tx).await;
 			}
 
-			if let Err(e) =
-				handle_socket_read(&log, readhalf, exit_barrier, &socket_tx, rx_counter, &rpc).await
+			if let Err(e) = handle_socket_read(
+				&log,
+				readhalf,
+				exit_barrier,
+				&socket_tx,
+				rx_counter,
+				&rpc,
+				trace,
+			)
+			.await
 			{
 				debug!(log, "closing socket reader: {}", e);
 				socket_tx
@@ -587,13 +726,34 @@ async fn process_socket(
 	}
 
 	let mut tx_counter = 0;
+	let mut drain_notified = false;
+	// frames sent in a burst, such as the responses to pipelined requests,
+	// are written together
+	let mut out = FrameBuffer::new(trace);
 
 	loop {
+		let mut closing = false;
 		tokio::select! {
 			_ = exit_barrier.wait() => {
+				out.write_to(&mut writehalf).await.ok();
 				writehalf.shutdown().await.ok();
 				break;
 			},
+			// sent to every client, since those that don't know the method ignore it
+			status = wait_for_drain(&drain), if !drain_notified => {
+				drain_notified = true;
+				let timeout = status.deadline - chrono::Utc::now();
+				let serialized = rmp_serde::to_vec_named(&ToClientRequest {
+					id: None,
+					params: ClientRequestMethod::servershutdown(ServerShutdownParams {
+						timeout_ms: timeout.num_milliseconds().max(0) as u64,
+					}),
+				})
+				.unwrap();
+
+				tx_counter += serialized.len();
+				out.push(&serialized);
+			}
 			Some(r) = http_rx.recv() => {
 				let id = next_message_id();
 				let serialized = rmp_serde::to_vec_named(&ToClientRequest {
@@ -609,28 +769,42 @@ async fn process_socket(
 				http_requests.lock().unwrap().insert(id, r);
 
 				tx_counter += serialized.len();
-				if let Err(e) = writehalf.write_all(&serialized).await {
-					debug!(log, "Closing connection: {}", e);
-					break;
-				}
+				out.push(&serialized);
 			}
-			recv = socket_rx.recv() => match recv {
-				None => break,
-				Some(message) => match message {
-					SocketSignal::Send(bytes) => {
-						tx_counter += bytes.len();
-						if let Err(e) = writehalf.write_all(&bytes).await {
-							debug!(log, "Closing connection: {}", e);
-							break;
+			recv = socket_rx.recv() => {
+				let mut recv = recv;
+				loop {
+					match recv {
+						None => closing = true,
+						Some(SocketSignal::Send(bytes)) => {
+							tx_counter += bytes.len();
+							out.push(&bytes);
+						}
+						Some(SocketSignal::CloseWith(reason)) => {
+							debug!(log, "Closing connection: {}", reason.0);
+							closing = true;
 						}
 					}
-					SocketSignal::CloseWith(reason) => {
-						debug!(log, "Closing connection: {}", reason.0);
+
+					if closing || out.is_full() {
 						break;
 					}
+					recv = match socket_rx.try_recv() {
+						Ok(m) => Some(m),
+						Err(_) => break,
+					};
 				}
 			}
 		}
+
+		// what was queued before closing is still sent
+		if let Err(e) = out.write_to(&mut writehalf).await {
+			debug!(log, "Closing connection: {}", e);
+			break;
+		}
+		if closing {
+			break;
+		}
 	}
 
 	SocketStats {
@@ -639,6 +813,13 @@ async fn process_socket(
 	}
 }
 
+async fn wait_for_drain(drain: &Option<Drain>) -> DrainStatus {
+	match drain {
+		Some(d) => d.started().await,
+		None => futures::future::pending().await,
+	}
+}
+
 async fn send_version(tx: &mpsc::Sender<SocketSignal>) {
 	tx.send(SocketSignal::from_message(&ToClientRequest {
 		id: None,
@@ -654,10 +835,11 @@ async fn handle_socket_read(
 	socket_tx: &mpsc::Sender<SocketSignal>,
 	rx_counter: Arc<AtomicUsize>,
 	rpc: &RpcDispatcher<MsgPackSerializer, HandlerContext>,
+	trace: ConnectionTrace,
 ) -> Result<(), std::io::Error> {
 	let mut readhalf = BufReader::new(readhalf);
-	let mut decoder = MsgPackCodec::new();
-	let mut decoder_buf = bytes::BytesMut::new();
+	let mut decoder = MsgPackCodec::with_max_frame_size(rpc.max_frame_size());
+	let mut decoder_buf = bytes::BytesMut::with_capacity(READ_BUFFER_SIZE);
 
 	loop {
 		let read_len = tokio::select! {
@@ -672,6 +854,7 @@ async fn handle_socket_read(
 		rx_counter.fetch_add(read_len, Ordering::Relaxed);
 
 		while let Some(frame) = decoder.decode(&mut decoder_buf)? {
+			trace.inbound(&frame.vec);
 			match rpc.dispatch_with_partial(&frame.vec, frame.obj) {
 				MaybeSync::Sync(Some(v)) => {
 					if socket_tx.send(SocketSignal::Send(v)).await.is_err() {
@@ -687,6 +870,9 @@ async fn handle_socket_read(
 						}
 					});
 				}
+				MaybeSync::Partial(results) => {
+					rpc.register_partials(socket_tx.clone(), results);
+				}
 				MaybeSync::Stream((stream, fut)) => {
 					if let Some(stream) = stream {
 						rpc.register_stream(socket_tx.clone(), stream).await;
@@ -731,6 +917,9 @@ async fn handle_serve(
 	// fill params.extensions into code_server_args.install_extensions
 	let mut csa = c.code_server_args.clone();
 	csa.connection_token = params.connection_token.or(csa.connection_token);
+	if let Some(t) = &csa.connection_token {
+		log::redact::register_secret(t);
+	}
 	csa.install_extensions.extend(params.extensions.into_iter());
 
 	let params_raw = ServerParamsRaw {
@@ -804,6 +993,16 @@ async fn handle_serve(
 			};
 
 			server_ref.replace(server.clone());
+			c.server_usage
+				.lock()
+				.unwrap()
+				.replace(SERVER_USAGE.acquire(&resolved.release.commit));
+			if let Some(exited) = server.origin.exited() {
+				start_server_watchdog(&c, resolved.clone(), params.use_local_download, exited);
+			}
+			if let Some(watcher) = &c.port_watcher {
+				watcher.add(&c);
+			}
 			server
 		}
 	};
@@ -820,6 +1019,218 @@ async fn handle_serve(
 	Ok(EmptyObject {})
 }
 
+/// Supervises a server spawned for this connection, restarting it if it
+/// crashes, until the connection is closed.
+fn start_server_watchdog(
+	c: &Arc<HandlerContext>,
+	resolved: ResolvedServerParams,
+	use_local_download: bool,
+	exited: watch::Receiver<Option<ServerExit>>,
+) {
+	let log = c.log.clone();
+	let socket_tx = c.socket_tx.clone();
+	let limit = c.code_server_args.restart_limit;
+	let server = WatchedServer {
+		c: c.clone(),
+		resolved,
+		use_local_download,
+		limit,
+	};
+
+	tokio::spawn(async move {
+		tokio::select! {
+			_ = supervise(&log, RestartBackoff::new(limit), exited, server) => {},
+			_ = socket_tx.closed() => {},
+		}
+	});
+}
+
+/// Watches for ports that the servers of the tunnel's connections listen on.
+/// There's one per tunnel, so that clients don't each forward the same ports.
+#[derive(Clone, Default)]
+struct TunnelPortWatcher {
+	/// Connections that started a server, by the address of their server cell.
+	connections: Arc<std::sync::Mutex<HashMap<usize, WatchedConnection>>>,
+}
+
+struct WatchedConnection {
+	code_server: CodeServerCell,
+	socket_tx: mpsc::Sender<SocketSignal>,
+}
+
+impl TunnelPortWatcher {
+	/// Watches the connection's server until the connection is closed. The
+	/// watcher follows the server across restarts.
+	fn add(&self, c: &HandlerContext) {
+		let key = Arc::as_ptr(&c.code_server) as usize;
+		let connection = WatchedConnection {
+			code_server: c.code_server.clone(),
+			socket_tx: c.socket_tx.clone(),
+		};
+		if self
+			.connections
+			.lock()
+			.unwrap()
+			.insert(key, connection)
+			.is_some()
+		{
+			return;
+		}
+
+		let connections = self.connections.clone();
+		let socket_tx = c.socket_tx.clone();
+		tokio::spawn(async move {
+			socket_tx.closed().await;
+			connections.lock().unwrap().remove(&key);
+		});
+	}
+
+	/// Forwards detected ports with the tunnel's forwarding until the future
+	/// is dropped. The forwarder unforwards what it forwarded when it stops.
+	async fn run(self, log: log::Logger, policy: AutoForwardPolicy, forwarding: PortForwarding) {
+		let mut host = TunnelPortWatchHost {
+			watcher: self,
+			forwarding,
+		};
+		watch_ports(&log, &policy, Arc::new(SystemPortScanner), &mut host).await
+	}
+
+	fn send_all(&self, params: ClientRequestMethod<'_>) {
+		let message = rmp_serde::to_vec_named(&ToClientRequest { id: None, params }).unwrap();
+		for c in self.connections.lock().unwrap().values() {
+			c.socket_tx
+				.try_send(SocketSignal::Send(message.clone()))
+				.ok();
+		}
+	}
+}
+
+struct TunnelPortWatchHost {
+	watcher: TunnelPortWatcher,
+	forwarding: PortForwarding,
+}
+
+#[async_trait]
+impl PortWatchHost for TunnelPortWatchHost {
+	async fn server_pids(&self) -> Vec<u32> {
+		let cells: Vec<CodeServerCell> = self
+			.watcher
+			.connections
+			.lock()
+			.unwrap()
+			.values()
+			.map(|c| c.code_server.clone())
+			.collect();
+
+		let mut pids = vec![];
+		for cell in cells {
+			if let Some(pid) = cell.lock().await.as_ref().and_then(|s| s.origin.pid()) {
+				pids.push(pid);
+			}
+		}
+		pids.sort_unstable();
+		pids.dedup();
+		pids
+	}
+
+	async fn forward(&mut self, port: u16, privacy: PortPrivacy) -> Result<String, AnyError> {
+		self.forwarding
+			.forward(port, privacy, PortProtocol::Auto, ForwardedBy::Detection)
+			.await
+	}
+
+	async fn unforward(&mut self, port: u16) -> Result<(), AnyError> {
+		self.forwarding
+			.unforward(port, ForwardedBy::Detection)
+			.await
+	}
+
+	fn port_detected(&mut self, params: PortDetectedParams) {
+		self.watcher
+			.send_all(ClientRequestMethod::portdetected(params));
+	}
+
+	fn port_closed(&mut self, params: PortClosedParams) {
+		self.watcher
+			.send_all(ClientRequestMethod::portclosed(params));
+	}
+}
+
+struct WatchedServer {
+	c: Arc<HandlerContext>,
+	resolved: ResolvedServerParams,
+	use_local_download: bool,
+	limit: u32,
+}
+
+#[async_trait]
+impl SupervisedServer for WatchedServer {
+	async fn respawn(&mut self) -> Result<watch::Receiver<Option<ServerExit>>, AnyError> {
+		let server = if self.use_local_download {
+			ServerBuilder::new(
+				&self.c.log,
+				&self.resolved,
+				&self.c.launcher_paths,
+				Arc::new(self.c.http.delegated()),
+			)
+			.listen_on_default_socket()
+			.await
+		} else {
+			ServerBuilder::new(
+				&self.c.log,
+				&self.resolved,
+				&self.c.launcher_paths,
+				self.c.http.clone(),
+			)
+			.listen_on_default_socket()
+			.await
+		}?;
+
+		let exited = server
+			.origin
+			.exited()
+			.expect("expected server to be spawned");
+		self.c.code_server.lock().await.replace(server);
+		Ok(exited)
+	}
+
+	fn notify(&mut self, event: WatchdogEvent<'_>) {
+		let params = match event {
+			WatchdogEvent::Restarting {
+				exit,
+				attempt,
+				delay,
+			} => ServerRestartParams {
+				exit_code: exit.code,
+				attempt,
+				limit: self.limit,
+				delay_ms: Some(delay.as_millis() as u64),
+			},
+			WatchdogEvent::GaveUp { exit } => {
+				// forget the dead server so the next `serve` call starts a new one
+				if let Ok(mut server) = self.c.code_server.try_lock() {
+					server.take();
+				}
+
+				ServerRestartParams {
+					exit_code: exit.code,
+					attempt: self.limit,
+					limit: self.limit,
+					delay_ms: None,
+				}
+			}
+		};
+
+		self.c
+			.socket_tx
+			.try_send(SocketSignal::from_message(&ToClientRequest {
+				id: None,
+				params: ClientRequestMethod::serverrestart(params),
+			}))
+			.ok();
+	}
+}
+
 async fn attach_server_bridge(
 	log: &log::Logger,
 	code_server: SocketCodeServer,
@@ -873,8 +1284,8 @@ fn handle_server_message(
 	}
 }
 
-fn handle_prune(paths: &LauncherPaths) -> Result<Vec<String>, AnyError> {
-	prune_stopped_servers(paths).map(|v| {
+fn handle_prune(log: &log::Logger, paths: &LauncherPaths) -> Result<Vec<String>, AnyError> {
+	prune_stopped_servers(log, paths).map(|v| {
 		v.iter()
 			.map(|p| p.server_dir.display().to_string())
 			.collect()
@@ -1052,39 +1463,66 @@ fn handle_get_env() -> Result<GetEnvResponse, AnyError> {
 fn handle_challenge_issue(
 	params: ChallengeIssueParams,
 	auth_state: &Arc<std::sync::Mutex<AuthState>>,
+	limiter: &Option<PeerChallengeLimiter>,
+	remote_exec: Option<&RemoteExecPolicy>,
 ) -> Result<ChallengeIssueResponse, AnyError> {
+	if let Some(l) = limiter {
+		l.check()?;
+	}
+
 	let challenge = create_challenge();
 
 	let mut auth_state = auth_state.lock().unwrap();
 	if let AuthState::WaitingForChallenge(Some(s)) = &*auth_state {
-		println!("looking for token {}, got {:?}", s, params.token);
-		match &params.token {
-			Some(t) if s != t => return Err(CodeError::AuthChallengeBadToken.into()),
-			None => return Err(CodeError::AuthChallengeBadToken.into()),
-			_ => {}
+		if params.token.as_ref() != Some(s) {
+			return Err(penalize(limiter, CodeError::AuthChallengeBadToken).into());
+		}
+	} else if let (Some(p), Some(_)) = (remote_exec, &params.token) {
+		// a token on a connection that doesn't need one is for remote exec, so
+		// guesses of it are penalized like guesses of the connection's
+		if !p.accepts_token(params.token.as_deref()) {
+			return Err(penalize(limiter, CodeError::RemoteExecBadToken).into());
 		}
 	}
 
-	*auth_state = AuthState::ChallengeIssued(challenge.clone());
+	*auth_state = AuthState::ChallengeIssued(challenge.clone(), params.token);
 	Ok(ChallengeIssueResponse { challenge })
 }
 
 fn handle_challenge_verify(
 	response: String,
 	auth_state: &Arc<std::sync::Mutex<AuthState>>,
+	limiter: &Option<PeerChallengeLimiter>,
 ) -> Result<EmptyObject, AnyError> {
 	let mut auth_state = auth_state.lock().unwrap();
 
 	match &*auth_state {
-		AuthState::Authenticated => Ok(EmptyObject {}),
+		AuthState::Authenticated | AuthState::ChallengeVerified(_) => Ok(EmptyObject {}),
 		AuthState::WaitingForChallenge(_) => Err(CodeError::AuthChallengeNotIssued.into()),
-		AuthState::ChallengeIssued(c) => match verify_challenge(c, &response) {
-			false => Err(CodeError::AuthChallengeNotIssued.into()),
-			true => {
-				*auth_state = AuthState::Authenticated;
-				Ok(EmptyObject {})
+		AuthState::ChallengeIssued(c, token) => {
+			if let Some(l) = limiter {
+				l.check()?;
 			}
-		},
+
+			match verify_challenge(c, &response) {
+				false => Err(penalize(limiter, CodeError::AuthChallengeNotIssued).into()),
+				true => {
+					if let Some(l) = limiter {
+						l.record_success();
+					}
+					*auth_state = AuthState::ChallengeVerified(token.clone());
+					Ok(EmptyObject {})
+				}
+			}
+		}
+	}
+}
+
+/// Records a failed auth challenge, getting the error to send the client.
+fn penalize(limiter: &Option<PeerChallengeLimiter>, error: CodeError) -> CodeError {
+	match limiter {
+		Some(l) => l.record_failure(error),
+		None => error,
 	}
 }
 
@@ -1098,15 +1536,20 @@ async fn handle_forward(
 		.ok_or(CodeError::PortForwardingNotAvailable)?;
 	info!(
 		log,
-		"Forwarding port {} (public={})", params.port, params.public
+		"Forwarding port {} (public={}, protocol={})", params.port, params.public, params.protocol
 	);
 	let privacy = match params.public {
 		true => PortPrivacy::Public,
 		false => PortPrivacy::Private,
 	};
 
-	let uri = port_forwarding.forward(params.port, privacy).await?;
-	Ok(ForwardResult { uri })
+	let uri = port_forwarding
+		.forward(params.port, privacy, params.protocol, ForwardedBy::Client)
+		.await?;
+	Ok(ForwardResult {
+		uri,
+		protocol: params.protocol,
+	})
 }
 
 async fn handle_unforward(
@@ -1118,7 +1561,9 @@ async fn handle_unforward(
 		.as_ref()
 		.ok_or(CodeError::PortForwardingNotAvailable)?;
 	info!(log, "Unforwarding port {}", params.port);
-	port_forwarding.unforward(params.port).await?;
+	port_forwarding
+		.unforward(params.port, ForwardedBy::Client)
+		.await?;
 	Ok(EmptyObject {})
 }
 
@@ -1264,6 +1709,36 @@ where
 	wait_for_process_exit(log, &params.command, p, block_futs, poll_futs).await
 }
 
+/// Runs a command from the host's remote exec allowlist, for clients that
+/// answered the auth challenge with the host's remote exec token.
+async fn handle_exec(
+	c: &HandlerContext,
+	params: ExecParams,
+	stdout: DuplexStream,
+	stderr: DuplexStream,
+) -> Result<ExecResult, AnyError> {
+	let policy = match &c.code_server_args.remote_exec {
+		Some(p) => p,
+		None => return Err(CodeError::RemoteExecDisabled.into()),
+	};
+	let token = match &*c.auth_state.lock().unwrap() {
+		AuthState::ChallengeVerified(t) => t.clone(),
+		_ => return Err(CodeError::RemoteExecRequiresChallenge.into()),
+	};
+
+	let audit = ExecAuditLog::new(c.launcher_paths.remote_exec_audit_log());
+	run_exec(
+		&c.log,
+		policy,
+		&audit,
+		token.as_deref(),
+		params,
+		stdout,
+		stderr,
+	)
+	.await
+}
+
 async fn handle_spawn_cli(
 	log: &log::Logger,
 	params: SpawnParams,
@@ -1425,3 +1900,49 @@ async fn do_challenge_response_flow(
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tunnels::challenge_limiter::ChallengeLimits;
+
+	#[test]
+	fn test_penalizes_wrong_remote_exec_token() {
+		let limiter = Arc::new(ChallengeLimiter::new(
+			log::Logger::test(),
+			ChallengeLimits {
+				lockout_after: 2,
+				..Default::default()
+			},
+			None,
+		));
+		let peer = Some(limiter.for_peer(TUNNEL_PEER));
+		let policy = RemoteExecPolicy::new(&[], "secret".to_string());
+		let auth_state = Arc::new(std::sync::Mutex::new(AuthState::Authenticated));
+		let issue = |token: Option<&str>| {
+			handle_challenge_issue(
+				ChallengeIssueParams {
+					token: token.map(|t| t.to_string()),
+				},
+				&auth_state,
+				&peer,
+				Some(&policy),
+			)
+		};
+
+		// clients that don't use remote exec needn't send a token
+		issue(None).unwrap();
+		issue(Some("secret")).unwrap();
+
+		let err = issue(Some("guess")).unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::RemoteExecBadToken)
+		));
+		assert!(matches!(
+			issue(Some("secret")),
+			Err(AnyError::CodeError(CodeError::AuthChallengeThrottled(_)))
+		));
+		assert_eq!(limiter.stats().failures, 1);
+	}
+}
@@ -2,15 +2,18 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
+use super::port_forwarder::forward_detecting_protocol;
 use super::protocol::{self, PortPrivacy, PortProtocol};
 use crate::auth;
-use crate::constants::{IS_INTERACTIVE_CLI, PROTOCOL_VERSION_TAG, TUNNEL_SERVICE_USER_AGENT};
+use crate::constants::{PROTOCOL_VERSION_TAG, TUNNEL_SERVICE_USER_AGENT};
+use crate::policy::{self, PolicyFeature};
 use crate::state::{LauncherPaths, PersistedState};
 use crate::util::errors::{
 	wrap, AnyError, CodeError, DevTunnelError, InvalidTunnelName, TunnelCreationFailed,
 	WrappedError,
 };
-use crate::util::input::prompt_placeholder;
+use crate::util::http::shared_client;
+use crate::util::input::{is_interactive, prompt_placeholder};
 use crate::{debug, info, log, spanf, trace, warning};
 use async_trait::async_trait;
 use futures::future::BoxFuture;
@@ -74,6 +77,9 @@ pub struct PersistedTunnel {
 	pub name: String,
 	pub id: String,
 	pub cluster: String,
+	/// Cloud of the account the tunnel was registered with.
+	#[serde(default)]
+	pub auth_cloud: auth::AuthCloud,
 }
 
 impl PersistedTunnel {
@@ -97,9 +103,10 @@ trait AccessTokenProvider: Send + Sync {
 	async fn refresh_token(&self) -> Result<String, WrappedError>;
 
 	/// Maintains the stored credential by refreshing it against the service
-	/// to ensure its stays current. Returns a future that should be polled and
-	/// only completes if a refresh fails in a consistent way.
-	fn keep_alive(&self) -> BoxFuture<'static, Result<(), AnyError>>;
+	/// to ensure its stays current, recording its health in the status.
+	/// Returns a future that should be polled and only completes if a refresh
+	/// fails in a consistent way.
+	fn keep_alive(&self, status: StatusLock) -> BoxFuture<'static, Result<(), AnyError>>;
 }
 
 /// Access token provider that provides a fixed token without refreshing.
@@ -117,7 +124,7 @@ impl AccessTokenProvider for StaticAccessTokenProvider {
 		Ok(self.0.clone())
 	}
 
-	fn keep_alive(&self) -> BoxFuture<'static, Result<(), AnyError>> {
+	fn keep_alive(&self, _status: StatusLock) -> BoxFuture<'static, Result<(), AnyError>> {
 		futures::future::pending().boxed()
 	}
 }
@@ -176,9 +183,10 @@ impl AccessTokenProvider for LookupAccessTokenProvider {
 		}
 	}
 
-	fn keep_alive(&self) -> BoxFuture<'static, Result<(), AnyError>> {
+	fn keep_alive(&self, status: StatusLock) -> BoxFuture<'static, Result<(), AnyError>> {
 		let auth = self.auth.clone();
-		auth.keep_token_alive().boxed()
+		auth.keep_token_alive(move |e| status.token_refreshed(e))
+			.boxed()
 	}
 }
 
@@ -188,6 +196,9 @@ pub struct DevTunnels {
 	log: log::Logger,
 	launcher_tunnel: PersistedState<Option<PersistedTunnel>>,
 	client: TunnelManagementClient,
+	/// Client that the management client makes requests with.
+	#[allow(dead_code)] // read by tests that check the pool is shared
+	pub(crate) http: reqwest::Client,
 	tag: &'static str,
 }
 
@@ -216,22 +227,46 @@ impl ActiveTunnel {
 		Ok(port)
 	}
 
-	/// Forwards a port over TCP.
+	/// Forwards a port over TCP. Connections to ports forwarded with
+	/// auto-detect are relayed by the CLI, which detects the protocol.
 	pub async fn add_port_tcp(
 		&self,
 		port_number: u16,
 		privacy: PortPrivacy,
 		protocol: PortProtocol,
 	) -> Result<(), AnyError> {
+		if privacy == PortPrivacy::Public {
+			policy::current().ensure_allowed(PolicyFeature::PublicPorts)?;
+		}
+
+		if protocol == PortProtocol::AutoDetect {
+			let connections = self.manager.add_port_raw(port_number, privacy).await?;
+			tokio::spawn(forward_detecting_protocol(
+				port_number,
+				connections,
+				self.manager.on_protocol_detected(port_number, privacy),
+			));
+		} else {
+			self.manager
+				.add_port_tcp(port_number, privacy, protocol)
+				.await?;
+		}
+
 		self.manager
-			.add_port_tcp(port_number, privacy, protocol)
-			.await?;
+			.get_status()
+			.port_forwarded(protocol::singleton::ForwardedPort {
+				number: port_number,
+				privacy,
+				protocol,
+				uri: self.get_port_uri(port_number).ok(),
+			});
 		Ok(())
 	}
 
 	/// Removes a forwarded port TCP.
 	pub async fn remove_port(&self, port_number: u16) -> Result<(), AnyError> {
 		self.manager.remove_port(port_number).await?;
+		self.manager.get_status().port_unforwarded(port_number);
 		Ok(())
 	}
 
@@ -329,13 +364,16 @@ impl DevTunnels {
 		auth: auth::Auth,
 		paths: &LauncherPaths,
 	) -> DevTunnels {
+		let http = shared_client();
 		let mut client = new_tunnel_management(&TUNNEL_SERVICE_USER_AGENT);
 		client.authorization_provider(auth.clone());
+		client.client(http.clone());
 
 		DevTunnels {
 			auth,
 			log: log.clone(),
 			client: client.into(),
+			http,
 			launcher_tunnel: PersistedState::new(paths.root().join("port_forwarding_tunnel.json")),
 			tag: VSCODE_CLI_FORWARDING_TAG,
 		}
@@ -347,13 +385,16 @@ impl DevTunnels {
 		auth: auth::Auth,
 		paths: &LauncherPaths,
 	) -> DevTunnels {
+		let http = shared_client();
 		let mut client = new_tunnel_management(&TUNNEL_SERVICE_USER_AGENT);
 		client.authorization_provider(auth.clone());
+		client.client(http.clone());
 
 		DevTunnels {
 			auth,
 			log: log.clone(),
 			client: client.into(),
+			http,
 			launcher_tunnel: PersistedState::new(paths.root().join("code_tunnel.json")),
 			tag: VSCODE_CLI_TUNNEL_TAG,
 		}
@@ -366,6 +407,7 @@ impl DevTunnels {
 				return Ok(());
 			}
 		};
+		self.ensure_same_cloud(&tunnel)?;
 
 		spanf!(
 			self.log,
@@ -434,6 +476,20 @@ impl DevTunnels {
 		Ok((updated_tunnel, persisted))
 	}
 
+	/// Fails if the tunnel was registered with credentials from another cloud
+	/// than the current ones, since it can't be found with those.
+	fn ensure_same_cloud(&self, persisted: &PersistedTunnel) -> Result<(), CodeError> {
+		let cloud = self.auth.cloud();
+		if persisted.auth_cloud != cloud {
+			return Err(CodeError::TunnelCloudMismatch(
+				persisted.auth_cloud.to_string(),
+				cloud.to_string(),
+			));
+		}
+
+		Ok(())
+	}
+
 	/// Gets the persisted tunnel from the service, or creates a new one.
 	/// If `create_with_new_name` is given, the new tunnel has that name
 	/// instead of the one previously persisted.
@@ -443,6 +499,8 @@ impl DevTunnels {
 		create_with_new_name: Option<&str>,
 		options: &TunnelRequestOptions,
 	) -> Result<(Tunnel, PersistedTunnel, /* is_new */ bool), AnyError> {
+		self.ensure_same_cloud(&persisted)?;
+
 		let tunnel_lookup = spanf!(
 			self.log,
 			self.log.span("dev-tunnel.tag.get"),
@@ -629,6 +687,7 @@ impl DevTunnels {
 			cluster: tunnel.cluster_id.clone().unwrap(),
 			id: tunnel.tunnel_id.clone().unwrap(),
 			name: name.to_string(),
+			auth_cloud: self.auth.cloud(),
 		};
 
 		self.launcher_tunnel.save(Some(pt.clone()))?;
@@ -797,7 +856,7 @@ impl DevTunnels {
 			}
 		}
 
-		if use_random_name || !*IS_INTERACTIVE_CLI {
+		if use_random_name || !is_interactive() {
 			return Ok(placeholder_name);
 		}
 
@@ -805,6 +864,7 @@ impl DevTunnels {
 			let mut name = prompt_placeholder(
 				"What would you like to call this machine?",
 				&placeholder_name,
+				"--name",
 			)?;
 
 			name.make_ascii_lowercase();
@@ -834,6 +894,9 @@ impl DevTunnels {
 			},
 			id: tunnel.tunnel_id,
 			cluster: tunnel.cluster,
+			// as for tunnels hosted with credentials, so that logging in to
+			// another cloud later is noticed
+			auth_cloud: self.auth.cloud(),
 		};
 
 		let mut mgmt = self.client.build();
@@ -916,6 +979,62 @@ impl StatusLock {
 		status.last_fail_reason = Some(reason);
 	}
 
+	fn token_refreshed(&self, event: auth::TokenRefreshEvent<'_>) {
+		let mut status = self.0.lock().unwrap();
+		let token = status.token.get_or_insert_with(Default::default);
+		match event {
+			auth::TokenRefreshEvent::Started {
+				expires_at,
+				next_refresh_at,
+			} => {
+				token.expires_at = expires_at;
+				token.next_refresh_at = Some(next_refresh_at);
+			}
+			auth::TokenRefreshEvent::Refreshed {
+				expires_at,
+				next_refresh_at,
+			} => {
+				token.expires_at = expires_at;
+				token.last_refreshed_at = Some(chrono::Utc::now());
+				token.next_refresh_at = Some(next_refresh_at);
+				token.consecutive_failures = 0;
+				token.last_error = None;
+			}
+			auth::TokenRefreshEvent::Failed {
+				error,
+				failures,
+				retry_at,
+			} => {
+				token.next_refresh_at = retry_at;
+				token.consecutive_failures = failures;
+				token.last_error = Some(error.to_string());
+			}
+		}
+	}
+
+	pub fn draining(&self, drain: protocol::singleton::DrainStatus) {
+		self.0.lock().unwrap().drain = Some(drain);
+	}
+
+	fn port_forwarded(&self, port: protocol::singleton::ForwardedPort) {
+		let mut status = self.0.lock().unwrap();
+		status.ports.retain(|p| p.number != port.number);
+		status.ports.push(port);
+		status.ports.sort_by_key(|p| p.number);
+	}
+
+	fn port_unforwarded(&self, number: u16) {
+		self.0.lock().unwrap().ports.retain(|p| p.number != number);
+	}
+
+	/// Records the protocol detected for a port forwarded with auto-detect.
+	pub fn port_protocol_detected(&self, number: u16, protocol: PortProtocol) {
+		let mut status = self.0.lock().unwrap();
+		if let Some(p) = status.ports.iter_mut().find(|p| p.number == number) {
+			p.protocol = protocol;
+		}
+	}
+
 	pub fn read(&self) -> protocol::singleton::Status {
 		let status = self.0.lock().unwrap();
 		status.clone()
@@ -927,6 +1046,9 @@ struct ActiveTunnelManager {
 	endpoint_rx: watch::Receiver<Option<Result<TunnelRelayTunnelEndpoint, WrappedError>>>,
 	relay: Arc<tokio::sync::Mutex<RelayTunnelHost>>,
 	status: StatusLock,
+	log: log::Logger,
+	mgmt: TunnelManagementClient,
+	locator: TunnelLocator,
 }
 
 impl ActiveTunnelManager {
@@ -939,15 +1061,19 @@ impl ActiveTunnelManager {
 		let (endpoint_tx, endpoint_rx) = watch::channel(None);
 		let (close_tx, close_rx) = mpsc::channel(1);
 
-		let relay = Arc::new(tokio::sync::Mutex::new(RelayTunnelHost::new(locator, mgmt)));
+		let relay = Arc::new(tokio::sync::Mutex::new(RelayTunnelHost::new(
+			locator.clone(),
+			mgmt.clone(),
+		)));
 		let relay_spawned = relay.clone();
 
 		let status = StatusLock::default();
 
 		let status_spawned = status.clone();
+		let log_spawned = log.clone();
 		tokio::spawn(async move {
 			ActiveTunnelManager::spawn_tunnel(
-				log,
+				log_spawned,
 				relay_spawned,
 				close_rx,
 				endpoint_tx,
@@ -962,6 +1088,9 @@ impl ActiveTunnelManager {
 			relay,
 			close_tx: Some(close_tx),
 			status,
+			log,
+			mgmt,
+			locator,
 		}
 	}
 
@@ -991,10 +1120,58 @@ impl ActiveTunnelManager {
 		Ok(())
 	}
 
+	/// Gets a callback for a port forwarded with auto-detect, which records
+	/// the detected protocol and sets it on the tunnel's port, so that its
+	/// public URL is served with the right scheme.
+	fn on_protocol_detected(
+		&self,
+		port_number: u16,
+		privacy: PortPrivacy,
+	) -> impl FnOnce(PortProtocol) + Send + 'static {
+		let (log, mgmt, locator, status) = (
+			self.log.clone(),
+			self.mgmt.clone(),
+			self.locator.clone(),
+			self.status.clone(),
+		);
+		move |protocol| {
+			status.port_protocol_detected(port_number, protocol);
+			tokio::spawn(async move {
+				let port = TunnelPort {
+					port_number,
+					protocol: Some(protocol.to_contract_str().to_string()),
+					access_control: Some(privacy_to_tunnel_acl(privacy)),
+					..Default::default()
+				};
+				match mgmt
+					.update_tunnel_port(&locator, &port, NO_REQUEST_OPTIONS)
+					.await
+				{
+					Ok(_) => debug!(log, "Port {} detected as {}", port_number, protocol),
+					Err(e) => warning!(
+						log,
+						"Could not set the detected protocol of port {}: {}",
+						port_number,
+						e
+					),
+				}
+			});
+		}
+	}
+
 	/// Adds a port for TCP/IP forwarding.
 	pub async fn add_port_direct(
 		&self,
 		port_number: u16,
+	) -> Result<mpsc::UnboundedReceiver<ForwardedPortConnection>, WrappedError> {
+		self.add_port_raw(port_number, PortPrivacy::Private).await
+	}
+
+	/// Adds a port whose connections are given to the caller.
+	pub async fn add_port_raw(
+		&self,
+		port_number: u16,
+		privacy: PortPrivacy,
 	) -> Result<mpsc::UnboundedReceiver<ForwardedPortConnection>, WrappedError> {
 		self.relay
 			.lock()
@@ -1002,7 +1179,7 @@ impl ActiveTunnelManager {
 			.add_port_raw(&TunnelPort {
 				port_number,
 				protocol: Some(TUNNEL_PROTOCOL_AUTO.to_owned()),
-				access_control: Some(privacy_to_tunnel_acl(PortPrivacy::Private)),
+				access_control: Some(privacy_to_tunnel_acl(privacy)),
 				..Default::default()
 			})
 			.await
@@ -1060,7 +1237,7 @@ impl ActiveTunnelManager {
 		access_token_provider: impl AccessTokenProvider + 'static,
 		status: StatusLock,
 	) {
-		let mut token_ka = access_token_provider.keep_alive();
+		let mut token_ka = access_token_provider.keep_alive(status.clone());
 		let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(120));
 
 		macro_rules! fail {
@@ -1261,4 +1438,42 @@ mod test {
 		);
 		assert_eq!(clean_hostname_for_tunnel("z"), "remote-machine".to_string());
 	}
+
+	#[tokio::test]
+	async fn test_policy_disables_public_ports() {
+		// configures the policy, so it's run in a child
+		if !crate::util::test_env::in_child_with_env(
+			concat!(module_path!(), "::test_policy_disables_public_ports"),
+			&[],
+		) {
+			return;
+		}
+		policy::configure(policy::Policy {
+			source: Some("test".to_string()),
+			disable_public_ports: true,
+			..Default::default()
+		});
+
+		let tunnel = ActiveTunnel {
+			name: "test".to_string(),
+			id: "test".to_string(),
+			manager: ActiveTunnelManager::new(
+				log::Logger::test(),
+				new_tunnel_management(&TUNNEL_SERVICE_USER_AGENT).into(),
+				TunnelLocator::ID {
+					cluster: "test".to_string(),
+					id: "test".to_string(),
+				},
+				StaticAccessTokenProvider::new("token".to_string()),
+			),
+		};
+		let err = tunnel
+			.add_port_tcp(8080, PortPrivacy::Public, PortProtocol::Http)
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::DisabledByPolicy(PolicyFeature::PublicPorts, _))
+		));
+	}
 }
@@ -2,10 +2,9 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use crate::constants::IS_INTERACTIVE_CLI;
 use crate::state::{LauncherPaths, PersistedState};
 use crate::util::errors::{AnyError, CodeError};
-use crate::util::input::prompt_yn;
+use crate::util::input::{is_interactive, prompt_yn};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
@@ -26,7 +25,7 @@ pub fn require_consent(
 	accept_server_license_terms: bool,
 ) -> Result<(), AnyError> {
 	match &*LICENSE_TEXT {
-		Some(t) => println!("{}", t.join("\r\n")),
+		Some(t) => eprintln!("{}", t.join("\r\n")),
 		None => return Ok(()),
 	}
 
@@ -35,8 +34,7 @@ pub fn require_consent(
 		None => return Ok(()),
 	};
 
-	let license: PersistedState<PersistedConsent> =
-		PersistedState::new(paths.root().join("license_consent.json"));
+	let license = consent_state(paths);
 
 	let mut load = license.load();
 	if let Some(true) = load.consented {
@@ -45,10 +43,10 @@ pub fn require_consent(
 
 	if accept_server_license_terms {
 		load.consented = Some(true);
-	} else if !*IS_INTERACTIVE_CLI {
+	} else if !is_interactive() {
 		return Err(CodeError::NeedsInteractiveLegalConsent.into());
 	} else {
-		match prompt_yn(prompt) {
+		match prompt_yn(prompt, "--accept-server-license-terms") {
 			Ok(true) => {
 				load.consented = Some(true);
 			}
@@ -60,3 +58,59 @@ pub fn require_consent(
 	license.save(load)?;
 	Ok(())
 }
+
+/// Checks that consent was persisted when the service was installed. The
+/// service has no terminal to prompt on, so this never prompts or prints
+/// the license text.
+pub fn require_persisted_consent(paths: &LauncherPaths) -> Result<(), AnyError> {
+	if LICENSE_TEXT.is_none() || LICENSE_PROMPT.is_none() {
+		return Ok(());
+	}
+
+	check_persisted_consent(&consent_state(paths))?;
+	Ok(())
+}
+
+fn check_persisted_consent(license: &PersistedState<PersistedConsent>) -> Result<(), CodeError> {
+	match license.load().consented {
+		Some(true) => Ok(()),
+		_ => Err(CodeError::ServiceMissingLegalConsent),
+	}
+}
+
+fn consent_state(paths: &LauncherPaths) -> PersistedState<PersistedConsent> {
+	PersistedState::new(paths.root().join("license_consent.json"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_persisted_consent_does_not_prompt() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_path_buf());
+
+		// persisted by `service install`, and read back by the service, which
+		// has no terminal to prompt on
+		consent_state(&paths)
+			.save(PersistedConsent {
+				consented: Some(true),
+			})
+			.unwrap();
+
+		assert!(check_persisted_consent(&consent_state(&paths)).is_ok());
+		assert!(require_persisted_consent(&paths).is_ok());
+	}
+
+	#[test]
+	fn test_missing_persisted_consent() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_path_buf());
+
+		assert!(matches!(
+			check_persisted_consent(&consent_state(&paths)),
+			Err(CodeError::ServiceMissingLegalConsent)
+		));
+	}
+}
@@ -4,21 +4,30 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
-	fs::{read_dir, read_to_string, remove_dir_all, write},
+	collections::HashMap,
+	fs::{read_to_string, remove_dir_all, write},
 	path::PathBuf,
+	sync::Mutex,
 };
 
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+	log,
 	options::{self, Quality},
 	state::LauncherPaths,
 	util::{
-		errors::{wrap, AnyError, WrappedError},
+		command::kill_tree,
+		errors::{wrap, AnyError, CodeError, WrappedError},
+		io::dir_size,
 		machine,
 	},
 };
 
+use super::protocol::UninstallServerResult;
+
 pub const SERVER_FOLDER_NAME: &str = "server";
 
 pub struct ServerPaths {
@@ -35,15 +44,15 @@ pub struct ServerPaths {
 impl ServerPaths {
 	// Queries the system to determine the process ID of the running server.
 	// Returns the process ID, if the server is running.
-	pub fn get_running_pid(&self) -> Option<u32> {
+	pub fn get_running_pid(&self, log: &log::Logger) -> Option<u32> {
 		if let Some(pid) = self.read_pid() {
-			return match machine::process_at_path_exists(pid, &self.executable) {
+			return match machine::process_at_path_exists(log, pid, &self.executable) {
 				true => Some(pid),
 				false => None,
 			};
 		}
 
-		if let Some(pid) = machine::find_running_process(&self.executable) {
+		if let Some(pid) = machine::find_running_process(log, &self.executable) {
 			// attempt to backfill process ID:
 			self.write_pid(pid).ok();
 			return Some(pid);
@@ -79,7 +88,7 @@ impl ServerPaths {
 	}
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct InstalledServer {
 	pub quality: options::Quality,
 	pub commit: String,
@@ -107,53 +116,561 @@ impl InstalledServer {
 	}
 
 	fn get_install_folder(&self, p: &LauncherPaths) -> PathBuf {
-		p.server_cache.path().join(if !self.headless {
+		p.server_cache.path().join(self.folder_name())
+	}
+
+	/// Gets the name of the server's entry in the server cache.
+	fn folder_name(&self) -> String {
+		if !self.headless {
 			format!("{}-web", get_server_folder_name(self.quality, &self.commit))
 		} else {
 			get_server_folder_name(self.quality, &self.commit)
+		}
+	}
+
+	/// Parses the name of a server cache entry, returning None for names
+	/// that aren't of a server.
+	pub fn from_folder_name(name: &str) -> Option<InstalledServer> {
+		let (name, headless) = match name.strip_suffix("-web") {
+			Some(n) => (n, false),
+			None => (name, true),
+		};
+		let (quality, commit) = name.split_once('-')?;
+		if commit.is_empty() || !commit.chars().all(|c| c.is_ascii_alphanumeric()) {
+			return None;
+		}
+
+		Some(InstalledServer {
+			quality: options::Quality::try_from(quality.to_lowercase().as_str()).ok()?,
+			commit: commit.to_string(),
+			headless,
 		})
 	}
 }
 
-/// Prunes servers not currently running, and returns the deleted servers.
-pub fn prune_stopped_servers(launcher_paths: &LauncherPaths) -> Result<Vec<ServerPaths>, AnyError> {
-	get_all_servers(launcher_paths)
-		.into_iter()
-		.map(|s| s.server_paths(launcher_paths))
-		.filter(|s| s.get_running_pid().is_none())
-		.map(|s| s.delete().map(|_| s))
-		.collect::<Result<_, _>>()
-		.map_err(AnyError::from)
+/// A server build in the server cache.
+#[derive(Serialize, Clone, Debug)]
+pub struct InstalledBuild {
+	#[serde(flatten)]
+	pub server: InstalledServer,
+	/// Name of the build's entry in the server cache.
+	pub folder: String,
+	/// Bytes used by the build's folder.
+	pub size: u64,
+	/// Last time the build's server wrote its log or process ID, or the
+	/// folder was changed.
+	pub last_used: Option<DateTime<Utc>>,
+	/// Position in the cache's recently used list, starting at 0 for the
+	/// most recent. Unset for builds the cache doesn't track.
+	pub lru_rank: Option<usize>,
+	/// Whether the server executable exists. It's missing from builds whose
+	/// deletion was interrupted, which are never reported as running.
+	pub complete: bool,
+	pub running_pid: Option<u32>,
 }
 
-// Gets a list of all servers which look like they might be running.
-pub fn get_all_servers(lp: &LauncherPaths) -> Vec<InstalledServer> {
-	let mut servers: Vec<InstalledServer> = vec![];
-	if let Ok(children) = read_dir(lp.server_cache.path()) {
-		for child in children.flatten() {
-			let fname = child.file_name();
-			let fname = fname.to_string_lossy();
-			let (quality, commit) = match fname.split_once('-') {
-				Some(r) => r,
-				None => continue,
-			};
+/// Finds the server builds in the server cache, most recently used first.
+/// Entries that aren't of servers, such as foreign files or builds still
+/// being installed, are skipped.
+pub fn list_installed_builds(log: &log::Logger, lp: &LauncherPaths) -> Vec<InstalledBuild> {
+	let recent = lp.server_cache.recently_used();
+	let mut builds: Vec<InstalledBuild> = lp
+		.server_cache
+		.entries()
+		.into_iter()
+		.filter_map(|folder| {
+			let server = InstalledServer::from_folder_name(&folder)?;
+			let paths = server.server_paths(lp);
+			let complete = paths.executable.is_file();
+			Some(InstalledBuild {
+				size: dir_size(&paths.server_dir),
+				last_used: [&paths.logfile, &paths.pidfile, &paths.server_dir]
+					.into_iter()
+					.filter_map(|p| p.metadata().and_then(|m| m.modified()).ok())
+					.max()
+					.map(DateTime::<Utc>::from),
+				lru_rank: recent.iter().position(|r| r == &folder),
+				running_pid: if complete {
+					paths.get_running_pid(log)
+				} else {
+					None
+				},
+				complete,
+				server,
+				folder,
+			})
+		})
+		.collect();
 
-			let quality = match options::Quality::try_from(quality) {
-				Ok(q) => q,
-				Err(_) => continue,
-			};
+	builds.sort_by(|a, b| {
+		let rank = |b: &InstalledBuild| b.lru_rank.unwrap_or(usize::MAX);
+		rank(a)
+			.cmp(&rank(b))
+			.then_with(|| b.last_used.cmp(&a.last_used))
+	});
+	builds
+}
 
-			servers.push(InstalledServer {
-				quality,
-				commit: commit.to_string(),
-				headless: true,
-			});
+/// Prunes servers not currently running, and returns the deleted servers.
+/// Builds still being installed are left alone.
+pub fn prune_stopped_servers(
+	log: &log::Logger,
+	launcher_paths: &LauncherPaths,
+) -> Result<Vec<ServerPaths>, AnyError> {
+	let mut pruned = vec![];
+	for b in list_installed_builds(log, launcher_paths) {
+		if b.running_pid.is_none()
+			&& launcher_paths
+				.server_cache
+				.delete_unless_creating(&b.folder)?
+		{
+			pruned.push(b.server.server_paths(launcher_paths));
 		}
 	}
+	Ok(pruned)
+}
 
-	servers
+// Gets a list of all servers in the server cache.
+pub fn get_all_servers(lp: &LauncherPaths) -> Vec<InstalledServer> {
+	lp.server_cache
+		.entries()
+		.iter()
+		.filter_map(|f| InstalledServer::from_folder_name(f))
+		.collect()
 }
 
 pub fn get_server_folder_name(quality: Quality, commit: &str) -> String {
 	format!("{}-{}", quality, commit)
 }
+
+/// Counts the connections using each server commit, so that servers aren't
+/// uninstalled from under their clients.
+#[derive(Default)]
+pub struct ServerUsage(Mutex<HashMap<String, usize>>);
+
+impl ServerUsage {
+	/// Marks the commit as in use until the guard is dropped.
+	pub fn acquire(&'static self, commit: &str) -> ServerUsageGuard {
+		*self
+			.0
+			.lock()
+			.unwrap()
+			.entry(commit.to_string())
+			.or_default() += 1;
+		ServerUsageGuard {
+			usage: self,
+			commit: commit.to_string(),
+		}
+	}
+
+	/// Gets the number of connections using the commit.
+	pub fn count(&self, commit: &str) -> usize {
+		self.0.lock().unwrap().get(commit).copied().unwrap_or(0)
+	}
+}
+
+pub struct ServerUsageGuard {
+	usage: &'static ServerUsage,
+	commit: String,
+}
+
+impl Drop for ServerUsageGuard {
+	fn drop(&mut self) {
+		let mut counts = self.usage.0.lock().unwrap();
+		if let Some(n) = counts.get_mut(&self.commit) {
+			*n -= 1;
+			if *n == 0 {
+				counts.remove(&self.commit);
+			}
+		}
+	}
+}
+
+/// The installed servers an uninstall acts on, abstracted so that tests can
+/// fake servers and their processes.
+#[async_trait]
+pub trait ServerRegistry: Sync {
+	/// Gets the installed servers built from the commit.
+	fn find(&self, commit: &str) -> Vec<InstalledServer>;
+	/// Gets the process ID of the server, if it's running.
+	fn running_pid(&self, server: &InstalledServer) -> Option<u32>;
+	/// Gets the number of connections using servers of the commit.
+	fn connections(&self, commit: &str) -> usize;
+	async fn stop(&self, pid: u32) -> Result<(), AnyError>;
+	fn remove(&self, server: &InstalledServer) -> Result<(), AnyError>;
+}
+
+/// Servers in the launcher's server cache.
+pub struct CachedServerRegistry<'a> {
+	log: &'a log::Logger,
+	paths: &'a LauncherPaths,
+	usage: &'a ServerUsage,
+}
+
+impl<'a> CachedServerRegistry<'a> {
+	pub fn new(log: &'a log::Logger, paths: &'a LauncherPaths, usage: &'a ServerUsage) -> Self {
+		Self { log, paths, usage }
+	}
+}
+
+#[async_trait]
+impl<'a> ServerRegistry for CachedServerRegistry<'a> {
+	fn find(&self, commit: &str) -> Vec<InstalledServer> {
+		get_all_servers(self.paths)
+			.into_iter()
+			.filter(|s| s.commit == commit)
+			.collect()
+	}
+
+	fn running_pid(&self, server: &InstalledServer) -> Option<u32> {
+		// as in the list, builds with no executable are never running
+		let paths = server.server_paths(self.paths);
+		if !paths.executable.is_file() {
+			return None;
+		}
+		paths.get_running_pid(self.log)
+	}
+
+	fn connections(&self, commit: &str) -> usize {
+		self.usage.count(commit)
+	}
+
+	async fn stop(&self, pid: u32) -> Result<(), AnyError> {
+		kill_tree(pid).await?;
+		Ok(())
+	}
+
+	fn remove(&self, server: &InstalledServer) -> Result<(), AnyError> {
+		// through the cache, so that it also forgets the server
+		self.paths.server_cache.delete(&server.folder_name())?;
+		Ok(())
+	}
+}
+
+/// Stops and removes the installed servers of the commit, so that the next
+/// client to ask for it gets a fresh install. Servers that connections are
+/// using are only removed if `force` is set.
+pub async fn uninstall_server(
+	registry: &impl ServerRegistry,
+	commit: &str,
+	force: bool,
+) -> Result<UninstallServerResult, AnyError> {
+	let servers = registry.find(commit);
+	if servers.is_empty() {
+		return Err(CodeError::ServerNotInstalled(commit.to_string()).into());
+	}
+
+	let connections = registry.connections(commit);
+	if connections > 0 && !force {
+		return Err(CodeError::ServerInUse {
+			commit: commit.to_string(),
+			connections,
+		}
+		.into());
+	}
+
+	let mut result = UninstallServerResult::default();
+	for server in servers {
+		if let Some(pid) = registry.running_pid(&server) {
+			registry.stop(pid).await?;
+			result.stopped.push(pid);
+		}
+		registry.remove(&server)?;
+		result.removed.push(server.folder_name());
+	}
+
+	Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs::{create_dir_all, write};
+
+	/// Creates a cache with a complete build, a half-deleted one, one still
+	/// being installed, and foreign entries.
+	fn synthesize_cache() -> (tempfile::TempDir, LauncherPaths) {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::inspect(dir.path().to_path_buf());
+		let cache = lp.server_cache.path().to_path_buf();
+
+		let complete = InstalledServer::from_folder_name("Stable-aaa111").unwrap();
+		let exe = complete.server_paths(&lp).executable;
+		create_dir_all(exe.parent().unwrap()).unwrap();
+		write(&exe, vec![0; 100]).unwrap();
+		write(cache.join("Stable-aaa111").join("log.txt"), "hello").unwrap();
+		// a pid that isn't of the build's server
+		write(cache.join("Stable-aaa111").join("pid.txt"), "1").unwrap();
+
+		create_dir_all(cache.join("Insiders-bbb222-web").join("server")).unwrap();
+		write(cache.join("Insiders-bbb222-web").join("log.txt"), "hi").unwrap();
+
+		create_dir_all(cache.join("Stable-ccc333.staging").join("server")).unwrap();
+		create_dir_all(cache.join("Unknown-ddd444")).unwrap();
+		create_dir_all(cache.join("Stable-")).unwrap();
+		create_dir_all(cache.join("something")).unwrap();
+		write(cache.join("Stable-eee555"), "not a folder").unwrap();
+		write(
+			cache.join("lru.json"),
+			r#"["Insiders-bbb222-web","Gone-fff666","Stable-aaa111"]"#,
+		)
+		.unwrap();
+
+		(dir, lp)
+	}
+
+	#[test]
+	fn test_parses_folder_names() {
+		let s = InstalledServer::from_folder_name("Insiders-abc123-web").unwrap();
+		assert_eq!(s.quality, Quality::Insiders);
+		assert_eq!(s.commit, "abc123");
+		assert!(!s.headless);
+		assert_eq!(s.folder_name(), "Insiders-abc123-web");
+
+		let s = InstalledServer::from_folder_name("stable-abc123").unwrap();
+		assert_eq!(s.quality, Quality::Stable);
+		assert!(s.headless);
+
+		for name in [
+			"abc",
+			"Stable-",
+			"Nightly-abc",
+			"Stable-abc.staging",
+			"lru.json",
+		] {
+			assert!(
+				InstalledServer::from_folder_name(name).is_none(),
+				"{}",
+				name
+			);
+		}
+	}
+
+	#[test]
+	fn test_lists_installed_builds() {
+		let (_dir, lp) = synthesize_cache();
+
+		let builds = list_installed_builds(&log::Logger::test(), &lp);
+		let folders: Vec<_> = builds.iter().map(|b| b.folder.as_str()).collect();
+		assert_eq!(folders, vec!["Insiders-bbb222-web", "Stable-aaa111"]);
+
+		let partial = &builds[0];
+		assert!(!partial.complete);
+		assert_eq!(partial.lru_rank, Some(0));
+		assert_eq!(partial.size, 2);
+		assert!(partial.running_pid.is_none());
+
+		let complete = &builds[1];
+		assert!(complete.complete);
+		assert_eq!(complete.server.commit, "aaa111");
+		assert_eq!(complete.lru_rank, Some(2));
+		assert_eq!(complete.size, 106);
+		assert!(complete.last_used.is_some());
+		assert!(complete.running_pid.is_none());
+	}
+
+	#[test]
+	fn test_lists_builds_of_a_missing_cache() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::inspect(dir.path().join("nothing"));
+		assert!(list_installed_builds(&log::Logger::test(), &lp).is_empty());
+	}
+
+	#[test]
+	fn test_prunes_stopped_builds() {
+		let (_dir, lp) = synthesize_cache();
+		let log = log::Logger::test();
+
+		let pruned = prune_stopped_servers(&log, &lp).unwrap();
+		assert_eq!(pruned.len(), 2);
+		assert!(list_installed_builds(&log, &lp).is_empty());
+		assert_eq!(lp.server_cache.recently_used(), vec!["Gone-fff666"]);
+
+		// foreign entries are left alone
+		let cache = lp.server_cache.path();
+		assert!(cache.join("something").exists());
+		assert!(cache.join("Stable-eee555").exists());
+		assert!(cache.join("Stable-ccc333.staging").exists());
+	}
+
+	#[tokio::test]
+	async fn test_prune_skips_builds_being_installed() {
+		let (_dir, lp) = synthesize_cache();
+		let log = log::Logger::test();
+		let _installing = crate::util::file_lock::acquire(
+			&lp.server_cache.path().join("Stable-aaa111.lock"),
+			std::time::Duration::from_secs(1),
+		)
+		.await
+		.unwrap();
+
+		let pruned = prune_stopped_servers(&log, &lp).unwrap();
+		assert_eq!(pruned.len(), 1);
+		let left: Vec<_> = list_installed_builds(&log, &lp)
+			.into_iter()
+			.map(|b| b.folder)
+			.collect();
+		assert_eq!(left, vec!["Stable-aaa111"]);
+
+		// the lock taken to prune a build isn't left behind
+		#[cfg(unix)]
+		assert!(!lp
+			.server_cache
+			.path()
+			.join("Insiders-bbb222-web.lock")
+			.exists());
+	}
+
+	#[test]
+	fn test_registry_finds_builds_of_commit() {
+		let (_dir, lp) = synthesize_cache();
+		let usage = ServerUsage::default();
+		let log = log::Logger::test();
+		let registry = CachedServerRegistry::new(&log, &lp, &usage);
+
+		let found = registry.find("bbb222");
+		assert_eq!(found.len(), 1);
+		assert!(!found[0].headless);
+		assert!(registry.running_pid(&found[0]).is_none());
+		assert!(registry.find("ccc333").is_empty());
+	}
+
+	#[derive(Default)]
+	struct FakeRegistry {
+		servers: Vec<(InstalledServer, Option<u32>)>,
+		connections: usize,
+		log: Mutex<Vec<String>>,
+	}
+
+	impl FakeRegistry {
+		fn with_server(mut self, quality: Quality, headless: bool, pid: Option<u32>) -> Self {
+			self.servers.push((
+				InstalledServer {
+					quality,
+					commit: "abc123".to_string(),
+					headless,
+				},
+				pid,
+			));
+			self
+		}
+
+		fn log(&self) -> Vec<String> {
+			self.log.lock().unwrap().clone()
+		}
+	}
+
+	#[async_trait]
+	impl ServerRegistry for FakeRegistry {
+		fn find(&self, commit: &str) -> Vec<InstalledServer> {
+			self.servers
+				.iter()
+				.filter(|(s, _)| s.commit == commit)
+				.map(|(s, _)| s.clone())
+				.collect()
+		}
+
+		fn running_pid(&self, server: &InstalledServer) -> Option<u32> {
+			self.servers
+				.iter()
+				.find(|(s, _)| s == server)
+				.and_then(|(_, pid)| *pid)
+		}
+
+		fn connections(&self, _commit: &str) -> usize {
+			self.connections
+		}
+
+		async fn stop(&self, pid: u32) -> Result<(), AnyError> {
+			self.log.lock().unwrap().push(format!("stop {}", pid));
+			Ok(())
+		}
+
+		fn remove(&self, server: &InstalledServer) -> Result<(), AnyError> {
+			self.log
+				.lock()
+				.unwrap()
+				.push(format!("remove {}", server.folder_name()));
+			Ok(())
+		}
+	}
+
+	#[tokio::test]
+	async fn test_uninstalls_stopped_server() {
+		let registry = FakeRegistry::default().with_server(Quality::Stable, true, None);
+
+		let result = uninstall_server(&registry, "abc123", false).await.unwrap();
+		assert_eq!(
+			result,
+			UninstallServerResult {
+				stopped: vec![],
+				removed: vec!["Stable-abc123".to_string()],
+			}
+		);
+		assert_eq!(registry.log(), vec!["remove Stable-abc123"]);
+	}
+
+	#[tokio::test]
+	async fn test_stops_running_server_before_removing() {
+		let registry = FakeRegistry::default()
+			.with_server(Quality::Stable, true, Some(42))
+			.with_server(Quality::Stable, false, None);
+
+		let result = uninstall_server(&registry, "abc123", false).await.unwrap();
+		assert_eq!(result.stopped, vec![42]);
+		assert_eq!(
+			registry.log(),
+			vec![
+				"stop 42",
+				"remove Stable-abc123",
+				"remove Stable-abc123-web"
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_requires_force_with_connections() {
+		let mut registry = FakeRegistry::default().with_server(Quality::Insiders, true, Some(7));
+		registry.connections = 2;
+
+		let err = uninstall_server(&registry, "abc123", false)
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::ServerInUse { connections: 2, .. })
+		));
+		assert!(registry.log().is_empty());
+
+		uninstall_server(&registry, "abc123", true).await.unwrap();
+		assert_eq!(registry.log(), vec!["stop 7", "remove Insiders-abc123"]);
+	}
+
+	#[tokio::test]
+	async fn test_fails_for_unknown_commit() {
+		let registry = FakeRegistry::default().with_server(Quality::Stable, true, None);
+
+		let err = uninstall_server(&registry, "def456", false)
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::ServerNotInstalled(_))
+		));
+	}
+
+	#[test]
+	fn test_usage_counts_until_dropped() {
+		lazy_static::lazy_static! {
+			static ref USAGE: ServerUsage = ServerUsage::default();
+		}
+
+		let a = USAGE.acquire("abc123");
+		let b = USAGE.acquire("abc123");
+		assert_eq!(USAGE.count("abc123"), 2);
+		drop(a);
+		assert_eq!(USAGE.count("abc123"), 1);
+		drop(b);
+		assert_eq!(USAGE.count("abc123"), 0);
+	}
+}
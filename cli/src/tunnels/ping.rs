@@ -0,0 +1,305 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	sync::mpsc,
+};
+
+use crate::{
+	log,
+	msgpack_rpc::{new_msgpack_rpc, start_msgpack_rpc, MsgPackSerializer},
+	rpc::{ResponseError, RpcCaller, Serialization},
+	util::{
+		errors::CodeError,
+		sync::{new_barrier, BarrierOpener},
+	},
+};
+
+use super::protocol::{PingParams, PingResult};
+
+fn now_us() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_micros() as u64)
+		.unwrap_or(0)
+}
+
+/// Answers a `ping`, echoing the client's timestamp along with the times the
+/// host received and replied to it.
+pub fn handle_ping(params: PingParams) -> PingResult {
+	let host_received_us = now_us();
+	PingResult {
+		client_time_us: params.client_time_us,
+		host_received_us,
+		host_sent_us: now_us(),
+	}
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingSample {
+	/// Round-trip time, excluding the time the host spent on the request.
+	pub rtt_us: u64,
+	/// Estimated offset of the host's clock from ours.
+	pub offset_us: i64,
+}
+
+impl PingSample {
+	/// Computes the sample like NTP does, from when the ping was sent, when
+	/// the host received and answered it, and when the answer arrived.
+	pub fn new(sent_us: u64, result: &PingResult, received_us: u64) -> Self {
+		let (t0, t1, t2, t3) = (
+			sent_us as i64,
+			result.host_received_us as i64,
+			result.host_sent_us as i64,
+			received_us as i64,
+		);
+
+		PingSample {
+			rtt_us: ((t3 - t0) - (t2 - t1)).max(0) as u64,
+			offset_us: ((t1 - t0) + (t2 - t3)) / 2,
+		}
+	}
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PingSummary {
+	pub count: usize,
+	pub min_rtt_ms: f64,
+	pub avg_rtt_ms: f64,
+	pub max_rtt_ms: f64,
+	/// Offset of the sample with the lowest round-trip time, which is the
+	/// least affected by asymmetric delays.
+	pub clock_skew_ms: f64,
+}
+
+impl PingSummary {
+	pub fn new(samples: &[PingSample]) -> Option<Self> {
+		let fastest = samples.iter().min_by_key(|s| s.rtt_us)?;
+		let slowest = samples.iter().map(|s| s.rtt_us).max()?;
+		let total: u64 = samples.iter().map(|s| s.rtt_us).sum();
+
+		Some(PingSummary {
+			count: samples.len(),
+			min_rtt_ms: fastest.rtt_us as f64 / 1000.0,
+			avg_rtt_ms: total as f64 / samples.len() as f64 / 1000.0,
+			max_rtt_ms: slowest as f64 / 1000.0,
+			clock_skew_ms: fastest.offset_us as f64 / 1000.0,
+		})
+	}
+}
+
+/// Pings the remote `count` times, one after another with the interval in
+/// between.
+pub async fn measure_ping<S: Serialization>(
+	caller: &RpcCaller<S>,
+	method: &'static str,
+	count: usize,
+	interval: Duration,
+) -> Result<Vec<PingSample>, CodeError> {
+	let mut samples = Vec::with_capacity(count);
+	for i in 0..count {
+		if i > 0 {
+			tokio::time::sleep(interval).await;
+		}
+
+		let sent_us = now_us();
+		let result: PingResult = caller
+			.call(
+				method,
+				PingParams {
+					client_time_us: Some(sent_us),
+				},
+			)
+			.await
+			.map_err(|_| {
+				CodeError::TunnelRpcCallFailed(ResponseError {
+					code: 0,
+					message: "connection closed".to_string(),
+				})
+			})?
+			.map_err(CodeError::TunnelRpcCallFailed)?;
+		samples.push(PingSample::new(sent_us, &result, now_us()));
+	}
+
+	Ok(samples)
+}
+
+/// Connection to a control server, such as one of `command-shell`, over
+/// which pings travel the same way as the requests of an editor. Pinging
+/// doesn't need the connection to be authenticated. The connection is closed
+/// once this is dropped.
+pub struct ControlConnection {
+	pub caller: RpcCaller<MsgPackSerializer>,
+	_close: BarrierOpener<()>,
+}
+
+impl ControlConnection {
+	pub fn new(
+		log: log::Logger,
+		read: impl AsyncRead + Send + Unpin + 'static,
+		write: impl AsyncWrite + Send + Unpin + 'static,
+	) -> Self {
+		let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+		let (closed, close) = new_barrier();
+		let mut rpc = new_msgpack_rpc();
+		let caller = rpc.get_caller(msg_tx);
+		tokio::spawn(start_msgpack_rpc(
+			rpc.methods(()).build(log),
+			read,
+			write,
+			msg_rx,
+			closed,
+		));
+
+		Self {
+			caller,
+			_close: close,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::sync::mpsc;
+
+	use super::*;
+	use crate::{
+		json_rpc::{new_json_rpc, start_json_rpc},
+		log,
+		tunnels::protocol::METHOD_PING,
+		util::sync::new_barrier,
+	};
+
+	#[test]
+	fn test_sample() {
+		// the host's clock is 500us ahead, and it took 100us to reply
+		let result = PingResult {
+			client_time_us: Some(1000),
+			host_received_us: 1000 + 500 + 200,
+			host_sent_us: 1000 + 500 + 300,
+		};
+
+		assert_eq!(
+			PingSample::new(1000, &result, 1000 + 500),
+			PingSample {
+				rtt_us: 400,
+				offset_us: 500,
+			}
+		);
+	}
+
+	#[test]
+	fn test_summary() {
+		let samples = [
+			PingSample {
+				rtt_us: 3000,
+				offset_us: 900,
+			},
+			PingSample {
+				rtt_us: 1000,
+				offset_us: 100,
+			},
+			PingSample {
+				rtt_us: 2000,
+				offset_us: -400,
+			},
+		];
+
+		assert_eq!(
+			PingSummary::new(&samples),
+			Some(PingSummary {
+				count: 3,
+				min_rtt_ms: 1.0,
+				avg_rtt_ms: 2.0,
+				max_rtt_ms: 3.0,
+				clock_skew_ms: 0.1,
+			})
+		);
+		assert_eq!(PingSummary::new(&[]), None);
+	}
+
+	#[tokio::test]
+	async fn test_ping_over_duplex() {
+		let mut server = new_json_rpc().methods(());
+		server.register_sync(METHOD_PING, |p: PingParams, _| Ok(handle_ping(p)));
+
+		let (server_io, client_io) = tokio::io::duplex(4096);
+		let (closed, _close) = new_barrier::<()>();
+		let (read, write) = tokio::io::split(server_io);
+		tokio::spawn(start_json_rpc(
+			server.build(log::Logger::test()),
+			read,
+			write,
+			(),
+			closed.clone(),
+		));
+
+		let mut client = new_json_rpc();
+		let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+		let caller = client.get_caller(msg_tx);
+		let (read, write) = tokio::io::split(client_io);
+		tokio::spawn(start_json_rpc(
+			client.methods(()).build(log::Logger::test()),
+			read,
+			write,
+			msg_rx,
+			closed,
+		));
+
+		let samples = measure_ping(&caller, METHOD_PING, 3, Duration::from_millis(1))
+			.await
+			.unwrap();
+		assert_eq!(samples.len(), 3);
+
+		// same clock on both sides, so there should be next to no skew
+		let summary = PingSummary::new(&samples).unwrap();
+		assert!(summary.clock_skew_ms.abs() < 1000.0);
+		assert!(summary.min_rtt_ms <= summary.max_rtt_ms);
+	}
+
+	#[tokio::test]
+	async fn test_ping_control_connection() {
+		let mut server = new_msgpack_rpc().methods(());
+		server.register_sync(METHOD_PING, |p: PingParams, _| Ok(handle_ping(p)));
+
+		let (server_io, client_io) = tokio::io::duplex(4096);
+		let (closed, _close_server) = new_barrier::<()>();
+		let (read, write) = tokio::io::split(server_io);
+		tokio::spawn(start_msgpack_rpc(
+			server.build(log::Logger::test()),
+			read,
+			write,
+			(),
+			closed,
+		));
+
+		let (read, write) = tokio::io::split(client_io);
+		let connection = ControlConnection::new(log::Logger::test(), read, write);
+		let samples = measure_ping(&connection.caller, METHOD_PING, 2, Duration::from_millis(1))
+			.await
+			.unwrap();
+		assert_eq!(samples.len(), 2);
+	}
+
+	#[tokio::test]
+	async fn test_ping_closed_connection() {
+		let mut rpc = new_msgpack_rpc();
+		let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+		let caller = rpc.get_caller(msg_tx);
+		drop(msg_rx);
+
+		// fails rather than panicking
+		let r = measure_ping(&caller, METHOD_PING, 1, Duration::ZERO).await;
+		assert!(
+			matches!(r, Err(CodeError::TunnelRpcCallFailed(_))),
+			"{:?}",
+			r
+		);
+	}
+}
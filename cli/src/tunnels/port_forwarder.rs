@@ -3,9 +3,17 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::collections::HashSet;
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
 
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::TcpStream,
+	sync::{mpsc, oneshot},
+};
+use tunnels::connections::ForwardedPortConnection;
 
 use crate::{
 	constants::CONTROL_PORT,
@@ -18,8 +26,69 @@ use super::{
 };
 
 pub enum PortForwardingRec {
-	Forward(u16, PortPrivacy, oneshot::Sender<Result<String, AnyError>>),
-	Unforward(u16, oneshot::Sender<Result<(), AnyError>>),
+	Forward(
+		u16,
+		PortPrivacy,
+		PortProtocol,
+		ForwardedBy,
+		oneshot::Sender<Result<String, AnyError>>,
+	),
+	Unforward(u16, ForwardedBy, oneshot::Sender<Result<(), AnyError>>),
+}
+
+/// Who asked for a port to be forwarded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardedBy {
+	/// A client, which may unforward any port.
+	Client,
+	/// The port watcher, after the server began listening on the port. It
+	/// only unforwards ports that it forwarded and no client also asked for.
+	Detection,
+}
+
+/// Tracks who each forwarded port was forwarded by.
+#[derive(Default)]
+struct ForwardedPorts(HashMap<u16, ForwardedBy>);
+
+impl ForwardedPorts {
+	/// Records a forward, returning whether the port must be added to the
+	/// tunnel. A client asking for a detected port takes it over.
+	fn forward(&mut self, port: u16, by: ForwardedBy) -> bool {
+		match self.0.get(&port) {
+			None => {
+				self.0.insert(port, by);
+				true
+			}
+			Some(_) => {
+				if by == ForwardedBy::Client {
+					self.0.insert(port, by);
+				}
+				false
+			}
+		}
+	}
+
+	/// Records an unforward, returning whether the port must be removed from
+	/// the tunnel.
+	fn unforward(&mut self, port: u16, by: ForwardedBy) -> bool {
+		if by == ForwardedBy::Detection && self.0.get(&port) != Some(&ForwardedBy::Detection) {
+			return false;
+		}
+		self.0.remove(&port);
+		true
+	}
+
+	/// Gets the ports forwarded only because they were detected.
+	fn detected(&self) -> Vec<u16> {
+		let mut ports: Vec<u16> = self
+			.0
+			.iter()
+			.filter(|(_, by)| **by == ForwardedBy::Detection)
+			.map(|(port, _)| *port)
+			.collect();
+		ports.sort_unstable();
+		ports
+	}
 }
 
 /// Provides a port forwarding service for connected clients. Clients can make
@@ -28,7 +97,7 @@ pub enum PortForwardingRec {
 pub struct PortForwardingProcessor {
 	tx: mpsc::Sender<PortForwardingRec>,
 	rx: mpsc::Receiver<PortForwardingRec>,
-	forwarded: HashSet<u16>,
+	forwarded: ForwardedPorts,
 }
 
 impl PortForwardingProcessor {
@@ -37,7 +106,7 @@ impl PortForwardingProcessor {
 		Self {
 			tx,
 			rx,
-			forwarded: HashSet::new(),
+			forwarded: ForwardedPorts::default(),
 		}
 	}
 
@@ -57,27 +126,42 @@ impl PortForwardingProcessor {
 	/// Processes the incoming forwarding request.
 	pub async fn process(&mut self, req: PortForwardingRec, tunnel: &mut ActiveTunnel) {
 		match req {
-			PortForwardingRec::Forward(port, privacy, tx) => {
-				tx.send(self.process_forward(port, privacy, tunnel).await)
-					.ok();
+			PortForwardingRec::Forward(port, privacy, protocol, by, tx) => {
+				tx.send(
+					self.process_forward(port, privacy, protocol, by, tunnel)
+						.await,
+				)
+				.ok();
 			}
-			PortForwardingRec::Unforward(port, tx) => {
-				tx.send(self.process_unforward(port, tunnel).await).ok();
+			PortForwardingRec::Unforward(port, by, tx) => {
+				tx.send(self.process_unforward(port, by, tunnel).await).ok();
 			}
 		}
 	}
 
+	/// Removes the ports that were only forwarded because they were detected,
+	/// for when the tunnel stops serving them.
+	pub async fn unforward_detected(&mut self, tunnel: &mut ActiveTunnel) {
+		for port in self.forwarded.detected() {
+			let _ = self
+				.process_unforward(port, ForwardedBy::Detection, tunnel)
+				.await;
+		}
+	}
+
 	async fn process_unforward(
 		&mut self,
 		port: u16,
+		by: ForwardedBy,
 		tunnel: &mut ActiveTunnel,
 	) -> Result<(), AnyError> {
 		if port == CONTROL_PORT {
 			return Err(CannotForwardControlPort().into());
 		}
 
-		tunnel.remove_port(port).await?;
-		self.forwarded.remove(&port);
+		if self.forwarded.unforward(port, by) {
+			tunnel.remove_port(port).await?;
+		}
 		Ok(())
 	}
 
@@ -85,17 +169,19 @@ impl PortForwardingProcessor {
 		&mut self,
 		port: u16,
 		privacy: PortPrivacy,
+		protocol: PortProtocol,
+		by: ForwardedBy,
 		tunnel: &mut ActiveTunnel,
 	) -> Result<String, AnyError> {
 		if port == CONTROL_PORT {
 			return Err(CannotForwardControlPort().into());
 		}
 
-		if !self.forwarded.contains(&port) {
-			tunnel
-				.add_port_tcp(port, privacy, PortProtocol::Auto)
-				.await?;
-			self.forwarded.insert(port);
+		if self.forwarded.forward(port, by) {
+			if let Err(e) = tunnel.add_port_tcp(port, privacy, protocol).await {
+				self.forwarded.unforward(port, by);
+				return Err(e);
+			}
 		}
 
 		tunnel.get_port_uri(port)
@@ -108,9 +194,15 @@ pub struct PortForwarding {
 }
 
 impl PortForwarding {
-	pub async fn forward(&self, port: u16, privacy: PortPrivacy) -> Result<String, AnyError> {
+	pub async fn forward(
+		&self,
+		port: u16,
+		privacy: PortPrivacy,
+		protocol: PortProtocol,
+		by: ForwardedBy,
+	) -> Result<String, AnyError> {
 		let (tx, rx) = oneshot::channel();
-		let req = PortForwardingRec::Forward(port, privacy, tx);
+		let req = PortForwardingRec::Forward(port, privacy, protocol, by, tx);
 
 		if self.tx.send(req).await.is_err() {
 			return Err(ServerHasClosed().into());
@@ -122,9 +214,9 @@ impl PortForwarding {
 		}
 	}
 
-	pub async fn unforward(&self, port: u16) -> Result<(), AnyError> {
+	pub async fn unforward(&self, port: u16, by: ForwardedBy) -> Result<(), AnyError> {
 		let (tx, rx) = oneshot::channel();
-		let req = PortForwardingRec::Unforward(port, tx);
+		let req = PortForwardingRec::Unforward(port, by, tx);
 
 		if self.tx.send(req).await.is_err() {
 			return Err(ServerHasClosed().into());
@@ -136,3 +228,214 @@ impl PortForwarding {
 		}
 	}
 }
+
+/// Guesses the protocol a client is speaking from the first bytes it sent:
+/// a TLS handshake record for HTTPS, or an HTTP method for HTTP.
+pub fn sniff_protocol(first: &[u8]) -> Option<PortProtocol> {
+	// content type 22 (handshake), then a 3.x protocol version
+	if first.len() >= 3 && first[0] == 0x16 && first[1] == 0x03 && first[2] <= 0x04 {
+		return Some(PortProtocol::Https);
+	}
+
+	const METHODS: &[&[u8]] = &[
+		b"GET ",
+		b"HEAD ",
+		b"POST ",
+		b"PUT ",
+		b"DELETE ",
+		b"OPTIONS ",
+		b"PATCH ",
+		b"CONNECT ",
+		b"TRACE ",
+		b"PRI * HTTP/2",
+	];
+	if METHODS.iter().any(|m| first.starts_with(m)) {
+		return Some(PortProtocol::Http);
+	}
+
+	None
+}
+
+/// Forwards the tunnel's connections to the port on localhost, until the
+/// port is removed. The protocol is detected from the first connection that
+/// starts with something recognizable, and passed to `on_detected`.
+pub async fn forward_detecting_protocol(
+	port: u16,
+	mut connections: mpsc::UnboundedReceiver<ForwardedPortConnection>,
+	on_detected: impl FnOnce(PortProtocol) + Send + 'static,
+) {
+	let on_detected = Arc::new(Mutex::new(Some(on_detected)));
+	while let Some(connection) = connections.recv().await {
+		let (write, read) = connection.into_split();
+		let on_detected = on_detected.clone();
+		tokio::spawn(async move {
+			let _ = relay_to_local(port, read, write, move |first| {
+				if let Some(protocol) = sniff_protocol(first) {
+					if let Some(f) = on_detected.lock().unwrap().take() {
+						f(protocol);
+					}
+				}
+			})
+			.await;
+		});
+	}
+}
+
+/// Copies between the tunnel connection and a new connection to the local
+/// port, passing the first bytes the client sent to `on_first`. Both
+/// directions are relayed from the start, since with protocols like SSH the
+/// server speaks first.
+async fn relay_to_local(
+	port: u16,
+	mut read: impl AsyncRead + Unpin,
+	mut write: impl AsyncWrite + Unpin,
+	on_first: impl FnOnce(&[u8]),
+) -> std::io::Result<()> {
+	let mut local = TcpStream::connect(("127.0.0.1", port)).await?;
+	let (mut local_read, mut local_write) = local.split();
+
+	let to_local = async {
+		let mut first = vec![0; 4096];
+		let n = read.read(&mut first).await?;
+		on_first(&first[..n]);
+		if n > 0 {
+			local_write.write_all(&first[..n]).await?;
+			tokio::io::copy(&mut read, &mut local_write).await?;
+		}
+		local_write.shutdown().await
+	};
+	let from_local = async {
+		tokio::io::copy(&mut local_read, &mut write).await?;
+		write.shutdown().await
+	};
+	tokio::try_join!(to_local, from_local)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::net::TcpListener;
+
+	use super::*;
+
+	#[test]
+	fn test_forwarded_ports_track_who_forwarded() {
+		let mut ports = ForwardedPorts::default();
+		assert!(ports.forward(3000, ForwardedBy::Detection));
+		assert!(ports.forward(8080, ForwardedBy::Client));
+		assert!(!ports.forward(8080, ForwardedBy::Detection));
+		assert_eq!(ports.detected(), vec![3000]);
+
+		// detection doesn't remove a port a client forwarded
+		assert!(!ports.unforward(8080, ForwardedBy::Detection));
+		assert!(!ports.unforward(9000, ForwardedBy::Detection));
+
+		// a client asking for a detected port keeps it forwarded
+		assert!(ports.forward(5173, ForwardedBy::Detection));
+		assert!(!ports.forward(5173, ForwardedBy::Client));
+		assert!(!ports.unforward(5173, ForwardedBy::Detection));
+		assert_eq!(ports.detected(), vec![3000]);
+
+		assert!(ports.unforward(3000, ForwardedBy::Detection));
+		assert!(ports.unforward(8080, ForwardedBy::Client));
+		assert!(ports.detected().is_empty());
+	}
+
+	#[test]
+	fn test_sniff_protocol() {
+		// start of a TLS 1.2 ClientHello record
+		let hello = [
+			0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03, 0x03,
+		];
+		assert_eq!(sniff_protocol(&hello), Some(PortProtocol::Https));
+		assert_eq!(
+			sniff_protocol(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+			Some(PortProtocol::Http)
+		);
+		assert_eq!(
+			sniff_protocol(b"OPTIONS * HTTP/1.1\r\n"),
+			Some(PortProtocol::Http)
+		);
+		assert_eq!(
+			sniff_protocol(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"),
+			Some(PortProtocol::Http)
+		);
+		assert_eq!(sniff_protocol(b"SSH-2.0-OpenSSH_9.0\r\n"), None);
+		assert_eq!(sniff_protocol(b"GETX"), None);
+		assert_eq!(sniff_protocol(&[0x16, 0x03]), None);
+		assert_eq!(sniff_protocol(&[]), None);
+	}
+
+	#[tokio::test]
+	async fn test_relays_to_local_port() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			let (mut s, _) = listener.accept().await.unwrap();
+			let mut buf = vec![0; 18];
+			s.read_exact(&mut buf).await.unwrap();
+			assert_eq!(buf, b"GET / HTTP/1.1\r\n\r\n");
+			s.write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+				.await
+				.unwrap();
+		});
+
+		let (client, tunnel_side) = tokio::io::duplex(1024);
+		let (mut client_read, mut client_write) = tokio::io::split(client);
+		let (read, write) = tokio::io::split(tunnel_side);
+		client_write
+			.write_all(b"GET / HTTP/1.1\r\n\r\n")
+			.await
+			.unwrap();
+		client_write.shutdown().await.unwrap();
+
+		let mut sniffed = None;
+		relay_to_local(port, read, write, |first| sniffed = sniff_protocol(first))
+			.await
+			.unwrap();
+		assert_eq!(sniffed, Some(PortProtocol::Http));
+
+		let mut response = String::new();
+		client_read.read_to_string(&mut response).await.unwrap();
+		assert_eq!(response, "HTTP/1.1 204 No Content\r\n\r\n");
+	}
+
+	#[tokio::test]
+	async fn test_relays_server_that_speaks_first() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			let (mut s, _) = listener.accept().await.unwrap();
+			s.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+			let mut buf = vec![0; 9];
+			s.read_exact(&mut buf).await.unwrap();
+			assert_eq!(buf, b"SSH-2.0-c");
+		});
+
+		let (client, tunnel_side) = tokio::io::duplex(1024);
+		let (mut client_read, mut client_write) = tokio::io::split(client);
+		let (read, write) = tokio::io::split(tunnel_side);
+		let relay = tokio::spawn(async move {
+			let mut sniffed = None;
+			relay_to_local(port, read, write, |first| sniffed = sniff_protocol(first))
+				.await
+				.unwrap();
+			sniffed
+		});
+
+		// the banner arrives before the client has sent anything
+		let mut banner = vec![0; 14];
+		tokio::time::timeout(
+			std::time::Duration::from_secs(5),
+			client_read.read_exact(&mut banner),
+		)
+		.await
+		.expect("expected the server's banner")
+		.unwrap();
+		assert_eq!(banner, b"SSH-2.0-test\r\n");
+
+		client_write.write_all(b"SSH-2.0-c").await.unwrap();
+		client_write.shutdown().await.unwrap();
+		assert_eq!(relay.await.unwrap(), None);
+	}
+}
@@ -0,0 +1,528 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
+
+use crate::{
+	constants::{DEFAULT_AUTO_FORWARD_GRACE_PERIOD, DEFAULT_AUTO_FORWARD_SCAN_INTERVAL},
+	log,
+	util::errors::AnyError,
+};
+
+use super::protocol::{PortClosedParams, PortDetectedParams, PortPrivacy};
+
+/// How the tunnel announces, and optionally forwards, ports that the server
+/// or processes it started begin listening on.
+#[derive(Clone, Debug)]
+pub struct AutoForwardPolicy {
+	/// Privacy to forward detected ports with, or None to only announce them.
+	forward: Option<PortPrivacy>,
+	excluded: HashSet<u16>,
+	interval: Duration,
+	/// How long a port must have stopped listening before it's unforwarded,
+	/// so that servers restarting in watch mode keep their port.
+	grace_period: Duration,
+}
+
+impl AutoForwardPolicy {
+	pub fn new(forward: Option<PortPrivacy>, excluded: &[u16]) -> Self {
+		Self {
+			forward,
+			excluded: excluded.iter().copied().collect(),
+			interval: DEFAULT_AUTO_FORWARD_SCAN_INTERVAL,
+			grace_period: DEFAULT_AUTO_FORWARD_GRACE_PERIOD,
+		}
+	}
+
+	pub fn with_timing(mut self, interval: Duration, grace_period: Duration) -> Self {
+		self.interval = interval;
+		self.grace_period = grace_period;
+		self
+	}
+}
+
+/// Finds the TCP ports a process tree listens on, abstracted so that tests
+/// can script what's listening.
+pub trait ListeningPortScanner: Send + Sync + 'static {
+	/// Gets the ports the process, or any of its descendants, listen on.
+	fn scan(&self, root_pid: u32) -> std::io::Result<HashSet<u16>>;
+}
+
+/// What a watcher reports to, which is shared by the tunnel's connections.
+#[async_trait]
+pub trait PortWatchHost: Send {
+	/// Gets the PIDs of the running servers whose processes are watched.
+	async fn server_pids(&self) -> Vec<u32>;
+	/// Forwards the port, returning its URI.
+	async fn forward(&mut self, port: u16, privacy: PortPrivacy) -> Result<String, AnyError>;
+	async fn unforward(&mut self, port: u16) -> Result<(), AnyError>;
+	fn port_detected(&mut self, params: PortDetectedParams);
+	fn port_closed(&mut self, params: PortClosedParams);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PortChange {
+	Opened(u16),
+	Closed(u16),
+}
+
+/// Tracks which ports are listening across scans.
+#[derive(Default)]
+struct PortWatchState {
+	/// Ports seen listening, with the time they were first missed since.
+	known: HashMap<u16, Option<Instant>>,
+}
+
+impl PortWatchState {
+	fn update(
+		&mut self,
+		now: Instant,
+		listening: &HashSet<u16>,
+		policy: &AutoForwardPolicy,
+	) -> Vec<PortChange> {
+		let mut changes = vec![];
+		for port in listening {
+			if policy.excluded.contains(port) {
+				continue;
+			}
+			if self.known.insert(*port, None).is_none() {
+				changes.push(PortChange::Opened(*port));
+			}
+		}
+
+		self.known.retain(|port, missing_since| {
+			if listening.contains(port) {
+				return true;
+			}
+			let since = missing_since.get_or_insert(now);
+			if now.duration_since(*since) >= policy.grace_period {
+				changes.push(PortChange::Closed(*port));
+				return false;
+			}
+			true
+		});
+
+		changes.sort_by_key(|c| match c {
+			PortChange::Closed(p) => (0, *p),
+			PortChange::Opened(p) => (1, *p),
+		});
+		changes
+	}
+}
+
+/// Scans for ports the host's servers listen on until the future is dropped,
+/// announcing ports as they open and close. Ports it forwarded stay forwarded
+/// when it's dropped, so the host must unforward them.
+pub async fn watch_ports(
+	log: &log::Logger,
+	policy: &AutoForwardPolicy,
+	scanner: Arc<dyn ListeningPortScanner>,
+	host: &mut impl PortWatchHost,
+) {
+	let mut state = PortWatchState::default();
+	let mut forwarded = HashSet::new();
+	let mut interval = tokio::time::interval(policy.interval);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+	loop {
+		interval.tick().await;
+
+		// once a server exits, its ports close like any others
+		let pids = host.server_pids().await;
+		let scanner = scanner.clone();
+		let scanned = tokio::task::spawn_blocking(move || {
+			let mut ports = HashSet::new();
+			for pid in pids {
+				ports.extend(scanner.scan(pid)?);
+			}
+			Ok::<_, std::io::Error>(ports)
+		});
+		let listening = match scanned.await {
+			Ok(Ok(ports)) => ports,
+			Ok(Err(e)) => {
+				debug!(log, "Could not scan for listening ports: {}", e);
+				continue;
+			}
+			Err(_) => continue,
+		};
+
+		for change in state.update(Instant::now(), &listening, policy) {
+			match change {
+				PortChange::Opened(port) => {
+					let uri = match policy.forward {
+						Some(privacy) => match host.forward(port, privacy).await {
+							Ok(uri) => {
+								forwarded.insert(port);
+								Some(uri)
+							}
+							Err(e) => {
+								warning!(log, "Could not forward detected port {}: {}", port, e);
+								None
+							}
+						},
+						None => None,
+					};
+					info!(log, "Detected server listening on port {}", port);
+					host.port_detected(PortDetectedParams { port, uri });
+				}
+				PortChange::Closed(port) => {
+					if forwarded.remove(&port) {
+						if let Err(e) = host.unforward(port).await {
+							warning!(log, "Could not unforward closed port {}: {}", port, e);
+						}
+					}
+					info!(log, "Server stopped listening on port {}", port);
+					host.port_closed(PortClosedParams { port });
+				}
+			}
+		}
+	}
+}
+
+/// Scans the listening sockets of the system.
+pub struct SystemPortScanner;
+
+impl ListeningPortScanner for SystemPortScanner {
+	fn scan(&self, root_pid: u32) -> std::io::Result<HashSet<u16>> {
+		let pids = process_tree(root_pid);
+		if pids.is_empty() {
+			return Ok(HashSet::new());
+		}
+
+		listening_ports_of(&pids)
+	}
+}
+
+/// Gets the PID and the PIDs of all descendants of the process.
+fn process_tree(root_pid: u32) -> HashSet<u32> {
+	let mut sys = System::new();
+	sys.refresh_processes();
+
+	let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+	for (pid, process) in sys.processes() {
+		if let Some(parent) = process.parent() {
+			children
+				.entry(parent.as_u32())
+				.or_default()
+				.push(pid.as_u32());
+		}
+	}
+
+	let mut tree = HashSet::new();
+	if sys.process(Pid::from_u32(root_pid)).is_none() {
+		return tree;
+	}
+
+	let mut stack = vec![root_pid];
+	while let Some(pid) = stack.pop() {
+		if tree.insert(pid) {
+			if let Some(c) = children.get(&pid) {
+				stack.extend(c);
+			}
+		}
+	}
+	tree
+}
+
+#[cfg(target_os = "linux")]
+fn listening_ports_of(pids: &HashSet<u32>) -> std::io::Result<HashSet<u16>> {
+	// sockets appear as links like "socket:[1234]" among a process's fds
+	let mut inodes = HashSet::new();
+	for pid in pids {
+		let fds = match std::fs::read_dir(format!("/proc/{}/fd", pid)) {
+			Ok(f) => f,
+			Err(_) => continue, // exited, or not ours to inspect
+		};
+		for fd in fds.flatten() {
+			if let Ok(target) = std::fs::read_link(fd.path()) {
+				let target = target.to_string_lossy();
+				if let Some(inode) = target
+					.strip_prefix("socket:[")
+					.and_then(|s| s.strip_suffix(']'))
+					.and_then(|s| s.parse::<u64>().ok())
+				{
+					inodes.insert(inode);
+				}
+			}
+		}
+	}
+
+	let mut ports = HashSet::new();
+	for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+		match std::fs::read_to_string(table) {
+			Ok(s) => ports.extend(parse_proc_net_tcp(&s, &inodes)),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(ports)
+}
+
+/// Gets the ports of listening sockets in a /proc/net/tcp table, whose rows
+/// look like `0: 00000000:0BB8 00000000:0000 0A ... 0 0 12345 ...`.
+#[cfg(target_os = "linux")]
+fn parse_proc_net_tcp(table: &str, inodes: &HashSet<u64>) -> Vec<u16> {
+	const TCP_LISTEN: &str = "0A";
+
+	table
+		.lines()
+		.skip(1)
+		.filter_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			if fields.len() < 10 || fields[3] != TCP_LISTEN {
+				return None;
+			}
+			let inode = fields[9].parse::<u64>().ok()?;
+			if !inodes.contains(&inode) {
+				return None;
+			}
+			let (_, port) = fields[1].rsplit_once(':')?;
+			u16::from_str_radix(port, 16).ok()
+		})
+		.collect()
+}
+
+#[cfg(target_os = "macos")]
+fn listening_ports_of(pids: &HashSet<u32>) -> std::io::Result<HashSet<u16>> {
+	let pid_list = pids
+		.iter()
+		.map(|p| p.to_string())
+		.collect::<Vec<_>>()
+		.join(",");
+	let output = std::process::Command::new("lsof")
+		.args(["-nP", "-a", "-iTCP", "-sTCP:LISTEN", "-Fn", "-p", &pid_list])
+		.output()?;
+
+	// with -Fn, sockets are printed on lines like "n*:3000" or "n[::1]:3000"
+	Ok(String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.filter_map(|l| l.strip_prefix('n'))
+		.filter_map(|addr| addr.rsplit_once(':')?.1.parse().ok())
+		.collect())
+}
+
+#[cfg(windows)]
+fn listening_ports_of(pids: &HashSet<u32>) -> std::io::Result<HashSet<u16>> {
+	let output = std::process::Command::new("netstat")
+		.args(["-ano", "-p", "TCP"])
+		.output()?;
+	let mut ports = parse_netstat(&String::from_utf8_lossy(&output.stdout), pids);
+
+	let output = std::process::Command::new("netstat")
+		.args(["-ano", "-p", "TCPv6"])
+		.output()?;
+	ports.extend(parse_netstat(
+		&String::from_utf8_lossy(&output.stdout),
+		pids,
+	));
+	Ok(ports)
+}
+
+/// Parses rows like `TCP  0.0.0.0:3000  0.0.0.0:0  LISTENING  1234`.
+#[cfg(windows)]
+fn parse_netstat(output: &str, pids: &HashSet<u32>) -> HashSet<u16> {
+	output
+		.lines()
+		.filter_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			if fields.len() != 5 || fields[3] != "LISTENING" {
+				return None;
+			}
+			let pid = fields[4].parse::<u32>().ok()?;
+			if !pids.contains(&pid) {
+				return None;
+			}
+			fields[1].rsplit_once(':')?.1.parse().ok()
+		})
+		.collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn listening_ports_of(_pids: &HashSet<u32>) -> std::io::Result<HashSet<u16>> {
+	Err(std::io::Error::new(
+		std::io::ErrorKind::Unsupported,
+		"listing listening ports is not supported on this platform",
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Mutex;
+
+	use super::*;
+
+	fn ports(p: &[u16]) -> HashSet<u16> {
+		p.iter().copied().collect()
+	}
+
+	#[test]
+	fn test_state_excludes_and_waits_for_grace_period() {
+		let policy = AutoForwardPolicy::new(None, &[9229])
+			.with_timing(Duration::from_secs(1), Duration::from_secs(5));
+		let mut state = PortWatchState::default();
+		let t0 = Instant::now();
+		let at = |s: u64| t0 + Duration::from_secs(s);
+
+		assert_eq!(
+			state.update(at(0), &ports(&[3000, 9229]), &policy),
+			vec![PortChange::Opened(3000)]
+		);
+		assert_eq!(state.update(at(1), &ports(&[3000]), &policy), vec![]);
+
+		// a restart within the grace period goes unnoticed
+		assert_eq!(state.update(at(2), &ports(&[]), &policy), vec![]);
+		assert_eq!(state.update(at(3), &ports(&[3000]), &policy), vec![]);
+
+		assert_eq!(
+			state.update(at(4), &ports(&[8080]), &policy),
+			vec![PortChange::Opened(8080)]
+		);
+		assert_eq!(state.update(at(8), &ports(&[8080]), &policy), vec![]);
+		assert_eq!(
+			state.update(at(9), &ports(&[8080]), &policy),
+			vec![PortChange::Closed(3000)]
+		);
+	}
+
+	/// Returns each scripted scan in turn, then the last one forever.
+	struct ScriptedScanner(Mutex<Vec<HashSet<u16>>>);
+
+	impl ListeningPortScanner for ScriptedScanner {
+		fn scan(&self, root_pid: u32) -> std::io::Result<HashSet<u16>> {
+			assert_eq!(root_pid, 42);
+			let mut scans = self.0.lock().unwrap();
+			if scans.len() > 1 {
+				Ok(scans.remove(0))
+			} else {
+				Ok(scans[0].clone())
+			}
+		}
+	}
+
+	#[derive(Default)]
+	struct RecordingHost {
+		events: Arc<Mutex<Vec<String>>>,
+	}
+
+	#[async_trait]
+	impl PortWatchHost for RecordingHost {
+		async fn server_pids(&self) -> Vec<u32> {
+			vec![42]
+		}
+
+		async fn forward(&mut self, port: u16, privacy: PortPrivacy) -> Result<String, AnyError> {
+			self.events
+				.lock()
+				.unwrap()
+				.push(format!("forward {} {}", port, privacy));
+			Ok(format!("https://tunnel-{}.example", port))
+		}
+
+		async fn unforward(&mut self, port: u16) -> Result<(), AnyError> {
+			self.events
+				.lock()
+				.unwrap()
+				.push(format!("unforward {}", port));
+			Ok(())
+		}
+
+		fn port_detected(&mut self, params: PortDetectedParams) {
+			self.events.lock().unwrap().push(format!(
+				"detected {} {}",
+				params.port,
+				params.uri.unwrap_or_default()
+			));
+		}
+
+		fn port_closed(&mut self, params: PortClosedParams) {
+			self.events
+				.lock()
+				.unwrap()
+				.push(format!("closed {}", params.port));
+		}
+	}
+
+	async fn run_watcher(policy: AutoForwardPolicy, scans: &[&[u16]], until: usize) -> Vec<String> {
+		let scanner = Arc::new(ScriptedScanner(Mutex::new(
+			scans.iter().map(|s| ports(s)).collect(),
+		)));
+		let mut host = RecordingHost::default();
+		let events = host.events.clone();
+		let log = log::Logger::test();
+
+		let watch = watch_ports(&log, &policy, scanner, &mut host);
+		let wait = async {
+			while events.lock().unwrap().len() < until {
+				tokio::time::sleep(Duration::from_millis(5)).await;
+			}
+		};
+		tokio::select! {
+			_ = watch => unreachable!(),
+			_ = tokio::time::timeout(Duration::from_secs(5), wait) => {},
+		}
+
+		let events = events.lock().unwrap().clone();
+		events
+	}
+
+	#[tokio::test]
+	async fn test_forwards_and_unforwards_detected_ports() {
+		let policy = AutoForwardPolicy::new(Some(PortPrivacy::Private), &[9229])
+			.with_timing(Duration::from_millis(10), Duration::ZERO);
+		let events = run_watcher(policy, &[&[3000, 9229], &[3000, 9229, 5173], &[5173]], 6).await;
+
+		assert_eq!(
+			events,
+			vec![
+				"forward 3000 private",
+				"detected 3000 https://tunnel-3000.example",
+				"forward 5173 private",
+				"detected 5173 https://tunnel-5173.example",
+				"unforward 3000",
+				"closed 3000",
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_announces_without_forwarding() {
+		let policy = AutoForwardPolicy::new(None, &[])
+			.with_timing(Duration::from_millis(10), Duration::ZERO);
+		let events = run_watcher(policy, &[&[8080], &[]], 2).await;
+
+		assert_eq!(events, vec!["detected 8080 ", "closed 8080"]);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_parse_proc_net_tcp() {
+		let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0BB8 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 111 1 0000000000000000 100 0 0 10 0
+   1: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 222 1 0000000000000000 100 0 0 10 0
+   2: 0100007F:0BB8 0100007F:D2F0 01 00000000:00000000 00:00000000 00000000  1000        0 111 1 0000000000000000 20 4 30 10 -1
+   3: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 333 1 0000000000000000 100 0 0 10 0";
+
+		let mut found = parse_proc_net_tcp(table, &[111, 222].into_iter().collect());
+		found.sort_unstable();
+		assert_eq!(found, vec![3000, 8080]);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn test_scans_own_listening_port() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+
+		let found = SystemPortScanner.scan(std::process::id()).unwrap();
+		assert!(found.contains(&port), "{:?} should contain {}", found, port);
+	}
+}
@@ -5,8 +5,9 @@
 use std::collections::HashMap;
 
 use crate::{
-	constants::{PROTOCOL_VERSION, VSCODE_CLI_VERSION},
+	constants::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, VSCODE_CLI_VERSION},
 	options::Quality,
+	rpc::ProtocolSupport,
 	update_service::Platform,
 };
 use serde::{Deserialize, Serialize};
@@ -18,6 +19,10 @@ pub enum ClientRequestMethod<'a> {
 	servermsg(RefServerMessageParams<'a>),
 	serverclose(ServerClosedParams),
 	serverlog(ServerLog<'a>),
+	serverrestart(ServerRestartParams),
+	servershutdown(ServerShutdownParams),
+	portdetected(PortDetectedParams),
+	portclosed(PortClosedParams),
 	makehttpreq(HttpRequestParams<'a>),
 	version(VersionResponse),
 }
@@ -49,6 +54,9 @@ pub struct ForwardParams {
 	pub port: u16,
 	#[serde(default)]
 	pub public: bool,
+	/// Protocol the local server speaks, which the tunnel uses to talk to it.
+	#[serde(default)]
+	pub protocol: PortProtocol,
 }
 
 #[derive(Deserialize, Debug)]
@@ -59,6 +67,7 @@ pub struct UnforwardParams {
 #[derive(Serialize)]
 pub struct ForwardResult {
 	pub uri: String,
+	pub protocol: PortProtocol,
 }
 
 #[derive(Deserialize, Debug)]
@@ -123,6 +132,40 @@ pub struct ServerLog<'a> {
 	pub level: u8,
 }
 
+/// Sent when the code server exited unexpectedly and is being restarted, or
+/// when the CLI has given up restarting it.
+#[derive(Debug, Serialize)]
+pub struct ServerRestartParams {
+	pub exit_code: Option<i32>,
+	pub attempt: u32,
+	pub limit: u32,
+	/// Delay before the restart is attempted. None if no further restarts
+	/// will be attempted.
+	pub delay_ms: Option<u64>,
+}
+
+/// Sent when auto-forwarding is on and the server, or a process it started,
+/// begins listening on a port.
+#[derive(Debug, Serialize)]
+pub struct PortDetectedParams {
+	pub port: u16,
+	/// URI the port was forwarded at, if the tunnel forwarded it.
+	pub uri: Option<String>,
+}
+
+/// Sent when a detected port has stopped listening for the grace period.
+#[derive(Debug, Serialize)]
+pub struct PortClosedParams {
+	pub port: u16,
+}
+
+/// Sent when the CLI is shutting down, asking the client to disconnect. The
+/// connection is closed once the timeout elapses.
+#[derive(Debug, Serialize)]
+pub struct ServerShutdownParams {
+	pub timeout_ms: u64,
+}
+
 #[derive(Serialize)]
 pub struct GetHostnameResponse {
 	pub value: String,
@@ -234,6 +277,21 @@ pub struct CallServerHttpResult {
 	pub headers: HashMap<String, String>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingParams {
+	/// Timestamp of the client, in microseconds since the Unix epoch. Older
+	/// clients send no parameters.
+	#[serde(default)]
+	pub client_time_us: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingResult {
+	pub client_time_us: Option<u64>,
+	pub host_received_us: u64,
+	pub host_sent_us: u64,
+}
+
 #[derive(Serialize, Debug)]
 pub struct VersionResponse {
 	pub version: &'static str,
@@ -274,15 +332,59 @@ pub struct SpawnResult {
 	pub exit_code: i32,
 }
 
+#[derive(Deserialize)]
+pub struct ExecParams {
+	pub command: String,
+	#[serde(default)]
+	pub args: Vec<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ExecResult {
+	/// Exit code of the command, if it exited on its own.
+	pub exit_code: Option<i32>,
+	pub timed_out: bool,
+	/// Whether the command was killed for printing more than the output limit.
+	pub truncated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UninstallServerParams {
+	pub commit: String,
+	/// Whether to uninstall the server even if connections are using it.
+	#[serde(default)]
+	pub force: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct UninstallServerResult {
+	/// Process IDs of the servers that were stopped.
+	pub stopped: Vec<u32>,
+	/// Names of the server folders that were removed.
+	pub removed: Vec<String>,
+}
+
+/// Clients can call `rpc.methods`, `rpc.load`, and `rpc.metrics`.
+pub const CAPABILITY_INTROSPECTION: &str = "introspection";
+
+/// Protocol the control server negotiates with clients.
+pub const CONTROL_PROTOCOL: ProtocolSupport = ProtocolSupport {
+	version: PROTOCOL_VERSION,
+	min_version: MIN_PROTOCOL_VERSION,
+	capabilities: &[CAPABILITY_INTROSPECTION],
+};
+
+pub const METHOD_PING: &str = "ping";
 pub const METHOD_CHALLENGE_ISSUE: &str = "challenge_issue";
 pub const METHOD_CHALLENGE_VERIFY: &str = "challenge_verify";
+pub const METHOD_UNINSTALL_SERVER: &str = "uninstall_server";
 
 #[derive(Serialize, Deserialize)]
 pub struct ChallengeIssueParams {
 	pub token: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ChallengeIssueResponse {
 	pub challenge: String,
 }
@@ -299,17 +401,33 @@ pub enum PortPrivacy {
 	Private,
 }
 
+impl std::fmt::Display for PortPrivacy {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Public => write!(f, "public"),
+			Self::Private => write!(f, "private"),
+		}
+	}
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Copy, Eq, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum PortProtocol {
 	Auto,
 	Http,
 	Https,
+	/// Detected from the first bytes clients send on the port, by forwarding
+	/// its connections through the CLI rather than the tunnel host.
+	#[serde(rename = "auto-detect")]
+	AutoDetect,
 }
 
 impl std::fmt::Display for PortProtocol {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		write!(f, "{}", self.to_contract_str())
+		match self {
+			Self::AutoDetect => write!(f, "auto-detect"),
+			p => write!(f, "{}", p.to_contract_str()),
+		}
 	}
 }
 
@@ -322,7 +440,7 @@ impl Default for PortProtocol {
 impl PortProtocol {
 	pub fn to_contract_str(&self) -> &'static str {
 		match *self {
-			Self::Auto => tunnels::contracts::TUNNEL_PROTOCOL_AUTO,
+			Self::Auto | Self::AutoDetect => tunnels::contracts::TUNNEL_PROTOCOL_AUTO,
 			Self::Http => tunnels::contracts::TUNNEL_PROTOCOL_HTTP,
 			Self::Https => tunnels::contracts::TUNNEL_PROTOCOL_HTTPS,
 		}
@@ -357,15 +475,54 @@ pub mod forward_singleton {
 }
 
 pub mod singleton {
-	use crate::log;
+	use crate::{log, rpc::ProtocolSupport};
 	use chrono::{DateTime, Utc};
 	use serde::{Deserialize, Serialize};
 
+	/// Protocol the singleton server and its clients negotiate.
+	///  1 - Initial version, adds `rpc.methods` and `rpc.load`
+	///  2 - Adds `rpc.metrics`, and `metrics` for the control server's metrics
+	///  3 - Adds `ping`
+	///  4 - Adds `stream_logs`
+	///  5 - Adds `uninstall_server`
+	pub const PROTOCOL: ProtocolSupport = ProtocolSupport {
+		version: 5,
+		min_version: 0,
+		capabilities: &[super::CAPABILITY_INTROSPECTION],
+	};
+
 	pub const METHOD_RESTART: &str = "restart";
 	pub const METHOD_SHUTDOWN: &str = "shutdown";
 	pub const METHOD_STATUS: &str = "status";
 	pub const METHOD_LOG: &str = "log";
 	pub const METHOD_LOG_REPLY_DONE: &str = "log_done";
+	pub const METHOD_METRICS: &str = "metrics";
+	pub const METHOD_STREAM_LOGS: &str = "stream_logs";
+
+	#[derive(Serialize, Deserialize, Clone, Debug)]
+	pub struct StreamLogsParams {
+		/// Number of recent records to send first.
+		pub lines: usize,
+		/// Whether to keep sending new records as they're logged.
+		pub follow: bool,
+		/// Least severe level to send. Results are always sent.
+		pub level: Option<log::Level>,
+		/// Whether to include the output of spawned servers.
+		pub server: bool,
+	}
+
+	/// Sent as a partial result of `stream_logs`.
+	#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+	pub struct LogRecord {
+		/// Increases by one for each record logged, starting at 1. Notices
+		/// about the stream itself, such as records being dropped, have 0.
+		pub seq: u64,
+		pub level: Option<log::Level>,
+		pub prefix: String,
+		pub message: String,
+		/// Whether this is output from a spawned server.
+		pub server: bool,
+	}
 
 	#[derive(Serialize)]
 	pub struct LogMessage<'a> {
@@ -386,6 +543,15 @@ pub mod singleton {
 		pub name: Option<String>,
 		#[serde(flatten)]
 		pub status: Status,
+		/// The last warnings and errors the tunnel logged, oldest first.
+		#[serde(default, skip_serializing_if = "Vec::is_empty")]
+		pub recent_problems: Vec<LogRecord>,
+		/// Whether the tunnel has opened its credential store.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub credentials: Option<crate::auth::CredentialStoreState>,
+		/// Failed auth challenges and lockouts of the tunnel's clients.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub auth_challenges: Option<crate::tunnels::challenge_limiter::ChallengeStats>,
 	}
 
 	#[derive(Serialize, Deserialize, Clone)]
@@ -395,6 +561,43 @@ pub mod singleton {
 		pub last_connected_at: Option<DateTime<Utc>>,
 		pub last_disconnected_at: Option<DateTime<Utc>>,
 		pub last_fail_reason: Option<String>,
+		/// Health of the background token refresh, if the tunnel refreshes one.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub token: Option<TokenStatus>,
+		/// Set once the tunnel is waiting for clients to disconnect before it
+		/// shuts down.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub drain: Option<DrainStatus>,
+		/// Ports that clients of the tunnel have forwarded.
+		#[serde(default, skip_serializing_if = "Vec::is_empty")]
+		pub ports: Vec<ForwardedPort>,
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+	pub struct ForwardedPort {
+		pub number: u16,
+		pub privacy: super::PortPrivacy,
+		/// Protocol the port was forwarded with. For ports forwarded with
+		/// auto-detect, it's the detected one once a client has connected.
+		pub protocol: super::PortProtocol,
+		/// Public URL of the port, once the tunnel knows it.
+		pub uri: Option<String>,
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+	pub struct DrainStatus {
+		pub started_at: DateTime<Utc>,
+		/// Time at which the tunnel exits even if clients are still connected.
+		pub deadline: DateTime<Utc>,
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+	pub struct TokenStatus {
+		pub expires_at: Option<DateTime<Utc>>,
+		pub last_refreshed_at: Option<DateTime<Utc>>,
+		pub next_refresh_at: Option<DateTime<Utc>>,
+		pub consecutive_failures: u32,
+		pub last_error: Option<String>,
 	}
 
 	impl Default for Status {
@@ -405,6 +608,9 @@ pub mod singleton {
 				last_connected_at: None,
 				last_disconnected_at: None,
 				last_fail_reason: None,
+				token: None,
+				drain: None,
+				ports: vec![],
 			}
 		}
 	}
@@ -0,0 +1,581 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs::OpenOptions,
+	io::Write,
+	path::{Path, PathBuf},
+	pin::Pin,
+	sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+	task::{Context, Poll},
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+	constants::{DEFAULT_REMOTE_EXEC_OUTPUT_LIMIT, DEFAULT_REMOTE_EXEC_TIMEOUT},
+	log,
+	util::{
+		command::{new_tokio_command, run_command_to, CommandEnd, CommandRunError},
+		crypto::constant_time_eq,
+		errors::{AnyError, CodeError},
+	},
+};
+
+use super::{
+	protocol::{ExecParams, ExecResult},
+	service::rotate_log_file,
+};
+
+/// Commands that trusted clients may run on the host with the `exec` method,
+/// and the limits they run under.
+#[derive(Clone, Debug)]
+pub struct RemoteExecPolicy {
+	/// Each is a command and the exact arguments an invocation must have,
+	/// such as `systemctl --user status`.
+	allowed: Vec<Vec<String>>,
+	/// Secret that clients send with the auth challenge to use remote exec.
+	/// The challenge alone can be answered by any client of the tunnel.
+	token: String,
+	timeout: Duration,
+	output_limit: usize,
+}
+
+impl RemoteExecPolicy {
+	pub fn new(allowed: &[String], token: String) -> Self {
+		Self {
+			allowed: allowed
+				.iter()
+				.map(|a| a.split_whitespace().map(|s| s.to_string()).collect())
+				.filter(|a: &Vec<String>| !a.is_empty())
+				.collect(),
+			token,
+			timeout: DEFAULT_REMOTE_EXEC_TIMEOUT,
+			output_limit: DEFAULT_REMOTE_EXEC_OUTPUT_LIMIT,
+		}
+	}
+
+	pub fn with_limits(mut self, timeout: Duration, output_limit: usize) -> Self {
+		self.timeout = timeout;
+		self.output_limit = output_limit;
+		self
+	}
+
+	/// Gets whether the command and arguments match an allowed entry exactly.
+	/// Extra arguments aren't allowed, since they can change what a command
+	/// runs, as `git -c core.pager=...` does.
+	pub fn is_allowed(&self, command: &str, args: &[String]) -> bool {
+		self.allowed
+			.iter()
+			.any(|entry| entry[0] == command && entry[1..] == *args)
+	}
+
+	/// Gets whether the client sent the host's token. It's compared in
+	/// constant time, as it's a bearer secret.
+	pub fn accepts_token(&self, token: Option<&str>) -> bool {
+		match token {
+			Some(token) => constant_time_eq(token.as_bytes(), self.token.as_bytes()),
+			None => false,
+		}
+	}
+}
+
+/// How an invocation ended, as written to the audit log.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecOutcome {
+	/// The client didn't send the host's token, so the command wasn't run.
+	Unauthorized,
+	/// The command isn't on the allowlist, so it wasn't run.
+	Rejected,
+	/// The command could not be started.
+	SpawnFailed,
+	/// The command ran until it exited.
+	Exited,
+	/// The command, with any processes it started, was killed after running
+	/// for too long.
+	TimedOut,
+	/// The command was killed after printing more than the output limit.
+	Truncated,
+	/// The command was killed since its output could not be sent.
+	Disconnected,
+}
+
+/// A line in the audit log, written for every invocation.
+#[derive(Serialize, Debug)]
+pub struct ExecAuditEntry {
+	pub time: DateTime<Utc>,
+	pub command: String,
+	pub args: Vec<String>,
+	pub outcome: ExecOutcome,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub exit_code: Option<i32>,
+	pub duration_ms: u128,
+}
+
+/// Appends entries to the remote exec audit log, which has one JSON object
+/// per line. Only the user can read it, and it's rotated like other logs.
+pub struct ExecAuditLog {
+	path: PathBuf,
+	rotation: log::LogRotation,
+}
+
+impl ExecAuditLog {
+	pub fn new(path: PathBuf) -> Self {
+		Self {
+			path,
+			rotation: log::LogRotation::default(),
+		}
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	async fn record(&self, log: &log::Logger, entry: &ExecAuditEntry) {
+		info!(
+			log,
+			"Remote exec of {} {:?}: {:?}", entry.command, entry.args, entry.outcome
+		);
+
+		let mut line = serde_json::to_vec(entry).expect("expected to serialize");
+		line.push(b'\n');
+		let (path, rotation) = (self.path.clone(), self.rotation.clone());
+		let written = tokio::task::spawn_blocking(move || append_private(&path, &rotation, &line))
+			.await
+			.unwrap_or_else(|e| Err(std::io::Error::other(e)));
+		if let Err(e) = written {
+			warning!(
+				log,
+				"Could not write to the remote exec audit log {}: {}",
+				self.path.display(),
+				e
+			);
+		}
+	}
+}
+
+/// Rotates the file if it's too large, then appends to it, creating it so
+/// that only the user can read it.
+fn append_private(path: &Path, rotation: &log::LogRotation, data: &[u8]) -> std::io::Result<()> {
+	#[cfg(not(windows))]
+	use std::os::unix::fs::OpenOptionsExt;
+
+	rotate_log_file(path, rotation)?;
+	let mut f = OpenOptions::new();
+	f.create(true).append(true);
+	#[cfg(not(windows))]
+	f.mode(0o600);
+	f.open(path)?.write_all(data)
+}
+
+/// Runs an allowed command for a client that sent the host's token,
+/// streaming its output to the writers until it exits, times out, or prints
+/// more than the output limit. Stdin is closed. A command that's killed is
+/// killed with any processes it started.
+pub async fn run_exec<W>(
+	log: &log::Logger,
+	policy: &RemoteExecPolicy,
+	audit: &ExecAuditLog,
+	token: Option<&str>,
+	params: ExecParams,
+	mut stdout: W,
+	mut stderr: W,
+) -> Result<ExecResult, AnyError>
+where
+	W: AsyncWrite + Unpin + Send,
+{
+	let started = Instant::now();
+	let record = |outcome: ExecOutcome, exit_code: Option<i32>| {
+		let entry = ExecAuditEntry {
+			time: Utc::now(),
+			command: params.command.clone(),
+			args: params.args.clone(),
+			outcome,
+			exit_code,
+			duration_ms: started.elapsed().as_millis(),
+		};
+		async move { audit.record(log, &entry).await }
+	};
+
+	if !policy.accepts_token(token) {
+		record(ExecOutcome::Unauthorized, None).await;
+		return Err(CodeError::RemoteExecBadToken.into());
+	}
+	if !policy.is_allowed(&params.command, &params.args) {
+		record(ExecOutcome::Rejected, None).await;
+		return Err(CodeError::RemoteExecNotAllowed(params.command.clone()).into());
+	}
+
+	let mut p = new_tokio_command(&params.command);
+	p.args(&params.args);
+
+	// hitting the limit on either stream stops the command, since the other
+	// may not close until it's killed
+	let limit = OutputLimit {
+		remaining: AtomicUsize::new(policy.output_limit),
+		reached: AtomicBool::new(false),
+	};
+	let ended = run_command_to(
+		p,
+		policy.timeout,
+		&mut LimitedWriter::new(&mut stdout, &limit),
+		&mut LimitedWriter::new(&mut stderr, &limit),
+	)
+	.await;
+
+	let result = match ended {
+		Ok(CommandEnd::Exited(status)) => ExecResult {
+			exit_code: status.code(),
+			timed_out: false,
+			truncated: false,
+		},
+		Ok(CommandEnd::TimedOut) => ExecResult {
+			exit_code: None,
+			timed_out: true,
+			truncated: false,
+		},
+		Err(CommandRunError::Output(_)) if limit.reached.load(Ordering::SeqCst) => ExecResult {
+			exit_code: None,
+			timed_out: false,
+			truncated: true,
+		},
+		Err(CommandRunError::Spawn(e)) => {
+			record(ExecOutcome::SpawnFailed, None).await;
+			return Err(CodeError::ProcessSpawnFailed(e).into());
+		}
+		Err(CommandRunError::Output(e) | CommandRunError::Wait(e)) => {
+			record(ExecOutcome::Disconnected, None).await;
+			return Err(CodeError::RemoteExecStreamFailed(e).into());
+		}
+	};
+
+	let outcome = if result.timed_out {
+		ExecOutcome::TimedOut
+	} else if result.truncated {
+		ExecOutcome::Truncated
+	} else {
+		ExecOutcome::Exited
+	};
+	record(outcome, result.exit_code).await;
+
+	let _ = stdout.shutdown().await;
+	let _ = stderr.shutdown().await;
+	Ok(result)
+}
+
+/// Bytes of output left for both of a command's streams.
+struct OutputLimit {
+	remaining: AtomicUsize,
+	reached: AtomicBool,
+}
+
+/// Passes writes through until the limit shared with the other stream is
+/// reached, then fails them.
+struct LimitedWriter<'a, W> {
+	inner: &'a mut W,
+	limit: &'a OutputLimit,
+}
+
+impl<'a, W> LimitedWriter<'a, W> {
+	fn new(inner: &'a mut W, limit: &'a OutputLimit) -> Self {
+		Self { inner, limit }
+	}
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for LimitedWriter<'_, W> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<std::io::Result<usize>> {
+		let this = self.get_mut();
+		let allowed = this.limit.remaining.load(Ordering::SeqCst).min(buf.len());
+		if allowed == 0 && !buf.is_empty() {
+			this.limit.reached.store(true, Ordering::SeqCst);
+			return Poll::Ready(Err(std::io::Error::other("output limit reached")));
+		}
+
+		let r = Pin::new(&mut *this.inner).poll_write(cx, &buf[..allowed]);
+		if let Poll::Ready(Ok(n)) = &r {
+			this.limit.remaining.fetch_sub(*n, Ordering::SeqCst);
+		}
+		r
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TOKEN: &str = "exec-token";
+
+	fn policy(allowed: &[&str]) -> RemoteExecPolicy {
+		let allowed: Vec<String> = allowed.iter().map(|s| s.to_string()).collect();
+		RemoteExecPolicy::new(&allowed, TOKEN.to_string())
+	}
+
+	fn params(command: &str, args: &[&str]) -> ExecParams {
+		ExecParams {
+			command: command.to_string(),
+			args: args.iter().map(|s| s.to_string()).collect(),
+		}
+	}
+
+	/// Writes a shell script, returning its path, since allowed arguments
+	/// can't have spaces.
+	fn script(dir: &Path, body: &str) -> String {
+		let path = dir.join("script.sh");
+		std::fs::write(&path, body).unwrap();
+		path.to_string_lossy().to_string()
+	}
+
+	fn audit_entries(audit: &ExecAuditLog) -> Vec<serde_json::Value> {
+		std::fs::read_to_string(audit.path())
+			.unwrap_or_default()
+			.lines()
+			.map(|l| serde_json::from_str(l).unwrap())
+			.collect()
+	}
+
+	async fn exec(
+		policy: &RemoteExecPolicy,
+		audit: &ExecAuditLog,
+		token: Option<&str>,
+		params: ExecParams,
+	) -> (Result<ExecResult, AnyError>, Vec<u8>, Vec<u8>) {
+		let (mut out, mut err) = (vec![], vec![]);
+		let r = run_exec(
+			&log::Logger::test(),
+			policy,
+			audit,
+			token,
+			params,
+			&mut out,
+			&mut err,
+		)
+		.await;
+		(r, out, err)
+	}
+
+	#[test]
+	fn test_allowlist() {
+		let p = policy(&["df", "systemctl --user status", "git status"]);
+		assert!(p.is_allowed("df", &[]));
+		assert!(!p.is_allowed("df", &["-h".to_string()]));
+		assert!(p.is_allowed("systemctl", &["--user".to_string(), "status".to_string()]));
+		assert!(!p.is_allowed(
+			"systemctl",
+			&["--user".to_string(), "status".to_string(), "a".to_string()]
+		));
+		assert!(!p.is_allowed("systemctl", &["--user".to_string()]));
+		assert!(!p.is_allowed("systemctl", &["--user".to_string(), "stop".to_string()]));
+		assert!(!p.is_allowed(
+			"git",
+			&[
+				"-c".to_string(),
+				"core.pager=sh".to_string(),
+				"status".to_string()
+			]
+		));
+		assert!(!p.is_allowed("/bin/df", &[]));
+		assert!(!p.is_allowed("rm", &[]));
+		assert!(!policy(&[]).is_allowed("df", &[]));
+	}
+
+	#[tokio::test]
+	async fn test_rejects_and_audits_wrong_token() {
+		let dir = tempfile::tempdir().unwrap();
+		let audit = ExecAuditLog::new(dir.path().join("audit.log"));
+		let prefix = &TOKEN[..TOKEN.len() - 1];
+		for token in [None, Some("wrong"), Some(prefix)] {
+			let (r, out, _) = exec(
+				&policy(&["echo hi"]),
+				&audit,
+				token,
+				params("echo", &["hi"]),
+			)
+			.await;
+			assert!(matches!(
+				r,
+				Err(AnyError::CodeError(CodeError::RemoteExecBadToken))
+			));
+			assert!(out.is_empty());
+		}
+
+		let entries = audit_entries(&audit);
+		assert_eq!(entries.len(), 3);
+		assert!(entries.iter().all(|e| e["outcome"] == "unauthorized"));
+	}
+
+	#[tokio::test]
+	async fn test_rejects_and_audits_unlisted_command() {
+		let dir = tempfile::tempdir().unwrap();
+		let audit = ExecAuditLog::new(dir.path().join("audit.log"));
+		let (r, out, _) = exec(
+			&policy(&["echo"]),
+			&audit,
+			Some(TOKEN),
+			params("sh", &["-c", "echo hi"]),
+		)
+		.await;
+
+		assert!(matches!(
+			r,
+			Err(AnyError::CodeError(CodeError::RemoteExecNotAllowed(ref c))) if c == "sh"
+		));
+		assert!(out.is_empty());
+		let entries = audit_entries(&audit);
+		assert_eq!(entries.len(), 1);
+		assert_eq!(entries[0]["command"], "sh");
+		assert_eq!(entries[0]["outcome"], "rejected");
+	}
+
+	#[tokio::test]
+	async fn test_streams_output() {
+		let dir = tempfile::tempdir().unwrap();
+		let audit = ExecAuditLog::new(dir.path().join("audit.log"));
+		let s = script(dir.path(), "echo out; echo err >&2; exit 3");
+		let (r, out, err) = exec(
+			&policy(&[format!("sh {}", s).as_str()]),
+			&audit,
+			Some(TOKEN),
+			params("sh", &[&s]),
+		)
+		.await;
+
+		let r = r.unwrap();
+		assert_eq!(r.exit_code, Some(3));
+		assert!(!r.timed_out && !r.truncated);
+		assert_eq!(out, b"out\n");
+		assert_eq!(err, b"err\n");
+
+		let entries = audit_entries(&audit);
+		assert_eq!(entries[0]["outcome"], "exited");
+		assert_eq!(entries[0]["exit_code"], 3);
+		assert_eq!(entries[0]["args"][0], s);
+	}
+
+	#[tokio::test]
+	async fn test_kills_after_timeout() {
+		let dir = tempfile::tempdir().unwrap();
+		let audit = ExecAuditLog::new(dir.path().join("audit.log"));
+		let s = script(dir.path(), "echo started; sleep 30");
+		let started = Instant::now();
+		let (r, out, _) = exec(
+			&policy(&[format!("sh {}", s).as_str()]).with_limits(Duration::from_millis(200), 1024),
+			&audit,
+			Some(TOKEN),
+			params("sh", &[&s]),
+		)
+		.await;
+
+		let r = r.unwrap();
+		assert!(r.timed_out);
+		assert_eq!(r.exit_code, None);
+		assert!(started.elapsed() < Duration::from_secs(10));
+		assert_eq!(out, b"started\n");
+		assert_eq!(audit_entries(&audit)[0]["outcome"], "timed_out");
+	}
+
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn test_timeout_kills_started_processes() {
+		let dir = tempfile::tempdir().unwrap();
+		let audit = ExecAuditLog::new(dir.path().join("audit.log"));
+		let pid_file = dir.path().join("pid");
+		let s = script(
+			dir.path(),
+			&format!("sleep 30 & echo $! > {}; wait", pid_file.display()),
+		);
+		let (r, _, _) = exec(
+			&policy(&[format!("sh {}", s).as_str()]).with_limits(Duration::from_millis(500), 1024),
+			&audit,
+			Some(TOKEN),
+			params("sh", &[&s]),
+		)
+		.await;
+		assert!(r.unwrap().timed_out);
+
+		// the background sleep was in the command's group, so it was killed too
+		let pid = std::fs::read_to_string(&pid_file).unwrap();
+		let is_running = || match std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())) {
+			// a zombie was killed, but may not be reaped in a container
+			Ok(stat) => !stat
+				.rsplit(')')
+				.next()
+				.unwrap()
+				.trim_start()
+				.starts_with('Z'),
+			Err(_) => false,
+		};
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while is_running() {
+			assert!(Instant::now() < deadline, "the background process survived");
+			tokio::time::sleep(Duration::from_millis(20)).await;
+		}
+	}
+
+	#[tokio::test]
+	async fn test_caps_output() {
+		let dir = tempfile::tempdir().unwrap();
+		let audit = ExecAuditLog::new(dir.path().join("audit.log"));
+		let (r, out, _) = exec(
+			&policy(&["yes"]).with_limits(Duration::from_secs(10), 10_000),
+			&audit,
+			Some(TOKEN),
+			params("yes", &[]),
+		)
+		.await;
+
+		assert!(r.unwrap().truncated);
+		assert_eq!(out.len(), 10_000);
+		assert_eq!(audit_entries(&audit)[0]["outcome"], "truncated");
+	}
+
+	#[tokio::test]
+	async fn test_audit_log_is_private_and_rotated() {
+		let dir = tempfile::tempdir().unwrap();
+		let audit = ExecAuditLog {
+			path: dir.path().join("audit.log"),
+			rotation: log::LogRotation {
+				max_size: 0,
+				keep: 1,
+				compress: false,
+			},
+		};
+		for _ in 0..2 {
+			let (r, _, _) =
+				exec(&policy(&["true"]), &audit, Some(TOKEN), params("true", &[])).await;
+			assert_eq!(r.unwrap().exit_code, Some(0));
+		}
+
+		// each write rotated the one before it
+		assert_eq!(audit_entries(&audit).len(), 1);
+		let rotated = log::rotated_log_path(audit.path(), 1, false);
+		assert_eq!(std::fs::read_to_string(rotated).unwrap().lines().count(), 1);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let mode = std::fs::metadata(audit.path())
+				.unwrap()
+				.permissions()
+				.mode();
+			assert_eq!(mode & 0o777, 0o600);
+		}
+	}
+}
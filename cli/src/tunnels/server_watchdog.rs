@@ -0,0 +1,320 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+use crate::{log, util::errors::AnyError};
+
+use super::code_server::{wait_for_server_exit, ServerExit};
+
+/// Servers that stay up for at least this long are considered healthy, and
+/// reset the restart backoff when they later exit.
+const HEALTHY_UPTIME: Duration = Duration::from_secs(5 * 60);
+
+/// Exponential backoff for restarting a crashed server, which gives up after
+/// a limited number of consecutive attempts.
+pub struct RestartBackoff {
+	limit: u32,
+	attempt: u32,
+	initial: Duration,
+	max: Duration,
+}
+
+impl RestartBackoff {
+	pub fn new(limit: u32) -> Self {
+		Self::with_delays(limit, Duration::from_secs(1), Duration::from_secs(60))
+	}
+
+	pub fn with_delays(limit: u32, initial: Duration, max: Duration) -> Self {
+		Self {
+			limit,
+			attempt: 0,
+			initial,
+			max,
+		}
+	}
+
+	/// Gets the number of restarts attempted since the last reset.
+	pub fn attempt(&self) -> u32 {
+		self.attempt
+	}
+
+	pub fn limit(&self) -> u32 {
+		self.limit
+	}
+
+	/// Gets the delay before the next restart, or None if the limit was reached.
+	pub fn next_delay(&mut self) -> Option<Duration> {
+		if self.attempt >= self.limit {
+			return None;
+		}
+
+		let delay = self
+			.initial
+			.checked_mul(1 << self.attempt.min(16))
+			.unwrap_or(self.max)
+			.min(self.max);
+		self.attempt += 1;
+		Some(delay)
+	}
+
+	pub fn reset(&mut self) {
+		self.attempt = 0;
+	}
+}
+
+/// Event emitted by the watchdog when a server exits unexpectedly.
+#[derive(Debug)]
+pub enum WatchdogEvent<'a> {
+	/// The server will be restarted after the given delay.
+	Restarting {
+		exit: &'a ServerExit,
+		attempt: u32,
+		delay: Duration,
+	},
+	/// The restart limit was reached and the server will not be restarted.
+	GaveUp { exit: &'a ServerExit },
+}
+
+/// A server that can be restarted by the watchdog.
+#[async_trait]
+pub trait SupervisedServer: Send {
+	/// Starts the server again, returning a receiver for its next exit.
+	async fn respawn(&mut self) -> Result<watch::Receiver<Option<ServerExit>>, AnyError>;
+
+	/// Called before the server is restarted, or once the watchdog gives up.
+	fn notify(&mut self, event: WatchdogEvent<'_>);
+}
+
+/// Watches the server for unexpected exits, restarting it with backoff until
+/// it either exits deliberately or the restart limit is reached. Callers
+/// should drop the future to stop supervision.
+pub async fn supervise(
+	log: &log::Logger,
+	mut backoff: RestartBackoff,
+	mut exited: watch::Receiver<Option<ServerExit>>,
+	mut server: impl SupervisedServer,
+) {
+	let mut started_at = Instant::now();
+
+	loop {
+		let mut exit = match wait_for_server_exit(exited).await {
+			Some(e) => e,
+			None => return,
+		};
+
+		if exit.is_expected() {
+			info!(log, "Server exited normally, not restarting");
+			return;
+		}
+
+		if started_at.elapsed() >= HEALTHY_UPTIME {
+			backoff.reset();
+		}
+
+		loop {
+			log_exit(log, &exit);
+
+			let delay = match backoff.next_delay() {
+				Some(d) => d,
+				None => {
+					error!(
+						log,
+						"Server exited {} times in a row, not restarting it again",
+						backoff.attempt() + 1
+					);
+					server.notify(WatchdogEvent::GaveUp { exit: &exit });
+					return;
+				}
+			};
+
+			info!(
+				log,
+				"Restarting server in {}ms (attempt {}/{})",
+				delay.as_millis(),
+				backoff.attempt(),
+				backoff.limit()
+			);
+			server.notify(WatchdogEvent::Restarting {
+				exit: &exit,
+				attempt: backoff.attempt(),
+				delay,
+			});
+			tokio::time::sleep(delay).await;
+
+			match server.respawn().await {
+				Ok(rx) => {
+					exited = rx;
+					started_at = Instant::now();
+					break;
+				}
+				Err(e) => {
+					warning!(log, "Failed to restart server: {}", e);
+					exit = ServerExit {
+						code: None,
+						deliberate: false,
+						stderr_tail: vec![e.to_string()],
+					};
+				}
+			}
+		}
+	}
+}
+
+fn log_exit(log: &log::Logger, exit: &ServerExit) {
+	match exit.code {
+		Some(code) => warning!(log, "Server exited unexpectedly with code {}", code),
+		None => warning!(log, "Server was terminated unexpectedly"),
+	}
+
+	if !exit.stderr_tail.is_empty() {
+		warning!(log, "Last server output:\n{}", exit.stderr_tail.join("\n"));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use tokio::process::Command;
+
+	use super::*;
+	use crate::tunnels::code_server::{monitor_server, NoOpMatcher};
+
+	#[test]
+	fn test_backoff_schedule() {
+		let mut b = RestartBackoff::with_delays(6, Duration::from_secs(1), Duration::from_secs(10));
+		let delays: Vec<_> = std::iter::from_fn(|| b.next_delay())
+			.map(|d| d.as_secs())
+			.collect();
+		assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+
+		b.reset();
+		assert_eq!(b.next_delay(), Some(Duration::from_secs(1)));
+	}
+
+	#[test]
+	fn test_backoff_zero_limit() {
+		let mut b = RestartBackoff::new(0);
+		assert_eq!(b.next_delay(), None);
+	}
+
+	/// Spawns a fake server that writes to stderr and exits after a delay.
+	fn spawn_fake_server(code: i32) -> watch::Receiver<Option<ServerExit>> {
+		let child = Command::new("sh")
+			.args([
+				"-c",
+				&format!(
+					"echo starting; echo out of memory >&2; sleep 0.1; exit {}",
+					code
+				),
+			])
+			.stdin(std::process::Stdio::null())
+			.stdout(std::process::Stdio::piped())
+			.stderr(std::process::Stdio::piped())
+			.spawn()
+			.unwrap();
+
+		let (origin, _) =
+			monitor_server::<NoOpMatcher, ()>(child, None, log::Logger::test(), false);
+		origin.exited().unwrap()
+	}
+
+	struct FakeServer {
+		code: i32,
+		events: Arc<Mutex<Vec<String>>>,
+	}
+
+	#[async_trait]
+	impl SupervisedServer for FakeServer {
+		async fn respawn(&mut self) -> Result<watch::Receiver<Option<ServerExit>>, AnyError> {
+			Ok(spawn_fake_server(self.code))
+		}
+
+		fn notify(&mut self, event: WatchdogEvent<'_>) {
+			let s = match event {
+				WatchdogEvent::Restarting {
+					exit,
+					attempt,
+					delay,
+				} => {
+					assert_eq!(exit.stderr_tail, vec!["out of memory".to_string()]);
+					format!(
+						"restart {} {:?} {}ms",
+						attempt,
+						exit.code,
+						delay.as_millis()
+					)
+				}
+				WatchdogEvent::GaveUp { exit } => format!("gave up {:?}", exit.code),
+			};
+			self.events.lock().unwrap().push(s);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_supervise_restarts_with_backoff() {
+		let events = Arc::new(Mutex::new(vec![]));
+		supervise(
+			&log::Logger::test(),
+			RestartBackoff::with_delays(3, Duration::from_millis(10), Duration::from_secs(1)),
+			spawn_fake_server(3),
+			FakeServer {
+				code: 3,
+				events: events.clone(),
+			},
+		)
+		.await;
+
+		assert_eq!(
+			*events.lock().unwrap(),
+			vec![
+				"restart 1 Some(3) 10ms",
+				"restart 2 Some(3) 20ms",
+				"restart 3 Some(3) 40ms",
+				"gave up Some(3)",
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_supervise_ignores_clean_exit() {
+		let events = Arc::new(Mutex::new(vec![]));
+		supervise(
+			&log::Logger::test(),
+			RestartBackoff::new(3),
+			spawn_fake_server(0),
+			FakeServer {
+				code: 0,
+				events: events.clone(),
+			},
+		)
+		.await;
+
+		assert!(events.lock().unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_deliberate_kill_is_expected() {
+		let child = Command::new("sh")
+			.args(["-c", "sleep 10"])
+			.stdout(std::process::Stdio::piped())
+			.stderr(std::process::Stdio::piped())
+			.spawn()
+			.unwrap();
+
+		let (mut origin, _) =
+			monitor_server::<NoOpMatcher, ()>(child, None, log::Logger::test(), false);
+		let exited = origin.exited().unwrap();
+		origin.kill().await;
+
+		let exit = wait_for_server_exit(exited).await.unwrap();
+		assert!(exit.deliberate);
+		assert!(exit.is_expected());
+	}
+}
@@ -3,17 +3,46 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::path::{Path, PathBuf};
+use std::{
+	fmt,
+	fs::File,
+	future::Future,
+	io::{BufWriter, Write},
+	path::{Path, PathBuf},
+	time::Duration,
+};
 
 use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
 
 use crate::log;
 use crate::state::LauncherPaths;
-use crate::util::errors::{wrap, AnyError};
+use crate::util::errors::{wrap, AnyError, CodeError};
 use crate::util::io::{tailf, TailEvent};
 
 pub const SERVICE_LOG_FILE_NAME: &str = "tunnel-service.log";
 
+/// How often to check whether a restarted service is ready.
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Options for showing logs from the service.
+#[derive(Clone, Debug)]
+pub struct ServiceLogOptions {
+	/// Whether to keep printing new lines as they're written.
+	pub follow: bool,
+	/// Number of existing lines to print.
+	pub lines: usize,
+}
+
+/// Logs collected from the service by its platform's backend, oldest first.
+#[derive(Clone, Debug, Default)]
+pub struct CollectedLogs {
+	pub lines: Vec<String>,
+	/// Whether the backend had more logs than could be collected.
+	pub truncated: bool,
+}
+
 #[async_trait]
 pub trait ServiceContainer: Send {
 	async fn run_service(
@@ -21,13 +50,38 @@ pub trait ServiceContainer: Send {
 		log: log::Logger,
 		launcher_paths: LauncherPaths,
 	) -> Result<(), AnyError>;
+
+	/// How the log file is rotated, for services whose logs are written to a
+	/// file by the platform rather than by the CLI.
+	fn log_rotation(&self) -> log::LogRotation {
+		log::LogRotation::default()
+	}
 }
 
 #[async_trait]
 pub trait ServiceManager {
 	/// Registers the current executable as a service to run with the given set
-	/// of arguments.
-	async fn register(&self, exe: PathBuf, args: &[&str]) -> Result<(), AnyError>;
+	/// of arguments and additional environment variables.
+	async fn register(
+		&self,
+		exe: PathBuf,
+		args: &[&str],
+		env: &[(String, String)],
+	) -> Result<(), AnyError>;
+
+	/// Registers the current executable as a service that's started on demand
+	/// when a client connects to its socket, rather than at login.
+	async fn register_socket_activated(
+		&self,
+		_exe: PathBuf,
+		_args: &[&str],
+		_env: &[(String, String)],
+	) -> Result<(), AnyError> {
+		Err(CodeError::UnsupportedPlatform(
+			"socket activation is only supported with systemd".to_string(),
+		)
+		.into())
+	}
 
 	/// Runs the service using the given handle. The executable *must not* take
 	/// any action which may fail prior to calling this to ensure service
@@ -39,15 +93,142 @@ pub trait ServiceManager {
 	) -> Result<(), AnyError>;
 
 	/// Show logs from the running service to standard out.
-	async fn show_logs(&self) -> Result<(), AnyError>;
+	async fn show_logs(&self, options: &ServiceLogOptions) -> Result<(), AnyError>;
+
+	/// Collects the service's logs written since the time, or all that are
+	/// kept if it's None, for exporting them.
+	async fn collect_logs(&self, since: Option<DateTime<Utc>>) -> Result<CollectedLogs, AnyError>;
 
 	/// Gets whether the tunnel service is installed.
 	async fn is_installed(&self) -> Result<bool, AnyError>;
 
+	/// Gets the detailed status of the tunnel service. Unknown or partially
+	/// installed states should be reported in the status, not as errors.
+	async fn status(&self) -> Result<ServiceStatus, AnyError>;
+
+	/// Stops and starts the installed service through the platform's service
+	/// manager. This does not wait for the tunnel to be ready.
+	async fn restart(&self) -> Result<(), AnyError>;
+
 	/// Unregisters the current executable as a service.
 	async fn unregister(&self) -> Result<(), AnyError>;
 }
 
+/// Scope in which the tunnel service is installed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServiceScope {
+	/// Runs as the current user while they're logged in.
+	User,
+	/// Runs at boot as the given user. Requires root to manage. The user is
+	/// only required when registering the service.
+	System { user: Option<String> },
+}
+
+impl ServiceScope {
+	/// Ensures the current process is allowed to manage services in this scope.
+	pub fn ensure_permitted(&self) -> Result<(), CodeError> {
+		check_scope_permitted(self, is_elevated())
+	}
+
+	pub fn is_system(&self) -> bool {
+		matches!(self, ServiceScope::System { .. })
+	}
+}
+
+fn check_scope_permitted(scope: &ServiceScope, is_elevated: bool) -> Result<(), CodeError> {
+	match scope {
+		ServiceScope::System { .. } if cfg!(windows) => Err(CodeError::UnsupportedPlatform(
+			"system-level services are not supported on Windows".to_string(),
+		)),
+		ServiceScope::System { .. } if !is_elevated => Err(CodeError::SystemServiceRequiresRoot),
+		_ => Ok(()),
+	}
+}
+
+#[cfg(unix)]
+fn is_elevated() -> bool {
+	unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(windows)]
+fn is_elevated() -> bool {
+	false
+}
+
+/// Status of the tunnel service as reported by the platform service manager.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ServiceStatus {
+	/// Name of the unit, agent, or registry value backing the service.
+	pub name: String,
+	pub installed: bool,
+	/// Whether the service will start automatically. None if unknown.
+	pub enabled: Option<bool>,
+	/// Whether the service is currently running. None if unknown.
+	pub running: Option<bool>,
+	pub pid: Option<u32>,
+	/// Executable the service is configured to run.
+	pub binary_path: Option<PathBuf>,
+	/// Whether `binary_path` refers to the currently running executable.
+	pub binary_matches_current: Option<bool>,
+	pub last_exit_code: Option<i32>,
+	/// Additional details, such as partially-installed states.
+	pub notes: Vec<String>,
+}
+
+impl ServiceStatus {
+	/// Compares the service's binary path against the given executable.
+	pub fn check_binary(&mut self, current_exe: &Path) {
+		let binary = match &self.binary_path {
+			Some(b) => b,
+			None => return,
+		};
+
+		let canonical = |p: &Path| std::fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+		let matches = canonical(binary) == canonical(current_exe);
+		if !matches {
+			self.notes.push(format!(
+				"The service runs {}, which is not the current executable ({})",
+				binary.display(),
+				current_exe.display()
+			));
+		}
+		self.binary_matches_current = Some(matches);
+	}
+}
+
+fn fmt_opt_bool(v: Option<bool>) -> &'static str {
+	match v {
+		Some(true) => "yes",
+		Some(false) => "no",
+		None => "unknown",
+	}
+}
+
+impl fmt::Display for ServiceStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "Service: {}", self.name)?;
+		writeln!(f, "Installed: {}", fmt_opt_bool(Some(self.installed)))?;
+		writeln!(f, "Enabled: {}", fmt_opt_bool(self.enabled))?;
+		match (self.running, self.pid) {
+			(Some(true), Some(pid)) => writeln!(f, "Running: yes (pid {})", pid)?,
+			(r, _) => writeln!(f, "Running: {}", fmt_opt_bool(r))?,
+		}
+		if let Some(b) = &self.binary_path {
+			match self.binary_matches_current {
+				Some(false) => writeln!(f, "Binary: {} (mismatch)", b.display())?,
+				_ => writeln!(f, "Binary: {}", b.display())?,
+			}
+		}
+		if let Some(c) = self.last_exit_code {
+			writeln!(f, "Last exit code: {}", c)?;
+		}
+		for note in &self.notes {
+			writeln!(f, "Note: {}", note)?;
+		}
+		Ok(())
+	}
+}
+
 #[cfg(target_os = "windows")]
 pub type ServiceManagerImpl = super::service_windows::WindowsService;
 
@@ -59,37 +240,889 @@ pub type ServiceManagerImpl = super::service_macos::LaunchdService;
 
 #[allow(unreachable_code)]
 #[allow(unused_variables)]
-pub fn create_service_manager(log: log::Logger, paths: &LauncherPaths) -> ServiceManagerImpl {
+pub fn create_service_manager(
+	log: log::Logger,
+	paths: &LauncherPaths,
+	scope: ServiceScope,
+) -> ServiceManagerImpl {
 	#[cfg(target_os = "macos")]
 	{
-		super::service_macos::LaunchdService::new(log, paths)
+		super::service_macos::LaunchdService::new(log, paths, scope)
 	}
 	#[cfg(target_os = "windows")]
 	{
-		super::service_windows::WindowsService::new(log, paths)
+		super::service_windows::WindowsService::new(log, paths, scope)
 	}
 	#[cfg(target_os = "linux")]
 	{
-		super::service_linux::SystemdService::new(log, paths.clone())
+		super::service_linux::SystemdService::new(log, paths.clone(), scope)
+	}
+}
+
+/// Checks for an existing registration before the service is installed.
+/// Registrations of the current executable are re-installed in place, while
+/// stale ones are only replaced, by unregistering them first, when `force`
+/// is given.
+pub async fn prepare_service_install(
+	log: &log::Logger,
+	manager: &impl ServiceManager,
+	current_exe: &Path,
+	force: bool,
+) -> Result<(), AnyError> {
+	let mut status = manager.status().await?;
+	if !status.installed {
+		debug!(log, "No existing service registration found");
+		return Ok(());
+	}
+
+	status.check_binary(current_exe);
+	match (stale_reason(&status), force) {
+		(None, false) => {
+			info!(log, "Re-installing the existing service registration");
+			Ok(())
+		}
+		(None, true) => {
+			info!(log, "Replacing the existing service registration");
+			manager.unregister().await
+		}
+		(Some(reason), false) => Err(CodeError::StaleServiceRegistration(reason).into()),
+		(Some(reason), true) => {
+			info!(log, "Replacing stale service registration: {}", reason);
+			manager.unregister().await
+		}
+	}
+}
+
+/// Gets why the registration described by the status can't be reused, if it's stale.
+fn stale_reason(status: &ServiceStatus) -> Option<String> {
+	let binary = status.binary_path.as_ref()?;
+	if !binary.exists() {
+		return Some(format!(
+			"it runs {}, which no longer exists",
+			binary.display()
+		));
+	}
+
+	if status.binary_matches_current == Some(false) {
+		return Some(format!(
+			"it runs {}, which is not the current executable",
+			binary.display()
+		));
+	}
+
+	None
+}
+
+/// Restarts the installed service, waiting up to the `timeout` for `is_ready`
+/// to report that the new tunnel is up, and returns the resulting status.
+pub async fn restart_service<Fut>(
+	manager: &impl ServiceManager,
+	timeout: Duration,
+	mut is_ready: impl FnMut() -> Fut,
+) -> Result<ServiceStatus, AnyError>
+where
+	Fut: Future<Output = bool>,
+{
+	if !manager.is_installed().await? {
+		return Err(CodeError::ServiceNotInstalled.into());
+	}
+
+	manager.restart().await?;
+
+	let wait_ready = async {
+		while !is_ready().await {
+			tokio::time::sleep(READY_POLL_INTERVAL).await;
+		}
+	};
+
+	if tokio::time::timeout(timeout, wait_ready).await.is_err() {
+		return Err(CodeError::ServiceRestartTimeout(timeout.as_secs()).into());
+	}
+
+	manager.status().await
+}
+
+/// Summary of the logs written by `export_logs`.
+#[derive(Serialize, Debug)]
+pub struct LogExportSummary {
+	pub path: PathBuf,
+	pub lines: usize,
+	/// Times of the first and last lines that have one.
+	pub first: Option<DateTime<Utc>>,
+	pub last: Option<DateTime<Utc>>,
+	pub compressed: bool,
+	pub truncated: bool,
+}
+
+impl fmt::Display for LogExportSummary {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Wrote {} lines", self.lines)?;
+		if let (Some(first), Some(last)) = (self.first, self.last) {
+			write!(
+				f,
+				" from {} to {}",
+				first.format("%Y-%m-%d %H:%M:%S UTC"),
+				last.format("%Y-%m-%d %H:%M:%S UTC")
+			)?;
+		}
+		write!(f, " to {}", self.path.display())?;
+		if self.truncated {
+			write!(
+				f,
+				". There were more logs than could be collected, use --since to export fewer"
+			)?;
+		}
+		Ok(())
+	}
+}
+
+/// Exports the service's logs since the time to the file, gzipped if its
+/// name ends in `.gz`. Lines are redacted as they would be when logged.
+pub async fn export_logs(
+	manager: &impl ServiceManager,
+	since: Option<DateTime<Utc>>,
+	path: &Path,
+) -> Result<LogExportSummary, AnyError> {
+	let logs = manager.collect_logs(since).await?;
+	write_exported_logs(&logs, path).map_err(|e| wrap(e, "error writing logs").into())
+}
+
+fn write_exported_logs(logs: &CollectedLogs, path: &Path) -> std::io::Result<LogExportSummary> {
+	let compressed = path.extension().map(|e| e == "gz").unwrap_or(false);
+	let file = BufWriter::new(File::create(path)?);
+	if compressed {
+		let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+		write_redacted_lines(&logs.lines, &mut encoder)?;
+		encoder.finish()?.flush()?;
+	} else {
+		let mut file = file;
+		write_redacted_lines(&logs.lines, &mut file)?;
+		file.flush()?;
 	}
+
+	let mut times = logs.lines.iter().filter_map(|l| log_line_time(l));
+	let first = times.next();
+	Ok(LogExportSummary {
+		path: path.to_path_buf(),
+		lines: logs.lines.len(),
+		first,
+		last: times.next_back().or(first),
+		compressed,
+		truncated: logs.truncated,
+	})
 }
 
+fn write_redacted_lines(lines: &[String], out: &mut impl Write) -> std::io::Result<()> {
+	for line in lines {
+		out.write_all(log::redact::redact(line).as_bytes())?;
+		out.write_all(b"\n")?;
+	}
+	Ok(())
+}
+
+#[derive(Deserialize)]
+struct JsonLogLine {
+	ts: String,
+}
+
+/// Gets the time a log line was written, from the CLI's human or JSON log
+/// formats, or journalctl's `short-iso` output. Human logs are in local time.
+pub(crate) fn log_line_time(line: &str) -> Option<DateTime<Utc>> {
+	if let Some(rest) = line.strip_prefix('[') {
+		let (ts, _) = rest.split_once(']')?;
+		let naive = NaiveDateTime::parse_from_str(ts, "%Y-%m-%d %H:%M:%S").ok()?;
+		return Local
+			.from_local_datetime(&naive)
+			.earliest()
+			.map(|t| t.with_timezone(&Utc));
+	}
+
+	if line.starts_with('{') {
+		let record: JsonLogLine = serde_json::from_str(line).ok()?;
+		return DateTime::parse_from_rfc3339(&record.ts)
+			.ok()
+			.map(|t| t.with_timezone(&Utc));
+	}
+
+	let ts = line.split_whitespace().next()?;
+	DateTime::parse_from_str(ts, "%Y-%m-%dT%H:%M:%S%z")
+		.or_else(|_| DateTime::parse_from_rfc3339(ts))
+		.ok()
+		.map(|t| t.with_timezone(&Utc))
+}
+
+/// Collects the log file's lines since the time, including its rotated
+/// files. Lines without a time, such as the rest of a multi-line message,
+/// go with the line before them.
 #[allow(dead_code)] // unused on Linux
-pub(crate) async fn tail_log_file(log_file: &Path) -> Result<(), AnyError> {
+pub(crate) fn collect_log_file(
+	log_file: &Path,
+	since: Option<DateTime<Utc>>,
+) -> Result<CollectedLogs, AnyError> {
+	let mut files = log::rotated_log_files(log_file);
+	files.push(log_file.to_path_buf());
+
+	let mut lines = vec![];
+	let mut keep = since.is_none();
+	for path in files {
+		let file_lines = match read_log_lines(&path) {
+			Ok(l) => l,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+			Err(e) => return Err(wrap(e, format!("error reading {}", path.display())).into()),
+		};
+
+		for line in file_lines {
+			if let (Some(since), Some(t)) = (since, log_line_time(&line)) {
+				keep = t >= since;
+			}
+			if keep {
+				lines.push(line);
+			}
+		}
+	}
+
+	Ok(CollectedLogs {
+		lines,
+		truncated: false,
+	})
+}
+
+#[allow(dead_code)] // unused on Linux
+pub(crate) async fn tail_log_file(
+	log_file: &Path,
+	options: &ServiceLogOptions,
+) -> Result<(), AnyError> {
 	if !log_file.exists() {
-		println!("The tunnel service has not started yet.");
+		eprintln!("The tunnel service has not started yet.");
 		return Ok(());
 	}
 
+	let mut out = std::io::stdout();
+	write_rotated_log_tail(log_file, options.lines, &mut out)
+		.map_err(|e| wrap(e, "error reading rotated log files"))?;
+
 	let file = std::fs::File::open(log_file).map_err(|e| wrap(e, "error opening log file"))?;
-	let mut rx = tailf(file, 20);
-	while let Some(line) = rx.recv().await {
-		match line {
-			TailEvent::Line(l) => print!("{}", l),
-			TailEvent::Reset => println!("== Tunnel service restarted =="),
+	write_log_tail(file, options, &mut out).await
+}
+
+/// Writes the lines from rotated log files that come before the last `lines`
+/// of the active file, oldest first, for when it has fewer than that.
+#[allow(dead_code)] // unused on Linux
+fn write_rotated_log_tail(
+	log_file: &Path,
+	lines: usize,
+	out: &mut impl std::io::Write,
+) -> std::io::Result<()> {
+	let mut needed = lines.saturating_sub(read_log_lines(log_file)?.len());
+	let mut older = Vec::new();
+	for path in log::rotated_log_files(log_file).iter().rev() {
+		if needed == 0 {
+			break;
+		}
+
+		let mut file_lines = read_log_lines(path)?;
+		let from = file_lines.len().saturating_sub(needed);
+		needed -= file_lines.len() - from;
+		older.push(file_lines.split_off(from));
+	}
+
+	for line in older.into_iter().rev().flatten() {
+		out.write_all(line.as_bytes())?;
+		out.write_all(b"\n")?;
+	}
+	out.flush()
+}
+
+fn read_log_lines(path: &Path) -> std::io::Result<Vec<String>> {
+	use std::io::Read;
+
+	let file = std::fs::File::open(path)?;
+	let mut contents = Vec::new();
+	if path.extension().map(|e| e == "gz").unwrap_or(false) {
+		flate2::read::GzDecoder::new(file).read_to_end(&mut contents)?;
+	} else {
+		std::io::BufReader::new(file).read_to_end(&mut contents)?;
+	}
+
+	Ok(String::from_utf8_lossy(&contents)
+		.lines()
+		.map(|l| l.to_string())
+		.collect())
+}
+
+#[allow(dead_code)] // unused on Linux
+async fn write_log_tail(
+	file: std::fs::File,
+	options: &ServiceLogOptions,
+	out: &mut (impl std::io::Write + Send),
+) -> Result<(), AnyError> {
+	let mut rx = tailf(file, options.lines);
+	let mut write_event = |event: TailEvent| -> Result<(), AnyError> {
+		match event {
+			TailEvent::Line(l) => out.write_all(l.as_bytes()),
+			TailEvent::Reset => out.write_all(b"== Tunnel service log rotated or restarted ==\n"),
 			TailEvent::Err(e) => return Err(wrap(e, "error reading log file").into()),
 		}
+		.and_then(|_| out.flush())
+		.map_err(|e| wrap(e, "error writing logs").into())
+	};
+
+	// existing lines are sent before tailf returns, so they can be drained
+	// without waiting if we're not following the file
+	if !options.follow {
+		while let Ok(event) = rx.try_recv() {
+			write_event(event)?;
+		}
+		return Ok(());
+	}
+
+	while let Some(event) = rx.recv().await {
+		write_event(event)?;
 	}
 
 	Ok(())
 }
+
+/// Rotates the log file if it's larger than `max_size`, keeping one previous
+/// file. Service managers like launchd keep the file open, so it's copied and
+/// truncated rather than renamed. Returns whether the file was rotated.
+pub(crate) fn rotate_log_file(
+	log_file: &Path,
+	rotation: &log::LogRotation,
+) -> std::io::Result<bool> {
+	match std::fs::metadata(log_file) {
+		Ok(m) if m.len() > rotation.max_size => {}
+		Ok(_) => return Ok(false),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+		Err(e) => return Err(e),
+	}
+
+	log::shift_rotated_log_files(log_file, rotation)?;
+	if rotation.keep > 0 {
+		std::fs::copy(log_file, log::rotated_log_path(log_file, 1, false))?;
+	}
+	std::fs::OpenOptions::new()
+		.write(true)
+		.open(log_file)?
+		.set_len(0)?;
+	log::compress_rotated_log_files(log_file, rotation)?;
+
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+
+	#[derive(Clone, Default)]
+	struct MockService {
+		installed: bool,
+		binary_path: Option<PathBuf>,
+		logs: CollectedLogs,
+		calls: Arc<Mutex<Vec<&'static str>>>,
+	}
+
+	impl MockService {
+		fn record(&self, call: &'static str) {
+			self.calls.lock().unwrap().push(call);
+		}
+	}
+
+	#[async_trait]
+	impl ServiceManager for MockService {
+		async fn register(
+			&self,
+			_exe: PathBuf,
+			_args: &[&str],
+			_env: &[(String, String)],
+		) -> Result<(), AnyError> {
+			self.record("register");
+			Ok(())
+		}
+
+		async fn run(
+			self,
+			_launcher_paths: LauncherPaths,
+			_handle: impl 'static + ServiceContainer,
+		) -> Result<(), AnyError> {
+			unimplemented!()
+		}
+
+		async fn show_logs(&self, _options: &ServiceLogOptions) -> Result<(), AnyError> {
+			unimplemented!()
+		}
+
+		async fn collect_logs(
+			&self,
+			_since: Option<DateTime<Utc>>,
+		) -> Result<CollectedLogs, AnyError> {
+			self.record("collect_logs");
+			Ok(self.logs.clone())
+		}
+
+		async fn is_installed(&self) -> Result<bool, AnyError> {
+			self.record("is_installed");
+			Ok(self.installed)
+		}
+
+		async fn status(&self) -> Result<ServiceStatus, AnyError> {
+			self.record("status");
+			Ok(ServiceStatus {
+				installed: self.installed,
+				running: Some(true),
+				binary_path: self.binary_path.clone(),
+				..Default::default()
+			})
+		}
+
+		async fn restart(&self) -> Result<(), AnyError> {
+			self.record("restart");
+			Ok(())
+		}
+
+		async fn unregister(&self) -> Result<(), AnyError> {
+			self.record("unregister");
+			Ok(())
+		}
+	}
+
+	#[tokio::test]
+	async fn test_restart_waits_for_ready() {
+		let service = MockService {
+			installed: true,
+			..Default::default()
+		};
+
+		let status = restart_service(&service, Duration::from_secs(10), || {
+			let service = service.clone();
+			async move {
+				service.record("is_ready");
+				service.calls.lock().unwrap().len() >= 4
+			}
+		})
+		.await
+		.unwrap();
+
+		assert_eq!(status.running, Some(true));
+		assert_eq!(
+			*service.calls.lock().unwrap(),
+			vec!["is_installed", "restart", "is_ready", "is_ready", "status"]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_restart_times_out() {
+		let service = MockService {
+			installed: true,
+			..Default::default()
+		};
+
+		let r = restart_service(&service, Duration::from_millis(50), || async { false }).await;
+		assert!(matches!(
+			r,
+			Err(AnyError::CodeError(CodeError::ServiceRestartTimeout(_)))
+		));
+		assert_eq!(
+			*service.calls.lock().unwrap(),
+			vec!["is_installed", "restart"]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_restart_requires_install() {
+		let service = MockService::default();
+		let r = restart_service(&service, Duration::from_secs(1), || async { true }).await;
+		assert!(matches!(
+			r,
+			Err(AnyError::CodeError(CodeError::ServiceNotInstalled))
+		));
+		assert_eq!(*service.calls.lock().unwrap(), vec!["is_installed"]);
+	}
+
+	/// Creates a service whose registration runs the given executable.
+	fn registered_service(binary_path: &Path) -> MockService {
+		MockService {
+			installed: true,
+			binary_path: Some(binary_path.to_path_buf()),
+			..Default::default()
+		}
+	}
+
+	#[tokio::test]
+	async fn test_install_without_registration() {
+		let service = MockService::default();
+		prepare_service_install(&log::Logger::test(), &service, Path::new("/code"), false)
+			.await
+			.unwrap();
+		assert_eq!(*service.calls.lock().unwrap(), vec!["status"]);
+	}
+
+	#[tokio::test]
+	async fn test_install_reuses_current_registration() {
+		let dir = tempfile::tempdir().unwrap();
+		let exe = dir.path().join("code");
+		std::fs::write(&exe, "").unwrap();
+
+		let service = registered_service(&exe);
+		prepare_service_install(&log::Logger::test(), &service, &exe, false)
+			.await
+			.unwrap();
+		assert_eq!(*service.calls.lock().unwrap(), vec!["status"]);
+	}
+
+	#[tokio::test]
+	async fn test_install_refuses_missing_binary() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = registered_service(&dir.path().join("deleted"));
+
+		let r = prepare_service_install(
+			&log::Logger::test(),
+			&service,
+			&dir.path().join("code"),
+			false,
+		)
+		.await;
+		assert!(matches!(
+			r,
+			Err(AnyError::CodeError(CodeError::StaleServiceRegistration(reason))) if reason.contains("no longer exists")
+		));
+		assert_eq!(*service.calls.lock().unwrap(), vec!["status"]);
+	}
+
+	#[tokio::test]
+	async fn test_install_refuses_other_binary() {
+		let dir = tempfile::tempdir().unwrap();
+		let old_exe = dir.path().join("code-old");
+		std::fs::write(&old_exe, "").unwrap();
+		let exe = dir.path().join("code");
+		std::fs::write(&exe, "").unwrap();
+
+		let service = registered_service(&old_exe);
+		let r = prepare_service_install(&log::Logger::test(), &service, &exe, false).await;
+		assert!(matches!(
+			r,
+			Err(AnyError::CodeError(CodeError::StaleServiceRegistration(reason))) if reason.contains("not the current executable")
+		));
+	}
+
+	#[tokio::test]
+	async fn test_install_force_replaces_stale() {
+		let dir = tempfile::tempdir().unwrap();
+		let service = registered_service(&dir.path().join("deleted"));
+
+		prepare_service_install(
+			&log::Logger::test(),
+			&service,
+			&dir.path().join("code"),
+			true,
+		)
+		.await
+		.unwrap();
+		assert_eq!(*service.calls.lock().unwrap(), vec!["status", "unregister"]);
+	}
+
+	#[test]
+	fn test_user_scope_always_permitted() {
+		assert!(check_scope_permitted(&ServiceScope::User, false).is_ok());
+		assert!(check_scope_permitted(&ServiceScope::User, true).is_ok());
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_system_scope_requires_root() {
+		let scope = ServiceScope::System {
+			user: Some("builder".to_string()),
+		};
+		assert!(matches!(
+			check_scope_permitted(&scope, false),
+			Err(CodeError::SystemServiceRequiresRoot)
+		));
+		assert!(check_scope_permitted(&scope, true).is_ok());
+	}
+
+	#[test]
+	#[cfg(windows)]
+	fn test_system_scope_unsupported_on_windows() {
+		let scope = ServiceScope::System { user: None };
+		assert!(matches!(
+			check_scope_permitted(&scope, true),
+			Err(CodeError::UnsupportedPlatform(_))
+		));
+	}
+
+	/// Writer that forwards each write to a channel, so that output can be
+	/// checked while the tail is still following the file.
+	struct ChannelWriter(tokio::sync::mpsc::UnboundedSender<String>);
+
+	impl std::io::Write for ChannelWriter {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.send(String::from_utf8_lossy(buf).to_string()).ok();
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[tokio::test]
+	async fn test_log_tail_without_follow() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("tunnel.log");
+		std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+
+		let mut out = vec![];
+		let options = ServiceLogOptions {
+			follow: false,
+			lines: 2,
+		};
+		write_log_tail(std::fs::File::open(&path).unwrap(), &options, &mut out)
+			.await
+			.unwrap();
+
+		assert_eq!(String::from_utf8(out).unwrap(), "two\nthree\n");
+	}
+
+	#[tokio::test]
+	async fn test_log_tail_follows_growing_file() {
+		use std::io::Write;
+
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("tunnel.log");
+		std::fs::write(&path, "one\ntwo\n").unwrap();
+
+		let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+		let file = std::fs::File::open(&path).unwrap();
+		let tail = tokio::spawn(async move {
+			let options = ServiceLogOptions {
+				follow: true,
+				lines: 1,
+			};
+			write_log_tail(file, &options, &mut ChannelWriter(tx)).await
+		});
+
+		assert_eq!(rx.recv().await.unwrap(), "two\n");
+
+		let mut append = std::fs::OpenOptions::new()
+			.append(true)
+			.open(&path)
+			.unwrap();
+		writeln!(append, "three").unwrap();
+		assert_eq!(rx.recv().await.unwrap(), "three\n");
+
+		// rotation truncates the file, which is reported before new lines
+		let rotation = log::LogRotation {
+			max_size: 0,
+			..Default::default()
+		};
+		assert!(rotate_log_file(&path, &rotation).unwrap());
+		writeln!(append, "four").unwrap();
+		assert!(rx.recv().await.unwrap().contains("rotated"));
+		assert_eq!(rx.recv().await.unwrap(), "four\n");
+
+		tail.abort();
+	}
+
+	#[test]
+	fn test_rotate_log_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("tunnel.log");
+
+		let rotation = log::LogRotation {
+			max_size: 4,
+			keep: 2,
+			compress: false,
+		};
+
+		assert!(!rotate_log_file(&path, &rotation).unwrap());
+
+		std::fs::write(&path, "abc").unwrap();
+		assert!(!rotate_log_file(&path, &rotation).unwrap());
+
+		std::fs::write(&path, "abcdef").unwrap();
+		assert!(rotate_log_file(&path, &rotation).unwrap());
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+		assert_eq!(
+			std::fs::read_to_string(dir.path().join("tunnel.log.1")).unwrap(),
+			"abcdef"
+		);
+
+		// older files are shifted along and dropped past the limit
+		std::fs::write(&path, "ghijkl").unwrap();
+		assert!(rotate_log_file(&path, &rotation).unwrap());
+		std::fs::write(&path, "mnopqr").unwrap();
+		assert!(rotate_log_file(&path, &rotation).unwrap());
+		assert_eq!(
+			std::fs::read_to_string(dir.path().join("tunnel.log.1")).unwrap(),
+			"mnopqr"
+		);
+		assert_eq!(
+			std::fs::read_to_string(dir.path().join("tunnel.log.2")).unwrap(),
+			"ghijkl"
+		);
+		assert!(!dir.path().join("tunnel.log.3").exists());
+	}
+
+	#[test]
+	fn test_rotated_log_tail_reads_across_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("tunnel.log");
+		let rotation = log::LogRotation {
+			max_size: 0,
+			keep: 3,
+			compress: true,
+		};
+
+		for contents in ["one\ntwo\n", "three\n", "four\nfive\n"] {
+			std::fs::write(&path, contents).unwrap();
+			rotate_log_file(&path, &rotation).unwrap();
+		}
+		std::fs::write(&path, "six\n").unwrap();
+		assert!(dir.path().join("tunnel.log.3.gz").exists());
+		assert!(dir.path().join("tunnel.log.1").exists());
+
+		let tail = |lines| {
+			let mut out = vec![];
+			write_rotated_log_tail(&path, lines, &mut out).unwrap();
+			String::from_utf8(out).unwrap()
+		};
+
+		assert_eq!(tail(1), "");
+		assert_eq!(tail(3), "four\nfive\n");
+		assert_eq!(tail(5), "two\nthree\nfour\nfive\n");
+		assert_eq!(tail(100), "one\ntwo\nthree\nfour\nfive\n");
+	}
+
+	fn utc(s: &str) -> DateTime<Utc> {
+		DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+	}
+
+	fn json_line(ts: &str, message: &str) -> String {
+		format!(
+			r#"{{"ts":"{}","level":"info","target":"cli","message":"{}","fields":{{}}}}"#,
+			ts, message
+		)
+	}
+
+	#[test]
+	fn test_log_line_time() {
+		let local = Local
+			.from_local_datetime(
+				&NaiveDateTime::parse_from_str("2024-03-01 10:00:00", "%Y-%m-%d %H:%M:%S").unwrap(),
+			)
+			.earliest()
+			.unwrap();
+		assert_eq!(
+			log_line_time("[2024-03-01 10:00:00] info Tunnel started"),
+			Some(local.with_timezone(&Utc))
+		);
+		assert_eq!(
+			log_line_time(&json_line("2024-03-01T10:00:00.000+02:00", "hi")),
+			Some(utc("2024-03-01T08:00:00Z"))
+		);
+		assert_eq!(
+			log_line_time("2024-03-01T10:00:00+0000 host code-tunnel[123]: started"),
+			Some(utc("2024-03-01T10:00:00Z"))
+		);
+		assert_eq!(log_line_time("    at some::frame"), None);
+		assert_eq!(log_line_time("[not a time] x"), None);
+		assert_eq!(log_line_time("{not json"), None);
+		assert_eq!(log_line_time(""), None);
+	}
+
+	#[test]
+	fn test_collect_log_file_since() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("tunnel.log");
+		assert!(collect_log_file(&path, None).unwrap().lines.is_empty());
+
+		let rotation = log::LogRotation {
+			max_size: 0,
+			keep: 2,
+			compress: true,
+		};
+		let old = [
+			json_line("2024-03-01T08:00:00Z", "one"),
+			json_line("2024-03-01T09:00:00Z", "two"),
+			"  continues two".to_string(),
+		];
+		std::fs::write(&path, old.join("\n") + "\n").unwrap();
+		rotate_log_file(&path, &rotation).unwrap();
+		std::fs::write(&path, json_line("2024-03-01T10:00:00Z", "three") + "\n").unwrap();
+		rotate_log_file(&path, &rotation).unwrap();
+		std::fs::write(&path, json_line("2024-03-01T11:00:00Z", "four") + "\n").unwrap();
+
+		let all = collect_log_file(&path, None).unwrap();
+		assert_eq!(all.lines.len(), 5);
+		assert!(all.lines[0].contains("one"));
+		assert!(all.lines[4].contains("four"));
+
+		let since = collect_log_file(&path, Some(utc("2024-03-01T08:30:00Z"))).unwrap();
+		assert_eq!(since.lines.len(), 4);
+		assert!(since.lines[0].contains("two"));
+		assert_eq!(since.lines[1], "  continues two");
+
+		let none = collect_log_file(&path, Some(utc("2024-03-02T00:00:00Z"))).unwrap();
+		assert!(none.lines.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_export_logs() {
+		use std::io::Read;
+
+		let dir = tempfile::tempdir().unwrap();
+		let service = MockService {
+			logs: CollectedLogs {
+				lines: vec![
+					"-- Boot 1a2b --".to_string(),
+					"2024-03-01T10:00:00+0000 host code-tunnel[1]: Tunnel started".to_string(),
+					"2024-03-01T10:05:00+0000 host code-tunnel[1]: request failed: Authorization: Bearer abc.def".to_string(),
+					"2024-03-01T10:30:00+0000 host code-tunnel[1]: Tunnel stopped".to_string(),
+				],
+				truncated: false,
+			},
+			..Default::default()
+		};
+
+		let path = dir.path().join("tunnel.log");
+		let summary = export_logs(&service, None, &path).await.unwrap();
+		let contents = std::fs::read_to_string(&path).unwrap();
+		assert_eq!(contents.lines().count(), 4);
+		assert!(contents.contains("Authorization: Bearer <redacted>"));
+		assert!(!contents.contains("abc.def"));
+		assert!(!summary.compressed);
+		assert_eq!(summary.lines, 4);
+		assert_eq!(summary.first, Some(utc("2024-03-01T10:00:00Z")));
+		assert_eq!(summary.last, Some(utc("2024-03-01T10:30:00Z")));
+		assert_eq!(
+			summary.to_string(),
+			format!(
+				"Wrote 4 lines from 2024-03-01 10:00:00 UTC to 2024-03-01 10:30:00 UTC to {}",
+				path.display()
+			)
+		);
+
+		let gz_path = dir.path().join("tunnel.log.gz");
+		let summary = export_logs(&service, None, &gz_path).await.unwrap();
+		assert!(summary.compressed);
+		let mut decompressed = String::new();
+		flate2::read::GzDecoder::new(File::open(&gz_path).unwrap())
+			.read_to_string(&mut decompressed)
+			.unwrap();
+		assert_eq!(decompressed, contents);
+
+		let empty = MockService {
+			logs: CollectedLogs {
+				lines: vec![],
+				truncated: true,
+			},
+			..Default::default()
+		};
+		let summary = export_logs(&empty, None, &path).await.unwrap();
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+		assert!(summary.to_string().starts_with("Wrote 0 lines to "));
+		assert!(summary.to_string().contains("--since"));
+	}
+}
@@ -4,66 +4,114 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
-	fs::File,
+	collections::HashMap,
+	fs::{File, Permissions},
+	future::Future,
 	io::{self, Write},
+	os::unix::fs::PermissionsExt,
 	path::PathBuf,
 	process::Command,
 };
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use zbus::{dbus_proxy, zvariant, Connection};
 
 use crate::{
-	constants::{APPLICATION_NAME, PRODUCT_NAME_LONG},
+	constants::{
+		ACTIVATION_SOCKET_NAME, APPLICATION_NAME, DEFAULT_COMMAND_TIMEOUT, PRODUCT_NAME_LONG,
+		SERVICE_LOG_EXPORT_LIMIT, SERVICE_LOG_EXPORT_TIMEOUT,
+	},
 	log,
 	state::LauncherPaths,
-	util::errors::{wrap, AnyError, DbusConnectFailedError},
+	util::{
+		command::{new_tokio_command, run_command, RunOptions},
+		errors::{wrap, AnyError, CodeError, DbusConnectFailedError},
+	},
 };
 
-use super::ServiceManager;
+use super::{
+	service::{CollectedLogs, ServiceLogOptions, ServiceScope, ServiceStatus},
+	ServiceManager,
+};
+
+/// Directory that system-level units are installed into.
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
 
 pub struct SystemdService {
 	log: log::Logger,
 	service_file: PathBuf,
+	socket_file: PathBuf,
+	scope: ServiceScope,
 }
 
 impl SystemdService {
-	pub fn new(log: log::Logger, paths: LauncherPaths) -> Self {
+	pub fn new(log: log::Logger, paths: LauncherPaths, scope: ServiceScope) -> Self {
+		let unit_dir = match scope {
+			ServiceScope::User => paths.root().to_path_buf(),
+			ServiceScope::System { .. } => PathBuf::from(SYSTEM_UNIT_DIR),
+		};
+
 		Self {
 			log,
-			service_file: paths.root().join(SystemdService::service_name_string()),
+			service_file: unit_dir.join(SystemdService::service_name_string()),
+			socket_file: unit_dir.join(SystemdService::socket_name_string()),
+			scope,
 		}
 	}
 }
 
 impl SystemdService {
-	async fn connect() -> Result<Connection, AnyError> {
-		let connection = Connection::session()
+	async fn connect(&self) -> Result<Connection, AnyError> {
+		let connecting = async {
+			match self.scope {
+				ServiceScope::User => Connection::session().await,
+				ServiceScope::System { .. } => Connection::system().await,
+			}
+		};
+		let connection = tokio::time::timeout(DEFAULT_COMMAND_TIMEOUT, connecting)
 			.await
+			.map_err(|_| {
+				DbusConnectFailedError(format!(
+					"no answer within {}s",
+					DEFAULT_COMMAND_TIMEOUT.as_secs()
+				))
+			})?
 			.map_err(|e| DbusConnectFailedError(e.to_string()))?;
 		Ok(connection)
 	}
 
-	async fn proxy(connection: &Connection) -> Result<SystemdManagerDbusProxy<'_>, AnyError> {
-		let proxy = SystemdManagerDbusProxy::new(connection)
-			.await
-			.map_err(|e| {
-				wrap(
-					e,
-					"error connecting to systemd, you may need to re-run with sudo:",
-				)
-			})?;
+	/// Scope arguments passed to systemctl and journalctl.
+	fn scope_args(&self) -> &'static [&'static str] {
+		match self.scope {
+			ServiceScope::User => &["--user"],
+			ServiceScope::System { .. } => &[],
+		}
+	}
 
-		Ok(proxy)
+	async fn proxy(connection: &Connection) -> Result<SystemdManagerDbusProxy<'_>, AnyError> {
+		systemd_call(
+			SystemdManagerDbusProxy::new(connection),
+			"error connecting to systemd, you may need to re-run with sudo:",
+		)
+		.await
 	}
 
 	fn service_path_string(&self) -> String {
 		self.service_file.as_os_str().to_string_lossy().to_string()
 	}
 
+	fn socket_path_string(&self) -> String {
+		self.socket_file.as_os_str().to_string_lossy().to_string()
+	}
+
 	fn service_name_string() -> String {
 		format!("{}-tunnel.service", APPLICATION_NAME)
 	}
+
+	fn socket_name_string() -> String {
+		format!("{}-tunnel.socket", APPLICATION_NAME)
+	}
 }
 
 #[async_trait]
@@ -72,67 +120,211 @@ impl ServiceManager for SystemdService {
 		&self,
 		exe: std::path::PathBuf,
 		args: &[&str],
+		env: &[(String, String)],
 	) -> Result<(), crate::util::errors::AnyError> {
-		let connection = SystemdService::connect().await?;
+		self.scope.ensure_permitted()?;
+		let connection = self.connect().await?;
 		let proxy = SystemdService::proxy(&connection).await?;
 
-		write_systemd_service_file(&self.service_file, exe, args)
-			.map_err(|e| wrap(e, "error creating service file"))?;
-
-		proxy
-			.link_unit_files(
-				vec![self.service_path_string()],
-				/* 'runtime only'= */ false,
-				/* replace existing = */ true,
+		write_unit_file(
+			&self.service_file,
+			&systemd_service_file_contents(exe, args, env, &self.scope, false),
+		)
+		.map_err(|e| wrap(e, "error creating service file"))?;
+
+		// remove the socket from a previous, socket activated installation
+		if self.socket_file.exists() {
+			systemd_call(
+				proxy.stop_unit(SystemdService::socket_name_string(), "replace".to_string()),
+				"error stopping unit",
+			)
+			.await
+			.ok();
+			systemd_call(
+				proxy.disable_unit_files(vec![SystemdService::socket_name_string()], false),
+				"error disabling unit files",
 			)
 			.await
-			.map_err(|e| wrap(e, "error registering service"))?;
+			.ok();
+			std::fs::remove_file(&self.socket_file).ok();
+		}
+
+		// system units are written directly into the unit directory, so only
+		// user units need to be linked
+		if !self.scope.is_system() {
+			systemd_call(
+				proxy.link_unit_files(
+					vec![self.service_path_string()],
+					/* 'runtime only'= */ false,
+					/* replace existing = */ true,
+				),
+				"error registering service",
+			)
+			.await?;
+		}
 
 		info!(self.log, "Successfully registered service...");
 
-		if let Err(e) = proxy.reload().await {
-			warning!(self.log, "Error issuing reload(): {}", e);
+		if let Err(e) = systemd_call(proxy.reload(), "error issuing reload()").await {
+			warning!(self.log, "{}", e);
 		}
 
 		// note: enablement is implicit in recent systemd version, but required for older systems
 		// https://github.com/microsoft/vscode/issues/167489#issuecomment-1331222826
-		proxy
-			.enable_unit_files(
+		systemd_call(
+			proxy.enable_unit_files(
 				vec![SystemdService::service_name_string()],
 				/* 'runtime only'= */ false,
 				/* replace existing = */ true,
-			)
-			.await
-			.map_err(|e| wrap(e, "error enabling unit files for service"))?;
+			),
+			"error enabling unit files for service",
+		)
+		.await?;
 
 		info!(self.log, "Successfully enabled unit files...");
 
-		proxy
-			.start_unit(SystemdService::service_name_string(), "replace".to_string())
-			.await
-			.map_err(|e| wrap(e, "error starting service"))?;
+		systemd_call(
+			proxy.start_unit(SystemdService::service_name_string(), "replace".to_string()),
+			"error starting service",
+		)
+		.await?;
 
 		info!(self.log, "Tunnel service successfully started");
 
-		if std::env::var("SSH_CLIENT").is_ok() || std::env::var("SSH_TTY").is_ok() {
+		if !self.scope.is_system()
+			&& (std::env::var("SSH_CLIENT").is_ok() || std::env::var("SSH_TTY").is_ok())
+		{
 			info!(self.log, "Tip: run `sudo loginctl enable-linger $USER` to ensure the service stays running after you disconnect.");
 		}
 
 		Ok(())
 	}
 
+	async fn register_socket_activated(
+		&self,
+		exe: PathBuf,
+		args: &[&str],
+		env: &[(String, String)],
+	) -> Result<(), AnyError> {
+		self.scope.ensure_permitted()?;
+		let connection = self.connect().await?;
+		let proxy = SystemdService::proxy(&connection).await?;
+
+		write_unit_file(
+			&self.service_file,
+			&systemd_service_file_contents(exe, args, env, &self.scope, true),
+		)
+		.and_then(|_| write_unit_file(&self.socket_file, &systemd_socket_file_contents()))
+		.map_err(|e| wrap(e, "error creating service file"))?;
+
+		if !self.scope.is_system() {
+			systemd_call(
+				proxy.link_unit_files(
+					vec![self.service_path_string(), self.socket_path_string()],
+					/* 'runtime only'= */ false,
+					/* replace existing = */ true,
+				),
+				"error registering service",
+			)
+			.await?;
+		}
+
+		info!(self.log, "Successfully registered service...");
+
+		if let Err(e) = systemd_call(proxy.reload(), "error issuing reload()").await {
+			warning!(self.log, "{}", e);
+		}
+
+		// the service is started by its socket, so stop and disable any
+		// instance from a previous, non-activated installation.
+		systemd_call(
+			proxy.stop_unit(SystemdService::service_name_string(), "replace".to_string()),
+			"error stopping unit",
+		)
+		.await
+		.ok();
+		systemd_call(
+			proxy.disable_unit_files(vec![SystemdService::service_name_string()], false),
+			"error disabling unit files",
+		)
+		.await
+		.ok();
+
+		systemd_call(
+			proxy.enable_unit_files(
+				vec![SystemdService::socket_name_string()],
+				/* 'runtime only'= */ false,
+				/* replace existing = */ true,
+			),
+			"error enabling unit files for socket",
+		)
+		.await?;
+
+		systemd_call(
+			proxy.start_unit(SystemdService::socket_name_string(), "replace".to_string()),
+			"error starting socket",
+		)
+		.await?;
+
+		info!(
+			self.log,
+			"Tunnel socket is listening, the tunnel will start when a client connects"
+		);
+
+		Ok(())
+	}
+
 	async fn is_installed(&self) -> Result<bool, AnyError> {
-		let connection = SystemdService::connect().await?;
+		let connection = self.connect().await?;
 		let proxy = SystemdService::proxy(&connection).await?;
-		let state = proxy
-			.get_unit_file_state(SystemdService::service_name_string())
-			.await;
-
-		if let Ok(s) = state {
-			Ok(s == "enabled")
-		} else {
-			Ok(false)
+
+		for unit in [
+			SystemdService::service_name_string(),
+			SystemdService::socket_name_string(),
+		] {
+			if let Ok(s) = systemd_call(
+				proxy.get_unit_file_state(unit),
+				"error getting unit file state",
+			)
+			.await
+			{
+				if s == "enabled" {
+					return Ok(true);
+				}
+			}
+		}
+
+		Ok(false)
+	}
+
+	async fn status(&self) -> Result<ServiceStatus, AnyError> {
+		let service_name = SystemdService::service_name_string();
+		let mut args = self.scope_args().to_vec();
+		args.extend_from_slice(&[
+			"show",
+			service_name.as_str(),
+			"--property=LoadState,UnitFileState,ActiveState,MainPID,ExecMainStatus,ExecMainExitTimestamp,ExecStart",
+		]);
+		let mut cmd = new_tokio_command("systemctl");
+		cmd.args(args);
+		let output = run_command(cmd, &RunOptions::default()).await?;
+
+		let mut status =
+			parse_systemctl_show(service_name, &String::from_utf8_lossy(&output.stdout));
+
+		if !output.status.success() {
+			status.notes.push(format!(
+				"systemctl exited with code {}: {}",
+				output.status.code().unwrap_or(-1),
+				String::from_utf8_lossy(&output.stderr).trim()
+			));
+		}
+
+		if let Ok(exe) = std::env::current_exe() {
+			status.check_binary(&exe);
 		}
+
+		Ok(status)
 	}
 
 	async fn run(
@@ -143,47 +335,122 @@ impl ServiceManager for SystemdService {
 		handle.run_service(self.log, launcher_paths).await
 	}
 
-	async fn show_logs(&self) -> Result<(), AnyError> {
-		// show the systemctl status header...
-		Command::new("systemctl")
-			.args([
-				"--user",
-				"status",
-				"-n",
-				"0",
-				&SystemdService::service_name_string(),
-			])
-			.status()
-			.map(|s| s.code().unwrap_or(1))
-			.map_err(|e| wrap(e, "error running systemctl"))?;
-
-		// then follow log files
+	async fn show_logs(&self, options: &ServiceLogOptions) -> Result<(), AnyError> {
+		// show the systemctl status header, which exits unsuccessfully when
+		// the service isn't running...
+		let mut cmd = new_tokio_command("systemctl");
+		cmd.args(self.scope_args()).args([
+			"status",
+			"-n",
+			"0",
+			&SystemdService::service_name_string(),
+		]);
+		let header = run_command(cmd, &RunOptions::default()).await?;
+		io::stdout()
+			.write_all(&header.stdout)
+			.map_err(|e| wrap(e, "error writing systemctl status"))?;
+
+		// then show log files
 		Command::new("journalctl")
-			.args(["--user", "-f", "-u", &SystemdService::service_name_string()])
+			.args(self.scope_args())
+			.args(journalctl_args(
+				&SystemdService::service_name_string(),
+				options,
+			))
 			.status()
 			.map(|s| s.code().unwrap_or(1))
 			.map_err(|e| wrap(e, "error running journalctl"))?;
 		Ok(())
 	}
 
-	async fn unregister(&self) -> Result<(), crate::util::errors::AnyError> {
-		let connection = SystemdService::connect().await?;
+	async fn collect_logs(&self, since: Option<DateTime<Utc>>) -> Result<CollectedLogs, AnyError> {
+		let mut cmd = new_tokio_command("journalctl");
+		cmd.args(self.scope_args()).args(journalctl_export_args(
+			&SystemdService::service_name_string(),
+			since,
+		));
+
+		let output = run_command(
+			cmd,
+			&RunOptions {
+				timeout: SERVICE_LOG_EXPORT_TIMEOUT,
+				output_limit: SERVICE_LOG_EXPORT_LIMIT,
+			},
+		)
+		.await?
+		.check()?;
+
+		Ok(CollectedLogs {
+			lines: journal_lines(&output.stdout, output.truncated),
+			truncated: output.truncated,
+		})
+	}
+
+	async fn restart(&self) -> Result<(), AnyError> {
+		self.scope.ensure_permitted()?;
+		let connection = self.connect().await?;
 		let proxy = SystemdService::proxy(&connection).await?;
 
-		proxy
-			.stop_unit(SystemdService::service_name_string(), "replace".to_string())
-			.await
-			.map_err(|e| wrap(e, "error unregistering service"))?;
+		systemd_call(
+			proxy.restart_unit(SystemdService::service_name_string(), "replace".to_string()),
+			"error restarting service",
+		)
+		.await?;
 
-		info!(self.log, "Successfully stopped service...");
+		info!(self.log, "Tunnel service restarted");
 
-		proxy
-			.disable_unit_files(
-				vec![SystemdService::service_name_string()],
-				/* 'runtime only'= */ false,
+		Ok(())
+	}
+
+	async fn unregister(&self) -> Result<(), crate::util::errors::AnyError> {
+		self.scope.ensure_permitted()?;
+		let connection = self.connect().await?;
+		let proxy = SystemdService::proxy(&connection).await?;
+
+		// Units may already be stopped, missing, or point to an executable that
+		// no longer exists, so failures are logged and cleanup continues. The
+		// socket is stopped first so that it doesn't start the service again.
+		for unit in [
+			SystemdService::socket_name_string(),
+			SystemdService::service_name_string(),
+		] {
+			match systemd_call(
+				proxy.stop_unit(unit.clone(), "replace".to_string()),
+				"error stopping unit",
 			)
 			.await
-			.map_err(|e| wrap(e, "error unregistering service"))?;
+			{
+				Ok(_) => info!(self.log, "Stopped {}", unit),
+				Err(e) => info!(self.log, "Could not stop {}, continuing: {}", unit, e),
+			}
+
+			match systemd_call(
+				proxy.disable_unit_files(vec![unit.clone()], /* 'runtime only'= */ false),
+				"error disabling unit files",
+			)
+			.await
+			{
+				Ok(_) => info!(self.log, "Disabled {}", unit),
+				Err(e) => info!(self.log, "Could not disable {}, continuing: {}", unit, e),
+			}
+		}
+
+		for file in [&self.socket_file, &self.service_file] {
+			match std::fs::remove_file(file) {
+				Ok(_) => info!(self.log, "Removed unit file {}", file.display()),
+				Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+				Err(e) => warning!(
+					self.log,
+					"Could not remove unit file {}: {}",
+					file.display(),
+					e
+				),
+			}
+		}
+
+		if let Err(e) = systemd_call(proxy.reload(), "error issuing reload()").await {
+			warning!(self.log, "{}", e);
+		}
 
 		info!(self.log, "Tunnel service uninstalled");
 
@@ -191,33 +458,241 @@ impl ServiceManager for SystemdService {
 	}
 }
 
-fn write_systemd_service_file(
-	path: &PathBuf,
+/// Waits for systemd to answer a call, which it may never do when dbus is
+/// misconfigured. Errors say what was being done.
+async fn systemd_call<T>(
+	call: impl Future<Output = zbus::Result<T>>,
+	context: &str,
+) -> Result<T, AnyError> {
+	match tokio::time::timeout(DEFAULT_COMMAND_TIMEOUT, call).await {
+		Ok(Ok(v)) => Ok(v),
+		Ok(Err(e)) => Err(wrap(e, context).into()),
+		Err(_) => Err(wrap(
+			CodeError::SystemdTimeout(DEFAULT_COMMAND_TIMEOUT.as_secs()),
+			context,
+		)
+		.into()),
+	}
+}
+
+/// Writes a unit file that only its owner can read, since its environment
+/// may include secrets such as the token passphrase.
+fn write_unit_file(path: &PathBuf, contents: &str) -> io::Result<()> {
+	let mut f = File::create(path)?;
+	f.set_permissions(Permissions::from_mode(0o600))?;
+	f.write_all(contents.as_bytes())?;
+	Ok(())
+}
+
+fn systemd_service_file_contents(
 	exe: std::path::PathBuf,
 	args: &[&str],
-) -> io::Result<()> {
-	let mut f = File::create(path)?;
-	write!(
-		&mut f,
+	env: &[(String, String)],
+	scope: &ServiceScope,
+	socket_activated: bool,
+) -> String {
+	let (after, mut service_extra, wanted_by) = match scope {
+		ServiceScope::User => ("network.target", String::new(), "default.target"),
+		ServiceScope::System { user } => (
+			"network-online.target\nWants=network-online.target",
+			format!(
+				"User={}\nEnvironment=VSCODE_CLI_USE_FILE_KEYCHAIN=1\n",
+				user.as_deref().unwrap_or("root")
+			),
+			"multi-user.target",
+		),
+	};
+
+	for (key, value) in env {
+		service_extra.push_str(&format!(
+			"Environment=\"{}={}\"\n",
+			systemd_escape(key),
+			systemd_escape(value)
+		));
+	}
+
+	// socket activated services exit while idle, and are started by their
+	// socket rather than being installed into a target themselves.
+	let (after, restart, install) = if socket_activated {
+		let socket = SystemdService::socket_name_string();
+		(
+			format!("{} {}\nRequires={}", after, socket, socket),
+			"on-failure",
+			String::new(),
+		)
+	} else {
+		(
+			after.to_string(),
+			"always",
+			format!("\n[Install]\nWantedBy={}\n", wanted_by),
+		)
+	};
+
+	format!(
 		"[Unit]\n\
       Description={} Tunnel\n\
-      After=network.target\n\
+      After={}\n\
       StartLimitIntervalSec=0\n\
       \n\
       [Service]\n\
       Type=simple\n\
-      Restart=always\n\
+      {}\
+      Restart={}\n\
       RestartSec=10\n\
       ExecStart={} \"{}\"\n\
-      \n\
-      [Install]\n\
-      WantedBy=default.target\n\
+      {}\
     ",
 		PRODUCT_NAME_LONG,
+		after,
+		service_extra,
+		restart,
 		exe.into_os_string().to_string_lossy(),
-		args.join("\" \"")
-	)?;
-	Ok(())
+		args.join("\" \""),
+		install,
+	)
+}
+
+/// Escapes a value for use within a double-quoted unit file setting.
+fn systemd_escape(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('%', "%%")
+}
+
+fn systemd_socket_file_contents() -> String {
+	format!(
+		"[Unit]\n\
+      Description={} Tunnel Socket\n\
+      \n\
+      [Socket]\n\
+      ListenStream=%t/{}\n\
+      SocketMode=0600\n\
+      \n\
+      [Install]\n\
+      WantedBy=sockets.target\n\
+    ",
+		PRODUCT_NAME_LONG, ACTIVATION_SOCKET_NAME,
+	)
+}
+
+fn journalctl_args(service_name: &str, options: &ServiceLogOptions) -> Vec<String> {
+	let mut args = vec![
+		"-u".to_string(),
+		service_name.to_string(),
+		"-n".to_string(),
+		options.lines.to_string(),
+	];
+	if options.follow {
+		args.push("-f".to_string());
+	} else {
+		args.push("--no-pager".to_string());
+	}
+
+	args
+}
+
+/// Gets the args for journalctl to print the unit's logs since the time, with
+/// ISO timestamps so that the time they span can be found.
+fn journalctl_export_args(service_name: &str, since: Option<DateTime<Utc>>) -> Vec<String> {
+	let mut args = vec![
+		"-u".to_string(),
+		service_name.to_string(),
+		"--no-pager".to_string(),
+		"-o".to_string(),
+		"short-iso".to_string(),
+		// newest first, so that the oldest are cut off past the limit
+		"-r".to_string(),
+	];
+	if let Some(since) = since {
+		args.push("--since".to_string());
+		args.push(format!("@{}", since.timestamp()));
+	}
+
+	args
+}
+
+/// Splits journalctl's output, newest first, into lines oldest first, without
+/// the note it prints when there are none. Output that was cut off ends with
+/// part of a line, which is left out.
+fn journal_lines(stdout: &[u8], truncated: bool) -> Vec<String> {
+	let mut lines: Vec<String> = String::from_utf8_lossy(stdout)
+		.lines()
+		.filter(|l| *l != "-- No entries --")
+		.map(|l| l.to_string())
+		.collect();
+	if truncated && !stdout.ends_with(b"\n") {
+		lines.pop();
+	}
+	lines.reverse();
+	lines
+}
+
+/// Parses the `KEY=VALUE` output of `systemctl show` into a service status.
+fn parse_systemctl_show(name: String, output: &str) -> ServiceStatus {
+	let props: HashMap<&str, &str> = output.lines().filter_map(|l| l.split_once('=')).collect();
+
+	let mut status = ServiceStatus {
+		name,
+		..Default::default()
+	};
+
+	match props.get("LoadState").copied() {
+		Some("loaded") => status.installed = true,
+		Some("not-found") | None => {}
+		Some(other) => {
+			status.installed = true;
+			status.notes.push(format!(
+				"The unit is in an unexpected load state: {}",
+				other
+			));
+		}
+	}
+
+	status.enabled = match props.get("UnitFileState").copied() {
+		Some("enabled") | Some("enabled-runtime") => Some(true),
+		Some("") | None => None,
+		Some(state) => {
+			if status.installed {
+				status.notes.push(format!(
+					"The unit file is {} but not enabled, it will not start automatically",
+					state
+				));
+			}
+			Some(false)
+		}
+	};
+
+	status.running = props.get("ActiveState").map(|s| {
+		if *s == "failed" {
+			status
+				.notes
+				.push("The unit is in a failed state".to_string());
+		}
+		matches!(*s, "active" | "activating" | "reloading")
+	});
+
+	status.pid = props
+		.get("MainPID")
+		.and_then(|p| p.parse::<u32>().ok())
+		.filter(|p| *p != 0);
+
+	if props
+		.get("ExecMainExitTimestamp")
+		.map(|t| !t.is_empty())
+		.unwrap_or(false)
+	{
+		status.last_exit_code = props.get("ExecMainStatus").and_then(|s| s.parse().ok());
+	}
+
+	status.binary_path = props.get("ExecStart").and_then(|e| {
+		e.split(';')
+			.map(|s| s.trim().trim_start_matches('{').trim())
+			.find_map(|s| s.strip_prefix("path="))
+			.map(|s| PathBuf::from(s.trim()))
+	});
+
+	status
 }
 
 /// Minimal implementation of systemd types for the services we need. The full
@@ -262,6 +737,227 @@ trait SystemdManagerDbus {
 	#[dbus_proxy(name = "StopUnit")]
 	fn stop_unit(&self, name: String, mode: String) -> zbus::Result<zvariant::OwnedObjectPath>;
 
+	#[dbus_proxy(name = "RestartUnit")]
+	fn restart_unit(&self, name: String, mode: String) -> zbus::Result<zvariant::OwnedObjectPath>;
+
 	#[dbus_proxy(name = "Reload")]
 	fn reload(&self) -> zbus::Result<()>;
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_user_service_file() {
+		let contents = systemd_service_file_contents(
+			PathBuf::from("/usr/bin/code"),
+			&["--verbose", "tunnel"],
+			&[],
+			&ServiceScope::User,
+			false,
+		);
+
+		assert!(contents.contains("ExecStart=/usr/bin/code \"--verbose\" \"tunnel\"\n"));
+		assert!(contents.contains("Restart=always\n"));
+		assert!(contents.contains("WantedBy=default.target\n"));
+		assert!(!contents.contains("User="));
+	}
+
+	#[test]
+	fn test_service_file_env() {
+		let contents = systemd_service_file_contents(
+			PathBuf::from("/usr/bin/code"),
+			&["tunnel"],
+			&[
+				(
+					"HTTPS_PROXY".to_string(),
+					"http://proxy.local:3128".to_string(),
+				),
+				("QUOTED".to_string(), "say \"hi\" 100%".to_string()),
+			],
+			&ServiceScope::User,
+			false,
+		);
+
+		assert!(contents.contains("\nEnvironment=\"HTTPS_PROXY=http://proxy.local:3128\"\n"));
+		assert!(contents.contains("\nEnvironment=\"QUOTED=say \\\"hi\\\" 100%%\"\n"));
+	}
+
+	#[test]
+	fn test_socket_activated_service_file() {
+		let contents = systemd_service_file_contents(
+			PathBuf::from("/usr/bin/code"),
+			&["--verbose", "tunnel"],
+			&[],
+			&ServiceScope::User,
+			true,
+		);
+
+		let socket = SystemdService::socket_name_string();
+		assert!(contents.contains(&format!("\nAfter=network.target {}\n", socket)));
+		assert!(contents.contains(&format!("\nRequires={}\n", socket)));
+		assert!(contents.contains("\nRestart=on-failure\n"));
+		assert!(contents.ends_with("ExecStart=/usr/bin/code \"--verbose\" \"tunnel\"\n"));
+		assert!(!contents.contains("[Install]"));
+	}
+
+	#[test]
+	fn test_socket_file() {
+		let contents = systemd_socket_file_contents();
+
+		assert!(contents.contains(&format!(
+			"\n[Socket]\nListenStream=%t/{}-tunnel.sock\nSocketMode=0600\n",
+			APPLICATION_NAME
+		)));
+		assert!(contents.ends_with("[Install]\nWantedBy=sockets.target\n"));
+	}
+
+	#[test]
+	fn test_system_service_file() {
+		let contents = systemd_service_file_contents(
+			PathBuf::from("/usr/bin/code"),
+			&["--verbose", "tunnel"],
+			&[],
+			&ServiceScope::System {
+				user: Some("builder".to_string()),
+			},
+			false,
+		);
+
+		assert!(contents.contains("\nUser=builder\n"));
+		assert!(contents.contains("\nEnvironment=VSCODE_CLI_USE_FILE_KEYCHAIN=1\n"));
+		assert!(contents.contains("\nWants=network-online.target\n"));
+		assert!(contents.contains("WantedBy=multi-user.target\n"));
+		assert!(contents.contains("ExecStart=/usr/bin/code \"--verbose\" \"tunnel\"\n"));
+	}
+
+	#[test]
+	fn test_journalctl_args() {
+		let follow = journalctl_args(
+			"code-tunnel.service",
+			&ServiceLogOptions {
+				follow: true,
+				lines: 50,
+			},
+		);
+		assert_eq!(follow, vec!["-u", "code-tunnel.service", "-n", "50", "-f"]);
+
+		let once = journalctl_args(
+			"code-tunnel.service",
+			&ServiceLogOptions {
+				follow: false,
+				lines: 20,
+			},
+		);
+		assert_eq!(
+			once,
+			vec!["-u", "code-tunnel.service", "-n", "20", "--no-pager"]
+		);
+	}
+
+	#[test]
+	fn test_journalctl_export_args() {
+		assert_eq!(
+			journalctl_export_args("code-tunnel.service", None),
+			vec![
+				"-u",
+				"code-tunnel.service",
+				"--no-pager",
+				"-o",
+				"short-iso",
+				"-r"
+			]
+		);
+
+		let since = DateTime::parse_from_rfc3339("2024-03-01T10:00:00Z")
+			.unwrap()
+			.with_timezone(&Utc);
+		let args = journalctl_export_args("code-tunnel.service", Some(since));
+		assert_eq!(&args[6..], ["--since", "@1709287200"]);
+	}
+
+	#[test]
+	fn test_journal_lines() {
+		assert!(journal_lines(b"-- No entries --\n", false).is_empty());
+		assert_eq!(
+			journal_lines(
+				b"\xffstopped\n-- Boot 1a2b --\n2024-03-01T10:00:00+0000 host code-tunnel[1]: started\n",
+				false
+			),
+			vec![
+				"2024-03-01T10:00:00+0000 host code-tunnel[1]: started",
+				"-- Boot 1a2b --",
+				"\u{fffd}stopped",
+			]
+		);
+
+		// the oldest line was cut off
+		assert_eq!(
+			journal_lines(b"newest\nnewer\n2024-03-01T10:00", true),
+			vec!["newer", "newest"]
+		);
+	}
+
+	#[test]
+	fn test_parse_systemctl_show_running() {
+		let status = parse_systemctl_show(
+			"code-tunnel.service".to_string(),
+			"LoadState=loaded\n\
+			UnitFileState=enabled\n\
+			ActiveState=active\n\
+			MainPID=1234\n\
+			ExecMainStatus=0\n\
+			ExecMainExitTimestamp=\n\
+			ExecStart={ path=/usr/bin/code ; argv[]=/usr/bin/code --verbose tunnel service internal-run ; ignore_errors=no ; start_time=[n/a] ; stop_time=[n/a] ; pid=0 ; code=(null) ; status=0/0 }\n",
+		);
+
+		assert_eq!(
+			status,
+			ServiceStatus {
+				name: "code-tunnel.service".to_string(),
+				installed: true,
+				enabled: Some(true),
+				running: Some(true),
+				pid: Some(1234),
+				binary_path: Some(PathBuf::from("/usr/bin/code")),
+				binary_matches_current: None,
+				last_exit_code: None,
+				notes: vec![],
+			}
+		);
+	}
+
+	#[test]
+	fn test_parse_systemctl_show_failed() {
+		let status = parse_systemctl_show(
+			"code-tunnel.service".to_string(),
+			"LoadState=loaded\n\
+			UnitFileState=linked\n\
+			ActiveState=failed\n\
+			MainPID=0\n\
+			ExecMainStatus=101\n\
+			ExecMainExitTimestamp=Mon 2023-01-02 03:04:05 UTC\n",
+		);
+
+		assert!(status.installed);
+		assert_eq!(status.enabled, Some(false));
+		assert_eq!(status.running, Some(false));
+		assert_eq!(status.pid, None);
+		assert_eq!(status.last_exit_code, Some(101));
+		assert_eq!(status.notes.len(), 2);
+	}
+
+	#[test]
+	fn test_parse_systemctl_show_not_found() {
+		let status = parse_systemctl_show(
+			"code-tunnel.service".to_string(),
+			"LoadState=not-found\nUnitFileState=\nActiveState=inactive\nMainPID=0\n",
+		);
+
+		assert!(!status.installed);
+		assert_eq!(status.enabled, None);
+		assert_eq!(status.running, Some(false));
+		assert!(status.notes.is_empty());
+	}
+}
@@ -4,35 +4,74 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
-	fs::{remove_file, File},
+	fs::{remove_file, File, Permissions},
 	io::{self, Write},
+	os::unix::fs::PermissionsExt,
 	path::{Path, PathBuf},
+	time::Duration,
 };
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 
 use crate::{
 	constants::APPLICATION_NAME,
 	log,
 	state::LauncherPaths,
 	util::{
-		command::capture_command_and_check_status,
+		command::{new_tokio_command, run_and_check, run_command, RunOptions},
 		errors::{wrap, AnyError, CodeError, MissingHomeDirectory},
 	},
 };
 
-use super::{service::tail_log_file, ServiceManager};
+use super::{
+	service::{
+		collect_log_file, rotate_log_file, tail_log_file, CollectedLogs, ServiceLogOptions,
+		ServiceScope, ServiceStatus,
+	},
+	ServiceManager,
+};
+
+/// Directory that system-level daemons are installed into.
+const LAUNCH_DAEMONS_DIR: &str = "/Library/LaunchDaemons";
+
+/// Minimum number of seconds launchd waits between restarts of the tunnel.
+const THROTTLE_INTERVAL_SECS: u32 = 10;
+
+/// How often the service checks whether its log file should be rotated.
+const LOG_ROTATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 pub struct LaunchdService {
 	log: log::Logger,
 	log_file: PathBuf,
+	scope: ServiceScope,
 }
 
 impl LaunchdService {
-	pub fn new(log: log::Logger, paths: &LauncherPaths) -> Self {
+	pub fn new(log: log::Logger, paths: &LauncherPaths, scope: ServiceScope) -> Self {
 		Self {
 			log,
 			log_file: paths.service_log_file(),
+			scope,
+		}
+	}
+
+	fn service_file_path(&self) -> Result<PathBuf, MissingHomeDirectory> {
+		match self.scope {
+			ServiceScope::User => get_service_file_path(),
+			ServiceScope::System { .. } => Ok(
+				PathBuf::from(LAUNCH_DAEMONS_DIR).join(format!("{}.plist", get_service_label()))
+			),
+		}
+	}
+
+	/// Gets the launchd domain target for the service, as used in `launchctl print`.
+	fn service_target(&self) -> String {
+		match self.scope {
+			ServiceScope::User => {
+				format!("gui/{}/{}", unsafe { libc::getuid() }, get_service_label())
+			}
+			ServiceScope::System { .. } => format!("system/{}", get_service_label()),
 		}
 	}
 }
@@ -43,28 +82,34 @@ impl ServiceManager for LaunchdService {
 		&self,
 		exe: std::path::PathBuf,
 		args: &[&str],
+		env: &[(String, String)],
 	) -> Result<(), crate::util::errors::AnyError> {
-		let service_file = get_service_file_path()?;
-		write_service_file(&service_file, &self.log_file, exe, args)
+		self.scope.ensure_permitted()?;
+		let service_file = self.service_file_path()?;
+		write_service_file(&service_file, &self.log_file, exe, args, env, &self.scope)
 			.map_err(|e| wrap(e, "error creating service file"))?;
 
 		info!(self.log, "Successfully registered service...");
 
-		capture_command_and_check_status(
+		run_and_check(
 			"launchctl",
 			&["load", service_file.as_os_str().to_string_lossy().as_ref()],
 		)
 		.await?;
 
-		capture_command_and_check_status("launchctl", &["start", &get_service_label()]).await?;
+		run_and_check("launchctl", &["start", &get_service_label()]).await?;
 
 		info!(self.log, "Tunnel service successfully started");
 
 		Ok(())
 	}
 
-	async fn show_logs(&self) -> Result<(), AnyError> {
-		tail_log_file(&self.log_file).await
+	async fn show_logs(&self, options: &ServiceLogOptions) -> Result<(), AnyError> {
+		tail_log_file(&self.log_file, options).await
+	}
+
+	async fn collect_logs(&self, since: Option<DateTime<Utc>>) -> Result<CollectedLogs, AnyError> {
+		collect_log_file(&self.log_file, since)
 	}
 
 	async fn run(
@@ -72,43 +117,169 @@ impl ServiceManager for LaunchdService {
 		launcher_paths: crate::state::LauncherPaths,
 		mut handle: impl 'static + super::ServiceContainer,
 	) -> Result<(), crate::util::errors::AnyError> {
+		// launchd writes our output to the log file but never rotates it
+		let log = self.log.clone();
+		let log_file = self.log_file.clone();
+		let rotation = handle.log_rotation();
+		tokio::spawn(async move {
+			loop {
+				match rotate_log_file(&log_file, &rotation) {
+					Ok(true) => info!(log, "Rotated service log file"),
+					Ok(false) => {}
+					Err(e) => warning!(log, "Error rotating service log file: {}", e),
+				}
+				tokio::time::sleep(LOG_ROTATE_INTERVAL).await;
+			}
+		});
+
 		handle.run_service(self.log, launcher_paths).await
 	}
 
 	async fn is_installed(&self) -> Result<bool, AnyError> {
-		let cmd = capture_command_and_check_status("launchctl", &["list"]).await?;
+		let cmd = run_and_check("launchctl", &["list"]).await?;
 		Ok(String::from_utf8_lossy(&cmd.stdout).contains(&get_service_label()))
 	}
 
+	async fn status(&self) -> Result<ServiceStatus, AnyError> {
+		let label = get_service_label();
+		let target = self.service_target();
+		let mut cmd = new_tokio_command("launchctl");
+		cmd.args(["print", target.as_str()]);
+		let output = run_command(cmd, &RunOptions::default()).await?;
+		let plist_exists = self
+			.service_file_path()
+			.map(|p| p.exists())
+			.unwrap_or(false);
+
+		let mut status = if output.status.success() {
+			parse_launchctl_print(label, &String::from_utf8_lossy(&output.stdout))
+		} else {
+			ServiceStatus {
+				name: label,
+				installed: plist_exists,
+				enabled: Some(false),
+				running: Some(false),
+				..Default::default()
+			}
+		};
+
+		if plist_exists && !output.status.success() {
+			status
+				.notes
+				.push("The service file exists, but the agent is not loaded".to_string());
+		} else if !plist_exists && output.status.success() {
+			status
+				.notes
+				.push("The agent is loaded, but its service file is missing".to_string());
+		}
+
+		if let Ok(exe) = std::env::current_exe() {
+			status.check_binary(&exe);
+		}
+
+		Ok(status)
+	}
+
+	async fn restart(&self) -> Result<(), AnyError> {
+		self.scope.ensure_permitted()?;
+		// -k kills the running instance before starting it again
+		run_and_check("launchctl", &["kickstart", "-k", &self.service_target()]).await?;
+
+		info!(self.log, "Tunnel service restarted");
+
+		Ok(())
+	}
+
 	async fn unregister(&self) -> Result<(), crate::util::errors::AnyError> {
-		let service_file = get_service_file_path()?;
+		self.scope.ensure_permitted()?;
+		let service_file = self.service_file_path()?;
 
-		match capture_command_and_check_status("launchctl", &["stop", &get_service_label()]).await {
-			Ok(_) => {}
+		// The agent may already be stopped or unloaded, or point to an
+		// executable that no longer exists, so failures are logged and cleanup
+		// continues.
+		match run_and_check("launchctl", &["stop", &get_service_label()]).await {
+			Ok(_) => info!(self.log, "Successfully stopped service..."),
 			// status 3 == "no such process"
-			Err(CodeError::CommandFailed { code: 3, .. }) => {}
-			Err(e) => return Err(wrap(e, "error stopping service").into()),
+			Err(CodeError::CommandFailed { code: 3, .. }) => {
+				info!(self.log, "Service was not running")
+			}
+			Err(e) => info!(self.log, "Could not stop service, continuing: {}", e),
 		};
 
-		info!(self.log, "Successfully stopped service...");
+		if service_file.exists() {
+			match run_and_check(
+				"launchctl",
+				&[
+					"unload",
+					service_file.as_os_str().to_string_lossy().as_ref(),
+				],
+			)
+			.await
+			{
+				Ok(_) => info!(self.log, "Unloaded service"),
+				Err(e) => info!(self.log, "Could not unload service, continuing: {}", e),
+			}
 
-		capture_command_and_check_status(
-			"launchctl",
-			&[
-				"unload",
-				service_file.as_os_str().to_string_lossy().as_ref(),
-			],
-		)
-		.await?;
+			match remove_file(&service_file) {
+				Ok(_) => info!(self.log, "Removed {}", service_file.display()),
+				Err(e) => warning!(
+					self.log,
+					"Could not remove {}: {}",
+					service_file.display(),
+					e
+				),
+			}
+		} else {
+			info!(
+				self.log,
+				"No service file found at {}, nothing to unload",
+				service_file.display()
+			);
+		}
 
 		info!(self.log, "Tunnel service uninstalled");
 
-		if let Ok(f) = get_service_file_path() {
-			remove_file(f).ok();
+		Ok(())
+	}
+}
+
+/// Parses the output of `launchctl print gui/<uid>/<label>` for a loaded agent.
+fn parse_launchctl_print(label: String, output: &str) -> ServiceStatus {
+	let mut status = ServiceStatus {
+		name: label,
+		installed: true,
+		enabled: Some(true),
+		..Default::default()
+	};
+
+	// only read top-level properties; nested dictionaries are further indented
+	for line in output.lines() {
+		let depth = line.chars().take_while(|c| *c == '\t').count();
+		if depth != 1 {
+			continue;
 		}
 
-		Ok(())
+		let (key, value) = match line.trim().split_once(" = ") {
+			Some(kv) => kv,
+			None => continue,
+		};
+
+		match key {
+			"state" => status.running = Some(value == "running"),
+			"pid" => status.pid = value.parse().ok(),
+			"program" => status.binary_path = Some(PathBuf::from(value)),
+			"last exit code" => status.last_exit_code = value.parse().ok(),
+			_ => {}
+		}
 	}
+
+	if status.running.is_none() {
+		status
+			.notes
+			.push("Could not determine the agent's state".to_string());
+	}
+
+	status
 }
 
 fn get_service_label() -> String {
@@ -125,33 +296,94 @@ fn get_service_file_path() -> Result<PathBuf, MissingHomeDirectory> {
 	}
 }
 
+fn xml_escape(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+}
+
 fn write_service_file(
 	path: &PathBuf,
 	log_file: &Path,
 	exe: std::path::PathBuf,
 	args: &[&str],
+	env: &[(String, String)],
+	scope: &ServiceScope,
 ) -> io::Result<()> {
+	// only readable by its owner, since the environment may include secrets
+	// such as the token passphrase
 	let mut f = File::create(path)?;
+	f.set_permissions(Permissions::from_mode(0o600))?;
+	f.write_all(service_file_contents(log_file, exe, args, env, scope).as_bytes())?;
+	Ok(())
+}
+
+fn service_file_contents(
+	log_file: &Path,
+	exe: std::path::PathBuf,
+	args: &[&str],
+	env: &[(String, String)],
+	scope: &ServiceScope,
+) -> String {
 	let log_file = log_file.as_os_str().to_string_lossy();
+	// agents are limited to the graphical session, while daemons run at boot
+	// as the configured user, who may never log in.
+	let (session, mut env_vars) = match scope {
+		ServiceScope::User => (
+			"\t<key>LimitLoadToSessionType</key>\n\
+			\t<string>Aqua</string>\n"
+				.to_string(),
+			vec![],
+		),
+		ServiceScope::System { user } => (
+			format!(
+				"\t<key>UserName</key>\n\
+				\t<string>{}</string>\n",
+				user.as_deref().unwrap_or("root")
+			),
+			vec![("VSCODE_CLI_USE_FILE_KEYCHAIN", "1")],
+		),
+	};
+
+	env_vars.extend(env.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+	let mut environment = String::new();
+	if !env_vars.is_empty() {
+		environment.push_str("\t<key>EnvironmentVariables</key>\n\t<dict>\n");
+		for (key, value) in env_vars {
+			environment.push_str(&format!(
+				"\t\t<key>{}</key>\n\t\t<string>{}</string>\n",
+				xml_escape(key),
+				xml_escape(value)
+			));
+		}
+		environment.push_str("\t</dict>\n");
+	}
+
 	// todo: we may be able to skip file logging and use the ASL instead
 	// if/when we no longer need to support older macOS versions.
-	write!(
-		&mut f,
+	format!(
 		"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
 		<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
 		<plist version=\"1.0\">\n\
 		<dict>\n\
 			<key>Label</key>\n\
 			<string>{}</string>\n\
-			<key>LimitLoadToSessionType</key>\n\
-			<string>Aqua</string>\n\
+		{}{}\
 			<key>ProgramArguments</key>\n\
 			<array>\n\
 				<string>{}</string>\n\
 				<string>{}</string>\n\
 			</array>\n\
-			<key>KeepAlive</key>\n\
+			<key>RunAtLoad</key>\n\
 			<true/>\n\
+			<key>KeepAlive</key>\n\
+			<dict>\n\
+				<key>SuccessfulExit</key>\n\
+				<false/>\n\
+			</dict>\n\
+			<key>ThrottleInterval</key>\n\
+			<integer>{}</integer>\n\
 			<key>StandardErrorPath</key>\n\
 			<string>{}</string>\n\
 			<key>StandardOutPath</key>\n\
@@ -159,10 +391,150 @@ fn write_service_file(
 		</dict>\n\
 		</plist>",
 		get_service_label(),
+		session,
+		environment,
 		exe.into_os_string().to_string_lossy(),
 		args.join("</string><string>"),
+		THROTTLE_INTERVAL_SECS,
 		log_file,
 		log_file
-	)?;
-	Ok(())
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_user_service_file() {
+		let contents = service_file_contents(
+			Path::new("/tmp/tunnel.log"),
+			PathBuf::from("/usr/local/bin/code"),
+			&["tunnel"],
+			&[],
+			&ServiceScope::User,
+		);
+
+		assert!(contents.contains("<key>LimitLoadToSessionType</key>"));
+		assert!(!contents.contains("<key>UserName</key>"));
+	}
+
+	#[test]
+	fn test_service_file_env() {
+		let contents = service_file_contents(
+			Path::new("/tmp/tunnel.log"),
+			PathBuf::from("/usr/local/bin/code"),
+			&["tunnel"],
+			&[(
+				"VSCODE_CLI_UPDATE_URL".to_string(),
+				"https://example.com/?a=1&b=2".to_string(),
+			)],
+			&ServiceScope::System {
+				user: Some("builder".to_string()),
+			},
+		);
+
+		assert!(contents.contains(
+			"\t<key>EnvironmentVariables</key>\n\t<dict>\n\
+			\t\t<key>VSCODE_CLI_USE_FILE_KEYCHAIN</key>\n\t\t<string>1</string>\n\
+			\t\t<key>VSCODE_CLI_UPDATE_URL</key>\n\t\t<string>https://example.com/?a=1&amp;b=2</string>\n\
+			\t</dict>\n"
+		));
+	}
+
+	#[test]
+	fn test_service_file_lifecycle() {
+		let contents = service_file_contents(
+			Path::new("/Users/me/.vscode/cli/tunnel-service.log"),
+			PathBuf::from("/usr/local/bin/code"),
+			&["tunnel"],
+			&[],
+			&ServiceScope::User,
+		);
+
+		// restarted only when it exits with an error, and not in a tight loop
+		assert!(contents.contains("<key>RunAtLoad</key>\n<true/>\n"));
+		assert!(contents.contains(
+			"<key>KeepAlive</key>\n<dict>\n<key>SuccessfulExit</key>\n<false/>\n</dict>\n"
+		));
+		assert!(contents.contains("<key>ThrottleInterval</key>\n<integer>10</integer>\n"));
+		assert!(contents.contains(
+			"<key>StandardErrorPath</key>\n<string>/Users/me/.vscode/cli/tunnel-service.log</string>\n"
+		));
+		assert!(contents.contains(
+			"<key>StandardOutPath</key>\n<string>/Users/me/.vscode/cli/tunnel-service.log</string>\n"
+		));
+	}
+
+	#[test]
+	fn test_system_service_file() {
+		let contents = service_file_contents(
+			Path::new("/tmp/tunnel.log"),
+			PathBuf::from("/usr/local/bin/code"),
+			&["tunnel"],
+			&[],
+			&ServiceScope::System {
+				user: Some("builder".to_string()),
+			},
+		);
+
+		assert!(contents.contains("<key>UserName</key>\n\t<string>builder</string>"));
+		assert!(contents.contains("<key>VSCODE_CLI_USE_FILE_KEYCHAIN</key>"));
+		assert!(!contents.contains("<key>LimitLoadToSessionType</key>"));
+	}
+
+	#[test]
+	fn test_parse_launchctl_print_running() {
+		let output = "gui/501/com.visualstudio.code.tunnel = {
+\tactive count = 1
+\tpath = /Users/me/com.visualstudio.code.tunnel.plist
+\tstate = running
+
+\tprogram = /usr/local/bin/code
+\targuments = {
+\t\t/usr/local/bin/code
+\t\t--verbose
+\t}
+
+\tpid = 4321
+\tlast exit code = (never exited)
+}
+";
+		let status = parse_launchctl_print("com.visualstudio.code.tunnel".to_string(), output);
+		assert_eq!(
+			status,
+			ServiceStatus {
+				name: "com.visualstudio.code.tunnel".to_string(),
+				installed: true,
+				enabled: Some(true),
+				running: Some(true),
+				pid: Some(4321),
+				binary_path: Some(PathBuf::from("/usr/local/bin/code")),
+				binary_matches_current: None,
+				last_exit_code: None,
+				notes: vec![],
+			}
+		);
+	}
+
+	#[test]
+	fn test_parse_launchctl_print_exited() {
+		let output = "gui/501/com.visualstudio.code.tunnel = {
+\tstate = not running
+\tprogram = /usr/local/bin/code
+\tlast exit code = 1
+}
+";
+		let status = parse_launchctl_print("com.visualstudio.code.tunnel".to_string(), output);
+		assert_eq!(status.running, Some(false));
+		assert_eq!(status.pid, None);
+		assert_eq!(status.last_exit_code, Some(1));
+	}
+
+	#[test]
+	fn test_parse_launchctl_print_unknown() {
+		let status = parse_launchctl_print("label".to_string(), "garbage");
+		assert_eq!(status.running, None);
+		assert_eq!(status.notes.len(), 1);
+	}
 }
@@ -4,9 +4,10 @@
  *--------------------------------------------------------------------------------------------*/
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use shell_escape::windows::escape as shell_escape;
 use std::os::windows::process::CommandExt;
-use std::{path::PathBuf, process::Stdio};
+use std::{path::PathBuf, process::Stdio, time::Duration};
 use winapi::um::winbase::{CREATE_NEW_PROCESS_GROUP, DETACHED_PROCESS};
 use winreg::{enums::HKEY_CURRENT_USER, RegKey};
 
@@ -16,25 +17,33 @@ use crate::{
 	log,
 	state::LauncherPaths,
 	tunnels::{protocol, singleton_client::do_single_rpc_call},
-	util::errors::{wrap, wrapdbg, AnyError},
+	util::errors::{wrap, wrapdbg, AnyError, CodeError},
 };
 
-use super::service::{tail_log_file, ServiceContainer, ServiceManager as CliServiceManager};
+use super::service::{
+	collect_log_file, tail_log_file, CollectedLogs, ServiceContainer, ServiceLogOptions,
+	ServiceManager as CliServiceManager, ServiceScope, ServiceStatus,
+};
 
 const DID_LAUNCH_AS_HIDDEN_PROCESS: &str = "VSCODE_CLI_DID_LAUNCH_AS_HIDDEN_PROCESS";
 
+/// How long to wait for the running tunnel to exit when restarting.
+const RESTART_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct WindowsService {
 	log: log::Logger,
 	tunnel_lock: PathBuf,
 	log_file: PathBuf,
+	scope: ServiceScope,
 }
 
 impl WindowsService {
-	pub fn new(log: log::Logger, paths: &LauncherPaths) -> Self {
+	pub fn new(log: log::Logger, paths: &LauncherPaths, scope: ServiceScope) -> Self {
 		Self {
 			log,
 			tunnel_lock: paths.tunnel_lockfile(),
 			log_file: paths.service_log_file(),
+			scope,
 		}
 	}
 
@@ -48,7 +57,18 @@ impl WindowsService {
 
 #[async_trait]
 impl CliServiceManager for WindowsService {
-	async fn register(&self, exe: std::path::PathBuf, args: &[&str]) -> Result<(), AnyError> {
+	async fn register(
+		&self,
+		exe: std::path::PathBuf,
+		args: &[&str],
+		env: &[(String, String)],
+	) -> Result<(), AnyError> {
+		self.scope.ensure_permitted()?;
+		// the Run key only holds a command line
+		if !env.is_empty() {
+			return Err(CodeError::ServiceEnvUnsupported.into());
+		}
+
 		let key = WindowsService::open_key()?;
 
 		let mut reg_str = String::new();
@@ -65,8 +85,12 @@ impl CliServiceManager for WindowsService {
 			add_arg(arg);
 		}
 
-		add_arg("--log-to-file");
-		add_arg(self.log_file.to_string_lossy().as_ref());
+		// unless logs go to a file of the user's choosing, they go to the
+		// service's own, which `tunnel service log` shows
+		if !args.iter().any(|a| a.starts_with("--log-to-file")) {
+			add_arg("--log-to-file");
+			add_arg(self.log_file.to_string_lossy().as_ref());
+		}
 
 		key.set_value(TUNNEL_ACTIVITY_NAME, &reg_str)
 			.map_err(|e| AnyError::from(wrapdbg(e, "error setting registry key")))?;
@@ -84,8 +108,12 @@ impl CliServiceManager for WindowsService {
 		Ok(())
 	}
 
-	async fn show_logs(&self) -> Result<(), AnyError> {
-		tail_log_file(&self.log_file).await
+	async fn show_logs(&self, options: &ServiceLogOptions) -> Result<(), AnyError> {
+		tail_log_file(&self.log_file, options).await
+	}
+
+	async fn collect_logs(&self, since: Option<DateTime<Utc>>) -> Result<CollectedLogs, AnyError> {
+		collect_log_file(&self.log_file, since)
 	}
 
 	async fn run(
@@ -118,11 +146,119 @@ impl CliServiceManager for WindowsService {
 		Ok(key.get_raw_value(TUNNEL_ACTIVITY_NAME).is_ok())
 	}
 
+	async fn status(&self) -> Result<ServiceStatus, AnyError> {
+		let key = WindowsService::open_key()?;
+		let mut status = ServiceStatus {
+			name: TUNNEL_ACTIVITY_NAME.to_string(),
+			..Default::default()
+		};
+
+		match key.get_value::<String, _>(TUNNEL_ACTIVITY_NAME) {
+			Ok(cmd) => {
+				status.installed = true;
+				status.enabled = Some(true);
+				status.binary_path = parse_run_command_exe(&cmd);
+				if status.binary_path.is_none() {
+					status
+						.notes
+						.push(format!("Could not parse the registered command: {}", cmd));
+				}
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				status.enabled = Some(false);
+			}
+			Err(e) => status
+				.notes
+				.push(format!("Could not read the registry value: {}", e)),
+		}
+
+		let running = do_single_rpc_call::<_, protocol::singleton::StatusWithTunnelName>(
+			&self.tunnel_lock,
+			self.log.clone(),
+			protocol::singleton::METHOD_STATUS,
+			protocol::EmptyObject {},
+		)
+		.await
+		.is_ok();
+		status.running = Some(running);
+		if running && !status.installed {
+			status
+				.notes
+				.push("A tunnel is running, but it was not started by the service".to_string());
+		}
+
+		if let Ok(exe) = std::env::current_exe() {
+			status.check_binary(&exe);
+		}
+
+		Ok(status)
+	}
+
+	async fn restart(&self) -> Result<(), AnyError> {
+		let key = WindowsService::open_key()?;
+		let cmd = key
+			.get_value::<String, _>(TUNNEL_ACTIVITY_NAME)
+			.map_err(|e| wrap(e, "error reading registry key"))?;
+		let (exe, args) = split_run_command(&cmd)
+			.ok_or_else(|| CodeError::InvalidServiceRegistration(format!("command `{}`", cmd)))?;
+
+		// There's no service manager to restart us, so ask the running tunnel to
+		// shut down and then launch the registered command as at login.
+		match do_single_rpc_call::<_, ()>(
+			&self.tunnel_lock,
+			self.log.clone(),
+			protocol::singleton::METHOD_SHUTDOWN,
+			protocol::EmptyObject {},
+		)
+		.await
+		{
+			Ok(_) | Err(CodeError::NoRunningTunnel) => {}
+			Err(e) => return Err(wrap(e, "error stopping service").into()),
+		}
+
+		let wait_stopped = async {
+			while do_single_rpc_call::<_, protocol::singleton::StatusWithTunnelName>(
+				&self.tunnel_lock,
+				self.log.clone(),
+				protocol::singleton::METHOD_STATUS,
+				protocol::EmptyObject {},
+			)
+			.await
+			.is_ok()
+			{
+				tokio::time::sleep(Duration::from_millis(200)).await;
+			}
+		};
+
+		if tokio::time::timeout(RESTART_STOP_TIMEOUT, wait_stopped)
+			.await
+			.is_err()
+		{
+			return Err(CodeError::ServiceStopTimeout(RESTART_STOP_TIMEOUT.as_secs()).into());
+		}
+
+		new_std_command(exe)
+			.raw_arg(args)
+			.stderr(Stdio::null())
+			.stdout(Stdio::null())
+			.stdin(Stdio::null())
+			.creation_flags(CREATE_NEW_PROCESS_GROUP | DETACHED_PROCESS)
+			.spawn()
+			.map_err(|e| wrapdbg(e, "error starting service"))?;
+
+		info!(self.log, "Tunnel service restarted");
+
+		Ok(())
+	}
+
 	async fn unregister(&self) -> Result<(), AnyError> {
+		self.scope.ensure_permitted()?;
 		let key = WindowsService::open_key()?;
 		match key.delete_value(TUNNEL_ACTIVITY_NAME) {
-			Ok(_) => {}
-			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+			Ok(_) => info!(self.log, "Removed the registry entry for the service"),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				info!(self.log, "No registry entry found for the service")
+			}
 			Err(e) => return Err(wrap(e, "error deleting registry key").into()),
 		}
 
@@ -145,3 +281,58 @@ impl CliServiceManager for WindowsService {
 		Ok(())
 	}
 }
+
+/// Extracts the executable from a command string written to the Run key, which
+/// is escaped as in `register`.
+fn parse_run_command_exe(cmd: &str) -> Option<PathBuf> {
+	split_run_command(cmd).map(|(exe, _)| exe)
+}
+
+/// Splits a command string written to the Run key into its executable and
+/// its still-escaped arguments.
+fn split_run_command(cmd: &str) -> Option<(PathBuf, &str)> {
+	let cmd = cmd.trim();
+	if let Some(rest) = cmd.strip_prefix('"') {
+		rest.find('"')
+			.map(|end| (PathBuf::from(&rest[..end]), rest[end + 1..].trim_start()))
+	} else {
+		let (exe, args) = cmd.split_once(' ').unwrap_or((cmd, ""));
+		Some(exe)
+			.filter(|s| !s.is_empty())
+			.map(|exe| (PathBuf::from(exe), args.trim_start()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_run_command_exe() {
+		assert_eq!(
+			parse_run_command_exe(r#""C:\Program Files\code.exe" --verbose tunnel"#),
+			Some(PathBuf::from(r"C:\Program Files\code.exe"))
+		);
+		assert_eq!(
+			parse_run_command_exe(r"C:\code\code.exe --verbose"),
+			Some(PathBuf::from(r"C:\code\code.exe"))
+		);
+		assert_eq!(parse_run_command_exe(r#""unterminated"#), None);
+		assert_eq!(parse_run_command_exe(""), None);
+	}
+
+	#[test]
+	fn test_split_run_command() {
+		assert_eq!(
+			split_run_command(r#""C:\Program Files\code.exe" --verbose tunnel"#),
+			Some((
+				PathBuf::from(r"C:\Program Files\code.exe"),
+				"--verbose tunnel"
+			))
+		);
+		assert_eq!(
+			split_run_command(r"C:\code\code.exe"),
+			Some((PathBuf::from(r"C:\code\code.exe"), ""))
+		);
+	}
+}
@@ -3,15 +3,22 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use chrono::{DateTime, Utc};
 use futures::{stream::FuturesUnordered, StreamExt};
-use std::{fmt, path::PathBuf};
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
 use sysinfo::Pid;
+use tokio::sync::watch;
 
 use crate::util::{
 	machine::{wait_until_exe_deleted, wait_until_process_exits},
-	sync::{new_barrier, Barrier, Receivable},
+	sync::{new_barrier, ActivityGuard, ActivityTracker, Barrier, Receivable},
 };
 
+use super::protocol::singleton::DrainStatus;
+
+/// Default time to wait for clients to disconnect after a termination signal.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Describes the signal to manully stop the server
 #[derive(Copy, Clone)]
 pub enum ShutdownSignal {
@@ -21,6 +28,9 @@ pub enum ShutdownSignal {
 	ServiceStopped,
 	RpcShutdownRequested,
 	RpcRestartRequested,
+	IdleTimeout,
+	Terminated,
+	DrainTimedOut,
 }
 
 impl fmt::Display for ShutdownSignal {
@@ -38,6 +48,11 @@ impl fmt::Display for ShutdownSignal {
 			ShutdownSignal::RpcRestartRequested => {
 				write!(f, "RPC client requested a tunnel restart")
 			}
+			ShutdownSignal::IdleTimeout => write!(f, "No clients connected, exiting while idle"),
+			ShutdownSignal::Terminated => write!(f, "Termination signal received"),
+			ShutdownSignal::DrainTimedOut => {
+				write!(f, "Clients were still connected when the drain timed out")
+			}
 		}
 	}
 }
@@ -46,6 +61,11 @@ pub enum ShutdownRequest {
 	CtrlC,
 	ParentProcessKilled(Pid),
 	ExeUninstalled(PathBuf),
+	/// Shuts down once there's been no activity for the given duration.
+	Idle(ActivityTracker, Duration),
+	/// Drains clients on the first termination signal, and shuts down once
+	/// they're gone or on a second one. See `TerminationSignal`.
+	Terminate(Drain),
 	Derived(Box<dyn Receivable<ShutdownSignal> + Send>),
 }
 
@@ -65,6 +85,23 @@ impl ShutdownRequest {
 				wait_until_exe_deleted(&exe_path, 2000).await;
 				Some(ShutdownSignal::ExeUninstalled)
 			}
+			ShutdownRequest::Idle(tracker, duration) => {
+				tracker.wait_idle(duration).await;
+				Some(ShutdownSignal::IdleTimeout)
+			}
+			ShutdownRequest::Terminate(drain) => {
+				let mut signal = TerminationSignal::new().ok()?;
+				signal.recv().await;
+				drain.start();
+
+				tokio::select! {
+					_ = signal.recv() => Some(ShutdownSignal::Terminated),
+					drained = drain.finished() => Some(match drained {
+						true => ShutdownSignal::Terminated,
+						false => ShutdownSignal::DrainTimedOut,
+					}),
+				}
+			}
 			ShutdownRequest::Derived(mut rx) => rx.recv_msg().await,
 		}
 	}
@@ -88,3 +125,186 @@ impl ShutdownRequest {
 		barrier
 	}
 }
+
+/// Signals asking the process to exit: SIGTERM on unix, which service managers
+/// send to stop it, and on Windows Ctrl+Break, and the console being closed or
+/// the user logging off or shutting down.
+struct TerminationSignal {
+	#[cfg(unix)]
+	sigterm: tokio::signal::unix::Signal,
+	#[cfg(windows)]
+	ctrl_break: tokio::signal::windows::CtrlBreak,
+	#[cfg(windows)]
+	ctrl_close: tokio::signal::windows::CtrlClose,
+	#[cfg(windows)]
+	ctrl_logoff: tokio::signal::windows::CtrlLogoff,
+	#[cfg(windows)]
+	ctrl_shutdown: tokio::signal::windows::CtrlShutdown,
+}
+
+impl TerminationSignal {
+	#[cfg(unix)]
+	fn new() -> std::io::Result<Self> {
+		use tokio::signal::unix::{signal, SignalKind};
+		Ok(Self {
+			sigterm: signal(SignalKind::terminate())?,
+		})
+	}
+
+	#[cfg(windows)]
+	fn new() -> std::io::Result<Self> {
+		use tokio::signal::windows;
+		Ok(Self {
+			ctrl_break: windows::ctrl_break()?,
+			ctrl_close: windows::ctrl_close()?,
+			ctrl_logoff: windows::ctrl_logoff()?,
+			ctrl_shutdown: windows::ctrl_shutdown()?,
+		})
+	}
+
+	#[cfg(unix)]
+	async fn recv(&mut self) {
+		self.sigterm.recv().await;
+	}
+
+	#[cfg(windows)]
+	async fn recv(&mut self) {
+		tokio::select! {
+			_ = self.ctrl_break.recv() => {},
+			_ = self.ctrl_close.recv() => {},
+			_ = self.ctrl_logoff.recv() => {},
+			_ = self.ctrl_shutdown.recv() => {},
+		}
+	}
+}
+
+/// Tracks clients of the control server so that they can be drained before
+/// the tunnel shuts down. While draining, new connections are rejected and
+/// connected clients are asked to disconnect.
+#[derive(Clone)]
+pub struct Drain {
+	timeout: Duration,
+	clients: ActivityTracker,
+	state: Arc<watch::Sender<Option<DrainStatus>>>,
+}
+
+impl Drain {
+	pub fn new(timeout: Duration) -> Self {
+		Self {
+			timeout,
+			clients: ActivityTracker::default(),
+			state: Arc::new(watch::channel(None).0),
+		}
+	}
+
+	/// Registers a new client, which is connected until the guard is dropped.
+	/// Returns None if the client should be rejected because of a drain.
+	pub fn admit(&self) -> Option<ActivityGuard> {
+		if self.status().is_some() {
+			None
+		} else {
+			Some(self.clients.start())
+		}
+	}
+
+	pub fn status(&self) -> Option<DrainStatus> {
+		self.state.borrow().clone()
+	}
+
+	/// Starts draining clients. Returns false if a drain was already started.
+	pub fn start(&self) -> bool {
+		let started_at = Utc::now();
+		let deadline = chrono::Duration::from_std(self.timeout)
+			.ok()
+			.and_then(|t| started_at.checked_add_signed(t))
+			.unwrap_or(DateTime::<Utc>::MAX_UTC);
+
+		self.state.send_if_modified(|s| {
+			if s.is_some() {
+				return false;
+			}
+
+			*s = Some(DrainStatus {
+				started_at,
+				deadline,
+			});
+			true
+		})
+	}
+
+	/// Resolves once a drain is started.
+	pub async fn started(&self) -> DrainStatus {
+		let mut rx = self.state.subscribe();
+		loop {
+			if let Some(s) = rx.borrow_and_update().clone() {
+				return s;
+			}
+
+			// the sender is held by self, so this can't fail
+			let _ = rx.changed().await;
+		}
+	}
+
+	/// Resolves once a drain is started and all clients have disconnected, or
+	/// the drain timed out. Returns whether all clients disconnected in time.
+	pub async fn finished(&self) -> bool {
+		self.started().await;
+		tokio::time::timeout(self.timeout, self.clients.wait_inactive())
+			.await
+			.is_ok()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_drain_rejects_new_clients() {
+		let drain = Drain::new(Duration::from_secs(10));
+		let client = drain.admit().expect("expected to admit before draining");
+
+		assert!(drain.start());
+		assert!(!drain.start());
+		assert!(drain.admit().is_none());
+
+		drop(client);
+		assert!(drain.finished().await);
+	}
+
+	#[tokio::test]
+	async fn test_drain_waits_for_clients() {
+		let drain = Drain::new(Duration::from_secs(10));
+		let client = drain.admit().unwrap();
+
+		let waiter = drain.clone();
+		let finished = tokio::spawn(async move { waiter.finished().await });
+
+		drain.start();
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert!(!finished.is_finished());
+
+		drop(client);
+		let drained = tokio::time::timeout(Duration::from_secs(1), finished)
+			.await
+			.expect("expected drain to finish")
+			.unwrap();
+		assert!(drained);
+	}
+
+	#[tokio::test]
+	async fn test_drain_times_out() {
+		let drain = Drain::new(Duration::from_millis(50));
+		let _client = drain.admit().unwrap();
+
+		assert!(drain.status().is_none());
+
+		drain.start();
+		let status = drain.started().await;
+		assert_eq!(
+			status.deadline - status.started_at,
+			chrono::Duration::milliseconds(50)
+		);
+		assert!(!drain.finished().await);
+	}
+}
@@ -17,13 +17,12 @@ use tokio::sync::mpsc;
 
 use crate::{
 	async_pipe::{socket_stream_split, AsyncPipe},
-	constants::IS_INTERACTIVE_CLI,
 	json_rpc::{new_json_rpc, start_json_rpc, JsonRpcSerializer},
 	log,
 	rpc::RpcCaller,
 	singleton::connect_as_client,
 	tunnels::{code_server::print_listening, protocol::EmptyObject},
-	util::{errors::CodeError, sync::Barrier},
+	util::{errors::CodeError, input::is_interactive, sync::Barrier},
 };
 
 use super::{
@@ -35,11 +34,14 @@ pub struct SingletonClientArgs {
 	pub log: log::Logger,
 	pub stream: AsyncPipe,
 	pub shutdown: Barrier<ShutdownSignal>,
+	/// Only follows the tunnel's output, without offering to stop or restart it.
+	pub attach_only: bool,
 }
 
 struct SingletonServerContext {
 	log: log::Logger,
 	exit_entirely: Arc<AtomicBool>,
+	attach_only: bool,
 	caller: RpcCaller<JsonRpcSerializer>,
 }
 
@@ -56,6 +58,9 @@ const CONTROL_INSTRUCTIONS_INTERACTIVE: &str = concatcp!(
 "
 );
 
+const ATTACH_INSTRUCTIONS: &str =
+	"Attached to the tunnel running on this machine. Press Ctrl+C to detach.";
+
 /// Serves a client singleton. Returns true if the process should exit after
 /// this returns, instead of trying to start a tunnel.
 pub async fn start_singleton_client(args: SingletonClientArgs) -> bool {
@@ -68,7 +73,7 @@ pub async fn start_singleton_client(args: SingletonClientArgs) -> bool {
 		"An existing tunnel is running on this machine, connecting to it..."
 	);
 
-	if *IS_INTERACTIVE_CLI {
+	if is_interactive() && !args.attach_only {
 		let stdin_handle = rpc.get_caller(msg_tx.clone());
 		thread::spawn(move || {
 			let mut input = String::new();
@@ -93,10 +98,22 @@ pub async fn start_singleton_client(args: SingletonClientArgs) -> bool {
 		});
 	}
 
+	// only informational, since the client sticks to methods every version has
+	let hello = rpc.get_caller(msg_tx.clone());
+	let hello_log = args.log.clone();
+	tokio::spawn(async move {
+		if let Err(e @ CodeError::IncompatibleProtocolVersion(_)) =
+			hello.hello(&protocol::singleton::PROTOCOL).await
+		{
+			warning!(hello_log, "{}", e);
+		}
+	});
+
 	let caller = rpc.get_caller(msg_tx);
 	let mut rpc = rpc.methods(SingletonServerContext {
 		log: args.log.clone(),
 		exit_entirely: exit_entirely.clone(),
+		attach_only: args.attach_only,
 		caller,
 	});
 
@@ -108,7 +125,9 @@ pub async fn start_singleton_client(args: SingletonClientArgs) -> bool {
 	rpc.register_async(
 		protocol::singleton::METHOD_LOG_REPLY_DONE,
 		|_: EmptyObject, c| async move {
-			c.log.result(if *IS_INTERACTIVE_CLI {
+			c.log.notice(if c.attach_only {
+				ATTACH_INSTRUCTIONS
+			} else if is_interactive() {
 				CONTROL_INSTRUCTIONS_INTERACTIVE
 			} else {
 				CONTROL_INSTRUCTIONS_COMMON
@@ -151,23 +170,53 @@ pub async fn start_singleton_client(args: SingletonClientArgs) -> bool {
 	exit_entirely.load(Ordering::SeqCst)
 }
 
-pub async fn do_single_rpc_call<
-	P: serde::Serialize + 'static,
-	R: serde::de::DeserializeOwned + Send + 'static,
->(
-	lock_file: &Path,
-	log: log::Logger,
-	method: &'static str,
-	params: P,
-) -> Result<R, CodeError> {
-	let client = match connect_as_client(lock_file).await {
-		Ok(p) => p,
+/// Connects to the singleton holding the lock file, failing with
+/// `NoRunningTunnel` if there's none. If a socket activated service is
+/// installed, its socket is connected to instead, which starts it.
+pub async fn connect_to_running_tunnel(lock_file: &Path) -> Result<AsyncPipe, CodeError> {
+	match connect_as_client(lock_file).await {
 		Err(CodeError::SingletonLockfileOpenFailed(_))
-		| Err(CodeError::SingletonLockedProcessExited(_)) => {
-			return Err(CodeError::NoRunningTunnel);
+		| Err(CodeError::SingletonLockedProcessExited(_)) => connect_to_activation_socket().await,
+		r => r,
+	}
+}
+
+#[cfg(target_os = "linux")]
+async fn connect_to_activation_socket() -> Result<AsyncPipe, CodeError> {
+	for path in crate::util::socket_activation::activation_socket_paths() {
+		if path.exists() {
+			if let Ok(s) = crate::async_pipe::get_socket_rw_stream(&path).await {
+				return Ok(s);
+			}
 		}
-		Err(e) => return Err(e),
-	};
+	}
+
+	Err(CodeError::NoRunningTunnel)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn connect_to_activation_socket() -> Result<AsyncPipe, CodeError> {
+	Err(CodeError::NoRunningTunnel)
+}
+
+/// RPC connection to the running tunnel's singleton, closed when dropped.
+pub struct SingletonRpcClient {
+	pub caller: RpcCaller<JsonRpcSerializer>,
+	task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for SingletonRpcClient {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+/// Connects to the running tunnel's singleton and negotiates the protocol.
+pub async fn connect_singleton_rpc(
+	lock_file: &Path,
+	log: log::Logger,
+) -> Result<SingletonRpcClient, CodeError> {
+	let client = connect_to_running_tunnel(lock_file).await?;
 
 	let (msg_tx, msg_rx) = mpsc::unbounded_channel();
 	let mut rpc = new_json_rpc();
@@ -186,7 +235,21 @@ pub async fn do_single_rpc_call<
 		.unwrap();
 	});
 
-	let r = caller.call(method, params).await.unwrap();
-	rpc.abort();
+	let client = SingletonRpcClient { caller, task: rpc };
+	client.caller.hello(&protocol::singleton::PROTOCOL).await?;
+	Ok(client)
+}
+
+pub async fn do_single_rpc_call<
+	P: serde::Serialize + 'static,
+	R: serde::de::DeserializeOwned + Send + 'static,
+>(
+	lock_file: &Path,
+	log: log::Logger,
+	method: &'static str,
+	params: P,
+) -> Result<R, CodeError> {
+	let client = connect_singleton_rpc(lock_file, log).await?;
+	let r = client.caller.call(method, params).await.unwrap();
 	r.map_err(CodeError::TunnelRpcCallFailed)
 }
@@ -9,17 +9,23 @@ use std::{
 };
 
 use super::{
-	code_server::CodeServerArgs,
-	control_server::ServerTermination,
+	challenge_limiter::ChallengeLimiter,
+	code_server::{CodeServerArgs, SERVER_OUTPUT_TARGET},
+	control_server::{ServerTermination, CONNECTION_ACTIVITY, CONTROL_RPC_STATS, SERVER_USAGE},
 	dev_tunnels::{ActiveTunnel, StatusLock},
+	paths::{uninstall_server, CachedServerRegistry},
+	ping::handle_ping,
 	protocol,
-	shutdown_signal::{ShutdownRequest, ShutdownSignal},
+	shutdown_signal::{Drain, ShutdownRequest, ShutdownSignal},
 };
 use crate::{
 	async_pipe::socket_stream_split,
+	auth::Auth,
 	json_rpc::{new_json_rpc, start_json_rpc, JsonRpcSerializer},
 	log,
-	rpc::{RpcCaller, RpcDispatcher},
+	rpc::{
+		PartialSender, RpcCaller, RpcDispatcher, METHOD_LIST_METHODS, METHOD_LOAD, METHOD_METRICS,
+	},
 	singleton::SingletonServer,
 	state::LauncherPaths,
 	tunnels::code_server::print_listening,
@@ -44,13 +50,21 @@ pub struct SingletonServerArgs<'a> {
 	pub paths: &'a LauncherPaths,
 	pub code_server_args: &'a CodeServerArgs,
 	pub platform: Platform,
+	pub drain: &'a Drain,
 	pub shutdown: Barrier<ShutdownSignal>,
 	pub log_broadcast: &'a BroadcastLogSink,
+	/// Auth the tunnel was started with, whose store is reported in the status.
+	pub auth: Auth,
+	/// Penalizes clients that fail auth challenges, whose totals are reported
+	/// in the status.
+	pub challenge_limiter: &'a Arc<ChallengeLimiter>,
 }
 
 struct StatusInfo {
 	name: String,
 	lock: StatusLock,
+	auth: Auth,
+	challenge_limiter: Arc<ChallengeLimiter>,
 }
 
 #[derive(Clone)]
@@ -58,12 +72,14 @@ struct SingletonServerContext {
 	log: log::Logger,
 	shutdown_tx: broadcast::Sender<ShutdownSignal>,
 	broadcast_tx: broadcast::Sender<Vec<u8>>,
+	logs: BroadcastLogSink,
 	// ugly: a lock in a lock. current_status needs to be provided only
 	// after we set up the tunnel, however the tunnel is created after the
 	// singleton server starts to avoid a gap in singleton availability.
 	// However, this should be safe, as the lock is only used for immediate
 	// data reads (in the `status` method).
 	current_status: Arc<Mutex<Option<StatusInfo>>>,
+	paths: LauncherPaths,
 }
 
 pub struct RpcServer {
@@ -77,6 +93,7 @@ pub fn make_singleton_server(
 	log: log::Logger,
 	server: SingletonServer,
 	shutdown_rx: Barrier<ShutdownSignal>,
+	paths: LauncherPaths,
 ) -> RpcServer {
 	let (shutdown_broadcast, _) = broadcast::channel(4);
 	let rpc = new_json_rpc();
@@ -86,7 +103,9 @@ pub fn make_singleton_server(
 		log: log.clone(),
 		shutdown_tx: shutdown_broadcast.clone(),
 		broadcast_tx: log_broadcast.get_brocaster(),
+		logs: log_broadcast.clone(),
 		current_status: current_status.clone(),
+		paths,
 	});
 
 	rpc.register_sync(
@@ -100,17 +119,7 @@ pub fn make_singleton_server(
 
 	rpc.register_sync(
 		protocol::singleton::METHOD_STATUS,
-		|_: protocol::EmptyObject, c| {
-			Ok(c.current_status
-				.lock()
-				.unwrap()
-				.as_ref()
-				.map(|s| protocol::singleton::StatusWithTunnelName {
-					name: Some(s.name.clone()),
-					status: s.lock.read(),
-				})
-				.unwrap_or_default())
-		},
+		|_: protocol::EmptyObject, c| Ok(get_status(&c.current_status, &c.logs)),
 	);
 
 	rpc.register_sync(
@@ -130,6 +139,45 @@ pub fn make_singleton_server(
 		},
 	);
 
+	rpc.register_async_streaming(
+		protocol::singleton::METHOD_STREAM_LOGS,
+		|p: protocol::singleton::StreamLogsParams, ctx, sender| async move {
+			stream_logs(&ctx.logs, p, sender).await;
+			Ok(protocol::EmptyObject {})
+		},
+	);
+
+	rpc.register_async(
+		protocol::METHOD_UNINSTALL_SERVER,
+		|p: protocol::UninstallServerParams, ctx| async move {
+			info!(
+				ctx.log,
+				"uninstalling server {} after client request", p.commit
+			);
+			let registry = CachedServerRegistry::new(&ctx.log, &ctx.paths, &SERVER_USAGE);
+			uninstall_server(&registry, &p.commit, p.force).await
+		},
+	);
+
+	rpc.register_sync(protocol::METHOD_PING, |p: protocol::PingParams, _| {
+		Ok(handle_ping(p))
+	});
+
+	rpc.register_sync(
+		protocol::singleton::METHOD_METRICS,
+		|_: protocol::EmptyObject, _| Ok(CONTROL_RPC_STATS.snapshot()),
+	);
+
+	rpc.set_protocol(protocol::singleton::PROTOCOL);
+	for method in [
+		METHOD_LIST_METHODS,
+		METHOD_LOAD,
+		METHOD_METRICS,
+		protocol::singleton::METHOD_METRICS,
+	] {
+		rpc.require_capability(method, protocol::CAPABILITY_INTROSPECTION);
+	}
+
 	// we tokio spawn instead of keeping a future, since we want it to progress
 	// even outside of the start_singleton_server loop (i.e. while the tunnel restarts)
 	let fut = tokio::spawn(async move {
@@ -156,6 +204,8 @@ pub async fn start_singleton_server<'a>(
 		*status = Some(StatusInfo {
 			name: args.tunnel.name.clone(),
 			lock: args.tunnel.status(),
+			auth: args.auth.clone(),
+			challenge_limiter: args.challenge_limiter.clone(),
 		})
 	}
 
@@ -165,6 +215,8 @@ pub async fn start_singleton_server<'a>(
 		args.paths,
 		args.code_server_args,
 		args.platform,
+		args.drain,
+		args.challenge_limiter,
 		shutdown_rx,
 	);
 
@@ -199,23 +251,161 @@ async fn serve_singleton_rpc<C: Clone + Send + Sync + 'static>(
 		};
 
 		let (read, write) = socket_stream_split(cnx);
-		let dispatcher = dispatcher.clone();
+		let dispatcher = dispatcher.for_connection();
 		let msg_rx = log_broadcast.replay_and_subscribe();
 		let shutdown_rx = shutdown_rx.clone();
+		let activity = CONNECTION_ACTIVITY.start();
 		tokio::spawn(async move {
+			let _activity = activity;
 			let _ = start_json_rpc(dispatcher.clone(), read, write, msg_rx, shutdown_rx).await;
 		});
 	}
 }
 
+/// Gets the status of the tunnel, which may not have started yet, along with
+/// the problems logged recently so that it's clear why it's unhealthy.
+fn get_status(
+	current_status: &Mutex<Option<StatusInfo>>,
+	logs: &BroadcastLogSink,
+) -> protocol::singleton::StatusWithTunnelName {
+	let mut status = current_status
+		.lock()
+		.unwrap()
+		.as_ref()
+		.map(|s| protocol::singleton::StatusWithTunnelName {
+			name: Some(s.name.clone()),
+			status: s.lock.read(),
+			credentials: Some(s.auth.credential_store_state()),
+			auth_challenges: Some(s.challenge_limiter.stats()),
+			..Default::default()
+		})
+		.unwrap_or_default();
+	status.recent_problems = logs.recent_problems(log::Level::Warn, STATUS_PROBLEM_LINES);
+	status
+}
+
+/// Sends logs to a `stream_logs` caller: the recent ones it asked for, then
+/// new ones if it's following. Records are dropped rather than queued for a
+/// caller that reads too slowly, so it never holds up logging.
+async fn stream_logs(
+	logs: &BroadcastLogSink,
+	params: protocol::singleton::StreamLogsParams,
+	sender: PartialSender<JsonRpcSerializer, protocol::singleton::LogRecord>,
+) {
+	let wanted = |r: &protocol::singleton::LogRecord| {
+		(params.server || !r.server)
+			&& match (r.level, params.level) {
+				(Some(level), Some(min)) => level >= min,
+				_ => true,
+			}
+	};
+
+	let (history, mut rx) = logs.history_and_subscribe();
+	let history: Vec<_> = history.into_iter().filter(wanted).collect();
+	let skip = history.len().saturating_sub(params.lines);
+	for record in history.into_iter().skip(skip) {
+		if !sender.send(record).await {
+			return;
+		}
+	}
+
+	if !params.follow {
+		return;
+	}
+
+	loop {
+		let record = match rx.recv().await {
+			Ok(r) if wanted(&r) => r,
+			Ok(_) => continue,
+			// always sent, so the caller knows its output has gaps
+			Err(broadcast::error::RecvError::Lagged(n)) => protocol::singleton::LogRecord {
+				seq: 0,
+				level: Some(log::Level::Warn),
+				prefix: String::new(),
+				message: format!("{} log records were dropped while reading slowly", n),
+				server: false,
+			},
+			Err(broadcast::error::RecvError::Closed) => return,
+		};
+
+		if !sender.send(record).await {
+			return;
+		}
+	}
+}
+
+/// Number of log records kept for each band of severity.
+const LOG_HISTORY_PER_BAND: usize = 200;
+
+/// Number of log records replayed to clients when they connect.
+const LOG_REPLAY_LINES: usize = 50;
+
+/// Number of recent warnings and errors included in the status.
+pub const STATUS_PROBLEM_LINES: usize = 10;
+
 /// Log sink that can broadcast and replay log events. Used for transmitting
 /// logs from the singleton to all clients. This should be created and injected
 /// into other services, like the tunnel, before `start_singleton_server`
 /// is called.
 #[derive(Clone)]
 pub struct BroadcastLogSink {
-	recent: Arc<Mutex<RingBuffer<Vec<u8>>>>,
 	tx: broadcast::Sender<Vec<u8>>,
+	history: Arc<Mutex<LogHistory>>,
+	records: broadcast::Sender<protocol::singleton::LogRecord>,
+}
+
+/// Recent log records. Verbose, informational, and problem records are kept
+/// in separate buffers, so that a burst of trace logs doesn't evict the
+/// errors that explain why the tunnel is unhealthy.
+struct LogHistory {
+	verbose: RingBuffer<protocol::singleton::LogRecord>,
+	info: RingBuffer<protocol::singleton::LogRecord>,
+	problems: RingBuffer<protocol::singleton::LogRecord>,
+	next_seq: u64,
+}
+
+impl LogHistory {
+	fn new(per_band: usize) -> Self {
+		Self {
+			verbose: RingBuffer::new(per_band),
+			info: RingBuffer::new(per_band),
+			problems: RingBuffer::new(per_band),
+			next_seq: 1,
+		}
+	}
+
+	fn push(&mut self, record: protocol::singleton::LogRecord) {
+		let band = match record.level {
+			Some(log::Level::Trace | log::Level::Debug) => &mut self.verbose,
+			Some(log::Level::Warn | log::Level::Error | log::Level::Critical) => &mut self.problems,
+			_ => &mut self.info,
+		};
+		band.push(record);
+	}
+
+	/// Gets the records in every band, oldest first.
+	fn records(&self) -> Vec<protocol::singleton::LogRecord> {
+		let mut records: Vec<_> = self
+			.verbose
+			.iter()
+			.chain(self.info.iter())
+			.chain(self.problems.iter())
+			.cloned()
+			.collect();
+		records.sort_by_key(|r| r.seq);
+		records
+	}
+
+	/// Gets the last `n` problems at or above the level, oldest first.
+	fn problems(&self, min: log::Level, n: usize) -> Vec<protocol::singleton::LogRecord> {
+		let problems: Vec<_> = self
+			.problems
+			.iter()
+			.filter(|r| r.level.map(|l| l >= min).unwrap_or(false))
+			.collect();
+		let skip = problems.len().saturating_sub(n);
+		problems.into_iter().skip(skip).cloned().collect()
+	}
 }
 
 impl Default for BroadcastLogSink {
@@ -227,9 +417,11 @@ impl Default for BroadcastLogSink {
 impl BroadcastLogSink {
 	pub fn new() -> Self {
 		let (tx, _) = broadcast::channel(64);
+		let (records, _) = broadcast::channel(256);
 		Self {
 			tx,
-			recent: Arc::new(Mutex::new(RingBuffer::new(50))),
+			history: Arc::new(Mutex::new(LogHistory::new(LOG_HISTORY_PER_BAND))),
+			records,
 		}
 	}
 
@@ -242,8 +434,10 @@ impl BroadcastLogSink {
 	) -> ConcatReceivable<Vec<u8>, mpsc::UnboundedReceiver<Vec<u8>>, broadcast::Receiver<Vec<u8>>> {
 		let (log_replay_tx, log_replay_rx) = mpsc::unbounded_channel();
 
-		for log in self.recent.lock().unwrap().iter() {
-			let _ = log_replay_tx.send(log.clone());
+		let history = self.history.lock().unwrap().records();
+		let skip = history.len().saturating_sub(LOG_REPLAY_LINES);
+		for r in history.into_iter().skip(skip) {
+			let _ = log_replay_tx.send(serialize_log(r.level, &r.prefix, &r.message));
 		}
 
 		let _ = log_replay_tx.send(RpcCaller::serialize_notify(
@@ -254,26 +448,411 @@ impl BroadcastLogSink {
 
 		ConcatReceivable::new(log_replay_rx, self.tx.subscribe())
 	}
+
+	/// Gets the recorded history along with a receiver for records logged
+	/// after it, so that none are missed or repeated between the two.
+	fn history_and_subscribe(
+		&self,
+	) -> (
+		Vec<protocol::singleton::LogRecord>,
+		broadcast::Receiver<protocol::singleton::LogRecord>,
+	) {
+		let history = self.history.lock().unwrap();
+		let rx = self.records.subscribe();
+		(history.records(), rx)
+	}
+
+	/// Gets the last `n` warnings or errors at or above the level.
+	pub fn recent_problems(
+		&self,
+		min: log::Level,
+		n: usize,
+	) -> Vec<protocol::singleton::LogRecord> {
+		self.history.lock().unwrap().problems(min, n)
+	}
+
+	fn write_record(&self, level: log::Level, target: &str, prefix: &str, message: &str) {
+		let mut history = self.history.lock().unwrap();
+		let record = protocol::singleton::LogRecord {
+			seq: history.next_seq,
+			level: Some(level),
+			prefix: prefix.to_string(),
+			message: message.to_string(),
+			server: target == SERVER_OUTPUT_TARGET,
+		};
+		history.next_seq += 1;
+		let _ = self.records.send(record.clone());
+		history.push(record);
+	}
+}
+
+fn serialize_log(level: Option<log::Level>, prefix: &str, message: &str) -> Vec<u8> {
+	RpcCaller::serialize_notify(
+		&JsonRpcSerializer {},
+		protocol::singleton::METHOD_LOG,
+		protocol::singleton::LogMessage {
+			level,
+			prefix,
+			message,
+		},
+	)
 }
 
 impl log::LogSink for BroadcastLogSink {
 	fn write_log(&self, level: log::Level, prefix: &str, message: &str) {
-		let s = JsonRpcSerializer {};
-		let serialized = RpcCaller::serialize_notify(
-			&s,
+		self.write_log_from(level, "", prefix, message)
+	}
+
+	fn write_log_from(&self, level: log::Level, target: &str, prefix: &str, message: &str) {
+		self.write_record(level, target, prefix, message);
+		let _ = self.tx.send(serialize_log(Some(level), prefix, message));
+	}
+
+	fn write_result(&self, message: &str) {
+		self.write_log(log::Level::Info, "", message);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+	use super::*;
+	use crate::{
+		log::LogSink,
+		rpc::PARTIAL_RESULTS_WINDOW,
+		util::sync::{new_barrier, BarrierOpener},
+	};
+
+	#[tokio::test]
+	async fn test_replays_history_then_follows() {
+		let sink = BroadcastLogSink::new();
+		sink.write_log(log::Level::Info, "", "first");
+		sink.write_log(log::Level::Warn, "", "second");
+
+		let (server_io, client_io) = tokio::io::duplex(4096);
+		let (server_closed, _close_server) = new_barrier::<()>();
+		let (server_read, server_write) = tokio::io::split(server_io);
+		tokio::spawn(start_json_rpc(
+			new_json_rpc().methods(()).build(log::Logger::test()),
+			server_read,
+			server_write,
+			sink.replay_and_subscribe(),
+			server_closed.clone(),
+		));
+
+		let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+		let mut client = new_json_rpc().methods(tx);
+		client.register_sync(
 			protocol::singleton::METHOD_LOG,
-			protocol::singleton::LogMessage {
-				level: Some(level),
-				prefix,
-				message,
+			|m: protocol::singleton::LogMessageOwned, tx| {
+				tx.send(m.message).ok();
+				Ok(())
 			},
 		);
+		client.register_sync(
+			protocol::singleton::METHOD_LOG_REPLY_DONE,
+			|_: protocol::EmptyObject, tx| {
+				tx.send("<replayed>".to_string()).ok();
+				Ok(())
+			},
+		);
+
+		let (client_read, client_write) = tokio::io::split(client_io);
+		let (_msg_tx, msg_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+		tokio::spawn(start_json_rpc(
+			client.build(log::Logger::test()),
+			client_read,
+			client_write,
+			msg_rx,
+			server_closed,
+		));
 
-		let _ = self.tx.send(serialized.clone());
-		self.recent.lock().unwrap().push(serialized);
+		for expected in ["first", "second", "<replayed>"] {
+			assert_eq!(rx.recv().await.unwrap(), expected);
+		}
+
+		sink.write_log(log::Level::Info, "", "live");
+		assert_eq!(rx.recv().await.unwrap(), "live");
 	}
 
-	fn write_result(&self, message: &str) {
-		self.write_log(log::Level::Info, "", message);
+	/// Builds a dispatcher that serves `stream_logs` from the sink.
+	fn log_stream_dispatcher(
+		sink: &BroadcastLogSink,
+	) -> RpcDispatcher<JsonRpcSerializer, BroadcastLogSink> {
+		let mut server = new_json_rpc().methods(sink.clone());
+		server.register_async_streaming(
+			protocol::singleton::METHOD_STREAM_LOGS,
+			|p: protocol::singleton::StreamLogsParams, ctx, sender| async move {
+				stream_logs(&ctx, p, sender).await;
+				Ok(protocol::EmptyObject {})
+			},
+		);
+		server.build(log::Logger::test())
+	}
+
+	/// Serves `stream_logs` from the sink, returning a caller connected to it
+	/// and the opener that closes the connection.
+	fn serve_log_stream(
+		sink: &BroadcastLogSink,
+	) -> (RpcCaller<JsonRpcSerializer>, BarrierOpener<()>) {
+		let (server_io, client_io) = tokio::io::duplex(4096);
+		let (closed, close) = new_barrier::<()>();
+
+		let (server_read, server_write) = tokio::io::split(server_io);
+		tokio::spawn(start_json_rpc(
+			log_stream_dispatcher(sink),
+			server_read,
+			server_write,
+			(),
+			closed.clone(),
+		));
+
+		let (msg_tx, msg_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+		let mut client = new_json_rpc();
+		let caller = client.get_caller(msg_tx);
+		let (client_read, client_write) = tokio::io::split(client_io);
+		tokio::spawn(start_json_rpc(
+			client.methods(()).build(log::Logger::test()),
+			client_read,
+			client_write,
+			msg_rx,
+			closed,
+		));
+
+		(caller, close)
+	}
+
+	#[tokio::test]
+	async fn test_streams_log_history_then_live() {
+		use futures::StreamExt;
+
+		let sink = BroadcastLogSink::new();
+		for i in 0..5 {
+			sink.write_log(log::Level::Info, "", &format!("old {}", i));
+		}
+		sink.write_log(log::Level::Debug, "", "too verbose");
+		sink.write_log_from(
+			log::Level::Trace,
+			SERVER_OUTPUT_TARGET,
+			"[codeserver.0] ",
+			"server output",
+		);
+
+		let (caller, _close) = serve_log_stream(&sink);
+		let (mut records, _) = caller
+			.call_streaming::<_, _, protocol::singleton::LogRecord, protocol::EmptyObject>(
+				protocol::singleton::METHOD_STREAM_LOGS,
+				protocol::singleton::StreamLogsParams {
+					lines: 2,
+					follow: true,
+					level: Some(log::Level::Info),
+					server: false,
+				},
+			);
+
+		assert_eq!(records.next().await.unwrap().unwrap().message, "old 3");
+		assert_eq!(records.next().await.unwrap().unwrap().message, "old 4");
+
+		sink.write_log(log::Level::Debug, "", "still too verbose");
+		sink.write_log_from(log::Level::Info, SERVER_OUTPUT_TARGET, "", "server");
+		sink.write_log(log::Level::Warn, "[tunnel.0] ", "live");
+		sink.write_log(log::Level::Error, "", "live 2");
+
+		let live = records.next().await.unwrap().unwrap();
+		assert_eq!(live.message, "live");
+		assert_eq!(live.prefix, "[tunnel.0] ");
+		assert_eq!(live.level, Some(log::Level::Warn));
+		let live_2 = records.next().await.unwrap().unwrap();
+		assert_eq!(live_2.message, "live 2");
+		assert!(live_2.seq > live.seq);
+	}
+
+	#[tokio::test]
+	async fn test_streams_log_history_without_following() {
+		use futures::StreamExt;
+
+		let sink = BroadcastLogSink::new();
+		sink.write_log(log::Level::Info, "", "first");
+		sink.write_log_from(
+			log::Level::Trace,
+			SERVER_OUTPUT_TARGET,
+			"[codeserver.0] ",
+			"server output",
+		);
+		sink.write_result("result");
+
+		let (caller, _close) = serve_log_stream(&sink);
+		let (records, result) = caller
+			.call_streaming::<_, _, protocol::singleton::LogRecord, protocol::EmptyObject>(
+				protocol::singleton::METHOD_STREAM_LOGS,
+				protocol::singleton::StreamLogsParams {
+					lines: 10,
+					follow: false,
+					level: None,
+					server: true,
+				},
+			);
+
+		let records: Vec<_> = records.map(|r| r.unwrap()).collect().await;
+		let messages: Vec<_> = records.iter().map(|r| r.message.as_str()).collect();
+		assert_eq!(messages, vec!["first", "server output", "result"]);
+		assert!(records[1].server);
+		assert_eq!(
+			records.iter().map(|r| r.seq).collect::<Vec<_>>(),
+			vec![1, 2, 3]
+		);
+		assert!(result.await.unwrap().is_ok());
+	}
+
+	/// Connection to a dispatcher shared with others, as in
+	/// `serve_singleton_rpc`, that's driven by hand so that requests can reuse
+	/// IDs like clients in separate processes do.
+	struct RawConnection {
+		lines: tokio::io::Lines<tokio::io::BufReader<tokio::io::ReadHalf<tokio::io::DuplexStream>>>,
+		write: tokio::io::WriteHalf<tokio::io::DuplexStream>,
+	}
+
+	impl RawConnection {
+		fn open(
+			dispatcher: &RpcDispatcher<JsonRpcSerializer, BroadcastLogSink>,
+			closed: Barrier<()>,
+		) -> Self {
+			let (server_io, client_io) = tokio::io::duplex(1 << 16);
+			let (server_read, server_write) = tokio::io::split(server_io);
+			tokio::spawn(start_json_rpc(
+				dispatcher.for_connection(),
+				server_read,
+				server_write,
+				(),
+				closed,
+			));
+
+			let (read, write) = tokio::io::split(client_io);
+			Self {
+				lines: tokio::io::BufReader::new(read).lines(),
+				write,
+			}
+		}
+
+		async fn send(&mut self, message: serde_json::Value) {
+			self.write
+				.write_all(format!("{}\n", message).as_bytes())
+				.await
+				.unwrap();
+		}
+
+		/// Counts the partial results received for the request until none
+		/// arrive for a while.
+		async fn count_partials(&mut self, id: u32) -> u32 {
+			let mut count = 0;
+			while let Ok(Ok(Some(line))) =
+				tokio::time::timeout(Duration::from_millis(200), self.lines.next_line()).await
+			{
+				let message: serde_json::Value = serde_json::from_str(&line).unwrap();
+				if message["method"] == "$/partial" && message["params"]["for_request_id"] == id {
+					count += 1;
+				}
+			}
+			count
+		}
+	}
+
+	#[tokio::test]
+	async fn test_streams_logs_to_connections_separately() {
+		let sink = BroadcastLogSink::new();
+		for i in 0..40 {
+			sink.write_log(log::Level::Info, "", &format!("old {}", i));
+		}
+
+		let dispatcher = log_stream_dispatcher(&sink);
+		let (closed, _close) = new_barrier::<()>();
+		let mut a = RawConnection::open(&dispatcher, closed.clone());
+		let mut b = RawConnection::open(&dispatcher, closed);
+
+		let request = serde_json::json!({
+			"id": 0,
+			"method": protocol::singleton::METHOD_STREAM_LOGS,
+			"params": { "lines": 40, "follow": true, "level": null, "server": false },
+		});
+		a.send(request.clone()).await;
+		b.send(request).await;
+		assert_eq!(a.count_partials(0).await, PARTIAL_RESULTS_WINDOW);
+		assert_eq!(b.count_partials(0).await, PARTIAL_RESULTS_WINDOW);
+
+		// acknowledging on one connection only refills its own send window
+		a.send(serde_json::json!({
+			"method": "$/partial_ack",
+			"params": { "id": 0, "count": PARTIAL_RESULTS_WINDOW },
+		}))
+		.await;
+		assert_eq!(a.count_partials(0).await, PARTIAL_RESULTS_WINDOW);
+		assert_eq!(b.count_partials(0).await, 0);
+
+		b.send(serde_json::json!({
+			"method": "$/partial_ack",
+			"params": { "id": 0, "count": PARTIAL_RESULTS_WINDOW },
+		}))
+		.await;
+		assert_eq!(b.count_partials(0).await, PARTIAL_RESULTS_WINDOW);
+	}
+
+	fn record(seq: u64, level: log::Level, message: &str) -> protocol::singleton::LogRecord {
+		protocol::singleton::LogRecord {
+			seq,
+			level: Some(level),
+			prefix: String::new(),
+			message: message.to_string(),
+			server: false,
+		}
+	}
+
+	#[test]
+	fn test_log_history_evicts_per_band() {
+		let mut history = LogHistory::new(2);
+		history.push(record(1, log::Level::Error, "error"));
+		history.push(record(2, log::Level::Info, "info"));
+		for i in 3..10 {
+			history.push(record(i, log::Level::Trace, "trace"));
+		}
+		history.push(record(10, log::Level::Warn, "warn"));
+
+		// trace spam only evicts older trace logs
+		let seqs: Vec<_> = history.records().iter().map(|r| r.seq).collect();
+		assert_eq!(seqs, vec![1, 2, 8, 9, 10]);
+
+		history.push(record(11, log::Level::Critical, "critical"));
+		let problems: Vec<_> = history
+			.problems(log::Level::Warn, 10)
+			.into_iter()
+			.map(|r| r.message)
+			.collect();
+		assert_eq!(problems, vec!["warn", "critical"]);
+
+		let problems = history.problems(log::Level::Error, 10);
+		assert_eq!(problems.len(), 1);
+		assert_eq!(problems[0].seq, 11);
+	}
+
+	#[test]
+	fn test_status_includes_recent_problems() {
+		let sink = BroadcastLogSink::new();
+		sink.write_log(log::Level::Info, "", "starting");
+		for i in 0..STATUS_PROBLEM_LINES + 2 {
+			sink.write_log(log::Level::Trace, "", "noise");
+			sink.write_log(log::Level::Error, "[tunnel.0] ", &format!("error {}", i));
+		}
+
+		// reported even before the tunnel has started
+		let status = get_status(&Mutex::new(None), &sink);
+		assert_eq!(status.name, None);
+		assert_eq!(status.recent_problems.len(), STATUS_PROBLEM_LINES);
+		assert_eq!(status.recent_problems[0].message, "error 2");
+		assert_eq!(
+			status.recent_problems.last().unwrap().message,
+			format!("error {}", STATUS_PROBLEM_LINES + 1)
+		);
 	}
 }
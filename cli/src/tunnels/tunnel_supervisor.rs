@@ -0,0 +1,287 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	any::Any,
+	fmt,
+	future::Future,
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	log,
+	state::{LauncherPaths, PersistedState},
+	util::{
+		errors::{AnyError, CodeError},
+		os::collect_host_info,
+	},
+};
+
+use super::server_watchdog::RestartBackoff;
+
+/// Tunnels that stay up for at least this long are considered healthy, and
+/// reset the restart backoff when they later fail.
+const HEALTHY_UPTIME: Duration = Duration::from_secs(10 * 60);
+
+/// Number of failures kept in the persisted history.
+const MAX_RECORDED_FAILURES: usize = 20;
+
+/// Failures older than this are dropped from the history, so that a tunnel
+/// which has since been healthy isn't reported as failing.
+const FAILURE_RETENTION_HOURS: i64 = 24;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TunnelFailure {
+	pub at: DateTime<Utc>,
+	pub error: String,
+}
+
+/// Recent tunnel failures, as shown in `code tunnel status`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct FailureSummary {
+	pub restarts_last_hour: usize,
+	pub last_failed_at: DateTime<Utc>,
+	pub last_error: String,
+}
+
+impl fmt::Display for FailureSummary {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"restarted {} times in the last hour, last error: {}",
+			self.restarts_last_hour, self.last_error
+		)
+	}
+}
+
+/// Failures of the tunnel within the service process. They're persisted so
+/// that other processes can report them, even after the service gave up.
+#[derive(Clone)]
+pub struct FailureHistory(PersistedState<Vec<TunnelFailure>>);
+
+impl FailureHistory {
+	pub fn new(paths: &LauncherPaths) -> Self {
+		Self(PersistedState::new(
+			paths.root().join("tunnel_failures.json"),
+		))
+	}
+
+	pub fn record(&self, error: String, at: DateTime<Utc>) {
+		let _ = self.0.update(|failures| {
+			failures.retain(|f| is_retained(f, at));
+			failures.push(TunnelFailure { at, error });
+			if failures.len() > MAX_RECORDED_FAILURES {
+				failures.drain(..failures.len() - MAX_RECORDED_FAILURES);
+			}
+		});
+	}
+
+	/// Summarizes recent failures, or returns None if the tunnel never failed.
+	pub fn summary(&self, now: DateTime<Utc>) -> Option<FailureSummary> {
+		let mut failures = self.0.load();
+		failures.retain(|f| is_retained(f, now));
+		let last = failures.last()?;
+
+		Some(FailureSummary {
+			restarts_last_hour: failures
+				.iter()
+				.filter(|f| now - f.at < chrono::Duration::hours(1))
+				.count(),
+			last_failed_at: last.at,
+			last_error: last.error.clone(),
+		})
+	}
+}
+
+fn is_retained(failure: &TunnelFailure, now: DateTime<Utc>) -> bool {
+	now - failure.at < chrono::Duration::hours(FAILURE_RETENTION_HOURS)
+}
+
+/// Runs the tunnel, restarting it in-process with backoff if it panics or
+/// fails. Once the limit of consecutive restarts is reached, this returns an
+/// error so that the service manager can take over. The exit code of a
+/// tunnel that stops without an error is returned as-is.
+pub async fn supervise_tunnel<F, Fut>(
+	log: &log::Logger,
+	mut backoff: RestartBackoff,
+	history: &FailureHistory,
+	mut run: F,
+) -> Result<i32, AnyError>
+where
+	F: FnMut() -> Fut,
+	Fut: Future<Output = Result<i32, AnyError>> + Send + 'static,
+{
+	loop {
+		let started_at = Instant::now();
+		let error = match tokio::spawn(run()).await {
+			Ok(Ok(code)) => return Ok(code),
+			Ok(Err(e)) => e.to_string(),
+			Err(e) if e.is_panic() => format!("panicked: {}", panic_message(e.into_panic())),
+			Err(e) => e.to_string(),
+		};
+
+		history.record(error.clone(), Utc::now());
+		if started_at.elapsed() >= HEALTHY_UPTIME {
+			backoff.reset();
+		}
+
+		let delay = match backoff.next_delay() {
+			Some(d) => d,
+			None => {
+				let failures = backoff.attempt() + 1;
+				error!(
+					log,
+					"Tunnel failed {} times in a row, not restarting it again: {}", failures, error
+				);
+				return Err(CodeError::TunnelRestartLimitReached(failures, error).into());
+			}
+		};
+
+		warning!(
+			log,
+			"Tunnel failed, restarting in {}ms (attempt {}/{}): {}",
+			delay.as_millis(),
+			backoff.attempt(),
+			backoff.limit(),
+			error
+		);
+		tokio::time::sleep(delay).await;
+	}
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+	if let Some(s) = payload.downcast_ref::<&str>() {
+		s.to_string()
+	} else if let Some(s) = payload.downcast_ref::<String>() {
+		s.clone()
+	} else {
+		"unknown panic".to_string()
+	}
+}
+
+/// Writes panics, their backtrace, and a description of the machine to the
+/// log before the default hook runs. The log redacts what it's given, like
+/// any other message. The machine is described up front, so the hook doesn't
+/// read files while panicking.
+pub fn install_panic_logger(log: log::Logger) {
+	let default_hook = std::panic::take_hook();
+	let host = collect_host_info().to_string();
+	std::panic::set_hook(Box::new(move |info| {
+		error!(
+			log,
+			"{}\n{}\nhost: {}",
+			info,
+			std::backtrace::Backtrace::force_capture(),
+			host
+		);
+		default_hook(info);
+	}));
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{
+		atomic::{AtomicU32, Ordering},
+		Arc,
+	};
+
+	use super::*;
+	use crate::util::errors::wrap;
+
+	fn make_history() -> (tempfile::TempDir, FailureHistory) {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let history = FailureHistory::new(&paths);
+		(dir, history)
+	}
+
+	#[tokio::test]
+	async fn test_supervise_tunnel_gives_up() {
+		let (_dir, history) = make_history();
+		let runs = Arc::new(AtomicU32::new(0));
+		let started_at = Instant::now();
+
+		let own_runs = runs.clone();
+		let result = supervise_tunnel(
+			&log::Logger::test(),
+			RestartBackoff::with_delays(2, Duration::from_millis(10), Duration::from_secs(1)),
+			&history,
+			move || {
+				own_runs.fetch_add(1, Ordering::SeqCst);
+				async { Err(wrap("relay closed", "tunnel failed").into()) }
+			},
+		)
+		.await;
+
+		assert_eq!(runs.load(Ordering::SeqCst), 3);
+		assert!(started_at.elapsed() >= Duration::from_millis(30));
+		assert_eq!(
+			result.unwrap_err().to_string(),
+			"the tunnel failed 3 times in a row, last error: tunnel failed: relay closed"
+		);
+
+		let summary = history.summary(Utc::now()).unwrap();
+		assert_eq!(summary.restarts_last_hour, 3);
+	}
+
+	#[tokio::test]
+	async fn test_supervise_tunnel_recovers_from_panic() {
+		let (_dir, history) = make_history();
+		let runs = Arc::new(AtomicU32::new(0));
+
+		let own_runs = runs.clone();
+		let code = supervise_tunnel(
+			&log::Logger::test(),
+			RestartBackoff::with_delays(2, Duration::from_millis(10), Duration::from_secs(1)),
+			&history,
+			move || {
+				let run = own_runs.fetch_add(1, Ordering::SeqCst);
+				async move {
+					if run == 0 {
+						panic!("boom");
+					}
+					Ok(3)
+				}
+			},
+		)
+		.await;
+
+		// the exit code of the run that didn't fail is passed on
+		assert_eq!(code.expect("expected to recover"), 3);
+		assert_eq!(runs.load(Ordering::SeqCst), 2);
+		assert_eq!(
+			history.summary(Utc::now()).unwrap().last_error,
+			"panicked: boom"
+		);
+	}
+
+	#[test]
+	fn test_failure_summary() {
+		let (_dir, history) = make_history();
+		let now = Utc::now();
+		assert_eq!(history.summary(now), None);
+
+		history.record("old".to_string(), now - chrono::Duration::hours(2));
+		history.record("first".to_string(), now - chrono::Duration::minutes(30));
+		history.record("second".to_string(), now - chrono::Duration::minutes(1));
+
+		let summary = history.summary(now).unwrap();
+		assert_eq!(summary.last_failed_at, now - chrono::Duration::minutes(1));
+		assert_eq!(
+			summary.to_string(),
+			"restarted 2 times in the last hour, last error: second"
+		);
+
+		// failures past the retention are no longer reported, and are pruned
+		// when the next one is recorded
+		let later = now + chrono::Duration::hours(FAILURE_RETENTION_HOURS);
+		assert_eq!(history.summary(later), None);
+		history.record("third".to_string(), later);
+		assert_eq!(history.0.load().len(), 1);
+	}
+}
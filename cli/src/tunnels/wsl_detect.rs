@@ -3,7 +3,25 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use crate::log;
+use std::{fmt, process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	constants::APPLICATION_NAME,
+	log,
+	state::{LauncherPaths, PersistedState},
+	util::command::new_tokio_command,
+};
+
+/// Maximum time to wait for the CLI on the other side of WSL to report its status.
+const INTEROP_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the result of probing the other side of WSL is reused for, since
+/// each probe starts the CLI there.
+const INTEROP_PROBE_CACHE_DURATION: Duration = Duration::from_secs(30);
 
 #[cfg(not(windows))]
 pub fn is_wsl_installed(_log: &log::Logger) -> bool {
@@ -51,3 +69,300 @@ pub fn is_wsl_installed(log: &log::Logger) -> bool {
 
 	false
 }
+
+/// Gets whether the CLI is running inside WSL.
+#[cfg(target_os = "linux")]
+pub fn is_wsl() -> bool {
+	std::env::var_os("WSL_DISTRO_NAME").is_some()
+		|| std::fs::read_to_string("/proc/sys/kernel/osrelease")
+			.map(|r| r.to_lowercase().contains("microsoft"))
+			.unwrap_or(false)
+}
+
+/// Side of the WSL boundary that a tunnel runs on.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InteropHost {
+	Windows,
+	Wsl,
+}
+
+impl fmt::Display for InteropHost {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			InteropHost::Windows => write!(f, "Windows"),
+			InteropHost::Wsl => write!(f, "WSL"),
+		}
+	}
+}
+
+/// A tunnel running on the other side of the WSL boundary of this machine.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InteropTunnel {
+	pub host: InteropHost,
+	pub name: Option<String>,
+}
+
+impl InteropTunnel {
+	/// Message shown before registering a tunnel that would duplicate this one.
+	pub fn duplicate_warning(&self) -> String {
+		let name = match &self.name {
+			Some(n) => format!("A tunnel named '{}'", n),
+			None => "A tunnel".to_string(),
+		};
+
+		format!(
+			"{} is already running on the {} side of this machine. Starting another one registers the machine twice; connect to the existing tunnel instead, or pass --ignore-interop to skip this check.",
+			name, self.host
+		)
+	}
+}
+
+/// Looks for a tunnel on the other side of the WSL boundary.
+#[async_trait]
+pub trait InteropProbe {
+	async fn probe(&self) -> Option<InteropTunnel>;
+}
+
+/// Probes by running `tunnel status` with the CLI on the other side: through
+/// `cmd.exe` interop from within WSL, or through `wsl.exe` from Windows.
+pub struct CommandInteropProbe {
+	log: log::Logger,
+}
+
+impl CommandInteropProbe {
+	pub fn new(log: log::Logger) -> Self {
+		Self { log }
+	}
+
+	fn status_command(&self) -> Option<(InteropHost, tokio::process::Command)> {
+		cfg_if::cfg_if! {
+			if #[cfg(target_os = "linux")] {
+				if !is_wsl() {
+					return None;
+				}
+
+				let mut cmd = new_tokio_command("cmd.exe");
+				cmd.args(["/d", "/c", APPLICATION_NAME]);
+				Some((InteropHost::Windows, cmd))
+			} else if #[cfg(windows)] {
+				if !is_wsl_installed(&self.log) {
+					return None;
+				}
+
+				let mut cmd = new_tokio_command("wsl.exe");
+				cmd.args(["--exec", APPLICATION_NAME]);
+				Some((InteropHost::Wsl, cmd))
+			} else {
+				None
+			}
+		}
+	}
+}
+
+#[async_trait]
+impl InteropProbe for CommandInteropProbe {
+	async fn probe(&self) -> Option<InteropTunnel> {
+		let (host, mut cmd) = self.status_command()?;
+		// the other side must not probe back across the boundary
+		cmd.args(["tunnel", "status", "--ignore-interop"])
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::null())
+			.kill_on_drop(true);
+
+		let output = match tokio::time::timeout(INTEROP_PROBE_TIMEOUT, cmd.output()).await {
+			Ok(Ok(o)) if o.status.success() => o,
+			Ok(Ok(o)) => {
+				debug!(self.log, "{} tunnel status exited with {}", host, o.status);
+				return None;
+			}
+			Ok(Err(e)) => {
+				debug!(self.log, "could not get {} tunnel status: {}", host, e);
+				return None;
+			}
+			Err(_) => {
+				debug!(self.log, "timed out getting {} tunnel status", host);
+				return None;
+			}
+		};
+
+		parse_interop_status(host, &output.stdout)
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct CachedProbe {
+	probed_at: Option<DateTime<Utc>>,
+	tunnel: Option<InteropTunnel>,
+}
+
+/// Reuses the result of another probe for `INTEROP_PROBE_CACHE_DURATION`,
+/// saved in the CLI's data directory so that it lasts across commands.
+pub struct CachedInteropProbe<P> {
+	probe: P,
+	state: PersistedState<CachedProbe>,
+}
+
+impl<P: InteropProbe> CachedInteropProbe<P> {
+	pub fn new(paths: &LauncherPaths, probe: P) -> Self {
+		Self {
+			probe,
+			state: PersistedState::new(paths.interop_probe_cache()),
+		}
+	}
+
+	async fn probe_at(&self, now: DateTime<Utc>) -> Option<InteropTunnel> {
+		let cached = self.state.load();
+		let fresh = cached
+			.probed_at
+			.and_then(|t| (now - t).to_std().ok())
+			.map(|age| age < INTEROP_PROBE_CACHE_DURATION)
+			.unwrap_or(false);
+		if fresh {
+			return cached.tunnel;
+		}
+
+		let tunnel = self.probe.probe().await;
+		let _ = self.state.save(CachedProbe {
+			probed_at: Some(now),
+			tunnel: tunnel.clone(),
+		});
+		tunnel
+	}
+}
+
+#[async_trait]
+impl<P: InteropProbe + Send + Sync> InteropProbe for CachedInteropProbe<P> {
+	async fn probe(&self) -> Option<InteropTunnel> {
+		self.probe_at(Utc::now()).await
+	}
+}
+
+#[derive(Deserialize)]
+struct InteropStatusOutput {
+	tunnel: Option<InteropStatusTunnel>,
+}
+
+#[derive(Deserialize)]
+struct InteropStatusTunnel {
+	name: Option<String>,
+}
+
+fn parse_interop_status(host: InteropHost, stdout: &[u8]) -> Option<InteropTunnel> {
+	let output: InteropStatusOutput = serde_json::from_slice(stdout).ok()?;
+	output.tunnel.map(|t| InteropTunnel { host, name: t.name })
+}
+
+/// Warns if a tunnel is already running on the other side of the WSL
+/// boundary, returning it if so.
+pub async fn warn_on_interop_tunnel(
+	log: &log::Logger,
+	probe: &impl InteropProbe,
+) -> Option<InteropTunnel> {
+	let tunnel = probe.probe().await?;
+	warning!(log, "{}", tunnel.duplicate_warning());
+	Some(tunnel)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	use super::*;
+
+	struct FakeProbe(Option<InteropTunnel>);
+
+	#[async_trait]
+	impl InteropProbe for FakeProbe {
+		async fn probe(&self) -> Option<InteropTunnel> {
+			self.0.clone()
+		}
+	}
+
+	#[derive(Default)]
+	struct CountingProbe(AtomicU32);
+
+	#[async_trait]
+	impl InteropProbe for CountingProbe {
+		async fn probe(&self) -> Option<InteropTunnel> {
+			let n = self.0.fetch_add(1, Ordering::SeqCst);
+			Some(InteropTunnel {
+				host: InteropHost::Windows,
+				name: Some(format!("probe-{}", n)),
+			})
+		}
+	}
+
+	#[tokio::test]
+	async fn test_cached_probe() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let start = Utc::now();
+		let name = |t: Option<InteropTunnel>| t.and_then(|t| t.name);
+
+		let cached = CachedInteropProbe::new(&paths, CountingProbe::default());
+		assert_eq!(name(cached.probe_at(start).await), Some("probe-0".into()));
+		assert_eq!(
+			name(cached.probe_at(start + chrono::Duration::seconds(10)).await),
+			Some("probe-0".into())
+		);
+
+		// the result is kept on disk for later commands
+		let later = CachedInteropProbe::new(&paths, CountingProbe::default());
+		assert_eq!(
+			name(later.probe_at(start + chrono::Duration::seconds(20)).await),
+			Some("probe-0".into())
+		);
+
+		assert_eq!(later.probe.0.load(Ordering::SeqCst), 0);
+
+		// and probed again once it's stale
+		assert_eq!(
+			name(cached.probe_at(start + chrono::Duration::seconds(31)).await),
+			Some("probe-1".into())
+		);
+	}
+
+	#[test]
+	fn test_parse_interop_status() {
+		assert_eq!(
+			parse_interop_status(
+				InteropHost::Windows,
+				br#"{"tunnel":{"name":"my-pc","tunnel":"Connected"},"service_installed":true}"#
+			),
+			Some(InteropTunnel {
+				host: InteropHost::Windows,
+				name: Some("my-pc".to_string()),
+			})
+		);
+		assert_eq!(
+			parse_interop_status(
+				InteropHost::Windows,
+				br#"{"tunnel":null,"service_installed":false}"#
+			),
+			None
+		);
+		assert_eq!(parse_interop_status(InteropHost::Wsl, b"not json"), None);
+	}
+
+	#[tokio::test]
+	async fn test_warn_on_interop_tunnel() {
+		let running = InteropTunnel {
+			host: InteropHost::Wsl,
+			name: Some("my-pc".to_string()),
+		};
+		assert_eq!(
+			warn_on_interop_tunnel(&log::Logger::test(), &FakeProbe(Some(running.clone()))).await,
+			Some(running.clone())
+		);
+		assert!(running
+			.duplicate_warning()
+			.starts_with("A tunnel named 'my-pc' is already running on the WSL side"));
+
+		assert_eq!(
+			warn_on_interop_tunnel(&log::Logger::test(), &FakeProbe(None)).await,
+			None
+		);
+	}
+}
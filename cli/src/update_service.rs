@@ -3,15 +3,27 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{ffi::OsStr, fmt, path::Path};
+use std::{
+	ffi::OsStr,
+	fmt,
+	path::Path,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+};
 
+use hyper::StatusCode;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
 
 use crate::{
 	constants::VSCODE_CLI_UPDATE_ENDPOINT,
-	debug, log, options, spanf,
+	debug, log, options, policy, spanf,
 	util::{
-		errors::{AnyError, CodeError, WrappedError},
+		errors::{wrap, AnyError, CodeError, WrappedError},
 		http::{BoxedHttp, SimpleResponse},
 		io::ReportCopyProgress,
 		tar, zipper,
@@ -55,8 +67,11 @@ fn quality_download_segment(quality: options::Quality) -> &'static str {
 	}
 }
 
-fn get_update_endpoint() -> Result<&'static str, CodeError> {
-	VSCODE_CLI_UPDATE_ENDPOINT.ok_or_else(|| CodeError::UpdatesNotConfigured("no service url"))
+fn get_update_endpoint() -> Result<String, CodeError> {
+	policy::current()
+		.update_endpoint(VSCODE_CLI_UPDATE_ENDPOINT)
+		.map(|e| e.to_string())
+		.ok_or(CodeError::UpdatesNotConfigured("no service url"))
 }
 
 impl UpdateService {
@@ -105,6 +120,56 @@ impl UpdateService {
 		})
 	}
 
+	/// Gets the release of the target built from the commit. Fails with
+	/// `CodeError::UnknownCommit` if there's no such build.
+	pub async fn get_release_by_commit(
+		&self,
+		platform: Platform,
+		target: TargetKind,
+		quality: options::Quality,
+		commit: &str,
+	) -> Result<Release, AnyError> {
+		let update_endpoint = get_update_endpoint()?;
+		let download_segment = target
+			.download_segment(platform)
+			.ok_or_else(|| CodeError::UnsupportedPlatform(platform.to_string()))?;
+		let download_url = format!(
+			"{}/api/versions/commit:{}/{}/{}",
+			update_endpoint,
+			commit,
+			download_segment,
+			quality_download_segment(quality),
+		);
+
+		let mut response = spanf!(
+			self.log,
+			self.log.span("server.version.resolve"),
+			self.client.make_request("GET", download_url)
+		)?;
+
+		if response.status_code == StatusCode::NOT_FOUND {
+			return Err(CodeError::UnknownCommit {
+				quality: quality.get_machine_name().to_string(),
+				commit: commit.to_string(),
+			}
+			.into());
+		}
+		if !response.status_code.is_success() {
+			return Err(response.into_err().await.into());
+		}
+
+		let res = response.json::<UpdateServerVersion>().await?;
+		debug!(self.log, "Resolved commit {} to {}", commit, res.name);
+
+		Ok(Release {
+			target,
+			platform,
+			quality,
+			name: res.name,
+			commit: res.version,
+		})
+	}
+
 	/// Gets the latest commit for the target of the given quality.
 	pub async fn get_latest_commit(
 		&self,
@@ -170,19 +235,56 @@ impl UpdateService {
 	}
 }
 
-pub fn unzip_downloaded_release<T>(
+lazy_static! {
+	/// Limits how many archives are extracted at once, since each keeps a
+	/// blocking thread and the disk busy.
+	static ref EXTRACT_PERMITS: Semaphore = Semaphore::new(extract_concurrency());
+}
+
+fn extract_concurrency() -> usize {
+	std::thread::available_parallelism()
+		.map(|n| (n.get() / 2).max(1))
+		.unwrap_or(1)
+}
+
+/// Sets the flag when dropped, which stops an extraction whose future was
+/// dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+	fn drop(&mut self) {
+		self.0.store(true, Ordering::Relaxed);
+	}
+}
+
+/// Extracts the release on the blocking thread pool, so that the runtime
+/// keeps handling connections and RPC calls while it runs.
+pub async fn unzip_downloaded_release<T>(
 	compressed_file: &Path,
 	target_dir: &Path,
 	reporter: T,
 ) -> Result<(), WrappedError>
 where
-	T: ReportCopyProgress,
+	T: ReportCopyProgress + Send + 'static,
 {
-	if compressed_file.extension() == Some(OsStr::new("zip")) {
-		zipper::unzip_file(compressed_file, target_dir, reporter)
-	} else {
-		tar::decompress_tarball(compressed_file, target_dir, reporter)
-	}
+	let _permit = EXTRACT_PERMITS
+		.acquire()
+		.await
+		.expect("expected the semaphore to stay open");
+	let cancelled = Arc::new(AtomicBool::new(false));
+	let _cancel = CancelOnDrop(cancelled.clone());
+	let compressed_file = compressed_file.to_path_buf();
+	let target_dir = target_dir.to_path_buf();
+
+	tokio::task::spawn_blocking(move || {
+		if compressed_file.extension() == Some(OsStr::new("zip")) {
+			zipper::unzip_file(&compressed_file, &target_dir, reporter, &cancelled)
+		} else {
+			tar::decompress_tarball(&compressed_file, &target_dir, reporter, &cancelled)
+		}
+	})
+	.await
+	.map_err(|e| wrap(e, "error joining extraction task"))?
 }
 
 #[derive(Eq, PartialEq, Copy, Clone)]
@@ -204,6 +306,23 @@ impl TargetKind {
 	}
 }
 
+/// Names that `--assume-platform` accepts for each platform.
+const PLATFORM_NAMES: &[(&str, Platform)] = &[
+	("linux-x64", Platform::LinuxX64),
+	("linux-arm64", Platform::LinuxARM64),
+	("linux-armhf", Platform::LinuxARM32),
+	("linux-legacy-x64", Platform::LinuxX64Legacy),
+	("linux-legacy-arm64", Platform::LinuxARM64Legacy),
+	("linux-legacy-armhf", Platform::LinuxARM32Legacy),
+	("linux-alpine-x64", Platform::LinuxAlpineX64),
+	("linux-alpine-arm64", Platform::LinuxAlpineARM64),
+	("darwin-x64", Platform::DarwinX64),
+	("darwin-arm64", Platform::DarwinARM64),
+	("win32-x64", Platform::WindowsX64),
+	("win32-x86", Platform::WindowsX86),
+	("win32-arm64", Platform::WindowsARM64),
+];
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Platform {
 	LinuxAlpineX64,
@@ -312,6 +431,33 @@ impl Platform {
 	}
 }
 
+impl Platform {
+	/// Gets the name `--assume-platform` accepts for the platform.
+	pub fn name(&self) -> &'static str {
+		PLATFORM_NAMES
+			.iter()
+			.find(|(_, p)| p == self)
+			.map(|(n, _)| *n)
+			.expect("expected every platform to have a name")
+	}
+}
+
+impl FromStr for Platform {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim().to_ascii_lowercase();
+		PLATFORM_NAMES
+			.iter()
+			.find(|(name, _)| *name == s)
+			.map(|(_, p)| *p)
+			.ok_or_else(|| {
+				let names: Vec<&str> = PLATFORM_NAMES.iter().map(|(n, _)| *n).collect();
+				format!("unknown platform, expected one of: {}", names.join(", "))
+			})
+	}
+}
+
 impl fmt::Display for Platform {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		f.write_str(match self {
@@ -331,3 +477,166 @@ impl fmt::Display for Platform {
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, Instant};
+
+	use super::*;
+	use crate::{
+		json_rpc::JsonRpcSerializer,
+		rpc::{MaybeSync, RpcBuilder},
+		tunnels::protocol::EmptyObject,
+		util::io::SilentCopyProgress,
+	};
+
+	/// Writes a tarball of small files in a top-level folder, as servers are.
+	fn write_tarball(path: &Path, files: usize) {
+		let file = std::fs::File::create(path).unwrap();
+		let gz = flate2::write::GzEncoder::new(file, flate2::Compression::fast());
+		let mut builder = ::tar::Builder::new(gz);
+		let contents = vec![b'a'; 4096];
+		for i in 0..files {
+			let mut header = ::tar::Header::new_gnu();
+			header.set_size(contents.len() as u64);
+			header.set_mode(0o644);
+			header.set_cksum();
+			builder
+				.append_data(
+					&mut header,
+					format!("server/{}/file-{}.js", i % 50, i),
+					&contents[..],
+				)
+				.unwrap();
+		}
+		builder.into_inner().unwrap().finish().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_extraction_keeps_runtime_responsive() {
+		let dir = tempfile::tempdir().unwrap();
+		let archive = dir.path().join("server.tar.gz");
+		write_tarball(&archive, 5000);
+
+		let mut rpc = RpcBuilder::new(JsonRpcSerializer {}).methods(());
+		rpc.register_async("ping", |_: EmptyObject, _| async { Ok(EmptyObject {}) });
+		let dispatcher = rpc.build(log::Logger::test());
+		let ping = br#"{"id":1,"method":"ping","params":{}}"#;
+
+		// the test runtime has a single thread, which extracting on would block
+		let out = dir.path().join("out");
+		let extract = unzip_downloaded_release(&archive, &out, SilentCopyProgress());
+		tokio::pin!(extract);
+		let mut pings = 0;
+		let mut slowest = Duration::ZERO;
+		loop {
+			let start = Instant::now();
+			let response = match dispatcher.dispatch(ping) {
+				MaybeSync::Future(f) => tokio::spawn(f),
+				_ => panic!("expected an async method"),
+			};
+			tokio::select! {
+				r = &mut extract => {
+					r.unwrap();
+					break;
+				}
+				r = response => assert!(r.unwrap().is_some()),
+			}
+
+			slowest = slowest.max(start.elapsed());
+			pings += 1;
+			tokio::time::sleep(Duration::from_millis(5)).await;
+		}
+
+		assert!(pings > 0, "expected pings to be answered during extraction");
+		assert!(
+			slowest < Duration::from_millis(250),
+			"slowest ping took {:?}",
+			slowest
+		);
+		assert!(dir.path().join("out/0/file-0.js").exists());
+	}
+
+	#[test]
+	fn test_platform_from_str() {
+		for (name, platform) in PLATFORM_NAMES {
+			assert_eq!(Platform::from_str(name), Ok(*platform));
+		}
+		assert_eq!("Linux-X64 ".parse(), Ok(Platform::LinuxX64));
+		assert_eq!("linux-armhf".parse(), Ok(Platform::LinuxARM32));
+		assert_eq!("linux-alpine-arm64".parse(), Ok(Platform::LinuxAlpineARM64));
+
+		for (name, platform) in PLATFORM_NAMES {
+			assert_eq!(platform.name(), *name);
+		}
+
+		let err = Platform::from_str("freebsd-x64").unwrap_err();
+		assert!(err.contains("linux-x64, linux-arm64"), "{}", err);
+		assert!(Platform::from_str("").is_err());
+	}
+
+	#[tokio::test]
+	async fn test_shares_client_with_dev_tunnels() {
+		// a new connection is counted each time the server makes a service
+		let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+		let counted = connections.clone();
+		let make_svc = hyper::service::make_service_fn(move |_| {
+			counted.fetch_add(1, Ordering::SeqCst);
+			async move {
+				Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+					|_: hyper::Request<hyper::Body>| async move {
+						Ok::<_, std::convert::Infallible>(hyper::Response::new(hyper::Body::from(
+							"ok",
+						)))
+					},
+				))
+			}
+		});
+		let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+		let url = format!("http://{}", server.local_addr());
+		tokio::spawn(server);
+
+		let dir = tempfile::tempdir().unwrap();
+		let paths = crate::state::LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let log = log::Logger::test();
+		let tunnels = crate::tunnels::dev_tunnels::DevTunnels::new_remote_tunnel(
+			&log,
+			crate::auth::Auth::new(&paths, log.clone()),
+			&paths,
+		);
+		let service =
+			UpdateService::new(log, Arc::new(crate::util::http::ReqwestSimpleHttp::new()));
+
+		let body = tunnels.http.get(&url).send().await.unwrap().text().await;
+		assert_eq!(body.unwrap(), "ok");
+		let mut res = service.client.make_request("GET", url).await.unwrap();
+		let mut body = String::new();
+		tokio::io::AsyncReadExt::read_to_string(&mut res.read, &mut body)
+			.await
+			.unwrap();
+		assert_eq!(body, "ok");
+
+		// the update service reused the connection the tunnels client opened
+		assert_eq!(connections.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn test_policy_pins_update_endpoint() {
+		// configures the policy, so it's run in a child
+		if !crate::util::test_env::in_child_with_env(
+			concat!(module_path!(), "::test_policy_pins_update_endpoint"),
+			&[],
+		) {
+			return;
+		}
+		policy::configure(policy::Policy {
+			update_endpoint: Some("https://updates.contoso.com".to_string()),
+			..Default::default()
+		});
+
+		assert_eq!(
+			get_update_endpoint().unwrap(),
+			"https://updates.contoso.com"
+		);
+	}
+}
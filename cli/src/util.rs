@@ -5,18 +5,27 @@
 
 mod is_integrated;
 
+pub mod browser;
 pub mod command;
+pub mod crypto;
 pub mod errors;
 pub mod http;
 pub mod input;
 pub mod io;
 pub mod machine;
+pub mod preflight;
 pub mod prereqs;
+pub mod progress;
 pub mod ring_buffer;
 pub mod sync;
+#[cfg(test)]
+pub mod test_env;
 pub use is_integrated::*;
 pub mod app_lock;
 pub mod file_lock;
 pub mod os;
+#[cfg(unix)]
+pub mod socket_activation;
 pub mod tar;
+pub mod tls;
 pub mod zipper;
@@ -0,0 +1,64 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use url::Url;
+
+use super::errors::CodeError;
+
+/// Gets whether there's likely a display to open a browser on. On Linux,
+/// sessions without one, such as over SSH, don't have a display server set.
+pub fn has_display() -> bool {
+	if cfg!(target_os = "linux") {
+		["DISPLAY", "WAYLAND_DISPLAY"]
+			.iter()
+			.any(|v| std::env::var_os(v).map(|v| !v.is_empty()).unwrap_or(false))
+	} else {
+		true
+	}
+}
+
+/// Only http(s) URLs are opened, and always in their serialized form, so
+/// that they're percent-encoded and can't be mistaken for files.
+fn browsable_url(url: &str) -> Result<Url, CodeError> {
+	let parsed = Url::parse(url).map_err(|_| CodeError::InvalidBrowserUrl(url.to_string()))?;
+	match parsed.scheme() {
+		"http" | "https" => Ok(parsed),
+		_ => Err(CodeError::InvalidBrowserUrl(url.to_string())),
+	}
+}
+
+/// Opens the URL in the default browser. Some launchers only exit once the
+/// browser does, so callers shouldn't wait on this before going on.
+pub async fn open(url: &str) -> Result<(), CodeError> {
+	let url = browsable_url(url)?;
+	tokio::task::spawn_blocking(move || ::open::that(url.as_str()))
+		.await
+		.map_err(|e| CodeError::CouldNotOpenBrowser(e.to_string()))?
+		.map_err(|e| CodeError::CouldNotOpenBrowser(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_browsable_url() {
+		assert_eq!(
+			browsable_url("http://127.0.0.1:8000/?tkn=abc")
+				.unwrap()
+				.as_str(),
+			"http://127.0.0.1:8000/?tkn=abc"
+		);
+		assert_eq!(
+			browsable_url("https://[::1]:8000/a b\"&c")
+				.unwrap()
+				.as_str(),
+			"https://[::1]:8000/a%20b%22&c"
+		);
+		assert!(browsable_url("file:///etc/passwd").is_err());
+		assert!(browsable_url("--help").is_err());
+		assert!(browsable_url("calc.exe").is_err());
+	}
+}
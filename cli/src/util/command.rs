@@ -3,49 +3,311 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use super::errors::CodeError;
+use crate::constants::{
+	COMMAND_OUTPUT_DRAIN, DEFAULT_COMMAND_OUTPUT_LIMIT, DEFAULT_COMMAND_TIMEOUT,
+};
 use std::{
-	borrow::Cow,
 	ffi::OsStr,
-	process::{Output, Stdio},
+	io,
+	pin::Pin,
+	process::{ExitStatus, Stdio},
+	task::{Context, Poll},
+	time::Duration,
 };
-use tokio::process::Command;
-
-pub async fn capture_command_and_check_status(
-	command_str: impl AsRef<OsStr>,
-	args: &[impl AsRef<OsStr>],
-) -> Result<std::process::Output, CodeError> {
-	let output = capture_command(&command_str, args).await?;
-
-	check_output_status(output, || {
-		format!(
-			"{} {}",
-			command_str.as_ref().to_string_lossy(),
-			args.iter()
-				.map(|a| a.as_ref().to_string_lossy())
-				.collect::<Vec<Cow<'_, str>>>()
-				.join(" ")
-		)
+use tokio::{io::AsyncWrite, process::Command};
+
+/// Limits that `run_command` runs a command under.
+#[derive(Clone, Debug)]
+pub struct RunOptions {
+	/// Time after which the command and its children are killed.
+	pub timeout: Duration,
+	/// Bytes kept of each of stdout and stderr. Output past it is read and
+	/// discarded, so that the command doesn't block writing it.
+	pub output_limit: usize,
+}
+
+impl Default for RunOptions {
+	fn default() -> Self {
+		Self {
+			timeout: DEFAULT_COMMAND_TIMEOUT,
+			output_limit: DEFAULT_COMMAND_OUTPUT_LIMIT,
+		}
+	}
+}
+
+impl RunOptions {
+	pub fn with_timeout(timeout: Duration) -> Self {
+		Self {
+			timeout,
+			..Default::default()
+		}
+	}
+}
+
+/// Output of a command that exited, from `run_command`.
+#[derive(Debug)]
+pub struct RunOutput {
+	/// The command line, for errors.
+	pub command: String,
+	pub status: ExitStatus,
+	pub stdout: Vec<u8>,
+	pub stderr: Vec<u8>,
+	/// Whether either stream printed more than the output limit.
+	pub truncated: bool,
+}
+
+impl RunOutput {
+	/// Returns a `CommandFailed` error with both streams if the command
+	/// didn't exit successfully.
+	pub fn check(self) -> Result<Self, CodeError> {
+		if self.status.success() {
+			return Ok(self);
+		}
+
+		Err(CodeError::CommandFailed {
+			code: self.status.code().unwrap_or(-1),
+			output: describe_streams(&self.stdout, &self.stderr, self.truncated),
+			command: self.command,
+		})
+	}
+}
+
+/// Runs the command, capturing its output. It's killed, with all its
+/// children, if it runs for longer than the timeout or the returned future is
+/// dropped. Exiting unsuccessfully isn't an error; see `RunOutput::check`.
+pub async fn run_command(cmd: Command, options: &RunOptions) -> Result<RunOutput, CodeError> {
+	let command = command_line(&cmd);
+	let mut stdout = CappedBuffer::new(options.output_limit);
+	let mut stderr = CappedBuffer::new(options.output_limit);
+	let ended = run_command_to(cmd, options.timeout, &mut stdout, &mut stderr).await;
+	let truncated = stdout.truncated || stderr.truncated;
+
+	match ended {
+		Ok(CommandEnd::Exited(status)) => Ok(RunOutput {
+			command,
+			status,
+			truncated,
+			stdout: stdout.data,
+			stderr: stderr.data,
+		}),
+		Ok(CommandEnd::TimedOut) => Err(CodeError::CommandFailed {
+			command,
+			code: -1,
+			output: format!(
+				"timed out after {}s, {}",
+				options.timeout.as_secs_f32(),
+				describe_streams(&stdout.data, &stderr.data, truncated)
+			),
+		}),
+		Err(e) => Err(CodeError::CommandFailed {
+			command,
+			code: -1,
+			output: e.to_string(),
+		}),
+	}
+}
+
+/// How a command run with `run_command_to` ended.
+#[derive(Debug)]
+pub(crate) enum CommandEnd {
+	Exited(ExitStatus),
+	/// It ran for longer than the timeout, so it was killed.
+	TimedOut,
+}
+
+/// Why `run_command_to` failed.
+#[derive(Debug)]
+pub(crate) enum CommandRunError {
+	Spawn(io::Error),
+	/// Writing its output failed, so it was killed.
+	Output(io::Error),
+	Wait(io::Error),
+}
+
+impl std::fmt::Display for CommandRunError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			CommandRunError::Spawn(e) => write!(f, "could not start: {}", e),
+			CommandRunError::Output(e) => write!(f, "could not write its output: {}", e),
+			CommandRunError::Wait(e) => write!(f, "could not wait for it: {}", e),
+		}
+	}
+}
+
+/// Runs the command with stdin closed, copying its stdout and stderr to the
+/// writers until it exits. It's killed, with all its children, if it runs for
+/// longer than the timeout, a write fails, or the returned future is
+/// dropped. Children left running once it exited were meant to be, so only
+/// what they print for a moment after is copied, since they may keep the
+/// pipes open.
+pub(crate) async fn run_command_to(
+	mut cmd: Command,
+	timeout: Duration,
+	stdout: &mut (impl AsyncWrite + Unpin),
+	stderr: &mut (impl AsyncWrite + Unpin),
+) -> Result<CommandEnd, CommandRunError> {
+	cmd.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.kill_on_drop(true);
+	#[cfg(unix)]
+	cmd.process_group(0);
+
+	let mut child = cmd.spawn().map_err(CommandRunError::Spawn)?;
+	let mut group = ProcessGroupGuard(child.id());
+	let mut stdout_pipe = child.stdout.take().expect("expected piped stdout");
+	let mut stderr_pipe = child.stderr.take().expect("expected piped stderr");
+
+	let finished = tokio::time::timeout(timeout, async {
+		let copying = async {
+			tokio::try_join!(
+				tokio::io::copy(&mut stdout_pipe, stdout),
+				tokio::io::copy(&mut stderr_pipe, stderr)
+			)
+		};
+		tokio::pin!(copying);
+
+		let status = tokio::select! {
+			status = child.wait() => status.map_err(CommandRunError::Wait)?,
+			r = &mut copying => {
+				r.map_err(CommandRunError::Output)?;
+				return child.wait().await.map_err(CommandRunError::Wait);
+			}
+		};
+		let _ = tokio::time::timeout(COMMAND_OUTPUT_DRAIN, copying).await;
+		Ok(status)
 	})
+	.await;
+
+	match finished {
+		Ok(Ok(status)) => {
+			group.0 = None;
+			Ok(CommandEnd::Exited(status))
+		}
+		Ok(Err(e)) => {
+			group.kill();
+			let _ = child.kill().await;
+			Err(e)
+		}
+		Err(_) => {
+			group.kill();
+			let _ = child.kill().await;
+			Ok(CommandEnd::TimedOut)
+		}
+	}
+}
+
+/// Runs the program under the default limits, failing with its output if it
+/// doesn't exit successfully.
+pub async fn run_and_check<I, S>(
+	program: impl AsRef<OsStr>,
+	args: I,
+) -> Result<RunOutput, CodeError>
+where
+	I: IntoIterator<Item = S>,
+	S: AsRef<OsStr>,
+{
+	let mut cmd = new_tokio_command(program);
+	cmd.args(args);
+	run_command(cmd, &RunOptions::default()).await?.check()
+}
+
+fn command_line(cmd: &Command) -> String {
+	let cmd = cmd.as_std();
+	std::iter::once(cmd.get_program())
+		.chain(cmd.get_args())
+		.map(|a| a.to_string_lossy())
+		.collect::<Vec<_>>()
+		.join(" ")
 }
 
-pub fn check_output_status(
-	output: Output,
-	cmd_str: impl FnOnce() -> String,
-) -> Result<std::process::Output, CodeError> {
-	if !output.status.success() {
-		return Err(CodeError::CommandFailed {
-			command: cmd_str(),
-			code: output.status.code().unwrap_or(-1),
-			output: String::from_utf8_lossy(if output.stderr.is_empty() {
-				&output.stdout
-			} else {
-				&output.stderr
-			})
-			.into(),
-		});
+/// Describes both streams of a failed command for its error.
+fn describe_streams(stdout: &[u8], stderr: &[u8], truncated: bool) -> String {
+	let parts: Vec<String> = [("stderr", stderr), ("stdout", stdout)]
+		.into_iter()
+		.filter_map(|(name, data)| {
+			let text = String::from_utf8_lossy(data);
+			let text = text.trim();
+			(!text.is_empty()).then(|| format!("{}: {}", name, text))
+		})
+		.collect();
+
+	match (parts.is_empty(), truncated) {
+		(true, _) => "no output".to_string(),
+		(false, false) => parts.join("; "),
+		(false, true) => format!("{} (output truncated)", parts.join("; ")),
+	}
+}
+
+/// Keeps up to a limit of the bytes written to it, discarding the rest.
+struct CappedBuffer {
+	data: Vec<u8>,
+	limit: usize,
+	truncated: bool,
+}
+
+impl CappedBuffer {
+	fn new(limit: usize) -> Self {
+		Self {
+			data: vec![],
+			limit,
+			truncated: false,
+		}
+	}
+}
+
+impl AsyncWrite for CappedBuffer {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		_cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		let keep = buf.len().min(this.limit - this.data.len());
+		this.data.extend_from_slice(&buf[..keep]);
+		this.truncated |= keep < buf.len();
+		Poll::Ready(Ok(buf.len()))
 	}
 
-	Ok(output)
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+}
+
+/// Kills the process group of a command spawned as its leader when dropped,
+/// unless the process ID is cleared. On Windows, the process tree is killed.
+struct ProcessGroupGuard(Option<u32>);
+
+impl ProcessGroupGuard {
+	/// Kills the group now, if it wasn't already.
+	fn kill(&mut self) {
+		let pid = match self.0.take() {
+			Some(p) => p,
+			None => return,
+		};
+
+		#[cfg(unix)]
+		unsafe {
+			libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+		}
+
+		#[cfg(windows)]
+		let _ = new_std_command("taskkill")
+			.args(["/t", "/f", "/pid", &pid.to_string()])
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn();
+	}
+}
+
+impl Drop for ProcessGroupGuard {
+	fn drop(&mut self) {
+		self.kill();
+	}
 }
 
 pub async fn capture_command<A, I, S>(
@@ -166,3 +428,108 @@ pub async fn kill_tree(process_id: u32) -> Result<(), CodeError> {
 	prgrep_cmd.kill().await.ok();
 	Ok(())
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use std::time::Instant;
+
+	fn sh(script: &str) -> Command {
+		let mut cmd = new_tokio_command("sh");
+		cmd.args(["-c", script]);
+		cmd
+	}
+
+	#[tokio::test]
+	async fn test_captures_both_streams_on_failure() {
+		let err = run_command(sh("echo out; echo err >&2; exit 3"), &RunOptions::default())
+			.await
+			.unwrap()
+			.check()
+			.unwrap_err();
+
+		match err {
+			CodeError::CommandFailed { code, output, .. } => {
+				assert_eq!(code, 3);
+				assert_eq!(output, "stderr: err; stdout: out");
+			}
+			e => panic!("unexpected error {:?}", e),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_kills_group_on_timeout() {
+		let dir = tempfile::tempdir().unwrap();
+		let marker = dir.path().join("marker");
+		let script = format!("(sleep 1; touch {}) & sleep 30", marker.display());
+
+		let start = Instant::now();
+		let err = run_command(
+			sh(&script),
+			&RunOptions::with_timeout(Duration::from_millis(200)),
+		)
+		.await
+		.unwrap_err();
+		assert!(start.elapsed() < Duration::from_secs(5));
+		match err {
+			CodeError::CommandFailed { code, output, .. } => {
+				assert_eq!(code, -1);
+				assert!(output.starts_with("timed out after"), "{}", output);
+			}
+			e => panic!("unexpected error {:?}", e),
+		}
+
+		// the background child was in the group, so it never gets to write
+		tokio::time::sleep(Duration::from_millis(1500)).await;
+		assert!(!marker.exists());
+	}
+
+	#[tokio::test]
+	async fn test_doesnt_wait_for_children_left_running() {
+		let start = Instant::now();
+		let output = run_command(
+			sh("echo started; sleep 30 &"),
+			&RunOptions::with_timeout(Duration::from_secs(10)),
+		)
+		.await
+		.unwrap()
+		.check()
+		.unwrap();
+
+		// the sleep keeps stdout open, but the command exited
+		assert!(start.elapsed() < Duration::from_secs(5));
+		assert_eq!(output.stdout, b"started\n");
+	}
+
+	#[tokio::test]
+	async fn test_truncates_large_output() {
+		let output = run_command(
+			sh("head -c 100000 /dev/zero"),
+			&RunOptions {
+				output_limit: 1000,
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+
+		assert!(output.status.success());
+		assert!(output.truncated);
+		assert_eq!(output.stdout.len(), 1000);
+	}
+
+	#[tokio::test]
+	async fn test_keeps_non_utf8_output() {
+		let output = run_command(sh("printf '\\377\\376'; exit 1"), &RunOptions::default())
+			.await
+			.unwrap();
+		assert_eq!(output.stdout, vec![0xff, 0xfe]);
+
+		match output.check().unwrap_err() {
+			CodeError::CommandFailed { output, .. } => {
+				assert_eq!(output, "stdout: \u{fffd}\u{fffd}");
+			}
+			e => panic!("unexpected error {:?}", e),
+		}
+	}
+}
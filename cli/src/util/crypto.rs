@@ -0,0 +1,246 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use aes_gcm::{
+	aead::{Aead, KeyInit, Payload},
+	Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose as b64, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ENVELOPE_VERSION: u8 = 2;
+
+/// Size of the AES-GCM nonce, which is random for each envelope.
+const NONCE_LEN: usize = 12;
+
+/// Number of PBKDF2 rounds used when sealing new envelopes.
+pub const DEFAULT_KDF_ITERATIONS: u32 = 100_000;
+
+/// Most PBKDF2 rounds an envelope may ask for. The count is read from disk,
+/// so without a limit a damaged file could stall opening it for minutes.
+const MAX_KDF_ITERATIONS: u32 = DEFAULT_KDF_ITERATIONS * 10;
+
+/// Data encrypted and authenticated with AES-256-GCM, using a key derived
+/// from a secret through PBKDF2-HMAC-SHA256.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SealedEnvelope {
+	#[serde(rename = "v")]
+	version: u8,
+	iterations: u32,
+	salt: String,
+	nonce: String,
+	/// Derived from the key, to tell a wrong secret apart from tampered data.
+	check: String,
+	/// The ciphertext, followed by its authentication tag.
+	data: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OpenError {
+	/// The envelope was sealed with a different secret.
+	WrongKey,
+	/// The envelope is malformed, or its contents were modified.
+	Corrupted(&'static str),
+}
+
+struct Keys {
+	root: [u8; 32],
+	encrypt: [u8; 32],
+}
+
+impl Keys {
+	fn derive(secret: &[u8], salt: &[u8], iterations: u32) -> Self {
+		let mut root = [0u8; 32];
+		pbkdf2::pbkdf2_hmac::<Sha256>(secret, salt, iterations, &mut root);
+		Keys {
+			encrypt: hmac_sha256(&root, b"encrypt"),
+			root,
+		}
+	}
+
+	fn check(&self) -> HmacSha256 {
+		<HmacSha256 as Mac>::new_from_slice(&self.root)
+			.expect("hmac takes keys of any length")
+			.chain_update(b"check")
+	}
+
+	fn cipher(&self) -> Aes256Gcm {
+		Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.encrypt))
+	}
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+	<HmacSha256 as Mac>::new_from_slice(key)
+		.expect("hmac takes keys of any length")
+		.chain_update(data)
+		.finalize()
+		.into_bytes()
+		.into()
+}
+
+/// Data authenticated along with the ciphertext, so the parameters it was
+/// sealed with can't be swapped out.
+fn associated_data(iterations: u32, salt: &[u8]) -> Vec<u8> {
+	let mut aad = Vec::with_capacity(5 + salt.len());
+	aad.push(ENVELOPE_VERSION);
+	aad.extend_from_slice(&iterations.to_be_bytes());
+	aad.extend_from_slice(salt);
+	aad
+}
+
+/// Compares the values in time that only depends on their length, so that a
+/// secret can't be guessed a byte at a time from how long checks take.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+
+	a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Encrypts the plaintext with a key derived from the secret.
+pub fn seal(secret: &[u8], plaintext: &[u8], iterations: u32) -> SealedEnvelope {
+	let mut salt = [0u8; 16];
+	let mut nonce = [0u8; NONCE_LEN];
+	rand::thread_rng().fill_bytes(&mut salt);
+	rand::thread_rng().fill_bytes(&mut nonce);
+
+	let keys = Keys::derive(secret, &salt, iterations);
+	let data = keys
+		.cipher()
+		.encrypt(
+			Nonce::from_slice(&nonce),
+			Payload {
+				msg: plaintext,
+				aad: &associated_data(iterations, &salt),
+			},
+		)
+		.expect("expected to encrypt");
+
+	SealedEnvelope {
+		version: ENVELOPE_VERSION,
+		iterations,
+		salt: b64::STANDARD.encode(salt),
+		nonce: b64::STANDARD.encode(nonce),
+		check: b64::STANDARD.encode(keys.check().finalize().into_bytes()),
+		data: b64::STANDARD.encode(data),
+	}
+}
+
+/// Decrypts the envelope, verifying it was sealed with the same secret and
+/// has not been modified.
+pub fn open(secret: &[u8], envelope: &SealedEnvelope) -> Result<Vec<u8>, OpenError> {
+	if envelope.version != ENVELOPE_VERSION {
+		return Err(OpenError::Corrupted("unknown version"));
+	}
+	if envelope.iterations == 0 || envelope.iterations > MAX_KDF_ITERATIONS {
+		return Err(OpenError::Corrupted("invalid key derivation rounds"));
+	}
+
+	let decode = |v: &str, what| {
+		b64::STANDARD
+			.decode(v)
+			.map_err(|_| OpenError::Corrupted(what))
+	};
+	let salt = decode(&envelope.salt, "invalid salt")?;
+	let nonce = decode(&envelope.nonce, "invalid nonce")?;
+	let check = decode(&envelope.check, "invalid key check")?;
+	let data = decode(&envelope.data, "invalid data")?;
+	if nonce.len() != NONCE_LEN {
+		return Err(OpenError::Corrupted("invalid nonce"));
+	}
+
+	let keys = Keys::derive(secret, &salt, envelope.iterations);
+	keys.check()
+		.verify_slice(&check)
+		.map_err(|_| OpenError::WrongKey)?;
+
+	keys.cipher()
+		.decrypt(
+			Nonce::from_slice(&nonce),
+			Payload {
+				msg: &data,
+				aad: &associated_data(envelope.iterations, &salt),
+			},
+		)
+		.map_err(|_| OpenError::Corrupted("authentication failed"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_constant_time_eq() {
+		assert!(constant_time_eq(b"token", b"token"));
+		assert!(constant_time_eq(b"", b""));
+		assert!(!constant_time_eq(b"token", b"tokem"));
+		assert!(!constant_time_eq(b"token", b"token2"));
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let plaintext = "x".repeat(100);
+		let sealed = seal(b"secret", plaintext.as_bytes(), 10);
+		assert_ne!(sealed.data, b64::STANDARD.encode(&plaintext));
+		assert_eq!(open(b"secret", &sealed).unwrap(), plaintext.as_bytes());
+
+		let empty = seal(b"secret", b"", 10);
+		assert_eq!(open(b"secret", &empty).unwrap(), b"");
+	}
+
+	#[test]
+	fn test_wrong_key() {
+		let sealed = seal(b"secret", b"hello", 10);
+		assert_eq!(open(b"other", &sealed), Err(OpenError::WrongKey));
+	}
+
+	#[test]
+	fn test_tamper_detection() {
+		let sealed = seal(b"secret", b"hello world", 10);
+
+		let mut data = b64::STANDARD.decode(&sealed.data).unwrap();
+		data[0] ^= 1;
+		let mut tampered = sealed.clone();
+		tampered.data = b64::STANDARD.encode(data);
+		assert_eq!(
+			open(b"secret", &tampered),
+			Err(OpenError::Corrupted("authentication failed"))
+		);
+
+		let mut tampered = sealed.clone();
+		tampered.nonce = b64::STANDARD.encode([0u8; NONCE_LEN]);
+		assert_eq!(
+			open(b"secret", &tampered),
+			Err(OpenError::Corrupted("authentication failed"))
+		);
+
+		let mut tampered = sealed;
+		tampered.data = "not base64!".to_string();
+		assert_eq!(
+			open(b"secret", &tampered),
+			Err(OpenError::Corrupted("invalid data"))
+		);
+	}
+
+	#[test]
+	fn test_iterations_out_of_range() {
+		let sealed = seal(b"secret", b"hello", 10);
+		// rejected before deriving a key, so a huge count doesn't stall
+		for iterations in [0, MAX_KDF_ITERATIONS + 1, u32::MAX] {
+			let mut tampered = sealed.clone();
+			tampered.iterations = iterations;
+			assert_eq!(
+				open(b"secret", &tampered),
+				Err(OpenError::Corrupted("invalid key derivation rounds"))
+			);
+		}
+	}
+}
@@ -3,7 +3,9 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use crate::{
+	auth::AuthProvider,
 	constants::{APPLICATION_NAME, CONTROL_PORT, DOCUMENTATION_URL, QUALITYLESS_PRODUCT_NAME},
+	policy::PolicyFeature,
 	rpc::ResponseError,
 };
 use std::fmt::Display;
@@ -271,7 +273,7 @@ impl std::fmt::Display for InvalidRequestedVersion {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 		write!(
             f,
-            "The reqested version is invalid, expected one of 'stable', 'insiders', version number (x.y.z), or absolute path.",
+            "The reqested version is invalid, expected one of 'stable', 'insiders', version number (x.y.z), a commit or <quality>/<commit>, or absolute path.",
         )
 	}
 }
@@ -452,8 +454,16 @@ pub enum CodeError {
 	SingletonLockfileReadFailed(rmp_serde::decode::Error),
 	#[error("the process holding the singleton lock file (pid={0}) exited")]
 	SingletonLockedProcessExited(u32),
+	#[error("could not terminate the process holding the singleton lock file (pid={0})")]
+	SingletonKillFailed(u32),
+	#[error("the singleton lock file is owned by another user (uid={0}), not stopping the process it names")]
+	SingletonLockfileNotOwned(u32),
 	#[error("no tunnel process is currently running")]
 	NoRunningTunnel,
+	#[error("no server is installed for commit {0}")]
+	ServerNotInstalled(String),
+	#[error("the server for commit {commit} has {connections} active connection(s), pass --force to uninstall it anyway")]
+	ServerInUse { commit: String, connections: usize },
 	#[error("rpc call failed: {0:?}")]
 	TunnelRpcCallFailed(ResponseError),
 	#[cfg(windows)]
@@ -462,6 +472,16 @@ pub enum CodeError {
 	#[cfg(windows)]
 	#[error("could not get windows app lock: {0:?}")]
 	AppLockFailed(std::io::Error),
+	#[error("could not lock {0}: {1:?}")]
+	FileLockFailed(String, std::io::Error),
+	#[error(
+		"timed out after {seconds}s waiting for the lock on {path}, which is held by {holder}"
+	)]
+	FileLockTimedOut {
+		path: String,
+		seconds: u64,
+		holder: String,
+	},
 	#[error("failed to run command \"{command}\" (code {code}): {output}")]
 	CommandFailed {
 		command: String,
@@ -469,10 +489,43 @@ pub enum CodeError {
 		output: String,
 	},
 
+	#[error("Not enough disk space in {path}, which needs {required} for the server but has {available}. Free up space, or pass --ignore-preflight to try anyway.")]
+	InsufficientDiskSpace {
+		path: String,
+		required: String,
+		available: String,
+	},
 	#[error("platform not currently supported: {0}")]
 	UnsupportedPlatform(String),
-	#[error("This machine does not meet {name}'s prerequisites, expected either...\n{bullets}")]
-	PrerequisitesFailed { name: &'static str, bullets: String },
+	#[error("Managing a system-level service requires root. Run this command again with sudo.")]
+	SystemServiceRequiresRoot,
+	#[error("Could not find the home directory of the user '{0}'")]
+	ServiceUserNotFound(String),
+	#[error("No credentials were found for the user '{user}' in {data_dir}. The system service cannot log in interactively, so log in as that user first by running:\n\n  sudo -u {user} VSCODE_CLI_USE_FILE_KEYCHAIN=1 {} tunnel user login --cli-data-dir {data_dir}\n", APPLICATION_NAME)]
+	ServiceUserNotLoggedIn { user: String, data_dir: String },
+	#[error(
+		"The tunnel service is not installed. Run `{} tunnel service install` to install it.",
+		APPLICATION_NAME
+	)]
+	ServiceNotInstalled,
+	#[error("The tunnel service was restarted, but did not report it was ready within {0} seconds. Run `{} tunnel service log` to see what it's doing.", APPLICATION_NAME)]
+	ServiceRestartTimeout(u64),
+	#[error("The running tunnel did not exit within {0} seconds of being asked to. Run `{} tunnel kill` to stop it, then try again.", APPLICATION_NAME)]
+	ServiceStopTimeout(u64),
+	#[error("The installed tunnel service is invalid ({0}). Run `{} tunnel service install` to reinstall it.", APPLICATION_NAME)]
+	InvalidServiceRegistration(String),
+	#[error("The server license terms were not accepted when the tunnel service was installed. Run `{} tunnel service install --accept-server-license-terms` to reinstall it.", APPLICATION_NAME)]
+	ServiceMissingLegalConsent,
+	#[error("The installed tunnel service is stale: {0}. Run `{} tunnel service install --force` to replace it.", APPLICATION_NAME)]
+	StaleServiceRegistration(String),
+	#[error("Environment variables, such as those given with --env or for a --token-passphrase, are not supported for the tunnel service on this platform")]
+	ServiceEnvUnsupported,
+	#[error("This machine does not meet {name}'s prerequisites:\n{bullets}\nRun with --skip-requirements-check to try anyway, though this is unsupported.")]
+	PrerequisitesFailed {
+		name: &'static str,
+		bullets: String,
+		requirements: Vec<super::prereqs::Requirement>,
+	},
 	#[error("failed to spawn process: {0:?}")]
 	ProcessSpawnFailed(std::io::Error),
 	#[error("failed to handshake spawned process: {0:?}")]
@@ -483,24 +536,134 @@ pub enum CodeError {
 	PortForwardingNotAvailable,
 	#[error("'auth' call required")]
 	ServerAuthRequired,
+	#[error("remote exec is not enabled on this host, start the tunnel with --enable-remote-exec to allow it")]
+	RemoteExecDisabled,
+	#[error("remote exec requires the client to answer the auth challenge")]
+	RemoteExecRequiresChallenge,
+	#[error("remote exec requires the token given to the host with --remote-exec-token")]
+	RemoteExecBadToken,
+	#[error("'{0}' is not in the remote exec allowlist of this host")]
+	RemoteExecNotAllowed(String),
+	#[error("could not stream the command's output: {0}")]
+	RemoteExecStreamFailed(std::io::Error),
 	#[error("challenge not yet issued")]
 	AuthChallengeNotIssued,
 	#[error("challenge token is invalid")]
 	AuthChallengeBadToken,
+	#[error("too many failed auth attempts, try again in {0}ms")]
+	AuthChallengeThrottled(u64),
+	#[error("locked out after too many failed auth attempts, try again in {0}s")]
+	AuthChallengeLockedOut(u64),
 	#[error("unauthorized client refused")]
 	AuthMismatch,
-	#[error("keyring communication timed out after 5s")]
-	KeyringTimeout,
+	#[error("keyring communication timed out after {0}s")]
+	KeyringTimeout(u64),
+	#[error("systemd did not answer within {0}s")]
+	SystemdTimeout(u64),
+	#[error("No auth profile named '{0}' has been logged into. Run `{} tunnel user login --profile {0}` first.", APPLICATION_NAME)]
+	UnknownAuthProfile(String),
+	#[error(
+		"GitHub rejected the access token with status {0}, it may have expired or been revoked"
+	)]
+	GithubTokenRejected(u16),
+	#[error("The GitHub access token is missing scopes required to host a tunnel: {0}")]
+	GithubTokenMissingScopes(String),
+	#[error("The stored credentials are missing the scopes {0}. Run `{} tunnel user login --upgrade-scopes` to grant them.", APPLICATION_NAME)]
+	MissingAuthScopes(String),
+	#[error("Not logged in, run `{} tunnel user login` first", APPLICATION_NAME)]
+	UpgradeScopesNotLoggedIn,
+	#[error("Personal access tokens can't be upgraded. Create a token with the scopes you need and log in with it instead.")]
+	UpgradeScopesPersonalAccessToken,
+	#[error(
+		"Tokens read from stdin must be GitHub personal access tokens, use `--provider github`"
+	)]
+	AccessTokenStdinNeedsGithub,
+	#[error("No access token was given on stdin")]
+	EmptyAccessToken,
+	#[error("The device code expired before the login was completed, please start a new login")]
+	DeviceCodeExpired,
+	#[error("No auth provider was given and there's no terminal to ask for one. Run `{} tunnel user set-default-provider <github|microsoft>` or pass `--provider`.", APPLICATION_NAME)]
+	NoDefaultAuthProvider,
+	#[error("The login was denied")]
+	LoginDenied,
+	#[error("Logged out on this machine, but the token could not be revoked: {0}\nIt may still be valid until it expires. To revoke it now, remove the app's access from your account's security settings, such as https://github.com/settings/applications for GitHub.")]
+	TokenRevocationFailed(String),
+	#[error("The Microsoft {0} cloud rejected the account: {1}\nIf the account is in a different cloud, log in again with `--auth-cloud`")]
+	AuthCloudRejected(String, String),
+	#[error("This machine's tunnel was registered with an account in the {0} cloud, but the current credentials are from the {1} cloud. Log in with `--auth-cloud {0}`, or run `{} tunnel unregister` first.", APPLICATION_NAME)]
+	TunnelCloudMismatch(String, String),
+	#[error("The login was cancelled")]
+	LoginCancelled,
+	#[error(
+		"No pending login was found for handle {0}, it may have expired or already been completed"
+	)]
+	UnknownLoginHandle(String),
+	#[error("Could not decrypt the token storage, check the passphrase given in --token-passphrase or VSCODE_CLI_TOKEN_PASSPHRASE")]
+	TokenStoreWrongPassphrase,
+	#[error("The encrypted token storage is corrupted or was modified ({0}), run `{} tunnel user login` to replace it", APPLICATION_NAME)]
+	TokenStoreCorrupted(String),
 	#[error("no host is connected to the tunnel relay")]
 	NoTunnelEndpoint,
 	#[error("could not parse `host`: {0}")]
 	InvalidHostAddress(std::net::AddrParseError),
 	#[error("could not start server on the given host/port: {0}")]
 	CouldNotListenOnInterface(hyper::Error),
+	#[error("refusing to open {0} in the browser, only http(s) URLs can be opened")]
+	InvalidBrowserUrl(String),
+	#[error("could not open the browser: {0}")]
+	CouldNotOpenBrowser(String),
+	#[error("could not open the access log at {0}: {1}")]
+	CouldNotOpenAccessLog(String, std::io::Error),
+	#[error("the {0} log sink isn't available on this platform")]
+	LogSinkUnsupported(String),
+	#[error("the file log sink needs a path, set with --log-to-file")]
+	LogFileSinkWithoutPath,
+	#[error("could not open the {0} log sink: {1}")]
+	CouldNotOpenLogSink(String, std::io::Error),
+	#[error("{error}\n\nRecent errors:\n{logs}")]
+	TunnelFailedWithLogs {
+		#[source]
+		error: Box<AnyError>,
+		logs: String,
+	},
+	#[error("could not bind to {0}: {1}")]
+	CouldNotBindAddress(std::net::SocketAddr, std::io::Error),
+	#[error("could not load the TLS certificate from {path}: {message}")]
+	InvalidTlsCertificate { path: String, message: String },
+	#[error("downloaded {actual} of the {expected} bytes of {url}")]
+	IncompleteDownload {
+		url: String,
+		expected: u64,
+		actual: u64,
+	},
+	#[error("could not read the config file {0}: {1}")]
+	CouldNotReadConfig(String, String),
+	#[error("could not read the policy {0}: {1}")]
+	CouldNotReadPolicy(String, String),
+	#[error("{0} is disabled by your organization (policy: {1})")]
+	DisabledByPolicy(PolicyFeature, String),
+	#[error("Signing in with a {0} is disabled by your organization (policy: {1})")]
+	AuthProviderDisabledByPolicy(AuthProvider, String),
+	#[error("could not write the config file {0}: {1}")]
+	CouldNotWriteConfig(String, std::io::Error),
+	#[error("`{0}` isn't a flag that can be set in the config file")]
+	UnknownConfigKey(String),
+	#[error("could not set `{key}`: {message}")]
+	InvalidConfigValue { key: String, message: String },
 	#[error(
 		"Run this command again with --accept-server-license-terms to indicate your agreement."
 	)]
 	NeedsInteractiveLegalConsent,
+	#[error("Can't ask \"{prompt}\" since input is turned off or stdin isn't a terminal. Pass {answered_by} to answer it.")]
+	NoInputForPrompt { prompt: String, answered_by: String },
+	#[error("No answer was given to \"{prompt}\" in time. Pass {answered_by} to answer it.")]
+	PromptTimedOut { prompt: String, answered_by: String },
+	#[error("Can't ask for \"{prompt}\" since input is turned off or stdin isn't a terminal. Set the {env_var} environment variable to give it.")]
+	NoInputForSecret { prompt: String, env_var: String },
+	#[error("The entries didn't match")]
+	SecretsDidNotMatch,
+	#[error("could not find the product.json of the install with {0}")]
+	ProductJsonNotFound(String),
 	#[error("Sorry, you cannot use this CLI without accepting the terms.")]
 	DeniedLegalConset,
 	#[error("The server is not yet downloaded, try again shortly.")]
@@ -509,17 +672,41 @@ pub enum CodeError {
 	ServerDownloadError(String),
 	#[error("Updates are are not available: {0}")]
 	UpdatesNotConfigured(&'static str),
+	#[error("No {quality} build exists with commit {commit}")]
+	UnknownCommit { quality: String, commit: String },
+	#[error(
+		"The {quality} build with commit {commit} exists, but is no longer available for download"
+	)]
+	CommitNotDownloadable { quality: String, commit: String },
+	#[error("This CLI has no quality to download builds of, give the version as <quality>/<commit> instead, such as stable/<commit>")]
+	NoQualityToDownload,
+	#[error("Desktop builds are not published for {0}, only servers are")]
+	NoDesktopBuildForPlatform(String),
 	// todo: can be specialized when update service is moved to CodeErrors
 	#[error("Could not check for update: {0}")]
 	UpdateCheckFailed(String),
 	#[error("Could not write connection token file: {0}")]
 	CouldNotCreateConnectionTokenFile(std::io::Error),
+	#[error("Could not read connection token file: {0}")]
+	CouldNotReadConnectionTokenFile(std::io::Error),
+	#[error("Connection tokens must only contain the characters a-z, A-Z, 0-9, - and _")]
+	InvalidConnectionToken,
 	#[error("A tunnel with the name {0} exists and is in-use. Please pick a different name or stop the existing tunnel.")]
 	TunnelActiveAndInUse(String),
 	#[error("Timed out looking for port/socket")]
 	ServerOriginTimeout,
 	#[error("Server exited without writing port/socket: {0}")]
 	ServerUnexpectedExit(String),
+	#[error("the tunnel failed {0} times in a row, last error: {1}")]
+	TunnelRestartLimitReached(u32, String),
+	#[error("the tunnel exited with code {0}")]
+	TunnelExitedWithCode(i32),
+	#[error("the request was cancelled")]
+	RequestCancelled,
+	#[error("client and host versions are incompatible, {0}")]
+	IncompatibleProtocolVersion(String),
+	#[error("rpc message of at least {size} bytes exceeds the limit of {limit} bytes")]
+	RpcFrameTooLarge { size: u64, limit: usize },
 }
 
 makeAnyError!(
@@ -3,8 +3,23 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use crate::util::errors::CodeError;
-use std::{fs::File, io};
+use crate::util::{errors::CodeError, machine::process_exists};
+use serde::{Deserialize, Serialize};
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+	sync::{mpsc, Arc, Mutex},
+	thread,
+	time::{Duration, Instant},
+};
+
+/// How long to wait between attempts to take a lock that's held.
+const ACQUIRE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the holder of a heartbeat lock marks that it's still alive.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long after its last heartbeat a heartbeat lock is taken over.
+const HEARTBEAT_STALE_AFTER: Duration = Duration::from_secs(30);
 
 pub struct FileLock {
 	file: File,
@@ -76,18 +91,33 @@ impl FileLock {
 	pub fn acquire(file: File) -> Result<Lock, CodeError> {
 		use std::os::unix::io::AsRawFd;
 
+		// older versions of the CLI only take flock, so it's always taken so
+		// that they're still excluded
 		let fd = file.as_raw_fd();
 		let res = unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) };
-		if res == 0 {
-			return Ok(Lock::Acquired(Self { file }));
+		if res != 0 {
+			let err = io::Error::last_os_error();
+			if err.kind() == io::ErrorKind::WouldBlock {
+				return Ok(Lock::AlreadyLocked(file));
+			}
+
+			return Err(CodeError::SingletonLockfileOpenFailed(err));
 		}
 
-		let err = io::Error::last_os_error();
-		if err.kind() == io::ErrorKind::WouldBlock {
-			return Ok(Lock::AlreadyLocked(file));
+		#[cfg(target_os = "linux")]
+		match set_ofd_lock(fd, libc::F_WRLCK) {
+			Ok(true) => {}
+			Ok(false) => {
+				unsafe { libc::flock(fd, libc::LOCK_UN) };
+				return Ok(Lock::AlreadyLocked(file));
+			}
+			Err(e) => {
+				unsafe { libc::flock(fd, libc::LOCK_UN) };
+				return Err(CodeError::SingletonLockfileOpenFailed(e));
+			}
 		}
 
-		Err(CodeError::SingletonLockfileOpenFailed(err))
+		Ok(Lock::Acquired(Self { file }))
 	}
 
 	pub fn file(&self) -> &File {
@@ -120,6 +150,772 @@ impl Drop for FileLock {
 	fn drop(&mut self) {
 		use std::os::unix::io::AsRawFd;
 
-		unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+		let fd = self.file.as_raw_fd();
+		#[cfg(target_os = "linux")]
+		let _ = set_ofd_lock(fd, libc::F_UNLCK);
+		unsafe { libc::flock(fd, libc::LOCK_UN) };
+	}
+}
+
+/// Takes or releases an open file description lock on the whole file. Like
+/// flock, and unlike classic POSIX locks, it belongs to the open file rather
+/// than the process, so two handles in one process exclude each other.
+/// Returns false if another handle holds the lock. Kernels older than 3.15
+/// don't have them, in which case only flock is relied on.
+#[cfg(target_os = "linux")]
+fn set_ofd_lock(fd: std::os::unix::io::RawFd, kind: libc::c_int) -> io::Result<bool> {
+	// safety: a zeroed flock is valid, and with l_start and l_len of 0 it
+	// covers the whole file
+	let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+	fl.l_type = kind as libc::c_short;
+	fl.l_whence = libc::SEEK_SET as libc::c_short;
+	if unsafe { libc::fcntl(fd, libc::F_OFD_SETLK, &fl) } == 0 {
+		return Ok(true);
+	}
+
+	let err = io::Error::last_os_error();
+	match err.raw_os_error() {
+		Some(libc::EAGAIN | libc::EACCES) => Ok(false),
+		Some(libc::EINVAL) => Ok(true),
+		_ => Err(err),
+	}
+}
+
+/// Exclusive lock on a path, from `acquire` or `try_acquire`. It's released
+/// when dropped.
+pub struct FileLockGuard {
+	path: PathBuf,
+	held: HeldLock,
+	#[cfg_attr(not(unix), allow(dead_code))]
+	remove: bool,
+}
+
+impl FileLockGuard {
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Replaces what the holder wrote in the lock file for others to read
+	/// with `read_data`, such as how to reach it.
+	pub fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+		match &mut self.held {
+			HeldLock::Os(lock) => write_contents(lock.file_mut(), data),
+			HeldLock::Heartbeat(lock) => lock.write_data(data),
+		}
+	}
+
+	/// Removes the lock file when the lock is released, so that it isn't left
+	/// behind once what it guarded is gone. On Windows, where a file that's
+	/// open elsewhere can't be replaced until it's closed, it's kept.
+	pub fn remove_on_release(mut self) -> Self {
+		self.remove = true;
+		self
+	}
+}
+
+impl Drop for FileLockGuard {
+	fn drop(&mut self) {
+		// removed while still locked, so that a process that was waiting on
+		// the file sees it's been replaced once it gets the lock. A heartbeat
+		// lock's file is always removed.
+		#[cfg(unix)]
+		if self.remove && matches!(self.held, HeldLock::Os(_)) {
+			let _ = fs::remove_file(&self.path);
+		}
+	}
+}
+
+enum HeldLock {
+	Os(FileLock),
+	Heartbeat(HeartbeatLock),
+}
+
+/// Ways of locking a path.
+#[derive(Clone, Copy, Debug)]
+enum LockScheme {
+	/// A lock from the OS on the file.
+	Os,
+	/// Owning the file, which is created exclusively and touched to show its
+	/// owner is alive. Used on network filesystems, where OS locks may only
+	/// exclude other processes on the same client, as with flock over NFS.
+	Heartbeat {
+		interval: Duration,
+		stale_after: Duration,
+	},
+}
+
+impl LockScheme {
+	fn for_path(path: &Path) -> Self {
+		if is_network_filesystem(path.parent().unwrap_or(path)) {
+			LockScheme::Heartbeat {
+				interval: HEARTBEAT_INTERVAL,
+				stale_after: HEARTBEAT_STALE_AFTER,
+			}
+		} else {
+			LockScheme::Os
+		}
+	}
+}
+
+/// Takes an exclusive lock on the path, creating it if needed, waiting up to
+/// the timeout for whoever holds it to release it.
+pub async fn acquire(path: &Path, timeout: Duration) -> Result<FileLockGuard, CodeError> {
+	acquire_with(path, timeout, LockScheme::for_path(path)).await
+}
+
+/// Takes an exclusive lock on the path if it's free, returning None if it's
+/// held elsewhere.
+pub fn try_acquire(path: &Path) -> Result<Option<FileLockGuard>, CodeError> {
+	try_acquire_with(path, LockScheme::for_path(path))
+}
+
+/// Gets whether the lock on the path is held, without taking it or creating
+/// the file.
+pub fn is_held(path: &Path) -> Result<bool, CodeError> {
+	is_held_with(path, LockScheme::for_path(path))
+}
+
+/// Reads what the lock's holder wrote with `FileLockGuard::write_data`.
+pub fn read_data(file: &mut File) -> io::Result<Vec<u8>> {
+	file.seek(SeekFrom::Start(PREFIX_LOCKED_BYTES as u64))?;
+	let mut contents = vec![];
+	file.read_to_end(&mut contents)?;
+	Ok(match parse_holder(&contents) {
+		Some((_, end)) => contents.split_off(end),
+		None => contents,
+	})
+}
+
+async fn acquire_with(
+	path: &Path,
+	timeout: Duration,
+	scheme: LockScheme,
+) -> Result<FileLockGuard, CodeError> {
+	let deadline = Instant::now() + timeout;
+	loop {
+		if let Some(guard) = try_acquire_with(path, scheme)? {
+			return Ok(guard);
+		}
+
+		if Instant::now() >= deadline {
+			return Err(CodeError::FileLockTimedOut {
+				path: path.display().to_string(),
+				seconds: timeout.as_secs(),
+				holder: match read_holder(path) {
+					Some(h) => format!("process {} on {}", h.pid, h.hostname),
+					None => "another process".to_string(),
+				},
+			});
+		}
+
+		tokio::time::sleep(ACQUIRE_POLL_INTERVAL).await;
+	}
+}
+
+fn try_acquire_with(path: &Path, scheme: LockScheme) -> Result<Option<FileLockGuard>, CodeError> {
+	let held = match scheme {
+		LockScheme::Os => loop {
+			let file = OpenOptions::new()
+				.read(true)
+				.write(true)
+				.create(true)
+				.truncate(false)
+				.open(path)
+				.map_err(|e| lock_failed(path, e))?;
+
+			match FileLock::acquire(file) {
+				Ok(Lock::Acquired(mut lock)) => {
+					// its last holder removed it, so the file at the path now
+					// is locked instead
+					if !is_at_path(lock.file(), path).map_err(|e| lock_failed(path, e))? {
+						continue;
+					}
+					// only informational, for errors of processes waiting on it
+					let _ = write_holder(lock.file_mut(), &LockHolder::current());
+					break HeldLock::Os(lock);
+				}
+				Ok(Lock::AlreadyLocked(_)) => return Ok(None),
+				Err(CodeError::SingletonLockfileOpenFailed(e)) => return Err(lock_failed(path, e)),
+				Err(e) => return Err(e),
+			}
+		},
+		LockScheme::Heartbeat {
+			interval,
+			stale_after,
+		} => match HeartbeatLock::try_acquire(path, interval, stale_after)? {
+			Some(lock) => HeldLock::Heartbeat(lock),
+			None => return Ok(None),
+		},
+	};
+
+	Ok(Some(FileLockGuard {
+		path: path.to_owned(),
+		held,
+		remove: false,
+	}))
+}
+
+fn is_held_with(path: &Path, scheme: LockScheme) -> Result<bool, CodeError> {
+	match scheme {
+		LockScheme::Os => {
+			let file = match OpenOptions::new().read(true).write(true).open(path) {
+				Ok(f) => f,
+				Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+				Err(e) => return Err(lock_failed(path, e)),
+			};
+
+			match FileLock::acquire(file) {
+				Ok(Lock::Acquired(_)) => Ok(false),
+				Ok(Lock::AlreadyLocked(_)) => Ok(true),
+				Err(CodeError::SingletonLockfileOpenFailed(e)) => Err(lock_failed(path, e)),
+				Err(e) => Err(e),
+			}
+		}
+		LockScheme::Heartbeat { stale_after, .. } => Ok(matches!(
+			heartbeat_state(path, stale_after)?,
+			Some((false, _))
+		)),
+	}
+}
+
+/// Gets whether the open file is still the one at the path.
+#[cfg(unix)]
+fn is_at_path(file: &File, path: &Path) -> io::Result<bool> {
+	use std::os::unix::fs::MetadataExt;
+
+	let open = file.metadata()?;
+	match fs::metadata(path) {
+		Ok(m) => Ok(m.dev() == open.dev() && m.ino() == open.ino()),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+		Err(e) => Err(e),
+	}
+}
+
+/// Lock files aren't removed while they're held on Windows, so the open file
+/// is always the one at the path.
+#[cfg(not(unix))]
+fn is_at_path(_file: &File, _path: &Path) -> io::Result<bool> {
+	Ok(true)
+}
+
+fn lock_failed(path: &Path, e: io::Error) -> CodeError {
+	CodeError::FileLockFailed(path.display().to_string(), e)
+}
+
+/// Who holds a lock, written into its file after the locked prefix.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct LockHolder {
+	pid: u32,
+	hostname: String,
+	/// Unique to each time the lock is taken.
+	token: String,
+}
+
+impl LockHolder {
+	fn current() -> Self {
+		Self {
+			pid: std::process::id(),
+			hostname: gethostname::gethostname().to_string_lossy().to_string(),
+			token: format!("{:016x}", rand::random::<u64>()),
+		}
+	}
+
+	/// Gets whether the holder was a process on this machine that's exited.
+	fn has_exited_locally(&self) -> bool {
+		self.hostname == gethostname::gethostname().to_string_lossy() && !process_exists(self.pid)
+	}
+}
+
+fn write_holder(file: &mut File, holder: &LockHolder) -> io::Result<()> {
+	write_contents(file, &serde_json::to_vec(holder).unwrap())
+}
+
+/// Replaces what's after the locked prefix. Writes before truncating, so other
+/// processes reading a heartbeat lock never see it empty.
+fn write_contents(file: &mut File, contents: &[u8]) -> io::Result<()> {
+	file.seek(SeekFrom::Start(PREFIX_LOCKED_BYTES as u64))?;
+	file.write_all(contents)?;
+	file.set_len((PREFIX_LOCKED_BYTES + contents.len()) as u64)?;
+	file.flush()
+}
+
+fn read_contents(path: &Path) -> Option<Vec<u8>> {
+	let mut file = File::open(path).ok()?;
+	file.seek(SeekFrom::Start(PREFIX_LOCKED_BYTES as u64))
+		.ok()?;
+	let mut contents = vec![];
+	file.read_to_end(&mut contents).ok()?;
+	Some(contents)
+}
+
+fn read_holder(path: &Path) -> Option<LockHolder> {
+	parse_holder(&read_contents(path)?).map(|(h, _)| h)
+}
+
+/// Parses the holder at the start of the contents, returning it and where
+/// the data its holder wrote after it starts. The data of an OS lock
+/// replaces its holder.
+fn parse_holder(contents: &[u8]) -> Option<(LockHolder, usize)> {
+	let mut holders = serde_json::Deserializer::from_slice(contents).into_iter::<LockHolder>();
+	let holder = holders.next()?.ok()?;
+	Some((holder, holders.byte_offset()))
+}
+
+/// Lock held by owning its file, which a thread touches periodically so
+/// that others can tell the lock isn't stale.
+struct HeartbeatLock {
+	path: PathBuf,
+	token: String,
+	/// Held while the file is rewritten, by a heartbeat or `write_data`.
+	writing: Arc<Mutex<()>>,
+	stop: Option<mpsc::Sender<()>>,
+	heartbeat: Option<thread::JoinHandle<()>>,
+}
+
+impl HeartbeatLock {
+	fn try_acquire(
+		path: &Path,
+		interval: Duration,
+		stale_after: Duration,
+	) -> Result<Option<Self>, CodeError> {
+		let holder = LockHolder::current();
+		// the second attempt is after taking over a stale lock
+		for _ in 0..2 {
+			match OpenOptions::new().write(true).create_new(true).open(path) {
+				Ok(mut file) => {
+					write_holder(&mut file, &holder).map_err(|e| lock_failed(path, e))?;
+					return Ok(Some(Self::start(path, holder.token, interval)));
+				}
+				Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+					if !take_over_if_stale(path, stale_after)? {
+						return Ok(None);
+					}
+				}
+				Err(e) => return Err(lock_failed(path, e)),
+			}
+		}
+
+		Ok(None)
+	}
+
+	fn start(path: &Path, token: String, interval: Duration) -> Self {
+		let (stop, stopped) = mpsc::channel::<()>();
+		let writing = Arc::new(Mutex::new(()));
+		let beat_path = path.to_owned();
+		let beat_token = token.clone();
+		let beat_writing = writing.clone();
+		let heartbeat = thread::spawn(move || {
+			while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+				let _writing = beat_writing.lock().unwrap();
+				if !beat(&beat_path, &beat_token) {
+					return;
+				}
+			}
+		});
+
+		Self {
+			path: path.to_owned(),
+			token,
+			writing,
+			stop: Some(stop),
+			heartbeat: Some(heartbeat),
+		}
+	}
+
+	/// Writes the data after the holder, if the file's still owned.
+	fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+		let _writing = self.writing.lock().unwrap();
+		let mut contents = match read_holder(&self.path) {
+			Some(h) if h.token == self.token => serde_json::to_vec(&h).unwrap(),
+			_ => return Err(io::Error::other("the lock was taken over")),
+		};
+		contents.extend_from_slice(data);
+		OpenOptions::new()
+			.write(true)
+			.open(&self.path)
+			.and_then(|mut f| write_contents(&mut f, &contents))
+	}
+}
+
+impl Drop for HeartbeatLock {
+	fn drop(&mut self) {
+		self.stop.take();
+		if let Some(h) = self.heartbeat.take() {
+			let _ = h.join();
+		}
+
+		if read_holder(&self.path).map(|h| h.token) == Some(self.token.clone()) {
+			let _ = fs::remove_file(&self.path);
+		}
+	}
+}
+
+/// Rewrites the lock file, updating its modified time, if it's still owned
+/// under the token. Returns false if it's not.
+fn beat(path: &Path, token: &str) -> bool {
+	let contents = match read_contents(path) {
+		Some(c) if parse_holder(&c).map(|(h, _)| h.token).as_deref() == Some(token) => c,
+		_ => return false,
+	};
+
+	OpenOptions::new()
+		.write(true)
+		.open(path)
+		.and_then(|mut f| write_contents(&mut f, &contents))
+		.is_ok()
+}
+
+/// Gets whether the heartbeat lock file is stale, because its holder stopped
+/// touching it or exited, and who holds it. None if there's no file.
+fn heartbeat_state(
+	path: &Path,
+	stale_after: Duration,
+) -> Result<Option<(bool, Option<LockHolder>)>, CodeError> {
+	let modified = match fs::metadata(path).and_then(|m| m.modified()) {
+		Ok(m) => m,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(lock_failed(path, e)),
+	};
+
+	let holder = read_holder(path);
+	let age = modified.elapsed().unwrap_or_default();
+	let exited = holder
+		.as_ref()
+		.map(|h| h.has_exited_locally())
+		.unwrap_or(false);
+	Ok(Some((age >= stale_after || exited, holder)))
+}
+
+/// Removes the lock file if its holder stopped touching it, or exited.
+/// Returns whether the path is free to be locked.
+fn take_over_if_stale(path: &Path, stale_after: Duration) -> Result<bool, CodeError> {
+	let holder = match heartbeat_state(path, stale_after)? {
+		None => return Ok(true),
+		Some((false, _)) => return Ok(false),
+		Some((true, holder)) => holder,
+	};
+
+	// The file's moved aside rather than removed, so that only one process
+	// takes it over. Another may have taken over and locked the path between
+	// reading it and moving it, in which case its lock is put back.
+	let mut aside = path.as_os_str().to_owned();
+	aside.push(format!(".stale-{:08x}", rand::random::<u32>()));
+	match fs::rename(path, &aside) {
+		Ok(()) => {}
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+		Err(e) => return Err(lock_failed(path, e)),
+	}
+
+	let moved = read_holder(Path::new(&aside));
+	if moved.map(|m| m.token) != holder.map(|h| h.token) {
+		// if yet another process made a new lock at the path meanwhile, this
+		// one can't be put back, so it's left aside rather than removed from
+		// under its holder, who stops touching it once it sees it's gone
+		if fs::hard_link(&aside, path).is_ok() {
+			let _ = fs::remove_file(&aside);
+		}
+		return Ok(false);
+	}
+
+	let _ = fs::remove_file(&aside);
+	Ok(true)
+}
+
+/// Gets whether the directory is on a network filesystem.
+#[cfg(target_os = "linux")]
+fn is_network_filesystem(dir: &Path) -> bool {
+	use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+	const NFS_SUPER_MAGIC: u32 = 0x6969;
+	const SMB_SUPER_MAGIC: u32 = 0x517b;
+	const CIFS_MAGIC_NUMBER: u32 = 0xff53_4d42;
+	const SMB2_MAGIC_NUMBER: u32 = 0xfe53_4d42;
+	const AFS_SUPER_MAGIC: u32 = 0x5346_414f;
+	const CEPH_SUPER_MAGIC: u32 = 0x00c3_6400;
+
+	let path = match CString::new(dir.as_os_str().as_bytes()) {
+		Ok(p) => p,
+		Err(_) => return false,
+	};
+
+	// safety: statfs only writes into the struct, which is valid zeroed
+	let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+	if unsafe { libc::statfs(path.as_ptr(), &mut st) } != 0 {
+		return false;
+	}
+
+	matches!(
+		st.f_type as u32,
+		NFS_SUPER_MAGIC
+			| SMB_SUPER_MAGIC
+			| CIFS_MAGIC_NUMBER
+			| SMB2_MAGIC_NUMBER
+			| AFS_SUPER_MAGIC
+			| CEPH_SUPER_MAGIC
+	)
+}
+
+/// Gets whether the directory is on a network filesystem.
+#[cfg(target_os = "macos")]
+fn is_network_filesystem(dir: &Path) -> bool {
+	use std::{
+		ffi::{CStr, CString},
+		os::unix::ffi::OsStrExt,
+	};
+
+	let path = match CString::new(dir.as_os_str().as_bytes()) {
+		Ok(p) => p,
+		Err(_) => return false,
+	};
+
+	// safety: statfs only writes into the struct, which is valid zeroed
+	let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+	if unsafe { libc::statfs(path.as_ptr(), &mut st) } != 0 {
+		return false;
+	}
+
+	let name = unsafe { CStr::from_ptr(st.f_fstypename.as_ptr()) };
+	matches!(
+		name.to_bytes(),
+		b"nfs" | b"smbfs" | b"afpfs" | b"webdav" | b"cifs"
+	)
+}
+
+/// Gets whether the directory is on a network drive.
+#[cfg(windows)]
+fn is_network_filesystem(dir: &Path) -> bool {
+	use std::os::windows::ffi::OsStrExt;
+	use winapi::um::{
+		fileapi::{GetDriveTypeW, GetVolumePathNameW},
+		winbase::DRIVE_REMOTE,
+	};
+
+	let wide: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+	let mut root = [0u16; 261];
+	unsafe {
+		GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) != 0
+			&& GetDriveTypeW(root.as_ptr()) == DRIVE_REMOTE
+	}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn is_network_filesystem(_dir: &Path) -> bool {
+	false
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{
+		io::{BufRead, BufReader},
+		process::{Child, Command, Stdio},
+	};
+
+	const HELPER_PATH_ENV: &str = "VSCODE_CLI_TEST_LOCK_PATH";
+	const HELPER_SCHEME_ENV: &str = "VSCODE_CLI_TEST_LOCK_SCHEME";
+
+	fn heartbeat() -> LockScheme {
+		LockScheme::Heartbeat {
+			interval: Duration::from_millis(20),
+			stale_after: Duration::from_millis(200),
+		}
+	}
+
+	/// Holds the lock for another test, run in its own process, until its
+	/// stdin is closed.
+	#[test]
+	#[ignore]
+	fn lock_holder_helper() {
+		let path = match std::env::var_os(HELPER_PATH_ENV) {
+			Some(p) => PathBuf::from(p),
+			None => return,
+		};
+		let scheme = match std::env::var(HELPER_SCHEME_ENV).as_deref() {
+			Ok("heartbeat") => heartbeat(),
+			_ => LockScheme::Os,
+		};
+
+		let _lock = try_acquire_with(&path, scheme)
+			.unwrap()
+			.expect("expected to take the lock");
+		println!("locked");
+		let _ = std::io::stdin().read_line(&mut String::new());
+	}
+
+	fn spawn_holder(path: &Path, scheme: &str) -> Child {
+		let mut child = Command::new(std::env::current_exe().unwrap())
+			.args([
+				"--exact",
+				"util::file_lock::tests::lock_holder_helper",
+				"--ignored",
+				"--nocapture",
+			])
+			.env(HELPER_PATH_ENV, path)
+			.env(HELPER_SCHEME_ENV, scheme)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()
+			.unwrap();
+
+		// the test harness prints it after the test's name, on the same line
+		let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+		let locked = lines.any(|l| l.map(|l| l.ends_with("locked")).unwrap_or(false));
+		assert!(locked, "expected the helper to take the lock");
+
+		// keep reading, so the helper can write its result before it exits
+		std::thread::spawn(move || lines.for_each(drop));
+		child
+	}
+
+	fn release_holder(mut child: Child) {
+		drop(child.stdin.take());
+		child.wait().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_contention_between_processes() {
+		for (name, scheme) in [("os", LockScheme::Os), ("heartbeat", heartbeat())] {
+			let dir = tempfile::tempdir().unwrap();
+			let path = dir.path().join("test.lock");
+			let helper = spawn_holder(&path, name);
+
+			assert!(
+				try_acquire_with(&path, scheme).unwrap().is_none(),
+				"{}",
+				name
+			);
+			match acquire_with(&path, Duration::from_millis(300), scheme).await {
+				Err(CodeError::FileLockTimedOut { holder, .. }) => {
+					assert!(holder.starts_with("process "), "{}: {}", name, holder)
+				}
+				Err(e) => panic!("{}: unexpected error {:?}", name, e),
+				Ok(_) => panic!("{}: expected the lock to be held", name),
+			}
+
+			release_holder(helper);
+			let guard = acquire_with(&path, Duration::from_secs(5), scheme)
+				.await
+				.unwrap();
+			assert_eq!(guard.path(), path);
+		}
+	}
+
+	#[test]
+	fn test_lock_excludes_other_handles() {
+		for scheme in [LockScheme::Os, heartbeat()] {
+			let dir = tempfile::tempdir().unwrap();
+			let path = dir.path().join("test.lock");
+
+			let guard = try_acquire_with(&path, scheme).unwrap().unwrap();
+			assert!(try_acquire_with(&path, scheme).unwrap().is_none());
+			drop(guard);
+			assert!(try_acquire_with(&path, scheme).unwrap().is_some());
+		}
+	}
+
+	#[test]
+	fn test_data_written_by_holder() {
+		for scheme in [LockScheme::Os, heartbeat()] {
+			let dir = tempfile::tempdir().unwrap();
+			let path = dir.path().join("test.lock");
+
+			let mut guard = try_acquire_with(&path, scheme).unwrap().unwrap();
+			guard.write_data(b"\x94data").unwrap();
+			// heartbeats keep it
+			thread::sleep(Duration::from_millis(100));
+			assert_eq!(
+				read_data(&mut File::open(&path).unwrap()).unwrap(),
+				b"\x94data"
+			);
+			assert!(is_held_with(&path, scheme).unwrap());
+
+			drop(guard);
+			assert!(!is_held_with(&path, scheme).unwrap());
+		}
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_removed_on_release() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("test.lock");
+
+		let guard = try_acquire_with(&path, LockScheme::Os)
+			.unwrap()
+			.unwrap()
+			.remove_on_release();
+		// opened by a process waiting on the lock
+		let waiting = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.open(&path)
+			.unwrap();
+		drop(guard);
+		assert!(!path.exists());
+
+		// the lock it gets is on a file that's no longer at the path
+		let lock = match FileLock::acquire(waiting).unwrap() {
+			Lock::Acquired(l) => l,
+			Lock::AlreadyLocked(_) => panic!("expected the lock to be released"),
+		};
+		assert!(!is_at_path(lock.file(), &path).unwrap());
+		assert!(try_acquire_with(&path, LockScheme::Os).unwrap().is_some());
+	}
+
+	#[test]
+	fn test_heartbeat_keeps_lock_fresh() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("test.lock");
+
+		let _guard = try_acquire_with(&path, heartbeat()).unwrap().unwrap();
+		thread::sleep(Duration::from_millis(500));
+		assert!(try_acquire_with(&path, heartbeat()).unwrap().is_none());
+	}
+
+	#[test]
+	fn test_takes_over_stale_heartbeat() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("test.lock");
+		let stale = LockHolder {
+			pid: 1,
+			hostname: "some-other-machine".to_string(),
+			token: "stale".to_string(),
+		};
+		write_holder(&mut File::create(&path).unwrap(), &stale).unwrap();
+
+		assert!(try_acquire_with(&path, heartbeat()).unwrap().is_none());
+		thread::sleep(Duration::from_millis(300));
+
+		let guard = try_acquire_with(&path, heartbeat()).unwrap().unwrap();
+		let holder = read_holder(&path).unwrap();
+		assert_eq!(holder.pid, std::process::id());
+		assert_ne!(holder.token, stale.token);
+
+		drop(guard);
+		assert!(!path.exists());
+	}
+
+	#[test]
+	fn test_takes_over_heartbeat_of_exited_process() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("test.lock");
+
+		let mut exited = Command::new(std::env::current_exe().unwrap())
+			.arg("--list")
+			.stdout(Stdio::null())
+			.spawn()
+			.unwrap();
+		let pid = exited.id();
+		exited.wait().unwrap();
+
+		let mut holder = LockHolder::current();
+		holder.pid = pid;
+		write_holder(&mut File::create(&path).unwrap(), &holder).unwrap();
+
+		let scheme = LockScheme::Heartbeat {
+			interval: HEARTBEAT_INTERVAL,
+			stale_after: HEARTBEAT_STALE_AFTER,
+		};
+		assert!(try_acquire_with(&path, scheme).unwrap().is_some());
 	}
 }
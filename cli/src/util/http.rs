@@ -15,8 +15,9 @@ use hyper::{
 	http::HeaderValue,
 	HeaderMap, StatusCode,
 };
+use lazy_static::lazy_static;
 use serde::de::DeserializeOwned;
-use std::{io, pin::Pin, str::FromStr, sync::Arc, task::Poll};
+use std::{io, pin::Pin, str::FromStr, sync::Arc, task::Poll, time::Duration};
 use tokio::{
 	fs,
 	io::{AsyncRead, AsyncReadExt},
@@ -130,24 +131,46 @@ pub trait SimpleHttp {
 
 pub type BoxedHttp = Arc<dyn SimpleHttp + Send + Sync + 'static>;
 
+lazy_static! {
+	static ref SHARED_CLIENT: reqwest::Client = reqwest::ClientBuilder::new()
+		.user_agent(get_default_user_agent())
+		.build()
+		.unwrap();
+}
+
+/// Gets the client that update checks, downloads, auth, and the tunnel
+/// service all make requests with, so that they share one connection pool
+/// and don't each pay for new connections and TLS handshakes. Reuse of
+/// pooled connections is logged at the trace level.
+pub fn shared_client() -> reqwest::Client {
+	SHARED_CLIENT.clone()
+}
+
 // Implementation of SimpleHttp that uses a reqwest client.
 #[derive(Clone)]
 pub struct ReqwestSimpleHttp {
 	client: reqwest::Client,
+	timeout: Option<Duration>,
 }
 
 impl ReqwestSimpleHttp {
+	/// Makes requests with the shared client.
 	pub fn new() -> Self {
+		Self::with_client(shared_client())
+	}
+
+	pub fn with_client(client: reqwest::Client) -> Self {
 		Self {
-			client: reqwest::ClientBuilder::new()
-				.user_agent(get_default_user_agent())
-				.build()
-				.unwrap(),
+			client,
+			timeout: None,
 		}
 	}
 
-	pub fn with_client(client: reqwest::Client) -> Self {
-		Self { client }
+	/// Fails requests that haven't finished, including reading the body,
+	/// within the timeout. The client's connections are still shared.
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
 	}
 }
 
@@ -164,11 +187,13 @@ impl SimpleHttp for ReqwestSimpleHttp {
 		method: &'static str,
 		url: String,
 	) -> Result<SimpleResponse, AnyError> {
-		let res = self
+		let mut req = self
 			.client
-			.request(reqwest::Method::try_from(method).unwrap(), &url)
-			.send()
-			.await?;
+			.request(reqwest::Method::try_from(method).unwrap(), &url);
+		if let Some(timeout) = self.timeout {
+			req = req.timeout(timeout);
+		}
+		let res = req.send().await?;
 
 		Ok(SimpleResponse {
 			status_code: res.status(),
@@ -374,3 +399,52 @@ impl SimpleHttp for FallbackSimpleHttp {
 		self.delegated.make_request(method, url).await
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::convert::Infallible;
+
+	use hyper::service::{make_service_fn, service_fn};
+
+	use super::*;
+
+	/// Serves "ok", after the delay for requests to /slow.
+	fn slow_server(delay: Duration) -> String {
+		let make_svc = make_service_fn(move |_| async move {
+			Ok::<_, Infallible>(service_fn(
+				move |req: hyper::Request<hyper::Body>| async move {
+					if req.uri().path() == "/slow" {
+						tokio::time::sleep(delay).await;
+					}
+					Ok::<_, Infallible>(hyper::Response::new(hyper::Body::from("ok")))
+				},
+			))
+		});
+		let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+		let url = format!("http://{}", server.local_addr());
+		tokio::spawn(server);
+		url
+	}
+
+	#[tokio::test]
+	async fn test_timeout_applies_per_call() {
+		let base = slow_server(Duration::from_secs(5));
+
+		let timed = ReqwestSimpleHttp::new().with_timeout(Duration::from_millis(100));
+		let err = timed
+			.make_request("GET", format!("{}/slow", base))
+			.await
+			.err()
+			.expect("expected the request to time out");
+		assert!(err.to_string().contains("timed out"), "{}", err);
+
+		// the shared client itself has no timeout
+		let mut res = ReqwestSimpleHttp::new()
+			.make_request("GET", format!("{}/fast", base))
+			.await
+			.unwrap();
+		let mut body = String::new();
+		res.read.read_to_string(&mut body).await.unwrap();
+		assert_eq!(body, "ok");
+	}
+}
@@ -2,68 +2,782 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use crate::util::errors::wrap;
+use crate::{
+	constants::IS_INTERACTIVE_CLI,
+	util::errors::{wrap, AnyError, CodeError},
+};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
-use indicatif::ProgressBar;
-use std::fmt::Display;
+use lazy_static::lazy_static;
+use std::{fmt::Display, sync::RwLock, time::Duration};
 
-use super::{errors::WrappedError, io::ReportCopyProgress};
+/// Times a secret is asked for again when its confirmation doesn't match.
+const SECRET_CONFIRM_ATTEMPTS: usize = 3;
 
-/// Wrapper around indicatif::ProgressBar that implements ReportCopyProgress.
-pub struct ProgressBarReporter {
-	bar: ProgressBar,
-	has_set_total: bool,
+/// How prompts behave, from `--no-input` and `--prompt-timeout`.
+#[derive(Clone, Debug)]
+pub struct InputSettings {
+	/// Whether prompts are shown. Otherwise they fail right away.
+	pub interactive: bool,
+	/// Time after which prompts take their default answer.
+	pub timeout: Option<Duration>,
 }
 
-impl From<ProgressBar> for ProgressBarReporter {
-	fn from(bar: ProgressBar) -> Self {
-		ProgressBarReporter {
-			bar,
-			has_set_total: false,
+impl InputSettings {
+	/// Prompts are only shown when stdin is a terminal, since a pipe may
+	/// never answer, and input isn't turned off.
+	pub fn new(no_input: bool, timeout: Option<Duration>) -> Self {
+		Self {
+			interactive: !no_input && *IS_INTERACTIVE_CLI,
+			timeout,
 		}
 	}
 }
 
-impl ReportCopyProgress for ProgressBarReporter {
-	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
-		if !self.has_set_total {
-			self.bar.set_length(total_bytes);
-		}
-
-		if bytes_so_far == total_bytes {
-			self.bar.finish_and_clear();
-		} else {
-			self.bar.set_position(bytes_so_far);
-		}
+impl Default for InputSettings {
+	fn default() -> Self {
+		Self::new(false, None)
 	}
 }
 
-pub fn prompt_yn(text: &str) -> Result<bool, WrappedError> {
-	Confirm::with_theme(&ColorfulTheme::default())
-		.with_prompt(text)
-		.default(true)
-		.interact()
-		.map_err(|e| wrap(e, "Failed to read confirm input"))
+lazy_static! {
+	static ref SETTINGS: RwLock<InputSettings> = RwLock::new(InputSettings::default());
+}
+
+/// Sets how prompts behave for the rest of the process.
+pub fn configure(settings: InputSettings) {
+	*SETTINGS.write().unwrap() = settings;
+}
+
+/// Gets whether the CLI may prompt, for code that decides whether to ask at
+/// all or to use a default.
+pub fn is_interactive() -> bool {
+	SETTINGS.read().unwrap().interactive
+}
+
+fn settings() -> InputSettings {
+	SETTINGS.read().unwrap().clone()
 }
 
-pub fn prompt_options<T>(text: impl Into<String>, options: &[T]) -> Result<T, WrappedError>
+/// Asks for confirmation. Confirmations have no answer to time out to, since
+/// that would agree on the user's behalf, so they fail instead.
+/// `answered_by` is the flag that gives the answer without a prompt.
+pub fn prompt_yn(text: &str, answered_by: &str) -> Result<bool, AnyError> {
+	let prompt = text.to_string();
+	interact(&settings(), text, answered_by, None, move || {
+		Confirm::with_theme(&ColorfulTheme::default())
+			.with_prompt(prompt)
+			.default(true)
+			.interact()
+	})
+}
+
+pub fn prompt_options<T>(
+	text: impl Into<String>,
+	options: &[T],
+	answered_by: &str,
+) -> Result<T, AnyError>
 where
 	T: Display + Copy,
 {
-	let chosen = Select::with_theme(&ColorfulTheme::default())
-		.with_prompt(text)
-		.items(options)
-		.default(0)
-		.interact()
-		.map_err(|e| wrap(e, "Failed to read select input"))?;
+	prompt_options_with_default(text, options, 0, answered_by)
+}
+
+/// Like `prompt_options`, but with the option at the given index selected initially.
+pub fn prompt_options_with_default<T>(
+	text: impl Into<String>,
+	options: &[T],
+	default: usize,
+	answered_by: &str,
+) -> Result<T, AnyError>
+where
+	T: Display + Copy,
+{
+	let text = text.into();
+	let prompt = text.clone();
+	let items: Vec<String> = options.iter().map(|o| o.to_string()).collect();
+	let chosen = interact(&settings(), &text, answered_by, Some(default), move || {
+		Select::with_theme(&ColorfulTheme::default())
+			.with_prompt(prompt)
+			.items(&items)
+			.default(default)
+			.interact()
+	})?;
 
 	Ok(options[chosen])
 }
 
-pub fn prompt_placeholder(question: &str, placeholder: &str) -> Result<String, WrappedError> {
-	Input::with_theme(&ColorfulTheme::default())
-		.with_prompt(question)
-		.default(placeholder.to_string())
-		.interact_text()
-		.map_err(|e| wrap(e, "Failed to read confirm input"))
+pub fn prompt_placeholder(
+	question: &str,
+	placeholder: &str,
+	answered_by: &str,
+) -> Result<String, AnyError> {
+	let prompt = question.to_string();
+	let default = placeholder.to_string();
+	interact(
+		&settings(),
+		question,
+		answered_by,
+		Some(default.clone()),
+		move || {
+			Input::with_theme(&ColorfulTheme::default())
+				.with_prompt(prompt)
+				.default(default)
+				.interact_text()
+		},
+	)
+}
+
+/// Asks for a secret, such as a passphrase, without echoing what's typed. If
+/// the `env_var` environment variable is set, its value is used without
+/// asking, which is how to give the secret when there's no terminal. The
+/// secret is never logged.
+pub fn prompt_secret(message: &str, env_var: &str) -> Result<String, AnyError> {
+	let settings = settings();
+	secret_from_env_or(
+		&settings,
+		message,
+		env_var,
+		secret_from_env(env_var),
+		|| read_secret(&settings, message, env_var),
+	)
+}
+
+/// Like `prompt_secret`, but has the secret typed twice, for setting one.
+pub fn prompt_secret_confirm(message: &str, env_var: &str) -> Result<String, AnyError> {
+	let settings = settings();
+	secret_from_env_or(
+		&settings,
+		message,
+		env_var,
+		secret_from_env(env_var),
+		|| confirm_secret(message, |m| read_secret(&settings, m, env_var)),
+	)
+}
+
+fn secret_from_env(env_var: &str) -> Option<String> {
+	std::env::var(env_var).ok().filter(|v| !v.is_empty())
+}
+
+fn secret_from_env_or(
+	settings: &InputSettings,
+	message: &str,
+	env_var: &str,
+	from_env: Option<String>,
+	prompt: impl FnOnce() -> Result<String, AnyError>,
+) -> Result<String, AnyError> {
+	if let Some(secret) = from_env {
+		return Ok(secret);
+	}
+
+	if !settings.interactive {
+		return Err(CodeError::NoInputForSecret {
+			prompt: message.to_string(),
+			env_var: env_var.to_string(),
+		}
+		.into());
+	}
+
+	prompt()
+}
+
+fn confirm_secret(
+	message: &str,
+	mut read: impl FnMut(&str) -> Result<String, AnyError>,
+) -> Result<String, AnyError> {
+	let confirm = format!("Confirm {}", message.to_lowercase());
+	for _ in 0..SECRET_CONFIRM_ATTEMPTS {
+		let secret = read(message)?;
+		if read(&confirm)? == secret {
+			return Ok(secret);
+		}
+
+		eprintln!("The entries didn't match, try again.");
+	}
+
+	Err(CodeError::SecretsDidNotMatch.into())
+}
+
+/// Reads a line from the terminal with echo off, turning it back on once the
+/// line is read or the prompt times out.
+fn read_secret(settings: &InputSettings, message: &str, env_var: &str) -> Result<String, AnyError> {
+	let _echo_off = terminal::ModeGuard::echo_off().map_err(|e| -> AnyError {
+		if terminal::is_not_console(&e) {
+			CodeError::NoInputForSecret {
+				prompt: message.to_string(),
+				env_var: env_var.to_string(),
+			}
+			.into()
+		} else {
+			wrap(e, "Failed to turn off echo").into()
+		}
+	})?;
+	let prompt = format!("{}: ", message);
+	let answered_by = format!("the {} environment variable", env_var);
+	interact(settings, message, &answered_by, None, move || {
+		eprint!("{}", prompt);
+		let mut line = String::new();
+		let read = std::io::stdin().read_line(&mut line);
+		// the newline isn't echoed on windows
+		#[cfg(windows)]
+		eprintln!();
+		read?;
+		Ok(line.trim_end_matches(['\r', '\n']).to_string())
+	})
+}
+
+/// Runs the prompt if the settings allow it, giving `on_timeout` if there's
+/// a timeout and no key is pressed by then.
+fn interact<T>(
+	settings: &InputSettings,
+	text: &str,
+	answered_by: &str,
+	on_timeout: Option<T>,
+	prompt: impl FnOnce() -> std::io::Result<T>,
+) -> Result<T, AnyError> {
+	interact_with(
+		settings,
+		text,
+		answered_by,
+		on_timeout,
+		wait_for_key,
+		prompt,
+	)
+}
+
+/// Like `interact`, with `wait` waiting for the first key. Waiting is done
+/// before the prompt reads anything, so a prompt that times out is never
+/// left blocked on stdin, or holding the terminal in raw mode.
+fn interact_with<T>(
+	settings: &InputSettings,
+	text: &str,
+	answered_by: &str,
+	on_timeout: Option<T>,
+	wait: impl FnOnce(&str, Duration) -> std::io::Result<bool>,
+	prompt: impl FnOnce() -> std::io::Result<T>,
+) -> Result<T, AnyError> {
+	if !settings.interactive {
+		return Err(CodeError::NoInputForPrompt {
+			prompt: text.to_string(),
+			answered_by: answered_by.to_string(),
+		}
+		.into());
+	}
+
+	if let Some(timeout) = settings.timeout {
+		let pressed = wait(text, timeout).map_err(|e| wrap(e, "Failed to read input"))?;
+		if !pressed {
+			return match on_timeout {
+				Some(answer) => Ok(answer),
+				None => Err(CodeError::PromptTimedOut {
+					prompt: text.to_string(),
+					answered_by: answered_by.to_string(),
+				}
+				.into()),
+			};
+		}
+	}
+
+	prompt().map_err(|e| wrap(e, "Failed to read input").into())
+}
+
+/// Shows the prompt's text until a key is pressed or the timeout passes,
+/// returning whether one was. The key is left for the prompt to read, which
+/// draws itself in place of the text.
+fn wait_for_key(text: &str, timeout: Duration) -> std::io::Result<bool> {
+	let term = console::Term::stderr();
+	term.write_str(&format!("{} ", text))?;
+	let pressed = terminal::wait_for_input(timeout);
+	term.clear_line()?;
+	pressed
+}
+
+#[cfg(unix)]
+mod terminal {
+	use std::{
+		io,
+		ptr::{addr_of, addr_of_mut, null_mut},
+		sync::atomic::{AtomicBool, Ordering},
+		time::{Duration, Instant},
+	};
+
+	/// Signals that end the process by default, after which the terminal
+	/// would be left without echo.
+	const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM];
+
+	// Written before ARMED is set and by the outermost guard only, so the
+	// signal handler never sees them partially written.
+	static ARMED: AtomicBool = AtomicBool::new(false);
+	static mut SAVED: Option<libc::termios> = None;
+	// whole actions, since handlers such as tokio's need their flags and mask
+	static mut PREVIOUS: Option<[libc::sigaction; 3]> = None;
+
+	/// Gets whether turning off echo failed because stdin isn't a terminal.
+	pub fn is_not_console(e: &io::Error) -> bool {
+		matches!(e.raw_os_error(), Some(libc::ENOTTY | libc::EBADF))
+	}
+
+	/// Changes the terminal's mode until dropped, or until the process is
+	/// signalled to exit. Guards may be nested, such as while waiting for a
+	/// key at a secret prompt; each puts back the mode it found, and the
+	/// outermost one handles the signals.
+	pub struct ModeGuard {
+		previous: libc::termios,
+		outermost: bool,
+	}
+
+	impl ModeGuard {
+		/// Turns off echo, other than of the newline ending the line.
+		pub fn echo_off() -> io::Result<Self> {
+			Self::set(|t| {
+				t.c_lflag &= !libc::ECHO;
+				t.c_lflag |= libc::ECHONL;
+			})
+		}
+
+		fn set(change: impl FnOnce(&mut libc::termios)) -> io::Result<Self> {
+			// safety: a zeroed termios is valid for tcgetattr to fill in
+			let mut previous: libc::termios = unsafe { std::mem::zeroed() };
+			if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut previous) } != 0 {
+				return Err(io::Error::last_os_error());
+			}
+
+			let mut mode = previous;
+			change(&mut mode);
+
+			let outermost = !ARMED.load(Ordering::SeqCst);
+			if outermost {
+				unsafe {
+					addr_of_mut!(SAVED).write(Some(previous));
+					// safety: zeroed sigactions are valid, with no flags and
+					// an empty mask
+					let mut action: libc::sigaction = std::mem::zeroed();
+					action.sa_sigaction =
+						restore_and_reraise as extern "C" fn(libc::c_int) as libc::sighandler_t;
+					libc::sigemptyset(&mut action.sa_mask);
+					let mut handlers: [libc::sigaction; 3] = std::mem::zeroed();
+					for (p, sig) in handlers.iter_mut().zip(SIGNALS) {
+						libc::sigaction(sig, &action, p);
+					}
+					addr_of_mut!(PREVIOUS).write(Some(handlers));
+				}
+				ARMED.store(true, Ordering::SeqCst);
+			}
+
+			let guard = ModeGuard {
+				previous,
+				outermost,
+			};
+			if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &mode) } != 0 {
+				return Err(io::Error::last_os_error());
+			}
+
+			Ok(guard)
+		}
+	}
+
+	impl Drop for ModeGuard {
+		fn drop(&mut self) {
+			if !self.outermost {
+				unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.previous) };
+				return;
+			}
+
+			ARMED.store(false, Ordering::SeqCst);
+			unsafe {
+				restore_terminal();
+				if let Some(handlers) = addr_of!(PREVIOUS).read() {
+					for (previous, sig) in handlers.iter().zip(SIGNALS) {
+						libc::sigaction(sig, previous, null_mut());
+					}
+				}
+			}
+		}
+	}
+
+	/// Waits until a key is pressed, without reading it. The terminal leaves
+	/// line mode while waiting, since otherwise input only becomes readable
+	/// once a whole line is typed; the key stays queued when it's put back.
+	pub fn wait_for_input(timeout: Duration) -> io::Result<bool> {
+		let _waiting = ModeGuard::set(|t| {
+			t.c_lflag &= !(libc::ICANON | libc::ECHO);
+			t.c_cc[libc::VMIN] = 1;
+			t.c_cc[libc::VTIME] = 0;
+		})?;
+
+		let deadline = Instant::now() + timeout;
+		loop {
+			let left = deadline.saturating_duration_since(Instant::now());
+			let mut fd = libc::pollfd {
+				fd: libc::STDIN_FILENO,
+				events: libc::POLLIN,
+				revents: 0,
+			};
+			let millis = left.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+			match unsafe { libc::poll(&mut fd, 1, millis) } {
+				-1 => {
+					let e = io::Error::last_os_error();
+					if e.kind() != io::ErrorKind::Interrupted {
+						return Err(e);
+					}
+				}
+				0 => return Ok(false),
+				_ => return Ok(true),
+			}
+		}
+	}
+
+	unsafe fn restore_terminal() {
+		if let Some(saved) = addr_of!(SAVED).read() {
+			libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved);
+		}
+	}
+
+	/// Turns echo back on and passes the signal to whatever handled it before.
+	/// Only calls async-signal-safe functions.
+	extern "C" fn restore_and_reraise(sig: libc::c_int) {
+		unsafe {
+			if ARMED.swap(false, Ordering::SeqCst) {
+				restore_terminal();
+			}
+
+			// the signal is blocked while this runs, so it reaches the
+			// previous handler once this returns
+			if let Some(handlers) = addr_of!(PREVIOUS).read() {
+				let i = SIGNALS.iter().position(|s| *s == sig).unwrap_or(0);
+				libc::sigaction(sig, &handlers[i], null_mut());
+			} else {
+				libc::signal(sig, libc::SIG_DFL);
+			}
+			libc::raise(sig);
+		}
+	}
+}
+
+#[cfg(windows)]
+mod terminal {
+	use std::{
+		io,
+		sync::atomic::{AtomicBool, AtomicU32, Ordering},
+		time::{Duration, Instant},
+	};
+	use winapi::{
+		shared::{
+			minwindef::{BOOL, DWORD, FALSE, TRUE},
+			winerror::{ERROR_INVALID_HANDLE, WAIT_TIMEOUT},
+		},
+		um::{
+			consoleapi::{
+				GetConsoleMode, PeekConsoleInputW, ReadConsoleInputW, SetConsoleCtrlHandler,
+				SetConsoleMode,
+			},
+			processenv::GetStdHandle,
+			synchapi::WaitForSingleObject,
+			winbase::{STD_INPUT_HANDLE, WAIT_OBJECT_0},
+			wincon::ENABLE_ECHO_INPUT,
+			wincontypes::{INPUT_RECORD, KEY_EVENT},
+		},
+	};
+
+	static ARMED: AtomicBool = AtomicBool::new(false);
+	static SAVED_MODE: AtomicU32 = AtomicU32::new(0);
+
+	/// Turns off echo on the console until dropped, or until Ctrl+C or the
+	/// console closing ends the process.
+	pub struct ModeGuard;
+
+	/// Gets whether turning off echo failed because stdin isn't a console,
+	/// such as when it's a pipe.
+	pub fn is_not_console(e: &io::Error) -> bool {
+		e.raw_os_error() == Some(ERROR_INVALID_HANDLE as i32)
+	}
+
+	impl ModeGuard {
+		pub fn echo_off() -> io::Result<Self> {
+			let mut mode: DWORD = 0;
+			unsafe {
+				if GetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), &mut mode) == 0 {
+					return Err(io::Error::last_os_error());
+				}
+
+				SAVED_MODE.store(mode, Ordering::SeqCst);
+				SetConsoleCtrlHandler(Some(restore_on_ctrl), TRUE);
+			}
+			ARMED.store(true, Ordering::SeqCst);
+
+			let guard = ModeGuard;
+			if unsafe { SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), mode & !ENABLE_ECHO_INPUT) }
+				== 0
+			{
+				return Err(io::Error::last_os_error());
+			}
+
+			Ok(guard)
+		}
+	}
+
+	impl Drop for ModeGuard {
+		fn drop(&mut self) {
+			ARMED.store(false, Ordering::SeqCst);
+			unsafe {
+				restore_console();
+				SetConsoleCtrlHandler(Some(restore_on_ctrl), FALSE);
+			}
+		}
+	}
+
+	/// Waits until a key is pressed, without reading it. The console's input
+	/// is also signalled for events such as focus changes, which are read and
+	/// dropped.
+	pub fn wait_for_input(timeout: Duration) -> io::Result<bool> {
+		let deadline = Instant::now() + timeout;
+		loop {
+			let left = deadline.saturating_duration_since(Instant::now());
+			let millis = left.as_millis().min(DWORD::MAX as u128 - 1) as DWORD;
+			unsafe {
+				let input = GetStdHandle(STD_INPUT_HANDLE);
+				match WaitForSingleObject(input, millis) {
+					WAIT_OBJECT_0 => {}
+					WAIT_TIMEOUT => return Ok(false),
+					_ => return Err(io::Error::last_os_error()),
+				}
+
+				let mut record: INPUT_RECORD = std::mem::zeroed();
+				let mut read: DWORD = 0;
+				if PeekConsoleInputW(input, &mut record, 1, &mut read) == 0 {
+					return Err(io::Error::last_os_error());
+				}
+				if read == 0 {
+					continue;
+				}
+				if record.EventType == KEY_EVENT && record.Event.KeyEvent().bKeyDown != 0 {
+					return Ok(true);
+				}
+				if ReadConsoleInputW(input, &mut record, 1, &mut read) == 0 {
+					return Err(io::Error::last_os_error());
+				}
+			}
+		}
+	}
+
+	unsafe fn restore_console() {
+		SetConsoleMode(
+			GetStdHandle(STD_INPUT_HANDLE),
+			SAVED_MODE.load(Ordering::SeqCst),
+		);
+	}
+
+	/// Turns echo back on, leaving the event to the next handler.
+	unsafe extern "system" fn restore_on_ctrl(_event: DWORD) -> BOOL {
+		if ARMED.swap(false, Ordering::SeqCst) {
+			restore_console();
+		}
+
+		FALSE
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use super::*;
+	use crate::util::test_env::in_child_with_env;
+
+	/// Stands in for a prompt that's never answered.
+	fn never_answers<T>() -> std::io::Result<T> {
+		std::thread::sleep(Duration::from_secs(3600));
+		Err(std::io::ErrorKind::TimedOut.into())
+	}
+
+	/// Waits for a key that isn't pressed in time.
+	fn no_key(_text: &str, _timeout: Duration) -> std::io::Result<bool> {
+		Ok(false)
+	}
+
+	#[test]
+	fn test_fails_without_input() {
+		let settings = InputSettings {
+			interactive: false,
+			timeout: None,
+		};
+		let started = Instant::now();
+		let err = interact(&settings, "Accept?", "--accept", Some(true), never_answers)
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("--accept"), "{}", err);
+		assert!(started.elapsed() < Duration::from_secs(1));
+	}
+
+	#[test]
+	fn test_prompts_fail_with_closed_stdin() {
+		// configures the process, so it's run in a child with stdin closed
+		if !in_child_with_env(
+			concat!(module_path!(), "::test_prompts_fail_with_closed_stdin"),
+			&[],
+		) {
+			return;
+		}
+
+		configure(InputSettings::new(true, None));
+		let err = prompt_yn("Agree?", "--accept-server-license-terms").unwrap_err();
+		assert!(err.to_string().contains("--accept-server-license-terms"));
+		let err = prompt_options_with_default("Pick one", &[1, 2], 1, "--pick").unwrap_err();
+		assert!(err.to_string().contains("--pick"));
+		let err = prompt_placeholder("Name?", "machine", "--name").unwrap_err();
+		assert!(err.to_string().contains("--name"));
+		assert!(!is_interactive());
+	}
+
+	#[test]
+	fn test_timeout_takes_default() {
+		let settings = InputSettings {
+			interactive: true,
+			timeout: Some(Duration::from_millis(50)),
+		};
+		let answer = interact_with(
+			&settings,
+			"Name?",
+			"--name",
+			Some("default"),
+			no_key,
+			never_answers,
+		);
+		assert_eq!(answer.unwrap(), "default");
+	}
+
+	#[test]
+	fn test_timeout_without_default_fails() {
+		let settings = InputSettings {
+			interactive: true,
+			timeout: Some(Duration::from_millis(50)),
+		};
+		let err =
+			interact_with::<bool>(&settings, "Agree?", "--accept", None, no_key, never_answers)
+				.unwrap_err()
+				.to_string();
+		assert!(err.contains("--accept"), "{}", err);
+	}
+
+	#[test]
+	fn test_secret_from_env() {
+		let settings = InputSettings {
+			interactive: false,
+			timeout: None,
+		};
+		let secret = secret_from_env_or(
+			&settings,
+			"Passphrase",
+			"SOME_SECRET",
+			Some("hunter2".to_string()),
+			|| panic!("expected not to prompt"),
+		);
+		assert_eq!(secret.unwrap(), "hunter2");
+	}
+
+	#[test]
+	fn test_prompt_secret_from_env() {
+		if !in_child_with_env(
+			concat!(module_path!(), "::test_prompt_secret_from_env"),
+			&[("VSCODE_CLI_TEST_PROMPT_SECRET", "from-env")],
+		) {
+			return;
+		}
+
+		configure(InputSettings::new(true, None));
+		assert_eq!(
+			prompt_secret_confirm("Passphrase", "VSCODE_CLI_TEST_PROMPT_SECRET").unwrap(),
+			"from-env"
+		);
+	}
+
+	#[test]
+	fn test_secret_without_input_fails() {
+		let settings = InputSettings {
+			interactive: false,
+			timeout: None,
+		};
+		let err = secret_from_env_or(&settings, "Passphrase", "SOME_SECRET", None, || {
+			panic!("expected not to prompt")
+		})
+		.unwrap_err()
+		.to_string();
+		assert!(err.contains("SOME_SECRET"), "{}", err);
+	}
+
+	#[test]
+	fn test_prompt_secret_with_closed_stdin() {
+		if !in_child_with_env(
+			concat!(module_path!(), "::test_prompt_secret_with_closed_stdin"),
+			&[],
+		) {
+			return;
+		}
+
+		configure(InputSettings::new(true, None));
+		let err = prompt_secret("Passphrase", "VSCODE_CLI_TEST_UNSET_SECRET")
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("VSCODE_CLI_TEST_UNSET_SECRET"), "{}", err);
+	}
+
+	#[test]
+	fn test_read_secret_without_terminal() {
+		// run in a child, whose stdin isn't a terminal
+		if !in_child_with_env(
+			concat!(module_path!(), "::test_read_secret_without_terminal"),
+			&[],
+		) {
+			return;
+		}
+
+		let settings = InputSettings {
+			interactive: true,
+			timeout: None,
+		};
+		match read_secret(&settings, "Passphrase", "SOME_SECRET") {
+			Err(AnyError::CodeError(CodeError::NoInputForSecret { env_var, .. })) => {
+				assert_eq!(env_var, "SOME_SECRET")
+			}
+			r => panic!("expected no input for the secret, got {:?}", r.map(|_| ())),
+		}
+	}
+
+	#[test]
+	fn test_confirm_secret() {
+		let mut answers = vec!["a", "b", "c", "c"].into_iter();
+		let secret = confirm_secret("Passphrase", |_| Ok(answers.next().unwrap().to_string()));
+		assert_eq!(secret.unwrap(), "c");
+
+		let mut n = 0;
+		let err = confirm_secret("Passphrase", |_| {
+			n += 1;
+			Ok(n.to_string())
+		})
+		.unwrap_err()
+		.to_string();
+		assert!(err.contains("didn't match"), "{}", err);
+		assert_eq!(n, SECRET_CONFIRM_ATTEMPTS * 2);
+	}
+
+	#[test]
+	fn test_answer_before_timeout() {
+		let settings = InputSettings {
+			interactive: true,
+			timeout: Some(Duration::from_secs(5)),
+		};
+		let mut waited = None;
+		let answer = interact_with(
+			&settings,
+			"Agree?",
+			"--accept",
+			None,
+			|text, timeout| {
+				waited = Some((text.to_string(), timeout));
+				Ok(true)
+			},
+			|| Ok(false),
+		);
+		assert!(!answer.unwrap());
+		assert_eq!(waited, Some(("Agree?".to_string(), Duration::from_secs(5))));
+	}
 }
@@ -4,7 +4,8 @@
  *--------------------------------------------------------------------------------------------*/
 use std::{
 	fs::File,
-	io::{self, BufRead, Seek},
+	io::{self, BufRead, Seek, Write},
+	path::Path,
 	task::Poll,
 	time::Duration,
 };
@@ -17,6 +18,41 @@ use tokio::{
 
 use super::ring_buffer::RingBuffer;
 
+/// Gets the bytes used by files in the directory and its children. Entries
+/// that can't be read count as empty.
+pub fn dir_size(path: &Path) -> u64 {
+	let entries = match std::fs::read_dir(path) {
+		Ok(e) => e,
+		Err(_) => return 0,
+	};
+
+	entries
+		.flatten()
+		.map(|e| match e.metadata() {
+			Ok(m) if m.is_dir() => dir_size(&e.path()),
+			Ok(m) => m.len(),
+			Err(_) => 0,
+		})
+		.sum()
+}
+
+/// Writes the file by replacing it with a new one that only the user can
+/// read, so it's never left half-written and doesn't keep the permissions
+/// of the file it replaces.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+	let dir = match path.parent() {
+		Some(d) if !d.as_os_str().is_empty() => d,
+		_ => Path::new("."),
+	};
+
+	// temporary files are created with mode 0600 on unix
+	let mut file = tempfile::NamedTempFile::new_in(dir)?;
+	file.write_all(contents)?;
+	file.as_file().sync_all()?;
+	file.persist(path).map_err(|e| e.error)?;
+	Ok(())
+}
+
 pub trait ReportCopyProgress {
 	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64);
 }
@@ -226,6 +262,29 @@ mod tests {
 
 	use super::*;
 
+	#[test]
+	fn test_write_private_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("secret");
+		std::fs::write(&path, "old contents that are longer").unwrap();
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+		}
+
+		write_private_file(&path, b"new").unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), b"new");
+		// the temporary file was moved over the old one
+		assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+			assert_eq!(mode & 0o777, 0o600);
+		}
+	}
+
 	#[tokio::test]
 	async fn test_tailf_empty() {
 		let dir = tempfile::tempdir().unwrap();
@@ -296,6 +355,35 @@ mod tests {
 		}
 	}
 
+	#[tokio::test]
+	async fn test_tailf_no_initial_lines() {
+		let dir = tempfile::tempdir().unwrap();
+		let file_path = dir.path().join("tmp");
+
+		let mut read_file = OpenOptions::new()
+			.write(true)
+			.read(true)
+			.create(true)
+			.truncate(true)
+			.open(&file_path)
+			.unwrap();
+		writeln!(&mut read_file, "existing").unwrap();
+		read_file.seek(io::SeekFrom::Start(0)).unwrap();
+
+		let mut rx = tailf(read_file, 0);
+		assert!(rx.try_recv().is_err());
+
+		let mut append_file = OpenOptions::new().append(true).open(&file_path).unwrap();
+		writeln!(append_file, "appended").unwrap();
+
+		let recv = rx.recv().await;
+		if let Some(TailEvent::Line(l)) = recv {
+			assert_eq!("appended\n".to_string(), l);
+		} else {
+			unreachable!("expect a line event, got {:?}", recv)
+		}
+	}
+
 	#[tokio::test]
 	async fn test_tailf_with_data() {
 		let dir = tempfile::tempdir().unwrap();
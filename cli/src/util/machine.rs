@@ -10,24 +10,38 @@ use std::{
 };
 use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
 
-pub fn process_at_path_exists(pid: u32, name: &Path) -> bool {
-	let mut sys = System::new();
-	let pid = Pid::from_u32(pid);
-	if !sys.refresh_process(pid) {
-		return false;
-	}
+use crate::log;
 
-	let name_str = format!("{}", name.display());
-	if let Some(process) = sys.process(pid) {
-		for cmd in process.cmd() {
-			if cmd.contains(&name_str) {
-				return true;
-			}
-		}
-	}
+/// Gets whether the process with the PID is running from the path, which is
+/// usually an executable or script in an installation directory.
+pub fn process_at_path_exists(log: &log::Logger, pid: u32, path: &Path) -> bool {
+	process_at_path_exists_in(&SystemProcessInfo, log, pid, path)
+}
 
-	false
+fn process_at_path_exists_in(
+	processes: &dyn ProcessList,
+	log: &log::Logger,
+	pid: u32,
+	path: &Path,
+) -> bool {
+	let target = ResolvedPath::new(path);
+	let process = match processes.process(pid) {
+		Some(p) => p,
+		None => return false,
+	};
+
+	let matched = target.matches(&process);
+	trace!(
+		log,
+		"Process {} ({}) {} {}",
+		pid,
+		describe_exe(&process),
+		if matched { "matches" } else { "does not match" },
+		target.resolved
+	);
+	matched
 }
+
 pub fn process_exists(pid: u32) -> bool {
 	let mut sys = System::new();
 	sys.refresh_process(Pid::from_u32(pid))
@@ -45,6 +59,60 @@ pub fn kill_pid(pid: u32) -> bool {
 	}
 }
 
+/// Identifies a process more precisely than its PID, which the OS may give
+/// to a new process once the old one exits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessIdentity {
+	/// Seconds since the epoch at which the process started.
+	pub start_time: u64,
+	pub exe: Option<PathBuf>,
+}
+
+/// Looks up and terminates running processes, abstracted so that tests can
+/// simulate PIDs being reused. Shared across the awaits of singleton futures,
+/// so it must be Sync.
+pub trait ProcessInfo: Send + Sync {
+	/// Gets the identity of the process with the PID, or None if there's no
+	/// such process.
+	fn identity(&self, pid: u32) -> Option<ProcessIdentity>;
+
+	/// Forcefully terminates the process, returning whether it was signalled.
+	fn terminate(&self, pid: u32) -> bool;
+}
+
+pub struct SystemProcessInfo;
+
+impl ProcessInfo for SystemProcessInfo {
+	fn identity(&self, pid: u32) -> Option<ProcessIdentity> {
+		let mut sys = System::new();
+		let pid = Pid::from_u32(pid);
+		if !sys.refresh_process(pid) {
+			return None;
+		}
+
+		sys.process(pid).map(|p| ProcessIdentity {
+			start_time: p.start_time(),
+			exe: Some(p.exe())
+				.filter(|e| !e.as_os_str().is_empty())
+				.map(strip_deleted_suffix),
+		})
+	}
+
+	fn terminate(&self, pid: u32) -> bool {
+		kill_pid(pid)
+	}
+}
+
+/// Linux reports executables that were replaced, as by an update, with a
+/// " (deleted)" suffix. The process is still the same one.
+fn strip_deleted_suffix(exe: &Path) -> PathBuf {
+	let s = exe.to_string_lossy();
+	match s.strip_suffix(" (deleted)") {
+		Some(s) => PathBuf::from(s),
+		None => exe.to_owned(),
+	}
+}
+
 pub async fn wait_until_process_exits(pid: Pid, poll_ms: u64) {
 	let mut s = System::new();
 	let duration = Duration::from_millis(poll_ms);
@@ -53,20 +121,156 @@ pub async fn wait_until_process_exits(pid: Pid, poll_ms: u64) {
 	}
 }
 
-pub fn find_running_process(name: &Path) -> Option<u32> {
-	let mut sys = System::new();
-	sys.refresh_processes();
+/// Finds a process running from the path, as by `process_at_path_exists`.
+pub fn find_running_process(log: &log::Logger, path: &Path) -> Option<u32> {
+	find_running_process_in(&SystemProcessInfo, log, path)
+}
+
+fn find_running_process_in(
+	processes: &dyn ProcessList,
+	log: &log::Logger,
+	path: &Path,
+) -> Option<u32> {
+	let target = ResolvedPath::new(path);
+	let all = processes.processes();
+	if let Some(process) = all.iter().find(|p| target.matches(p)) {
+		trace!(
+			log,
+			"Process {} ({}) matches {}",
+			process.pid,
+			describe_exe(process),
+			target.resolved
+		);
+		return Some(process.pid);
+	}
 
-	let name_str = format!("{}", name.display());
+	trace!(
+		log,
+		"None of {} processes match {} ({} executables could not be read)",
+		all.len(),
+		target.resolved,
+		all.iter().filter(|p| p.exe.is_none()).count()
+	);
+	None
+}
+
+/// A running process, as needed to tell where it runs from.
+#[derive(Debug, Clone)]
+pub struct ProcessEntry {
+	pub pid: u32,
+	/// The process executable. It's None if it can't be read, as for other
+	/// users' processes.
+	pub exe: Option<PathBuf>,
+	pub cmd: Vec<String>,
+}
+
+/// Lists running processes, abstracted so that tests can provide their own.
+pub trait ProcessList {
+	fn process(&self, pid: u32) -> Option<ProcessEntry>;
+
+	fn processes(&self) -> Vec<ProcessEntry>;
+}
+
+impl ProcessList for SystemProcessInfo {
+	fn process(&self, pid: u32) -> Option<ProcessEntry> {
+		let mut sys = System::new();
+		let pid = Pid::from_u32(pid);
+		if !sys.refresh_process(pid) {
+			return None;
+		}
+
+		sys.process(pid).map(to_process_entry)
+	}
+
+	fn processes(&self) -> Vec<ProcessEntry> {
+		let mut sys = System::new();
+		sys.refresh_processes();
+		sys.processes().values().map(to_process_entry).collect()
+	}
+}
+
+fn to_process_entry(p: &sysinfo::Process) -> ProcessEntry {
+	ProcessEntry {
+		pid: p.pid().as_u32(),
+		// sysinfo reads /proc/<pid>/exe on Linux and QueryFullProcessImageName
+		// on Windows, leaving the path empty if it's denied access
+		exe: Some(p.exe())
+			.filter(|e| !e.as_os_str().is_empty())
+			.map(strip_deleted_suffix),
+		cmd: p.cmd().to_vec(),
+	}
+}
+
+fn describe_exe(process: &ProcessEntry) -> String {
+	match &process.exe {
+		Some(exe) => normalize_path(exe),
+		None => "executable unknown".to_string(),
+	}
+}
+
+/// A path resolved through any symlinks, for comparing to where processes
+/// run from.
+struct ResolvedPath {
+	original: String,
+	resolved: String,
+}
 
-	for (pid, process) in sys.processes() {
-		for cmd in process.cmd() {
-			if cmd.contains(&name_str) {
-				return Some(pid.as_u32());
+impl ResolvedPath {
+	fn new(path: &Path) -> Self {
+		Self {
+			original: case_fold(path.display().to_string()),
+			resolved: normalize_path(path),
+		}
+	}
+
+	/// Gets whether the process's executable is the path, or its command line
+	/// refers to it, as when it's a script run by an interpreter.
+	fn matches(&self, process: &ProcessEntry) -> bool {
+		if let Some(exe) = &process.exe {
+			if normalize_path(exe) == self.resolved {
+				return true;
 			}
 		}
+
+		process.cmd.iter().any(|arg| {
+			let folded = case_fold(arg.clone());
+			if folded.contains(&self.original) || folded.contains(&self.resolved) {
+				return true;
+			}
+
+			let arg = Path::new(arg);
+			arg.is_absolute() && normalize_path(arg) == self.resolved
+		})
 	}
-	None
+}
+
+/// Resolves symlinks in the path and normalizes it for comparison. Paths that
+/// can't be resolved, because they don't exist or can't be accessed, are
+/// compared as given.
+fn normalize_path(path: &Path) -> String {
+	let resolved = std::fs::canonicalize(path)
+		.map(|p| p.display().to_string())
+		.unwrap_or_else(|_| path.display().to_string());
+
+	// canonicalize gives verbatim paths on Windows, which processes don't use
+	#[cfg(windows)]
+	let resolved = match resolved.strip_prefix(r"\\?\") {
+		Some(s) => s.to_string(),
+		None => resolved,
+	};
+
+	case_fold(resolved)
+}
+
+/// Windows paths are case-insensitive.
+#[cfg(windows)]
+fn case_fold(s: String) -> String {
+	s.to_lowercase()
+}
+
+#[cfg(not(windows))]
+fn case_fold(s: String) -> String {
+	s
 }
 
 pub async fn wait_until_exe_deleted(current_exe: &Path, poll_ms: u64) {
@@ -116,6 +320,85 @@ fn canonical_exe_inner(
 	Ok(exe)
 }
 
+/// Gets the home directory of the given user from the password database.
+#[cfg(unix)]
+pub fn get_user_home_dir(user: &str) -> Option<PathBuf> {
+	use std::{
+		ffi::{CStr, CString},
+		os::unix::ffi::OsStrExt,
+	};
+
+	let name = CString::new(user).ok()?;
+	// safety: getpwnam returns a pointer into static storage, which we copy
+	// out of before making any other calls that may overwrite it.
+	unsafe {
+		let pw = libc::getpwnam(name.as_ptr());
+		if pw.is_null() || (*pw).pw_dir.is_null() {
+			return None;
+		}
+
+		let dir = CStr::from_ptr((*pw).pw_dir);
+		Some(PathBuf::from(std::ffi::OsStr::from_bytes(dir.to_bytes())))
+	}
+}
+
+/// System-level services are not supported on Windows, so user lookups are
+/// not needed there.
+#[cfg(windows)]
+pub fn get_user_home_dir(_user: &str) -> Option<PathBuf> {
+	None
+}
+
+/// Gets an identifier that's stable for this machine, used as a secret when
+/// deriving keys for data that should only be readable on this machine.
+/// Falls back to the hostname if no machine ID is available.
+pub fn get_machine_secret() -> Vec<u8> {
+	match get_machine_id() {
+		Some(id) if !id.trim().is_empty() => id.trim().as_bytes().to_vec(),
+		_ => gethostname::gethostname()
+			.to_string_lossy()
+			.as_bytes()
+			.to_vec(),
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn get_machine_id() -> Option<String> {
+	["/etc/machine-id", "/var/lib/dbus/machine-id"]
+		.iter()
+		.find_map(|p| std::fs::read_to_string(p).ok())
+}
+
+#[cfg(target_os = "macos")]
+fn get_machine_id() -> Option<String> {
+	let output = std::process::Command::new("ioreg")
+		.args(["-rd1", "-c", "IOPlatformExpertDevice"])
+		.output()
+		.ok()?;
+
+	String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.find(|l| l.contains("\"IOPlatformUUID\""))
+		.and_then(|l| l.split('"').nth(3))
+		.map(|s| s.to_string())
+}
+
+#[cfg(windows)]
+fn get_machine_id() -> Option<String> {
+	use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
+
+	RegKey::predef(HKEY_LOCAL_MACHINE)
+		.open_subkey("SOFTWARE\\Microsoft\\Cryptography")
+		.ok()?
+		.get_value("MachineGuid")
+		.ok()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn get_machine_id() -> Option<String> {
+	None
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -144,6 +427,101 @@ mod tests {
 		assert_eq!(exe, PathBuf::from("/not-in-snap"));
 	}
 
+	struct FakeProcesses(Vec<ProcessEntry>);
+
+	impl ProcessList for FakeProcesses {
+		fn process(&self, pid: u32) -> Option<ProcessEntry> {
+			self.0.iter().find(|p| p.pid == pid).cloned()
+		}
+
+		fn processes(&self) -> Vec<ProcessEntry> {
+			self.0.clone()
+		}
+	}
+
+	fn entry(pid: u32, exe: Option<&Path>, cmd: &[&str]) -> ProcessEntry {
+		ProcessEntry {
+			pid,
+			exe: exe.map(|e| e.to_owned()),
+			cmd: cmd.iter().map(|s| s.to_string()).collect(),
+		}
+	}
+
+	/// Makes an installation at `real/bin/server` reachable through a
+	/// symlinked `link` directory, as package managers do.
+	#[cfg(unix)]
+	fn symlinked_install() -> (tempfile::TempDir, PathBuf, PathBuf) {
+		let dir = tempfile::tempdir().unwrap();
+		let real = dir.path().join("real");
+		std::fs::create_dir_all(real.join("bin")).unwrap();
+		std::fs::write(real.join("bin").join("server"), "").unwrap();
+		std::os::unix::fs::symlink(&real, dir.path().join("link")).unwrap();
+
+		let real_exe = real.join("bin").join("server");
+		let link_exe = dir.path().join("link").join("bin").join("server");
+		(dir, real_exe, link_exe)
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_matches_exe_through_symlink() {
+		let (_dir, real_exe, link_exe) = symlinked_install();
+		let processes = FakeProcesses(vec![
+			entry(1, Some(Path::new("/usr/bin/other")), &["other"]),
+			entry(2, Some(&real_exe), &["server", "--start"]),
+		]);
+
+		let log = log::Logger::test();
+		assert!(process_at_path_exists_in(&processes, &log, 2, &link_exe));
+		assert!(!process_at_path_exists_in(&processes, &log, 1, &link_exe));
+		assert_eq!(
+			find_running_process_in(&processes, &log, &link_exe),
+			Some(2)
+		);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_matches_script_argument_through_symlink() {
+		let (_dir, real_exe, link_exe) = symlinked_install();
+		let processes = FakeProcesses(vec![entry(
+			3,
+			Some(Path::new("/bin/sh")),
+			&["/bin/sh", link_exe.to_str().unwrap(), "--port", "0"],
+		)]);
+
+		let log = log::Logger::test();
+		assert_eq!(
+			find_running_process_in(&processes, &log, &real_exe),
+			Some(3)
+		);
+	}
+
+	#[test]
+	fn test_unreadable_processes_do_not_match() {
+		let dir = tempfile::tempdir().unwrap();
+		let exe = dir.path().join("server");
+		std::fs::write(&exe, "").unwrap();
+		let processes = FakeProcesses(vec![
+			entry(4, None, &[]),
+			entry(5, None, &["/usr/bin/other", "--flag"]),
+		]);
+
+		let log = log::Logger::test();
+		assert!(!process_at_path_exists_in(&processes, &log, 4, &exe));
+		assert!(!process_at_path_exists_in(&processes, &log, 6, &exe));
+		assert_eq!(find_running_process_in(&processes, &log, &exe), None);
+	}
+
+	#[test]
+	fn test_matches_missing_path_as_given() {
+		let exe = Path::new("/does/not/exist/server");
+		let processes = FakeProcesses(vec![entry(7, None, &["/does/not/exist/server"])]);
+
+		let log = log::Logger::test();
+		assert!(process_at_path_exists_in(&processes, &log, 7, exe));
+	}
+
 	#[test]
 	fn test_canonical_exe_not_in_snap2() {
 		let exe = canonical_exe_inner(Ok(PathBuf::from("/not-in-snap")), None, None).unwrap();
@@ -3,6 +3,319 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use std::{collections::HashMap, fmt};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// Files the distro is described in, in order of precedence.
+#[cfg(target_os = "linux")]
+const OS_RELEASE_PATHS: [&str; 2] = ["/etc/os-release", "/usr/lib/os-release"];
+
+lazy_static! {
+	static ref HOST_INFO: HostInfo = HostInfo::collect();
+}
+
+/// Facts about the machine the CLI runs on, shown in diagnostics such as
+/// `tunnel doctor` and `status --verbose`. Nothing that identifies the
+/// machine or its user, such as the hostname, is included.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostInfo {
+	pub os: String,
+	pub arch: String,
+	/// Kernel release on Unix, or the version number on Windows.
+	pub kernel: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub distro: Option<Distro>,
+	pub wsl: bool,
+	/// Kind of container the CLI runs in, such as "docker" or "kubernetes".
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub container: Option<String>,
+	pub systemd: bool,
+	/// Graphical session type, such as "wayland" or "x11".
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub session_type: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub windows: Option<WindowsInfo>,
+}
+
+/// Linux distribution, from os-release.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Distro {
+	pub id: Option<String>,
+	pub name: Option<String>,
+	pub version_id: Option<String>,
+	pub pretty_name: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WindowsInfo {
+	pub product_name: Option<String>,
+	pub build: Option<u32>,
+	pub server: bool,
+}
+
+/// Gets facts about the machine. They're collected once per process, since
+/// they don't change while it runs.
+pub fn collect_host_info() -> HostInfo {
+	HOST_INFO.clone()
+}
+
+impl HostInfo {
+	fn collect() -> Self {
+		#[cfg(target_os = "linux")]
+		let (distro, wsl, container, systemd, session_type) = (
+			read_distro(),
+			crate::tunnels::is_wsl(),
+			detect_container(),
+			std::path::Path::new("/run/systemd/system").is_dir(),
+			session_type(),
+		);
+		#[cfg(not(target_os = "linux"))]
+		let (distro, wsl, container, systemd, session_type) = (None, false, None, false, None);
+
+		#[cfg(windows)]
+		let windows = read_windows_info();
+		#[cfg(not(windows))]
+		let windows = None;
+
+		HostInfo {
+			os: std::env::consts::OS.to_string(),
+			arch: std::env::consts::ARCH.to_string(),
+			kernel: os_release().ok(),
+			distro,
+			wsl,
+			container,
+			systemd,
+			session_type,
+			windows,
+		}
+	}
+}
+
+impl fmt::Display for HostInfo {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let described = self
+			.distro
+			.as_ref()
+			.and_then(|d| d.describe())
+			.or_else(|| self.windows.as_ref().and_then(|w| w.product_name.clone()));
+		if let Some(d) = described {
+			write!(f, "{}, ", d)?;
+		}
+
+		write!(f, "{} {}", self.os, self.arch)?;
+		if let Some(w) = &self.windows {
+			if let Some(build) = w.build {
+				write!(f, ", build {}", build)?;
+			}
+			if w.server {
+				write!(f, ", server")?;
+			}
+		}
+		if let Some(k) = &self.kernel {
+			write!(f, ", kernel {}", k)?;
+		}
+		if self.wsl {
+			write!(f, ", WSL")?;
+		}
+		if let Some(c) = &self.container {
+			write!(f, ", in a {} container", c)?;
+		}
+		if self.systemd {
+			write!(f, ", systemd")?;
+		}
+		if let Some(s) = &self.session_type {
+			write!(f, ", {} session", s)?;
+		}
+
+		Ok(())
+	}
+}
+
+impl Distro {
+	/// Gets the distro from parsed os-release fields, if any describe it.
+	pub fn from_os_release(mut fields: HashMap<String, String>) -> Option<Self> {
+		let mut take = |k: &str| fields.remove(k).filter(|v| !v.is_empty());
+		let distro = Distro {
+			id: take("ID"),
+			name: take("NAME"),
+			version_id: take("VERSION_ID"),
+			pretty_name: take("PRETTY_NAME"),
+		};
+
+		if distro == Distro::default() {
+			None
+		} else {
+			Some(distro)
+		}
+	}
+
+	/// Gets a name for people, such as "Ubuntu 22.04.3 LTS".
+	fn describe(&self) -> Option<String> {
+		if let Some(p) = &self.pretty_name {
+			return Some(p.clone());
+		}
+
+		let name = self.name.as_ref().or(self.id.as_ref())?;
+		Some(match &self.version_id {
+			Some(v) => format!("{} {}", name, v),
+			None => name.clone(),
+		})
+	}
+}
+
+/// Parses os-release, which holds shell-style assignments. Values may be
+/// quoted with double or single quotes, or not at all with backslash escapes.
+/// Lines that can't be parsed are skipped rather than failing the whole file,
+/// since some distros write values a shell wouldn't accept.
+pub fn parse_os_release(contents: &str) -> HashMap<String, String> {
+	let mut fields = HashMap::new();
+	for line in contents.lines() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let line = line.strip_prefix("export ").unwrap_or(line);
+		let (key, value) = match line.split_once('=') {
+			Some((k, v)) => (k.trim(), v),
+			None => continue,
+		};
+		if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+			continue;
+		}
+
+		fields.insert(key.to_string(), unquote_shell_value(value));
+	}
+
+	fields
+}
+
+/// Unquotes a shell value. An unterminated quote runs to the end of the line,
+/// and unquoted whitespace followed by a comment ends the value.
+fn unquote_shell_value(value: &str) -> String {
+	let mut out = String::with_capacity(value.len());
+	let mut quote: Option<char> = None;
+	let mut chars = value.trim().chars();
+	while let Some(c) = chars.next() {
+		match (quote, c) {
+			(Some(q), c) if c == q => quote = None,
+			(Some('\''), c) => out.push(c),
+			(Some(_), '\\') => match chars.clone().next() {
+				// only these are escaped within double quotes
+				Some(n @ ('"' | '\\' | '$' | '`')) => {
+					chars.next();
+					out.push(n);
+				}
+				_ => out.push('\\'),
+			},
+			(Some(_), c) => out.push(c),
+			(None, '"' | '\'') => quote = Some(c),
+			(None, '\\') => {
+				if let Some(n) = chars.next() {
+					out.push(n);
+				}
+			}
+			(None, c) if c.is_whitespace() => {
+				let rest = chars.as_str().trim_start();
+				if rest.is_empty() || rest.starts_with('#') {
+					break;
+				}
+				out.push(c);
+			}
+			(None, c) => out.push(c),
+		}
+	}
+
+	out
+}
+
+#[cfg(target_os = "linux")]
+fn read_distro() -> Option<Distro> {
+	OS_RELEASE_PATHS
+		.iter()
+		.find_map(|p| std::fs::read_to_string(p).ok())
+		.and_then(|c| Distro::from_os_release(parse_os_release(&c)))
+}
+
+#[cfg(target_os = "linux")]
+fn detect_container() -> Option<String> {
+	// set by systemd-nspawn, podman, and others that follow systemd's interface
+	if let Some(c) = std::env::var("container").ok().filter(|c| !c.is_empty()) {
+		return Some(c);
+	}
+	if std::path::Path::new("/.dockerenv").exists() {
+		return Some("docker".to_string());
+	}
+	if std::path::Path::new("/run/.containerenv").exists() {
+		return Some("podman".to_string());
+	}
+
+	std::fs::read_to_string("/proc/1/cgroup")
+		.ok()
+		.and_then(|c| container_from_cgroup(&c))
+		.map(|c| c.to_string())
+}
+
+/// Gets the container runtime named in a process' cgroup paths. These only
+/// give a hint on cgroup v1, where the container's path is visible.
+pub fn container_from_cgroup(cgroup: &str) -> Option<&'static str> {
+	const HINTS: [(&str, &str); 5] = [
+		("kubepods", "kubernetes"),
+		("libpod", "podman"),
+		("docker", "docker"),
+		("containerd", "containerd"),
+		("lxc", "lxc"),
+	];
+
+	cgroup
+		.lines()
+		.filter_map(|l| l.splitn(3, ':').nth(2))
+		.find_map(|path| {
+			HINTS
+				.iter()
+				.find(|(h, _)| path.contains(h))
+				.map(|(_, c)| *c)
+		})
+}
+
+#[cfg(target_os = "linux")]
+fn session_type() -> Option<String> {
+	let var = |k: &str| std::env::var(k).ok().filter(|v| !v.is_empty());
+	if let Some(t) = var("XDG_SESSION_TYPE") {
+		return Some(t.to_lowercase());
+	}
+
+	if var("WAYLAND_DISPLAY").is_some() {
+		Some("wayland".to_string())
+	} else if var("DISPLAY").is_some() {
+		Some("x11".to_string())
+	} else {
+		None
+	}
+}
+
+#[cfg(windows)]
+fn read_windows_info() -> Option<WindowsInfo> {
+	use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
+
+	let key = RegKey::predef(HKEY_LOCAL_MACHINE)
+		.open_subkey(r"SOFTWARE\Microsoft\Windows NT\CurrentVersion")
+		.ok()?;
+	let build: Option<String> = key.get_value("CurrentBuildNumber").ok();
+	let installation: Option<String> = key.get_value("InstallationType").ok();
+
+	Some(WindowsInfo {
+		product_name: key.get_value("ProductName").ok(),
+		build: build.and_then(|b| b.parse().ok()),
+		// "Server" or "Server Core", as opposed to "Client"
+		server: installation
+			.map(|i| i.starts_with("Server"))
+			.unwrap_or(false),
+	})
+}
+
 #[cfg(windows)]
 pub fn os_release() -> Result<String, std::io::Error> {
 	// The windows API *had* nice GetVersionEx/A APIs, but these were deprecated
@@ -37,3 +350,270 @@ pub fn os_release() -> Result<String, std::io::Error> {
 		Ok(c_str.to_string_lossy().into_owned())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const UBUNTU_22_04: &str = r#"PRETTY_NAME="Ubuntu 22.04.3 LTS"
+NAME="Ubuntu"
+VERSION_ID="22.04"
+VERSION="22.04.3 LTS (Jammy Jellyfish)"
+VERSION_CODENAME=jammy
+ID=ubuntu
+ID_LIKE=debian
+HOME_URL="https://www.ubuntu.com/"
+SUPPORT_URL="https://help.ubuntu.com/"
+BUG_REPORT_URL="https://bugs.launchpad.net/ubuntu/"
+PRIVACY_POLICY_URL="https://www.ubuntu.com/legal/terms-and-policies/privacy-policy"
+UBUNTU_CODENAME=jammy
+"#;
+
+	const DEBIAN_12: &str = r#"PRETTY_NAME="Debian GNU/Linux 12 (bookworm)"
+NAME="Debian GNU/Linux"
+VERSION_ID="12"
+VERSION="12 (bookworm)"
+VERSION_CODENAME=bookworm
+ID=debian
+HOME_URL="https://www.debian.org/"
+SUPPORT_URL="https://www.debian.org/support"
+BUG_REPORT_URL="https://bugs.debian.org/"
+"#;
+
+	const FEDORA_39: &str = r#"NAME="Fedora Linux"
+VERSION="39 (Workstation Edition)"
+ID=fedora
+VERSION_ID=39
+VERSION_CODENAME=""
+PLATFORM_ID="platform:f39"
+PRETTY_NAME="Fedora Linux 39 (Workstation Edition)"
+ANSI_COLOR="0;38;2;60;110;180"
+LOGO=fedora-logo-icon
+CPE_NAME="cpe:/o:fedoraproject:fedora:39"
+DEFAULT_HOSTNAME="fedora"
+HOME_URL="https://fedoraproject.org/"
+SUPPORT_END=2024-11-12
+VARIANT="Workstation Edition"
+VARIANT_ID=workstation
+"#;
+
+	const ALPINE_3_19: &str = "NAME=\"Alpine Linux\"
+ID=alpine
+VERSION_ID=3.19.1
+PRETTY_NAME=\"Alpine Linux v3.19\"
+HOME_URL=\"https://alpinelinux.org/\"
+BUG_REPORT_URL=\"https://gitlab.alpinelinux.org/alpine/aports/-/issues\"
+";
+
+	const ARCH: &str = r#"NAME="Arch Linux"
+PRETTY_NAME="Arch Linux"
+ID=arch
+BUILD_ID=rolling
+ANSI_COLOR="38;2;23;147;209"
+HOME_URL="https://archlinux.org/"
+LOGO=archlinux-logo
+"#;
+
+	const CENTOS_7: &str = r#"NAME="CentOS Linux"
+VERSION="7 (Core)"
+ID="centos"
+ID_LIKE="rhel fedora"
+VERSION_ID="7"
+PRETTY_NAME="CentOS Linux 7 (Core)"
+ANSI_COLOR="0;31"
+CPE_NAME="cpe:/o:centos:centos:7"
+HOME_URL="https://www.centos.org/"
+
+CENTOS_MANTISBT_PROJECT="CentOS-7"
+CENTOS_MANTISBT_PROJECT_VERSION="7"
+REDHAT_SUPPORT_PRODUCT="centos"
+REDHAT_SUPPORT_PRODUCT_VERSION="7"
+"#;
+
+	const AMAZON_LINUX_2: &str = r#"NAME="Amazon Linux"
+VERSION="2"
+ID="amzn"
+ID_LIKE="centos rhel fedora"
+VERSION_ID="2"
+PRETTY_NAME="Amazon Linux 2"
+ANSI_COLOR="0;33"
+CPE_NAME="cpe:2.3:o:amazon:amazon_linux:2"
+HOME_URL="https://amazonlinux.com/"
+"#;
+
+	const OPENSUSE_TUMBLEWEED: &str = r#"NAME="openSUSE Tumbleweed"
+# VERSION="20240101"
+ID="opensuse-tumbleweed"
+ID_LIKE="opensuse suse"
+VERSION_ID="20240101"
+PRETTY_NAME="openSUSE Tumbleweed"
+ANSI_COLOR="0;32"
+CPE_NAME="cpe:/o:opensuse:tumbleweed:20240101"
+"#;
+
+	const NIXOS: &str = "ANSI_COLOR=\"1;34\"
+BUG_REPORT_URL=\"https://github.com/NixOS/nixpkgs/issues\"
+BUILD_ID=\"23.11.20240115.b8dd8be\"
+DOCUMENTATION_URL=\"https://nixos.org/learn.html\"
+HOME_URL=\"https://nixos.org/\"
+ID=nixos
+LOGO=\"nix-snowflake\"
+NAME=NixOS
+PRETTY_NAME=\"NixOS 23.11 (Tapir)\"
+VERSION=\"23.11 (Tapir)\"
+VERSION_CODENAME=tapir
+VERSION_ID=\"23.11\"
+";
+
+	#[test]
+	fn test_parses_os_release_corpus() {
+		let cases = [
+			(UBUNTU_22_04, "ubuntu", Some("22.04"), "Ubuntu 22.04.3 LTS"),
+			(
+				DEBIAN_12,
+				"debian",
+				Some("12"),
+				"Debian GNU/Linux 12 (bookworm)",
+			),
+			(
+				FEDORA_39,
+				"fedora",
+				Some("39"),
+				"Fedora Linux 39 (Workstation Edition)",
+			),
+			(ALPINE_3_19, "alpine", Some("3.19.1"), "Alpine Linux v3.19"),
+			(ARCH, "arch", None, "Arch Linux"),
+			(CENTOS_7, "centos", Some("7"), "CentOS Linux 7 (Core)"),
+			(AMAZON_LINUX_2, "amzn", Some("2"), "Amazon Linux 2"),
+			(
+				OPENSUSE_TUMBLEWEED,
+				"opensuse-tumbleweed",
+				Some("20240101"),
+				"openSUSE Tumbleweed",
+			),
+			(NIXOS, "nixos", Some("23.11"), "NixOS 23.11 (Tapir)"),
+		];
+
+		for (contents, id, version_id, described) in cases {
+			let distro = Distro::from_os_release(parse_os_release(contents)).unwrap();
+			assert_eq!(distro.id.as_deref(), Some(id));
+			assert_eq!(distro.version_id.as_deref(), version_id, "{}", id);
+			assert_eq!(distro.describe().as_deref(), Some(described));
+		}
+
+		// the commented out line isn't read
+		let suse = parse_os_release(OPENSUSE_TUMBLEWEED);
+		assert!(!suse.contains_key("VERSION"));
+		assert_eq!(suse["ID_LIKE"], "opensuse suse");
+		assert_eq!(parse_os_release(FEDORA_39)["VERSION_CODENAME"], "");
+	}
+
+	#[test]
+	fn test_parses_odd_quoting() {
+		let fields = parse_os_release(
+			"NAME='Single Quoted'\r\n\
+			 VERSION=\"with \\\"escaped\\\" quotes and \\$dollar\"\r\n\
+			 ID=unquoted\\ space\n\
+			 BUILD_ID=\"back\\slash\"\n\
+			 VARIANT=\"Trailing\" # comment\n\
+			 VARIANT_ID=two words\n\
+			 PRETTY_NAME=\"never closed\n\
+			 not a field\n\
+			 BAD KEY=1\n\
+			 export LOGO=exported\n\
+			 =no key\n\
+			 EMPTY=\n",
+		);
+
+		assert_eq!(fields["NAME"], "Single Quoted");
+		assert_eq!(fields["VERSION"], "with \"escaped\" quotes and $dollar");
+		assert_eq!(fields["ID"], "unquoted space");
+		assert_eq!(fields["BUILD_ID"], "back\\slash");
+		assert_eq!(fields["VARIANT"], "Trailing");
+		assert_eq!(fields["VARIANT_ID"], "two words");
+		assert_eq!(fields["PRETTY_NAME"], "never closed");
+		assert_eq!(fields["LOGO"], "exported");
+		assert_eq!(fields["EMPTY"], "");
+		assert_eq!(fields.len(), 9);
+	}
+
+	#[test]
+	fn test_missing_os_release() {
+		assert!(Distro::from_os_release(parse_os_release("")).is_none());
+		assert!(Distro::from_os_release(parse_os_release("ID=\nHOME_URL=x\n")).is_none());
+
+		let distro = Distro::from_os_release(parse_os_release("NAME=Custom\n")).unwrap();
+		assert_eq!(distro.describe().as_deref(), Some("Custom"));
+	}
+
+	#[test]
+	fn test_container_from_cgroup() {
+		assert_eq!(
+			container_from_cgroup(
+				"12:pids:/docker/3601745b3bd54d9780436faa5f0e4f72bb46231663bb99a6bb892764917832c2\n\
+				 11:cpuset:/docker/3601745b3bd5\n"
+			),
+			Some("docker")
+		);
+		assert_eq!(
+			container_from_cgroup("1:name=systemd:/kubepods/besteffort/pod1234/abcd\n"),
+			Some("kubernetes")
+		);
+		assert_eq!(
+			container_from_cgroup("0::/machine.slice/libpod-abcd.scope\n"),
+			Some("podman")
+		);
+		assert_eq!(container_from_cgroup("0::/\n"), None);
+		assert_eq!(
+			container_from_cgroup("0::/user.slice/user-1000.slice/session-2.scope\n"),
+			None
+		);
+		assert_eq!(container_from_cgroup(""), None);
+	}
+
+	#[test]
+	fn test_summary() {
+		let host = HostInfo {
+			os: "linux".to_string(),
+			arch: "x86_64".to_string(),
+			kernel: Some("5.15.133.1-microsoft-standard-WSL2".to_string()),
+			distro: Distro::from_os_release(parse_os_release(UBUNTU_22_04)),
+			wsl: true,
+			container: Some("docker".to_string()),
+			systemd: true,
+			session_type: Some("wayland".to_string()),
+			windows: None,
+		};
+		assert_eq!(
+			host.to_string(),
+			"Ubuntu 22.04.3 LTS, linux x86_64, kernel 5.15.133.1-microsoft-standard-WSL2, WSL, in a docker container, systemd, wayland session"
+		);
+
+		let host = HostInfo {
+			os: "windows".to_string(),
+			arch: "x86_64".to_string(),
+			kernel: Some("10.0.20348".to_string()),
+			windows: Some(WindowsInfo {
+				product_name: Some("Windows Server 2022 Datacenter".to_string()),
+				build: Some(20348),
+				server: true,
+			}),
+			..Default::default()
+		};
+		assert_eq!(
+			host.to_string(),
+			"Windows Server 2022 Datacenter, windows x86_64, build 20348, server, kernel 10.0.20348"
+		);
+
+		let json = serde_json::to_value(&host).unwrap();
+		assert_eq!(json["windows"]["build"], 20348);
+		assert!(json.get("distro").is_none());
+	}
+
+	#[test]
+	fn test_collect_host_info() {
+		let host = collect_host_info();
+		assert_eq!(host.os, std::env::consts::OS);
+		assert_eq!(host, collect_host_info());
+	}
+}
@@ -0,0 +1,394 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	io,
+	path::{Path, PathBuf},
+};
+
+use hyper::header::CONTENT_LENGTH;
+
+use super::{errors::CodeError, http::SimpleResponse};
+
+/// A server extracts to about this many times the size of its archive.
+const EXTRACTED_SIZE_RATIO: u64 = 4;
+/// Archive size assumed when the download doesn't give its length.
+const DEFAULT_ARCHIVE_SIZE: u64 = 100 * 1024 * 1024;
+/// Available memory below which the server is likely to be killed, or too
+/// slow to use, once a client connects.
+const MIN_AVAILABLE_MEMORY: u64 = 512 * 1024 * 1024;
+
+/// Space on the filesystem that contains a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskSpace {
+	/// Bytes that the user may write.
+	pub available: u64,
+	/// Identifies the filesystem, so that paths on the same one are summed.
+	pub filesystem: u64,
+}
+
+/// Reads the space and memory on the machine.
+pub trait PreflightProbe {
+	fn disk_space(&self, path: &Path) -> io::Result<DiskSpace>;
+
+	/// Gets the bytes of memory available for new programs, if known.
+	fn available_memory(&self) -> Option<u64>;
+}
+
+/// Probes the machine the CLI is running on.
+pub struct SystemProbe;
+
+impl PreflightProbe for SystemProbe {
+	#[cfg(unix)]
+	fn disk_space(&self, path: &Path) -> io::Result<DiskSpace> {
+		use std::os::unix::{ffi::OsStrExt, fs::MetadataExt};
+
+		let path = existing_ancestor(path);
+		let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+		let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+		if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		#[allow(clippy::unnecessary_cast)]
+		let space = DiskSpace {
+			available: stat.f_bavail as u64 * stat.f_frsize as u64,
+			filesystem: std::fs::metadata(path)?.dev(),
+		};
+		Ok(space)
+	}
+
+	#[cfg(windows)]
+	fn disk_space(&self, path: &Path) -> io::Result<DiskSpace> {
+		use std::os::windows::ffi::OsStrExt;
+		use winapi::um::fileapi::GetDiskFreeSpaceExW;
+
+		let path = existing_ancestor(path);
+		let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+		let mut available: winapi::shared::ntdef::ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+		let ok = unsafe {
+			GetDiskFreeSpaceExW(
+				wide.as_ptr(),
+				&mut available,
+				std::ptr::null_mut(),
+				std::ptr::null_mut(),
+			)
+		};
+		if ok == 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		// drives are told apart by their root, such as C:\
+		let root = path
+			.components()
+			.next()
+			.map(|c| c.as_os_str().to_string_lossy().to_lowercase())
+			.unwrap_or_default();
+		Ok(DiskSpace {
+			available: unsafe { *available.QuadPart() },
+			filesystem: root
+				.bytes()
+				.fold(0u64, |h, b| h.wrapping_mul(31).wrapping_add(b as u64)),
+		})
+	}
+
+	fn available_memory(&self) -> Option<u64> {
+		#[cfg(target_os = "linux")]
+		return std::fs::read_to_string("/proc/meminfo")
+			.ok()
+			.and_then(|m| parse_meminfo_available(&m));
+		#[cfg(not(target_os = "linux"))]
+		return None;
+	}
+}
+
+/// Gets the path, or its closest parent that exists, since the space of a
+/// directory that's yet to be created is that of its parent.
+fn existing_ancestor(path: &Path) -> &Path {
+	path.ancestors().find(|p| p.exists()).unwrap_or(path)
+}
+
+/// Gets MemAvailable from /proc/meminfo in bytes, or MemFree on kernels
+/// older than 3.14 that don't report it.
+#[allow(dead_code)]
+fn parse_meminfo_available(meminfo: &str) -> Option<u64> {
+	let field = |name: &str| {
+		meminfo.lines().find_map(|l| {
+			let rest = l.strip_prefix(name)?.strip_prefix(':')?;
+			let kb = rest.trim().trim_end_matches("kB").trim();
+			kb.parse::<u64>().ok().map(|kb| kb * 1024)
+		})
+	};
+
+	field("MemAvailable").or_else(|| field("MemFree"))
+}
+
+/// What a server download needs, and where.
+pub struct PreflightNeeds {
+	/// Directory the archive is downloaded into.
+	pub archive_dir: PathBuf,
+	/// Directory the archive is extracted into.
+	pub extract_dir: PathBuf,
+	/// Content-Length of the archive, if it was given.
+	pub archive_size: Option<u64>,
+}
+
+impl PreflightNeeds {
+	/// Needs for downloading the response into `archive_dir` and extracting
+	/// it into `extract_dir`.
+	pub fn for_download(archive_dir: &Path, extract_dir: &Path, response: &SimpleResponse) -> Self {
+		PreflightNeeds {
+			archive_dir: archive_dir.to_path_buf(),
+			extract_dir: extract_dir.to_path_buf(),
+			archive_size: response
+				.headers
+				.get(CONTENT_LENGTH)
+				.and_then(|h| h.to_str().ok())
+				.and_then(|s| s.parse().ok()),
+		}
+	}
+
+	fn archive_bytes(&self) -> u64 {
+		self.archive_size.unwrap_or(DEFAULT_ARCHIVE_SIZE)
+	}
+
+	fn extracted_bytes(&self) -> u64 {
+		self.archive_bytes() * EXTRACTED_SIZE_RATIO
+	}
+}
+
+/// Checks that there's room to download and extract a server, failing with
+/// the path that's short on space if there isn't. Returns warnings, such as
+/// for low memory, that shouldn't stop the download.
+pub fn check(
+	probe: &impl PreflightProbe,
+	needs: &PreflightNeeds,
+) -> Result<Vec<String>, CodeError> {
+	let mut warnings = vec![];
+
+	let archive = probe.disk_space(&needs.archive_dir);
+	let extract = probe.disk_space(&needs.extract_dir);
+	match (archive, extract) {
+		(Ok(a), Ok(e)) if a.filesystem == e.filesystem => {
+			// the archive is only deleted once it's been extracted
+			require_space(
+				&needs.extract_dir,
+				needs.archive_bytes() + needs.extracted_bytes(),
+				e.available,
+			)?;
+		}
+		(archive, extract) => {
+			match archive {
+				Ok(a) => require_space(&needs.archive_dir, needs.archive_bytes(), a.available)?,
+				Err(e) => warnings.push(unknown_space(&needs.archive_dir, e)),
+			}
+			match extract {
+				Ok(e) => require_space(&needs.extract_dir, needs.extracted_bytes(), e.available)?,
+				Err(e) => warnings.push(unknown_space(&needs.extract_dir, e)),
+			}
+		}
+	}
+
+	if let Some(memory) = probe.available_memory() {
+		if memory < MIN_AVAILABLE_MEMORY {
+			warnings.push(format!(
+				"only {} of memory is available, and the server needs about {} to run well",
+				format_size(memory),
+				format_size(MIN_AVAILABLE_MEMORY)
+			));
+		}
+	}
+
+	Ok(warnings)
+}
+
+fn require_space(path: &Path, required: u64, available: u64) -> Result<(), CodeError> {
+	if available < required {
+		return Err(CodeError::InsufficientDiskSpace {
+			path: path.display().to_string(),
+			required: format_size(required),
+			available: format_size(available),
+		});
+	}
+	Ok(())
+}
+
+fn unknown_space(path: &Path, e: io::Error) -> String {
+	format!(
+		"could not check the space available in {}: {}",
+		path.display(),
+		e
+	)
+}
+
+/// Formats bytes in MB, or GB when there are many of them.
+fn format_size(bytes: u64) -> String {
+	const MB: u64 = 1024 * 1024;
+	const GB: u64 = 1024 * MB;
+	if bytes >= 10 * GB {
+		format!("{}GB", bytes / GB)
+	} else if bytes >= GB {
+		format!("{:.1}GB", bytes as f64 / GB as f64)
+	} else {
+		format!("{}MB", bytes / MB)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+
+	const MB: u64 = 1024 * 1024;
+
+	/// Reports space for paths by their first component, so that `/tmp/a`
+	/// and `/home/b` can be put on different filesystems.
+	struct MockProbe {
+		disks: HashMap<&'static str, DiskSpace>,
+		memory: Option<u64>,
+	}
+
+	impl MockProbe {
+		fn new(memory: Option<u64>) -> Self {
+			MockProbe {
+				disks: HashMap::new(),
+				memory,
+			}
+		}
+
+		fn disk(mut self, root: &'static str, filesystem: u64, available: u64) -> Self {
+			self.disks.insert(
+				root,
+				DiskSpace {
+					available,
+					filesystem,
+				},
+			);
+			self
+		}
+	}
+
+	impl PreflightProbe for MockProbe {
+		fn disk_space(&self, path: &Path) -> io::Result<DiskSpace> {
+			let root = path.iter().nth(1).and_then(|r| r.to_str()).unwrap_or("");
+			self.disks
+				.get(root)
+				.copied()
+				.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such disk"))
+		}
+
+		fn available_memory(&self) -> Option<u64> {
+			self.memory
+		}
+	}
+
+	fn needs(archive_size: Option<u64>) -> PreflightNeeds {
+		PreflightNeeds {
+			archive_dir: PathBuf::from("/tmp/download"),
+			extract_dir: PathBuf::from("/home/.vscode-server/cli/servers/Stable-abc.staging"),
+			archive_size,
+		}
+	}
+
+	#[test]
+	fn test_enough_space() {
+		let probe = MockProbe::new(Some(2048 * MB))
+			.disk("tmp", 1, 100 * MB)
+			.disk("home", 2, 300 * MB);
+		assert_eq!(
+			check(&probe, &needs(Some(60 * MB))).unwrap(),
+			Vec::<String>::new()
+		);
+	}
+
+	#[test]
+	fn test_short_on_extract_space() {
+		let probe = MockProbe::new(None)
+			.disk("tmp", 1, 100 * MB)
+			.disk("home", 2, 200 * MB);
+		match check(&probe, &needs(Some(60 * MB))) {
+			Err(CodeError::InsufficientDiskSpace {
+				path,
+				required,
+				available,
+			}) => {
+				assert_eq!(path, "/home/.vscode-server/cli/servers/Stable-abc.staging");
+				assert_eq!(required, "240MB");
+				assert_eq!(available, "200MB");
+			}
+			r => panic!("expected to be short on space, got {:?}", r),
+		}
+	}
+
+	#[test]
+	fn test_same_filesystem_needs_both() {
+		// 299MB is enough for the extracted server, but not with the archive
+		let probe = MockProbe::new(None)
+			.disk("tmp", 1, 299 * MB)
+			.disk("home", 1, 299 * MB);
+		match check(&probe, &needs(Some(60 * MB))) {
+			Err(CodeError::InsufficientDiskSpace { required, .. }) => {
+				assert_eq!(required, "300MB")
+			}
+			r => panic!("expected to be short on space, got {:?}", r),
+		}
+
+		let probe = MockProbe::new(None)
+			.disk("tmp", 1, 300 * MB)
+			.disk("home", 1, 300 * MB);
+		assert!(check(&probe, &needs(Some(60 * MB))).is_ok());
+	}
+
+	#[test]
+	fn test_assumes_size_without_content_length() {
+		let probe = MockProbe::new(None)
+			.disk("tmp", 1, 50 * MB)
+			.disk("home", 2, 1024 * MB);
+		match check(&probe, &needs(None)) {
+			Err(CodeError::InsufficientDiskSpace { path, required, .. }) => {
+				assert_eq!(path, "/tmp/download");
+				assert_eq!(required, "100MB");
+			}
+			r => panic!("expected to be short on space, got {:?}", r),
+		}
+	}
+
+	#[test]
+	fn test_warns_on_low_memory_and_unknown_space() {
+		let probe = MockProbe::new(Some(300 * MB)).disk("home", 2, 1024 * MB);
+		let warnings = check(&probe, &needs(Some(60 * MB))).unwrap();
+		assert_eq!(warnings.len(), 2, "{:?}", warnings);
+		assert!(
+			warnings[0].starts_with("could not check the space available in /tmp/download"),
+			"{}",
+			warnings[0]
+		);
+		assert_eq!(
+			warnings[1],
+			"only 300MB of memory is available, and the server needs about 512MB to run well"
+		);
+	}
+
+	#[test]
+	fn test_parse_meminfo_available() {
+		let meminfo = "MemTotal:        1004892 kB\nMemFree:          101236 kB\nMemAvailable:     524288 kB\nBuffers:           20484 kB\n";
+		assert_eq!(parse_meminfo_available(meminfo), Some(512 * MB));
+
+		// kernels older than 3.14
+		let old = "MemTotal:        1004892 kB\nMemFree:          102400 kB\n";
+		assert_eq!(parse_meminfo_available(old), Some(100 * MB));
+
+		assert_eq!(parse_meminfo_available(""), None);
+	}
+
+	#[test]
+	fn test_format_size() {
+		assert_eq!(format_size(240 * MB), "240MB");
+		assert_eq!(format_size(1536 * MB), "1.5GB");
+		assert_eq!(format_size(20 * 1024 * MB), "20GB");
+	}
+}
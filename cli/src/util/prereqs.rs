@@ -2,20 +2,29 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use std::cmp::Ordering;
+use std::{cmp::Ordering, fmt};
 
 use crate::constants::QUALITYLESS_SERVER_NAME;
+use crate::log;
 use crate::update_service::Platform;
 use lazy_static::lazy_static;
 use regex::bytes::Regex as BinRegex;
 use regex::Regex;
+use serde::Serialize;
 use tokio::fs;
 
 use super::errors::CodeError;
 
 lazy_static! {
-	static ref LDCONFIG_STDC_RE: Regex = Regex::new(r"libstdc\+\+.* => (.+)").unwrap();
-	static ref LDD_VERSION_RE: BinRegex = BinRegex::new(r"^ldd.*(.+)\.(.+)\s").unwrap();
+	static ref LDD_VERSION_RE: BinRegex =
+		BinRegex::new(r"(?m)^ldd\b.*\s\(?([0-9]+)\.([0-9]+)\S*\s*$").unwrap();
+	static ref GETCONF_VERSION_RE: Regex =
+		Regex::new(r"(?i)^\s*glibc\s+([0-9]+)\.([0-9]+)").unwrap();
+	static ref LIBC_BANNER_RE: BinRegex =
+		BinRegex::new(r"GNU C Library[^\n\x00]*?version ([0-9]+)\.([0-9]+)").unwrap();
+	static ref LIBC_SYMBOL_VERSION_RE: BinRegex =
+		BinRegex::new(r"GLIBC_([0-9]+)\.([0-9]+)(?:\.([0-9]+))?").unwrap();
+	static ref MUSL_VERSION_RE: Regex = Regex::new(r"(?m)^Version ([0-9.]+)").unwrap();
 	static ref GENERIC_VERSION_RE: Regex = Regex::new(r"^([0-9]+)\.([0-9]+)$").unwrap();
 	static ref LIBSTD_CXX_VERSION_RE: BinRegex =
 		BinRegex::new(r"GLIBCXX_([0-9]+)\.([0-9]+)(?:\.([0-9]+))?").unwrap();
@@ -23,105 +32,851 @@ lazy_static! {
 	static ref MIN_LEGACY_CXX_VERSION: SimpleSemver = SimpleSemver::new(3, 4, 19);
 	static ref MIN_LDD_VERSION: SimpleSemver = SimpleSemver::new(2, 28, 0);
 	static ref MIN_LEGACY_LDD_VERSION: SimpleSemver = SimpleSemver::new(2, 17, 0);
+	static ref CPU_ARCHITECTURE_RE: Regex =
+		Regex::new(r"(?mi)^CPU architecture\s*:\s*([0-9]+)").unwrap();
+	static ref ARMV6_MODEL_RE: Regex =
+		Regex::new(r"(?mi)^(?:model name|Processor)\s*:.*(?:ARMv6|\(v6l\))").unwrap();
 }
 
+const ELF_MACHINE_X86: u16 = 3;
+const ELF_MACHINE_ARM: u16 = 40;
+const ELF_MACHINE_X86_64: u16 = 62;
+const ELF_MACHINE_AARCH64: u16 = 183;
+
 const NIXOS_TEST_PATH: &str = "/etc/NIXOS";
 
-pub struct PreReqChecker {}
+#[derive(Default)]
+pub struct PreReqChecker {
+	log: Option<log::Logger>,
+	skip: bool,
+	arch: Option<Arch>,
+	assumed_platform: Option<Platform>,
+}
+
+/// The operating system the CLI runs on, as reported by `uname`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostOs {
+	Linux,
+	MacOs,
+	/// FreeBSD, running either its own build or the Linux CLI under its Linux
+	/// emulation.
+	FreeBsd {
+		linux_emulation: bool,
+	},
+	/// illumos, running either its own build or the Linux CLI in an LX zone.
+	Illumos {
+		lx_zone: bool,
+	},
+	Other(String),
+}
+
+impl HostOs {
+	/// Gets whether the server can run on the host.
+	pub fn is_supported(&self) -> bool {
+		matches!(self, HostOs::Linux | HostOs::MacOs)
+	}
+}
+
+impl fmt::Display for HostOs {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			HostOs::Linux => write!(f, "Linux"),
+			HostOs::MacOs => write!(f, "macOS"),
+			HostOs::FreeBsd {
+				linux_emulation: true,
+			} => write!(f, "FreeBSD's Linux emulation"),
+			HostOs::FreeBsd {
+				linux_emulation: false,
+			} => write!(f, "FreeBSD"),
+			HostOs::Illumos { lx_zone: true } => write!(f, "an illumos LX zone"),
+			HostOs::Illumos { lx_zone: false } => write!(f, "illumos"),
+			HostOs::Other(name) => write!(f, "{}", name),
+		}
+	}
+}
+
+/// Fields of `uname`.
+#[derive(Debug, Default, Clone)]
+#[allow(dead_code)]
+struct Uname {
+	sysname: String,
+	release: String,
+	version: String,
+}
+
+/// A CPU architecture that Linux servers are built for.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Arch {
+	X64,
+	Arm64,
+	/// 32-bit ARMv7 or newer, with hardware floating point.
+	Armhf,
+}
+
+impl Arch {
+	/// Gets the architecture the CLI was built for.
+	fn compiled() -> Arch {
+		if cfg!(target_arch = "x86_64") {
+			Arch::X64
+		} else if cfg!(target_arch = "arm") {
+			Arch::Armhf
+		} else {
+			Arch::Arm64
+		}
+	}
+}
+
+impl fmt::Display for Arch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Arch::X64 => write!(f, "x64"),
+			Arch::Arm64 => write!(f, "arm64"),
+			Arch::Armhf => write!(f, "armhf"),
+		}
+	}
+}
+
+/// The class and machine from an ELF header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ElfHeader {
+	is_64_bit: bool,
+	machine: u16,
+}
+
+/// What's used to detect the architecture that programs run as, which may
+/// differ from the kernel's, such as a 32-bit userland on a 64-bit kernel.
+#[derive(Debug, Default)]
+#[allow(dead_code)]
+struct ArchProbe {
+	/// Header of /bin/sh, or of the CLI itself if that can't be read.
+	userland: Option<ElfHeader>,
+	/// Contents of /proc/cpuinfo.
+	cpuinfo: Option<String>,
+}
+
+/// The C library that programs on the machine are run with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Libc {
+	/// glibc, with the version if `ldd` reported it.
+	Glibc(Option<String>),
+	/// musl, as on Alpine, with the version if `ldd` reported it.
+	Musl(Option<String>),
+	/// Neither was found, as on machines that only run static binaries.
+	None,
+}
 
-impl Default for PreReqChecker {
-	fn default() -> Self {
-		Self::new()
+impl fmt::Display for Libc {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Libc::Glibc(Some(v)) => write!(f, "glibc {}", v),
+			Libc::Glibc(None) => write!(f, "glibc"),
+			Libc::Musl(Some(v)) => write!(f, "musl {}", v),
+			Libc::Musl(None) => write!(f, "musl"),
+			Libc::None => write!(f, "no glibc or musl loader (static binaries only)"),
+		}
+	}
+}
+
+/// What was found on the machine to detect its libc from.
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+struct LibcProbe {
+	/// Output of `ldd --version`, which musl prints to stderr.
+	ldd: Option<Vec<u8>>,
+	/// Path of the musl loader, if it exists.
+	musl_loader: Option<&'static str>,
+	/// Path of the glibc loader, if it exists.
+	glibc_loader: Option<&'static str>,
+}
+
+/// How well the machine meets a requirement of the server.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RequirementStatus {
+	Met,
+	/// Only met well enough for the legacy server.
+	Legacy,
+	Unmet,
+}
+
+impl fmt::Display for RequirementStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			RequirementStatus::Met => write!(f, "met"),
+			RequirementStatus::Legacy => write!(f, "met for the legacy server"),
+			RequirementStatus::Unmet => write!(f, "unmet"),
+		}
+	}
+}
+
+/// A requirement of the server, and what was found on the machine.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Requirement {
+	pub name: String,
+	pub required: String,
+	pub found: Option<String>,
+	pub status: RequirementStatus,
+}
+
+impl Requirement {
+	/// Checks a found version against the minimum for the server and the
+	/// legacy server, using `unknown` if no version was found.
+	fn version(
+		name: &str,
+		found: Option<&SimpleSemver>,
+		min: &SimpleSemver,
+		min_legacy: &SimpleSemver,
+		unknown: RequirementStatus,
+	) -> Requirement {
+		let status = match found {
+			Some(v) if v >= min => RequirementStatus::Met,
+			Some(v) if v >= min_legacy => RequirementStatus::Legacy,
+			Some(_) => RequirementStatus::Unmet,
+			None => unknown,
+		};
+
+		Requirement {
+			name: name.to_string(),
+			required: format!(">= {} (>= {} for the legacy server)", min, min_legacy),
+			found: found.map(|v| v.to_string()),
+			status,
+		}
+	}
+}
+
+impl fmt::Display for Requirement {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{} {}, found {}",
+			self.name,
+			self.required,
+			self.found.as_deref().unwrap_or("none")
+		)
 	}
 }
 
 impl PreReqChecker {
 	pub fn new() -> PreReqChecker {
-		PreReqChecker {}
+		PreReqChecker::default()
+	}
+
+	/// Logs what's detected to the logger.
+	pub fn with_log(mut self, log: log::Logger) -> PreReqChecker {
+		self.log = Some(log);
+		self
+	}
+
+	/// Runs the server anyway when requirements aren't met, with a warning.
+	pub fn skip_requirements_check(mut self, skip: bool) -> PreReqChecker {
+		self.skip = skip;
+		self
+	}
+
+	/// Uses the architecture instead of detecting it.
+	pub fn with_arch(mut self, arch: Option<Arch>) -> PreReqChecker {
+		self.arch = arch;
+		self
+	}
+
+	/// Uses the platform without checking anything about the machine.
+	pub fn assume_platform(mut self, platform: Option<Platform>) -> PreReqChecker {
+		self.assumed_platform = platform;
+		self
+	}
+
+	/// Gets the platform from `--assume-platform`, warning that it's unchecked.
+	fn use_assumed_platform(&self) -> Option<Platform> {
+		let platform = self.assumed_platform?;
+		if let Some(log) = &self.log {
+			warning!(
+				log,
+				"Assuming the {} platform from --assume-platform, without checking whether this machine can run it",
+				platform
+			);
+		}
+		Some(platform)
 	}
 
 	#[cfg(not(target_os = "linux"))]
 	pub async fn verify(&self) -> Result<Platform, CodeError> {
-		Platform::env_default().ok_or_else(|| {
-			CodeError::UnsupportedPlatform(format!(
-				"{} {}",
-				std::env::consts::OS,
-				std::env::consts::ARCH
-			))
-		})
+		if let Some(platform) = self.use_assumed_platform() {
+			return Ok(platform);
+		}
+
+		#[cfg(unix)]
+		let unsupported = || match probe_uname() {
+			Some(uname) => unsupported_host(&host_from_uname(&uname), &uname),
+			None => format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+		};
+		#[cfg(not(unix))]
+		let unsupported = || format!("{} {}", std::env::consts::OS, std::env::consts::ARCH);
+
+		let platform =
+			Platform::env_default().ok_or_else(|| CodeError::UnsupportedPlatform(unsupported()))?;
+		if let Some(log) = &self.log {
+			trace!(log, "Detected platform {}", platform);
+			if let Some(arch) = self.arch {
+				warning!(log, "Ignoring --arch {}, which only applies on Linux", arch);
+			}
+		}
+		Ok(platform)
 	}
 
 	#[cfg(target_os = "linux")]
 	pub async fn verify(&self) -> Result<Platform, CodeError> {
-		let (is_nixos, skip_glibc_checks, or_musl) = tokio::join!(
-			check_is_nixos(),
-			skip_requirements_check(),
-			check_musl_interpreter()
-		);
+		if let Some(platform) = self.use_assumed_platform() {
+			return Ok(platform);
+		}
 
-		let (gnu_a, gnu_b) = if !skip_glibc_checks {
-			tokio::join!(check_glibc_version(), check_glibcxx_version())
-		} else {
-			println!("!!! WARNING: Skipping server pre-requisite check !!!");
-			println!("!!! Server stability is not guaranteed. Proceed at your own risk. !!!");
-			// Use the legacy server for #210029
-			(Ok(true), Ok(true))
+		// emulated Linux runs the CLI, but not reliably the server
+		if let Some(uname) = probe_uname() {
+			let host = host_from_uname(&uname);
+			if !host.is_supported() {
+				return Err(CodeError::UnsupportedPlatform(unsupported_host(
+					&host, &uname,
+				)));
+			}
+		}
+
+		let (is_nixos, skip_file, libc) =
+			tokio::join!(check_is_nixos(), skip_requirements_check(), detect_libc());
+		if let Some(log) = &self.log {
+			trace!(log, "Detected {} (nixos: {})", libc, is_nixos);
+		}
+
+		let arch = match self.arch {
+			Some(arch) => Ok(arch),
+			None => detect_arch().await,
 		};
+		if let Some(log) = &self.log {
+			match (&arch, self.arch) {
+				(_, Some(a)) => trace!(log, "Using the {} architecture from --arch", a),
+				(Ok(a), None) => trace!(log, "Detected the {} architecture", a),
+				(Err(found), None) => {
+					trace!(log, "Detected the unsupported {} architecture", found)
+				}
+			}
+		}
 
-		match (&gnu_a, &gnu_b, is_nixos) {
-			(Ok(false), Ok(false), _) | (_, _, true) => {
-				return Ok(if cfg!(target_arch = "x86_64") {
-					Platform::LinuxX64
-				} else if cfg!(target_arch = "arm") {
-					Platform::LinuxARM32
-				} else {
-					Platform::LinuxARM64
-				});
+		let gnu = match libc {
+			Libc::Glibc(_) if !is_nixos => Some(probe_gnu().await),
+			_ => None,
+		};
+		if let (Some(log), Some(gnu)) = (&self.log, &gnu) {
+			for p in &gnu.probes {
+				trace!(log, "Probed {}: {}", p.source, p.output);
 			}
-			(Ok(_), Ok(_), _) => {
-				return Ok(if cfg!(target_arch = "x86_64") {
-					Platform::LinuxX64Legacy
-				} else if cfg!(target_arch = "arm") {
-					Platform::LinuxARM32Legacy
-				} else {
-					Platform::LinuxARM64Legacy
-				});
+			for r in gnu_requirements(gnu) {
+				trace!(log, "Requirement {}", r);
 			}
-			_ => {}
-		};
-
-		if or_musl.is_ok() {
-			return Ok(if cfg!(target_arch = "x86_64") {
-				Platform::LinuxAlpineX64
-			} else {
-				Platform::LinuxAlpineARM64
-			});
 		}
 
-		let mut errors: Vec<String> = vec![];
-		if let Err(e) = gnu_a {
-			errors.push(e);
-		} else if let Err(e) = gnu_b {
-			errors.push(e);
-		}
+		let has_musl_loader = check_musl_interpreter().await.is_ok();
+		let result = match &arch {
+			Ok(arch) => select_platform(*arch, &libc, is_nixos, gnu.as_ref(), has_musl_loader),
+			Err(found) => Err(vec![arch_requirement(found)]),
+		};
+		let fallback = arch.unwrap_or_else(|_| Arch::compiled());
+		self.apply_skip(result, &libc, self.skip || skip_file, fallback)
+	}
+
+	/// Proceeds with the legacy glibc server when requirements aren't met and
+	/// the check is skipped, warning that it's unsupported.
+	#[allow(dead_code)]
+	fn apply_skip(
+		&self,
+		result: Result<Platform, Vec<Requirement>>,
+		libc: &Libc,
+		skip: bool,
+		fallback: Arch,
+	) -> Result<Platform, CodeError> {
+		let unmet = match result {
+			Ok(p) => return Ok(p),
+			Err(unmet) => unmet,
+		};
 
-		if let Err(e) = or_musl {
-			errors.push(e);
+		if skip {
+			eprintln!("!!! WARNING: Skipping server pre-requisite check !!!");
+			eprintln!("!!! Server stability is not guaranteed. Proceed at your own risk. !!!");
+			for r in &unmet {
+				eprintln!("!!!   unmet: {}", r);
+			}
+			if let Some(log) = &self.log {
+				warning!(
+					log,
+					"Skipped the server requirements check, though {} are unmet",
+					unmet
+						.iter()
+						.map(|r| r.to_string())
+						.collect::<Vec<_>>()
+						.join("; ")
+				);
+			}
+			// Use the legacy server for #210029
+			return Ok(gnu_platform(fallback, true));
 		}
 
-		let bullets = errors
+		let mut bullets = unmet
 			.iter()
-			.map(|e| format!("  - {}", e))
+			.map(|r| format!("  - {}", r))
 			.collect::<Vec<String>>()
 			.join("\n");
+		bullets.push_str(&format!("\nDetected {}.", libc));
 
 		Err(CodeError::PrerequisitesFailed {
 			bullets,
 			name: QUALITYLESS_SERVER_NAME,
+			requirements: unmet,
 		})
 	}
 }
 
+/// Gets the server platform from what was detected, or the requirements that
+/// aren't met by any build.
+#[allow(dead_code)]
+fn select_platform(
+	arch: Arch,
+	libc: &Libc,
+	is_nixos: bool,
+	gnu: Option<&GnuProbe>,
+	has_musl_loader: bool,
+) -> Result<Platform, Vec<Requirement>> {
+	match libc {
+		Libc::Musl(_) => musl_platform(arch).ok_or_else(|| vec![musl_build_requirement(arch)]),
+		// nixos doesn't keep libraries in the usual places, see check_is_nixos
+		_ if is_nixos => Ok(gnu_platform(arch, false)),
+		Libc::Glibc(_) => {
+			let reqs = gnu.map(gnu_requirements).unwrap_or_default();
+			if reqs.iter().any(|r| r.status == RequirementStatus::Unmet) {
+				// glibc compatibility layers may be installed next to musl
+				match musl_platform(arch).filter(|_| has_musl_loader) {
+					Some(p) => Ok(p),
+					None => Err(reqs
+						.into_iter()
+						.filter(|r| r.status == RequirementStatus::Unmet)
+						.collect()),
+				}
+			} else {
+				let legacy = reqs.iter().any(|r| r.status == RequirementStatus::Legacy);
+				Ok(gnu_platform(arch, legacy))
+			}
+		}
+		Libc::None => Err(vec![libc_requirement()]),
+	}
+}
+
+#[allow(dead_code)]
+fn musl_build_requirement(arch: Arch) -> Requirement {
+	let platform = musl_platform(arch);
+	Requirement {
+		name: "musl server build".to_string(),
+		required: format!("one for {}", arch),
+		found: platform.map(|p| p.headless()),
+		status: match platform {
+			Some(_) => RequirementStatus::Met,
+			None => RequirementStatus::Unmet,
+		},
+	}
+}
+
+#[allow(dead_code)]
+fn libc_requirement() -> Requirement {
+	Requirement {
+		name: "libc".to_string(),
+		required: "the glibc or musl dynamic loader".to_string(),
+		found: None,
+		status: RequirementStatus::Unmet,
+	}
+}
+
+#[allow(dead_code)]
+fn arch_requirement(found: &str) -> Requirement {
+	Requirement {
+		name: "CPU architecture".to_string(),
+		required: "x64, arm64, or armv7 and newer".to_string(),
+		found: Some(found.to_string()),
+		status: RequirementStatus::Unmet,
+	}
+}
+
+#[cfg(unix)]
+fn probe_uname() -> Option<Uname> {
+	let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+	if unsafe { libc::uname(&mut uts) } != 0 {
+		return None;
+	}
+
+	let field = |f: &[libc::c_char]| {
+		unsafe { std::ffi::CStr::from_ptr(f.as_ptr()) }
+			.to_string_lossy()
+			.to_string()
+	};
+	Some(Uname {
+		sysname: field(&uts.sysname),
+		release: field(&uts.release),
+		version: field(&uts.version),
+	})
+}
+
+/// Gets the host from `uname`. FreeBSD's Linux emulation and illumos LX
+/// zones report "Linux", but give away the actual system in the version.
+#[allow(dead_code)]
+fn host_from_uname(uname: &Uname) -> HostOs {
+	let version = uname.version.to_ascii_lowercase();
+	match uname.sysname.to_ascii_lowercase().as_str() {
+		"linux" if version.contains("freebsd") => HostOs::FreeBsd {
+			linux_emulation: true,
+		},
+		"linux" if version.contains("brandz") => HostOs::Illumos { lx_zone: true },
+		"linux" => HostOs::Linux,
+		"darwin" => HostOs::MacOs,
+		"freebsd" => HostOs::FreeBsd {
+			linux_emulation: false,
+		},
+		"sunos" if version.contains("illumos") => HostOs::Illumos { lx_zone: false },
+		_ => HostOs::Other(uname.sysname.clone()),
+	}
+}
+
+/// Describes why the host is unsupported for `CodeError::UnsupportedPlatform`,
+/// and what can be done about it.
+#[allow(dead_code)]
+fn unsupported_host(host: &HostOs, uname: &Uname) -> String {
+	let mut message = format!(
+		"detected {} ({} {}), where the {} can't run.",
+		host, uname.sysname, uname.release, QUALITYLESS_SERVER_NAME
+	);
+	message.push_str(" Commands that don't run it, such as `tunnel user login` and forwarding this machine's ports through a tunnel, may still work.");
+	match host {
+		HostOs::FreeBsd {
+			linux_emulation: true,
+		} => message.push_str(" The Linux emulation lacks some of the system calls the server uses, see https://docs.freebsd.org/en/books/handbook/linuxemu/."),
+		HostOs::Illumos { lx_zone: true } => message.push_str(" LX zones lack some of the system calls the server uses."),
+		_ => {}
+	}
+	message.push_str(" If you know this machine can run one of the server's builds, pass it with --assume-platform, such as `--assume-platform linux-x64`.");
+	message
+}
+
+/// Gets the glibc server platform for the architecture.
+#[allow(dead_code)]
+fn gnu_platform(arch: Arch, legacy: bool) -> Platform {
+	match (arch, legacy) {
+		(Arch::X64, false) => Platform::LinuxX64,
+		(Arch::Armhf, false) => Platform::LinuxARM32,
+		(Arch::Arm64, false) => Platform::LinuxARM64,
+		(Arch::X64, true) => Platform::LinuxX64Legacy,
+		(Arch::Armhf, true) => Platform::LinuxARM32Legacy,
+		(Arch::Arm64, true) => Platform::LinuxARM64Legacy,
+	}
+}
+
+/// Detects the architecture programs on the machine run as.
+#[cfg(target_os = "linux")]
+async fn detect_arch() -> Result<Arch, String> {
+	let userland = match read_elf_header(std::path::Path::new("/bin/sh")).await {
+		Some(h) => Some(h),
+		None => match std::env::current_exe() {
+			Ok(exe) => read_elf_header(&exe).await,
+			Err(_) => None,
+		},
+	};
+
+	arch_from_probe(&ArchProbe {
+		userland,
+		cpuinfo: fs::read_to_string("/proc/cpuinfo").await.ok(),
+	})
+}
+
+#[cfg(target_os = "linux")]
+async fn read_elf_header(path: &std::path::Path) -> Option<ElfHeader> {
+	use tokio::io::AsyncReadExt;
+
+	let mut bytes = [0u8; 20];
+	let mut file = fs::File::open(path).await.ok()?;
+	file.read_exact(&mut bytes).await.ok()?;
+	parse_elf_header(&bytes)
+}
+
+#[allow(dead_code)]
+fn parse_elf_header(bytes: &[u8]) -> Option<ElfHeader> {
+	if bytes.len() < 20 || &bytes[0..4] != b"\x7fELF" {
+		return None;
+	}
+
+	let machine = [bytes[18], bytes[19]];
+	Some(ElfHeader {
+		is_64_bit: bytes[4] == 2,
+		machine: match bytes[5] {
+			2 => u16::from_be_bytes(machine),
+			_ => u16::from_le_bytes(machine),
+		},
+	})
+}
+
+/// Gets the architecture from the probe, or a description of the
+/// architecture if no server is built for it. The userland is preferred over
+/// the architecture the CLI was built for, since a 64-bit kernel, which can
+/// run the CLI, may have a 32-bit userland that can't run a 64-bit server.
+#[allow(dead_code)]
+fn arch_from_probe(probe: &ArchProbe) -> Result<Arch, String> {
+	let arch = match probe.userland {
+		Some(ElfHeader {
+			machine: ELF_MACHINE_X86_64,
+			is_64_bit: true,
+		}) => Arch::X64,
+		Some(ElfHeader {
+			machine: ELF_MACHINE_AARCH64,
+			is_64_bit: true,
+		}) => Arch::Arm64,
+		Some(ElfHeader {
+			machine: ELF_MACHINE_ARM,
+			..
+		}) => Arch::Armhf,
+		Some(ElfHeader {
+			machine: ELF_MACHINE_X86,
+			..
+		}) => return Err("x86".to_string()),
+		Some(ElfHeader { machine, .. }) => return Err(format!("ELF machine {}", machine)),
+		None => Arch::compiled(),
+	};
+
+	if arch == Arch::Armhf && probe.cpuinfo.as_deref().is_some_and(is_armv6) {
+		return Err("armv6".to_string());
+	}
+
+	Ok(arch)
+}
+
+/// Gets whether the CPU is ARMv6, such as on the first Raspberry Pis. Their
+/// ARM1176 reports "CPU architecture: 7", so the model name is checked, too.
+#[allow(dead_code)]
+fn is_armv6(cpuinfo: &str) -> bool {
+	if ARMV6_MODEL_RE.is_match(cpuinfo) {
+		return true;
+	}
+
+	CPU_ARCHITECTURE_RE
+		.captures(cpuinfo)
+		.and_then(|c| c[1].parse::<u32>().ok())
+		.is_some_and(|v| v < 7)
+}
+
+/// Versions found on a glibc machine.
+#[derive(Debug, Default)]
+#[allow(dead_code)]
+struct GnuProbe {
+	glibc: Option<SimpleSemver>,
+	/// The newest GLIBCXX symbol version in libstdc++, if it was found.
+	glibcxx: Option<SimpleSemver>,
+	/// What each source of the versions gave, for diagnostics.
+	probes: Vec<ProbeOutput>,
+}
+
+/// What a source of a version printed or read, kept for diagnostics.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProbeOutput {
+	/// What was run or read, such as `getconf GNU_LIBC_VERSION`.
+	pub source: String,
+	/// The output, a summary of what was read, or why the probe failed.
+	pub output: String,
+	pub version: Option<String>,
+}
+
+impl ProbeOutput {
+	fn new(
+		source: impl Into<String>,
+		output: impl Into<String>,
+		version: Option<&SimpleSemver>,
+	) -> Self {
+		const MAX_OUTPUT_LEN: usize = 500;
+		let mut output = output.into().trim().to_string();
+		if output.len() > MAX_OUTPUT_LEN {
+			let mut end = MAX_OUTPUT_LEN;
+			while !output.is_char_boundary(end) {
+				end -= 1;
+			}
+			output.truncate(end);
+			output.push_str("...");
+		}
+
+		ProbeOutput {
+			source: source.into(),
+			output,
+			version: version.map(|v| v.to_string()),
+		}
+	}
+}
+
+/// A version from a source, and what the source gave.
+type Probed = (Option<SimpleSemver>, ProbeOutput);
+
+/// Gets the version from the first source that found one, since sources are
+/// ordered from most to least trustworthy, and keeps what each gave.
+#[allow(dead_code)]
+fn combine_probes(probed: Vec<Probed>) -> (Option<SimpleSemver>, Vec<ProbeOutput>) {
+	let version = probed.iter().find_map(|(v, _)| v.clone());
+	(version, probed.into_iter().map(|(_, o)| o).collect())
+}
+
+/// The requirements of the server, and what their versions were found from.
+#[derive(Serialize, Debug, Default)]
+pub struct RequirementsCheck {
+	pub requirements: Vec<Requirement>,
+	pub probes: Vec<ProbeOutput>,
+}
+
+/// Checks the probed versions against what the server and legacy server
+/// need. Versions that couldn't be found are assumed to work, except for
+/// libstdc++, which the server can't run without.
+#[allow(dead_code)]
+fn gnu_requirements(probe: &GnuProbe) -> Vec<Requirement> {
+	vec![
+		Requirement::version(
+			"glibc",
+			probe.glibc.as_ref(),
+			&MIN_LDD_VERSION,
+			&MIN_LEGACY_LDD_VERSION,
+			RequirementStatus::Met,
+		),
+		Requirement::version(
+			"libstdc++ GLIBCXX symbols",
+			probe.glibcxx.as_ref(),
+			&MIN_CXX_VERSION,
+			&MIN_LEGACY_CXX_VERSION,
+			RequirementStatus::Unmet,
+		),
+	]
+}
+
+#[cfg(target_os = "linux")]
+async fn probe_gnu() -> GnuProbe {
+	let ldconfig = probe_ldconfig().await;
+	let ldconfig = ldconfig.as_deref();
+	let ((glibc, mut probes), (glibcxx, cxx_probes)) = tokio::join!(
+		probe_glibc_version(ldconfig),
+		probe_glibcxx_version(ldconfig)
+	);
+	probes.extend(cxx_probes);
+
+	GnuProbe {
+		glibc,
+		glibcxx,
+		probes,
+	}
+}
+
+/// Gets the requirements of the server on the machine, and whether they're
+/// met, such as for diagnostics.
+#[cfg(target_os = "linux")]
+pub async fn check_requirements(arch: Option<Arch>) -> RequirementsCheck {
+	let arch = match arch {
+		Some(arch) => Ok(arch),
+		None => detect_arch().await,
+	};
+	let arch = match arch {
+		Ok(arch) => arch,
+		Err(found) => {
+			return RequirementsCheck {
+				requirements: vec![arch_requirement(&found)],
+				probes: vec![],
+			}
+		}
+	};
+
+	let mut reqs = vec![Requirement {
+		status: RequirementStatus::Met,
+		..arch_requirement(&arch.to_string())
+	}];
+	let mut probes = vec![];
+	match detect_libc().await {
+		Libc::Glibc(_) => {
+			let gnu = probe_gnu().await;
+			reqs.extend(gnu_requirements(&gnu));
+			probes = gnu.probes;
+		}
+		Libc::Musl(_) => reqs.push(musl_build_requirement(arch)),
+		Libc::None => reqs.push(libc_requirement()),
+	}
+
+	RequirementsCheck {
+		requirements: reqs,
+		probes,
+	}
+}
+
+/// Gets the musl server platform for the architecture, if one is built.
+#[allow(dead_code)]
+fn musl_platform(arch: Arch) -> Option<Platform> {
+	match arch {
+		Arch::X64 => Some(Platform::LinuxAlpineX64),
+		Arch::Arm64 => Some(Platform::LinuxAlpineARM64),
+		Arch::Armhf => None,
+	}
+}
+
+/// Detects the machine's libc from `ldd` and the dynamic loaders in /lib.
+#[cfg(target_os = "linux")]
+pub async fn detect_libc() -> Libc {
+	const MUSL_LOADERS: &[&str] = &[
+		"/lib/ld-musl-x86_64.so.1",
+		"/lib/ld-musl-aarch64.so.1",
+		"/lib/ld-musl-armhf.so.1",
+	];
+	const GLIBC_LOADERS: &[&str] = &[
+		"/lib64/ld-linux-x86-64.so.2",
+		"/lib/ld-linux-aarch64.so.1",
+		"/lib/ld-linux-armhf.so.3",
+		"/lib/ld-linux.so.2",
+	];
+
+	async fn first_existing(paths: &[&'static str]) -> Option<&'static str> {
+		for p in paths {
+			if fs::metadata(p).await.is_ok() {
+				return Some(p);
+			}
+		}
+		None
+	}
+
+	let (ldd, musl_loader, glibc_loader) = tokio::join!(
+		super::command::capture_command("ldd", ["--version"]),
+		first_existing(MUSL_LOADERS),
+		first_existing(GLIBC_LOADERS),
+	);
+
+	libc_from_probe(&LibcProbe {
+		ldd: ldd.ok().map(|o| [o.stdout, o.stderr].concat()),
+		musl_loader,
+		glibc_loader,
+	})
+}
+
+/// Gets the libc from the probe. What `ldd` says is preferred over the
+/// loaders, since both may be installed for compatibility.
+#[allow(dead_code)]
+fn libc_from_probe(probe: &LibcProbe) -> Libc {
+	if let Some(output) = &probe.ldd {
+		let text = String::from_utf8_lossy(output);
+		if text.contains("musl libc") {
+			let version = MUSL_VERSION_RE.captures(&text).map(|c| c[1].to_string());
+			return Libc::Musl(version);
+		}
+		if text.contains("GLIBC") || text.contains("GNU libc") {
+			return Libc::Glibc(
+				extract_ldd_version(output).map(|v| format!("{}.{}", v.major, v.minor)),
+			);
+		}
+	}
+
+	match (probe.musl_loader, probe.glibc_loader) {
+		(_, Some(_)) => Libc::Glibc(None),
+		(Some(_), None) => Libc::Musl(None),
+		(None, None) => Libc::None,
+	}
+}
+
 #[allow(dead_code)]
 async fn check_musl_interpreter() -> Result<(), String> {
 	const MUSL_PATH: &str = if cfg!(target_arch = "aarch64") {
@@ -140,38 +895,120 @@ async fn check_musl_interpreter() -> Result<(), String> {
 	Ok(())
 }
 
-/// Checks the glibc version, returns "true" if the legacy server is required.
+/// Gets the output of `ldconfig -p`, which lists the libraries in the
+/// dynamic linker's cache. ldconfig is often not on the PATH of non-root users.
+#[cfg(target_os = "linux")]
+async fn probe_ldconfig() -> Option<Vec<u8>> {
+	for cmd in ["ldconfig", "/sbin/ldconfig", "/usr/sbin/ldconfig"] {
+		if let Ok(o) = super::command::capture_command(cmd, ["-p"]).await {
+			if o.status.success() {
+				return Some(o.stdout);
+			}
+		}
+	}
+	None
+}
+
+/// Finds a library through the dynamic linker cache, falling back to where
+/// distros usually put it.
+#[cfg(target_os = "linux")]
+async fn find_library(ldconfig: Option<&[u8]>, name: &str) -> Option<String> {
+	if let Some(path) = ldconfig.and_then(|o| extract_lib_from_ldconfig(o, name)) {
+		return Some(path);
+	}
+
+	for dir in [
+		"/lib64",
+		"/usr/lib64",
+		"/lib/x86_64-linux-gnu",
+		"/usr/lib/x86_64-linux-gnu",
+		"/lib/aarch64-linux-gnu",
+		"/usr/lib/aarch64-linux-gnu",
+		"/lib/arm-linux-gnueabihf",
+		"/usr/lib/arm-linux-gnueabihf",
+		"/lib",
+		"/usr/lib",
+	] {
+		let path = format!("{}/{}", dir, name);
+		if fs::metadata(&path).await.is_ok() {
+			return Some(path);
+		}
+	}
+	None
+}
+
+/// Gets the glibc version from each source the CLI knows of, in order of
+/// how much they're trusted: the loaded glibc, `getconf`, libc.so.6 itself,
+/// and lastly `ldd`, which is a script that some distros change.
 #[cfg(target_os = "linux")]
-async fn check_glibc_version() -> Result<bool, String> {
+async fn probe_glibc_version(ldconfig: Option<&[u8]>) -> (Option<SimpleSemver>, Vec<ProbeOutput>) {
+	let mut probed: Vec<Probed> = vec![];
+
 	#[cfg(target_env = "gnu")]
-	let version = {
+	{
 		let v = unsafe { libc::gnu_get_libc_version() };
-		let v = unsafe { std::ffi::CStr::from_ptr(v) };
-		let v = v.to_str().unwrap();
-		extract_generic_version(v)
-	};
-	#[cfg(not(target_env = "gnu"))]
-	let version = {
-		super::command::capture_command("ldd", ["--version"])
-			.await
-			.ok()
-			.and_then(|o| extract_ldd_version(&o.stdout))
-	};
-
-	if let Some(v) = version {
-		return if v >= *MIN_LDD_VERSION {
-			Ok(false)
-		} else if v >= *MIN_LEGACY_LDD_VERSION {
-			Ok(true)
-		} else {
-			Err(format!(
-				"find GLIBC >= {} (but found {} instead) for GNU environments",
-				*MIN_LDD_VERSION, v
-			))
-		};
+		let v = unsafe { std::ffi::CStr::from_ptr(v) }.to_string_lossy();
+		let version = extract_generic_version(&v);
+		probed.push((
+			version.clone(),
+			ProbeOutput::new("gnu_get_libc_version()", v, version.as_ref()),
+		));
 	}
 
-	Ok(false)
+	probed.push(
+		match super::command::capture_command("getconf", ["GNU_LIBC_VERSION"]).await {
+			Ok(o) => {
+				let output = String::from_utf8_lossy(&[o.stdout, o.stderr].concat()).to_string();
+				let version = extract_getconf_version(&output);
+				(
+					version.clone(),
+					ProbeOutput::new("getconf GNU_LIBC_VERSION", output, version.as_ref()),
+				)
+			}
+			Err(e) => (
+				None,
+				ProbeOutput::new("getconf GNU_LIBC_VERSION", e.to_string(), None),
+			),
+		},
+	);
+
+	probed.push(match find_library(ldconfig, "libc.so.6").await {
+		Some(path) => match fs::read(&path).await {
+			Ok(contents) => {
+				let (version, how) = extract_libc_binary_version(&contents);
+				(
+					version.clone(),
+					ProbeOutput::new(
+						path,
+						format!("{} in {} bytes", how, contents.len()),
+						version.as_ref(),
+					),
+				)
+			}
+			Err(e) => (None, ProbeOutput::new(path, e.to_string(), None)),
+		},
+		None => (None, ProbeOutput::new("libc.so.6", "not found", None)),
+	});
+
+	probed.push(
+		match super::command::capture_command("ldd", ["--version"]).await {
+			Ok(o) => {
+				let output = [o.stdout, o.stderr].concat();
+				let version = extract_ldd_version(&output);
+				(
+					version.clone(),
+					ProbeOutput::new(
+						"ldd --version",
+						String::from_utf8_lossy(&output),
+						version.as_ref(),
+					),
+				)
+			}
+			Err(e) => (None, ProbeOutput::new("ldd --version", e.to_string(), None)),
+		},
+	);
+
+	combine_probes(probed)
 }
 
 /// Check for nixos to avoid mandating glibc versions. See:
@@ -197,67 +1034,47 @@ pub async fn skip_requirements_check() -> bool {
 	false
 }
 
-/// Checks the glibc++ version, returns "true" if the legacy server is required.
+/// Gets the newest GLIBCXX version libstdc++ has symbols for, if libstdc++
+/// can be found.
 #[cfg(target_os = "linux")]
-async fn check_glibcxx_version() -> Result<bool, String> {
-	let mut libstdc_path: Option<String> = None;
-
-	#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-	const DEFAULT_LIB_PATH: &str = "/usr/lib64/libstdc++.so.6";
-	#[cfg(any(target_arch = "x86", target_arch = "arm"))]
-	const DEFAULT_LIB_PATH: &str = "/usr/lib/libstdc++.so.6";
-	const LDCONFIG_PATH: &str = "/sbin/ldconfig";
-
-	if fs::metadata(DEFAULT_LIB_PATH).await.is_ok() {
-		libstdc_path = Some(DEFAULT_LIB_PATH.to_owned());
-	} else if fs::metadata(LDCONFIG_PATH).await.is_ok() {
-		libstdc_path = super::command::capture_command(LDCONFIG_PATH, ["-p"])
-			.await
-			.ok()
-			.and_then(|o| extract_libstd_from_ldconfig(&o.stdout));
-	}
-
-	match libstdc_path {
+async fn probe_glibcxx_version(
+	ldconfig: Option<&[u8]>,
+) -> (Option<SimpleSemver>, Vec<ProbeOutput>) {
+	let probed = match find_library(ldconfig, "libstdc++.so.6").await {
 		Some(path) => match fs::read(&path).await {
-			Ok(contents) => check_for_sufficient_glibcxx_versions(contents),
-			Err(e) => Err(format!(
-				"validate GLIBCXX version for GNU environments, but could not: {}",
-				e
-			)),
+			Ok(contents) => {
+				let version = max_glibcxx_version(&contents);
+				let output = format!("newest GLIBCXX symbol version in {} bytes", contents.len());
+				(
+					version.clone(),
+					ProbeOutput::new(path, output, version.as_ref()),
+				)
+			}
+			Err(e) => (None, ProbeOutput::new(path, e.to_string(), None)),
 		},
-		None => Err("find libstdc++.so or ldconfig for GNU environments".to_owned()),
-	}
+		None => (
+			None,
+			ProbeOutput::new(
+				"libstdc++.so.6",
+				"not found in the linker cache or usual paths",
+				None,
+			),
+		),
+	};
+
+	combine_probes(vec![probed])
 }
 
-#[cfg(target_os = "linux")]
-fn check_for_sufficient_glibcxx_versions(contents: Vec<u8>) -> Result<bool, String> {
-	let max_version = LIBSTD_CXX_VERSION_RE
-		.captures_iter(&contents)
+#[allow(dead_code)]
+fn max_glibcxx_version(contents: &[u8]) -> Option<SimpleSemver> {
+	LIBSTD_CXX_VERSION_RE
+		.captures_iter(contents)
 		.map(|m| SimpleSemver {
 			major: m.get(1).map_or(0, |s| u32_from_bytes(s.as_bytes())),
 			minor: m.get(2).map_or(0, |s| u32_from_bytes(s.as_bytes())),
 			patch: m.get(3).map_or(0, |s| u32_from_bytes(s.as_bytes())),
 		})
-		.max();
-
-	if let Some(max_version) = &max_version {
-		if max_version >= &*MIN_CXX_VERSION {
-			return Ok(false);
-		}
-
-		if max_version >= &*MIN_LEGACY_CXX_VERSION {
-			return Ok(true);
-		}
-	}
-
-	Err(format!(
-		"find GLIBCXX >= {} (but found {} instead) for GNU environments",
-		*MIN_CXX_VERSION,
-		max_version
-			.as_ref()
-			.map(String::from)
-			.unwrap_or("none".to_string())
-	))
+		.max()
 }
 
 #[allow(dead_code)]
@@ -278,20 +1095,79 @@ fn extract_generic_version(output: &str) -> Option<SimpleSemver> {
 	})
 }
 
+/// Gets the path of the library from `ldconfig -p` output, preferring one
+/// built for the CLI's architecture when several are listed.
 #[allow(dead_code)]
-fn extract_libstd_from_ldconfig(output: &[u8]) -> Option<String> {
-	String::from_utf8_lossy(output)
+fn extract_lib_from_ldconfig(output: &[u8], name: &str) -> Option<String> {
+	#[cfg(target_arch = "x86_64")]
+	const ARCH_TAG: Option<&str> = Some("x86-64");
+	#[cfg(target_arch = "aarch64")]
+	const ARCH_TAG: Option<&str> = Some("AArch64");
+	#[cfg(target_arch = "arm")]
+	const ARCH_TAG: Option<&str> = Some("hard-float");
+	#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "arm")))]
+	const ARCH_TAG: Option<&str> = None;
+
+	let text = String::from_utf8_lossy(output);
+	let entries: Vec<(&str, &str)> = text
 		.lines()
-		.find_map(|l| LDCONFIG_STDC_RE.captures(l))
-		.and_then(|cap| cap.get(1))
-		.map(|cap| cap.as_str().to_owned())
+		.filter_map(|l| {
+			let (lib, path) = l.trim().split_once(" => ")?;
+			let (lib_name, tags) = lib.split_once(' ').unwrap_or((lib, ""));
+			(lib_name == name).then_some((tags, path.trim()))
+		})
+		.collect();
+
+	entries
+		.iter()
+		.find(|(tags, _)| ARCH_TAG.is_some_and(|t| tags.contains(t)))
+		.or_else(|| entries.first())
+		.map(|(_, path)| path.to_string())
+}
+
+/// Gets the glibc version from `getconf GNU_LIBC_VERSION`, like "glibc 2.31".
+#[allow(dead_code)]
+fn extract_getconf_version(output: &str) -> Option<SimpleSemver> {
+	GETCONF_VERSION_RE.captures(output).map(|m| SimpleSemver {
+		major: m[1].parse().unwrap_or(0),
+		minor: m[2].parse().unwrap_or(0),
+		patch: 0,
+	})
+}
+
+/// Gets the glibc version from libc.so.6, from the banner it prints when
+/// run, or else the newest symbol version, which may be older than the
+/// release if it added no symbols. Also returns which was used.
+#[allow(dead_code)]
+fn extract_libc_binary_version(contents: &[u8]) -> (Option<SimpleSemver>, &'static str) {
+	if let Some(m) = LIBC_BANNER_RE.captures(contents) {
+		let version = SimpleSemver {
+			major: u32_from_bytes(&m[1]),
+			minor: u32_from_bytes(&m[2]),
+			patch: 0,
+		};
+		return (Some(version), "release banner");
+	}
+
+	let newest = LIBC_SYMBOL_VERSION_RE
+		.captures_iter(contents)
+		.map(|m| SimpleSemver {
+			major: m.get(1).map_or(0, |s| u32_from_bytes(s.as_bytes())),
+			minor: m.get(2).map_or(0, |s| u32_from_bytes(s.as_bytes())),
+			patch: m.get(3).map_or(0, |s| u32_from_bytes(s.as_bytes())),
+		})
+		.max();
+	match newest {
+		Some(v) => (Some(v), "newest GLIBC symbol version"),
+		None => (None, "no version"),
+	}
 }
 
 fn u32_from_bytes(b: &[u8]) -> u32 {
 	String::from_utf8_lossy(b).parse::<u32>().unwrap_or(0)
 }
 
-#[derive(Debug, Default, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 struct SimpleSemver {
 	major: u32,
 	minor: u32,
@@ -348,7 +1224,7 @@ mod tests {
 	use super::*;
 
 	#[test]
-	fn test_extract_libstd_from_ldconfig() {
+	fn test_extract_lib_from_ldconfig() {
 		let actual = "
 							libstoken.so.1 (libc6,x86-64) => /lib/x86_64-linux-gnu/libstoken.so.1
 							libstemmer.so.0d (libc6,x86-64) => /lib/x86_64-linux-gnu/libstemmer.so.0d
@@ -358,16 +1234,150 @@ mod tests {
 					".to_owned().into_bytes();
 
 		assert_eq!(
-			extract_libstd_from_ldconfig(&actual),
+			extract_lib_from_ldconfig(&actual, "libstdc++.so.6"),
 			Some("/lib/x86_64-linux-gnu/libstdc++.so.6".to_owned()),
 		);
 
 		assert_eq!(
-			extract_libstd_from_ldconfig(&"nothing here!".to_owned().into_bytes()),
+			extract_lib_from_ldconfig(&"nothing here!".to_owned().into_bytes(), "libstdc++.so.6"),
 			None,
 		);
 	}
 
+	#[test]
+	#[cfg(target_arch = "x86_64")]
+	fn test_extract_lib_from_ldconfig_prefers_arch() {
+		// multilib machines list the 32-bit library too, sometimes first
+		let actual = b"1234 libs found in cache `/etc/ld.so.cache'
+	libc.so.6 (libc6) => /lib/i386-linux-gnu/libc.so.6
+	libc.so.6 (libc6,x86-64, OS ABI: Linux 3.2.0) => /lib/x86_64-linux-gnu/libc.so.6
+	libc.so.6.1 (libc6,x86-64) => /opt/other/libc.so.6.1
+";
+		assert_eq!(
+			extract_lib_from_ldconfig(actual, "libc.so.6"),
+			Some("/lib/x86_64-linux-gnu/libc.so.6".to_owned()),
+		);
+	}
+
+	/// `ldd --version` output from user reports.
+	#[test]
+	fn test_extract_ldd_version_corpus() {
+		let cases: &[(&str, Option<(u32, u32)>)] = &[
+			(
+				"ldd (GNU libc) 2.28\nCopyright (C) 2018 Free Software Foundation, Inc.\n",
+				Some((2, 28)),
+			),
+			("ldd (Ubuntu GLIBC 2.35-0ubuntu3.6) 2.35\n", Some((2, 35))),
+			("ldd (Debian GLIBC 2.36-9+deb12u4) 2.36\n", Some((2, 36))),
+			("ldd (Ubuntu EGLIBC 2.19-0ubuntu6.15) 2.19\n", Some((2, 19))),
+			(
+				"ldd (GNU libc) 2.17\r\nCopyright (C) 2012 Free Software Foundation, Inc.\r\n",
+				Some((2, 17)),
+			),
+			// a version with a two-digit major shouldn't be truncated
+			("ldd (Example libc) 10.2\n", Some((10, 2))),
+			// NixOS wrappers and busybox
+			(
+				"/run/current-system/sw/bin/ldd: line 1: exec: --version: not found\n",
+				None,
+			),
+			(
+				"ldd: unrecognized option '--version'\nBusyBox v1.36.1 multi-call binary.\n",
+				None,
+			),
+			(
+				"musl libc (x86_64)\nVersion 1.2.4\nDynamic Program Loader\n",
+				None,
+			),
+			("", None),
+		];
+
+		for (output, expected) in cases {
+			assert_eq!(
+				extract_ldd_version(output.as_bytes()),
+				expected.map(|(major, minor)| SimpleSemver::new(major, minor, 0)),
+				"{:?}",
+				output
+			);
+		}
+	}
+
+	#[test]
+	fn test_extract_getconf_version() {
+		assert_eq!(
+			extract_getconf_version("glibc 2.31\n"),
+			Some(SimpleSemver::new(2, 31, 0))
+		);
+		assert_eq!(
+			extract_getconf_version("glibc 2.17"),
+			Some(SimpleSemver::new(2, 17, 0))
+		);
+		// musl and other libcs don't know the variable
+		assert_eq!(
+			extract_getconf_version("getconf: GNU_LIBC_VERSION: unknown variable\n"),
+			None
+		);
+		assert_eq!(
+			extract_getconf_version("getconf: Unrecognized variable `GNU_LIBC_VERSION'\n"),
+			None
+		);
+	}
+
+	#[test]
+	fn test_extract_libc_binary_version() {
+		let banner = b"\x7fELF\0\0GLIBC_2.2.5\0GLIBC_2.34\0GNU C Library (Ubuntu GLIBC 2.35-0ubuntu3.6) stable release version 2.35.\nCopyright (C) 2022";
+		assert_eq!(
+			extract_libc_binary_version(banner),
+			(Some(SimpleSemver::new(2, 35, 0)), "release banner")
+		);
+
+		// glibc 2.35 added no symbols, so the symbols alone undercount it
+		let stripped = b"\x7fELF\0\0GLIBC_2.2.5\0GLIBC_2.34\0GLIBC_PRIVATE\0GLIBC_2.17\0";
+		assert_eq!(
+			extract_libc_binary_version(stripped),
+			(
+				Some(SimpleSemver::new(2, 34, 0)),
+				"newest GLIBC symbol version"
+			)
+		);
+
+		assert_eq!(
+			extract_libc_binary_version(b"\x7fELF\0"),
+			(None, "no version")
+		);
+	}
+
+	#[test]
+	fn test_combine_probes() {
+		let probe = |v: Option<SimpleSemver>, source: &str| {
+			let output = ProbeOutput::new(source, format!("output of {}", source), v.as_ref());
+			(v, output)
+		};
+
+		// getconf is trusted over ldd, even when ldd finds something else
+		let (version, probes) = combine_probes(vec![
+			probe(None, "gnu_get_libc_version()"),
+			probe(
+				Some(SimpleSemver::new(2, 31, 0)),
+				"getconf GNU_LIBC_VERSION",
+			),
+			probe(Some(SimpleSemver::new(2, 3, 0)), "ldd --version"),
+		]);
+		assert_eq!(version, Some(SimpleSemver::new(2, 31, 0)));
+		assert_eq!(probes.len(), 3);
+		assert_eq!(probes[1].version.as_deref(), Some("v2.31.0"));
+		assert_eq!(probes[2].output, "output of ldd --version");
+
+		assert_eq!(combine_probes(vec![probe(None, "ldd --version")]).0, None);
+	}
+
+	#[test]
+	fn test_probe_output_is_bounded() {
+		let output = ProbeOutput::new("ldd --version", "é".repeat(400), None);
+		assert!(output.output.len() <= 503, "{}", output.output.len());
+		assert!(output.output.ends_with("..."));
+	}
+
 	#[test]
 	fn test_gte() {
 		assert!(SimpleSemver::new(1, 2, 3) >= SimpleSemver::new(1, 2, 3));
@@ -394,4 +1404,449 @@ mod tests {
 			Some(SimpleSemver::new(2, 31, 0)),
 		);
 	}
+
+	#[test]
+	fn test_libc_from_probe() {
+		let glibc = LibcProbe {
+			ldd: Some(
+				b"ldd (GNU libc) 2.28\nCopyright (C) 2018 Free Software Foundation, Inc.\n"
+					.to_vec(),
+			),
+			musl_loader: None,
+			glibc_loader: Some("/lib64/ld-linux-x86-64.so.2"),
+		};
+		assert_eq!(
+			libc_from_probe(&glibc),
+			Libc::Glibc(Some("2.28".to_string()))
+		);
+
+		// musl's ldd prints its version to stderr, and exits with 1
+		let musl = LibcProbe {
+			ldd: Some(
+				b"musl libc (x86_64)\nVersion 1.2.4\nDynamic Program Loader\nUsage: ldd [options] [--] pathname\n"
+					.to_vec(),
+			),
+			musl_loader: Some("/lib/ld-musl-x86_64.so.1"),
+			// gcompat installs a glibc loader
+			glibc_loader: Some("/lib64/ld-linux-x86-64.so.2"),
+		};
+		assert_eq!(
+			libc_from_probe(&musl),
+			Libc::Musl(Some("1.2.4".to_string()))
+		);
+
+		let musl_without_ldd = LibcProbe {
+			ldd: None,
+			musl_loader: Some("/lib/ld-musl-aarch64.so.1"),
+			glibc_loader: None,
+		};
+		assert_eq!(libc_from_probe(&musl_without_ldd), Libc::Musl(None));
+
+		let static_only = LibcProbe {
+			ldd: Some(b"sh: ldd: not found\n".to_vec()),
+			musl_loader: None,
+			glibc_loader: None,
+		};
+		assert_eq!(libc_from_probe(&static_only), Libc::None);
+		assert_eq!(libc_from_probe(&LibcProbe::default()), Libc::None);
+	}
+
+	#[test]
+	fn test_libc_display() {
+		assert_eq!(
+			Libc::Glibc(Some("2.31".to_string())).to_string(),
+			"glibc 2.31"
+		);
+		assert_eq!(Libc::Musl(None).to_string(), "musl");
+		assert!(Libc::None.to_string().contains("static"));
+	}
+
+	fn centos7() -> GnuProbe {
+		GnuProbe {
+			glibc: Some(SimpleSemver::new(2, 17, 0)),
+			glibcxx: Some(SimpleSemver::new(3, 4, 19)),
+			probes: vec![],
+		}
+	}
+
+	fn statuses(reqs: &[Requirement]) -> Vec<RequirementStatus> {
+		reqs.iter().map(|r| r.status).collect()
+	}
+
+	#[test]
+	fn test_gnu_requirements() {
+		let current = GnuProbe {
+			glibc: Some(SimpleSemver::new(2, 31, 0)),
+			glibcxx: Some(SimpleSemver::new(3, 4, 28)),
+			probes: vec![],
+		};
+		assert_eq!(
+			statuses(&gnu_requirements(&current)),
+			vec![RequirementStatus::Met; 2]
+		);
+		assert_eq!(
+			statuses(&gnu_requirements(&centos7())),
+			vec![RequirementStatus::Legacy; 2]
+		);
+
+		// unknown versions are assumed to work, except for libstdc++
+		assert_eq!(
+			statuses(&gnu_requirements(&GnuProbe::default())),
+			vec![RequirementStatus::Met, RequirementStatus::Unmet]
+		);
+
+		let old_cxx = GnuProbe {
+			glibcxx: Some(SimpleSemver::new(3, 4, 13)),
+			..centos7()
+		};
+		let reqs = gnu_requirements(&old_cxx);
+		assert_eq!(reqs[1].status, RequirementStatus::Unmet);
+		assert_eq!(
+			reqs[1].to_string(),
+			"libstdc++ GLIBCXX symbols >= v3.4.25 (>= v3.4.19 for the legacy server), found v3.4.13"
+		);
+	}
+
+	#[test]
+	fn test_requirement_json() {
+		let reqs = gnu_requirements(&GnuProbe {
+			glibcxx: None,
+			..centos7()
+		});
+		let json = serde_json::to_value(&reqs).unwrap();
+		assert_eq!(
+			json[0],
+			serde_json::json!({
+				"name": "glibc",
+				"required": ">= v2.28.0 (>= v2.17.0 for the legacy server)",
+				"found": "v2.17.0",
+				"status": "legacy",
+			})
+		);
+		assert_eq!(json[1]["found"], serde_json::Value::Null);
+		assert_eq!(json[1]["status"], "unmet");
+	}
+
+	#[test]
+	fn test_extract_versions() {
+		let lib = b"\0GLIBCXX_3.4.19\0GLIBCXX_3.4.21\0GLIBCXX_3.4\0CXXABI_1.3.9\0";
+		assert_eq!(max_glibcxx_version(lib), Some(SimpleSemver::new(3, 4, 21)));
+		assert_eq!(max_glibcxx_version(b"nothing"), None);
+	}
+
+	#[test]
+	fn test_select_platform() {
+		let glibc = Libc::Glibc(Some("2.17".to_string()));
+		assert_eq!(
+			select_platform(Arch::X64, &glibc, false, Some(&centos7()), false).unwrap(),
+			gnu_platform(Arch::X64, true)
+		);
+		assert_eq!(
+			select_platform(Arch::X64, &glibc, true, None, false).unwrap(),
+			gnu_platform(Arch::X64, false)
+		);
+
+		let no_cxx = GnuProbe {
+			glibcxx: None,
+			..centos7()
+		};
+		let unmet = select_platform(Arch::X64, &glibc, false, Some(&no_cxx), false).unwrap_err();
+		assert_eq!(unmet.len(), 1);
+		assert_eq!(unmet[0].name, "libstdc++ GLIBCXX symbols");
+
+		// falls back to a musl loader installed for compatibility
+		assert_eq!(
+			select_platform(Arch::X64, &glibc, false, Some(&no_cxx), true).ok(),
+			Some(Platform::LinuxAlpineX64)
+		);
+
+		let unmet = select_platform(Arch::X64, &Libc::None, false, None, false).unwrap_err();
+		assert_eq!(unmet[0].name, "libc");
+	}
+
+	#[test]
+	fn test_apply_skip() {
+		let unmet = || {
+			select_platform(
+				Arch::X64,
+				&Libc::Glibc(None),
+				false,
+				Some(&GnuProbe::default()),
+				false,
+			)
+		};
+
+		match PreReqChecker::new().apply_skip(unmet(), &Libc::Glibc(None), false, Arch::X64) {
+			Err(CodeError::PrerequisitesFailed {
+				bullets,
+				requirements,
+				..
+			}) => {
+				assert_eq!(
+					bullets,
+					"  - libstdc++ GLIBCXX symbols >= v3.4.25 (>= v3.4.19 for the legacy server), found none\nDetected glibc."
+				);
+				assert_eq!(requirements.len(), 1);
+			}
+			r => panic!("expected prerequisites to fail, got {:?}", r),
+		}
+
+		let skipped = PreReqChecker::new()
+			.skip_requirements_check(true)
+			.apply_skip(unmet(), &Libc::Glibc(None), true, Arch::X64)
+			.unwrap();
+		assert_eq!(skipped, gnu_platform(Arch::X64, true));
+
+		// met requirements aren't affected by skipping
+		let met = Ok(gnu_platform(Arch::X64, false));
+		assert_eq!(
+			PreReqChecker::new()
+				.apply_skip(met, &Libc::Glibc(None), true, Arch::X64)
+				.unwrap(),
+			gnu_platform(Arch::X64, false)
+		);
+	}
+
+	/// Gets the first bytes of an ELF file with the class and machine.
+	fn elf(is_64_bit: bool, machine: u16) -> Vec<u8> {
+		let mut bytes = b"\x7fELF".to_vec();
+		bytes.extend([if is_64_bit { 2 } else { 1 }, 1, 1, 0]);
+		bytes.extend([0; 10]);
+		bytes.extend(machine.to_le_bytes());
+		bytes
+	}
+
+	const PI_ZERO_CPUINFO: &str = "processor	: 0\nmodel name	: ARMv6-compatible processor rev 7 (v6l)\nBogoMIPS	: 697.95\nFeatures	: half thumb fastmult vfp edsp java tls\nCPU architecture: 7\n";
+	const PI_4_CPUINFO: &str = "processor	: 0\nBogoMIPS	: 108.00\nFeatures	: fp asimd evtstrm crc32 cpuid\nCPU implementer	: 0x41\nCPU architecture: 8\n";
+
+	#[test]
+	fn test_parse_elf_header() {
+		assert_eq!(
+			parse_elf_header(&elf(true, ELF_MACHINE_AARCH64)),
+			Some(ElfHeader {
+				is_64_bit: true,
+				machine: ELF_MACHINE_AARCH64
+			})
+		);
+
+		let mut big_endian = elf(false, 0);
+		big_endian[5] = 2;
+		big_endian[18..20].copy_from_slice(&ELF_MACHINE_ARM.to_be_bytes());
+		assert_eq!(
+			parse_elf_header(&big_endian),
+			Some(ElfHeader {
+				is_64_bit: false,
+				machine: ELF_MACHINE_ARM
+			})
+		);
+
+		assert_eq!(parse_elf_header(b"#!/bin/sh\necho hello world\n"), None);
+		assert_eq!(parse_elf_header(b"\x7fELF"), None);
+	}
+
+	#[test]
+	fn test_arch_from_probe() {
+		let probe = |is_64_bit, machine, cpuinfo: Option<&str>| ArchProbe {
+			userland: parse_elf_header(&elf(is_64_bit, machine)),
+			cpuinfo: cpuinfo.map(|c| c.to_string()),
+		};
+
+		// (userland, cpuinfo, expected), where the kernel may be 64-bit for
+		// any of them
+		let cases = [
+			(probe(true, ELF_MACHINE_X86_64, None), Ok(Arch::X64)),
+			(
+				probe(true, ELF_MACHINE_AARCH64, Some(PI_4_CPUINFO)),
+				Ok(Arch::Arm64),
+			),
+			// 64-bit kernel with a 32-bit userland, as on Raspberry Pi OS
+			(
+				probe(false, ELF_MACHINE_ARM, Some(PI_4_CPUINFO)),
+				Ok(Arch::Armhf),
+			),
+			(probe(false, ELF_MACHINE_ARM, None), Ok(Arch::Armhf)),
+			(
+				probe(false, ELF_MACHINE_ARM, Some(PI_ZERO_CPUINFO)),
+				Err("armv6".to_string()),
+			),
+			(
+				probe(
+					false,
+					ELF_MACHINE_ARM,
+					Some("Processor	: ARMv7 Processor rev 10 (v7l)\nCPU architecture: 7\n"),
+				),
+				Ok(Arch::Armhf),
+			),
+			// 64-bit kernel with a 32-bit x86 userland
+			(probe(false, ELF_MACHINE_X86, None), Err("x86".to_string())),
+			(probe(true, 243, None), Err("ELF machine 243".to_string())),
+		];
+		for (probe, expected) in cases {
+			assert_eq!(arch_from_probe(&probe), expected, "{:?}", probe);
+		}
+
+		assert_eq!(arch_from_probe(&ArchProbe::default()), Ok(Arch::compiled()));
+	}
+
+	#[test]
+	fn test_is_armv6() {
+		assert!(is_armv6(PI_ZERO_CPUINFO));
+		assert!(is_armv6("CPU architecture: 6\n"));
+		assert!(!is_armv6(PI_4_CPUINFO));
+		assert!(!is_armv6(""));
+	}
+
+	#[test]
+	fn test_platform_for_arch() {
+		let glibc = Libc::Glibc(Some("2.31".to_string()));
+		let current = GnuProbe {
+			glibc: Some(SimpleSemver::new(2, 31, 0)),
+			glibcxx: Some(SimpleSemver::new(3, 4, 28)),
+			probes: vec![],
+		};
+		let cases = [
+			(Arch::X64, Platform::LinuxX64),
+			(Arch::Arm64, Platform::LinuxARM64),
+			(Arch::Armhf, Platform::LinuxARM32),
+		];
+		for (arch, expected) in cases {
+			assert_eq!(
+				select_platform(arch, &glibc, false, Some(&current), false),
+				Ok(expected)
+			);
+		}
+
+		assert_eq!(
+			select_platform(Arch::Arm64, &Libc::Musl(None), false, None, false),
+			Ok(Platform::LinuxAlpineARM64)
+		);
+		let unmet =
+			select_platform(Arch::Armhf, &Libc::Musl(None), false, None, false).unwrap_err();
+		assert_eq!(unmet[0].required, "one for armhf");
+	}
+
+	#[test]
+	fn test_unsupported_arch_fails_up_front() {
+		let result = Err(vec![arch_requirement("armv6")]);
+		match PreReqChecker::new().apply_skip(result, &Libc::Glibc(None), false, Arch::Armhf) {
+			Err(CodeError::PrerequisitesFailed { bullets, .. }) => {
+				assert!(
+					bullets.starts_with(
+						"  - CPU architecture x64, arm64, or armv7 and newer, found armv6"
+					),
+					"{}",
+					bullets
+				);
+			}
+			r => panic!("expected prerequisites to fail, got {:?}", r),
+		}
+	}
+
+	fn uname(sysname: &str, release: &str, version: &str) -> Uname {
+		Uname {
+			sysname: sysname.to_string(),
+			release: release.to_string(),
+			version: version.to_string(),
+		}
+	}
+
+	#[test]
+	fn test_host_from_uname() {
+		let cases = [
+			(
+				uname(
+					"Linux",
+					"5.15.0-91-generic",
+					"#101-Ubuntu SMP Tue Nov 14 13:30:08 UTC 2023",
+				),
+				HostOs::Linux,
+			),
+			(
+				uname("Darwin", "23.1.0", "Darwin Kernel Version 23.1.0"),
+				HostOs::MacOs,
+			),
+			(
+				uname(
+					"FreeBSD",
+					"13.2-RELEASE",
+					"FreeBSD 13.2-RELEASE releng/13.2-n254617",
+				),
+				HostOs::FreeBsd {
+					linux_emulation: false,
+				},
+			),
+			// the Linux CLI under FreeBSD's Linux emulation
+			(
+				uname(
+					"Linux",
+					"5.15.0",
+					"FreeBSD 13.2-RELEASE releng/13.2-n254617",
+				),
+				HostOs::FreeBsd {
+					linux_emulation: true,
+				},
+			),
+			(
+				uname("SunOS", "5.11", "illumos-2f1a8b3c9e"),
+				HostOs::Illumos { lx_zone: false },
+			),
+			(
+				uname("Linux", "4.4", "BrandZ virtual linux"),
+				HostOs::Illumos { lx_zone: true },
+			),
+			(
+				uname("OpenBSD", "7.4", "GENERIC.MP#1397"),
+				HostOs::Other("OpenBSD".to_string()),
+			),
+		];
+
+		for (uname, expected) in cases {
+			let host = host_from_uname(&uname);
+			assert_eq!(host, expected, "{:?}", uname);
+			assert_eq!(
+				host.is_supported(),
+				matches!(expected, HostOs::Linux | HostOs::MacOs)
+			);
+		}
+	}
+
+	#[test]
+	fn test_unsupported_host_message() {
+		let freebsd = uname("Linux", "5.15.0", "FreeBSD 14.0-RELEASE");
+		let message = unsupported_host(&host_from_uname(&freebsd), &freebsd);
+		assert!(
+			message.starts_with("detected FreeBSD's Linux emulation (Linux 5.15.0)"),
+			"{}",
+			message
+		);
+		assert!(message.contains("handbook/linuxemu"), "{}", message);
+		assert!(
+			message.contains("--assume-platform linux-x64"),
+			"{}",
+			message
+		);
+
+		let openbsd = uname("OpenBSD", "7.4", "GENERIC.MP#1397");
+		let message = unsupported_host(&host_from_uname(&openbsd), &openbsd);
+		assert!(
+			message.starts_with("detected OpenBSD (OpenBSD 7.4)"),
+			"{}",
+			message
+		);
+		assert!(!message.contains("linuxemu"), "{}", message);
+	}
+
+	#[tokio::test]
+	async fn test_assumed_platform_skips_checks() {
+		for platform in [
+			Platform::LinuxX64,
+			Platform::LinuxARM32,
+			Platform::DarwinARM64,
+		] {
+			let checker = PreReqChecker::new()
+				.with_arch(Some(Arch::Arm64))
+				.assume_platform(Some(platform));
+			assert_eq!(checker.verify().await.unwrap(), platform);
+		}
+	}
 }
@@ -0,0 +1,395 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	sync::RwLock,
+	time::{Duration, Instant},
+};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use lazy_static::lazy_static;
+
+use crate::{commands::output::format_bytes, log};
+
+use super::io::ReportCopyProgress;
+
+/// Percentages at which a transfer's progress is logged in `Phases` mode.
+const PHASES: [u64; 3] = [25, 50, 75];
+
+/// How the progress of transfers, such as downloads, is shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressMode {
+	/// A bar that's redrawn in place, for terminals.
+	Bar,
+	/// A line with the percentage done at most every `interval`, for output
+	/// that's kept, such as a CI log, where a redrawn bar becomes one long line.
+	Lines { interval: Duration },
+	/// Only log records when the transfer starts, passes each of `PHASES`,
+	/// and finishes.
+	Phases,
+}
+
+impl ProgressMode {
+	/// Picks how to show progress. Logs that are records, such as those in a
+	/// file, the system log, or JSON, only get phases. Otherwise a terminal,
+	/// detected as for colors, gets a bar, and other output gets lines.
+	pub fn select(logs_are_records: bool, is_terminal: bool, interval: Duration) -> Self {
+		if logs_are_records {
+			ProgressMode::Phases
+		} else if is_terminal {
+			ProgressMode::Bar
+		} else {
+			ProgressMode::Lines { interval }
+		}
+	}
+}
+
+lazy_static! {
+	static ref MODE: RwLock<ProgressMode> = RwLock::new(ProgressMode::Phases);
+}
+
+/// Sets how progress is shown for the rest of the process.
+pub fn configure(mode: ProgressMode) {
+	*MODE.write().unwrap() = mode;
+}
+
+/// Creates a reporter for a transfer of bytes, shown as the process is
+/// configured to. The label says what's being transferred, such as
+/// "Downloading server".
+pub fn reporter(log: &log::Logger, label: &str) -> ProgressReporter {
+	let mode = *MODE.read().unwrap();
+	let target = if mode == ProgressMode::Bar && log.notices_enabled() {
+		ProgressDrawTarget::stderr()
+	} else {
+		ProgressDrawTarget::hidden()
+	};
+	ProgressReporter::new(log.clone(), label, mode, target)
+}
+
+#[derive(Clone, Copy)]
+enum Unit {
+	Bytes,
+	Items(&'static str),
+}
+
+impl Unit {
+	fn format(&self, n: u64) -> String {
+		match self {
+			Unit::Bytes => format_bytes(n),
+			Unit::Items(noun) => format!("{} {}", n, noun),
+		}
+	}
+}
+
+/// Shows a transfer's progress in one of the `ProgressMode`s.
+pub struct ProgressReporter {
+	log: log::Logger,
+	label: String,
+	mode: ProgressMode,
+	unit: Unit,
+	bar: Option<ProgressBar>,
+	started_at: Option<Instant>,
+	last_line_at: Option<Instant>,
+	phases_passed: usize,
+	finished: bool,
+}
+
+impl ProgressReporter {
+	fn new(log: log::Logger, label: &str, mode: ProgressMode, target: ProgressDrawTarget) -> Self {
+		let bar = (mode == ProgressMode::Bar).then(|| {
+			let bar = ProgressBar::with_draw_target(None, target);
+			bar.set_message(label.to_string());
+			bar
+		});
+
+		let reporter = Self {
+			log,
+			label: label.to_string(),
+			mode,
+			unit: Unit::Bytes,
+			bar,
+			started_at: None,
+			last_line_at: None,
+			phases_passed: 0,
+			finished: false,
+		};
+		reporter.style_bar();
+		reporter
+	}
+
+	/// Counts items, such as the files extracted from an archive, rather than
+	/// bytes.
+	pub fn counting(mut self, noun: &'static str) -> Self {
+		self.unit = Unit::Items(noun);
+		self.style_bar();
+		self
+	}
+
+	fn style_bar(&self) {
+		let template = match self.unit {
+			Unit::Bytes => "{msg} [{wide_bar}] {percent}% ({bytes}/{total_bytes})",
+			Unit::Items(_) => "{msg} [{wide_bar}] {percent}% ({pos}/{len})",
+		};
+		if let Some(bar) = &self.bar {
+			bar.set_style(ProgressStyle::with_template(template).unwrap());
+		}
+	}
+
+	fn report_at(&mut self, done: u64, total: u64, now: Instant) {
+		if self.finished {
+			return;
+		}
+
+		let started_at = *self.started_at.get_or_insert(now);
+		let is_done = total > 0 && done >= total;
+		let percent = (done.min(total) * 100).checked_div(total).unwrap_or(0);
+
+		match self.mode {
+			ProgressMode::Bar => {
+				let bar = self.bar.as_ref().unwrap();
+				bar.set_length(total);
+				if is_done {
+					bar.finish_and_clear();
+				} else {
+					bar.set_position(done);
+				}
+			}
+			ProgressMode::Lines { interval } => {
+				let due = self
+					.last_line_at
+					.map(|t| now.duration_since(t) >= interval)
+					.unwrap_or(true);
+				if is_done {
+					self.log.notice(format!(
+						"{}: done, {} in {:.1}s",
+						self.label,
+						self.unit.format(done),
+						now.duration_since(started_at).as_secs_f64()
+					));
+				} else if due {
+					self.last_line_at = Some(now);
+					self.log.notice(match total {
+						0 => format!("{}: {}", self.label, self.unit.format(done)),
+						_ => format!(
+							"{}: {}% ({} of {})",
+							self.label,
+							percent,
+							self.unit.format(done),
+							self.unit.format(total)
+						),
+					});
+				}
+			}
+			ProgressMode::Phases => {
+				if self.last_line_at.is_none() {
+					self.last_line_at = Some(now);
+					match total {
+						0 => info!(self.log, "{}: started", self.label),
+						_ => info!(
+							self.log,
+							"{}: started, {}",
+							self.label,
+							self.unit.format(total)
+						),
+					}
+				}
+
+				if is_done {
+					info!(
+						self.log,
+						"{}: finished, {} in {:.1}s",
+						self.label,
+						self.unit.format(done),
+						now.duration_since(started_at).as_secs_f64()
+					);
+				} else {
+					// a jump past several phases is logged once, at the last
+					let passed = PHASES.iter().filter(|p| percent >= **p).count();
+					if passed > self.phases_passed {
+						self.phases_passed = passed;
+						info!(self.log, "{}: {}%", self.label, PHASES[passed - 1]);
+					}
+				}
+			}
+		}
+
+		self.finished = is_done;
+	}
+}
+
+impl ReportCopyProgress for ProgressReporter {
+	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
+		self.report_at(bytes_so_far, total_bytes, Instant::now());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use indicatif::InMemoryTerm;
+
+	use super::*;
+	use crate::log::{Level, LogSink};
+
+	type Captured = Arc<Mutex<Vec<(Option<Level>, String)>>>;
+
+	#[derive(Clone, Default)]
+	struct CapturingSink(Captured);
+
+	impl LogSink for CapturingSink {
+		fn write_log(&self, level: Level, _prefix: &str, message: &str) {
+			self.0
+				.lock()
+				.unwrap()
+				.push((Some(level), message.to_string()));
+		}
+
+		fn write_result(&self, _message: &str) {}
+
+		fn write_notice(&self, _prefix: &str, message: &str) {
+			self.0.lock().unwrap().push((None, message.to_string()));
+		}
+	}
+
+	/// Reports a 1000 byte transfer that takes 20 seconds, 50 bytes a second,
+	/// and returns what was logged.
+	fn simulate(mode: ProgressMode, target: ProgressDrawTarget) -> Vec<(Option<Level>, String)> {
+		let sink = CapturingSink::default();
+		let log = log::Logger::test().with_sink(sink.clone());
+		let mut reporter = ProgressReporter::new(log, "Downloading", mode, target);
+
+		let start = Instant::now();
+		for s in 0..=20 {
+			reporter.report_at(s * 50, 1000, start + Duration::from_secs(s));
+		}
+		// reports after the transfer finished are ignored
+		reporter.report_at(1000, 1000, start + Duration::from_secs(21));
+
+		let lines = sink.0.lock().unwrap().clone();
+		lines
+	}
+
+	#[test]
+	fn test_select() {
+		let interval = Duration::from_secs(5);
+		assert_eq!(
+			ProgressMode::select(true, true, interval),
+			ProgressMode::Phases
+		);
+		assert_eq!(
+			ProgressMode::select(false, true, interval),
+			ProgressMode::Bar
+		);
+		assert_eq!(
+			ProgressMode::select(false, false, interval),
+			ProgressMode::Lines { interval }
+		);
+	}
+
+	#[test]
+	fn test_lines() {
+		let lines = simulate(
+			ProgressMode::Lines {
+				interval: Duration::from_secs(5),
+			},
+			ProgressDrawTarget::hidden(),
+		);
+
+		let messages: Vec<&str> = lines.iter().map(|(_, m)| m.as_str()).collect();
+		assert_eq!(
+			messages,
+			vec![
+				"Downloading: 0% (0 B of 1000 B)",
+				"Downloading: 25% (250 B of 1000 B)",
+				"Downloading: 50% (500 B of 1000 B)",
+				"Downloading: 75% (750 B of 1000 B)",
+				"Downloading: done, 1000 B in 20.0s",
+			]
+		);
+		assert!(lines.iter().all(|(level, _)| level.is_none()));
+	}
+
+	#[test]
+	fn test_phases() {
+		let lines = simulate(ProgressMode::Phases, ProgressDrawTarget::hidden());
+
+		let messages: Vec<&str> = lines.iter().map(|(_, m)| m.as_str()).collect();
+		assert_eq!(
+			messages,
+			vec![
+				"Downloading: started, 1000 B",
+				"Downloading: 25%",
+				"Downloading: 50%",
+				"Downloading: 75%",
+				"Downloading: finished, 1000 B in 20.0s",
+			]
+		);
+		assert!(lines.iter().all(|(level, _)| *level == Some(Level::Info)));
+	}
+
+	#[test]
+	fn test_phases_skipped_over() {
+		let sink = CapturingSink::default();
+		let log = log::Logger::test().with_sink(sink.clone());
+		let mut reporter = ProgressReporter::new(
+			log,
+			"Extracting",
+			ProgressMode::Phases,
+			ProgressDrawTarget::hidden(),
+		)
+		.counting("files");
+
+		let start = Instant::now();
+		reporter.report_at(0, 10, start);
+		reporter.report_at(8, 10, start);
+		reporter.report_at(9, 10, start);
+		reporter.report_at(10, 10, start + Duration::from_millis(1500));
+
+		let messages: Vec<String> = sink
+			.0
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(_, m)| m.clone())
+			.collect();
+		assert_eq!(
+			messages,
+			vec![
+				"Extracting: started, 10 files",
+				"Extracting: 75%",
+				"Extracting: finished, 10 files in 1.5s",
+			]
+		);
+	}
+
+	#[test]
+	fn test_bar() {
+		let term = InMemoryTerm::new(4, 80);
+		let lines = simulate(
+			ProgressMode::Bar,
+			ProgressDrawTarget::term_like(Box::new(term.clone())),
+		);
+
+		// the bar is drawn on the terminal and cleared when done, with nothing logged
+		assert!(lines.is_empty());
+		assert_eq!(term.contents().trim(), "");
+
+		let mut reporter = ProgressReporter::new(
+			log::Logger::test(),
+			"Downloading",
+			ProgressMode::Bar,
+			ProgressDrawTarget::term_like(Box::new(term.clone())),
+		);
+		reporter.report_at(500, 1000, Instant::now());
+		// past the bar's rate limit, so the last position is drawn
+		std::thread::sleep(Duration::from_millis(100));
+		reporter.bar.as_ref().unwrap().tick();
+		let contents = term.contents();
+		assert_eq!(contents.trim().lines().count(), 1, "{}", contents);
+		assert!(contents.starts_with("Downloading ["), "{}", contents);
+		assert!(contents.contains("50%"), "{}", contents);
+	}
+}
@@ -3,21 +3,44 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use std::{iter::Chain, slice};
+
+/// Keeps the most recent elements pushed to it, overwriting the oldest once
+/// it's full. Storage for the capacity is allocated up front, so pushing
+/// never allocates.
 pub struct RingBuffer<T> {
 	data: Vec<T>,
+	capacity: usize,
+	/// Index the next element is written to. Once the buffer is full, it's
+	/// also the index of the oldest element.
 	i: usize,
+	on_overwrite: Option<Box<dyn FnMut(T) + Send>>,
 }
 
 impl<T> RingBuffer<T> {
 	pub fn new(capacity: usize) -> Self {
 		Self {
 			data: Vec::with_capacity(capacity),
+			capacity,
 			i: 0,
+			on_overwrite: None,
+		}
+	}
+
+	/// Creates a buffer that calls the function with each element pushed out
+	/// of it, such as to keep a running total of the elements' sizes.
+	pub fn with_on_overwrite(
+		capacity: usize,
+		on_overwrite: impl FnMut(T) + Send + 'static,
+	) -> Self {
+		Self {
+			on_overwrite: Some(Box::new(on_overwrite)),
+			..Self::new(capacity)
 		}
 	}
 
 	pub fn capacity(&self) -> usize {
-		self.data.capacity()
+		self.capacity
 	}
 
 	pub fn len(&self) -> usize {
@@ -25,89 +48,128 @@ impl<T> RingBuffer<T> {
 	}
 
 	pub fn is_full(&self) -> bool {
-		self.data.len() == self.data.capacity()
+		self.data.len() == self.capacity
 	}
 
 	pub fn is_empty(&self) -> bool {
-		self.data.len() == 0
+		self.data.is_empty()
 	}
 
+	/// Adds an element, overwriting the oldest one if the buffer is full.
+	/// With a capacity of 0, the element is overwritten right away.
 	pub fn push(&mut self, value: T) {
-		if self.data.len() == self.data.capacity() {
-			self.data[self.i] = value;
-		} else {
+		if self.capacity == 0 {
+			self.overwritten(value);
+			return;
+		}
+
+		if self.data.len() < self.capacity {
 			self.data.push(value);
+		} else {
+			let old = std::mem::replace(&mut self.data[self.i], value);
+			self.overwritten(old);
 		}
 
-		self.i = (self.i + 1) % self.data.capacity();
+		self.i = (self.i + 1) % self.capacity;
 	}
 
-	pub fn iter(&self) -> RingBufferIter<'_, T> {
-		RingBufferIter {
-			index: 0,
-			buffer: self,
+	/// Changes the capacity, dropping the oldest elements that no longer fit
+	/// as if they were overwritten.
+	pub fn set_capacity(&mut self, capacity: usize) {
+		let oldest = self.oldest();
+		self.data.rotate_left(oldest);
+
+		let excess = self.data.len().saturating_sub(capacity);
+		let on_overwrite = &mut self.on_overwrite;
+		for old in self.data.drain(..excess) {
+			if let Some(f) = on_overwrite {
+				f(old);
+			}
 		}
-	}
-}
 
-impl<T: Default> IntoIterator for RingBuffer<T> {
-	type Item = T;
-	type IntoIter = OwnedRingBufferIter<T>;
-
-	fn into_iter(self) -> OwnedRingBufferIter<T>
-	where
-		T: Default,
-	{
-		OwnedRingBufferIter {
-			index: 0,
-			buffer: self,
+		if capacity > self.data.capacity() {
+			self.data.reserve_exact(capacity - self.data.len());
+		} else {
+			self.data.shrink_to(capacity);
 		}
+
+		self.capacity = capacity;
+		self.i = match capacity {
+			0 => 0,
+			c => self.data.len() % c,
+		};
 	}
-}
 
-pub struct OwnedRingBufferIter<T: Default> {
-	buffer: RingBuffer<T>,
-	index: usize,
-}
+	/// Iterates over the elements, oldest first.
+	pub fn iter(&self) -> RingBufferIter<'_, T> {
+		let (newer, older) = self.data.split_at(self.oldest());
+		RingBufferIter(older.iter().chain(newer.iter()))
+	}
 
-impl<T: Default> Iterator for OwnedRingBufferIter<T> {
-	type Item = T;
+	/// Iterates over the elements in the order they're stored in, which is
+	/// cheaper when the order doesn't matter.
+	pub fn iter_unordered(&self) -> slice::Iter<'_, T> {
+		self.data.iter()
+	}
 
-	fn next(&mut self) -> Option<Self::Item> {
-		if self.index == self.buffer.len() {
-			return None;
+	fn oldest(&self) -> usize {
+		if self.is_full() {
+			self.i
+		} else {
+			0
 		}
+	}
 
-		let ii = (self.index + self.buffer.i) % self.buffer.len();
-		let item = std::mem::take(&mut self.buffer.data[ii]);
-		self.index += 1;
-		Some(item)
+	fn overwritten(&mut self, value: T) {
+		if let Some(f) = &mut self.on_overwrite {
+			f(value);
+		}
 	}
 }
 
-pub struct RingBufferIter<'a, T> {
-	buffer: &'a RingBuffer<T>,
-	index: usize,
+impl<T> IntoIterator for RingBuffer<T> {
+	type Item = T;
+	type IntoIter = std::vec::IntoIter<T>;
+
+	/// Iterates over the elements, oldest first.
+	fn into_iter(mut self) -> std::vec::IntoIter<T> {
+		let oldest = self.oldest();
+		self.data.rotate_left(oldest);
+		self.data.into_iter()
+	}
 }
 
+pub struct RingBufferIter<'a, T>(Chain<slice::Iter<'a, T>, slice::Iter<'a, T>>);
+
 impl<'a, T> Iterator for RingBufferIter<'a, T> {
 	type Item = &'a T;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		if self.index == self.buffer.len() {
-			return None;
-		}
+		self.0.next()
+	}
 
-		let ii = (self.index + self.buffer.i) % self.buffer.len();
-		let item = &self.buffer.data[ii];
-		self.index += 1;
-		Some(item)
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		self.0.size_hint()
+	}
+}
+
+impl<'a, T> DoubleEndedIterator for RingBufferIter<'a, T> {
+	fn next_back(&mut self) -> Option<Self::Item> {
+		self.0.next_back()
 	}
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use rand::{rngs::StdRng, Rng, SeedableRng};
+	use std::{
+		collections::VecDeque,
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc,
+		},
+	};
 
 	#[test]
 	fn test_inserts() {
@@ -136,7 +198,102 @@ mod tests {
 		assert!(rb.is_full());
 		assert_eq!(rb.len(), 3);
 		assert_eq!(rb.iter().copied().collect::<Vec<i32>>(), vec![2, 3, 4]);
+		assert_eq!(
+			rb.iter().rev().copied().collect::<Vec<i32>>(),
+			vec![4, 3, 2]
+		);
+
+		let mut unordered = rb.iter_unordered().copied().collect::<Vec<i32>>();
+		unordered.sort();
+		assert_eq!(unordered, vec![2, 3, 4]);
 
 		assert_eq!(rb.into_iter().collect::<Vec<i32>>(), vec![2, 3, 4]);
 	}
+
+	#[test]
+	fn test_zero_capacity() {
+		let dropped = Arc::new(AtomicUsize::new(0));
+		let counter = dropped.clone();
+		let mut rb = RingBuffer::with_on_overwrite(0, move |_: String| {
+			counter.fetch_add(1, Ordering::SeqCst);
+		});
+
+		rb.push("a".to_string());
+		assert!(rb.is_empty());
+		assert_eq!(dropped.load(Ordering::SeqCst), 1);
+
+		rb.set_capacity(2);
+		rb.push("b".to_string());
+		rb.push("c".to_string());
+		rb.push("d".to_string());
+		assert_eq!(rb.into_iter().collect::<Vec<_>>(), vec!["c", "d"]);
+		assert_eq!(dropped.load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn test_set_capacity_keeps_newest() {
+		let mut rb = RingBuffer::new(4);
+		for i in 0..6 {
+			rb.push(i);
+		}
+
+		rb.set_capacity(6);
+		assert_eq!(rb.iter().copied().collect::<Vec<_>>(), vec![2, 3, 4, 5]);
+		rb.push(6);
+		rb.push(7);
+		rb.push(8);
+		assert_eq!(
+			rb.iter().copied().collect::<Vec<_>>(),
+			vec![3, 4, 5, 6, 7, 8]
+		);
+
+		rb.set_capacity(2);
+		assert_eq!(rb.capacity(), 2);
+		assert_eq!(rb.iter().copied().collect::<Vec<_>>(), vec![7, 8]);
+	}
+
+	/// Checks the buffer against a queue of the last `capacity` elements, and
+	/// that the bytes of the elements overwritten plus the bytes of those held
+	/// add up to the bytes pushed, over random pushes and capacity changes.
+	#[test]
+	fn test_matches_model_with_accounting() {
+		let mut rng = StdRng::seed_from_u64(0x5eed);
+
+		for _ in 0..200 {
+			let mut capacity = rng.gen_range(0..8);
+			let overwritten = Arc::new(AtomicUsize::new(0));
+			let counter = overwritten.clone();
+			let mut rb = RingBuffer::with_on_overwrite(capacity, move |s: String| {
+				counter.fetch_add(s.len(), Ordering::SeqCst);
+			});
+			let mut model = VecDeque::new();
+			let mut pushed = 0;
+
+			for step in 0..rng.gen_range(0..64) {
+				if rng.gen_ratio(1, 10) {
+					capacity = rng.gen_range(0..8);
+					rb.set_capacity(capacity);
+				} else {
+					let value = "x".repeat(rng.gen_range(0..16)) + &step.to_string();
+					pushed += value.len();
+					model.push_back(value.clone());
+					rb.push(value);
+				}
+
+				while model.len() > capacity {
+					model.pop_front();
+				}
+
+				assert_eq!(rb.len(), model.len());
+				assert_eq!(rb.is_full(), model.len() == capacity);
+				assert!(rb.iter().eq(model.iter()));
+				assert!(rb.iter().rev().eq(model.iter().rev()));
+
+				let held: usize = rb.iter_unordered().map(|s| s.len()).sum();
+				assert_eq!(held + overwritten.load(Ordering::SeqCst), pushed);
+			}
+
+			assert!(rb.into_iter().eq(model.into_iter()));
+		}
+	}
 }
@@ -0,0 +1,188 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	io,
+	os::unix::{
+		io::{FromRawFd, RawFd},
+		net::UnixListener as StdUnixListener,
+	},
+	path::PathBuf,
+	sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+
+use crate::constants::ACTIVATION_SOCKET_NAME;
+
+/// File descriptor of the first passed socket.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed to the process by its service manager.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ListenFd {
+	pub fd: RawFd,
+	/// Name given in the socket unit's `FileDescriptorName=`, if any.
+	pub name: Option<String>,
+}
+
+lazy_static! {
+	static ref RECEIVED: Mutex<Vec<ListenFd>> = Mutex::default();
+}
+
+/// Receives the sockets passed to the process through `LISTEN_FDS`, if it was
+/// socket activated by systemd (see `sd_listen_fds(3)`), to be taken later by
+/// `take_listen_fds`. The environment variables are cleared so that they're
+/// not inherited by child processes, so this must be called at startup,
+/// before any other threads exist.
+pub fn receive_listen_fds() {
+	let fds = parse_listen_fds(
+		std::env::var("LISTEN_PID").ok().as_deref(),
+		std::env::var("LISTEN_FDS").ok().as_deref(),
+		std::env::var("LISTEN_FDNAMES").ok().as_deref(),
+		std::process::id(),
+	);
+
+	std::env::remove_var("LISTEN_PID");
+	std::env::remove_var("LISTEN_FDS");
+	std::env::remove_var("LISTEN_FDNAMES");
+
+	for f in &fds {
+		unsafe {
+			libc::fcntl(f.fd, libc::F_SETFD, libc::FD_CLOEXEC);
+		}
+	}
+
+	*RECEIVED.lock().unwrap() = fds;
+}
+
+/// Takes the sockets received by `receive_listen_fds`, if any.
+pub fn take_listen_fds() -> Vec<ListenFd> {
+	std::mem::take(&mut *RECEIVED.lock().unwrap())
+}
+
+/// Paths where the socket unit of a socket activated tunnel service listens,
+/// for a user service and then for a system service. Connecting to one starts
+/// the service.
+pub fn activation_socket_paths() -> Vec<PathBuf> {
+	let mut paths = Vec::with_capacity(2);
+	if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+		paths.push(PathBuf::from(dir).join(ACTIVATION_SOCKET_NAME));
+	}
+	paths.push(PathBuf::from("/run").join(ACTIVATION_SOCKET_NAME));
+	paths
+}
+
+fn parse_listen_fds(
+	listen_pid: Option<&str>,
+	listen_fds: Option<&str>,
+	listen_fdnames: Option<&str>,
+	own_pid: u32,
+) -> Vec<ListenFd> {
+	// the variables are meant for us only if the pid matches
+	if listen_pid.and_then(|p| p.parse::<u32>().ok()) != Some(own_pid) {
+		return vec![];
+	}
+
+	let count = match listen_fds.and_then(|n| n.parse::<RawFd>().ok()) {
+		Some(n) if n > 0 => n,
+		_ => return vec![],
+	};
+
+	let mut names = listen_fdnames.unwrap_or_default().split(':');
+	(0..count)
+		.map(|i| ListenFd {
+			fd: SD_LISTEN_FDS_START + i,
+			name: names
+				.next()
+				.filter(|n| !n.is_empty())
+				.map(|n| n.to_string()),
+		})
+		.collect()
+}
+
+/// Adopts the passed file descriptor as a Unix socket listener. Fails if the
+/// descriptor is not a listening socket.
+pub fn adopt_unix_listener(fd: RawFd) -> io::Result<tokio::net::UnixListener> {
+	let mut accepting: libc::c_int = 0;
+	let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+	let r = unsafe {
+		libc::getsockopt(
+			fd,
+			libc::SOL_SOCKET,
+			libc::SO_ACCEPTCONN,
+			&mut accepting as *mut _ as *mut libc::c_void,
+			&mut len,
+		)
+	};
+
+	if r != 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	if accepting == 0 {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("file descriptor {} is not a listening socket", fd),
+		));
+	}
+
+	let listener = unsafe { StdUnixListener::from_raw_fd(fd) };
+	listener.set_nonblocking(true)?;
+	tokio::net::UnixListener::from_std(listener)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::{io::IntoRawFd, net::UnixStream as StdUnixStream};
+
+	use super::*;
+
+	#[test]
+	fn test_parse_listen_fds() {
+		assert_eq!(
+			parse_listen_fds(Some("42"), Some("2"), Some("control:"), 42),
+			vec![
+				ListenFd {
+					fd: 3,
+					name: Some("control".to_string())
+				},
+				ListenFd { fd: 4, name: None },
+			]
+		);
+	}
+
+	#[test]
+	fn test_parse_listen_fds_other_pid() {
+		assert_eq!(parse_listen_fds(Some("41"), Some("1"), None, 42), vec![]);
+		assert_eq!(parse_listen_fds(None, Some("1"), None, 42), vec![]);
+		assert_eq!(parse_listen_fds(Some("42"), Some("0"), None, 42), vec![]);
+	}
+
+	#[tokio::test]
+	async fn test_adopts_listener() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("activated.sock");
+		let fd = StdUnixListener::bind(&path).unwrap().into_raw_fd();
+
+		let listener = adopt_unix_listener(fd).unwrap();
+		assert_eq!(listener.local_addr().unwrap().as_pathname(), Some(&*path));
+
+		let (accepted, connected) =
+			tokio::join!(listener.accept(), tokio::net::UnixStream::connect(&path));
+		accepted.unwrap();
+		connected.unwrap();
+	}
+
+	#[test]
+	fn test_rejects_non_listening_socket() {
+		let (a, _b) = StdUnixStream::pair().unwrap();
+		let fd = a.into_raw_fd();
+
+		let err = adopt_unix_listener(fd).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+		unsafe { libc::close(fd) };
+	}
+}
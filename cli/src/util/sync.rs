@@ -3,12 +3,17 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use async_trait::async_trait;
-use std::{marker::PhantomData, sync::Arc};
+use std::{marker::PhantomData, sync::Arc, time::Duration};
 use tokio::sync::{
 	broadcast, mpsc,
 	watch::{self, error::RecvError},
 };
 
+/// Side of a barrier that waits for it to open. Clones share the barrier.
+///
+/// The value is stored before waiters are woken, under the channel's lock,
+/// so anything the opener wrote before `open` is visible to a task once its
+/// `wait` returns or `is_open` returns true.
 #[derive(Clone)]
 pub struct Barrier<T>(watch::Receiver<Option<T>>)
 where
@@ -18,18 +23,36 @@ impl<T> Barrier<T>
 where
 	T: Clone,
 {
-	/// Waits for the barrier to be closed, returning a value if one was sent.
+	/// Waits for the barrier to be opened, returning its value. Returns right
+	/// away if it's already open. Fails if the opener is dropped without
+	/// opening it.
 	pub async fn wait(&mut self) -> Result<T, RecvError> {
 		loop {
-			self.0.changed().await?;
-
-			if let Some(v) = self.0.borrow().clone() {
+			// checked first, since `changed` doesn't resolve for a value this
+			// receiver has already seen
+			if let Some(v) = self.0.borrow_and_update().clone() {
 				return Ok(v);
 			}
+
+			self.0.changed().await?;
 		}
 	}
 
-	/// Gets whether the barrier is currently open
+	/// Waits for the barrier to be opened for up to the duration, returning
+	/// None if it didn't open in time. A barrier whose opener was dropped can
+	/// never open, so this waits out the duration.
+	pub async fn wait_timeout(&mut self, duration: Duration) -> Option<T> {
+		tokio::time::timeout(duration, async {
+			match self.wait().await {
+				Ok(v) => v,
+				Err(_) => futures::future::pending().await,
+			}
+		})
+		.await
+		.ok()
+	}
+
+	/// Gets whether the barrier is currently open, without waiting.
 	pub fn is_open(&self) -> bool {
 		self.0.borrow().is_some()
 	}
@@ -46,16 +69,23 @@ impl<T: Clone + Send + Sync> Receivable<T> for Barrier<T> {
 pub struct BarrierOpener<T: Clone>(Arc<watch::Sender<Option<T>>>);
 
 impl<T: Clone> BarrierOpener<T> {
-	/// Opens the barrier.
+	/// Opens the barrier. Does nothing if it's already open.
 	pub fn open(&self, value: T) {
+		self.open_with(|| value);
+	}
+
+	/// Opens the barrier with the value returned from the function, which is
+	/// only called if the barrier isn't open yet. Returns whether this call
+	/// opened it; of concurrent calls, exactly one does.
+	pub fn open_with(&self, value: impl FnOnce() -> T) -> bool {
 		self.0.send_if_modified(|v| {
 			if v.is_none() {
-				*v = Some(value);
+				*v = Some(value());
 				true
 			} else {
 				false
 			}
-		});
+		})
 	}
 }
 
@@ -181,6 +211,59 @@ impl<T: Send, A: Send + Receivable<T>, B: Send + Receivable<T>> Receivable<T>
 	}
 }
 
+/// Counts ongoing activity, such as open connections, so that callers can
+/// wait until there's been no activity for some time.
+#[derive(Clone)]
+pub struct ActivityTracker(Arc<watch::Sender<usize>>);
+
+impl Default for ActivityTracker {
+	fn default() -> Self {
+		Self(Arc::new(watch::channel(0).0))
+	}
+}
+
+impl ActivityTracker {
+	/// Marks the start of some activity, which lasts until the guard is dropped.
+	pub fn start(&self) -> ActivityGuard {
+		self.0.send_modify(|n| *n += 1);
+		ActivityGuard(self.0.clone())
+	}
+
+	/// Resolves once there's been no activity for the given duration.
+	pub async fn wait_idle(&self, duration: Duration) {
+		let mut rx = self.0.subscribe();
+		loop {
+			if *rx.borrow_and_update() > 0 {
+				// the sender is held by self, so this can't fail
+				let _ = rx.changed().await;
+				continue;
+			}
+
+			tokio::select! {
+				_ = tokio::time::sleep(duration) => return,
+				_ = rx.changed() => continue,
+			}
+		}
+	}
+
+	/// Resolves once there's no ongoing activity.
+	pub async fn wait_inactive(&self) {
+		let mut rx = self.0.subscribe();
+		while *rx.borrow_and_update() > 0 {
+			// the sender is held by self, so this can't fail
+			let _ = rx.changed().await;
+		}
+	}
+}
+
+pub struct ActivityGuard(Arc<watch::Sender<usize>>);
+
+impl Drop for ActivityGuard {
+	fn drop(&mut self) {
+		self.0.send_modify(|n| *n -= 1);
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -218,4 +301,95 @@ mod tests {
 		assert!(rx1.await.unwrap() == 42);
 		assert!(rx2.await.unwrap() == 42);
 	}
+
+	#[tokio::test]
+	async fn test_barrier_waits_again_once_open() {
+		let (mut barrier, opener) = new_barrier::<u32>();
+		opener.open(1);
+		opener.open(2);
+
+		assert_eq!(barrier.wait().await.unwrap(), 1);
+		assert_eq!(barrier.wait().await.unwrap(), 1);
+		assert_eq!(
+			barrier.wait_timeout(Duration::from_millis(10)).await,
+			Some(1)
+		);
+		assert!(barrier.is_open());
+	}
+
+	#[tokio::test]
+	async fn test_barrier_wait_timeout_elapses() {
+		let (mut barrier, opener) = new_barrier::<u32>();
+
+		assert_eq!(barrier.wait_timeout(Duration::from_millis(20)).await, None);
+		assert!(!barrier.is_open());
+
+		// a dropped opener doesn't end the wait early
+		drop(opener);
+		let start = tokio::time::Instant::now();
+		assert_eq!(barrier.wait_timeout(Duration::from_millis(20)).await, None);
+		assert!(start.elapsed() >= Duration::from_millis(20));
+		assert!(barrier.wait().await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_barrier_opened_during_wait_timeout() {
+		let (barrier, opener) = new_barrier::<u32>();
+
+		let waiters: Vec<_> = (0..8)
+			.map(|_| {
+				let mut b = barrier.clone();
+				tokio::spawn(async move { b.wait_timeout(Duration::from_secs(5)).await })
+			})
+			.collect();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		opener.open(42);
+
+		for w in waiters {
+			assert_eq!(w.await.unwrap(), Some(42));
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn test_barrier_opens_once() {
+		let (mut barrier, opener) = new_barrier::<usize>();
+
+		let openers: Vec<_> = (0..16)
+			.map(|i| {
+				let opener = opener.clone();
+				tokio::spawn(async move { opener.open_with(|| i) })
+			})
+			.collect();
+
+		let mut opened = vec![];
+		for (i, o) in openers.into_iter().enumerate() {
+			if o.await.unwrap() {
+				opened.push(i);
+			}
+		}
+
+		assert_eq!(opened.len(), 1);
+		assert_eq!(barrier.wait().await.unwrap(), opened[0]);
+	}
+
+	#[tokio::test]
+	async fn test_activity_tracker_waits_for_idle() {
+		let tracker = ActivityTracker::default();
+		let guard = tracker.start();
+
+		let waiter = tracker.clone();
+		let idle = tokio::spawn(async move {
+			waiter.wait_idle(Duration::from_millis(20)).await;
+		});
+
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert!(!idle.is_finished());
+
+		drop(guard);
+		tokio::time::timeout(Duration::from_secs(1), idle)
+			.await
+			.expect("expected to become idle")
+			.unwrap();
+	}
 }
@@ -8,6 +8,7 @@ use flate2::read::GzDecoder;
 use std::fs;
 use std::io::Seek;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use tar::Archive;
 
 use super::errors::wrapdbg;
@@ -56,10 +57,12 @@ fn should_skip_first_segment(file: &fs::File) -> Result<(bool, u64), WrappedErro
 	Ok((!had_different_prefixes && num_entries > 1, num_entries)) // prefix removal is invalid if there's only a single file
 }
 
+/// Extracts the tarball, stopping between entries once `cancelled` is set.
 pub fn decompress_tarball<T>(
 	path: &Path,
 	parent_path: &Path,
 	mut reporter: T,
+	cancelled: &AtomicBool,
 ) -> Result<(), WrappedError>
 where
 	T: ReportCopyProgress,
@@ -84,6 +87,13 @@ where
 		.map_err(|e| wrap(e, format!("error opening archive {}", path.display())))?
 		.filter_map(|e| e.ok())
 		.try_for_each::<_, Result<_, WrappedError>>(|mut entry| {
+			if cancelled.load(Ordering::Relaxed) {
+				return Err(wrap(
+					"extraction was cancelled",
+					format!("error extracting {}", path.display()),
+				));
+			}
+
 			// approximate progress based on where we are in the archive:
 			entries_so_far += 1;
 			if entries_so_far - last_reported_at > report_progress_every {
@@ -0,0 +1,57 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Runs tests that read environment variables in a child process with them
+//! set, since changing the environment of the test process races with the
+//! tests running on its other threads. Tests that change other process-wide
+//! settings run in a child for the same reason. The child's stdin is closed.
+
+use std::process::Command;
+
+/// Set in the child process that runs the test with the environment.
+const CHILD_ENV: &str = "VSCODE_CLI_TEST_ENV_CHILD";
+
+/// Gets whether this is the child process that runs the test. Otherwise it
+/// runs the test, named by its module path and name, again in a child with
+/// the variables set, and checks that it passes. Tests call it first and
+/// return if it's false:
+///
+/// ```ignore
+/// if !in_child_with_env(concat!(module_path!(), "::test_name"), &[("A", "1")]) {
+///     return;
+/// }
+/// ```
+pub fn in_child_with_env(test: &str, vars: &[(&str, &str)]) -> bool {
+	if std::env::var_os(CHILD_ENV).is_some() {
+		return true;
+	}
+
+	// tests are named without the crate
+	let name = test.split_once("::").map(|(_, n)| n).unwrap_or(test);
+	let output = Command::new(std::env::current_exe().unwrap())
+		.args([name, "--exact", "--nocapture", "--test-threads=1"])
+		.env(CHILD_ENV, "1")
+		.envs(vars.iter().copied())
+		.output()
+		.unwrap();
+
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert!(
+		output.status.success(),
+		"{} failed with {:?}:\n{}\n{}",
+		name,
+		vars,
+		stdout,
+		String::from_utf8_lossy(&output.stderr)
+	);
+	// make sure the test ran, rather than matching nothing
+	assert!(
+		stdout.contains("1 passed"),
+		"{} didn't run:\n{}",
+		name,
+		stdout
+	);
+	false
+}
@@ -0,0 +1,382 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs, io,
+	path::{Path, PathBuf},
+	pin::Pin,
+	sync::{Arc, RwLock},
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+	net::{TcpListener, TcpStream},
+	sync::mpsc,
+	task::JoinHandle,
+};
+use tokio_rustls::{
+	rustls::{
+		self,
+		server::{ClientHello, ResolvesServerCert},
+		sign::CertifiedKey,
+	},
+	server::TlsStream,
+	TlsAcceptor,
+};
+
+use crate::{
+	log,
+	util::{errors::CodeError, io::write_private_file},
+};
+
+/// Connections that don't finish their handshake in this time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Locations of a PEM-encoded certificate chain and its private key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertificatePaths {
+	pub cert: PathBuf,
+	pub key: PathBuf,
+}
+
+/// Certificate that handshakes are resolved to, which can be reloaded from
+/// disk without restarting the listener.
+pub struct ReloadableCertificate {
+	paths: CertificatePaths,
+	current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCertificate {
+	pub fn load(paths: CertificatePaths) -> Result<Arc<Self>, CodeError> {
+		let current = RwLock::new(Arc::new(load_certified_key(&paths)?));
+		Ok(Arc::new(Self { paths, current }))
+	}
+
+	/// Reads the certificate and key again. The previous certificate is kept
+	/// if they can't be loaded.
+	pub fn reload(&self) -> Result<(), CodeError> {
+		let key = load_certified_key(&self.paths)?;
+		*self.current.write().unwrap() = Arc::new(key);
+		Ok(())
+	}
+
+	/// Gets the SHA-256 fingerprint of the current leaf certificate.
+	pub fn fingerprint(&self) -> String {
+		fingerprint(&self.current.read().unwrap().cert[0].0)
+	}
+}
+
+impl ResolvesServerCert for ReloadableCertificate {
+	fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+		Some(self.current.read().unwrap().clone())
+	}
+}
+
+fn tls_err(path: &Path, message: impl ToString) -> CodeError {
+	CodeError::InvalidTlsCertificate {
+		path: path.display().to_string(),
+		message: message.to_string(),
+	}
+}
+
+fn load_certified_key(paths: &CertificatePaths) -> Result<CertifiedKey, CodeError> {
+	let cert_pem = fs::read(&paths.cert).map_err(|e| tls_err(&paths.cert, e))?;
+	let certs =
+		rustls_pemfile::certs(&mut cert_pem.as_slice()).map_err(|e| tls_err(&paths.cert, e))?;
+	if certs.is_empty() {
+		return Err(tls_err(&paths.cert, "no certificates found"));
+	}
+
+	let key_pem = fs::read(&paths.key).map_err(|e| tls_err(&paths.key, e))?;
+	let mut reader = key_pem.as_slice();
+	let key = loop {
+		match rustls_pemfile::read_one(&mut reader).map_err(|e| tls_err(&paths.key, e))? {
+			Some(rustls_pemfile::Item::PKCS8Key(k))
+			| Some(rustls_pemfile::Item::RSAKey(k))
+			| Some(rustls_pemfile::Item::ECKey(k)) => break rustls::PrivateKey(k),
+			Some(_) => continue,
+			None => return Err(tls_err(&paths.key, "no private key found")),
+		}
+	};
+
+	let key = rustls::sign::any_supported_type(&key).map_err(|e| tls_err(&paths.key, e))?;
+	Ok(CertifiedKey::new(
+		certs.into_iter().map(rustls::Certificate).collect(),
+		key,
+	))
+}
+
+/// Formats the SHA-256 hash of a DER-encoded certificate as colon-separated
+/// hex, the way browsers show it.
+pub fn fingerprint(der: &[u8]) -> String {
+	Sha256::digest(der)
+		.iter()
+		.map(|b| format!("{:02X}", b))
+		.collect::<Vec<_>>()
+		.join(":")
+}
+
+/// Gets a self-signed certificate for the hosts from the directory,
+/// generating one the first time it's asked for.
+pub fn ensure_self_signed(dir: &Path, hosts: &[String]) -> Result<CertificatePaths, CodeError> {
+	let id = Sha256::digest(hosts.join(",").as_bytes());
+	let id: String = id.iter().take(4).map(|b| format!("{:02x}", b)).collect();
+	let paths = CertificatePaths {
+		cert: dir.join(format!("self-signed-{}.pem", id)),
+		key: dir.join(format!("self-signed-{}.key", id)),
+	};
+
+	if paths.cert.exists() && paths.key.exists() {
+		return Ok(paths);
+	}
+
+	let cert =
+		rcgen::generate_simple_self_signed(hosts.to_vec()).map_err(|e| tls_err(&paths.cert, e))?;
+	let cert_pem = cert.serialize_pem().map_err(|e| tls_err(&paths.cert, e))?;
+
+	fs::create_dir_all(dir).map_err(|e| tls_err(dir, e))?;
+	write_private_file(&paths.key, cert.serialize_private_key_pem().as_bytes())
+		.map_err(|e| tls_err(&paths.key, e))?;
+	fs::write(&paths.cert, cert_pem).map_err(|e| tls_err(&paths.cert, e))?;
+
+	Ok(paths)
+}
+
+pub fn make_acceptor(cert: Arc<ReloadableCertificate>) -> TlsAcceptor {
+	let mut config = rustls::ServerConfig::builder()
+		.with_safe_defaults()
+		.with_no_client_auth()
+		.with_cert_resolver(cert);
+	config.alpn_protocols = vec![b"http/1.1".to_vec()];
+	TlsAcceptor::from(Arc::new(config))
+}
+
+/// Accepts connections on the listener and yields them to hyper once their
+/// TLS handshake is done. Handshakes happen in the background so that a slow
+/// client doesn't hold up others.
+pub struct TlsIncoming {
+	rx: mpsc::Receiver<TlsStream<TcpStream>>,
+	task: JoinHandle<()>,
+}
+
+impl TlsIncoming {
+	pub fn new(listener: TcpListener, acceptor: TlsAcceptor, log: log::Logger) -> Self {
+		let (tx, rx) = mpsc::channel(32);
+		let task = tokio::spawn(async move {
+			loop {
+				let (socket, addr) = match listener.accept().await {
+					Ok(s) => s,
+					Err(e) => {
+						// usually out of file descriptors, give some time to recover
+						warning!(log, "Error accepting connection: {}", e);
+						tokio::time::sleep(Duration::from_millis(100)).await;
+						continue;
+					}
+				};
+
+				let acceptor = acceptor.clone();
+				let tx = tx.clone();
+				let log = log.clone();
+				tokio::spawn(async move {
+					match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+						Ok(Ok(stream)) => {
+							tx.send(stream).await.ok();
+						}
+						Ok(Err(e)) => debug!(log, "TLS handshake with {} failed: {}", addr, e),
+						Err(_) => debug!(log, "TLS handshake with {} timed out", addr),
+					}
+				});
+			}
+		});
+
+		Self { rx, task }
+	}
+}
+
+impl Drop for TlsIncoming {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+impl hyper::server::accept::Accept for TlsIncoming {
+	type Conn = TlsStream<TcpStream>;
+	type Error = io::Error;
+
+	fn poll_accept(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+	) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+		self.rx.poll_recv(cx).map(|c| c.map(Ok))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::convert::Infallible;
+
+	use hyper::{
+		service::{make_service_fn, service_fn},
+		Body, Request, Response, Server, StatusCode,
+	};
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use tokio_rustls::{client, TlsConnector};
+
+	use super::*;
+
+	/// Upgrades every request to a connection that echos what it's sent,
+	/// like websocket connections are handled.
+	async fn echo_upgrade(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+		tokio::spawn(async move {
+			let mut upgraded = hyper::upgrade::on(req).await.unwrap();
+			let mut buf = [0u8; 5];
+			upgraded.read_exact(&mut buf).await.unwrap();
+			upgraded.write_all(&buf).await.unwrap();
+		});
+
+		Ok(Response::builder()
+			.status(StatusCode::SWITCHING_PROTOCOLS)
+			.header(hyper::header::CONNECTION, "upgrade")
+			.header(hyper::header::UPGRADE, "echo")
+			.body(Body::empty())
+			.unwrap())
+	}
+
+	async fn start_server(cert: Arc<ReloadableCertificate>) -> u16 {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let incoming = TlsIncoming::new(listener, make_acceptor(cert), log::Logger::test());
+		tokio::spawn(Server::builder(incoming).serve(make_service_fn(|_| async {
+			Ok::<_, Infallible>(service_fn(echo_upgrade))
+		})));
+		port
+	}
+
+	/// Connects to the server, trusting only the given certificate.
+	async fn connect(port: u16, trusted: &CertificatePaths) -> client::TlsStream<TcpStream> {
+		let pem = fs::read(&trusted.cert).unwrap();
+		let mut roots = rustls::RootCertStore::empty();
+		for der in rustls_pemfile::certs(&mut pem.as_slice()).unwrap() {
+			roots.add(&rustls::Certificate(der)).unwrap();
+		}
+
+		let config = rustls::ClientConfig::builder()
+			.with_safe_defaults()
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+		let socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+		TlsConnector::from(Arc::new(config))
+			.connect("localhost".try_into().unwrap(), socket)
+			.await
+			.unwrap()
+	}
+
+	fn peer_fingerprint(stream: &client::TlsStream<TcpStream>) -> String {
+		fingerprint(&stream.get_ref().1.peer_certificates().unwrap()[0].0)
+	}
+
+	#[test]
+	fn test_self_signed_is_cached() {
+		let dir = tempfile::tempdir().unwrap();
+		let hosts = vec!["localhost".to_string()];
+
+		let paths = ensure_self_signed(dir.path(), &hosts).unwrap();
+		let first = ReloadableCertificate::load(paths.clone())
+			.unwrap()
+			.fingerprint();
+		assert_eq!(first.len(), 32 * 3 - 1);
+
+		assert_eq!(ensure_self_signed(dir.path(), &hosts).unwrap(), paths);
+		let second = ReloadableCertificate::load(paths.clone())
+			.unwrap()
+			.fingerprint();
+		assert_eq!(first, second);
+
+		let other = ensure_self_signed(dir.path(), &["example.com".to_string()]).unwrap();
+		assert_ne!(other, paths);
+		assert_ne!(
+			ReloadableCertificate::load(other).unwrap().fingerprint(),
+			first
+		);
+	}
+
+	#[test]
+	fn test_rejects_invalid_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = CertificatePaths {
+			cert: dir.path().join("cert.pem"),
+			key: dir.path().join("key.pem"),
+		};
+		fs::write(&paths.cert, "not a certificate").unwrap();
+		fs::write(&paths.key, "not a key").unwrap();
+
+		assert!(matches!(
+			ReloadableCertificate::load(paths),
+			Err(CodeError::InvalidTlsCertificate { .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn test_upgrade_over_tls() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = ensure_self_signed(dir.path(), &["localhost".to_string()]).unwrap();
+		let cert = ReloadableCertificate::load(paths.clone()).unwrap();
+		let port = start_server(cert.clone()).await;
+
+		let mut stream = connect(port, &paths).await;
+		assert_eq!(peer_fingerprint(&stream), cert.fingerprint());
+
+		stream
+			.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\n")
+			.await
+			.unwrap();
+
+		let mut head = vec![];
+		while !head.ends_with(b"\r\n\r\n") {
+			head.push(stream.read_u8().await.unwrap());
+		}
+		assert!(head.starts_with(b"HTTP/1.1 101"));
+
+		stream.write_all(b"hello").await.unwrap();
+		let mut echoed = [0u8; 5];
+		stream.read_exact(&mut echoed).await.unwrap();
+		assert_eq!(&echoed, b"hello");
+	}
+
+	#[tokio::test]
+	async fn test_reload_swaps_certificate() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = CertificatePaths {
+			cert: dir.path().join("cert.pem"),
+			key: dir.path().join("key.pem"),
+		};
+
+		let generated = ensure_self_signed(dir.path(), &["localhost".to_string()]).unwrap();
+		fs::rename(&generated.cert, &paths.cert).unwrap();
+		fs::rename(&generated.key, &paths.key).unwrap();
+
+		let cert = ReloadableCertificate::load(paths.clone()).unwrap();
+		let before = cert.fingerprint();
+		let port = start_server(cert.clone()).await;
+		assert_eq!(peer_fingerprint(&connect(port, &paths).await), before);
+
+		// a new certificate for the same host, then a reload, like on SIGHUP
+		let generated = ensure_self_signed(dir.path(), &["localhost".to_string()]).unwrap();
+		fs::rename(&generated.cert, &paths.cert).unwrap();
+		fs::rename(&generated.key, &paths.key).unwrap();
+		cert.reload().unwrap();
+
+		let after = cert.fingerprint();
+		assert_ne!(before, after);
+		assert_eq!(peer_fingerprint(&connect(port, &paths).await), after);
+
+		// broken files keep the previous certificate
+		fs::write(&paths.key, "garbage").unwrap();
+		assert!(cert.reload().is_err());
+		assert_eq!(cert.fingerprint(), after);
+	}
+}
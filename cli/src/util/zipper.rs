@@ -8,6 +8,7 @@ use std::fs::{self, File};
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use zip::read::ZipFile;
 use zip::{self, ZipArchive};
 
@@ -44,7 +45,13 @@ fn should_skip_first_segment(archive: &mut ZipArchive<File>) -> bool {
 	archive.len() > 1 // prefix removal is invalid if there's only a single file
 }
 
-pub fn unzip_file<T>(path: &Path, parent_path: &Path, mut reporter: T) -> Result<(), WrappedError>
+/// Extracts the zip file, stopping between entries once `cancelled` is set.
+pub fn unzip_file<T>(
+	path: &Path,
+	parent_path: &Path,
+	mut reporter: T,
+	cancelled: &AtomicBool,
+) -> Result<(), WrappedError>
 where
 	T: ReportCopyProgress,
 {
@@ -58,6 +65,13 @@ where
 	let report_progress_every = archive.len() / 20;
 
 	for i in 0..archive.len() {
+		if cancelled.load(Ordering::Relaxed) {
+			return Err(wrap(
+				"extraction was cancelled",
+				format!("error extracting {}", path.display()),
+			));
+		}
+
 		if i % report_progress_every == 0 {
 			reporter.report_progress(i as u64, archive.len() as u64);
 		}
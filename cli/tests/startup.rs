@@ -0,0 +1,62 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Runs the CLI binary to check that commands which never need credentials
+//! don't wait on the OS keyring. The keyring is made to hang with a session
+//! bus that accepts connections but never answers, as on machines where dbus
+//! is misconfigured.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+	os::unix::net::UnixListener,
+	process::Command,
+	time::{Duration, Instant},
+};
+
+/// Well under the keyring timeout, which these commands would otherwise
+/// wait for.
+const MAX_STARTUP: Duration = Duration::from_secs(2);
+
+#[test]
+fn test_commands_exit_with_hanging_keyring() {
+	let dir = tempfile::tempdir().unwrap();
+	let bus = dir.path().join("bus");
+	let listener = UnixListener::bind(&bus).unwrap();
+	std::thread::spawn(move || {
+		// connections are kept open, so clients wait for an answer
+		let mut held = vec![];
+		for stream in listener.incoming().flatten() {
+			held.push(stream);
+		}
+	});
+
+	for args in [&["--version"][..], &["tunnel", "--help"][..]] {
+		let start = Instant::now();
+		let output = Command::new(env!("CARGO_BIN_EXE_code"))
+			.args(args)
+			.env(
+				"DBUS_SESSION_BUS_ADDRESS",
+				format!("unix:path={}", bus.display()),
+			)
+			.env("VSCODE_CLI_DATA_DIR", dir.path().join("data"))
+			.env_remove("VSCODE_CLI_USE_FILE_KEYCHAIN")
+			.output()
+			.unwrap();
+
+		assert!(
+			output.status.success(),
+			"{:?} failed: {}",
+			args,
+			String::from_utf8_lossy(&output.stderr)
+		);
+		assert!(
+			start.elapsed() < MAX_STARTUP,
+			"{:?} took {:?}",
+			args,
+			start.elapsed()
+		);
+	}
+}